@@ -0,0 +1,12 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use wokwi_server::gdb::parse_packet;
+
+fuzz_target!(|data: &[u8]| {
+    let mut buffer = data;
+    while let Some((_, len)) = parse_packet(buffer) {
+        assert!(len > 0 && len <= buffer.len());
+        buffer = &buffer[len..];
+    }
+});
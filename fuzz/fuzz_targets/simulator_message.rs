@@ -0,0 +1,61 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use serde_json::Value;
+use wokwi_server::buses::{I2cTransaction, SpiTransaction};
+use wokwi_server::error::Result;
+use wokwi_server::gdb;
+use wokwi_server::messages::{self, Simulation};
+use wokwi_server::router::Outbox;
+use wokwi_server::vcd::PinChange;
+
+/// Does what the server does with each decoded message, short of running a simulation
+#[derive(Default)]
+struct Decoded {
+    uart: Vec<u8>,
+}
+
+impl Simulation for Decoded {
+    fn uart_data(&mut self, bytes: Vec<u8>, _out: &mut Outbox) -> Result<()> {
+        self.uart.extend(bytes);
+        Ok(())
+    }
+
+    fn gdb_response(&mut self, response: Vec<u8>, out: &mut Outbox) -> Result<()> {
+        if let Some(packet) = gdb::unframe(&response).and_then(|p| std::str::from_utf8(p).ok()) {
+            gdb::decode_hex(packet);
+        }
+        out.send_to_gdb(response);
+        Ok(())
+    }
+
+    fn i2c_transaction(&mut self, transaction: I2cTransaction, _out: &mut Outbox) -> Result<()> {
+        transaction.describe();
+        Ok(())
+    }
+
+    fn spi_transaction(&mut self, transaction: SpiTransaction, _out: &mut Outbox) -> Result<()> {
+        transaction.describe();
+        Ok(())
+    }
+
+    fn pin_change(&mut self, _change: PinChange, _out: &mut Outbox) -> Result<()> {
+        Ok(())
+    }
+
+    fn paused(&mut self, _out: &mut Outbox) -> Result<()> {
+        Ok(())
+    }
+
+    fn chip_message(&mut self, _chip: &str, _message: &Value, _out: &mut Outbox) -> Result<()> {
+        Ok(())
+    }
+}
+
+fuzz_target!(|data: &[u8]| {
+    let Ok(message) = serde_json::from_slice::<Value>(data) else {
+        return;
+    };
+    let mut router = messages::router();
+    router.dispatch(&mut Decoded::default(), &message).ok();
+});
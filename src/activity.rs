@@ -0,0 +1,42 @@
+use crate::sinks::{self, SinkOptions, UartSink};
+use std::sync::OnceLock;
+use tokio::sync::broadcast;
+
+/// how far a slow subscriber can fall behind before missing activity
+const BACKLOG: usize = 1024;
+
+/// Something the server did, for live views like the TUI
+#[derive(Debug, Clone)]
+pub enum Activity {
+    /// output from the simulated UART
+    Uart(Vec<u8>),
+    /// a GDB packet, from GDB to the simulator or the response
+    Gdb { to_target: bool, packet: String },
+    /// progress sending firmware to the simulator
+    Transfer(String),
+}
+
+static FEED: OnceLock<broadcast::Sender<Activity>> = OnceLock::new();
+
+/// Start receiving activity
+pub fn subscribe() -> broadcast::Receiver<Activity> {
+    FEED.get_or_init(|| broadcast::channel(BACKLOG).0)
+        .subscribe()
+}
+
+/// Let subscribers know about some activity, does nothing if nobody has subscribed
+pub fn publish(activity: Activity) {
+    if let Some(feed) = FEED.get() {
+        feed.send(activity).ok();
+    }
+}
+
+/// Publishes UART output, rendered like stdout would be
+pub fn uart_sink(opts: &SinkOptions) -> Box<dyn UartSink> {
+    sinks::rendered(opts, |bytes| {
+        if !bytes.is_empty() {
+            publish(Activity::Uart(bytes.to_vec()));
+        }
+        Ok(())
+    })
+}
@@ -0,0 +1,50 @@
+use xmas_elf::sections::{ShType, SHF_ALLOC};
+use xmas_elf::ElfFile;
+
+const APP_DESC_MAGIC: u32 = 0xABCD5432;
+const APP_DESC_SIZE: usize = 256;
+
+/// The `esp_app_desc_t` structure ESP-IDF embeds at the start of the application image
+#[derive(Debug)]
+pub struct AppDescriptor {
+    pub secure_version: u32,
+    pub version: String,
+    pub project_name: String,
+    pub time: String,
+    pub date: String,
+    pub idf_version: String,
+    pub elf_sha256: [u8; 32],
+}
+
+impl AppDescriptor {
+    /// Look for an application descriptor in the loadable sections of the elf
+    pub fn find(elf: &ElfFile) -> Option<Self> {
+        elf.section_iter()
+            .filter(|s| s.flags() & SHF_ALLOC != 0 && s.get_type() == Ok(ShType::ProgBits))
+            .find_map(|s| {
+                let data = s.raw_data(elf);
+                (0..data.len().saturating_sub(APP_DESC_SIZE - 1))
+                    .step_by(4)
+                    .find(|&i| data[i..i + 4] == APP_DESC_MAGIC.to_le_bytes())
+                    .and_then(|i| Self::parse(&data[i..i + APP_DESC_SIZE]))
+            })
+    }
+
+    fn parse(data: &[u8]) -> Option<Self> {
+        let string = |range: std::ops::Range<usize>| {
+            let bytes = &data[range];
+            let end = bytes.iter().position(|&b| b == 0).unwrap_or(bytes.len());
+            String::from_utf8_lossy(&bytes[..end]).into_owned()
+        };
+
+        Some(Self {
+            secure_version: u32::from_le_bytes(data[4..8].try_into().ok()?),
+            version: string(16..48),
+            project_name: string(48..80),
+            time: string(80..96),
+            date: string(96..112),
+            idf_version: string(112..144),
+            elf_sha256: data[144..176].try_into().ok()?,
+        })
+    }
+}
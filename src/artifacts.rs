@@ -0,0 +1,88 @@
+use anyhow::{Context, Result};
+use serde_json::{json, Value};
+use std::collections::VecDeque;
+use std::path::Path;
+use std::time::Instant;
+use wokwi_server::SimulationPacket;
+
+/// how many websocket messages to keep
+const MAX_MESSAGES: usize = 100;
+/// websocket messages are cut to this many characters, start packets are mostly base64
+const MAX_MESSAGE_LEN: usize = 512;
+
+/// What happened during a headless run, kept to explain failures
+pub struct RunLog {
+    pub started: Instant,
+    uart: Vec<u8>,
+    messages: VecDeque<String>,
+    segments: Value,
+}
+
+impl RunLog {
+    pub fn new() -> Self {
+        Self {
+            started: Instant::now(),
+            uart: Vec::new(),
+            messages: VecDeque::new(),
+            segments: Value::Null,
+        }
+    }
+
+    /// Record output from the simulated UART
+    pub fn uart(&mut self, bytes: &[u8]) {
+        self.uart.extend_from_slice(bytes);
+    }
+
+    pub fn uart_output(&self) -> String {
+        String::from_utf8_lossy(&self.uart).into_owned()
+    }
+
+    /// Record a message sent to the simulator
+    pub fn sent(&mut self, text: &str) {
+        self.message(">", text);
+    }
+
+    /// Record a message received from the simulator
+    pub fn received(&mut self, text: &str) {
+        self.message("<", text);
+    }
+
+    /// Remember the flash segments of a start packet
+    pub fn segments(&mut self, packet: &SimulationPacket) {
+        self.segments = json!(packet.extensions);
+    }
+
+    fn message(&mut self, direction: &str, text: &str) {
+        if self.messages.len() == MAX_MESSAGES {
+            self.messages.pop_front();
+        }
+        let mut line = format!("{} ", direction);
+        line.extend(text.chars().take(MAX_MESSAGE_LEN));
+        if text.chars().nth(MAX_MESSAGE_LEN).is_some() {
+            line.push_str("...");
+        }
+        self.messages.push_back(line);
+    }
+
+    /// Write everything needed to debug a failed run into `dir`
+    pub fn write(&self, dir: &Path, events: &[Value]) -> Result<()> {
+        std::fs::create_dir_all(dir)
+            .with_context(|| format!("Failed to create {}", dir.display()))?;
+        let write = |name: &str, contents: &[u8]| {
+            let path = dir.join(name);
+            std::fs::write(&path, contents)
+                .with_context(|| format!("Failed to write {}", path.display()))
+        };
+
+        write("uart.log", &self.uart)?;
+        let events: String = events.iter().map(|e| format!("{}\n", e)).collect();
+        write("events.ndjson", events.as_bytes())?;
+        let messages: String = self.messages.iter().map(|m| format!("{}\n", m)).collect();
+        write("websocket.log", messages.as_bytes())?;
+        write(
+            "segments.json",
+            serde_json::to_string_pretty(&self.segments)?.as_bytes(),
+        )?;
+        Ok(())
+    }
+}
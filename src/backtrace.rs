@@ -0,0 +1,229 @@
+//! Backtraces of firmware which panicked, picked out of its UART output and turned into
+//! functions and source lines with the debug info of the elf
+
+use crate::profile::Symbols;
+use addr2line::gimli::{self, EndianArcSlice, RunTimeEndian};
+use addr2line::object::{self, Object, ObjectSection};
+use regex::Regex;
+use std::sync::Arc;
+
+/// lines longer than this are cut short
+const MAX_LINE: usize = 4096;
+
+/// What a panic printed on the UART
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Panic {
+    /// code addresses, the innermost first
+    Backtrace(Vec<u32>),
+    /// where a Rust panic happened, as written by the compiler, often relative to the crate
+    Location {
+        file: String,
+        line: u32,
+        column: u32,
+    },
+}
+
+/// Watches UART output for the addresses printed when firmware panics: ESP-IDF's
+/// `Backtrace: 0x400d1234:0x3ffb5f00 ...` and register dumps, and the one address per line
+/// after a `Backtrace:` line of esp-backtrace. Also the source location of Rust panics
+pub struct Backtraces {
+    line: Vec<u8>,
+    /// addresses of the lines seen so far of a backtrace which may go on
+    trace: Vec<u32>,
+    /// in the lines of addresses after a `Backtrace:` line
+    in_list: bool,
+    pairs: Regex,
+    registers: Regex,
+    address: Regex,
+    locations: Vec<Regex>,
+}
+
+impl Default for Backtraces {
+    fn default() -> Self {
+        Self {
+            line: Vec::new(),
+            trace: Vec::new(),
+            in_list: false,
+            pairs: Regex::new(r"(0x[0-9a-fA-F]{8}):0x[0-9a-fA-F]{8}").unwrap(),
+            registers: Regex::new(r"\b(?:PC|MEPC|RA)\s*:\s*(0x[0-9a-fA-F]{8})").unwrap(),
+            address: Regex::new(r"^\s*(0x[0-9a-fA-F]{8})\b").unwrap(),
+            locations: [
+                // `panicked at src/main.rs:12:5:`, or `panicked at 'msg', src/main.rs:12:5`
+                // before Rust 1.73
+                r"panicked at (?:'.*', )?(\S+?):(\d+):(\d+)",
+                // older versions of esp-backtrace
+                r"panic occured in '([^']+)', at line (\d+), column (\d+)",
+            ]
+            .iter()
+            .map(|pattern| Regex::new(pattern).unwrap())
+            .collect(),
+        }
+    }
+}
+
+impl Backtraces {
+    /// Feed UART output, returning what the panics in it printed. Consecutive lines with
+    /// addresses make one backtrace, which ends at the next line without any
+    pub fn feed(&mut self, bytes: &[u8]) -> Vec<Panic> {
+        let mut panics = Vec::new();
+        for &b in bytes {
+            if b == b'\n' {
+                let line = String::from_utf8_lossy(&self.line).into_owned();
+                self.line.clear();
+                let addresses = self.addresses(line.trim_end());
+                if addresses.is_empty() && !self.trace.is_empty() {
+                    panics.push(Panic::Backtrace(std::mem::take(&mut self.trace)));
+                }
+                self.trace.extend(addresses);
+                panics.extend(self.location(&line));
+            } else if self.line.len() < MAX_LINE {
+                self.line.push(b);
+            }
+        }
+        panics
+    }
+
+    fn location(&self, line: &str) -> Option<Panic> {
+        let captures = self.locations.iter().find_map(|l| l.captures(line))?;
+        Some(Panic::Location {
+            file: captures[1].to_owned(),
+            line: captures[2].parse().ok()?,
+            column: captures[3].parse().ok()?,
+        })
+    }
+
+    /// The code addresses on a line of a panic
+    fn addresses(&mut self, line: &str) -> Vec<u32> {
+        let captures = if line.trim_start().starts_with("Backtrace:") {
+            // esp-backtrace lists the addresses on the lines which follow
+            self.in_list = line.trim() == "Backtrace:";
+            self.pairs.captures_iter(line).collect()
+        } else if self.in_list && line.trim().is_empty() {
+            Vec::new()
+        } else if self.in_list && self.address.is_match(line) {
+            self.address.captures_iter(line).collect()
+        } else {
+            self.in_list = false;
+            self.registers.captures_iter(line).collect::<Vec<_>>()
+        };
+        captures
+            .iter()
+            .filter_map(|c| u32::from_str_radix(&c[1][2..], 16).ok())
+            // the buses instructions are fetched from
+            .filter(|addr| addr >> 28 == 4)
+            .collect()
+    }
+}
+
+/// A function an address is in, innermost first when calls were inlined
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Frame {
+    pub function: Option<String>,
+    pub file: Option<String>,
+    pub line: Option<u32>,
+    /// inlined into the next frame, rather than called from it
+    pub inlined: bool,
+}
+
+/// Looks up addresses in the debug info of an elf, or its symbols if it was built without. More
+/// elfs loaded at other addresses, like the bootloader's, can be added
+pub struct Symbolizer {
+    images: Vec<Image>,
+}
+
+/// The debug info and symbols of one elf
+struct Image {
+    context: Option<addr2line::Context<EndianArcSlice<RunTimeEndian>>>,
+    symbols: Symbols,
+}
+
+impl Symbolizer {
+    /// Nothing is found in an elf which can't be parsed
+    pub fn new(elf: &[u8]) -> Self {
+        Self {
+            images: vec![Image::new(elf)],
+        }
+    }
+
+    /// Also look up addresses in `elf`, when the elfs added before don't know them
+    pub fn add(&mut self, elf: &[u8]) {
+        self.images.push(Image::new(elf));
+    }
+
+    /// The frames at `addr`, empty if it isn't in any function that is known
+    pub fn frames(&self, addr: u32) -> Vec<Frame> {
+        self.images
+            .iter()
+            .map(|image| image.frames(addr))
+            .find(|frames| !frames.is_empty())
+            .unwrap_or_default()
+    }
+}
+
+impl Image {
+    fn new(elf: &[u8]) -> Self {
+        let context = object::File::parse(elf).ok().and_then(|file| {
+            // `Arc`s rather than the `Rc`s of `Context::new`, for a context that can be sent
+            // between threads
+            let dwarf = gimli::Dwarf::load(|id| {
+                let data = file
+                    .section_by_name(id.name())
+                    .and_then(|section| section.uncompressed_data().ok())
+                    .unwrap_or_default();
+                Ok::<_, gimli::Error>(EndianArcSlice::new(
+                    Arc::from(&*data),
+                    RunTimeEndian::Little,
+                ))
+            });
+            addr2line::Context::from_dwarf(dwarf.ok()?).ok()
+        });
+        let symbols = xmas_elf::ElfFile::new(elf)
+            .map(|elf| Symbols::find(&elf))
+            .unwrap_or_default();
+        Self { context, symbols }
+    }
+
+    fn frames(&self, addr: u32) -> Vec<Frame> {
+        let mut frames = Vec::new();
+        if let Some(mut found) = self
+            .context
+            .as_ref()
+            .and_then(|c| c.find_frames(addr.into()).ok())
+        {
+            while let Ok(Some(frame)) = found.next() {
+                let function = frame.function.as_ref().and_then(|f| f.raw_name().ok());
+                let location = frame.location.as_ref();
+                frames.push(Frame {
+                    function: function.map(|name| demangle(&name)),
+                    file: location.and_then(|l| l.file).map(str::to_owned),
+                    line: location.and_then(|l| l.line),
+                    inlined: true,
+                });
+            }
+        }
+        if let Some(outermost) = frames.last_mut() {
+            outermost.inlined = false;
+            if outermost.function.is_none() {
+                outermost.function = self.symbols.function(addr).map(str::to_owned);
+            }
+        } else if let Some(function) = self.symbols.function(addr) {
+            frames.push(Frame {
+                function: Some(function.to_owned()),
+                file: None,
+                line: None,
+                inlined: false,
+            });
+        }
+        frames
+    }
+}
+
+/// Rust symbols without their hash, and C++ ones as they were written
+fn demangle(name: &str) -> String {
+    match rustc_demangle::try_demangle(name) {
+        Ok(demangled) => format!("{:#}", demangled),
+        Err(_) => {
+            addr2line::demangle(name, gimli::DW_LANG_C_plus_plus).unwrap_or_else(|| name.to_owned())
+        }
+    }
+}
@@ -0,0 +1,500 @@
+use crate::artifacts::RunLog;
+use crate::boot_hints::BootHints;
+use crate::expect::{Expectations, Outcome};
+use crate::image::{self, ImageArgs};
+use crate::keepalive::Keepalive;
+use crate::report::{self, ReportFormat, TestResult};
+use crate::session::{Kind, Session, Sessions};
+use crate::simulator_port::{SimulatorPort, SimulatorStream};
+use crate::{browser, container, repeats, ServerArgs, HANDSHAKE_TIMEOUT};
+use anyhow::{Context, Result};
+use espflash::Chip;
+use futures_util::StreamExt;
+use serde::Deserialize;
+use serde_json::Value;
+use std::collections::VecDeque;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::Duration;
+use tokio_tungstenite::{accept_async_with_config, WebSocketStream};
+use wokwi_server::error;
+use wokwi_server::messages::{self, Simulation};
+use wokwi_server::router::{Outbox, Router};
+use wokwi_server::{buses, protocol, vcd};
+
+/// how long to wait for the browser to connect for each chip
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(120);
+const DEFAULT_TEST_TIMEOUT: u64 = 60;
+
+/// Run a list of firmwares, checking their UART output
+#[derive(clap::Args, Debug)]
+// `-h` is taken by `--host`
+#[clap(disable_help_flag = true)]
+pub struct BatchArgs {
+    /// Print help information
+    #[clap(long, action = clap::ArgAction::Help)]
+    help: Option<bool>,
+
+    #[clap(flatten)]
+    server: ServerArgs,
+
+    /// format of the summary
+    #[clap(long, value_enum, default_value_t = ReportFormat::Json)]
+    format: ReportFormat,
+
+    /// write the summary to this file instead of printing it
+    #[clap(short, long)]
+    output: Option<PathBuf>,
+
+    /// print GitHub Actions annotations for failed tests
+    #[clap(long)]
+    gha_annotations: bool,
+
+    /// write the logs of each failed test to a subdirectory of this directory
+    #[clap(long)]
+    artifacts_dir: Option<PathBuf>,
+
+    /// run this many tests at once, each in its own browser tab on a port chosen by the OS.
+    /// Their UART output is only kept for the summary, as it would be interleaved
+    #[clap(short, long, default_value_t = 1, value_parser = clap::value_parser!(u16).range(1..))]
+    jobs: u16,
+
+    /// TOML file listing the tests to run
+    tests: PathBuf,
+}
+
+/// The test list, e.g.
+///
+/// ```toml
+/// [defaults]
+/// chip = "esp32"
+/// timeout = 30
+///
+/// [[test]]
+/// name = "blink"
+/// elf = "target/xtensa-esp32-espidf/debug/blink"
+/// expect = ["LED on", "LED off"]
+/// fail_on = ["panicked"]
+/// ```
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Manifest {
+    #[serde(default)]
+    defaults: Defaults,
+    #[serde(rename = "test", default)]
+    tests: Vec<TestSpec>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Defaults {
+    chip: Option<String>,
+    /// seconds
+    timeout: Option<u64>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct TestSpec {
+    name: Option<String>,
+    elf: PathBuf,
+    chip: Option<String>,
+    bootloader: Option<PathBuf>,
+    partition_table: Option<PathBuf>,
+    /// run the elf straight from flash, without a bootloader or partition table
+    #[serde(default)]
+    direct_boot: bool,
+    /// seconds
+    timeout: Option<u64>,
+    #[serde(default)]
+    expect: Vec<String>,
+    #[serde(default)]
+    fail_on: Vec<String>,
+}
+
+/// A test from the list, ready to run
+struct Test {
+    name: String,
+    image: ImageArgs,
+    timeout: Duration,
+    expect: Vec<String>,
+    fail_on: Vec<String>,
+}
+
+/// A simulator connected for a particular chip
+struct Connection {
+    chip: Chip,
+    websocket: WebSocketStream<SimulatorStream>,
+    /// the same router the server uses, whose outbox splits messages to fit what the simulator
+    /// accepts
+    router: Router<TestRun>,
+    session: Session,
+}
+
+/// The state of a running test, which the simulator's messages are dispatched to
+struct TestRun {
+    session: String,
+    /// whether to print the UART output, which would be interleaved with several jobs
+    echo: bool,
+    log: RunLog,
+    boot_hints: BootHints,
+    expectations: Expectations,
+    outcome: Option<Outcome>,
+}
+
+impl TestRun {
+    fn new(test: &Test, echo: bool) -> Self {
+        Self {
+            session: String::new(),
+            echo,
+            log: RunLog::new(),
+            boot_hints: BootHints::new(test.image.chip),
+            expectations: Expectations::new(test.expect.clone(), test.fail_on.clone()),
+            outcome: None,
+        }
+    }
+}
+
+impl Simulation for TestRun {
+    fn uart_data(&mut self, bytes: Vec<u8>, _out: &mut Outbox) -> error::Result<()> {
+        if self.echo {
+            let mut stdout = std::io::stdout();
+            stdout.write_all(&bytes)?;
+            stdout.flush()?;
+        }
+        self.log.uart(&bytes);
+        for hint in self.boot_hints.feed(&bytes) {
+            println!("[{}] Hint: {}", self.session, hint);
+        }
+        if self.outcome.is_none() {
+            self.outcome = self.expectations.feed(&bytes);
+        }
+        Ok(())
+    }
+
+    // a test has no GDB client, peripheral logs or custom chips to pass these on to
+    fn gdb_response(&mut self, _response: Vec<u8>, _out: &mut Outbox) -> error::Result<()> {
+        Ok(())
+    }
+
+    fn i2c_transaction(
+        &mut self,
+        _transaction: buses::I2cTransaction,
+        _out: &mut Outbox,
+    ) -> error::Result<()> {
+        Ok(())
+    }
+
+    fn spi_transaction(
+        &mut self,
+        _transaction: buses::SpiTransaction,
+        _out: &mut Outbox,
+    ) -> error::Result<()> {
+        Ok(())
+    }
+
+    fn pin_change(&mut self, _change: vcd::PinChange, _out: &mut Outbox) -> error::Result<()> {
+        Ok(())
+    }
+
+    fn paused(&mut self, _out: &mut Outbox) -> error::Result<()> {
+        Ok(())
+    }
+
+    fn chip_message(
+        &mut self,
+        _chip: &str,
+        _message: &Value,
+        _out: &mut Outbox,
+    ) -> error::Result<()> {
+        Ok(())
+    }
+}
+
+/// Run all the tests, returning the exit code for the server
+pub async fn run(mut args: BatchArgs) -> Result<i32> {
+    let mut tests = load(&args.tests)?;
+    for test in &mut tests {
+        test.image
+            .resolve()
+            .and_then(|_| test.image.validate(args.server.id.is_some()))
+            .with_context(|| format!("Invalid test `{}`", test.name))?;
+    }
+    crate::endpoint::check(&mut args.server, false).await;
+
+    let bind = args.server.bind_addr(container::in_container());
+    let sessions = Sessions::default();
+    let queue = Mutex::new((0..tests.len()).collect::<VecDeque<_>>());
+    let jobs = tests.len().clamp(1, args.jobs as usize);
+    let mut workers = Vec::with_capacity(jobs);
+    for _ in 0..jobs {
+        // several browser tabs can't share a port
+        let port = if jobs == 1 { args.server.port } else { 0 };
+        let server = SimulatorPort::serve(crate::listen(None, (bind, port), "--port").await?)?;
+        workers.push(worker(&args, server, &sessions, &tests, &queue, jobs == 1));
+    }
+    let mut results: Vec<_> = futures_util::future::join_all(workers)
+        .await
+        .into_iter()
+        .flatten()
+        .collect();
+    results.sort_by_key(|(i, _)| *i);
+    let results: Vec<_> = results.into_iter().map(|(_, result)| result).collect();
+    repeats::flush();
+
+    let suite = args
+        .tests
+        .file_stem()
+        .map(|s| s.to_string_lossy().into_owned())
+        .unwrap_or_else(|| "wokwi-server".to_owned());
+    let summary = report::render(args.format, &suite, &results);
+    match &args.output {
+        Some(path) => std::fs::write(path, summary)
+            .with_context(|| format!("Failed to write {}", path.display()))?,
+        None => println!("{}", summary),
+    }
+
+    let passed = results
+        .iter()
+        .filter(|r| r.outcome == Outcome::Passed)
+        .count();
+    println!("{} of {} tests passed", passed, results.len());
+    Ok(if passed == results.len() { 0 } else { 1 })
+}
+
+/// Run tests from the queue in one browser tab until none are left, returning each result with
+/// the test's position in the list
+async fn worker(
+    args: &BatchArgs,
+    mut server: SimulatorPort,
+    sessions: &Sessions,
+    tests: &[Test],
+    queue: &Mutex<VecDeque<usize>>,
+    echo: bool,
+) -> Vec<(usize, TestResult)> {
+    let mut connection = None;
+    let mut results = Vec::new();
+    loop {
+        // popped on its own, a guard in the `while let` would be held across the test
+        let next = queue.lock().unwrap().pop_front();
+        let Some(i) = next else {
+            break;
+        };
+        let test = &tests[i];
+        println!("=== {}", test.name);
+        let mut run = TestRun::new(test, echo);
+        let result = run_test(args, &mut server, sessions, &mut connection, test, &mut run).await;
+        let session = connection.as_ref().map(|c| c.session.id.clone());
+        let outcome = match result {
+            Ok(outcome) => outcome,
+            Err(e) => {
+                connection = None;
+                Outcome::Error(format!("{:#}", e))
+            }
+        };
+        match &outcome {
+            Outcome::Passed => println!("=== {}: passed", test.name),
+            Outcome::Failed(reason) => println!("=== {}: failed, {}", test.name, reason),
+            Outcome::Error(reason) => println!("=== {}: error, {}", test.name, reason),
+        }
+        let result = TestResult {
+            name: test.name.clone(),
+            outcome,
+            duration: run.log.started.elapsed(),
+            output: run.log.uart_output(),
+        };
+        if let (Some(dir), false) = (&args.artifacts_dir, result.outcome == Outcome::Passed) {
+            let dir = dir.join(artifact_name(&test.name));
+            let events = session
+                .map(|id| sessions.recent_events(&id))
+                .unwrap_or_default();
+            match run.log.write(&dir, &events) {
+                Ok(_) => println!("Wrote failure artifacts to {}", dir.display()),
+                Err(e) => println!("Failed to write failure artifacts: {:#}", e),
+            }
+        }
+        if args.gha_annotations {
+            report::annotate(&result);
+        }
+        results.push((i, result));
+    }
+
+    if let Some(mut connection) = connection {
+        connection.websocket.close(None).await.ok();
+    }
+    results
+}
+
+/// Read the test list, resolving paths relative to the file
+fn load(path: &Path) -> Result<Vec<Test>> {
+    let contents = std::fs::read_to_string(path)
+        .with_context(|| format!("Failed to read {}", path.display()))?;
+    let manifest: Manifest = toml::from_str(&contents)
+        .with_context(|| format!("Invalid test list {}", path.display()))?;
+    let base = path.parent().unwrap_or_else(|| Path::new("."));
+
+    manifest
+        .tests
+        .into_iter()
+        .enumerate()
+        .map(|(i, spec)| {
+            let chip = spec
+                .chip
+                .or_else(|| manifest.defaults.chip.clone())
+                .with_context(|| format!("No chip given for test {}", i + 1))?;
+            let chip = chip
+                .parse::<Chip>()
+                .map_err(|_| anyhow::anyhow!("Unknown chip `{}`", chip))?;
+            // resolved against the directory of the list, as the server does with --project-dir
+            let mut image = ImageArgs::new(chip, spec.elf.clone());
+            image.project_dir = Some(base.to_owned());
+            image.bootloader = spec.bootloader;
+            image.partition_table = spec.partition_table;
+            image.direct_boot = spec.direct_boot;
+            let timeout = spec
+                .timeout
+                .or(manifest.defaults.timeout)
+                .unwrap_or(DEFAULT_TEST_TIMEOUT);
+
+            Ok(Test {
+                name: spec.name.unwrap_or_else(|| spec.elf.display().to_string()),
+                image,
+                timeout: Duration::from_secs(timeout),
+                expect: spec.expect,
+                fail_on: spec.fail_on,
+            })
+        })
+        .collect()
+}
+
+async fn run_test(
+    args: &BatchArgs,
+    server: &mut SimulatorPort,
+    sessions: &Sessions,
+    connection: &mut Option<Connection>,
+    test: &Test,
+    run: &mut TestRun,
+) -> Result<Outcome> {
+    // a different chip needs a different project, and so a new browser session
+    if matches!(connection, Some(c) if c.chip != test.image.chip) {
+        if let Some(mut old) = connection.take() {
+            old.websocket.close(None).await.ok();
+        }
+    }
+    let conn = match connection {
+        Some(conn) => conn,
+        None => connection.insert(connect(args, server, sessions, test.image.chip).await?),
+    };
+    run.session = conn.session.id.clone();
+
+    let keepalive = Keepalive::new(args.server.keepalive);
+    let building = image::start_packet(&test.image, &conn.session.id);
+    let simdata = keepalive.during(&mut conn.websocket, building).await??;
+    simdata
+        .validate()
+        .context("Refusing to send an invalid start packet")?;
+    run.log.segments(&simdata);
+    conn.router.outbox.send_to_simulator(&simdata)?;
+    crate::send_queued(
+        &mut conn.router.outbox,
+        &mut conn.websocket,
+        &mut run.log,
+        keepalive,
+    )
+    .await?;
+
+    let deadline = tokio::time::sleep(test.timeout);
+    tokio::pin!(deadline);
+    loop {
+        tokio::select! {
+            msg = conn.websocket.next() => {
+                let msg = match msg {
+                    Some(msg) => msg?,
+                    None => anyhow::bail!("Simulator disconnected"),
+                };
+                if msg.is_close() {
+                    anyhow::bail!("Simulator disconnected");
+                }
+                if !msg.is_text() {
+                    continue;
+                }
+                let text = msg.to_text()?;
+                run.log.received(text);
+                let session = &conn.session.id;
+                match serde_json::from_str::<Value>(text) {
+                    Ok(v) => match conn.router.dispatch(run, &v) {
+                        Ok(true) => {}
+                        Ok(false) => repeats::report(session, format_args!("Ignoring unexpected message from simulator: {}", v)),
+                        Err(e) => repeats::report(session, format_args!("Skipping malformed message from simulator: {:#}", e)),
+                    },
+                    Err(e) => repeats::report(session, format_args!("Skipping malformed message from simulator: {}", e)),
+                }
+                if let Some(outcome) = run.outcome.take() {
+                    return Ok(outcome);
+                }
+                crate::send_queued(&mut conn.router.outbox, &mut conn.websocket, &mut run.log, keepalive)
+                    .await?;
+            }
+            _ = &mut deadline => return Ok(run.expectations.timed_out()),
+        }
+    }
+}
+
+/// A directory name for the artifacts of a test
+fn artifact_name(test: &str) -> String {
+    test.chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '-' || c == '.' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect()
+}
+
+/// Wait for the browser to connect a simulator for `chip`
+async fn connect(
+    args: &BatchArgs,
+    server: &mut SimulatorPort,
+    sessions: &Sessions,
+    chip: Chip,
+) -> Result<Connection> {
+    let url = crate::simulation_url(&args.server, chip, server.local_addr().port());
+    println!(
+        "Open the following link in the browser\r\n\r\n{}\r\n\r\n",
+        url
+    );
+    if !args.server.no_open {
+        browser::open(&url, args.server.browser.as_deref());
+    }
+
+    let (stream, peer) = tokio::time::timeout(CONNECT_TIMEOUT, server.accept())
+        .await
+        .context("Timed out waiting for the simulator to connect")??;
+
+    let session = sessions.open(Kind::Simulator, peer);
+    println!("[{}] Simulation client connected from {}", session.id, peer);
+    let mut websocket = tokio::time::timeout(
+        HANDSHAKE_TIMEOUT,
+        accept_async_with_config(stream, Some(protocol::websocket_config())),
+    )
+    .await
+    .context("Timed out during websocket handshake")??;
+    let msg = tokio::time::timeout(HANDSHAKE_TIMEOUT, websocket.next())
+        .await
+        .context("Timed out waiting for hello message")?
+        .ok_or_else(|| anyhow::anyhow!("Simulator disconnected before sending hello message"))??;
+    let capabilities =
+        crate::accept_hello(&mut websocket, &msg, &session, args.server.embed_protocol).await?;
+    let mut router = messages::router();
+    router.outbox.negotiated(capabilities);
+
+    Ok(Connection {
+        chip,
+        websocket,
+        router,
+        session,
+    })
+}
@@ -0,0 +1,185 @@
+//! `--benchmark`: boots the firmware a number of times, timing each boot from the start packet
+//! being delivered to the first UART output and to the `--ready-marker`, and reports the mean and
+//! standard deviation of the runs
+
+use anyhow::{Context, Result};
+use serde_json::{json, Value};
+use std::path::Path;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use wokwi_server::SimulationPacket;
+
+/// The times of one boot
+#[derive(Debug, Clone, Copy)]
+struct Sample {
+    first_output: Duration,
+    ready: Option<Duration>,
+}
+
+pub struct Benchmark {
+    runs: u32,
+    ready_marker: Option<String>,
+    /// sent again to boot the firmware for the next run
+    packet: Arc<SimulationPacket>,
+    /// when the start packet of the current run was delivered, `None` until it has been
+    started: Option<Instant>,
+    first_output: Option<Duration>,
+    /// the end of the current run's output, long enough to find the marker across messages
+    tail: Vec<u8>,
+    samples: Vec<Sample>,
+}
+
+/// What the server should do after some UART output
+pub enum Step {
+    Continue,
+    /// boot the firmware again by sending this
+    Restart(Arc<SimulationPacket>),
+    /// all the runs are done
+    Done,
+}
+
+impl Benchmark {
+    pub fn new(runs: u32, ready_marker: Option<String>, packet: Arc<SimulationPacket>) -> Self {
+        Self {
+            runs,
+            ready_marker,
+            packet,
+            started: None,
+            first_output: None,
+            tail: Vec::new(),
+            samples: Vec::new(),
+        }
+    }
+
+    /// The start packet of a run has been delivered, which starts its clock
+    pub fn delivered(&mut self) {
+        if self.started.is_none() && !self.is_done() {
+            self.started = Some(Instant::now());
+        }
+    }
+
+    fn is_done(&self) -> bool {
+        self.samples.len() as u32 >= self.runs
+    }
+
+    /// Time UART output of the current run, returning what to do next. Output before the start
+    /// packet is delivered is from the previous boot
+    pub fn feed(&mut self, bytes: &[u8], label: &str) -> Step {
+        let Some(started) = self.started else {
+            return Step::Continue;
+        };
+        let elapsed = started.elapsed();
+        let first_output = *self.first_output.get_or_insert(elapsed);
+        let ready = match &self.ready_marker {
+            None => None,
+            Some(marker) => {
+                self.tail.extend_from_slice(bytes);
+                let marker = marker.as_bytes();
+                if !self.tail.windows(marker.len()).any(|w| w == marker) {
+                    let keep = self.tail.len().saturating_sub(marker.len() - 1);
+                    self.tail.drain(..keep);
+                    return Step::Continue;
+                }
+                Some(elapsed)
+            }
+        };
+
+        let sample = Sample {
+            first_output,
+            ready,
+        };
+        self.samples.push(sample);
+        println!(
+            "[{}] Benchmark run {}/{}: first output after {}{}",
+            label,
+            self.samples.len(),
+            self.runs,
+            millis(first_output),
+            ready.map_or_else(String::new, |ready| format!(
+                ", ready after {}",
+                millis(ready)
+            ))
+        );
+        self.started = None;
+        self.first_output = None;
+        self.tail.clear();
+        match self.is_done() {
+            true => Step::Done,
+            false => Step::Restart(self.packet.clone()),
+        }
+    }
+
+    /// Print the mean and standard deviation of the runs
+    pub fn print(&self, label: &str) {
+        println!("[{}] Benchmark of {} runs:", label, self.samples.len());
+        let (mean, stddev) = spread(&self.first_output());
+        println!(
+            "[{}]   first output  {} ± {}",
+            label,
+            millis(mean),
+            millis(stddev)
+        );
+        if let Some(ready) = self.ready() {
+            let (mean, stddev) = spread(&ready);
+            println!(
+                "[{}]   ready         {} ± {}",
+                label,
+                millis(mean),
+                millis(stddev)
+            );
+        }
+    }
+
+    /// The runs as JSON for `--benchmark-json`, in milliseconds
+    pub fn to_json(&self) -> Value {
+        let summary = |times: &[Duration]| {
+            let (mean, stddev) = spread(times);
+            json!({
+                "mean_ms": mean.as_secs_f64() * 1000.0,
+                "stddev_ms": stddev.as_secs_f64() * 1000.0,
+                "runs_ms": times.iter().map(|t| t.as_secs_f64() * 1000.0).collect::<Vec<_>>(),
+            })
+        };
+        json!({
+            "runs": self.samples.len(),
+            "ready_marker": self.ready_marker,
+            "first_output": summary(&self.first_output()),
+            "ready": self.ready().as_deref().map(summary),
+        })
+    }
+
+    pub fn write(&self, path: &Path) -> Result<()> {
+        std::fs::write(path, serde_json::to_string_pretty(&self.to_json())?)
+            .with_context(|| format!("Failed to write {}", path.display()))
+    }
+
+    fn first_output(&self) -> Vec<Duration> {
+        self.samples.iter().map(|s| s.first_output).collect()
+    }
+
+    fn ready(&self) -> Option<Vec<Duration>> {
+        self.ready_marker.as_ref()?;
+        self.samples.iter().map(|s| s.ready).collect()
+    }
+}
+
+/// The mean and the sample standard deviation of some times
+fn spread(times: &[Duration]) -> (Duration, Duration) {
+    if times.is_empty() {
+        return (Duration::ZERO, Duration::ZERO);
+    }
+    let secs: Vec<f64> = times.iter().map(Duration::as_secs_f64).collect();
+    let mean = secs.iter().sum::<f64>() / secs.len() as f64;
+    let variance = match secs.len() {
+        1 => 0.0,
+        n => secs.iter().map(|s| (s - mean).powi(2)).sum::<f64>() / (n - 1) as f64,
+    };
+    (
+        Duration::from_secs_f64(mean),
+        Duration::from_secs_f64(variance.sqrt()),
+    )
+}
+
+fn millis(duration: Duration) -> String {
+    format!("{:.1} ms", duration.as_secs_f64() * 1000.0)
+}
@@ -0,0 +1,107 @@
+use espflash::Chip;
+use regex::Regex;
+
+/// lines longer than this are discarded rather than buffered indefinitely
+const MAX_LINE: usize = 4096;
+/// resets seen while booting before the firmware is considered to be in a boot loop
+const BOOT_LOOP_RESETS: u32 = 3;
+
+/// UART output which means the firmware failed to boot, and what to do about it
+struct Signature {
+    pattern: &'static str,
+    /// `{chip}` is replaced with the selected chip
+    hint: &'static str,
+}
+
+const SIGNATURES: &[Signature] = &[
+    Signature {
+        pattern: r"invalid header: 0x",
+        hint: "The ROM bootloader found no valid image in flash. Check that the firmware was built for {chip} (--chip), and that --bootloader and --partition-table match it",
+    },
+    Signature {
+        pattern: r"flash read err",
+        hint: "The ROM bootloader couldn't read the second stage bootloader from flash. It is missing or was built for a different chip, pass the one from your build with --bootloader",
+    },
+    Signature {
+        pattern: r"(?i)mismatch chip id",
+        hint: "The image was built for a different chip than {chip}, select the chip the firmware targets with --chip",
+    },
+    Signature {
+        pattern: r"Detected size\(\d+k\) smaller than the size in the binary image header",
+        hint: "The image header asks for more flash than the simulated {chip} has, rebuild with a smaller flash size (e.g. CONFIG_ESPTOOLPY_FLASHSIZE_4MB)",
+    },
+    Signature {
+        pattern: r"No bootable app partitions",
+        hint: "The bootloader found no app partition to start, check the partition table given with --partition-table",
+    },
+    Signature {
+        pattern: r"doesn't fit in partition length",
+        hint: "The application is larger than its partition, use a partition table with a bigger app partition (--partition-table)",
+    },
+    Signature {
+        pattern: r"Image requires chip rev",
+        hint: "The image requires a newer chip revision than the simulator provides, lower the minimum chip revision in the firmware's configuration and rebuild",
+    },
+];
+
+/// Watches UART output for signs the firmware failed to boot, giving each hint once
+pub struct BootHints {
+    chip: Chip,
+    signatures: Vec<(Regex, &'static str)>,
+    shown: Vec<&'static str>,
+    /// `rst:0x..` lines printed by the ROM
+    resets: u32,
+    line: Vec<u8>,
+}
+
+impl BootHints {
+    pub fn new(chip: Chip) -> Self {
+        Self {
+            chip,
+            signatures: SIGNATURES
+                .iter()
+                .map(|s| (Regex::new(s.pattern).unwrap(), s.hint))
+                .collect(),
+            shown: Vec::new(),
+            resets: 0,
+            line: Vec::new(),
+        }
+    }
+
+    /// Feed UART output, returning hints for any failures it shows
+    pub fn feed(&mut self, bytes: &[u8]) -> Vec<String> {
+        let mut hints = Vec::new();
+        for &b in bytes {
+            if b == b'\n' {
+                let line = std::mem::take(&mut self.line);
+                hints.extend(self.check(&String::from_utf8_lossy(&line)));
+            } else if self.line.len() < MAX_LINE {
+                self.line.push(b);
+            }
+        }
+        hints
+    }
+
+    fn check(&mut self, line: &str) -> Option<String> {
+        if line.starts_with("rst:0x") {
+            self.resets += 1;
+            if self.resets == BOOT_LOOP_RESETS {
+                return Some(format!(
+                    "The firmware has reset {} times, it may be stuck in a boot loop. Look for a panic or watchdog message above the resets, and check that the flash size and --chip ({}) match the build",
+                    self.resets, self.chip
+                ));
+            }
+            return None;
+        }
+        let hint = self
+            .signatures
+            .iter()
+            .find(|(regex, _)| regex.is_match(line))
+            .map(|(_, hint)| *hint)?;
+        if self.shown.contains(&hint) {
+            return None;
+        }
+        self.shown.push(hint);
+        Some(hint.replace("{chip}", &self.chip.to_string()))
+    }
+}
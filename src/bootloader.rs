@@ -0,0 +1,83 @@
+use espflash::Chip;
+
+const IMAGE_MAGIC: u8 = 0xE9;
+/// the header all chips' images start with
+const COMMON_HEADER_LEN: usize = 8;
+/// the common header and the extended header, which holds the chip ID
+const HEADER_LEN: usize = 24;
+const CHIP_ID_OFFSET: usize = 12;
+
+/// Why a bootloader image can't boot the selected chip
+#[derive(Debug, PartialEq, Eq)]
+pub enum Problem {
+    /// too short to hold an image header
+    Truncated(usize),
+    /// doesn't start with the image magic byte
+    BadMagic(u8),
+    /// the chip ID in the extended header belongs to another chip
+    WrongChip { found: u16 },
+}
+
+impl std::fmt::Display for Problem {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Problem::Truncated(len) => write!(f, "it is only {} bytes long", len),
+            Problem::BadMagic(magic) => write!(
+                f,
+                "it starts with {:#04x} rather than the image magic {:#04x}",
+                magic, IMAGE_MAGIC
+            ),
+            Problem::WrongChip { found } => match chip_for_id(*found) {
+                Some(chip) => write!(f, "it was built for {}", chip),
+                None => write!(f, "it was built for an unknown chip (ID {})", found),
+            },
+        }
+    }
+}
+
+/// The chip ID ESP-IDF writes to the extended image header, ESP8266 images don't have one
+pub fn chip_id(chip: Chip) -> Option<u16> {
+    match chip {
+        Chip::Esp32 => Some(0),
+        Chip::Esp32s2 => Some(2),
+        Chip::Esp32c3 => Some(5),
+        Chip::Esp32s3 => Some(9),
+        Chip::Esp32c2 => Some(12),
+        Chip::Esp8266 => None,
+    }
+}
+
+fn chip_for_id(id: u16) -> Option<Chip> {
+    [
+        Chip::Esp32,
+        Chip::Esp32s2,
+        Chip::Esp32c3,
+        Chip::Esp32s3,
+        Chip::Esp32c2,
+    ]
+    .into_iter()
+    .find(|&chip| chip_id(chip) == Some(id))
+}
+
+/// Check that `image` has a valid header for a bootloader which runs on `chip`
+pub fn check(chip: Chip, image: &[u8]) -> Result<(), Problem> {
+    let expected = chip_id(chip);
+    let header_len = if expected.is_some() {
+        HEADER_LEN
+    } else {
+        COMMON_HEADER_LEN
+    };
+    if image.len() < header_len {
+        return Err(Problem::Truncated(image.len()));
+    }
+    if image[0] != IMAGE_MAGIC {
+        return Err(Problem::BadMagic(image[0]));
+    }
+    if let Some(expected) = expected {
+        let found = u16::from_le_bytes([image[CHIP_ID_OFFSET], image[CHIP_ID_OFFSET + 1]]);
+        if found != expected {
+            return Err(Problem::WrongChip { found });
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,67 @@
+//! Where breakpoints go for `--break-at` and `--gdb-init` scripts, found in the elf
+
+use crate::error::{bail, Result};
+use xmas_elf::sections::SectionData;
+use xmas_elf::symbol_table::Entry;
+use xmas_elf::ElfFile;
+
+/// `e_machine` of RISC-V in the elf header, the other chips are Xtensa
+const EM_RISCV: u16 = 243;
+const E_MACHINE_OFFSET: usize = 18;
+
+/// Where the firmware should stop
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Breakpoint {
+    /// what was given, a symbol or an address
+    pub target: String,
+    pub addr: u32,
+}
+
+/// Find `target` in the elf, either an address in hex (`0x400d1234`) or the name of a symbol,
+/// mangled or demangled without its hash (`blinky::main`)
+pub fn resolve(elf: &[u8], target: &str) -> Result<Breakpoint> {
+    let hex = target
+        .strip_prefix("0x")
+        .or_else(|| target.strip_prefix("0X"));
+    if let Some(hex) = hex {
+        let Ok(addr) = u32::from_str_radix(hex, 16) else {
+            bail!(Image, "`{}` is not an address", target);
+        };
+        return Ok(Breakpoint {
+            target: target.to_owned(),
+            addr,
+        });
+    }
+
+    let file = ElfFile::new(elf).map_err(|e| crate::WokwiServerError::Image(e.to_owned()))?;
+    for section in file.section_iter() {
+        let Ok(SectionData::SymbolTable32(entries)) = section.get_data(&file) else {
+            continue;
+        };
+        for entry in entries {
+            let Ok(name) = entry.get_name(&file) else {
+                continue;
+            };
+            let matches =
+                name == target || format!("{:#}", rustc_demangle::demangle(name)) == target;
+            if matches && entry.value() != 0 {
+                return Ok(Breakpoint {
+                    target: target.to_owned(),
+                    addr: entry.value() as u32,
+                });
+            }
+        }
+    }
+    bail!(Image, "There is no symbol {} in the elf", target)
+}
+
+/// The kind GDB gives breakpoints in the firmware, the length of the instruction they replace
+pub fn kind(elf: &[u8]) -> u8 {
+    let machine = elf
+        .get(E_MACHINE_OFFSET..E_MACHINE_OFFSET + 2)
+        .map(|bytes| u16::from_le_bytes([bytes[0], bytes[1]]));
+    match machine {
+        Some(EM_RISCV) => 4,
+        _ => 3,
+    }
+}
@@ -0,0 +1,226 @@
+use anyhow::Result;
+use std::collections::VecDeque;
+use tokio::sync::mpsc;
+use tokio_util::sync::CancellationToken;
+use wokwi_server::GdbInstruction;
+
+/// how many packets can wait for a GDB client or simulator to pick them up
+const LINK_CAPACITY: usize = 16;
+
+enum Event {
+    GdbAttached {
+        id: String,
+        responses: mpsc::Sender<Vec<u8>>,
+    },
+    GdbDetached {
+        id: String,
+    },
+    SimulatorAttached {
+        id: String,
+        commands: mpsc::Sender<GdbInstruction>,
+    },
+    SimulatorDetached {
+        id: String,
+    },
+    Command(GdbInstruction),
+    Response(Vec<u8>),
+}
+
+/// Hands out links between GDB clients and simulators, either of which can come and go
+#[derive(Clone)]
+pub struct Broker {
+    events: mpsc::UnboundedSender<Event>,
+}
+
+/// The task routing packets between whichever GDB client and simulator are attached
+pub struct Routing {
+    events: mpsc::UnboundedReceiver<Event>,
+    /// commands to hold while no simulator is attached
+    queue_len: usize,
+}
+
+/// A GDB client's end of the broker, detached when dropped
+pub struct GdbLink {
+    id: String,
+    events: mpsc::UnboundedSender<Event>,
+    /// responses from the simulator
+    pub responses: mpsc::Receiver<Vec<u8>>,
+}
+
+/// A simulator's end of the broker, detached when dropped
+pub struct SimulatorLink {
+    id: String,
+    events: mpsc::UnboundedSender<Event>,
+    /// commands from the GDB client
+    pub commands: mpsc::Receiver<GdbInstruction>,
+}
+
+impl Broker {
+    pub fn new(queue_len: usize) -> (Self, Routing) {
+        let (events, receiver) = mpsc::unbounded_channel();
+        (
+            Self { events },
+            Routing {
+                events: receiver,
+                queue_len,
+            },
+        )
+    }
+
+    /// Route responses to this GDB client instead of any earlier one
+    pub fn attach_gdb(&self, id: &str) -> GdbLink {
+        let (responses, receiver) = mpsc::channel(LINK_CAPACITY);
+        self.events
+            .send(Event::GdbAttached {
+                id: id.to_owned(),
+                responses,
+            })
+            .ok();
+        GdbLink {
+            id: id.to_owned(),
+            events: self.events.clone(),
+            responses: receiver,
+        }
+    }
+
+    /// Route commands to this simulator instead of any earlier one, starting with those held
+    /// while none was attached
+    pub fn attach_simulator(&self, id: &str) -> SimulatorLink {
+        let (commands, receiver) = mpsc::channel(LINK_CAPACITY);
+        self.events
+            .send(Event::SimulatorAttached {
+                id: id.to_owned(),
+                commands,
+            })
+            .ok();
+        SimulatorLink {
+            id: id.to_owned(),
+            events: self.events.clone(),
+            commands: receiver,
+        }
+    }
+}
+
+impl GdbLink {
+    /// Pass a command on to the simulator, never waits
+    pub fn send(&self, instruction: GdbInstruction) {
+        self.events.send(Event::Command(instruction)).ok();
+    }
+}
+
+impl Drop for GdbLink {
+    fn drop(&mut self) {
+        let id = std::mem::take(&mut self.id);
+        self.events.send(Event::GdbDetached { id }).ok();
+    }
+}
+
+impl SimulatorLink {
+    /// Pass a response on to the GDB client, never waits
+    pub fn respond(&self, response: Vec<u8>) {
+        self.events.send(Event::Response(response)).ok();
+    }
+}
+
+impl Drop for SimulatorLink {
+    fn drop(&mut self) {
+        let id = std::mem::take(&mut self.id);
+        self.events.send(Event::SimulatorDetached { id }).ok();
+    }
+}
+
+impl Routing {
+    pub async fn run(mut self, shutdown: CancellationToken) -> Result<()> {
+        let mut gdb: Option<(String, mpsc::Sender<Vec<u8>>)> = None;
+        let mut simulator: Option<(String, mpsc::Sender<GdbInstruction>)> = None;
+        let mut held = VecDeque::new();
+        loop {
+            let event = tokio::select! {
+                event = self.events.recv() => match event {
+                    Some(event) => event,
+                    None => return Ok(()),
+                },
+                _ = shutdown.cancelled() => return Ok(()),
+            };
+            match event {
+                Event::GdbAttached { id, responses } => {
+                    // whatever the last client asked for is no use to this one
+                    held.clear();
+                    gdb = Some((id, responses));
+                }
+                Event::GdbDetached { id } => {
+                    if gdb.as_ref().is_some_and(|(current, _)| *current == id) {
+                        held.clear();
+                        gdb = None;
+                    }
+                }
+                Event::SimulatorAttached { id, commands } => {
+                    if !held.is_empty() {
+                        println!(
+                            "[{}] Sending {} GDB packet(s) held while no simulation was running",
+                            id,
+                            held.len()
+                        );
+                    }
+                    for instruction in held.drain(..) {
+                        if commands.send(instruction).await.is_err() {
+                            break;
+                        }
+                    }
+                    simulator = Some((id, commands));
+                }
+                Event::SimulatorDetached { id } => {
+                    if simulator
+                        .as_ref()
+                        .is_some_and(|(current, _)| *current == id)
+                    {
+                        simulator = None;
+                    }
+                }
+                Event::Command(instruction) => match &simulator {
+                    Some((_, commands)) => {
+                        if commands.send(instruction).await.is_err() {
+                            simulator = None;
+                        }
+                    }
+                    None => self.hold(&mut held, instruction, gdb.as_ref()),
+                },
+                Event::Response(response) => {
+                    if let Some((_, responses)) = &gdb {
+                        if responses.send(response).await.is_err() {
+                            gdb = None;
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    /// Keep a command until a simulator attaches, dropping the oldest beyond the queue length
+    fn hold(
+        &self,
+        held: &mut VecDeque<GdbInstruction>,
+        instruction: GdbInstruction,
+        gdb: Option<&(String, mpsc::Sender<Vec<u8>>)>,
+    ) {
+        let id = gdb.map_or("gdb", |(id, _)| id.as_str());
+        if self.queue_len == 0 {
+            println!("[{}] No simulation is running, dropping GDB packet", id);
+            return;
+        }
+        if held.is_empty() {
+            println!(
+                "[{}] No simulation is running, holding GDB packets until one starts",
+                id
+            );
+        }
+        held.push_back(instruction);
+        if held.len() > self.queue_len {
+            held.pop_front();
+            println!(
+                "[{}] Dropped a held GDB packet, more than {} are waiting",
+                id, self.queue_len
+            );
+        }
+    }
+}
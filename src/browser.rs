@@ -0,0 +1,106 @@
+use crate::command;
+use anyhow::{Context, Result};
+use std::io::Write;
+use std::process::{Command, Stdio};
+
+/// The browser command to use: the one given with `--browser` as it is, or else the first of the
+/// colon separated commands in `$BROWSER`. Only `$BROWSER` is split, as a command given on its own
+/// can have a colon in it, like `C:\...\chrome.exe`
+pub fn command(browser: Option<&str>) -> Option<String> {
+    match browser {
+        Some(browser) => Some(browser.to_owned()),
+        None => std::env::var("BROWSER")
+            .ok()?
+            .split(':')
+            .find(|command| !command.trim().is_empty())
+            .map(str::to_owned),
+    }
+}
+
+/// Open the url in the user's browser, explaining why if that isn't possible
+///
+/// A custom browser command may contain a `{url}` (or `%s`) placeholder, otherwise the url is
+/// appended to it.
+pub fn open(url: &str, browser: Option<&str>) {
+    if let Some(browser) = command(browser) {
+        if let Err(e) = run_browser_command(&browser, url) {
+            println!(
+                "Failed to run browser command ({:#}), open the link above manually.",
+                e
+            );
+        }
+        return;
+    }
+    if !has_display() {
+        println!("No graphical session detected, open the link above manually.");
+        return;
+    }
+    if let Err(e) = opener::open_browser(url) {
+        println!(
+            "Failed to open the browser ({}), open the link above manually.",
+            e
+        );
+    }
+}
+
+fn run_browser_command(browser: &str, url: &str) -> Result<()> {
+    let browser = browser.replace("%s", "{url}");
+    let mut words = command::split(&browser);
+    if !words.iter().any(|w| w.contains("{url}")) {
+        words.push("{url}".to_string());
+    }
+    let words = command::expand(&words, &[("url", url)]);
+
+    let (program, args) = words.split_first().context("Browser command is empty")?;
+    Command::new(program)
+        .args(args)
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()
+        .with_context(|| format!("failed to launch `{}`", program))?;
+    Ok(())
+}
+
+/// Whether there is likely a display to open a browser on
+fn has_display() -> bool {
+    if cfg!(target_os = "linux") && std::env::var_os("BROWSER").is_none() {
+        std::env::var_os("DISPLAY").is_some() || std::env::var_os("WAYLAND_DISPLAY").is_some()
+    } else {
+        true
+    }
+}
+
+/// Copy text to the system clipboard using the platform's clipboard utility
+pub fn copy_to_clipboard(text: &str) -> Result<()> {
+    let candidates: &[&[&str]] = if cfg!(target_os = "macos") {
+        &[&["pbcopy"]]
+    } else if cfg!(windows) {
+        &[&["clip"]]
+    } else {
+        &[
+            &["wl-copy"],
+            &["xclip", "-selection", "clipboard"],
+            &["xsel", "--clipboard", "--input"],
+        ]
+    };
+
+    for command in candidates {
+        let child = Command::new(command[0])
+            .args(&command[1..])
+            .stdin(Stdio::piped())
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn();
+        if let Ok(mut child) = child {
+            if let Some(mut stdin) = child.stdin.take() {
+                stdin.write_all(text.as_bytes())?;
+            }
+            if child.wait()?.success() {
+                return Ok(());
+            }
+        }
+    }
+
+    anyhow::bail!("No clipboard utility found")
+}
@@ -0,0 +1,99 @@
+//! `bug-report`: the logs of the last sessions and a description of the environment, zipped up
+//! to attach to an issue
+
+use crate::last_session;
+use anyhow::{Context, Result};
+use flate2::write::DeflateEncoder;
+use flate2::{Compression, Crc};
+use std::io::Write;
+use std::path::PathBuf;
+
+const ISSUES: &str = "https://github.com/MabezDev/wokwi-server/issues";
+
+/// Bundle the log of the last session into a zip to attach to an issue
+#[derive(clap::Args, Debug)]
+pub struct BugReportArgs {
+    /// where to write the zip
+    #[clap(short, long, default_value = "wokwi-server-bug-report.zip")]
+    output: PathBuf,
+}
+
+pub fn run(args: BugReportArgs) -> Result<i32> {
+    let dir = last_session::dir()
+        .context("There is nowhere to look for the session log, set $HOME or $XDG_STATE_HOME")?;
+    let mut environment = last_session::environment().join("\n");
+    environment.push('\n');
+    let mut files = vec![("environment.txt", environment.into_bytes())];
+    for name in [last_session::FILE, last_session::PREVIOUS] {
+        if let Ok(log) = std::fs::read(dir.join(name)) {
+            files.push((name, log));
+        }
+    }
+    if files.len() == 1 {
+        println!(
+            "Warning: no session has been logged in {}, run the command that went wrong again first",
+            dir.display()
+        );
+    }
+
+    std::fs::write(&args.output, zip(&files)?)
+        .with_context(|| format!("Failed to write {}", args.output.display()))?;
+    println!(
+        "Wrote {}. Check it holds nothing you'd rather not share, then attach it to an issue at {}",
+        args.output.display(),
+        ISSUES
+    );
+    Ok(0)
+}
+
+/// A zip archive of deflated `files`
+fn zip(files: &[(&str, Vec<u8>)]) -> Result<Vec<u8>> {
+    // 1980-01-01, the earliest date a zip can hold, as nothing needs the real one
+    const DATE: u16 = 0x21;
+    let mut archive = Vec::new();
+    let mut directory = Vec::new();
+    for (name, data) in files {
+        let mut encoder = DeflateEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(data)?;
+        let compressed = encoder.finish()?;
+        let mut crc = Crc::new();
+        crc.update(data);
+
+        // the fields shared by the local header and the central directory, from the version
+        // needed to the length of the name
+        let mut fields = Vec::new();
+        fields.extend(20u16.to_le_bytes());
+        fields.extend(0u16.to_le_bytes());
+        fields.extend(8u16.to_le_bytes());
+        fields.extend(0u16.to_le_bytes());
+        fields.extend(DATE.to_le_bytes());
+        fields.extend(crc.sum().to_le_bytes());
+        fields.extend((compressed.len() as u32).to_le_bytes());
+        fields.extend((data.len() as u32).to_le_bytes());
+        fields.extend((name.len() as u16).to_le_bytes());
+        fields.extend(0u16.to_le_bytes());
+
+        directory.extend(0x0201_4b50u32.to_le_bytes());
+        directory.extend(20u16.to_le_bytes());
+        directory.extend(&fields);
+        directory.extend([0; 10]);
+        directory.extend((archive.len() as u32).to_le_bytes());
+        directory.extend(name.as_bytes());
+
+        archive.extend(0x0403_4b50u32.to_le_bytes());
+        archive.extend(&fields);
+        archive.extend(name.as_bytes());
+        archive.extend(compressed);
+    }
+
+    let offset = archive.len() as u32;
+    archive.extend(&directory);
+    archive.extend(0x0605_4b50u32.to_le_bytes());
+    archive.extend([0; 4]);
+    archive.extend((files.len() as u16).to_le_bytes());
+    archive.extend((files.len() as u16).to_le_bytes());
+    archive.extend((directory.len() as u32).to_le_bytes());
+    archive.extend(offset.to_le_bytes());
+    archive.extend(0u16.to_le_bytes());
+    Ok(archive)
+}
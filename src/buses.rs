@@ -0,0 +1,139 @@
+//! Decoding the I2C and SPI transactions a simulator reports when it offers the
+//! `peripheralEvents` capability, for `--i2c-log` and `--spi-log`. Each transaction is one
+//! message: `i2cTransaction` with the bytes written to and read from a device address, and
+//! `spiTransaction` with the bytes shifted out and in while chip select was held
+
+use crate::error::{Result, WokwiServerError};
+use serde::Deserialize;
+use serde_json::Value;
+use std::fmt::Write;
+
+/// An I2C transfer: an optional write, e.g. of a register address, then an optional read after a
+/// repeated start
+#[derive(Debug, Deserialize, PartialEq, Eq)]
+pub struct I2cTransaction {
+    #[serde(default)]
+    pub bus: u8,
+    pub address: u16,
+    #[serde(default)]
+    pub write: Vec<u8>,
+    #[serde(default)]
+    pub read: Vec<u8>,
+    /// whether the device didn't acknowledge its address
+    #[serde(default)]
+    pub nack: bool,
+}
+
+/// An SPI transfer, the bytes on MOSI and MISO while chip select was held
+#[derive(Debug, Deserialize, PartialEq, Eq)]
+pub struct SpiTransaction {
+    #[serde(default)]
+    pub bus: u8,
+    /// the GPIO used as chip select, if the simulator knows it
+    #[serde(default)]
+    pub cs: Option<u8>,
+    #[serde(default)]
+    pub mosi: Vec<u8>,
+    #[serde(default)]
+    pub miso: Vec<u8>,
+}
+
+/// Common devices at each I2C address, as a guess at what the firmware is talking to
+const I2C_DEVICES: &[(u16, &str)] = &[
+    (0x1e, "HMC5883L"),
+    (0x23, "BH1750"),
+    (0x27, "PCF8574 LCD backpack"),
+    (0x29, "VL53L0X"),
+    (0x3c, "SSD1306"),
+    (0x3d, "SSD1306"),
+    (0x40, "INA219"),
+    (0x44, "SHT3x"),
+    (0x48, "ADS1115"),
+    (0x50, "24Cxx EEPROM"),
+    (0x53, "ADXL345"),
+    (0x57, "DS3231 EEPROM"),
+    (0x5c, "AM2320"),
+    (0x68, "MPU-6050 or DS1307/DS3231"),
+    (0x69, "MPU-6050"),
+    (0x76, "BME280/BMP280"),
+    (0x77, "BME280/BMP180"),
+];
+
+/// The devices usually found at an I2C address
+pub fn i2c_device(address: u16) -> Option<&'static str> {
+    I2C_DEVICES
+        .iter()
+        .find(|(a, _)| *a == address)
+        .map(|(_, name)| *name)
+}
+
+impl I2cTransaction {
+    pub fn parse(message: &Value) -> Result<Self> {
+        Self::deserialize(message)
+            .map_err(|e| WokwiServerError::Protocol(format!("Malformed i2cTransaction: {}", e)))
+    }
+
+    /// One line describing the transaction, reading the first byte written as a register
+    pub fn describe(&self) -> String {
+        let mut line = format!("I2C{} {:#04x}", self.bus, self.address);
+        if let Some(device) = i2c_device(self.address) {
+            write!(line, " ({})", device).unwrap();
+        }
+        if self.nack {
+            return line + " NACK";
+        }
+        match (self.write.split_first(), self.read.is_empty()) {
+            (None, true) => line.push_str(" probe"),
+            (None, false) => write!(line, " read {}", hex(&self.read)).unwrap(),
+            (Some((register, [])), true) => write!(line, " select {:#04x}", register).unwrap(),
+            (Some((register, data)), true) => {
+                write!(line, " write {:#04x} = {}", register, hex(data)).unwrap()
+            }
+            (Some((register, data)), false) => {
+                if !data.is_empty() {
+                    write!(line, " write {:#04x} = {},", register, hex(data)).unwrap();
+                }
+                write!(line, " read {:#04x}: {}", register, hex(&self.read)).unwrap()
+            }
+        }
+        line
+    }
+}
+
+impl SpiTransaction {
+    pub fn parse(message: &Value) -> Result<Self> {
+        Self::deserialize(message)
+            .map_err(|e| WokwiServerError::Protocol(format!("Malformed spiTransaction: {}", e)))
+    }
+
+    /// One line describing the transaction, reading the first byte sent as a command or register
+    pub fn describe(&self) -> String {
+        let mut line = format!("SPI{}", self.bus);
+        if let Some(cs) = self.cs {
+            write!(line, " cs{}", cs).unwrap();
+        }
+        match self.mosi.split_first() {
+            None => line.push_str(" empty"),
+            Some((command, args)) => {
+                write!(line, " cmd {:#04x}", command).unwrap();
+                if !args.is_empty() {
+                    write!(line, " {}", hex(args)).unwrap();
+                }
+            }
+        }
+        // the first byte comes back while the command is sent, and an idle MISO reads as 0xff
+        if self.miso.len() > 1 && self.miso[1..].iter().any(|&b| b != 0xff) {
+            write!(line, " -> {}", hex(&self.miso[1..])).unwrap();
+        }
+        line
+    }
+}
+
+/// Bytes as space separated hex
+fn hex(bytes: &[u8]) -> String {
+    bytes
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
@@ -0,0 +1,73 @@
+//! Timestamped captures of the UART traffic in both directions, for analysis in other tools
+
+use anyhow::{Context, Result};
+use std::fs::File;
+use std::io::BufWriter;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime};
+
+mod pcapng;
+mod sigrok;
+
+/// Which way bytes crossed the UART
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    /// written by the firmware
+    Tx,
+    /// sent to the firmware
+    Rx,
+}
+
+/// A file format UART traffic can be captured in
+trait Format: Send {
+    /// Record bytes which crossed the UART `at` after the capture started
+    fn record(&mut self, at: Duration, direction: Direction, bytes: &[u8]) -> Result<()>;
+
+    /// Write out anything held back, the file is complete afterwards
+    fn finish(&mut self) -> Result<()>;
+}
+
+/// Starts writing a format to a file, given when the capture started and the baud rate
+type CreateFn = fn(BufWriter<File>, SystemTime, u32) -> Result<Box<dyn Format>>;
+
+/// A capture being written, finished when dropped
+pub struct Capture {
+    path: PathBuf,
+    format: Box<dyn Format>,
+    started: Instant,
+}
+
+impl Capture {
+    /// Start a capture, as a sigrok session if the path ends in `.sr` or pcapng otherwise
+    pub fn create(path: &Path, baud: u32) -> Result<Self> {
+        let format: CreateFn = match path.extension().and_then(|e| e.to_str()) {
+            Some("sr") => sigrok::create,
+            Some("pcapng") => pcapng::create,
+            _ => anyhow::bail!(
+                "Can't tell the format of {}, use a .pcapng or .sr extension",
+                path.display()
+            ),
+        };
+        let file =
+            File::create(path).with_context(|| format!("Failed to create {}", path.display()))?;
+        Ok(Self {
+            path: path.to_owned(),
+            format: format(BufWriter::new(file), SystemTime::now(), baud)?,
+            started: Instant::now(),
+        })
+    }
+
+    pub fn record(&mut self, direction: Direction, bytes: &[u8]) -> Result<()> {
+        self.format
+            .record(self.started.elapsed(), direction, bytes)
+            .with_context(|| format!("Failed to write {}", self.path.display()))
+    }
+}
+
+impl Drop for Capture {
+    fn drop(&mut self) {
+        if let Err(e) = self.format.finish() {
+            println!("Failed to finish {}: {:#}", self.path.display(), e);
+        }
+    }
+}
@@ -0,0 +1,96 @@
+use super::{Direction, Format};
+use anyhow::Result;
+use std::io::Write;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// LINKTYPE_USER0, which Wireshark can be told to decode with any dissector
+const LINKTYPE: u16 = 147;
+
+const SECTION_HEADER: u32 = 0x0A0D_0D0A;
+const INTERFACE_DESCRIPTION: u32 = 1;
+const ENHANCED_PACKET: u32 = 6;
+
+const OPT_END: u16 = 0;
+const IF_NAME: u16 = 2;
+const EPB_FLAGS: u16 = 2;
+
+/// Each burst of bytes is a packet, timestamped in microseconds, with its direction in the packet
+/// flags: inbound for bytes sent to the firmware and outbound for bytes it wrote
+struct Pcapng<W> {
+    out: W,
+    started: Duration,
+}
+
+pub fn create<W: Write + Send + 'static>(
+    mut out: W,
+    started: SystemTime,
+    _baud: u32,
+) -> Result<Box<dyn Format>> {
+    // byte order magic, version 1.0 and an unknown section length
+    let mut section = Vec::new();
+    section.extend(0x1A2B_3C4D_u32.to_le_bytes());
+    section.extend(1_u16.to_le_bytes());
+    section.extend(0_u16.to_le_bytes());
+    section.extend((-1_i64).to_le_bytes());
+    write_block(&mut out, SECTION_HEADER, &section)?;
+
+    let mut interface = Vec::new();
+    interface.extend(LINKTYPE.to_le_bytes());
+    interface.extend([0; 2]);
+    interface.extend(0_u32.to_le_bytes());
+    option(&mut interface, IF_NAME, b"uart");
+    option(&mut interface, OPT_END, &[]);
+    write_block(&mut out, INTERFACE_DESCRIPTION, &interface)?;
+
+    Ok(Box::new(Pcapng {
+        out,
+        started: started.duration_since(UNIX_EPOCH).unwrap_or_default(),
+    }))
+}
+
+impl<W: Write + Send> Format for Pcapng<W> {
+    fn record(&mut self, at: Duration, direction: Direction, bytes: &[u8]) -> Result<()> {
+        let micros = (self.started + at).as_micros() as u64;
+        let flags: u32 = match direction {
+            Direction::Rx => 1,
+            Direction::Tx => 2,
+        };
+        let mut packet = Vec::with_capacity(bytes.len() + 40);
+        packet.extend(0_u32.to_le_bytes());
+        packet.extend(((micros >> 32) as u32).to_le_bytes());
+        packet.extend((micros as u32).to_le_bytes());
+        packet.extend((bytes.len() as u32).to_le_bytes());
+        packet.extend((bytes.len() as u32).to_le_bytes());
+        packet.extend(bytes);
+        pad(&mut packet);
+        option(&mut packet, EPB_FLAGS, &flags.to_le_bytes());
+        option(&mut packet, OPT_END, &[]);
+        write_block(&mut self.out, ENHANCED_PACKET, &packet)
+    }
+
+    fn finish(&mut self) -> Result<()> {
+        self.out.flush()?;
+        Ok(())
+    }
+}
+
+/// Write a block, its body already padded to 32 bits
+fn write_block(out: &mut impl Write, kind: u32, body: &[u8]) -> Result<()> {
+    let len = (body.len() as u32 + 12).to_le_bytes();
+    out.write_all(&kind.to_le_bytes())?;
+    out.write_all(&len)?;
+    out.write_all(body)?;
+    out.write_all(&len)?;
+    Ok(())
+}
+
+fn option(body: &mut Vec<u8>, code: u16, value: &[u8]) {
+    body.extend(code.to_le_bytes());
+    body.extend((value.len() as u16).to_le_bytes());
+    body.extend(value);
+    pad(body);
+}
+
+fn pad(body: &mut Vec<u8>) {
+    body.resize(body.len().next_multiple_of(4), 0);
+}
@@ -0,0 +1,230 @@
+use super::{Direction, Format};
+use anyhow::Result;
+use flate2::write::DeflateEncoder;
+use flate2::{Compression, Crc};
+use std::io::Write;
+use std::time::{Duration, SystemTime};
+
+/// samples per bit, enough for PulseView's UART decoder to find the middle of each bit
+const OVERSAMPLING: u32 = 8;
+
+/// samples in each chunk of the session, so no entry in the zip grows past 4 GiB
+const CHUNK_SAMPLES: usize = 4 << 20;
+
+/// both lines idle high
+const IDLE: u8 = 0b11;
+
+/// A sigrok session: a zip holding the metadata and the sampled logic levels of the TX line as
+/// the first probe and RX as the second, drawn as 8N1 frames at the baud rate
+struct Sigrok<W> {
+    zip: Zip<W>,
+    rate: u64,
+    samples_per_frame: usize,
+    /// samples which can still change, starting at `flushed`
+    pending: Vec<u8>,
+    flushed: u64,
+    /// the first sample each line is free to start a frame at
+    free: [u64; 2],
+    chunk: Vec<u8>,
+    chunks: usize,
+}
+
+pub fn create<W: Write + Send + 'static>(
+    out: W,
+    _started: SystemTime,
+    baud: u32,
+) -> Result<Box<dyn Format>> {
+    anyhow::ensure!(baud > 0, "The baud rate must be greater than zero");
+    let rate = baud as u64 * OVERSAMPLING as u64;
+    let mut zip = Zip::new(out);
+    zip.add("version", b"2")?;
+    let metadata = format!(
+        "[global]\nsigrok version=0.5.2\n\n[device 1]\ncapturefile=logic-1\ntotal probes=2\nsamplerate={}\nprobe1=TX\nprobe2=RX\nunitsize=1\n",
+        rate
+    );
+    zip.add("metadata", metadata.as_bytes())?;
+    Ok(Box::new(Sigrok {
+        zip,
+        rate,
+        samples_per_frame: 10 * OVERSAMPLING as usize,
+        pending: Vec::new(),
+        flushed: 0,
+        free: [0; 2],
+        chunk: Vec::with_capacity(CHUNK_SAMPLES),
+        chunks: 0,
+    }))
+}
+
+impl<W: Write + Send> Format for Sigrok<W> {
+    fn record(&mut self, at: Duration, direction: Direction, bytes: &[u8]) -> Result<()> {
+        let now = (at.as_secs_f64() * self.rate as f64) as u64;
+        // nothing can change before now, bytes already on a line are sent before these
+        self.flush(now.max(self.flushed))?;
+        let line = match direction {
+            Direction::Tx => 0,
+            Direction::Rx => 1,
+        };
+        let start = now.max(self.free[line]);
+        let end = start + (bytes.len() * self.samples_per_frame) as u64;
+        self.pending.resize((end - self.flushed) as usize, IDLE);
+
+        let first = (start - self.flushed) as usize;
+        let frames = self.pending[first..].chunks_mut(self.samples_per_frame);
+        for (frame, byte) in frames.zip(bytes) {
+            // a start bit, eight data bits from the lowest and a stop bit
+            let bits = 0x200 | (*byte as u16) << 1;
+            for (i, sample) in frame.iter_mut().enumerate() {
+                let level = (bits >> (i / OVERSAMPLING as usize)) & 1;
+                *sample = (*sample & !(1 << line)) | (level as u8) << line;
+            }
+        }
+        self.free[line] = end;
+        Ok(())
+    }
+
+    fn finish(&mut self) -> Result<()> {
+        let end = self.flushed + self.pending.len() as u64;
+        self.flush(end)?;
+        self.write_chunk()?;
+        self.zip.finish()
+    }
+}
+
+impl<W: Write> Sigrok<W> {
+    /// Move samples before `until` into chunks, filling any gap with idle lines
+    fn flush(&mut self, until: u64) -> Result<()> {
+        if until > self.flushed + self.pending.len() as u64 {
+            self.pending.resize((until - self.flushed) as usize, IDLE);
+        }
+        let done: Vec<u8> = self
+            .pending
+            .drain(..(until - self.flushed) as usize)
+            .collect();
+        self.flushed = until;
+        let mut done = &done[..];
+        while !done.is_empty() {
+            let room = CHUNK_SAMPLES - self.chunk.len();
+            let (now, later) = done.split_at(room.min(done.len()));
+            self.chunk.extend_from_slice(now);
+            if self.chunk.len() == CHUNK_SAMPLES {
+                self.write_chunk()?;
+            }
+            done = later;
+        }
+        Ok(())
+    }
+
+    fn write_chunk(&mut self) -> Result<()> {
+        if self.chunk.is_empty() {
+            return Ok(());
+        }
+        self.chunks += 1;
+        self.zip
+            .add(&format!("logic-1-{}", self.chunks), &self.chunk)?;
+        self.chunk.clear();
+        Ok(())
+    }
+}
+
+/// The central directory entry for a file in the zip
+struct Entry {
+    name: String,
+    crc: u32,
+    compressed: u32,
+    size: u32,
+    offset: u32,
+}
+
+/// Writes a zip archive one whole, deflated file at a time
+struct Zip<W> {
+    out: W,
+    written: u32,
+    entries: Vec<Entry>,
+}
+
+impl<W: Write> Zip<W> {
+    fn new(out: W) -> Self {
+        Self {
+            out,
+            written: 0,
+            entries: Vec::new(),
+        }
+    }
+
+    fn add(&mut self, name: &str, data: &[u8]) -> Result<()> {
+        let mut crc = Crc::new();
+        crc.update(data);
+        let mut encoder = DeflateEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(data)?;
+        let compressed = encoder.finish()?;
+        let entry = Entry {
+            name: name.to_owned(),
+            crc: crc.sum(),
+            compressed: compressed.len() as u32,
+            size: data.len() as u32,
+            offset: self.written,
+        };
+
+        let mut header = Vec::new();
+        header.extend(0x0403_4b50_u32.to_le_bytes());
+        header.extend(20_u16.to_le_bytes());
+        header.extend(entry.fields());
+        header.extend(0_u16.to_le_bytes());
+        header.extend(name.as_bytes());
+        self.write(&header)?;
+        self.write(&compressed)?;
+        self.entries.push(entry);
+        Ok(())
+    }
+
+    /// Write the central directory, completing the archive
+    fn finish(&mut self) -> Result<()> {
+        let start = self.written;
+        let mut directory = Vec::new();
+        for entry in &self.entries {
+            directory.extend(0x0201_4b50_u32.to_le_bytes());
+            directory.extend(20_u16.to_le_bytes());
+            directory.extend(20_u16.to_le_bytes());
+            directory.extend(entry.fields());
+            // no extra field, comment, disk number or attributes
+            directory.extend([0; 12]);
+            directory.extend(entry.offset.to_le_bytes());
+            directory.extend(entry.name.as_bytes());
+        }
+        let count = (self.entries.len() as u16).to_le_bytes();
+        directory.extend(0x0605_4b50_u32.to_le_bytes());
+        directory.extend([0; 4]);
+        directory.extend(count);
+        directory.extend(count);
+        directory.extend((directory.len() as u32 - 12).to_le_bytes());
+        directory.extend(start.to_le_bytes());
+        directory.extend(0_u16.to_le_bytes());
+        self.write(&directory)?;
+        self.out.flush()?;
+        Ok(())
+    }
+
+    fn write(&mut self, bytes: &[u8]) -> Result<()> {
+        self.out.write_all(bytes)?;
+        self.written += bytes.len() as u32;
+        Ok(())
+    }
+}
+
+impl Entry {
+    /// The fields shared by the local header and the central directory, from the flags to the
+    /// length of the name
+    fn fields(&self) -> Vec<u8> {
+        let mut fields = Vec::new();
+        fields.extend(0_u16.to_le_bytes());
+        fields.extend(8_u16.to_le_bytes());
+        // 1980-01-01 00:00, the earliest time a zip can hold
+        fields.extend(0_u16.to_le_bytes());
+        fields.extend(0x21_u16.to_le_bytes());
+        fields.extend(self.crc.to_le_bytes());
+        fields.extend(self.compressed.to_le_bytes());
+        fields.extend(self.size.to_le_bytes());
+        fields.extend((self.name.len() as u16).to_le_bytes());
+        fields
+    }
+}
@@ -0,0 +1,193 @@
+//! Finding the builds of a firmware for other cargo profiles, so the one simulated is the one
+//! that was meant, and the binaries of a cargo workspace for `--cargo`
+
+use anyhow::{Context, Result};
+use serde::Deserialize;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+/// An elf in a cargo target directory, e.g. `target/<triple>/debug/examples/blink`
+struct Artifact {
+    /// the directory holding a directory for each profile, e.g. `target/<triple>`
+    target_dir: PathBuf,
+    /// the directory of the profile it was built with, e.g. `debug`
+    profile_dir: String,
+    /// its path within the profile's directory, e.g. `examples/blink`
+    path: PathBuf,
+}
+
+impl Artifact {
+    /// Where `elf` was built, if it is in a cargo target directory
+    fn locate(elf: &Path) -> Option<Self> {
+        // binaries are at the top of the profile's directory, examples one level down
+        let dir = elf
+            .ancestors()
+            .skip(1)
+            .take(2)
+            .find(|dir| is_profile_dir(dir))?;
+        Some(Self {
+            target_dir: dir.parent()?.to_owned(),
+            profile_dir: dir.file_name()?.to_str()?.to_owned(),
+            path: elf.strip_prefix(dir).ok()?.to_owned(),
+        })
+    }
+
+    /// The same artifact built with the profile whose directory is `profile_dir`
+    fn built_with(&self, profile_dir: &str) -> PathBuf {
+        self.target_dir.join(profile_dir).join(&self.path)
+    }
+}
+
+/// Cargo keeps fingerprints in every profile's directory
+fn is_profile_dir(dir: &Path) -> bool {
+    dir.join(".fingerprint").is_dir()
+}
+
+/// The directory cargo builds `profile` into
+fn profile_dir(profile: &str) -> &str {
+    match profile {
+        "dev" | "test" => "debug",
+        "bench" => "release",
+        profile => profile,
+    }
+}
+
+/// The build of `elf` for `profile`, which must be in a cargo target directory
+pub fn select(elf: &Path, profile: &str) -> Result<PathBuf> {
+    let Some(artifact) = Artifact::locate(elf) else {
+        anyhow::bail!(
+            "--profile needs an elf in a cargo target directory, {} isn't in one",
+            elf.display()
+        );
+    };
+    let selected = artifact.built_with(profile_dir(profile));
+    if !selected.is_file() {
+        anyhow::bail!(
+            "There is no {} build at {}, build it with `cargo build --profile {}`",
+            profile,
+            selected.display(),
+            profile
+        );
+    }
+    Ok(selected)
+}
+
+/// A warning if another profile's build of `elf` is newer, as `elf` is then likely stale
+pub fn newer_build(elf: &Path) -> Option<String> {
+    let artifact = Artifact::locate(elf)?;
+    let modified = |path: &Path| -> Option<SystemTime> { path.metadata().ok()?.modified().ok() };
+    let built = modified(elf)?;
+    let (newest, profile) = std::fs::read_dir(&artifact.target_dir)
+        .ok()?
+        .flatten()
+        .filter(|dir| is_profile_dir(&dir.path()))
+        .filter_map(|dir| dir.file_name().into_string().ok())
+        .filter(|profile| *profile != artifact.profile_dir)
+        .filter_map(|profile| {
+            let other = artifact.built_with(&profile);
+            Some((modified(&other)?, other, profile))
+        })
+        .filter(|(modified, _, _)| *modified > built)
+        .max_by_key(|(modified, _, _)| *modified)
+        .map(|(_, other, profile)| (other, profile))?;
+    Some(format!(
+        "{} is newer than the {} build being simulated, pass --profile {} to use it",
+        newest.display(),
+        artifact.profile_dir,
+        profile
+    ))
+}
+
+/// What `cargo metadata` says about a workspace
+#[derive(Deserialize)]
+struct Metadata {
+    /// the workspace's members, as `--no-deps` is passed
+    packages: Vec<Package>,
+    /// the directory builds go to, with `CARGO_TARGET_DIR` or `build.target-dir` applied
+    target_directory: PathBuf,
+}
+
+#[derive(Deserialize)]
+struct Package {
+    targets: Vec<Target>,
+}
+
+#[derive(Deserialize)]
+struct Target {
+    name: String,
+    kind: Vec<String>,
+}
+
+/// The build for `profile` of the binary `bin` of the cargo workspace `dir` is in, or of its only
+/// binary when none is named. The target triple isn't known, so the newest build of the binary
+/// for any target is used
+pub fn workspace_bin(dir: &Path, bin: Option<&str>, profile: &str) -> Result<PathBuf> {
+    // set when the server is run by cargo, e.g. as a runner
+    let cargo = std::env::var_os("CARGO").unwrap_or_else(|| "cargo".into());
+    let output = std::process::Command::new(cargo)
+        .args(["metadata", "--format-version", "1", "--no-deps"])
+        .current_dir(dir)
+        .output()
+        .context("Failed to run cargo metadata, is cargo installed?")?;
+    if !output.status.success() {
+        anyhow::bail!(
+            "cargo metadata failed in {}: {}",
+            dir.display(),
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    let metadata: Metadata =
+        serde_json::from_slice(&output.stdout).context("Unexpected output from cargo metadata")?;
+
+    let mut bins: Vec<&str> = metadata
+        .packages
+        .iter()
+        .flat_map(|package| &package.targets)
+        .filter(|target| target.kind.iter().any(|kind| kind == "bin"))
+        .map(|target| target.name.as_str())
+        .collect();
+    bins.sort_unstable();
+    let bin = match (bin, bins.as_slice()) {
+        (Some(bin), _) if bins.contains(&bin) => bin,
+        (Some(bin), _) => anyhow::bail!(
+            "The workspace has no binary {}, it has {}",
+            bin,
+            bins.join(", ")
+        ),
+        (None, []) => anyhow::bail!("The cargo workspace in {} has no binaries", dir.display()),
+        (None, [bin]) => bin,
+        (None, _) => anyhow::bail!(
+            "The workspace has several binaries, pick one with --bin: {}",
+            bins.join(", ")
+        ),
+    };
+
+    let profile_dir = profile_dir(profile);
+    let modified = |path: &Path| -> Option<SystemTime> { path.metadata().ok()?.modified().ok() };
+    // `<target dir>/<triple>/<profile>/<bin>`, or without the triple for the host
+    let built = std::fs::read_dir(&metadata.target_directory)
+        .into_iter()
+        .flatten()
+        .flatten()
+        .map(|triple| triple.path())
+        .chain([metadata.target_directory.clone()])
+        .map(|dir| dir.join(profile_dir))
+        .filter(|dir| is_profile_dir(dir))
+        .map(|dir| dir.join(bin))
+        .filter_map(|elf| Some((modified(&elf)?, elf)))
+        .max_by_key(|(modified, _)| *modified);
+    match built {
+        Some((_, elf)) => {
+            println!("Simulating {} from the cargo workspace", elf.display());
+            Ok(elf)
+        }
+        None => anyhow::bail!(
+            "There is no {} build of {} in {}, build it with `cargo build --profile {} --bin {}`",
+            profile,
+            bin,
+            metadata.target_directory.display(),
+            profile,
+            bin
+        ),
+    }
+}
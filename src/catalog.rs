@@ -0,0 +1,123 @@
+//! Stable codes for the failures users run into, printed with the error so they can be looked up
+//! in docs/errors.md and referred to in issues. Codes are never reused or renumbered
+
+use serde_json::{json, Value};
+use std::fmt;
+
+/// where the codes are explained, each under a heading of its own
+pub const DOCS: &str = "https://github.com/MabezDev/wokwi-server/blob/main/docs/errors.md";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u16)]
+pub enum Code {
+    PortInUse = 1,
+    ElfArchMismatch = 2,
+    FileNotFound = 3,
+    InvalidElf = 4,
+    UnsupportedChip = 5,
+    NoElf = 6,
+    ImageLayout = 7,
+    ChecksumMismatch = 8,
+    InvalidStartPacket = 9,
+    TooManyErrors = 10,
+    MessageTooLarge = 11,
+}
+
+impl Code {
+    /// What went wrong, in a few words
+    pub fn title(self) -> &'static str {
+        match self {
+            Code::PortInUse => "port in use",
+            Code::ElfArchMismatch => "elf built for another architecture",
+            Code::FileNotFound => "file not found",
+            Code::InvalidElf => "invalid elf",
+            Code::UnsupportedChip => "chip not supported by Wokwi",
+            Code::NoElf => "no elf to simulate",
+            Code::ImageLayout => "flash images overlap",
+            Code::ChecksumMismatch => "application checksum mismatch",
+            Code::InvalidStartPacket => "invalid start message",
+            Code::TooManyErrors => "too many connection errors",
+            Code::MessageTooLarge => "firmware too large to send",
+        }
+    }
+
+    /// The explanation of the code in the docs
+    pub fn url(self) -> String {
+        format!("{}#{}", DOCS, self.to_string().to_lowercase())
+    }
+}
+
+impl fmt::Display for Code {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "WKS{:04}", *self as u16)
+    }
+}
+
+/// An error with a code from the catalog. Its message is shown unchanged, so it can stand in for
+/// an `anyhow!` message or context
+#[derive(Debug)]
+pub struct Failure {
+    pub code: Code,
+    message: String,
+}
+
+impl Failure {
+    pub fn new(code: Code, message: impl Into<String>) -> Self {
+        Self {
+            code,
+            message: message.into(),
+        }
+    }
+}
+
+impl fmt::Display for Failure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl std::error::Error for Failure {}
+
+/// Return early with a [`Failure`] with the given code, formatted like `format!`
+macro_rules! bail {
+    ($code:ident, $($arg:tt)*) => {
+        return Err($crate::catalog::Failure::new(
+            $crate::catalog::Code::$code,
+            format!($($arg)*),
+        )
+        .into())
+    };
+}
+
+pub(crate) use bail;
+
+/// The code of an error, if it or any of its context has one
+pub fn code(error: &anyhow::Error) -> Option<Code> {
+    error.downcast_ref::<Failure>().map(|failure| failure.code)
+}
+
+/// An error with its causes, followed by its code and where it is explained if it has one
+pub fn describe(error: &anyhow::Error) -> String {
+    match code(error) {
+        Some(code) => format!(
+            "{:?}\n\n{} ({}): see {}",
+            error,
+            code,
+            code.title(),
+            code.url()
+        ),
+        None => format!("{:?}", error),
+    }
+}
+
+/// An error as JSON for `--output-json`, with its code if it has one
+pub fn to_json(error: &anyhow::Error) -> Value {
+    let causes: Vec<_> = error.chain().skip(1).map(|e| e.to_string()).collect();
+    let mut json = json!({ "message": error.to_string(), "causes": causes });
+    if let Some(code) = code(error) {
+        json["code"] = json!(code.to_string());
+        json["title"] = json!(code.title());
+        json["url"] = json!(code.url());
+    }
+    json!({ "error": json })
+}
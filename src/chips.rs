@@ -0,0 +1,59 @@
+use espflash::Chip;
+use serde_json::{json, Value};
+
+/// What wokwi-server knows about simulating a chip in Wokwi
+#[derive(Debug)]
+pub struct ChipInfo {
+    pub chip: Chip,
+    /// the Wokwi project used when no `--id` is given
+    pub project_id: &'static str,
+    /// the Wokwi part of the chip's development board
+    pub board: &'static str,
+}
+
+/// Chips Wokwi is known to simulate, add new chips here as Wokwi supports them
+pub const SUPPORTED_CHIPS: &[ChipInfo] = &[
+    ChipInfo {
+        chip: Chip::Esp32,
+        project_id: "338154815612781140",
+        board: "board-esp32-devkit-c-v4",
+    },
+    ChipInfo {
+        chip: Chip::Esp32s2,
+        project_id: "338154940543271506",
+        board: "board-esp32-s2-devkitm-1",
+    },
+    ChipInfo {
+        chip: Chip::Esp32c3,
+        project_id: "338322025101656660",
+        board: "board-esp32-c3-devkitm-1",
+    },
+    ChipInfo {
+        chip: Chip::Esp32s3,
+        project_id: "345144250522927698",
+        board: "board-esp32-s3-devkitc-1",
+    },
+];
+
+impl ChipInfo {
+    /// A diagram of just the development board, its UART wired to the serial monitor. Bundled
+    /// so a project doesn't depend on the default Wokwi project staying as it is
+    pub fn diagram(&self) -> Value {
+        json!({
+            "version": 1,
+            "author": "wokwi-server",
+            "editor": "wokwi",
+            "parts": [{ "type": self.board, "id": "esp", "top": 0, "left": 0, "attrs": {} }],
+            "connections": [
+                ["esp:TX", "$serialMonitor:RX", "", []],
+                ["esp:RX", "$serialMonitor:TX", "", []],
+            ],
+            "dependencies": {},
+        })
+    }
+}
+
+/// Look up a chip in the table of supported chips
+pub fn lookup(chip: Chip) -> Option<&'static ChipInfo> {
+    SUPPORTED_CHIPS.iter().find(|info| info.chip == chip)
+}
@@ -0,0 +1,52 @@
+/// Split a command line into words, honouring single quotes, double quotes and backslash escapes
+pub fn split(command: &str) -> Vec<String> {
+    let mut words = Vec::new();
+    let mut word = String::new();
+    let mut in_word = false;
+    let mut quote = None;
+    let mut chars = command.chars();
+
+    while let Some(c) = chars.next() {
+        match (c, quote) {
+            ('\\', Some('\'')) => word.push(c),
+            ('\\', _) => {
+                if let Some(escaped) = chars.next() {
+                    word.push(escaped);
+                }
+                in_word = true;
+            }
+            (q, None) if q == '"' || q == '\'' => {
+                quote = Some(q);
+                in_word = true;
+            }
+            (q, Some(open)) if q == open => quote = None,
+            (c, None) if c.is_whitespace() => {
+                if in_word {
+                    words.push(std::mem::take(&mut word));
+                    in_word = false;
+                }
+            }
+            (c, _) => {
+                word.push(c);
+                in_word = true;
+            }
+        }
+    }
+    if in_word {
+        words.push(word);
+    }
+
+    words
+}
+
+/// Substitute `{name}` placeholders in each word of a command template
+pub fn expand(words: &[String], vars: &[(&str, &str)]) -> Vec<String> {
+    words
+        .iter()
+        .map(|word| {
+            vars.iter().fold(word.clone(), |word, (name, value)| {
+                word.replace(&format!("{{{}}}", name), value)
+            })
+        })
+        .collect()
+}
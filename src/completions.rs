@@ -0,0 +1,22 @@
+use crate::Command;
+use anyhow::Result;
+use clap::CommandFactory;
+use clap_complete::Shell;
+
+/// Print a shell completion script, e.g. `wokwi-server completions bash > /etc/bash_completion.d/wokwi-server`
+#[derive(clap::Args, Debug)]
+pub struct CompletionsArgs {
+    /// shell to complete commands for
+    #[clap(value_enum)]
+    shell: Shell,
+}
+
+pub fn run(args: CompletionsArgs) -> Result<i32> {
+    clap_complete::generate(
+        args.shell,
+        &mut Command::command(),
+        "wokwi-server",
+        &mut std::io::stdout(),
+    );
+    Ok(0)
+}
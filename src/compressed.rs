@@ -0,0 +1,98 @@
+//! Firmware inputs compressed with gzip or zstd, as CI artifact storage often keeps them, are
+//! decompressed to a temporary directory and used from there, so everything reading the elf or
+//! images, GDB included, sees them uncompressed. Compression is told from the file's contents, the
+//! `.gz` or `.zst` extension is only dropped from the name
+
+use anyhow::{Context, Result};
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+const GZIP_MAGIC: &[u8] = &[0x1f, 0x8b];
+const ZSTD_MAGIC: &[u8] = &[0x28, 0xb5, 0x2f, 0xfd];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Format {
+    Gzip,
+    Zstd,
+}
+
+impl Format {
+    /// The compression of the file at `path`, `None` if it isn't compressed or can't be read
+    fn detect(path: &Path) -> Option<Self> {
+        let mut magic = [0; 4];
+        let read = std::fs::File::open(path)
+            .and_then(|mut file| file.read(&mut magic))
+            .ok()?;
+        let magic = &magic[..read];
+        if magic.starts_with(GZIP_MAGIC) {
+            Some(Format::Gzip)
+        } else if magic.starts_with(ZSTD_MAGIC) {
+            Some(Format::Zstd)
+        } else {
+            None
+        }
+    }
+}
+
+/// The directory decompressed and downloaded inputs are written to, one per server process
+pub fn dir() -> PathBuf {
+    std::env::temp_dir().join(format!("wokwi-server-{}-inputs", std::process::id()))
+}
+
+/// The path to read the input at `path` from: its decompressed copy if it is compressed, or
+/// `path` itself if it isn't
+pub fn decompressed(path: &Path) -> Result<PathBuf> {
+    let Some(format) = Format::detect(path) else {
+        return Ok(path.to_owned());
+    };
+    let data = match format {
+        Format::Gzip => {
+            let file = std::fs::File::open(path)?;
+            let mut data = Vec::new();
+            flate2::read::MultiGzDecoder::new(file)
+                .read_to_end(&mut data)
+                .with_context(|| format!("Failed to decompress {}", path.display()))?;
+            data
+        }
+        Format::Zstd => unzstd(path)?,
+    };
+
+    let name = match path.extension().and_then(|e| e.to_str()) {
+        Some("gz" | "zst") => path.file_stem(),
+        _ => path.file_name(),
+    }
+    .context("Compressed input has no file name")?;
+    let dir = dir();
+    std::fs::create_dir_all(&dir).with_context(|| format!("Failed to create {}", dir.display()))?;
+    let out = dir.join(name);
+    std::fs::write(&out, &data).with_context(|| format!("Failed to write {}", out.display()))?;
+    println!("Decompressed {} ({} bytes)", path.display(), data.len());
+    Ok(out)
+}
+
+/// zstd has no decoder among the dependencies, so the `zstd` command line tool does it
+fn unzstd(path: &Path) -> Result<Vec<u8>> {
+    let output = std::process::Command::new("zstd")
+        .args(["--decompress", "--stdout", "--quiet"])
+        .arg(path)
+        .output()
+        .with_context(|| {
+            format!(
+                "{} is compressed with zstd, which needs the zstd command to be installed",
+                path.display()
+            )
+        })?;
+    if !output.status.success() {
+        anyhow::bail!(
+            "Failed to decompress {}: {}",
+            path.display(),
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    Ok(output.stdout)
+}
+
+/// Remove the decompressed and downloaded inputs once the server is done with them
+pub fn clean_up() {
+    std::fs::remove_dir_all(dir()).ok();
+}
@@ -0,0 +1,137 @@
+//! `wokwi-server.toml`, settings which can be changed while the server runs, e.g.
+//!
+//! ```toml
+//! log_filter = "wifi=warn,*=info"
+//! uart_sinks = ["stdout", "file:uart.log"]
+//! expect = ["Connected"]
+//! fail_on = ["panicked"]
+//! ```
+//!
+//! Settings in the file override the command line, removing one goes back to the command line.
+
+use crate::log_filter::{self, LogFilter};
+use crate::sinks::{self, SinkSpec};
+use anyhow::{Context, Result};
+use serde::Deserialize;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+use tokio::sync::mpsc::Sender;
+use tokio_util::sync::CancellationToken;
+
+pub const CONFIG_FILE: &str = "wokwi-server.toml";
+
+/// how often the file is checked for changes
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct File {
+    log_filter: Option<String>,
+    uart_sinks: Option<Vec<String>>,
+    expect: Option<Vec<String>>,
+    fail_on: Option<Vec<String>>,
+}
+
+/// The settings which are safe to change at runtime
+#[derive(Debug, Clone, PartialEq)]
+pub struct Settings {
+    pub log_filter: Option<LogFilter>,
+    pub uart_sinks: Vec<SinkSpec>,
+    pub expect: Vec<String>,
+    pub fail_on: Vec<String>,
+}
+
+impl Settings {
+    /// `base`, from the command line, overridden by the file at `path`
+    pub fn load(path: &Path, base: &Settings) -> Result<Self> {
+        let contents = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read {}", path.display()))?;
+        let file: File =
+            toml::from_str(&contents).with_context(|| format!("Invalid {}", path.display()))?;
+        let invalid = |e: String| anyhow::anyhow!(e).context(format!("Invalid {}", path.display()));
+        let log_filter = match &file.log_filter {
+            Some(filter) => Some(log_filter::parse(filter).map_err(invalid)?),
+            None => base.log_filter.clone(),
+        };
+        let uart_sinks = match &file.uart_sinks {
+            Some(specs) => specs
+                .iter()
+                .map(|spec| sinks::parse_spec(spec))
+                .collect::<Result<_, _>>()
+                .map_err(invalid)?,
+            None => base.uart_sinks.clone(),
+        };
+        Ok(Self {
+            log_filter,
+            uart_sinks,
+            expect: file.expect.unwrap_or_else(|| base.expect.clone()),
+            fail_on: file.fail_on.unwrap_or_else(|| base.fail_on.clone()),
+        })
+    }
+
+    /// The names of the settings which differ from `other`
+    pub fn changes(&self, other: &Settings) -> Vec<&'static str> {
+        let mut changes = Vec::new();
+        if self.log_filter != other.log_filter {
+            changes.push("log_filter");
+        }
+        if self.uart_sinks != other.uart_sinks {
+            changes.push("uart_sinks");
+        }
+        if self.expect != other.expect {
+            changes.push("expect");
+        }
+        if self.fail_on != other.fail_on {
+            changes.push("fail_on");
+        }
+        changes
+    }
+}
+
+/// The config file to use: the one given, or `wokwi-server.toml` in the current directory
+pub fn path(given: Option<&Path>) -> PathBuf {
+    given.map_or_else(|| PathBuf::from(CONFIG_FILE), Path::to_owned)
+}
+
+/// Send the settings whenever the file at `path` changes to something valid. A file which is
+/// removed gives back the command line settings in `base`
+pub async fn watch(
+    path: PathBuf,
+    base: Settings,
+    mut current: Settings,
+    send: Sender<Settings>,
+    shutdown: CancellationToken,
+) -> Result<()> {
+    let modified = |path: &Path| -> Option<SystemTime> {
+        std::fs::metadata(path).and_then(|m| m.modified()).ok()
+    };
+    let mut last = modified(&path);
+    let mut interval = tokio::time::interval(POLL_INTERVAL);
+    loop {
+        tokio::select! {
+            _ = interval.tick() => {}
+            _ = shutdown.cancelled() => return Ok(()),
+        }
+        let now = modified(&path);
+        if now == last {
+            continue;
+        }
+        last = now;
+        let settings = match now {
+            Some(_) => match Settings::load(&path, &base) {
+                Ok(settings) => settings,
+                Err(e) => {
+                    println!("Ignoring the changes to {}: {:#}", path.display(), e);
+                    continue;
+                }
+            },
+            None => base.clone(),
+        };
+        if settings != current {
+            current = settings.clone();
+            if send.send(settings).await.is_err() {
+                return Ok(());
+            }
+        }
+    }
+}
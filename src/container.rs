@@ -0,0 +1,15 @@
+use std::path::Path;
+
+/// Best effort detection of running inside a Docker (or similar) container
+pub fn in_container() -> bool {
+    if Path::new("/.dockerenv").exists() || Path::new("/run/.containerenv").exists() {
+        return true;
+    }
+    std::fs::read_to_string("/proc/1/cgroup")
+        .map(|cgroup| {
+            ["docker", "containerd", "kubepods", "libpod"]
+                .iter()
+                .any(|runtime| cgroup.contains(runtime))
+        })
+        .unwrap_or(false)
+}
@@ -0,0 +1,150 @@
+use crate::session::Sessions;
+use anyhow::Result;
+use serde_json::{json, Value};
+use std::path::PathBuf;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{mpsc, oneshot};
+use tokio_util::sync::CancellationToken;
+
+/// A control API command which has to be carried out by the simulation
+pub enum ControlRequest {
+    /// rebuild the image from a new elf and restart the simulation with it
+    LoadFirmware {
+        elf: PathBuf,
+        reply: oneshot::Sender<Result<()>>,
+    },
+    /// rebuild the image from the current elf and restart the simulation with it
+    Restart { reply: oneshot::Sender<Result<()>> },
+    /// let the simulation run for this many milliseconds of simulated time, answered once the
+    /// simulator has paused it again
+    RunFor {
+        ms: u64,
+        reply: oneshot::Sender<Result<()>>,
+    },
+}
+
+impl ControlRequest {
+    /// Answer the request with an error without carrying it out
+    pub fn reject(self, reason: &str) {
+        match self {
+            ControlRequest::LoadFirmware { reply, .. }
+            | ControlRequest::Restart { reply }
+            | ControlRequest::RunFor { reply, .. } => {
+                reply.send(Err(anyhow::anyhow!("{}", reason))).ok();
+            }
+        }
+    }
+}
+
+/// Serve the control API: one command per line, answered with one line of JSON
+pub async fn control_task(
+    server: TcpListener,
+    sessions: Sessions,
+    requests: mpsc::Sender<ControlRequest>,
+    shutdown: CancellationToken,
+) -> Result<()> {
+    loop {
+        let (stream, _) = tokio::select! {
+            accepted = server.accept() => accepted?,
+            _ = shutdown.cancelled() => return Ok(()),
+        };
+        let sessions = sessions.clone();
+        let requests = requests.clone();
+        let shutdown = shutdown.clone();
+        tokio::spawn(async move {
+            if let Err(e) = handle_control_client(stream, sessions, requests, shutdown).await {
+                println!("Control connection failed: {:?}", e);
+            }
+        });
+    }
+}
+
+async fn handle_control_client(
+    stream: TcpStream,
+    sessions: Sessions,
+    requests: mpsc::Sender<ControlRequest>,
+    shutdown: CancellationToken,
+) -> Result<()> {
+    let (reader, mut writer) = stream.into_split();
+    let mut lines = BufReader::new(reader).lines();
+    loop {
+        let line = tokio::select! {
+            line = lines.next_line() => line?,
+            _ = shutdown.cancelled() => return Ok(()),
+        };
+        let line = match line {
+            Some(line) => line,
+            None => return Ok(()),
+        };
+        let (command, argument) = match line.trim().split_once(char::is_whitespace) {
+            Some((command, argument)) => (command, argument.trim()),
+            None => (line.trim(), ""),
+        };
+        if command.is_empty() {
+            continue;
+        }
+        let response = match execute(command, argument, &sessions, &requests, &shutdown).await {
+            Ok(response) => response,
+            Err(e) => json!({ "ok": false, "error": format!("{:#}", e) }),
+        };
+        writer
+            .write_all(format!("{}\n", response).as_bytes())
+            .await?;
+    }
+}
+
+/// Carry out one control API command
+pub async fn execute(
+    command: &str,
+    argument: &str,
+    sessions: &Sessions,
+    requests: &mpsc::Sender<ControlRequest>,
+    shutdown: &CancellationToken,
+) -> Result<Value> {
+    match command {
+        "sessions" => Ok(json!({ "ok": true, "sessions": sessions.list() })),
+        "load-firmware" => {
+            if argument.is_empty() {
+                anyhow::bail!("usage: load-firmware <path>");
+            }
+            let elf = std::fs::canonicalize(argument)
+                .map_err(|e| anyhow::anyhow!("Cannot load {}: {}", argument, e))?;
+            let (reply, response) = oneshot::channel();
+            requests
+                .send(ControlRequest::LoadFirmware { elf, reply })
+                .await
+                .map_err(|_| anyhow::anyhow!("The server is shutting down"))?;
+            response.await??;
+            Ok(json!({ "ok": true }))
+        }
+        "restart" => {
+            let (reply, response) = oneshot::channel();
+            requests
+                .send(ControlRequest::Restart { reply })
+                .await
+                .map_err(|_| anyhow::anyhow!("The server is shutting down"))?;
+            response.await??;
+            Ok(json!({ "ok": true }))
+        }
+        "run-for" => {
+            let ms = argument
+                .parse()
+                .map_err(|_| anyhow::anyhow!("usage: run-for <ms>"))?;
+            let (reply, response) = oneshot::channel();
+            requests
+                .send(ControlRequest::RunFor { ms, reply })
+                .await
+                .map_err(|_| anyhow::anyhow!("The server is shutting down"))?;
+            response
+                .await
+                .map_err(|_| anyhow::anyhow!("The simulator disconnected before pausing"))??;
+            Ok(json!({ "ok": true }))
+        }
+        "shutdown" => {
+            shutdown.cancel();
+            Ok(json!({ "ok": true }))
+        }
+        _ => anyhow::bail!("unknown command `{}`", command),
+    }
+}
@@ -0,0 +1,286 @@
+//! Core dumps of crashed firmware, read through the simulator's GDB stub and written in the ELF
+//! format of ESP-IDF's core dumps, so `espcoredump.py` and GDB can look at the crash afterwards
+
+use crate::app_desc::AppDescriptor;
+use crate::{gdb, target_description};
+use espflash::Chip;
+use sha2::{Digest, Sha256};
+use std::collections::VecDeque;
+
+/// UART output of ESP-IDF's panic handler
+const CRASH_PATTERNS: &[&str] = &["Guru Meditation Error", "abort() was called"];
+/// lines longer than this are cut short
+const MAX_LINE: usize = 4096;
+/// the most memory read in one packet
+const CHUNK: u32 = 0x800;
+
+/// `ELF_ESP_CORE_DUMP_INFO_TYPE`
+const NOTE_INFO: u32 = 8266;
+/// `NT_PRSTATUS`
+const NOTE_PRSTATUS: u32 = 1;
+/// `COREDUMP_VERSION_ELF_CRC32`, the chip goes in the upper half
+const VERSION: u32 = 2;
+const EM_XTENSA: u16 = 94;
+const EM_RISCV: u16 = 243;
+const PT_LOAD: u32 = 1;
+const PT_NOTE: u32 = 4;
+
+/// Watches UART output for the firmware crashing
+#[derive(Default)]
+pub struct CrashWatch {
+    line: Vec<u8>,
+}
+
+impl CrashWatch {
+    /// Feed UART output, returning whether it shows a crash
+    pub fn feed(&mut self, bytes: &[u8]) -> bool {
+        let mut crashed = false;
+        for &b in bytes {
+            if b == b'\n' {
+                let line = String::from_utf8_lossy(&self.line).into_owned();
+                crashed |= CRASH_PATTERNS.iter().any(|p| line.contains(p));
+                self.line.clear();
+            } else if self.line.len() < MAX_LINE {
+                self.line.push(b);
+            }
+        }
+        crashed
+    }
+}
+
+/// The signal of an (unframed) stop reply which means the firmware crashed, e.g. `T0b...`
+pub fn fatal_signal(reply: &str) -> Option<u8> {
+    let signal = reply.strip_prefix(['S', 'T'])?.get(..2)?;
+    // SIGILL, SIGABRT, SIGFPE, SIGBUS and SIGSEGV
+    match u8::from_str_radix(signal, 16).ok()? {
+        signal @ (4 | 6 | 8 | 10 | 11) => Some(signal),
+        _ => None,
+    }
+}
+
+/// The RAM worth dumping on each chip, its data memory
+fn regions(chip: Chip) -> Option<&'static [(u32, u32)]> {
+    match chip {
+        Chip::Esp32 => Some(&[(0x3ffa_e000, 0x5_2000)]),
+        Chip::Esp32s2 => Some(&[(0x3ffb_0000, 0x5_0000)]),
+        Chip::Esp32s3 => Some(&[(0x3fc8_8000, 0x7_8000)]),
+        Chip::Esp32c2 => Some(&[(0x3fca_0000, 0x4_0000)]),
+        Chip::Esp32c3 => Some(&[(0x3fc8_0000, 0x6_0000)]),
+        _ => None,
+    }
+}
+
+/// The chip id ESP-IDF puts in the core dump version
+fn chip_id(chip: Chip) -> u32 {
+    match chip {
+        Chip::Esp32s2 => 2,
+        Chip::Esp32c3 => 5,
+        Chip::Esp32s3 => 9,
+        Chip::Esp32c2 => 12,
+        _ => 0,
+    }
+}
+
+/// What a capture needs next
+#[derive(Debug, PartialEq, Eq)]
+pub enum Step {
+    /// stop the firmware, passing the stop reply to [`Capture::feed`]
+    Halt,
+    /// read the registers with a `g` packet
+    Registers,
+    /// the contents of memory
+    Read { addr: u32, len: u32 },
+    /// the core dump
+    Done(Vec<u8>),
+}
+
+enum Stage {
+    Halt,
+    Registers,
+    Read { addr: u32 },
+}
+
+/// Reads the firmware's registers and RAM, one packet at a time
+pub struct Capture {
+    chip: Chip,
+    elf_sha256: [u8; 32],
+    signal: u8,
+    stage: Stage,
+    registers: Vec<u8>,
+    /// what is left to read
+    reads: VecDeque<(u32, u32)>,
+    /// the memory read so far, split where reads failed
+    segments: Vec<(u32, Vec<u8>)>,
+}
+
+impl Capture {
+    /// Start dumping firmware built from `elf`, returning the first step. `signal` is the one
+    /// the firmware stopped with, or `None` if it is still running. `None` if the chip's memory
+    /// isn't known
+    pub fn start(chip: Chip, elf: &[u8], signal: Option<u8>) -> Option<(Self, Step)> {
+        let reads = regions(chip)?
+            .iter()
+            .flat_map(|&(start, len)| {
+                (start..start + len)
+                    .step_by(CHUNK as usize)
+                    .map(move |addr| (addr, CHUNK.min(start + len - addr)))
+            })
+            .collect();
+        let elf_sha256 = xmas_elf::ElfFile::new(elf)
+            .ok()
+            .and_then(|elf| AppDescriptor::find(&elf))
+            .map_or_else(|| Sha256::digest(elf).into(), |desc| desc.elf_sha256);
+        let (stage, step) = match signal {
+            Some(_) => (Stage::Registers, Step::Registers),
+            None => (Stage::Halt, Step::Halt),
+        };
+        let capture = Self {
+            chip,
+            elf_sha256,
+            signal: signal.unwrap_or(0),
+            stage,
+            registers: Vec::new(),
+            reads,
+            segments: Vec::new(),
+        };
+        Some((capture, step))
+    }
+
+    /// Carry on with the simulator's (unframed) reply to the last step. `None` if it isn't the
+    /// stop reply being waited for, which belongs to someone else
+    pub fn feed(&mut self, reply: &str) -> Option<Step> {
+        match self.stage {
+            Stage::Halt if !reply.starts_with(['S', 'T']) => return None,
+            Stage::Halt => {
+                self.stage = Stage::Registers;
+                return Some(Step::Registers);
+            }
+            // registers which couldn't be read are left as zeros
+            Stage::Registers => self.registers = gdb::decode_hex(reply).unwrap_or_default(),
+            Stage::Read { addr } => {
+                if let Some(memory) = gdb::decode_hex(reply).filter(|m| !m.is_empty()) {
+                    match self.segments.last_mut() {
+                        Some((start, data)) if *start + data.len() as u32 == addr => {
+                            data.extend(memory)
+                        }
+                        _ => self.segments.push((addr, memory)),
+                    }
+                }
+            }
+        }
+        Some(match self.reads.pop_front() {
+            Some((addr, len)) => {
+                self.stage = Stage::Read { addr };
+                Step::Read { addr, len }
+            }
+            None => Step::Done(self.core()),
+        })
+    }
+
+    /// The ELF core file, a note segment with the registers and one segment per piece of memory
+    fn core(&self) -> Vec<u8> {
+        let mut notes = Vec::new();
+        let mut info = (chip_id(self.chip) << 16 | VERSION).to_le_bytes().to_vec();
+        info.extend(gdb::encode_hex(&self.elf_sha256).into_bytes());
+        info.push(0);
+        note(&mut notes, b"ESP_CORE_DUMP_INFO", NOTE_INFO, &info);
+        note(&mut notes, b"CORE", NOTE_PRSTATUS, &self.prstatus());
+
+        let machine = match self.chip {
+            Chip::Esp32c2 | Chip::Esp32c3 => EM_RISCV,
+            _ => EM_XTENSA,
+        };
+        let phnum = 1 + self.segments.len();
+        let mut offset = 52 + 32 * phnum as u32;
+        let mut core = elf_header(machine, phnum as u16);
+        program_header(&mut core, PT_NOTE, offset, 0, notes.len() as u32);
+        offset += notes.len() as u32;
+        for (addr, data) in &self.segments {
+            program_header(&mut core, PT_LOAD, offset, *addr, data.len() as u32);
+            offset += data.len() as u32;
+        }
+        core.extend(notes);
+        for (_, data) in &self.segments {
+            core.extend(data);
+        }
+        core
+    }
+
+    /// `prstatus` with the registers of the core that stopped, laid out like Linux's for the
+    /// architecture
+    fn prstatus(&self) -> Vec<u8> {
+        let mut status = Vec::with_capacity(72);
+        status.extend([0; 12]); // si_signo, si_code, si_errno
+        status.extend(u16::from(self.signal).to_le_bytes());
+        status.extend([0; 6]); // pr_pad0 and pr_sigpend
+        status.extend([0; 4]); // pr_sighold
+        status.extend(1u32.to_le_bytes()); // pr_pid
+        status.extend([0; 12 + 32]); // pr_ppid, pr_pgrp, pr_sid and the times
+        let values = target_description::parse_registers(self.chip, &self.registers);
+        let register = |name: &str| values.get(name).copied().unwrap_or_default();
+        let registers: Vec<u32> = match self.chip {
+            Chip::Esp32c2 | Chip::Esp32c3 => {
+                let names = ["ra", "sp", "gp", "tp", "t0", "t1", "t2", "fp", "s1"];
+                let mut registers = vec![register("pc")];
+                registers.extend(names.iter().map(|name| register(name)));
+                registers.extend((0..8).map(|i| register(&format!("a{}", i))));
+                registers.extend((2..12).map(|i| register(&format!("s{}", i))));
+                registers.extend((3..7).map(|i| register(&format!("t{}", i))));
+                registers
+            }
+            _ => {
+                // the address registers from the current window on, which makes it window 0
+                let base = register("windowbase") % 16;
+                let start = register("windowstart") & 0xffff;
+                let start = (start >> base | start << (16 - base)) & 0xffff;
+                let mut registers = ["pc", "ps", "lbeg", "lend", "lcount", "sar"]
+                    .map(&register)
+                    .to_vec();
+                registers.extend([start, 0]);
+                registers.extend([0; 56]);
+                registers.extend((0..64).map(|i| register(&format!("ar{}", (base * 4 + i) % 64))));
+                registers
+            }
+        };
+        for value in registers {
+            status.extend(value.to_le_bytes());
+        }
+        status
+    }
+}
+
+fn elf_header(machine: u16, phnum: u16) -> Vec<u8> {
+    let mut header = b"\x7fELF\x01\x01\x01".to_vec();
+    header.resize(16, 0);
+    header.extend(4u16.to_le_bytes()); // ET_CORE
+    header.extend(machine.to_le_bytes());
+    header.extend(1u32.to_le_bytes()); // e_version
+    header.extend(0u32.to_le_bytes()); // e_entry
+    header.extend(52u32.to_le_bytes()); // e_phoff
+    header.extend(0u32.to_le_bytes()); // e_shoff
+    header.extend(0u32.to_le_bytes()); // e_flags
+    header.extend(52u16.to_le_bytes()); // e_ehsize
+    header.extend(32u16.to_le_bytes()); // e_phentsize
+    header.extend(phnum.to_le_bytes());
+    header.extend(40u16.to_le_bytes()); // e_shentsize
+    header.extend([0; 4]); // e_shnum and e_shstrndx
+    header
+}
+
+fn program_header(core: &mut Vec<u8>, kind: u32, offset: u32, addr: u32, len: u32) {
+    let flags: u32 = if kind == PT_LOAD { 6 } else { 4 }; // RW or R
+    for field in [kind, offset, addr, addr, len, len, flags, 4] {
+        core.extend(field.to_le_bytes());
+    }
+}
+
+fn note(notes: &mut Vec<u8>, name: &[u8], kind: u32, desc: &[u8]) {
+    notes.extend((name.len() as u32 + 1).to_le_bytes());
+    notes.extend((desc.len() as u32).to_le_bytes());
+    notes.extend(kind.to_le_bytes());
+    notes.extend(name);
+    notes.push(0);
+    notes.resize(notes.len().next_multiple_of(4), 0);
+    notes.extend(desc);
+    notes.resize(notes.len().next_multiple_of(4), 0);
+}
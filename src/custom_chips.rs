@@ -0,0 +1,223 @@
+//! Wokwi custom chips built locally, sent to simulators which can load them, and bridges which
+//! connect a chip's serial and pin traffic to a process on the host, so a peripheral can be
+//! implemented there instead of in the chip
+//!
+//! A chip is its WebAssembly binary, e.g. `chips/inverter.chip.wasm`, and the definition of its
+//! pins next to it, `chips/inverter.chip.json`. Bridged messages are `chipSerial`
+//! (`{"chip": name, "bytes": [..]}`) and `chipPin` (`{"chip": name, "pin": "OUT", "value": 1}`),
+//! passed to and from the host process as lines of JSON
+
+use crate::command;
+use anyhow::{Context, Result};
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::sync::mpsc;
+use wokwi_server::messages::CHIP_MESSAGE_TYPES;
+
+/// what every WebAssembly module starts with
+const WASM_MAGIC: &[u8] = b"\0asm";
+
+/// A chip given with `--custom-chip <name>=<path>` or in wokwi.toml
+#[derive(Debug, Clone)]
+pub struct ChipSpec {
+    pub name: String,
+    pub binary: PathBuf,
+}
+
+/// Parse `<name>=<path of the .wasm>`
+pub fn parse_spec(s: &str) -> Result<ChipSpec, String> {
+    match s.split_once('=') {
+        Some((name, binary)) if !name.is_empty() && !binary.is_empty() => Ok(ChipSpec {
+            name: name.to_owned(),
+            binary: binary.into(),
+        }),
+        _ => Err("expected `<name>=<path of the .wasm>`".to_owned()),
+    }
+}
+
+/// A chip ready to send to the simulator
+#[derive(Debug, Clone)]
+pub struct CustomChip {
+    pub name: String,
+    /// the chip's `.chip.json`, with its pins and controls
+    pub definition: Value,
+    pub wasm: Vec<u8>,
+}
+
+impl CustomChip {
+    /// Read a chip's binary, and its definition from the `.json` next to it
+    pub fn load(spec: &ChipSpec) -> Result<Self> {
+        let wasm = std::fs::read(&spec.binary)
+            .with_context(|| format!("Failed to read chip {}", spec.binary.display()))?;
+        if !wasm.starts_with(WASM_MAGIC) {
+            anyhow::bail!(
+                "Chip {} isn't a WebAssembly binary, pass the .wasm the chip's build produced",
+                spec.binary.display()
+            );
+        }
+        let path = definition_path(&spec.binary);
+        let definition = std::fs::read_to_string(&path)
+            .with_context(|| format!("Failed to read the definition of chip {}", spec.name))?;
+        let definition = serde_json::from_str(&definition)
+            .with_context(|| format!("Invalid chip definition {}", path.display()))?;
+        Ok(Self {
+            name: spec.name.clone(),
+            definition,
+            wasm,
+        })
+    }
+}
+
+/// `inverter.chip.json` for `inverter.chip.wasm`
+fn definition_path(binary: &Path) -> PathBuf {
+    binary.with_extension("json")
+}
+
+/// The message giving the simulator the chips to load, sent before the firmware
+pub fn message(chips: &[CustomChip]) -> Value {
+    let chips: Vec<_> = chips
+        .iter()
+        .map(|chip| {
+            json!({
+                "name": chip.name,
+                "definition": chip.definition,
+                "wasm": base64::encode(&chip.wasm),
+            })
+        })
+        .collect();
+    json!({ "type": "customChips", "chips": chips })
+}
+
+/// A host process given with `--chip-bridge <chip>=<command>`
+#[derive(Debug, Clone)]
+pub struct BridgeSpec {
+    pub chip: String,
+    pub command: String,
+}
+
+/// Parse `<chip>=<command>`
+pub fn parse_bridge(s: &str) -> Result<BridgeSpec, String> {
+    match s.split_once('=') {
+        Some((chip, command)) if !chip.is_empty() && !command.trim().is_empty() => Ok(BridgeSpec {
+            chip: chip.to_owned(),
+            command: command.to_owned(),
+        }),
+        _ => Err("expected `<chip>=<command>`".to_owned()),
+    }
+}
+
+/// Where messages from the chips are passed to their running host processes
+#[derive(Default)]
+pub struct Bridges(HashMap<String, mpsc::UnboundedSender<Value>>);
+
+impl Bridges {
+    /// Pass a message from a chip to its host process, if it has one
+    pub fn feed(&self, chip: &str, message: &Value) {
+        if let Some(bridge) = self.0.get(chip) {
+            bridge.send(message.clone()).ok();
+        }
+    }
+}
+
+/// Start the host process of each bridge, returning where to feed them and the messages they
+/// send to their chips. The processes are killed once the feed is dropped
+pub fn spawn(specs: &[BridgeSpec], label: &str) -> Result<(Bridges, mpsc::Receiver<Value>)> {
+    let (send, replies) = mpsc::channel(16);
+    let mut bridges = Bridges::default();
+    for spec in specs {
+        let words = command::split(&spec.command);
+        let (program, args) = words
+            .split_first()
+            .context("Chip bridge command is empty")?;
+        let child = tokio::process::Command::new(program)
+            .args(args)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .kill_on_drop(true)
+            .spawn()
+            .with_context(|| format!("Failed to start the bridge for chip {}", spec.chip))?;
+        let (feed, messages) = mpsc::unbounded_channel();
+        tokio::spawn(run(
+            spec.chip.clone(),
+            label.to_owned(),
+            child,
+            messages,
+            send.clone(),
+        ));
+        bridges.0.insert(spec.chip.clone(), feed);
+        println!(
+            "[{}] Bridging chip {} to `{}`",
+            label, spec.chip, spec.command
+        );
+    }
+    Ok((bridges, replies))
+}
+
+async fn run(
+    chip: String,
+    label: String,
+    mut child: tokio::process::Child,
+    mut messages: mpsc::UnboundedReceiver<Value>,
+    replies: mpsc::Sender<Value>,
+) {
+    let mut stdin = child.stdin.take().expect("stdin is piped");
+    let mut lines = BufReader::new(child.stdout.take().expect("stdout is piped")).lines();
+    loop {
+        tokio::select! {
+            message = messages.recv() => {
+                let Some(message) = message else {
+                    return; /* the simulation went away, dropping the child kills it */
+                };
+                let line = message.to_string() + "\n";
+                if stdin.write_all(line.as_bytes()).await.is_err() {
+                    // the process has exited, which reading its output reports
+                    continue;
+                }
+            }
+            line = lines.next_line() => match line {
+                Ok(Some(line)) if line.trim().is_empty() => {}
+                Ok(Some(line)) => match reply(&chip, &line) {
+                    Ok(reply) => {
+                        if replies.send(reply).await.is_err() {
+                            return;
+                        }
+                    }
+                    Err(e) => println!(
+                        "[{}] Warning: skipping output of the bridge for chip {}: {}",
+                        label, chip, e
+                    ),
+                },
+                Ok(None) | Err(_) => {
+                    let status = child.wait().await;
+                    println!(
+                        "[{}] The bridge for chip {} exited{}",
+                        label,
+                        chip,
+                        status.map_or_else(|_| String::new(), |s| format!(" ({})", s))
+                    );
+                    // keep taking messages until the simulation goes away
+                    while messages.recv().await.is_some() {}
+                    return;
+                }
+            },
+        }
+    }
+}
+
+/// A line from a host process as a message for its chip
+fn reply(chip: &str, line: &str) -> Result<Value> {
+    let mut message: Value = serde_json::from_str(line).context("not JSON")?;
+    let r#type = message["type"].as_str().unwrap_or_default();
+    if !CHIP_MESSAGE_TYPES.contains(&r#type) {
+        anyhow::bail!(
+            "expected a message of type {}, got {}",
+            CHIP_MESSAGE_TYPES.join(" or "),
+            line
+        );
+    }
+    message["chip"] = json!(chip);
+    Ok(message)
+}
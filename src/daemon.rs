@@ -0,0 +1,134 @@
+use anyhow::{Context, Result};
+use std::path::Path;
+use std::process::{Command, Stdio};
+
+/// Set in the environment of the detached server process
+const DETACHED_ENV: &str = "WOKWI_SERVER_DETACHED";
+
+/// Whether this process is the detached instance spawned by `--daemon`
+pub fn is_detached() -> bool {
+    std::env::var_os(DETACHED_ENV).is_some()
+}
+
+/// Re-launch the current executable with the same arguments, detached from the terminal
+pub fn spawn_detached(log_file: Option<&Path>) -> Result<u32> {
+    let exe = std::env::current_exe()?;
+    let (stdout, stderr) = match log_file {
+        Some(path) => {
+            let file = std::fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+                .with_context(|| format!("Failed to open log file {}", path.display()))?;
+            (Stdio::from(file.try_clone()?), Stdio::from(file))
+        }
+        None => (Stdio::null(), Stdio::null()),
+    };
+
+    let mut command = Command::new(exe);
+    command
+        .args(std::env::args_os().skip(1))
+        .env(DETACHED_ENV, "1")
+        .stdin(Stdio::null())
+        .stdout(stdout)
+        .stderr(stderr);
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::process::CommandExt;
+        // don't receive signals meant for the terminal's foreground process group
+        command.process_group(0);
+    }
+    #[cfg(windows)]
+    {
+        use std::os::windows::process::CommandExt;
+        const DETACHED_PROCESS: u32 = 0x00000008;
+        command.creation_flags(DETACHED_PROCESS);
+    }
+
+    Ok(command.spawn()?.id())
+}
+
+/// Write the current process id to `path`, refusing to overwrite the pidfile of a running server
+pub fn write_pidfile(path: &Path) -> Result<()> {
+    if let Ok(contents) = std::fs::read_to_string(path) {
+        if let Ok(pid) = contents.trim().parse::<u32>() {
+            if pid != std::process::id() && is_running(pid) {
+                anyhow::bail!(
+                    "wokwi-server is already running (pid {}, pidfile {})",
+                    pid,
+                    path.display()
+                );
+            }
+        }
+    }
+    std::fs::write(path, format!("{}\n", std::process::id()))
+        .with_context(|| format!("Failed to write pidfile {}", path.display()))
+}
+
+fn is_running(pid: u32) -> bool {
+    #[cfg(unix)]
+    {
+        let Ok(pid) = libc::pid_t::try_from(pid) else {
+            return false;
+        };
+        // SAFETY: signal 0 only checks the process exists, nothing is sent
+        if unsafe { libc::kill(pid, 0) } == 0 {
+            return true;
+        }
+        // the process exists, it just belongs to someone else
+        std::io::Error::last_os_error().raw_os_error() == Some(libc::EPERM)
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = pid;
+        false
+    }
+}
+
+/// Listening sockets passed to us by systemd socket activation
+#[derive(Default)]
+pub struct ActivatedSockets {
+    pub wokwi: Option<std::net::TcpListener>,
+    pub gdb: Option<std::net::TcpListener>,
+}
+
+/// Take the sockets passed via the `LISTEN_FDS` protocol, if any.
+///
+/// Sockets named `gdb` in `LISTEN_FDNAMES` are used for the GDB server and `wokwi` for the
+/// simulator. Without names, the first socket is used for the simulator and the second for GDB.
+pub fn activated_sockets() -> ActivatedSockets {
+    let mut sockets = ActivatedSockets::default();
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::io::FromRawFd;
+        const SD_LISTEN_FDS_START: i32 = 3;
+
+        // left set, as the runtime's threads are already running by now; LISTEN_PID keeps child
+        // processes from taking the sockets too
+        let pid = std::env::var("LISTEN_PID").ok();
+        let fds = std::env::var("LISTEN_FDS").ok();
+        let names = std::env::var("LISTEN_FDNAMES").unwrap_or_default();
+
+        if pid.and_then(|p| p.parse::<u32>().ok()) != Some(std::process::id()) {
+            return sockets;
+        }
+        let count = fds.and_then(|n| n.parse::<i32>().ok()).unwrap_or(0);
+        let names: Vec<_> = names.split(':').collect();
+
+        for i in 0..count {
+            // SAFETY: systemd guarantees the fds from SD_LISTEN_FDS_START onwards are ours
+            let listener = unsafe { std::net::TcpListener::from_raw_fd(SD_LISTEN_FDS_START + i) };
+            match names.get(i as usize).copied() {
+                Some("gdb") => sockets.gdb = Some(listener),
+                Some("wokwi") => sockets.wokwi = Some(listener),
+                _ if i == 0 => sockets.wokwi = Some(listener),
+                _ if i == 1 => sockets.gdb = Some(listener),
+                _ => println!("Ignoring unexpected socket passed by systemd"),
+            }
+        }
+    }
+
+    sockets
+}
@@ -0,0 +1,217 @@
+use crate::activity::{self, Activity};
+use crate::control::{self, ControlRequest};
+use crate::session::Sessions;
+use anyhow::{Context, Result};
+use serde_json::{json, Value};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{broadcast, mpsc};
+use tokio_util::sync::CancellationToken;
+
+/// The dashboard, with its script and styles inline
+const PAGE: &str = include_str!("dashboard.html");
+/// largest request head or body accepted
+const MAX_REQUEST: usize = 64 * 1024;
+/// how often the connected clients are sent to the page
+const STATUS_INTERVAL: Duration = Duration::from_secs(1);
+/// must be sent with API calls, so other sites can't make them from the browser
+const API_HEADER: &str = "x-wokwi-dashboard";
+
+struct Request {
+    method: String,
+    path: String,
+    host: String,
+    /// whether `API_HEADER` was sent
+    from_dashboard: bool,
+    body: String,
+}
+
+/// Serve the dashboard: the page itself, a stream of events and the control API
+pub async fn dashboard_task(
+    server: TcpListener,
+    url: String,
+    sessions: Sessions,
+    requests: mpsc::Sender<ControlRequest>,
+    shutdown: CancellationToken,
+) -> Result<()> {
+    loop {
+        let (stream, _) = tokio::select! {
+            accepted = server.accept() => accepted?,
+            _ = shutdown.cancelled() => return Ok(()),
+        };
+        let url = url.clone();
+        let sessions = sessions.clone();
+        let requests = requests.clone();
+        let shutdown = shutdown.clone();
+        tokio::spawn(async move {
+            if let Err(e) = handle_client(stream, url, sessions, requests, shutdown).await {
+                println!("Dashboard connection failed: {:?}", e);
+            }
+        });
+    }
+}
+
+async fn handle_client(
+    mut stream: TcpStream,
+    url: String,
+    sessions: Sessions,
+    requests: mpsc::Sender<ControlRequest>,
+    shutdown: CancellationToken,
+) -> Result<()> {
+    let request = tokio::time::timeout(crate::HANDSHAKE_TIMEOUT, read_request(&mut stream))
+        .await
+        .context("Timed out waiting for request")??;
+    // a page on some other site can reach us through DNS rebinding, but not with our name
+    if !local_host(&request.host) {
+        return respond(&mut stream, "403 Forbidden", "text/plain", "forbidden").await;
+    }
+    match (request.method.as_str(), request.path.as_str()) {
+        ("GET", "/") => respond(&mut stream, "200 OK", "text/html; charset=utf-8", PAGE).await,
+        ("GET", "/events") => stream_events(stream, url, sessions, shutdown).await,
+        ("POST", path) if path.starts_with("/api/") && request.from_dashboard => {
+            let command = &path["/api/".len()..];
+            let response = match control::execute(
+                command,
+                request.body.trim(),
+                &sessions,
+                &requests,
+                &shutdown,
+            )
+            .await
+            {
+                Ok(response) => response,
+                Err(e) => json!({ "ok": false, "error": format!("{:#}", e) }),
+            };
+            respond(
+                &mut stream,
+                "200 OK",
+                "application/json",
+                &response.to_string(),
+            )
+            .await
+        }
+        _ => respond(&mut stream, "404 Not Found", "text/plain", "not found").await,
+    }
+}
+
+/// Whether `host` names this machine or an address, rather than some domain
+fn local_host(host: &str) -> bool {
+    let name = match host.strip_prefix('[') {
+        Some(v6) => v6.split(']').next().unwrap_or_default(),
+        None => host.rsplit_once(':').map_or(host, |(name, _)| name),
+    };
+    name == "localhost" || name.parse::<std::net::IpAddr>().is_ok()
+}
+
+async fn read_request(stream: &mut TcpStream) -> Result<Request> {
+    let mut buf = Vec::new();
+    let head_len = loop {
+        if let Some(end) = buf.windows(4).position(|w| w == b"\r\n\r\n") {
+            break end + 4;
+        }
+        if buf.len() > MAX_REQUEST {
+            anyhow::bail!("Request too large");
+        }
+        if stream.read_buf(&mut buf).await? == 0 {
+            anyhow::bail!("Connection closed before the request was complete");
+        }
+    };
+    let head = String::from_utf8_lossy(&buf[..head_len]).into_owned();
+    let mut lines = head.lines();
+    let mut request_line = lines.next().unwrap_or_default().split(' ');
+    let method = request_line.next().unwrap_or_default().to_owned();
+    let path = request_line.next().unwrap_or_default().to_owned();
+    let headers: Vec<_> = lines
+        .filter_map(|line| line.split_once(':'))
+        .map(|(name, value)| (name.trim().to_ascii_lowercase(), value.trim()))
+        .collect();
+    let header = |name: &str| {
+        headers
+            .iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, value)| *value)
+    };
+
+    let body_len: usize = match header("content-length") {
+        Some(len) => len.parse().context("Invalid Content-Length")?,
+        None => 0,
+    };
+    if body_len > MAX_REQUEST {
+        anyhow::bail!("Request too large");
+    }
+    while buf.len() < head_len + body_len {
+        if stream.read_buf(&mut buf).await? == 0 {
+            anyhow::bail!("Connection closed before the request was complete");
+        }
+    }
+    Ok(Request {
+        host: header("host").unwrap_or_default().to_owned(),
+        from_dashboard: header(API_HEADER).is_some(),
+        body: String::from_utf8_lossy(&buf[head_len..head_len + body_len]).into_owned(),
+        method,
+        path,
+    })
+}
+
+async fn respond(
+    stream: &mut TcpStream,
+    status: &str,
+    content_type: &str,
+    body: &str,
+) -> Result<()> {
+    let head = format!(
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        status,
+        content_type,
+        body.len()
+    );
+    stream.write_all(head.as_bytes()).await?;
+    stream.write_all(body.as_bytes()).await?;
+    stream.shutdown().await?;
+    Ok(())
+}
+
+/// Send activity to the page as server-sent events until it goes away
+async fn stream_events(
+    mut stream: TcpStream,
+    url: String,
+    sessions: Sessions,
+    shutdown: CancellationToken,
+) -> Result<()> {
+    stream
+        .write_all(b"HTTP/1.1 200 OK\r\nContent-Type: text/event-stream\r\nCache-Control: no-cache\r\nConnection: close\r\n\r\n")
+        .await?;
+    let mut activity = activity::subscribe();
+    let mut status = tokio::time::interval(STATUS_INTERVAL);
+    send_event(&mut stream, json!({ "type": "hello", "url": url })).await?;
+    loop {
+        let event = tokio::select! {
+            received = activity.recv() => match received {
+                Ok(activity) => event(activity),
+                Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                Err(broadcast::error::RecvError::Closed) => return Ok(()),
+            },
+            _ = status.tick() => json!({ "type": "sessions", "sessions": sessions.list() }),
+            _ = shutdown.cancelled() => return Ok(()),
+        };
+        send_event(&mut stream, event).await?;
+    }
+}
+
+async fn send_event(stream: &mut TcpStream, event: Value) -> Result<()> {
+    stream
+        .write_all(format!("data: {}\n\n", event).as_bytes())
+        .await?;
+    Ok(())
+}
+
+fn event(activity: Activity) -> Value {
+    match activity {
+        Activity::Uart(bytes) => json!({ "type": "uart", "text": String::from_utf8_lossy(&bytes) }),
+        Activity::Gdb { to_target, packet } => {
+            json!({ "type": "gdb", "toTarget": to_target, "packet": packet })
+        }
+        Activity::Transfer(status) => json!({ "type": "transfer", "status": status }),
+    }
+}
@@ -0,0 +1,73 @@
+use crate::command;
+use anyhow::{Context, Result};
+use std::path::{Path, PathBuf};
+use tokio::sync::{mpsc, watch};
+
+/// GDB commands to load the elf, and the bootloader's symbols if given, and connect to our GDB
+/// server
+pub fn init_script(elf: &Path, bootloader: Option<&Path>, gdb_addr: &str) -> Result<String> {
+    let mut script = format!(
+        "# generated by wokwi-server, regenerated every time the server starts\nfile \"{}\"\n",
+        gdb_path(elf)?
+    );
+    if let Some(bootloader) = bootloader {
+        script += &format!("add-symbol-file \"{}\"\n", gdb_path(bootloader)?);
+    }
+    script += &format!("target remote {}\n", gdb_addr);
+    Ok(script)
+}
+
+/// An absolute path as GDB wants it, with forward slashes on Windows too
+fn gdb_path(path: &Path) -> Result<String> {
+    Ok(std::fs::canonicalize(path)?
+        .display()
+        .to_string()
+        .replace('\\', "/"))
+}
+
+/// Launch a GDB instance attached to our GDB server once the simulation has started,
+/// requesting a shutdown of the server when it exits. `running` is set for as long as GDB is
+#[allow(clippy::too_many_arguments)]
+pub async fn launch(
+    gdb: String,
+    gdb_args: Option<String>,
+    elf: PathBuf,
+    bootloader: Option<PathBuf>,
+    gdb_addr: String,
+    mut started: watch::Receiver<bool>,
+    exit: mpsc::Sender<i32>,
+    running: watch::Sender<bool>,
+) -> Result<()> {
+    let mut words = command::split(&gdb);
+    if let Some(args) = gdb_args {
+        words.extend(command::split(&args));
+    }
+    let (program, args) = words.split_first().context("GDB command is empty")?;
+
+    while !*started.borrow() {
+        started.changed().await?;
+    }
+
+    println!("Launching {}", program);
+    let mut command = tokio::process::Command::new(program);
+    command.args(args).arg(&elf);
+    if let Some(bootloader) = &bootloader {
+        command
+            .arg("-ex")
+            .arg(format!("add-symbol-file \"{}\"", gdb_path(bootloader)?));
+    }
+    let mut child = command
+        .arg("-ex")
+        .arg(format!("target remote {}", gdb_addr))
+        .kill_on_drop(true)
+        .spawn()
+        .with_context(|| format!("Failed to launch `{}`", program))?;
+    running.send(true).ok();
+    let status = child.wait().await;
+    running.send(false).ok();
+    let status = status.with_context(|| format!("Failed to wait for `{}`", program))?;
+    println!("GDB exited ({})", status);
+
+    exit.send(0).await.ok();
+    Ok(())
+}
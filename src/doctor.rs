@@ -0,0 +1,294 @@
+use crate::catalog::Code;
+use crate::image;
+use crate::project::{self, ProjectConfig, Sdkconfig};
+use crate::{container, endpoint, GDB_PORT, PORT, URL_BASE};
+use anyhow::Result;
+use espflash::Chip;
+use std::net::{Ipv4Addr, TcpListener};
+use std::path::{Path, PathBuf};
+use wokwi_server::chips;
+
+/// Check the project and environment for problems which would stop a simulation
+#[derive(clap::Args, Debug)]
+pub struct DoctorArgs {
+    /// chip the firmware is built for, enables the checks which depend on it
+    #[clap(short, long)]
+    chip: Option<Chip>,
+
+    /// directory of the project to check, defaults to the current directory
+    #[clap(long)]
+    project_dir: Option<PathBuf>,
+
+    /// port the simulator will connect to
+    #[clap(long, default_value_t = PORT)]
+    port: u16,
+
+    /// port the GDB server will listen on
+    #[clap(long, default_value_t = GDB_PORT)]
+    gdb_port: u16,
+
+    /// url of the Wokwi embed the simulation will be loaded from
+    #[clap(long, value_name = "URL", env = "WOKWI_URL_BASE", default_value = URL_BASE, value_parser = crate::parse_url_base)]
+    wokwi_url_base: url::Url,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Status {
+    Ok,
+    Warning,
+    Error,
+}
+
+#[derive(Default)]
+struct Report {
+    errors: usize,
+}
+
+impl Report {
+    fn add(&mut self, status: Status, message: impl std::fmt::Display) {
+        let label = match status {
+            Status::Ok => "ok",
+            Status::Warning => "warning",
+            Status::Error => "error",
+        };
+        println!("{:>8}  {}", label, message);
+        if status == Status::Error {
+            self.errors += 1;
+        }
+    }
+}
+
+pub fn run(args: DoctorArgs) -> Result<i32> {
+    let cwd = std::env::current_dir()?;
+    let dir = match &args.project_dir {
+        Some(dir) => project::resolve(&cwd, dir),
+        None => cwd,
+    };
+    let mut report = Report::default();
+
+    let elf = check_project(&mut report, &dir)?;
+    check_sdkconfig(&mut report, &dir)?;
+    match args.chip {
+        Some(chip) => check_chip(&mut report, chip, elf.as_deref()),
+        None => report.add(Status::Warning, "no --chip given, skipping the chip checks"),
+    }
+    for (port, flag) in [(args.port, "--port"), (args.gdb_port, "--gdb-port")] {
+        if port == 0 {
+            report.add(Status::Ok, format!("{} 0 picks a free port on start", flag));
+            continue;
+        }
+        match TcpListener::bind((Ipv4Addr::LOCALHOST, port)) {
+            Ok(_) => report.add(Status::Ok, format!("port {} is free", port)),
+            Err(e) => {
+                let reason = match crate::port_owner::find(port) {
+                    Some(owner) => format!("in use by {}", owner),
+                    None => e.to_string(),
+                };
+                report.add(
+                    Status::Error,
+                    format!(
+                        "port {} can't be used ({}), choose another with {} ({})",
+                        port,
+                        reason,
+                        flag,
+                        Code::PortInUse
+                    ),
+                )
+            }
+        }
+    }
+    // the simulation can start without it, e.g. when the browser has another way out
+    match endpoint::probe(&args.wokwi_url_base) {
+        Ok(()) => report.add(
+            Status::Ok,
+            format!("the Wokwi embed at {} can be reached", args.wokwi_url_base),
+        ),
+        Err(e) => report.add(
+            Status::Warning,
+            format!(
+                "the Wokwi embed at {} can't be reached ({}), check the network connection and proxy",
+                args.wokwi_url_base, e
+            ),
+        ),
+    }
+    if container::in_container() {
+        report.add(
+            Status::Warning,
+            "running in a container, publish the ports and open the simulation link from the host",
+        );
+    }
+
+    if report.errors > 0 {
+        println!("{} problem(s) found", report.errors);
+        return Ok(1);
+    }
+    println!("No problems found");
+    Ok(0)
+}
+
+/// Check wokwi.toml, returning the elf it names
+fn check_project(report: &mut Report, dir: &Path) -> Result<Option<PathBuf>> {
+    let config = match ProjectConfig::find(dir) {
+        Ok(config) => config,
+        Err(e) => {
+            report.add(Status::Error, format!("{:#}", e));
+            return Ok(None);
+        }
+    };
+    let Some(config) = config else {
+        report.add(
+            Status::Warning,
+            format!(
+                "no {} in {} or its parents, the elf has to be given on the command line (`wokwi-server init` writes one)",
+                project::CONFIG_FILE,
+                dir.display()
+            ),
+        );
+        return Ok(None);
+    };
+    let path = config.dir.join(project::CONFIG_FILE);
+    let Some(elf) = config.elf else {
+        report.add(
+            Status::Warning,
+            format!("{} doesn't name an elf", path.display()),
+        );
+        return Ok(None);
+    };
+    if !elf.is_file() {
+        report.add(
+            Status::Error,
+            format!(
+                "{} names {}, which doesn't exist, build the project first",
+                path.display(),
+                elf.display()
+            ),
+        );
+        return Ok(None);
+    }
+    report.add(
+        Status::Ok,
+        format!("{} names {}", path.display(), elf.display()),
+    );
+    Ok(Some(elf))
+}
+
+fn check_sdkconfig(report: &mut Report, dir: &Path) -> Result<()> {
+    let sdkconfig = match Sdkconfig::find(dir) {
+        Ok(Some(sdkconfig)) => sdkconfig,
+        Ok(None) => return Ok(()),
+        Err(e) => {
+            report.add(Status::Error, format!("{:#}", e));
+            return Ok(());
+        }
+    };
+    let Some(offset) = sdkconfig.get("CONFIG_PARTITION_TABLE_OFFSET") else {
+        return Ok(());
+    };
+    match image::parse_offset(offset) {
+        Ok(offset) => report.add(
+            Status::Ok,
+            format!(
+                "{} puts the partition table at {:#x}",
+                sdkconfig.path.display(),
+                offset
+            ),
+        ),
+        Err(e) => report.add(
+            Status::Error,
+            format!(
+                "invalid CONFIG_PARTITION_TABLE_OFFSET in {}: {}",
+                sdkconfig.path.display(),
+                e
+            ),
+        ),
+    }
+    Ok(())
+}
+
+fn check_chip(report: &mut Report, chip: Chip, elf: Option<&Path>) {
+    match chips::lookup(chip) {
+        Some(_) => report.add(Status::Ok, format!("Wokwi simulates {}", chip)),
+        None => report.add(
+            Status::Warning,
+            format!(
+                "{} isn't known to be simulated by Wokwi, it needs --force-chip and --id",
+                chip
+            ),
+        ),
+    }
+
+    if let Some(elf) = elf {
+        check_elf_machine(report, chip, elf);
+    }
+
+    let candidates = gdb_names(chip);
+    match candidates.iter().find(|name| on_path(name)) {
+        Some(gdb) => report.add(Status::Ok, format!("{} is available for debugging", gdb)),
+        None => report.add(
+            Status::Warning,
+            format!(
+                "{} isn't on PATH, it is needed to debug with --gdb",
+                candidates.join(" or ")
+            ),
+        ),
+    }
+}
+
+/// Check that the elf was built for the chip's architecture
+fn check_elf_machine(report: &mut Report, chip: Chip, elf: &Path) {
+    let bytes = match std::fs::read(elf) {
+        Ok(bytes) => bytes,
+        Err(e) => {
+            return report.add(
+                Status::Error,
+                format!("can't read {}: {}", elf.display(), e),
+            )
+        }
+    };
+    if let Err(e) = xmas_elf::ElfFile::new(&bytes) {
+        return report.add(
+            Status::Error,
+            format!(
+                "{} isn't a valid elf: {} ({})",
+                elf.display(),
+                e,
+                Code::InvalidElf
+            ),
+        );
+    }
+    if image::built_for(&bytes, chip) {
+        report.add(
+            Status::Ok,
+            format!("{} is built for {}", elf.display(), chip),
+        );
+    } else {
+        report.add(
+            Status::Error,
+            format!(
+                "{} isn't built for {}, check --chip and the build target ({})",
+                elf.display(),
+                chip,
+                Code::ElfArchMismatch
+            ),
+        );
+    }
+}
+
+/// GDB executables which can debug the chip, the unified Xtensa one comes with newer toolchains
+fn gdb_names(chip: Chip) -> &'static [&'static str] {
+    match chip {
+        Chip::Esp32 => &["xtensa-esp32-elf-gdb", "xtensa-esp-elf-gdb"],
+        Chip::Esp32s2 => &["xtensa-esp32s2-elf-gdb", "xtensa-esp-elf-gdb"],
+        Chip::Esp32s3 => &["xtensa-esp32s3-elf-gdb", "xtensa-esp-elf-gdb"],
+        Chip::Esp32c3 | Chip::Esp32c2 => &["riscv32-esp-elf-gdb"],
+        Chip::Esp8266 => &["xtensa-lx106-elf-gdb"],
+    }
+}
+
+fn on_path(name: &str) -> bool {
+    let Some(path) = std::env::var_os("PATH") else {
+        return false;
+    };
+    let file = format!("{}{}", name, std::env::consts::EXE_SUFFIX);
+    std::env::split_paths(&path).any(|dir| dir.join(&file).is_file())
+}
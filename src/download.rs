@@ -0,0 +1,60 @@
+//! Firmware given as an `http://` or `https://` url, e.g. a CI artifact, is downloaded to the
+//! temporary directory compressed inputs are written to, and used from there
+
+use crate::compressed;
+use anyhow::{Context, Result};
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+/// Whether an input is a url to download rather than a path
+pub fn is_url(path: &Path) -> bool {
+    path.to_str()
+        .is_some_and(|s| s.starts_with("http://") || s.starts_with("https://"))
+}
+
+/// Parse a `--http-header` as `<name>: <value>`
+pub fn parse_header(s: &str) -> Result<(String, String), String> {
+    match s.split_once(':') {
+        Some((name, value)) if !name.trim().is_empty() => {
+            Ok((name.trim().to_owned(), value.trim().to_owned()))
+        }
+        _ => Err("expected `<name>: <value>`".to_owned()),
+    }
+}
+
+/// Download `url` with the extra `headers`, returning where it was written
+pub fn fetch(url: &Path, headers: &[(String, String)]) -> Result<PathBuf> {
+    let url = url.to_string_lossy();
+    let parsed = url::Url::parse(&url).with_context(|| format!("Invalid url {}", url))?;
+    let name = parsed
+        .path_segments()
+        .and_then(|mut segments| segments.next_back())
+        .filter(|name| !name.is_empty())
+        .unwrap_or("firmware");
+
+    println!("Downloading {}", url);
+    let mut request = crate::http::agent(&url)?.get(&url);
+    for (name, value) in headers {
+        request = request.set(name, value);
+    }
+    let response = request.call().map_err(|e| match e {
+        ureq::Error::Status(code @ (401 | 403), _) => anyhow::anyhow!(
+            "Downloading {} was refused ({}), pass its credentials with --http-header",
+            url,
+            code
+        ),
+        e => anyhow::anyhow!("Failed to download {}: {}", url, e),
+    })?;
+    let mut data = Vec::new();
+    response
+        .into_reader()
+        .read_to_end(&mut data)
+        .with_context(|| format!("Failed to download {}", url))?;
+
+    let dir = compressed::dir();
+    std::fs::create_dir_all(&dir).with_context(|| format!("Failed to create {}", dir.display()))?;
+    let path = dir.join(name);
+    std::fs::write(&path, &data).with_context(|| format!("Failed to write {}", path.display()))?;
+    println!("Downloaded {} ({} bytes)", url, data.len());
+    Ok(path)
+}
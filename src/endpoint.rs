@@ -0,0 +1,72 @@
+//! Checking the Wokwi embed can be reached, so a network problem shows up when the server starts
+//! rather than as a browser tab that never connects
+
+use crate::ServerArgs;
+use std::time::Duration;
+use url::Url;
+
+/// how long to wait for the embed to answer
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Make sure the embed of `server` can be reached, or switch to the first of its fallbacks that
+/// can. Without fallbacks, nothing waits for the check, which warns in the background. `quiet`
+/// leaves out the warnings
+pub async fn check(server: &mut ServerArgs, quiet: bool) {
+    if server.no_probe {
+        return;
+    }
+    let base = server.wokwi_url_base.clone();
+    if server.wokwi_url_fallback.is_empty() {
+        // a thread rather than a blocking task, which the runtime would wait for at exit
+        std::thread::spawn(move || match probe(&base) {
+            Err(e) if !quiet => unreachable(&base, &e),
+            _ => {}
+        });
+        return;
+    }
+
+    let mut first_error = None;
+    for url in std::iter::once(&base).chain(&server.wokwi_url_fallback) {
+        let probed = url.clone();
+        let result = tokio::task::spawn_blocking(move || probe(&probed))
+            .await
+            .unwrap_or_else(|e| Err(e.to_string()));
+        match result {
+            Ok(()) if *url == base => return,
+            Ok(()) => {
+                if !quiet {
+                    println!("{} can't be reached, using {} instead", base, url);
+                }
+                server.wokwi_url_base = url.clone();
+                return;
+            }
+            Err(e) => {
+                first_error.get_or_insert(e);
+            }
+        }
+    }
+    if !quiet {
+        unreachable(&base, &first_error.unwrap_or_default());
+    }
+}
+
+/// Whether `url` answers at all. Any HTTP status will do, as the embed's base needn't be a page
+/// of its own
+pub fn probe(url: &Url) -> Result<(), String> {
+    let agent = crate::http::agent(url.as_str()).map_err(|e| format!("{:#}", e))?;
+    match agent.head(url.as_str()).timeout(TIMEOUT).call() {
+        Ok(_) | Err(ureq::Error::Status(..)) => Ok(()),
+        // without the url, which is in the message around it
+        Err(ureq::Error::Transport(e)) => Err(match e.message() {
+            Some(message) => format!("{}: {}", e.kind(), message),
+            None => e.kind().to_string(),
+        }),
+    }
+}
+
+fn unreachable(url: &Url, error: &str) {
+    println!(
+        "Warning: {} can't be reached ({}), so the simulation won't load in the browser. Check the network connection and proxy, or use another Wokwi with --wokwi-url-base",
+        url, error
+    );
+}
@@ -0,0 +1,40 @@
+//! Errors returned by the library, grouped by what went wrong so callers can match on them
+
+use thiserror::Error;
+
+/// Why a library call failed
+#[derive(Debug, Error)]
+pub enum WokwiServerError {
+    /// reading or writing a file or socket failed
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+    /// the simulator sent something we or a plugin can't handle, or a message can't be sent to it
+    #[error("{0}")]
+    Protocol(String),
+    /// the firmware or a flash image built from it is unusable
+    #[error("{0}")]
+    Image(String),
+    /// a GDB packet or response is malformed
+    #[error("{0}")]
+    Gdb(String),
+}
+
+pub type Result<T, E = WokwiServerError> = std::result::Result<T, E>;
+
+/// Return early with a [`WokwiServerError`] of the given kind, formatted like `format!`
+macro_rules! bail {
+    ($kind:ident, $($arg:tt)*) => {
+        return Err($crate::error::WokwiServerError::$kind(format!($($arg)*)))
+    };
+}
+
+/// Return early with a [`WokwiServerError`] of the given kind unless the condition holds
+macro_rules! ensure {
+    ($cond:expr, $kind:ident, $($arg:tt)*) => {
+        if !$cond {
+            $crate::error::bail!($kind, $($arg)*);
+        }
+    };
+}
+
+pub(crate) use {bail, ensure};
@@ -0,0 +1,51 @@
+use regex::Regex;
+
+/// The default marker, e.g. `WOKWI_EXIT 1`
+pub const DEFAULT_PATTERN: &str = r"WOKWI_EXIT (-?\d+)";
+
+/// lines longer than this are truncated to it rather than buffered indefinitely
+const MAX_LINE: usize = 4096;
+
+/// Watches UART output for a line requesting the server to exit
+pub struct ExitMarker {
+    regex: Regex,
+    line: Vec<u8>,
+}
+
+impl ExitMarker {
+    pub fn new(regex: Regex) -> Self {
+        Self {
+            regex,
+            line: Vec::new(),
+        }
+    }
+
+    /// Feed UART output, returning the exit code captured from the first matching line
+    pub fn feed(&mut self, bytes: &[u8]) -> Option<i32> {
+        let mut code = None;
+        for &b in bytes {
+            if b == b'\n' {
+                let line = std::mem::take(&mut self.line);
+                code = code.or_else(|| self.matches(&line));
+            } else if self.line.len() < MAX_LINE {
+                self.line.push(b);
+            }
+        }
+        code
+    }
+
+    fn matches(&self, line: &[u8]) -> Option<i32> {
+        let line = String::from_utf8_lossy(line);
+        let captures = self.regex.captures(line.trim_end_matches('\r'))?;
+        captures.get(1)?.as_str().parse().ok()
+    }
+}
+
+/// Parse a marker regex, which must capture the exit code in its first group
+pub fn parse_pattern(s: &str) -> Result<Regex, String> {
+    let regex = Regex::new(s).map_err(|e| e.to_string())?;
+    if regex.captures_len() < 2 {
+        return Err("the pattern needs a capture group for the exit code".to_owned());
+    }
+    Ok(regex)
+}
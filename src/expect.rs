@@ -0,0 +1,80 @@
+use std::collections::VecDeque;
+
+/// lines longer than this are split rather than buffered indefinitely
+const MAX_LINE: usize = 4096;
+
+/// How a headless run ended
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Outcome {
+    Passed,
+    /// the firmware misbehaved, e.g. an expectation wasn't met
+    Failed(String),
+    /// the run couldn't be completed, e.g. the simulator disconnected
+    Error(String),
+}
+
+impl Outcome {
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            Outcome::Passed => 0,
+            _ => 1,
+        }
+    }
+}
+
+/// Checks UART output for lines that must appear, in order, and lines that must not
+pub struct Expectations {
+    pending: VecDeque<String>,
+    fail_on: Vec<String>,
+    line: Vec<u8>,
+}
+
+impl Expectations {
+    pub fn new(expect: Vec<String>, fail_on: Vec<String>) -> Self {
+        Self {
+            pending: expect.into(),
+            fail_on,
+            line: Vec::new(),
+        }
+    }
+
+    /// Feed UART output, returning the outcome once it has been decided
+    pub fn feed(&mut self, bytes: &[u8]) -> Option<Outcome> {
+        for &b in bytes {
+            if b != b'\n' && self.line.len() < MAX_LINE {
+                self.line.push(b);
+                continue;
+            }
+            let line = std::mem::take(&mut self.line);
+            if let Some(outcome) = self.check(&String::from_utf8_lossy(&line)) {
+                return Some(outcome);
+            }
+        }
+        None
+    }
+
+    /// The outcome when the run is cut short by a timeout
+    pub fn timed_out(&self) -> Outcome {
+        match self.pending.front() {
+            Some(expected) => Outcome::Failed(format!("Timed out waiting for `{}`", expected)),
+            None => Outcome::Passed,
+        }
+    }
+
+    fn check(&mut self, line: &str) -> Option<Outcome> {
+        if let Some(pattern) = self.fail_on.iter().find(|p| line.contains(p.as_str())) {
+            return Some(Outcome::Failed(format!(
+                "Found `{}` in `{}`",
+                pattern,
+                line.trim_end()
+            )));
+        }
+        if matches!(self.pending.front(), Some(expected) if line.contains(expected.as_str())) {
+            self.pending.pop_front();
+            if self.pending.is_empty() {
+                return Some(Outcome::Passed);
+            }
+        }
+        None
+    }
+}
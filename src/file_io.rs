@@ -0,0 +1,414 @@
+//! The GDB File-I/O protocol (`F` packets), serving the firmware's open, read, write... calls
+//! from a host directory rather than GDB's. Paths and buffers live in the target's memory, so a
+//! call may take a few memory reads and writes through the simulator before it is answered
+
+use std::collections::HashMap;
+use std::fs::{File, Metadata, OpenOptions};
+use std::io::{self, ErrorKind, Read, Seek, SeekFrom, Write};
+use std::path::{Component, Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// the most read or written in one call, the firmware is told about the short count
+const MAX_READ: u32 = 16 * 1024;
+
+/// open flags, as sent in `Fopen`
+const O_WRONLY: u32 = 0x1;
+const O_RDWR: u32 = 0x2;
+const O_APPEND: u32 = 0x8;
+const O_CREAT: u32 = 0x200;
+const O_TRUNC: u32 = 0x400;
+const O_EXCL: u32 = 0x800;
+
+/// errno values of the protocol
+const EPERM: u32 = 1;
+const ENOENT: u32 = 2;
+const EBADF: u32 = 9;
+const EACCES: u32 = 13;
+const EFAULT: u32 = 14;
+const EEXIST: u32 = 17;
+const ENOTDIR: u32 = 20;
+const EISDIR: u32 = 21;
+const EINVAL: u32 = 22;
+const EUNKNOWN: u32 = 9999;
+
+/// file types of `st_mode`
+const S_IFREG: u32 = 0o100000;
+const S_IFDIR: u32 = 0o040000;
+const S_IFCHR: u32 = 0o020000;
+
+/// the console descriptors, stdin reads nothing and the others print on the server's output
+const STDIN: u32 = 0;
+const STDERR: u32 = 2;
+
+/// What a call needs next
+#[derive(Debug, PartialEq, Eq)]
+pub enum Step {
+    /// the contents of memory, passed to [`FileIo::feed`]
+    Read { addr: u32, len: u32 },
+    /// write memory, then call [`FileIo::feed`] once the simulator has
+    Write { addr: u32, data: Vec<u8> },
+    /// the reply which lets the firmware carry on, e.g. `F3` or `F-1,2`
+    Reply(String),
+}
+
+/// A call waiting on the simulator
+enum Pending {
+    Open {
+        flags: u32,
+    },
+    Write {
+        fd: u32,
+    },
+    /// reading the old path, then the new one at `to`
+    Rename {
+        from: Option<PathBuf>,
+        to: (u32, u32),
+    },
+    Unlink,
+    Stat {
+        buf: u32,
+    },
+    /// memory has been written and this is the reply
+    Written(String),
+}
+
+/// The files the firmware has open, all inside `root`
+pub struct FileIo {
+    root: PathBuf,
+    files: HashMap<u32, File>,
+    next_fd: u32,
+    pending: Option<Pending>,
+}
+
+impl FileIo {
+    /// Serve files from the directory `root`
+    pub fn new(root: &Path) -> io::Result<Self> {
+        let root = root.canonicalize()?;
+        if !root.is_dir() {
+            return Err(io::Error::from(ErrorKind::NotADirectory));
+        }
+        Ok(Self {
+            root,
+            files: HashMap::new(),
+            next_fd: STDERR + 1,
+            pending: None,
+        })
+    }
+
+    /// Whether a call is waiting for memory to be read or written
+    pub fn is_pending(&self) -> bool {
+        self.pending.is_some()
+    }
+
+    /// Start on `packet` from the simulator, e.g. `Fopen,3ffb0000/9,0,1a4`. `None` if it isn't
+    /// a File-I/O call
+    pub fn request(&mut self, packet: &str) -> Option<Step> {
+        let request = packet.strip_prefix('F')?;
+        let (call, args) = request.split_once(',').unwrap_or((request, ""));
+        if call.is_empty() || !call.bytes().all(|b| b.is_ascii_lowercase()) {
+            return None;
+        }
+        self.pending = None;
+        let args: Vec<&str> = args.split(',').collect();
+        Some(self.start(call, &args).unwrap_or_else(|| error(EINVAL)))
+    }
+
+    /// Carry on with the memory asked for by the last step, or an empty slice once memory has
+    /// been written. `None` when the simulator couldn't read or write it, which fails the call
+    pub fn feed(&mut self, memory: Option<&[u8]>) -> Step {
+        let pending = self.pending.take();
+        let Some(memory) = memory else {
+            return error(EFAULT);
+        };
+        match pending {
+            Some(Pending::Open { flags }) => self
+                .path(memory)
+                .map_or_else(error, |path| result(self.open(&path, flags).map(u64::from))),
+            Some(Pending::Write { fd }) => result(self.write(fd, memory).map(|n| n as u64)),
+            Some(Pending::Rename { from: None, to }) => match self.path(memory) {
+                Ok(from) => {
+                    self.pending = Some(Pending::Rename {
+                        from: Some(from),
+                        to,
+                    });
+                    Step::Read {
+                        addr: to.0,
+                        len: to.1,
+                    }
+                }
+                Err(errno) => error(errno),
+            },
+            Some(Pending::Rename {
+                from: Some(from), ..
+            }) => self
+                .path(memory)
+                .map_or_else(error, |to| result(std::fs::rename(from, to).map(|_| 0))),
+            Some(Pending::Unlink) => self
+                .path(memory)
+                .map_or_else(error, |path| result(std::fs::remove_file(path).map(|_| 0))),
+            Some(Pending::Stat { buf }) => {
+                let metadata = self
+                    .path(memory)
+                    .and_then(|path| std::fs::metadata(path).map_err(|e| errno(&e)));
+                match metadata {
+                    Ok(metadata) => self.written(buf, stat(&metadata), 0),
+                    Err(errno) => error(errno),
+                }
+            }
+            Some(Pending::Written(reply)) => Step::Reply(reply),
+            None => error(EINVAL),
+        }
+    }
+
+    fn start(&mut self, call: &str, args: &[&str]) -> Option<Step> {
+        let arg = |i: usize| args.get(i).and_then(|arg| hex(arg));
+        let step = match call {
+            "open" => {
+                let (addr, len) = pointer(args.first()?)?;
+                self.pending = Some(Pending::Open { flags: arg(1)? });
+                Step::Read { addr, len }
+            }
+            "close" => {
+                let fd = arg(0)?;
+                match self.files.remove(&fd) {
+                    Some(_) => reply(0),
+                    None if fd <= STDERR => reply(0),
+                    None => error(EBADF),
+                }
+            }
+            "read" => {
+                let (fd, buf, count) = (arg(0)?, arg(1)?, arg(2)?);
+                let mut data = vec![0; count.min(MAX_READ) as usize];
+                let read = match self.files.get_mut(&fd) {
+                    Some(file) => file.read(&mut data),
+                    None if fd == STDIN => Ok(0),
+                    None => return Some(error(EBADF)),
+                };
+                match read {
+                    Ok(0) => reply(0),
+                    Ok(n) => {
+                        data.truncate(n);
+                        self.written(buf, data, n as u64)
+                    }
+                    Err(e) => error(errno(&e)),
+                }
+            }
+            "write" => {
+                let (fd, buf, count) = (arg(0)?, arg(1)?, arg(2)?);
+                if count == 0 {
+                    return Some(reply(0));
+                }
+                self.pending = Some(Pending::Write { fd });
+                Step::Read {
+                    addr: buf,
+                    len: count.min(MAX_READ),
+                }
+            }
+            "lseek" => {
+                let fd = arg(0)?;
+                let offset = signed(args.get(1)?)?;
+                let from = match arg(2)? {
+                    0 => SeekFrom::Start(u64::try_from(offset).ok()?),
+                    1 => SeekFrom::Current(offset),
+                    2 => SeekFrom::End(offset),
+                    _ => return None,
+                };
+                match self.files.get_mut(&fd) {
+                    Some(file) => result(file.seek(from)),
+                    None => error(EBADF),
+                }
+            }
+            "rename" => {
+                let (addr, len) = pointer(args.first()?)?;
+                let to = pointer(args.get(1)?)?;
+                self.pending = Some(Pending::Rename { from: None, to });
+                Step::Read { addr, len }
+            }
+            "unlink" => {
+                let (addr, len) = pointer(args.first()?)?;
+                self.pending = Some(Pending::Unlink);
+                Step::Read { addr, len }
+            }
+            "stat" => {
+                let (addr, len) = pointer(args.first()?)?;
+                self.pending = Some(Pending::Stat { buf: arg(1)? });
+                Step::Read { addr, len }
+            }
+            "fstat" => {
+                let (fd, buf) = (arg(0)?, arg(1)?);
+                let stat = match self.files.get(&fd) {
+                    Some(file) => match file.metadata() {
+                        Ok(metadata) => stat(&metadata),
+                        Err(e) => return Some(error(errno(&e))),
+                    },
+                    None if fd <= STDERR => console_stat(),
+                    None => return Some(error(EBADF)),
+                };
+                self.written(buf, stat, 0)
+            }
+            "gettimeofday" => {
+                let now = SystemTime::now().duration_since(UNIX_EPOCH).ok()?;
+                let mut timeval = (now.as_secs() as u32).to_be_bytes().to_vec();
+                timeval.extend(u64::from(now.subsec_micros()).to_be_bytes());
+                self.written(arg(0)?, timeval, 0)
+            }
+            "isatty" => reply(u64::from(arg(0)? <= STDERR)),
+            // the firmware doesn't get to run commands on the host
+            "system" => error(EPERM),
+            _ => error(EUNKNOWN),
+        };
+        Some(step)
+    }
+
+    /// Write `data` to `addr`, then reply with `value`
+    fn written(&mut self, addr: u32, data: Vec<u8>, value: u64) -> Step {
+        self.pending = Some(Pending::Written(format!("F{:x}", value)));
+        Step::Write { addr, data }
+    }
+
+    /// The host path of a NUL terminated path from the firmware, which can't leave the root
+    fn path(&self, memory: &[u8]) -> Result<PathBuf, u32> {
+        let end = memory.iter().position(|&b| b == 0).unwrap_or(memory.len());
+        let name = std::str::from_utf8(&memory[..end]).map_err(|_| EINVAL)?;
+        if name.is_empty() {
+            return Err(ENOENT);
+        }
+        let mut path = self.root.clone();
+        for component in Path::new(name).components() {
+            match component {
+                Component::Normal(part) => path.push(part),
+                Component::RootDir | Component::CurDir => {}
+                Component::ParentDir | Component::Prefix(_) => return Err(EACCES),
+            }
+        }
+        // a symlink in the root mustn't lead out of it either, and neither may a dangling one,
+        // which creating the file would follow to wherever it points
+        let existing = path.ancestors().find(|p| p.symlink_metadata().is_ok());
+        match existing.and_then(|p| p.canonicalize().ok()) {
+            Some(real) if real.starts_with(&self.root) => Ok(path),
+            _ => Err(EACCES),
+        }
+    }
+
+    fn open(&mut self, path: &Path, flags: u32) -> io::Result<u32> {
+        if path.is_dir() {
+            return Err(io::Error::from(ErrorKind::IsADirectory));
+        }
+        let file = OpenOptions::new()
+            .read(flags & O_WRONLY == 0)
+            .write(flags & (O_WRONLY | O_RDWR) != 0 && flags & O_APPEND == 0)
+            .append(flags & O_APPEND != 0)
+            .truncate(flags & O_TRUNC != 0)
+            .create(flags & O_CREAT != 0 && flags & O_EXCL == 0)
+            .create_new(flags & O_CREAT != 0 && flags & O_EXCL != 0)
+            .open(path)?;
+        let fd = self.next_fd;
+        self.next_fd += 1;
+        self.files.insert(fd, file);
+        Ok(fd)
+    }
+
+    fn write(&mut self, fd: u32, data: &[u8]) -> io::Result<usize> {
+        match self.files.get_mut(&fd) {
+            Some(file) => file.write(data),
+            None if fd != STDIN && fd <= STDERR => {
+                let mut stdout = io::stdout();
+                stdout.write_all(data)?;
+                stdout.flush()?;
+                Ok(data.len())
+            }
+            None => Err(io::Error::from_raw_os_error(EBADF as i32)),
+        }
+    }
+}
+
+fn reply(value: u64) -> Step {
+    Step::Reply(format!("F{:x}", value))
+}
+
+fn error(errno: u32) -> Step {
+    Step::Reply(format!("F-1,{:x}", errno))
+}
+
+fn result(result: io::Result<u64>) -> Step {
+    match result {
+        Ok(value) => reply(value),
+        Err(e) => error(errno(&e)),
+    }
+}
+
+/// The protocol's errno for `e`
+fn errno(e: &io::Error) -> u32 {
+    match e.kind() {
+        ErrorKind::NotFound => ENOENT,
+        ErrorKind::PermissionDenied => EACCES,
+        ErrorKind::AlreadyExists => EEXIST,
+        ErrorKind::NotADirectory => ENOTDIR,
+        ErrorKind::IsADirectory => EISDIR,
+        ErrorKind::InvalidInput => EINVAL,
+        _ if e.raw_os_error() == Some(EBADF as i32) => EBADF,
+        _ => EUNKNOWN,
+    }
+}
+
+/// The protocol's `struct stat`, 64 big endian bytes
+fn stat(metadata: &Metadata) -> Vec<u8> {
+    let kind = if metadata.is_dir() { S_IFDIR } else { S_IFREG };
+    let permissions = if metadata.permissions().readonly() {
+        0o444
+    } else {
+        0o644
+    };
+    let seconds = |time: io::Result<SystemTime>| {
+        time.ok()
+            .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+            .map_or(0, |d| d.as_secs() as u32)
+    };
+    encode_stat(
+        kind | permissions,
+        metadata.len(),
+        [
+            seconds(metadata.accessed()),
+            seconds(metadata.modified()),
+            seconds(metadata.created()),
+        ],
+    )
+}
+
+fn console_stat() -> Vec<u8> {
+    encode_stat(S_IFCHR | 0o666, 0, [0; 3])
+}
+
+fn encode_stat(mode: u32, size: u64, times: [u32; 3]) -> Vec<u8> {
+    let mut stat = Vec::with_capacity(64);
+    // st_dev, st_ino, st_mode, st_nlink, st_uid, st_gid, st_rdev
+    for field in [0, 0, mode, 1, 0, 0, 0] {
+        stat.extend(u32::to_be_bytes(field));
+    }
+    // st_size, st_blksize, st_blocks
+    for field in [size, 512, size.div_ceil(512)] {
+        stat.extend(u64::to_be_bytes(field));
+    }
+    for time in times {
+        stat.extend(time.to_be_bytes());
+    }
+    stat
+}
+
+fn hex(arg: &str) -> Option<u32> {
+    u32::from_str_radix(arg, 16).ok()
+}
+
+/// A signed hex number, e.g. `-10`
+fn signed(arg: &str) -> Option<i64> {
+    match arg.strip_prefix('-') {
+        Some(arg) => i64::from_str_radix(arg, 16).ok().map(|n| -n),
+        None => i64::from_str_radix(arg, 16).ok(),
+    }
+}
+
+/// A pointer to a string, `<addr>/<len>` where the length counts the trailing NUL
+fn pointer(arg: &str) -> Option<(u32, u32)> {
+    let (addr, len) = arg.split_once('/')?;
+    Some((hex(addr)?, hex(len)?))
+}
@@ -0,0 +1,100 @@
+//! Where the simulated firmware came from, so results archived by CI can be traced back to it
+
+use serde::Serialize;
+use std::path::Path;
+use std::process::{Command, Stdio};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// What is known about the origin of an elf
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct FirmwareInfo {
+    /// the commit checked out where the elf was built, ending in `-dirty` if files had changed
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub git_commit: Option<String>,
+    /// when the elf was last written, in RFC 3339
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub built_at: Option<String>,
+}
+
+impl FirmwareInfo {
+    /// Look at the elf and the git repository it is in, leaving out anything that can't be found
+    pub fn collect(elf: &Path) -> Self {
+        let built_at = std::fs::metadata(elf)
+            .and_then(|m| m.modified())
+            .ok()
+            .map(rfc3339);
+        Self {
+            git_commit: elf.parent().and_then(git_commit),
+            built_at,
+        }
+    }
+}
+
+impl std::fmt::Display for FirmwareInfo {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.git_commit {
+            Some(commit) => write!(f, "commit {}", commit)?,
+            None => f.write_str("not in a git repository")?,
+        }
+        if let Some(built_at) = &self.built_at {
+            write!(f, ", built {}", built_at)?;
+        }
+        Ok(())
+    }
+}
+
+fn git_commit(dir: &Path) -> Option<String> {
+    let git = |args: &[&str]| {
+        let output = Command::new("git")
+            .arg("-C")
+            .arg(dir)
+            .args(args)
+            .stderr(Stdio::null())
+            .output()
+            .ok()?;
+        output
+            .status
+            .success()
+            .then(|| String::from_utf8_lossy(&output.stdout).trim().to_owned())
+    };
+    let commit = git(&["rev-parse", "HEAD"])?;
+    // untracked files are usually build output rather than changes to the firmware
+    let changed = git(&["status", "--porcelain", "--untracked-files=no"])?;
+    Some(match changed.is_empty() {
+        true => commit,
+        false => format!("{}-dirty", commit),
+    })
+}
+
+/// `2022-10-05T14:48:00Z`, in UTC
+fn rfc3339(time: SystemTime) -> String {
+    let secs = time
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    let (year, month, day) = civil_from_days((secs / 86_400) as i64);
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+        year,
+        month,
+        day,
+        secs / 3600 % 24,
+        secs / 60 % 60,
+        secs % 60
+    )
+}
+
+/// The year, month and day of a count of days since 1970, from Howard Hinnant's `civil_from_days`
+pub fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    let days = days + 719_468;
+    let era = days.div_euclid(146_097);
+    let day_of_era = days - era * 146_097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = year_of_era + era * 400 + (month <= 2) as i64;
+    (year, month, day)
+}
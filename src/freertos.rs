@@ -0,0 +1,256 @@
+//! Finding the FreeRTOS tasks of a running firmware, so GDB can show them as threads. The task
+//! lists are walked through memory reads, one round trip to the simulator at a time
+
+use std::collections::{HashMap, VecDeque};
+use xmas_elf::sections::SectionData;
+use xmas_elf::symbol_table::Entry;
+use xmas_elf::ElfFile;
+
+/// size of a `List_t`: uxNumberOfItems, pxIndex, then xListEnd's xItemValue, pxNext, pxPrevious
+const LIST_SIZE: u32 = 20;
+/// offset of `xListEnd` in a `List_t`
+const LIST_END: u32 = 8;
+/// size of a `ListItem_t`: xItemValue, pxNext, pxPrevious, pvOwner, pvContainer
+const LIST_ITEM_SIZE: u32 = 20;
+/// offset of `pcTaskName` in a `TCB_t`, after pxTopOfStack, xStateListItem, xEventListItem,
+/// uxPriority and pxStack
+const TASK_NAME: u32 = 52;
+/// `configMAX_TASK_NAME_LEN` in ESP-IDF
+const TASK_NAME_LEN: u32 = 16;
+/// lists with more items than this are taken to be corrupt
+const MAX_TASKS: usize = 256;
+
+/// Why a task isn't running
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum State {
+    Ready,
+    Blocked,
+    Suspended,
+    Deleted,
+}
+
+/// the task lists of the kernel, and the state of the tasks on them
+const LISTS: &[(&str, State)] = &[
+    ("pxReadyTasksLists", State::Ready),
+    ("xPendingReadyList", State::Ready),
+    ("xDelayedTaskList1", State::Blocked),
+    ("xDelayedTaskList2", State::Blocked),
+    ("xSuspendedTaskList", State::Suspended),
+    ("xTasksWaitingTermination", State::Deleted),
+];
+
+/// Where the kernel keeps its tasks, from the symbols of the elf
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Symbols {
+    /// the address and size of `pxCurrentTCB`, or `pxCurrentTCBs` with one entry per core
+    current: (u32, u32),
+    /// the address of each task list, arrays of lists are split up
+    lists: Vec<(u32, State)>,
+}
+
+impl Symbols {
+    /// The kernel's variables, `None` if the elf doesn't use FreeRTOS or has no symbols
+    pub fn find(elf: &ElfFile) -> Option<Self> {
+        let mut wanted = HashMap::new();
+        for section in elf.section_iter() {
+            let Ok(SectionData::SymbolTable32(entries)) = section.get_data(elf) else {
+                continue;
+            };
+            for entry in entries {
+                let Ok(name) = entry.get_name(elf) else {
+                    continue;
+                };
+                let is_wanted = name == "pxCurrentTCB"
+                    || name == "pxCurrentTCBs"
+                    || LISTS.iter().any(|(list, _)| *list == name);
+                if is_wanted && entry.value() != 0 {
+                    wanted.insert(name, (entry.value() as u32, entry.size() as u32));
+                }
+            }
+        }
+
+        let current = wanted
+            .get("pxCurrentTCB")
+            .or_else(|| wanted.get("pxCurrentTCBs"))
+            .map(|&(addr, size)| (addr, size.max(4)))?;
+        wanted.get("pxReadyTasksLists")?;
+        let lists = LISTS
+            .iter()
+            .filter_map(|(name, state)| Some((*wanted.get(name)?, *state)))
+            .flat_map(|((addr, size), state)| {
+                (0..(size / LIST_SIZE).max(1)).map(move |i| (addr + i * LIST_SIZE, state))
+            })
+            .collect();
+        Some(Self { current, lists })
+    }
+}
+
+/// A task, which GDB knows by the address of its control block
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Task {
+    pub tcb: u32,
+    pub name: String,
+    pub state: State,
+    /// whether a core is running it
+    pub running: bool,
+}
+
+impl Task {
+    /// What GDB shows alongside the thread, e.g. `main (Running)`
+    pub fn describe(&self) -> String {
+        let state = match (self.running, self.state) {
+            (true, _) => "Running",
+            (false, State::Ready) => "Ready",
+            (false, State::Blocked) => "Blocked",
+            (false, State::Suspended) => "Suspended",
+            (false, State::Deleted) => "Deleted",
+        };
+        format!("{} ({})", self.name, state)
+    }
+}
+
+/// What a walk needs next
+#[derive(Debug, PartialEq, Eq)]
+pub enum Step {
+    /// the contents of memory, passed to [`Walk::feed`]
+    Read { addr: u32, len: u32 },
+    /// every task found
+    Done(Vec<Task>),
+}
+
+enum Stage {
+    Current,
+    List {
+        addr: u32,
+        state: State,
+    },
+    Item {
+        list_end: u32,
+        state: State,
+        seen: usize,
+    },
+    Name(usize),
+}
+
+/// Reads the task lists piece by piece
+pub struct Walk {
+    stage: Stage,
+    lists: VecDeque<(u32, State)>,
+    running: Vec<u32>,
+    tasks: Vec<Task>,
+}
+
+impl Walk {
+    /// Start walking the lists of `symbols`, returning the first read to make
+    pub fn start(symbols: &Symbols) -> (Self, Step) {
+        let walk = Self {
+            stage: Stage::Current,
+            lists: symbols.lists.iter().copied().collect(),
+            running: Vec::new(),
+            tasks: Vec::new(),
+        };
+        let (addr, len) = symbols.current;
+        (walk, Step::Read { addr, len })
+    }
+
+    /// Carry on with the memory asked for by the last step. Memory which couldn't be read ends
+    /// the walk with the tasks found so far
+    pub fn feed(&mut self, memory: &[u8]) -> Step {
+        match self.stage {
+            Stage::Current => {
+                self.running = memory
+                    .chunks_exact(4)
+                    .map(|word| u32::from_le_bytes(word.try_into().unwrap()))
+                    .filter(|&tcb| tcb != 0)
+                    .collect();
+                self.next_list()
+            }
+            // xListEnd.pxNext is the first item
+            Stage::List { addr, state } => match (word(memory, 0), word(memory, LIST_END + 4)) {
+                (Some(0), _) => self.next_list(),
+                (Some(_), Some(first)) => self.next_item(first, addr + LIST_END, state, 0),
+                _ => self.finish(),
+            },
+            Stage::Item {
+                list_end,
+                state,
+                seen,
+            } => match (word(memory, 4), word(memory, 12)) {
+                // pxNext and pvOwner
+                (Some(next), Some(owner)) => {
+                    if owner != 0 && !self.tasks.iter().any(|t| t.tcb == owner) {
+                        self.tasks.push(Task {
+                            tcb: owner,
+                            name: String::new(),
+                            state,
+                            running: self.running.contains(&owner),
+                        });
+                    }
+                    self.next_item(next, list_end, state, seen + 1)
+                }
+                _ => self.finish(),
+            },
+            Stage::Name(index) => {
+                if memory.is_empty() {
+                    return self.finish();
+                }
+                let end = memory.iter().position(|&b| b == 0).unwrap_or(memory.len());
+                self.tasks[index].name = String::from_utf8_lossy(&memory[..end]).into_owned();
+                self.read_name(index + 1)
+            }
+        }
+    }
+
+    fn next_list(&mut self) -> Step {
+        match self.lists.pop_front() {
+            Some((addr, state)) => {
+                self.stage = Stage::List { addr, state };
+                Step::Read {
+                    addr,
+                    len: LIST_SIZE,
+                }
+            }
+            None => self.read_name(0),
+        }
+    }
+
+    fn next_item(&mut self, item: u32, list_end: u32, state: State, seen: usize) -> Step {
+        if item == list_end || item == 0 || seen >= MAX_TASKS {
+            return self.next_list();
+        }
+        self.stage = Stage::Item {
+            list_end,
+            state,
+            seen,
+        };
+        Step::Read {
+            addr: item,
+            len: LIST_ITEM_SIZE,
+        }
+    }
+
+    fn read_name(&mut self, index: usize) -> Step {
+        match self.tasks.get(index) {
+            Some(task) => {
+                self.stage = Stage::Name(index);
+                Step::Read {
+                    addr: task.tcb + TASK_NAME,
+                    len: TASK_NAME_LEN,
+                }
+            }
+            None => self.finish(),
+        }
+    }
+
+    fn finish(&mut self) -> Step {
+        self.lists.clear();
+        Step::Done(std::mem::take(&mut self.tasks))
+    }
+}
+
+/// The little endian word at `offset` in `memory`
+fn word(memory: &[u8], offset: u32) -> Option<u32> {
+    let offset = offset as usize;
+    let bytes = memory.get(offset..offset + 4)?;
+    Some(u32::from_le_bytes(bytes.try_into().ok()?))
+}
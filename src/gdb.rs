@@ -0,0 +1,121 @@
+/// Something received from a GDB client
+#[derive(Debug, PartialEq, Eq)]
+pub enum GdbPacket {
+    /// a command with a valid checksum, to be acknowledged with `+`, as its bytes since binary
+    /// data like that of `X` packets isn't UTF-8
+    Command(Vec<u8>),
+    /// a command with a bad checksum, to be rejected with `-`
+    BadChecksum { received: String, calculated: u8 },
+    /// Ctrl-C, asking for the target to be interrupted
+    Break,
+    /// `-`, asking for the last packet to be sent again
+    Nak,
+    /// acknowledgements and anything else outside of a packet
+    Garbage,
+}
+
+/// Parse whatever is at the start of `buffer`, returning it and the number of bytes it took up.
+///
+/// Returns `None` if more data is needed.
+pub fn parse_packet(buffer: &[u8]) -> Option<(GdbPacket, usize)> {
+    match buffer.iter().position(|&b| b == b'$') {
+        Some(0) => {
+            let end = buffer.iter().position(|&b| b == b'#')?;
+            let received = buffer.get(end + 1..end + 3)?;
+            let command = &buffer[1..end];
+            let calculated = checksum(command);
+            let packet = if parse_hex(received) == Some(calculated) {
+                GdbPacket::Command(command.to_vec())
+            } else {
+                GdbPacket::BadChecksum {
+                    received: String::from_utf8_lossy(received).into_owned(),
+                    calculated,
+                }
+            };
+            Some((packet, end + 3))
+        }
+        // everything up to the next packet, or all of it if there is none
+        start => {
+            let len = start.unwrap_or(buffer.len());
+            if len == 0 {
+                return None;
+            }
+            let packet = if buffer[..len].contains(&0x03) {
+                GdbPacket::Break
+            } else if buffer[..len].contains(&b'-') {
+                GdbPacket::Nak
+            } else {
+                GdbPacket::Garbage
+            };
+            Some((packet, len))
+        }
+    }
+}
+
+/// The checksum of a packet's contents, as sent after the `#`
+pub fn checksum(data: &[u8]) -> u8 {
+    data.iter().fold(0, |sum, &b| sum.wrapping_add(b))
+}
+
+fn parse_hex(digits: &[u8]) -> Option<u8> {
+    if !digits.iter().all(u8::is_ascii_hexdigit) {
+        return None;
+    }
+    u8::from_str_radix(std::str::from_utf8(digits).ok()?, 16).ok()
+}
+
+/// Wrap a response in a packet, `$<data>#<checksum>`
+pub fn frame(data: impl AsRef<[u8]>) -> Vec<u8> {
+    let data = data.as_ref();
+    let mut packet = Vec::with_capacity(data.len() + 4);
+    packet.push(b'$');
+    packet.extend_from_slice(data);
+    packet.extend_from_slice(format!("#{:02x}", checksum(data)).as_bytes());
+    packet
+}
+
+/// The bytes of a packet as a binary string, a character for each byte, as JavaScript keeps
+/// binary data. Packets are exchanged with the simulator as binary strings, so bytes which aren't
+/// UTF-8, like those of `X` packets, arrive as they were sent
+pub fn to_binary_string(bytes: &[u8]) -> String {
+    bytes.iter().map(|&b| char::from(b)).collect()
+}
+
+/// The bytes of a packet held as a binary string, `None` if it has a character beyond U+00FF,
+/// which a binary string can't
+pub fn from_binary_string(text: &str) -> Option<Vec<u8>> {
+    text.chars().map(|c| u8::try_from(c).ok()).collect()
+}
+
+/// The contents of a packet, `None` if it isn't a well formed `$<data>#<checksum>`
+pub fn unframe(packet: &[u8]) -> Option<&[u8]> {
+    let data = packet.strip_prefix(b"$")?;
+    let hash = data.iter().rposition(|&b| b == b'#')?;
+    (data.len() - hash == 3).then_some(&data[..hash])
+}
+
+/// Escape the bytes which can't appear in binary data, `}` followed by the byte xor 0x20
+pub fn escape_binary(data: &[u8]) -> Vec<u8> {
+    let mut escaped = Vec::with_capacity(data.len());
+    for &b in data {
+        match b {
+            b'#' | b'$' | b'}' | b'*' => escaped.extend_from_slice(&[b'}', b ^ 0x20]),
+            b => escaped.push(b),
+        }
+    }
+    escaped
+}
+
+/// Bytes as pairs of hex digits, as memory and strings are sent
+pub fn encode_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// The bytes of pairs of hex digits, `None` if there are any other characters
+pub fn decode_hex(text: &str) -> Option<Vec<u8>> {
+    let digits = text.as_bytes();
+    if !digits.len().is_multiple_of(2) {
+        return None;
+    }
+    digits.chunks(2).map(parse_hex).collect()
+}
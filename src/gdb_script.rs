@@ -0,0 +1,260 @@
+//! Commands run through the simulator's GDB stub as the firmware starts, before any GDB client is
+//! attached, for `--gdb-init` and `--break-at`. The firmware is interrupted along with the start
+//! packet, the commands are sent, and it is continued. Their replies are kept from GDB, which asks
+//! why the firmware stopped once it attaches
+//!
+//! Scripts take a subset of GDB's commands, one per line, with `#` starting a comment:
+//!
+//! ```text
+//! hbreak app_main
+//! set {int}0x3ffb0000 = 42
+//! monitor reset halt
+//! maintenance packet qOffsets
+//! ```
+
+use crate::breakpoints::{self, Breakpoint};
+use crate::error::{bail, Result};
+use crate::gdb;
+use std::collections::VecDeque;
+
+/// A command of a script, as the GDB packet which carries it out
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Command {
+    /// the line of the script, to say which command failed
+    pub line: String,
+    pub packet: String,
+    reply: Reply,
+    /// where the firmware stops for a breakpoint
+    breakpoint: Option<Breakpoint>,
+}
+
+/// The reply a command gets
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Reply {
+    /// `OK`, or an error
+    Ok,
+    /// console output packets, then `OK`
+    Monitor,
+    /// anything, which is shown
+    Any,
+}
+
+impl Command {
+    /// Set a hardware breakpoint, like GDB's `hbreak`
+    pub fn hbreak(breakpoint: Breakpoint, kind: u8) -> Self {
+        Self {
+            line: format!("hbreak {}", breakpoint.target),
+            packet: format!("Z1,{:x},{}", breakpoint.addr, kind),
+            reply: Reply::Ok,
+            breakpoint: Some(breakpoint),
+        }
+    }
+}
+
+/// Parse a script, with symbols looked up in `elf`
+pub fn parse(script: &str, elf: &[u8]) -> Result<Vec<Command>> {
+    let kind = breakpoints::kind(elf);
+    let mut commands = Vec::new();
+    for (number, line) in script.lines().enumerate() {
+        let line = line.split('#').next().unwrap_or_default().trim();
+        if line.is_empty() {
+            continue;
+        }
+        let command = parse_line(line, elf, kind)
+            .map_err(|e| crate::WokwiServerError::Gdb(format!("line {}: {}", number + 1, e)))?;
+        commands.push(command);
+    }
+    Ok(commands)
+}
+
+fn parse_line(line: &str, elf: &[u8], kind: u8) -> Result<Command> {
+    let (name, rest) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+    let rest = rest.trim();
+    let command = |packet: String, reply| Command {
+        line: line.to_owned(),
+        packet,
+        reply,
+        breakpoint: None,
+    };
+    Ok(match name {
+        "break" | "b" | "hbreak" => {
+            let breakpoint = breakpoints::resolve(elf, rest)?;
+            // Z0 is a software breakpoint, Z1 a hardware one
+            let z = if name == "hbreak" { 1 } else { 0 };
+            Command {
+                line: line.to_owned(),
+                packet: format!("Z{},{:x},{}", z, breakpoint.addr, kind),
+                reply: Reply::Ok,
+                breakpoint: Some(breakpoint),
+            }
+        }
+        "set" => {
+            let (addr, bytes) = parse_write(rest, elf)?;
+            let packet = format!("M{:x},{:x}:{}", addr, bytes.len(), gdb::encode_hex(&bytes));
+            command(packet, Reply::Ok)
+        }
+        "monitor" | "mon" => command(
+            format!("qRcmd,{}", gdb::encode_hex(rest.as_bytes())),
+            Reply::Monitor,
+        ),
+        "maintenance" | "maint" => match rest.strip_prefix("packet") {
+            Some(packet) if !packet.trim().is_empty() => {
+                command(packet.trim().to_owned(), Reply::Any)
+            }
+            _ => bail!(Gdb, "expected `maintenance packet <packet>`"),
+        },
+        name => bail!(
+            Gdb,
+            "`{}` isn't supported, use break, hbreak, set, monitor or maintenance packet",
+            name
+        ),
+    })
+}
+
+/// Parse `{<type>}<address or symbol> = <value>`, returning the address and the bytes to write
+fn parse_write(rest: &str, elf: &[u8]) -> Result<(u32, Vec<u8>)> {
+    let parsed = rest.strip_prefix('{').and_then(|rest| {
+        let (ty, rest) = rest.split_once('}')?;
+        let (target, value) = rest.split_once('=')?;
+        Some((ty.trim(), target.trim(), value.trim()))
+    });
+    let Some((ty, target, value)) = parsed else {
+        bail!(Gdb, "expected `set {{<type>}}<address> = <value>`");
+    };
+    let size = match ty {
+        "char" | "unsigned char" | "int8_t" | "uint8_t" => 1,
+        "short" | "unsigned short" | "int16_t" | "uint16_t" => 2,
+        "int" | "unsigned" | "unsigned int" | "long" | "unsigned long" | "int32_t" | "uint32_t" => {
+            4
+        }
+        ty => bail!(Gdb, "unknown type {}", ty),
+    };
+    let addr = breakpoints::resolve(elf, target)?.addr;
+    let Some(value) = parse_value(value) else {
+        bail!(Gdb, "`{}` is not a number", value);
+    };
+    Ok((addr, value.to_le_bytes()[..size].to_vec()))
+}
+
+/// A number in decimal or `0x` hex, possibly negative
+fn parse_value(value: &str) -> Option<i64> {
+    let (negative, value) = match value.strip_prefix('-') {
+        Some(value) => (true, value),
+        None => (false, value),
+    };
+    let magnitude = match value
+        .strip_prefix("0x")
+        .or_else(|| value.strip_prefix("0X"))
+    {
+        Some(hex) => i64::from_str_radix(hex, 16).ok()?,
+        None => value.parse().ok()?,
+    };
+    Some(if negative { -magnitude } else { magnitude })
+}
+
+/// The replies the runner still waits for, in the order its packets were sent
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Expect {
+    /// the firmware stopping for the interrupt
+    Interrupted,
+    /// the reply to the command at this index
+    Reply(usize),
+    /// the firmware stopping, at a breakpoint or for another reason, once continued
+    Stop,
+}
+
+/// What to do with a reply of the GDB stub
+#[derive(Debug, PartialEq, Eq)]
+pub enum Step {
+    /// it was for the script, keep it from GDB
+    Swallow,
+    /// something to show, e.g. the output of a monitor command
+    Output(String),
+    /// a command failed, and why
+    Failed(String),
+    /// the firmware stopped after being continued, and the script is done
+    Stopped,
+}
+
+/// A script being run while the firmware starts
+#[derive(Debug)]
+pub struct Runner {
+    commands: Vec<Command>,
+    expected: VecDeque<Expect>,
+}
+
+impl Runner {
+    /// Start running `commands`, returning the GDB packets to send once the firmware has been
+    /// interrupted. The firmware is continued after them unless `resume` is false
+    pub fn start(commands: Vec<Command>, resume: bool) -> (Self, Vec<String>) {
+        let mut packets: Vec<_> = commands.iter().map(|c| c.packet.clone()).collect();
+        let mut expected: VecDeque<_> = std::iter::once(Expect::Interrupted)
+            .chain((0..commands.len()).map(Expect::Reply))
+            .collect();
+        if resume {
+            packets.push("c".to_owned());
+            expected.push_back(Expect::Stop);
+        }
+        (Self { commands, expected }, packets)
+    }
+
+    /// Whether the firmware has been continued and is running after the script
+    pub fn is_running(&self) -> bool {
+        self.expected.front() == Some(&Expect::Stop)
+    }
+
+    /// Take a reply of the GDB stub, unframed
+    pub fn feed(&mut self, packet: &str) -> Step {
+        let stopped = packet.starts_with(['S', 'T', 'W', 'X']);
+        match self.expected.front().copied() {
+            Some(Expect::Interrupted) if stopped => {
+                self.expected.pop_front();
+                Step::Swallow
+            }
+            Some(Expect::Reply(index)) => {
+                let command = &self.commands[index];
+                let output = packet
+                    .strip_prefix('O')
+                    .filter(|_| packet != "OK")
+                    .and_then(gdb::decode_hex);
+                if let (Reply::Monitor, Some(output)) = (command.reply, output) {
+                    return Step::Output(String::from_utf8_lossy(&output).into_owned());
+                }
+                self.expected.pop_front();
+                match (command.reply, packet) {
+                    (Reply::Any, reply) => Step::Output(format!("{}: {}", command.line, reply)),
+                    (_, "OK") => Step::Swallow,
+                    (_, reply) => Step::Failed(format!(
+                        "{} failed: {}",
+                        command.line,
+                        match reply {
+                            "" => "the simulator doesn't support it",
+                            reply => reply,
+                        }
+                    )),
+                }
+            }
+            Some(Expect::Stop) if stopped => {
+                self.expected.clear();
+                Step::Stopped
+            }
+            // console output while running, or the firmware stopping on its own
+            _ => Step::Swallow,
+        }
+    }
+
+    /// Whether all the replies have come
+    pub fn is_done(&self) -> bool {
+        self.expected.is_empty()
+    }
+
+    /// The breakpoints the script set, to say where the firmware may have stopped
+    pub fn breakpoints(&self) -> String {
+        self.commands
+            .iter()
+            .filter_map(|command| command.breakpoint.as_ref())
+            .map(|breakpoint| format!("{} ({:#010x})", breakpoint.target, breakpoint.addr))
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+}
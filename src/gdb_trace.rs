@@ -0,0 +1,153 @@
+//! A log of every GDB remote protocol packet passing through the server, both between GDB clients
+//! and the server and between the server and the simulator's GDB stub, for debugging stepping and
+//! breakpoint issues. Each packet is a line of its own:
+//!
+//! ```text
+//! 1760534400.123456 gdb-2 gdb->server ok $vCont;c:p1.-1#0f
+//! 1760534400.123601 sim-1 server->stub ok $vCont;c:p1.-1#0f
+//! 1760534400.131077 sim-1 stub->server ok $T05thread:p01.01;#1e
+//! 1760534400.131230 gdb-2 server->gdb ok $T05thread:p01.01;#1e
+//! ```
+
+use anyhow::{Context, Result};
+use serde_json::Value;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tungstenite::Message;
+use wokwi_server::gdb::{self, GdbPacket};
+
+/// Where a packet went
+#[derive(Debug, Clone, Copy)]
+pub enum Hop {
+    /// from a GDB client
+    FromGdb,
+    /// to a GDB client
+    ToGdb,
+    /// to the simulator's GDB stub
+    ToStub,
+    /// from the simulator's GDB stub
+    FromStub,
+}
+
+impl Hop {
+    fn label(self) -> &'static str {
+        match self {
+            Hop::FromGdb => "gdb->server",
+            Hop::ToGdb => "server->gdb",
+            Hop::ToStub => "server->stub",
+            Hop::FromStub => "stub->server",
+        }
+    }
+}
+
+/// Writes a line for each packet, shared by all GDB clients and simulators
+#[derive(Clone, Default)]
+pub struct GdbTrace {
+    file: Arc<Mutex<Option<BufWriter<File>>>>,
+}
+
+impl GdbTrace {
+    /// Trace to `path` if given, or do nothing
+    pub fn create(path: Option<&Path>) -> Result<Self> {
+        let file = path
+            .map(|path| {
+                File::create(path)
+                    .map(BufWriter::new)
+                    .with_context(|| format!("Failed to create {}", path.display()))
+            })
+            .transpose()?;
+        Ok(Self {
+            file: Arc::new(Mutex::new(file)),
+        })
+    }
+
+    fn enabled(&self) -> bool {
+        self.file.lock().unwrap().is_some()
+    }
+
+    /// Record a packet as it is on the wire, framed, a break (`0x03`) or a `-` asking for the last
+    /// one again. `+` acknowledgements are left out
+    pub fn record(&self, session: &str, hop: Hop, raw: &[u8]) {
+        let status = match gdb::parse_packet(raw) {
+            Some((GdbPacket::Command(_), _)) => "ok".to_owned(),
+            Some((GdbPacket::BadChecksum { calculated, .. }, _)) => {
+                format!("bad-checksum(calculated:{:02x})", calculated)
+            }
+            Some((GdbPacket::Break, _)) => "break".to_owned(),
+            Some((GdbPacket::Nak, _)) => "nak".to_owned(),
+            Some((GdbPacket::Garbage, _)) | None => return,
+        };
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        // the whole line is written at once, so packets of different connections don't mix
+        let line = format!(
+            "{}.{:06} {} {} {} {}\n",
+            timestamp.as_secs(),
+            timestamp.subsec_micros(),
+            session,
+            hop.label(),
+            status,
+            escape(raw)
+        );
+        let mut file = self.file.lock().unwrap();
+        let Some(out) = file.as_mut() else {
+            return;
+        };
+        // flushed each time, so the trace is complete even if the server is killed
+        if out
+            .write_all(line.as_bytes())
+            .and_then(|_| out.flush())
+            .is_err()
+        {
+            println!("Failed to write to the GDB trace, disabling it");
+            *file = None;
+        }
+    }
+
+    /// Record the GDB packet carried by a websocket message to or from the simulator, if any
+    pub fn record_message(&self, session: &str, message: &Message) {
+        let Message::Text(text) = message else {
+            return;
+        };
+        // the start packet holds the whole firmware, so it isn't parsed for nothing
+        if !self.enabled() || !text.contains("\"gdb") {
+            return;
+        }
+        let Ok(message) = serde_json::from_str::<Value>(text) else {
+            return;
+        };
+        match (
+            message["type"].as_str(),
+            &message["message"],
+            &message["response"],
+        ) {
+            (Some("gdb"), Value::String(command), _) => {
+                if let Some(command) = gdb::from_binary_string(command) {
+                    self.record(session, Hop::ToStub, &gdb::frame(command));
+                }
+            }
+            (Some("gdbBreak"), _, _) => self.record(session, Hop::ToStub, b"\x03"),
+            (Some("gdbResponse"), _, Value::String(response)) => {
+                if let Some(response) = gdb::from_binary_string(response) {
+                    self.record(session, Hop::FromStub, &response);
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+/// The packet with anything but printable ASCII escaped as `\xNN`, so each is one line
+fn escape(raw: &[u8]) -> String {
+    raw.iter()
+        .map(|&b| match b {
+            b'\\' => "\\\\".to_owned(),
+            b' '..=b'~' => (b as char).to_string(),
+            b => format!("\\x{:02x}", b),
+        })
+        .collect()
+}
@@ -0,0 +1,798 @@
+use crate::artifacts::RunLog;
+use crate::benchmark::{Benchmark, Step};
+use crate::boot_hints::BootHints;
+use crate::custom_chips::Bridges;
+use crate::exit_marker::ExitMarker;
+use crate::expect::{Expectations, Outcome};
+use crate::panic_trace::PanicTrace;
+use crate::peer::PeerFeed;
+use crate::profiler::Profiler;
+use crate::report::{self, TestResult};
+use crate::session::{Kind, Session};
+use crate::sinks::Sinks;
+use crate::stats;
+use crate::waveform::Waveform;
+use crate::Args;
+use anyhow::Result;
+use serde_json::{json, Value};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::mpsc::Sender;
+use tokio::sync::oneshot;
+use tokio::time::Instant;
+use wokwi_server::coredump::{self, Capture, CrashWatch};
+use wokwi_server::error::{self, WokwiServerError};
+use wokwi_server::file_io::{self, FileIo};
+use wokwi_server::gdb_script::{self, Runner};
+use wokwi_server::messages::{self, Simulation};
+use wokwi_server::router::{Outbox, Router};
+use wokwi_server::target_description;
+use wokwi_server::watchpoints::{Action, Watchpoints};
+use wokwi_server::{buses, freertos, gdb, heap, profile, vcd, SimulationPacket};
+
+/// The state of a simulation, shared by the handlers of messages from the simulator
+pub struct Run<'a> {
+    pub opts: &'a mut Args,
+    pub session: &'a Session,
+    pub sinks: &'a mut Sinks,
+    /// exit codes requested by the firmware or a headless run
+    pub exit: &'a Sender<i32>,
+    pub log: RunLog,
+    /// the start packet the firmware was sent in, for `monitor wokwi info`
+    pub payload: Arc<SimulationPacket>,
+    /// whether UART output is kept for reports
+    pub keep_uart: bool,
+    pub exit_marker: Option<ExitMarker>,
+    pub boot_hints: Option<BootHints>,
+    pub expectations: Option<Expectations>,
+    pub deadline: Option<Instant>,
+    /// the `--uart-peer` attached to the firmware
+    pub peer: Option<PeerFeed>,
+    /// the host processes given with `--chip-bridge`, by chip
+    pub bridges: Bridges,
+    /// the target description served to GDB
+    pub target_xml: Option<Vec<u8>>,
+    /// whether the last GDB command was `qSupported`, whose reply has to offer the target description
+    pub qsupported_sent: bool,
+    /// where the firmware's FreeRTOS tasks are, to show them to GDB as threads
+    pub freertos: Option<freertos::Symbols>,
+    /// the task lists being read for GDB, which takes over the simulator's GDB responses
+    pub thread_walk: Option<freertos::Walk>,
+    /// the tasks found by the last walk
+    pub tasks: Vec<freertos::Task>,
+    /// the watchpoints GDB has set, if the chip's are known
+    pub watchpoints: Option<Watchpoints>,
+    /// the `--fio-root` the firmware's File-I/O calls are answered from, rather than by GDB
+    pub file_io: Option<FileIo>,
+    /// where to write a core dump when the firmware crashes, until one has been
+    pub core_dump: Option<PathBuf>,
+    pub crashes: CrashWatch,
+    /// the core dump being read, which takes over the simulator's GDB responses, and the stop
+    /// reply to pass on to GDB once it is done
+    pub capture: Option<(Capture, Option<Vec<u8>>)>,
+    /// where the firmware's allocator keeps its books, for `--heap-stats`
+    pub heap: Option<heap::Symbols>,
+    /// when to read the heap next
+    pub heap_poll: Option<Instant>,
+    /// the heap being read, which takes over the simulator's GDB responses
+    pub heap_walk: Option<heap::Walk>,
+    /// the free heap of the first reading, to compare the others with
+    pub heap_baseline: Option<u32>,
+    /// whether GDB has the firmware stopped, going by its packets
+    pub halted: bool,
+    /// whether the firmware is being stopped for `--halt-on-attach`, whose stop reply no GDB
+    /// client asked for
+    pub halting_at_boot: bool,
+    /// the commands of `--break-at` and `--gdb-init` being run, whose replies no GDB client asked
+    /// for either
+    pub gdb_script: Option<Runner>,
+    pub profiler: Option<Profiler>,
+    /// when to sample the call stack next
+    pub profile_poll: Option<Instant>,
+    /// the call stack being read, which takes over the simulator's GDB responses
+    pub sample: Option<profile::Sample>,
+    /// the span of simulated time asked for with the control API's `run-for`, answered once the
+    /// simulator reports it has paused
+    pub running_for: Option<(u64, oneshot::Sender<Result<()>>)>,
+    /// the boots timed so far by `--benchmark`
+    pub benchmark: Option<Benchmark>,
+    /// the pin changes recorded for `--vcd`
+    pub waveform: Option<Waveform>,
+    /// `None` with `--no-backtrace-decode`
+    pub panic_trace: Option<PanicTrace>,
+}
+
+/// The router for the messages the simulator sends
+pub fn router<'a>() -> Router<Run<'a>> {
+    messages::router()
+}
+
+impl Simulation for Run<'_> {
+    fn uart_data(&mut self, bytes: Vec<u8>, out: &mut Outbox) -> error::Result<()> {
+        reported(uart_data(self, bytes, out))
+    }
+
+    fn gdb_response(&mut self, response: Vec<u8>, out: &mut Outbox) -> error::Result<()> {
+        reported(gdb_response(self, response, out))
+    }
+
+    fn i2c_transaction(
+        &mut self,
+        transaction: buses::I2cTransaction,
+        _out: &mut Outbox,
+    ) -> error::Result<()> {
+        if self.opts.i2c_log {
+            println!("[{}] {}", self.session.id, transaction.describe());
+        }
+        Ok(())
+    }
+
+    fn spi_transaction(
+        &mut self,
+        transaction: buses::SpiTransaction,
+        _out: &mut Outbox,
+    ) -> error::Result<()> {
+        if self.opts.spi_log {
+            println!("[{}] {}", self.session.id, transaction.describe());
+        }
+        Ok(())
+    }
+
+    fn pin_change(&mut self, change: vcd::PinChange, _out: &mut Outbox) -> error::Result<()> {
+        if let Some(waveform) = &mut self.waveform {
+            waveform.add(change);
+        }
+        Ok(())
+    }
+
+    fn paused(&mut self, _out: &mut Outbox) -> error::Result<()> {
+        if let Some((ms, reply)) = self.running_for.take() {
+            println!("[{}] Paused after running for {} ms", self.session.id, ms);
+            reply.send(Ok(())).ok();
+        }
+        Ok(())
+    }
+
+    fn chip_message(
+        &mut self,
+        chip: &str,
+        message: &Value,
+        _out: &mut Outbox,
+    ) -> error::Result<()> {
+        self.bridges.feed(chip, message);
+        Ok(())
+    }
+}
+
+/// A handler's failure as the router reports it, keeping its kind if it came from the library
+fn reported(result: Result<()>) -> error::Result<()> {
+    result.map_err(|e| match e.downcast::<WokwiServerError>() {
+        Ok(e) => e,
+        Err(e) => WokwiServerError::Protocol(format!("{:#}", e)),
+    })
+}
+
+/// Ask the simulator to run for `ms` of simulated time, answering `reply` once it has paused
+pub fn run_for(
+    run: &mut Run,
+    controls_time: bool,
+    ms: u64,
+    reply: oneshot::Sender<Result<()>>,
+    out: &mut Outbox,
+) -> Result<()> {
+    if !controls_time {
+        let reason = "The simulator can't run for a span of simulated time";
+        reply.send(Err(anyhow::anyhow!(reason))).ok();
+        return Ok(());
+    }
+    if run.running_for.is_some() {
+        let reason = "The simulation is already running for a span of time";
+        reply.send(Err(anyhow::anyhow!(reason))).ok();
+        return Ok(());
+    }
+    println!("[{}] Running the simulation for {} ms", run.session.id, ms);
+    out.send_to_simulator(&json!({ "type": "runFor", "ms": ms }))?;
+    run.running_for = Some((ms, reply));
+    Ok(())
+}
+
+fn uart_data(run: &mut Run, bytes: Vec<u8>, out: &mut Outbox) -> Result<()> {
+    run.session
+        .count(|stats| stats.uart_bytes += bytes.len() as u64);
+    run.sinks.write(&bytes);
+    if let Some(peer) = &run.peer {
+        peer.feed(&bytes);
+    }
+    if let Some(benchmark) = &mut run.benchmark {
+        match benchmark.feed(&bytes, &run.session.id) {
+            Step::Continue => {}
+            Step::Restart(packet) => out.send_to_simulator(&*packet)?,
+            Step::Done => {
+                benchmark.print(&run.session.id);
+                if let Some(path) = &run.opts.benchmark_json {
+                    benchmark.write(path)?;
+                }
+                run.exit.try_send(0).ok();
+            }
+        }
+    }
+    if let Some(code) = run.exit_marker.as_mut().and_then(|m| m.feed(&bytes)) {
+        println!(
+            "[{}] Firmware requested exit with code {}",
+            run.session.id, code
+        );
+        run.session.event("exit-marker", json!({ "code": code }));
+        run.exit.try_send(code).ok();
+    }
+    for hint in run.boot_hints.iter_mut().flat_map(|h| h.feed(&bytes)) {
+        println!("[{}] Hint: {}", run.session.id, hint);
+        run.session.event("boot-hint", json!({ "hint": hint }));
+    }
+    for (event, details) in run
+        .panic_trace
+        .iter_mut()
+        .flat_map(|t| t.feed(&bytes, &run.session.id))
+    {
+        run.session.event(event, details);
+    }
+    if run.keep_uart {
+        run.log.uart(&bytes);
+    }
+    if run.core_dump.is_some() && run.capture.is_none() && run.crashes.feed(&bytes) {
+        start_capture(run, None, None, out)?;
+    }
+    if let Some(outcome) = run.expectations.as_mut().and_then(|e| e.feed(&bytes)) {
+        run.finish(outcome);
+    }
+    Ok(())
+}
+
+fn gdb_response(run: &mut Run, response: Vec<u8>, out: &mut Outbox) -> Result<()> {
+    // the replies the server reads for itself are ASCII, anything else only passes through
+    let packet = gdb::unframe(&response)
+        .and_then(|packet| std::str::from_utf8(packet).ok())
+        .unwrap_or_default();
+    if run.halting_at_boot && packet.starts_with(['S', 'T']) {
+        // GDB asks why the firmware stopped once it attaches
+        run.halting_at_boot = false;
+        run.halted = true;
+        println!(
+            "[{}] Halted the firmware at boot, attach GDB and continue to run it",
+            run.session.id
+        );
+        return Ok(());
+    }
+    if let Some(script) = &mut run.gdb_script {
+        let step = script.feed(packet);
+        match &step {
+            gdb_script::Step::Swallow => {}
+            gdb_script::Step::Output(output) => {
+                println!("[{}] {}", run.session.id, output.trim_end())
+            }
+            gdb_script::Step::Failed(reason) => {
+                println!("[{}] Warning: {}", run.session.id, reason)
+            }
+            gdb_script::Step::Stopped => match script.breakpoints().as_str() {
+                "" => println!(
+                    "[{}] The firmware stopped, attach GDB to debug from there",
+                    run.session.id
+                ),
+                breakpoints => println!(
+                    "[{}] Stopped at a breakpoint, {}, attach GDB to debug from there",
+                    run.session.id, breakpoints
+                ),
+            },
+        }
+        if script.is_done() {
+            if step != gdb_script::Step::Stopped {
+                println!(
+                    "[{}] Halted the firmware at boot, attach GDB and continue to run it",
+                    run.session.id
+                );
+            }
+            run.gdb_script = None;
+            run.halted = true;
+        }
+        return Ok(());
+    }
+    if let Some((capture, _)) = &mut run.capture {
+        if let Some(step) = capture.feed(packet) {
+            return capture_step(run, step, out);
+        }
+    }
+    if let Some(walk) = &mut run.heap_walk {
+        if let Some(step) = walk.feed(packet) {
+            return heap_step(run, step, out);
+        }
+    }
+    if let Some(sample) = &mut run.sample {
+        if let Some(step) = sample.feed(packet) {
+            return profile_step(run, step, out);
+        }
+    }
+    if run.core_dump.is_some() && run.capture.is_none() {
+        // GDB hears about the crash once the core dump has been read
+        if let Some(signal) = coredump::fatal_signal(packet) {
+            return start_capture(run, Some(signal), Some(response.clone()), out);
+        }
+    }
+    if let Some(walk) = &mut run.thread_walk {
+        // a failed read is an error reply, which isn't hex, and ends the walk
+        let memory = gdb::decode_hex(packet);
+        let step = walk.feed(&memory.unwrap_or_default());
+        return walk_step(run, step, out);
+    }
+    if let Some(files) = &mut run.file_io {
+        let step = match files.is_pending() {
+            // memory that was read comes back in hex, and a write is acknowledged with OK
+            true => Some(match packet {
+                "OK" => files.feed(Some(&[])),
+                packet => files.feed(gdb::decode_hex(packet).as_deref()),
+            }),
+            false => files.request(packet),
+        };
+        if let Some(step) = step {
+            return file_io_step(step, out);
+        }
+    }
+    if let Some(watchpoints) = run.watchpoints.as_mut().filter(|w| w.is_pending()) {
+        watchpoints.response(packet);
+    }
+    if packet.starts_with(['S', 'T']) {
+        run.halted = true;
+    }
+    let response = match std::mem::take(&mut run.qsupported_sent) && run.target_xml.is_some() {
+        true => target_description::advertise(&response),
+        false => response,
+    };
+    out.send_to_gdb(response);
+    Ok(())
+}
+
+/// Handle a packet from the GDB client, answering the ones the server implements itself and
+/// passing the rest on to the simulator
+pub fn gdb_command(run: &mut Run, packet: &[u8], out: &mut Outbox) -> Result<()> {
+    // the commands the server reads for itself are ASCII, anything else, like the binary data of
+    // `X` packets, only passes through
+    let command = std::str::from_utf8(packet).unwrap_or_default();
+    // the target description is served here rather than by the simulator
+    let description = run.target_xml.as_deref();
+    if let Some(reply) = description.and_then(|xml| target_description::read(xml, command)) {
+        out.send_to_gdb(reply);
+        return Ok(());
+    }
+    if thread_query(run, command, out)? {
+        return Ok(());
+    }
+    if let Some(output) = monitor_command(run, command) {
+        out.send_to_gdb(gdb::frame(gdb::encode_hex(output.as_bytes())));
+        return Ok(());
+    }
+    match run.watchpoints.as_mut().and_then(|w| w.command(command)) {
+        Some(Action::Reply(reply)) => {
+            out.send_to_gdb(gdb::frame(reply));
+            return Ok(());
+        }
+        Some(Action::Reject { reply, reason }) => {
+            println!("[{}] {}", run.session.id, reason);
+            out.send_to_gdb(gdb::frame(reply));
+            return Ok(());
+        }
+        Some(Action::Forward) | None => {}
+    }
+    if run.gdb_script.as_ref().is_some_and(Runner::is_running) {
+        // a client attached before the firmware stopped, and takes over from here
+        run.gdb_script = None;
+    }
+    if command.starts_with(['c', 's', 'C', 'S']) || command.starts_with("vCont;") {
+        run.halted = false;
+    }
+    run.qsupported_sent = command.starts_with("qSupported");
+    gdb_to_simulator(packet, out)
+}
+
+/// The output of a `monitor wokwi ...` command, which the server answers itself rather than the
+/// simulator. GDB shows a hex encoded reply to `qRcmd` as the command's output
+fn monitor_command(run: &Run, command: &str) -> Option<String> {
+    let line = gdb::decode_hex(command.strip_prefix("qRcmd,")?)?;
+    let line = String::from_utf8(line).ok()?;
+    let mut words = line.split_whitespace();
+    if words.next() != Some("wokwi") {
+        return None;
+    }
+    Some(match words.next() {
+        Some("info") => info(run),
+        _ => "The server answers `monitor wokwi info`\n".to_owned(),
+    })
+}
+
+/// What `monitor wokwi info` shows: the server, who is connected, what was sent to the simulator
+/// and how long it took
+fn info(run: &Run) -> String {
+    use std::fmt::Write;
+
+    let mut info = String::new();
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map_or(0, stats::millis);
+    let payload = &run.payload;
+    let stats = run.session.stats();
+    let decoded = |data: &str| base64::decode(data).map_or(0, |data| data.len());
+
+    writeln!(info, "wokwi-server {}", env!("CARGO_PKG_VERSION")).ok();
+    writeln!(
+        info,
+        "simulating {} on the {}",
+        run.opts.image.elf.display(),
+        run.opts.image.chip
+    )
+    .ok();
+    writeln!(info, "Clients:").ok();
+    for client in run.session.connected() {
+        let kind = match client.kind {
+            Kind::Simulator => "simulator",
+            Kind::Gdb => "GDB",
+        };
+        writeln!(
+            info,
+            "  {:<16} {:<10} {} for {}",
+            client.id,
+            kind,
+            client.peer,
+            stats::seconds(now.saturating_sub(client.connected_at))
+        )
+        .ok();
+    }
+    let packet_size = serde_json::to_string(&**payload).map_or(0, |packet| packet.len());
+    writeln!(info, "Payload: {} bytes of JSON", packet_size).ok();
+    writeln!(
+        info,
+        "  {:<16} {:>10} {:>8} bytes",
+        "elf",
+        "",
+        decoded(&payload.elf)
+    )
+    .ok();
+    let names = payload.extensions.as_ref().map(|e| &e.segments);
+    for (i, segment) in payload.esp_bin.iter().enumerate() {
+        let addr = segment.first().and_then(Value::as_u64).unwrap_or_default();
+        let size = segment.get(1).and_then(Value::as_str).map_or(0, decoded);
+        let name = names
+            .and_then(|segments| segments.get(i))
+            .map_or_else(|| format!("segment {}", i), |segment| segment.name.clone());
+        writeln!(info, "  {:<16} {:#010x} {:>8} bytes", name, addr, size).ok();
+    }
+    writeln!(info, "Transfer:").ok();
+    writeln!(
+        info,
+        "  transfer time    {}",
+        stats::seconds(stats.transfer_ms)
+    )
+    .ok();
+    writeln!(info, "  UART received    {} bytes", stats.uart_bytes).ok();
+    writeln!(info, "  GDB packets      {}", stats.gdb_packets).ok();
+    writeln!(info, "  reconnects       {}", stats.reconnects).ok();
+    info
+}
+
+/// Pass the GDB client's interrupt on to the simulator. The firmware is taken to be stopped from
+/// here on, so the heap and profile polls leave it alone until GDB continues it
+pub fn gdb_break(run: &mut Run, out: &mut Outbox) -> Result<()> {
+    run.halted = true;
+    out.send_to_simulator(&json!({ "type": "gdbBreak" }))?;
+    Ok(())
+}
+
+fn gdb_to_simulator(command: impl AsRef<[u8]>, out: &mut Outbox) -> Result<()> {
+    out.send_to_simulator(&json!({
+        "type": "gdb",
+        "message": gdb::to_binary_string(command.as_ref())
+    }))?;
+    Ok(())
+}
+
+/// Answer GDB's questions about threads with the firmware's FreeRTOS tasks, returning whether
+/// `command` was one
+fn thread_query(run: &mut Run, command: &str, out: &mut Outbox) -> Result<bool> {
+    let Some(symbols) = &run.freertos else {
+        return Ok(false);
+    };
+    let task = |id: &str| {
+        let tcb = u32::from_str_radix(id, 16).ok()?;
+        run.tasks.iter().find(|task| task.tcb == tcb)
+    };
+    let reply = if command == "qfThreadInfo" {
+        let (walk, step) = freertos::Walk::start(symbols);
+        run.thread_walk = Some(walk);
+        walk_step(run, step, out)?;
+        return Ok(true);
+    } else if command == "qsThreadInfo" {
+        "l".to_owned()
+    } else if command == "qC" {
+        match run.tasks.iter().find(|task| task.running) {
+            Some(task) => format!("QC{:x}", task.tcb),
+            None => return Ok(false),
+        }
+    } else if let Some(id) = command.strip_prefix("qThreadExtraInfo,") {
+        match task(id) {
+            Some(task) => gdb::encode_hex(task.describe().as_bytes()),
+            None => "E01".to_owned(),
+        }
+    } else if let Some(id) = command.strip_prefix('T') {
+        task(id).map_or("E01", |_| "OK").to_owned()
+    } else if command.starts_with("Hg") || command.starts_with("Hc") {
+        // the simulator only has the registers of the running task, whichever is selected
+        "OK".to_owned()
+    } else {
+        return Ok(false);
+    };
+    out.send_to_gdb(gdb::frame(&reply));
+    Ok(true)
+}
+
+/// Read the memory the walk of the task lists needs next, or give GDB the tasks once it's done
+fn walk_step(run: &mut Run, step: freertos::Step, out: &mut Outbox) -> Result<()> {
+    match step {
+        freertos::Step::Read { addr, len } => {
+            gdb_to_simulator(format!("m{:x},{:x}", addr, len), out)
+        }
+        freertos::Step::Done(tasks) => {
+            run.thread_walk = None;
+            let ids: Vec<_> = tasks.iter().map(|task| format!("{:x}", task.tcb)).collect();
+            let reply = match ids.is_empty() {
+                true => "l".to_owned(),
+                false => format!("m{}", ids.join(",")),
+            };
+            run.tasks = tasks;
+            out.send_to_gdb(gdb::frame(&reply));
+            Ok(())
+        }
+    }
+}
+
+/// Read the heap statistics, unless the firmware is stopped or something else is reading memory
+pub fn poll_heap(run: &mut Run, out: &mut Outbox) -> Result<()> {
+    let Some(symbols) = &run.heap else {
+        println!(
+            "[{}] The elf has no FreeRTOS or ESP-IDF heap symbols, --heap-stats is ignored",
+            run.session.id
+        );
+        run.heap_poll = None;
+        return Ok(());
+    };
+    let interval = Duration::from_secs(run.opts.heap_stats.unwrap_or_default());
+    run.heap_poll = Some(Instant::now() + interval);
+    // a stopped firmware's heap isn't changing, and GDB may be reading memory itself
+    if run.reading_memory() || run.halted {
+        return Ok(());
+    }
+    let (walk, step) = heap::Walk::start(symbols);
+    run.heap_walk = Some(walk);
+    heap_step(run, step, out)
+}
+
+fn heap_step(run: &mut Run, step: heap::Step, out: &mut Outbox) -> Result<()> {
+    match step {
+        heap::Step::Halt => {
+            out.send_to_simulator(&json!({ "type": "gdbBreak" }))?;
+            Ok(())
+        }
+        heap::Step::Read { addr, len } => gdb_to_simulator(format!("m{:x},{:x}", addr, len), out),
+        heap::Step::Done(stats) => {
+            run.heap_walk = None;
+            match stats {
+                Some(stats) => heap_stats(run, stats),
+                None => println!("[{}] Couldn't read the heap", run.session.id),
+            }
+            gdb_to_simulator("c", out)
+        }
+    }
+}
+
+fn heap_stats(run: &mut Run, stats: heap::Stats) {
+    let baseline = *run.heap_baseline.get_or_insert(stats.free);
+    let change = i64::from(stats.free) - i64::from(baseline);
+    let largest = match stats.largest_block {
+        Some(largest) => format!(", largest free block {}", largest),
+        None => String::new(),
+    };
+    println!(
+        "[{}] Heap: {} bytes free ({:+} since the first reading), {} at the lowest{}",
+        run.session.id, stats.free, change, stats.minimum_free, largest
+    );
+    run.session.event(
+        "heap",
+        json!({
+            "free": stats.free,
+            "minimum_free": stats.minimum_free,
+            "largest_block": stats.largest_block,
+        }),
+    );
+}
+
+/// Sample the firmware's call stack for `--cpu-profile`, unless it is stopped or something else
+/// is reading memory
+pub fn sample_profile(run: &mut Run, out: &mut Outbox) -> Result<()> {
+    let Some(profiler) = &run.profiler else {
+        return Ok(());
+    };
+    run.profile_poll = Some(Instant::now() + profiler.interval);
+    if run.reading_memory() || run.halted {
+        return Ok(());
+    }
+    let chip = run.opts.image.chip;
+    let Some((sample, step)) = profile::Sample::start(chip) else {
+        println!(
+            "[{}] Profiling the {} isn't supported",
+            run.session.id, chip
+        );
+        run.profiler = None;
+        run.profile_poll = None;
+        return Ok(());
+    };
+    run.sample = Some(sample);
+    profile_step(run, step, out)
+}
+
+fn profile_step(run: &mut Run, step: profile::Step, out: &mut Outbox) -> Result<()> {
+    match step {
+        profile::Step::Halt => {
+            out.send_to_simulator(&json!({ "type": "gdbBreak" }))?;
+            Ok(())
+        }
+        profile::Step::Registers => gdb_to_simulator("g", out),
+        profile::Step::Read { addr, len } => {
+            gdb_to_simulator(format!("m{:x},{:x}", addr, len), out)
+        }
+        profile::Step::Done(stack) => {
+            run.sample = None;
+            if let Some(profiler) = &mut run.profiler {
+                profiler.add(&stack);
+            }
+            gdb_to_simulator("c", out)
+        }
+    }
+}
+
+/// Start reading a core dump of the crashed firmware, stopped with `signal` or still running
+fn start_capture(
+    run: &mut Run,
+    signal: Option<u8>,
+    stop_reply: Option<Vec<u8>>,
+    out: &mut Outbox,
+) -> Result<()> {
+    let chip = run.opts.image.chip;
+    let elf = std::fs::read(&run.opts.image.elf).unwrap_or_default();
+    let Some((capture, step)) = Capture::start(chip, &elf, signal) else {
+        println!(
+            "[{}] Core dumps of the {} aren't supported",
+            run.session.id, chip
+        );
+        run.core_dump = None;
+        if let Some(reply) = stop_reply {
+            out.send_to_gdb(reply);
+        }
+        return Ok(());
+    };
+    println!(
+        "[{}] The firmware crashed, reading a core dump",
+        run.session.id
+    );
+    run.capture = Some((capture, stop_reply));
+    capture_step(run, step, out)
+}
+
+fn capture_step(run: &mut Run, step: coredump::Step, out: &mut Outbox) -> Result<()> {
+    match step {
+        coredump::Step::Halt => {
+            out.send_to_simulator(&json!({ "type": "gdbBreak" }))?;
+            Ok(())
+        }
+        coredump::Step::Registers => gdb_to_simulator("g", out),
+        coredump::Step::Read { addr, len } => {
+            gdb_to_simulator(format!("m{:x},{:x}", addr, len), out)
+        }
+        coredump::Step::Done(core) => {
+            let stop_reply = run.capture.take().and_then(|(_, reply)| reply);
+            if let Some(path) = run.core_dump.take() {
+                match std::fs::write(&path, core) {
+                    Ok(()) => {
+                        println!(
+                            "[{}] Core dump written to {}",
+                            run.session.id,
+                            path.display()
+                        );
+                        run.session
+                            .event("core-dump", json!({ "path": path.display().to_string() }));
+                    }
+                    Err(e) => println!(
+                        "[{}] Failed to write the core dump to {}: {}",
+                        run.session.id,
+                        path.display(),
+                        e
+                    ),
+                }
+            }
+            // a crash seen on the UART was stopped for the dump, so carries on to its reset
+            match stop_reply {
+                Some(reply) => out.send_to_gdb(reply),
+                None => gdb_to_simulator("c", out)?,
+            }
+            Ok(())
+        }
+    }
+}
+
+/// Carry out the next step of a File-I/O call, all of which are between the server and the
+/// simulator
+fn file_io_step(step: file_io::Step, out: &mut Outbox) -> Result<()> {
+    let command = match step {
+        file_io::Step::Read { addr, len } => format!("m{:x},{:x}", addr, len),
+        file_io::Step::Write { addr, data } => {
+            format!("M{:x},{:x}:{}", addr, data.len(), gdb::encode_hex(&data))
+        }
+        file_io::Step::Reply(reply) => reply,
+    };
+    gdb_to_simulator(&command, out)
+}
+
+impl Run<'_> {
+    /// Whether the server is in the middle of reading the firmware's memory for itself, while
+    /// which packets from the GDB client are held back
+    pub fn reading_memory(&self) -> bool {
+        self.heap_walk.is_some()
+            || self.sample.is_some()
+            || self.capture.is_some()
+            || self.thread_walk.is_some()
+            || self.file_io.as_ref().is_some_and(|f| f.is_pending())
+    }
+
+    /// Report the outcome of a headless run and ask the server to exit accordingly
+    pub fn finish(&mut self, outcome: Outcome) {
+        self.expectations = None;
+        self.deadline = None;
+        let (opts, session) = (&self.opts, self.session);
+
+        match &outcome {
+            Outcome::Passed => println!("[{}] Passed", session.id),
+            Outcome::Failed(reason) | Outcome::Error(reason) => {
+                println!("[{}] Failed: {}", session.id, reason)
+            }
+        }
+        session.event("finished", json!({ "passed": outcome == Outcome::Passed }));
+        self.exit.try_send(outcome.exit_code()).ok();
+
+        let name = opts
+            .image
+            .elf
+            .file_name()
+            .map(|n| n.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        let result = TestResult {
+            name,
+            outcome,
+            duration: self.log.started.elapsed(),
+            output: self.log.uart_output(),
+        };
+        if let (Some(dir), false) = (&opts.artifacts_dir, result.outcome == Outcome::Passed) {
+            match self.log.write(dir, &session.recent_events()) {
+                Ok(_) => println!(
+                    "[{}] Wrote failure artifacts to {}",
+                    session.id,
+                    dir.display()
+                ),
+                Err(e) => println!("Failed to write failure artifacts: {:#}", e),
+            }
+        }
+        if opts.gha_annotations {
+            report::annotate(&result);
+        }
+        if let Some(path) = &opts.report {
+            let suite = opts.session_name.as_deref().unwrap_or("wokwi-server");
+            if let Err(e) = report::write(path, suite, &[result]) {
+                println!("Failed to write the report: {:#}", e);
+            }
+        }
+    }
+}
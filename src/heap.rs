@@ -0,0 +1,221 @@
+//! Heap statistics of a running firmware, read from the allocator's variables through the
+//! simulator's memory reads. Both FreeRTOS' own heaps (heap_4 and heap_5) and the heaps of
+//! ESP-IDF's `heap_caps` are understood
+
+use std::collections::HashMap;
+use xmas_elf::sections::SectionData;
+use xmas_elf::symbol_table::Entry;
+use xmas_elf::ElfFile;
+
+/// a `BlockLink_t` of FreeRTOS: pxNextFreeBlock and xBlockSize
+const BLOCK_LINK_SIZE: u32 = 8;
+/// the top bit of xBlockSize marks an allocated block
+const BLOCK_ALLOCATED: u32 = 0x8000_0000;
+/// a `heap_t` of ESP-IDF: caps[3], start, end, heap_mux (8 bytes), heap and next
+const HEAP_SIZE: u32 = 36;
+/// offset of `heap` in a `heap_t`
+const HEAP_HANDLE: u32 = 28;
+/// offset of `next` in a `heap_t`
+const HEAP_NEXT: u32 = 32;
+/// offset of `free_bytes` in ESP-IDF's `multi_heap_info`, followed by `minimum_free_bytes`
+const MULTI_HEAP_FREE: u32 = 4;
+/// lists longer than this are taken to be corrupt
+const MAX_BLOCKS: usize = 1024;
+
+/// Where the allocator keeps its books, from the symbols of the elf
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Symbols {
+    /// FreeRTOS' heap_4 or heap_5
+    FreeRtos {
+        free: u32,
+        minimum_free: u32,
+        /// `xStart`, the head of the free list
+        start: u32,
+    },
+    /// `registered_heaps` of ESP-IDF, a list of `heap_t`
+    EspIdf { heaps: u32 },
+}
+
+impl Symbols {
+    /// The allocator's variables, `None` if the elf has neither heap or no symbols
+    pub fn find(elf: &ElfFile) -> Option<Self> {
+        let mut wanted = HashMap::new();
+        for section in elf.section_iter() {
+            let Ok(SectionData::SymbolTable32(entries)) = section.get_data(elf) else {
+                continue;
+            };
+            for entry in entries {
+                let Ok(name) = entry.get_name(elf) else {
+                    continue;
+                };
+                let is_wanted = matches!(
+                    name,
+                    "registered_heaps"
+                        | "xFreeBytesRemaining"
+                        | "xMinimumEverFreeBytesRemaining"
+                        | "xStart"
+                );
+                if is_wanted && entry.value() != 0 {
+                    wanted.insert(name, entry.value() as u32);
+                }
+            }
+        }
+
+        if let Some(&heaps) = wanted.get("registered_heaps") {
+            return Some(Self::EspIdf { heaps });
+        }
+        Some(Self::FreeRtos {
+            free: *wanted.get("xFreeBytesRemaining")?,
+            minimum_free: *wanted.get("xMinimumEverFreeBytesRemaining")?,
+            start: *wanted.get("xStart")?,
+        })
+    }
+}
+
+/// The state of the heap at one moment
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Stats {
+    /// bytes free across all heaps
+    pub free: u32,
+    /// the fewest bytes there have been free since boot
+    pub minimum_free: u32,
+    /// the largest free block, `None` for ESP-IDF's heaps whose free blocks aren't walked
+    pub largest_block: Option<u32>,
+}
+
+/// What a walk needs next
+#[derive(Debug, PartialEq, Eq)]
+pub enum Step {
+    /// stop the firmware, passing the stop reply to [`Walk::feed`]
+    Halt,
+    /// the contents of memory
+    Read { addr: u32, len: u32 },
+    /// the statistics, `None` if memory couldn't be read
+    Done(Option<Stats>),
+}
+
+enum Stage {
+    Halt,
+    /// `registered_heaps`, the first `heap_t`
+    Heaps,
+    Free,
+    MinimumFree,
+    /// a block of the FreeRTOS free list
+    Block {
+        seen: usize,
+    },
+    /// a `heap_t` of ESP-IDF
+    Heap {
+        seen: usize,
+    },
+    /// the `multi_heap_info` of the heap, and the next `heap_t`
+    HeapInfo {
+        next: u32,
+        seen: usize,
+    },
+}
+
+/// Reads the heap's variables piece by piece
+pub struct Walk {
+    symbols: Symbols,
+    stage: Stage,
+    stats: Stats,
+}
+
+impl Walk {
+    /// Start reading the heap of `symbols`, which begins by stopping the firmware
+    pub fn start(symbols: &Symbols) -> (Self, Step) {
+        let walk = Self {
+            symbols: symbols.clone(),
+            stage: Stage::Halt,
+            stats: Stats {
+                free: 0,
+                minimum_free: 0,
+                largest_block: None,
+            },
+        };
+        (walk, Step::Halt)
+    }
+
+    /// Carry on with the simulator's (unframed) reply to the last step. `None` if it isn't the
+    /// stop reply being waited for, which belongs to someone else
+    pub fn feed(&mut self, reply: &str) -> Option<Step> {
+        if let Stage::Halt = self.stage {
+            return reply.starts_with(['S', 'T']).then(|| self.first_read());
+        }
+        // memory which couldn't be read, or too little of it, ends the walk
+        let memory = crate::gdb::decode_hex(reply).unwrap_or_default();
+        Some(self.advance(&memory).unwrap_or(Step::Done(None)))
+    }
+
+    fn advance(&mut self, memory: &[u8]) -> Option<Step> {
+        let word = |offset: u32| word(memory, offset);
+        let step = match self.stage {
+            Stage::Halt => return None,
+            Stage::Heaps => self.next_heap(word(0)?, 0),
+            Stage::Free => {
+                self.stats.free = word(0)?;
+                let Symbols::FreeRtos { minimum_free, .. } = self.symbols else {
+                    return None;
+                };
+                self.read(Stage::MinimumFree, minimum_free, 4)
+            }
+            Stage::MinimumFree => {
+                self.stats.minimum_free = word(0)?;
+                let Symbols::FreeRtos { start, .. } = self.symbols else {
+                    return None;
+                };
+                self.read(Stage::Block { seen: 0 }, start, BLOCK_LINK_SIZE)
+            }
+            Stage::Block { seen } => {
+                let (next, size) = (word(0)?, word(4)? & !BLOCK_ALLOCATED);
+                let largest = self.stats.largest_block.get_or_insert(0);
+                *largest = (*largest).max(size);
+                match next {
+                    0 => Step::Done(Some(self.stats)),
+                    _ if seen >= MAX_BLOCKS => Step::Done(None),
+                    next => self.read(Stage::Block { seen: seen + 1 }, next, BLOCK_LINK_SIZE),
+                }
+            }
+            Stage::Heap { seen } => {
+                let (heap, next) = (word(HEAP_HANDLE)?, word(HEAP_NEXT)?);
+                let stage = Stage::HeapInfo { next, seen };
+                self.read(stage, heap + MULTI_HEAP_FREE, 8)
+            }
+            Stage::HeapInfo { next, seen } => {
+                self.stats.free += word(0)?;
+                self.stats.minimum_free += word(4)?;
+                self.next_heap(next, seen + 1)
+            }
+        };
+        Some(step)
+    }
+
+    fn first_read(&mut self) -> Step {
+        match self.symbols {
+            Symbols::FreeRtos { free, .. } => self.read(Stage::Free, free, 4),
+            // an SLIST_HEAD is just a pointer to the first entry
+            Symbols::EspIdf { heaps } => self.read(Stage::Heaps, heaps, 4),
+        }
+    }
+
+    fn next_heap(&mut self, heap: u32, seen: usize) -> Step {
+        match heap {
+            0 => Step::Done(Some(self.stats)),
+            _ if seen >= MAX_BLOCKS => Step::Done(None),
+            heap => self.read(Stage::Heap { seen }, heap, HEAP_SIZE),
+        }
+    }
+
+    fn read(&mut self, stage: Stage, addr: u32, len: u32) -> Step {
+        self.stage = stage;
+        Step::Read { addr, len }
+    }
+}
+
+/// The little endian word at `offset` in `memory`
+fn word(memory: &[u8], offset: u32) -> Option<u32> {
+    let offset = offset as usize;
+    let bytes = memory.get(offset..offset + 4)?;
+    Some(u32::from_le_bytes(bytes.try_into().ok()?))
+}
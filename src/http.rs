@@ -0,0 +1,81 @@
+//! Outbound HTTP, through the proxy the environment names the way curl and most tools read it:
+//! `HTTPS_PROXY` or `HTTP_PROXY` by the scheme of the url, then `ALL_PROXY`, except for the
+//! hosts in `NO_PROXY`
+
+use anyhow::{Context, Result};
+use std::net::IpAddr;
+use url::Url;
+
+/// An agent for requests to `url`, going through the proxy for it if there is one
+pub fn agent(url: &str) -> Result<ureq::Agent> {
+    let mut builder = ureq::AgentBuilder::new();
+    if let Some((var, proxy)) = proxy_for(url) {
+        let proxy = ureq::Proxy::new(&proxy)
+            .with_context(|| format!("${} isn't a proxy ureq understands: {}", var, proxy))?;
+        builder = builder.proxy(proxy);
+    }
+    Ok(builder.build())
+}
+
+/// The proxy for `url` and the variable it is from, `None` to connect directly
+fn proxy_for(url: &str) -> Option<(&'static str, String)> {
+    let url = Url::parse(url).ok()?;
+    if bypassed(url.host_str()?, url.port_or_known_default()) {
+        return None;
+    }
+    let vars = match url.scheme() {
+        "https" => ["HTTPS_PROXY", "ALL_PROXY"],
+        _ => ["HTTP_PROXY", "ALL_PROXY"],
+    };
+    vars.into_iter().find_map(|var| Some((var, env(var)?)))
+}
+
+/// An environment variable in upper or lower case, e.g. `$https_proxy`, if it isn't empty
+fn env(var: &str) -> Option<String> {
+    [var.to_owned(), var.to_lowercase()]
+        .iter()
+        .filter_map(|var| std::env::var(var).ok())
+        .find(|value| !value.trim().is_empty())
+}
+
+/// Whether to connect to `host` directly: this machine always is, and so are the hosts in
+/// `NO_PROXY`. Its entries are `*`, or a host or domain (`example.com` and `.example.com` both
+/// cover the subdomains), optionally with a port
+fn bypassed(host: &str, port: Option<u16>) -> bool {
+    let host = host.trim_matches(['[', ']']).to_lowercase();
+    let is_loopback = host.parse::<IpAddr>().is_ok_and(|ip| ip.is_loopback());
+    if host == "localhost" || is_loopback {
+        return true;
+    }
+    let Some(no_proxy) = env("NO_PROXY") else {
+        return false;
+    };
+    no_proxy
+        .split(',')
+        .map(str::trim)
+        .filter(|entry| !entry.is_empty())
+        .any(|entry| {
+            if entry == "*" {
+                return true;
+            }
+            let (pattern, entry_port) = split_port(entry);
+            if entry_port.is_some() && entry_port != port {
+                return false;
+            }
+            let pattern = pattern.trim_start_matches('*').trim_start_matches('.');
+            let pattern = pattern.to_lowercase();
+            host == pattern || host.ends_with(&format!(".{}", pattern))
+        })
+}
+
+/// `host:port` or `[v6]:port` split apart. A bare IPv6 address has no port
+fn split_port(entry: &str) -> (&str, Option<u16>) {
+    if let Some(bracketed) = entry.strip_prefix('[') {
+        let (host, rest) = bracketed.split_once(']').unwrap_or((bracketed, ""));
+        return (host, rest.strip_prefix(':').and_then(|p| p.parse().ok()));
+    }
+    match entry.split_once(':') {
+        Some((host, port)) if !port.contains(':') => (host, port.parse().ok()),
+        _ => (entry, None),
+    }
+}
@@ -0,0 +1,648 @@
+use crate::cargo_profile;
+use crate::catalog::{self, Code, Failure};
+use crate::compressed;
+use crate::custom_chips::ChipSpec;
+use crate::download;
+use crate::project::{self, ProjectConfig, Sdkconfig};
+use crate::stale;
+use anyhow::Result;
+use espflash::elf::{ElfFirmwareImage, RomSegment};
+use espflash::{Chip, ImageFormatId, PartitionTable};
+use serde_json::Value;
+use std::path::{Path, PathBuf};
+use wokwi_server::app_desc::AppDescriptor;
+use wokwi_server::{
+    bootloader, chips, partitions, secure_boot, strip, PacketExtensions, SegmentChecksum,
+    SimulationPacket,
+};
+use xmas_elf::program;
+
+/// flash offsets of images have to be aligned to this
+const SECTOR_SIZE: u32 = 0x1000;
+/// the space reserved for the partition table
+const PARTITION_TABLE_SIZE: u32 = 0x1000;
+/// `e_machine` values of the elf header
+const EM_XTENSA: u16 = 94;
+const EM_RISCV: u16 = 243;
+const E_MACHINE_OFFSET: usize = 18;
+
+/// Options selecting the firmware to simulate, and how to build its image
+#[derive(clap::Args, Debug, Clone)]
+pub struct ImageArgs {
+    /// chip name
+    #[clap(short, long)]
+    pub chip: Chip,
+
+    /// try to simulate a chip wokwi-server doesn't know Wokwi supports, requires `--id`
+    #[clap(long)]
+    pub force_chip: bool,
+
+    /// fail instead of starting the simulation if the SHA-256 of the application image differs
+    #[clap(long, value_name = "SHA256")]
+    pub expected_sha: Option<String>,
+
+    /// path to bootloader
+    #[clap(short, long)]
+    pub bootloader: Option<PathBuf>,
+
+    /// use the default bootloader if the one given with `--bootloader` isn't valid for the chip
+    #[clap(long, requires = "bootloader")]
+    pub fallback_bootloader: bool,
+
+    /// path to partition table csv
+    #[clap(short, long)]
+    pub partition_table: Option<PathBuf>,
+
+    /// flash offset of the partition table, defaults to `CONFIG_PARTITION_TABLE_OFFSET` from the
+    /// project's sdkconfig when `--bootloader` is given, or 0x8000
+    #[clap(long, value_name = "OFFSET", value_parser = parse_offset)]
+    pub partition_table_offset: Option<u32>,
+
+    /// how the firmware boots: `bootloader` through the second stage bootloader, or `direct-boot`
+    /// straight from flash, for chips whose ROM supports it
+    #[clap(long, value_name = "FORMAT", value_parser = parse_image_format)]
+    pub image_format: Option<ImageFormatId>,
+
+    /// run the firmware straight from flash without a bootloader or partition table, like some
+    /// bare-metal esp-hal projects. The same as `--image-format direct-boot`
+    #[clap(long, conflicts_with = "image-format")]
+    pub direct_boot: bool,
+
+    /// path to a prebuilt (e.g. signed) application image, used as-is instead of generating one from the elf
+    #[clap(long)]
+    pub app_bin: Option<PathBuf>,
+
+    /// pass signed images through untouched and warn when signature verification would fail
+    #[clap(long, requires = "bootloader")]
+    pub secure_boot: bool,
+
+    /// strip sections which are not needed for simulation from the elf before sending it
+    #[clap(long)]
+    pub strip_elf: bool,
+
+    /// keep debug info when stripping the elf
+    #[clap(long, requires = "strip-elf")]
+    pub keep_debug: bool,
+
+    /// directory relative paths are resolved against and wokwi.toml is searched from, defaults to
+    /// the current directory
+    #[clap(long)]
+    pub project_dir: Option<PathBuf>,
+
+    /// simulate the build of the elf for this cargo profile, e.g. `release`, when the elf is in a
+    /// cargo target directory
+    #[clap(long, value_name = "NAME")]
+    pub profile: Option<String>,
+
+    /// simulate the release build, the same as `--profile release`
+    #[clap(long, conflicts_with = "profile")]
+    pub release: bool,
+
+    /// source files the elf is built from, relative to its cargo project, to warn when the elf is
+    /// older than them. May be repeated, defaults to `src/**`
+    #[clap(long, value_name = "GLOB")]
+    pub sources: Vec<String>,
+
+    /// find the elf with `cargo metadata` in the cargo workspace of the project directory,
+    /// instead of taking it as an argument
+    #[clap(long)]
+    pub cargo: bool,
+
+    /// the binary to simulate with `--cargo`, when the workspace has several
+    #[clap(long, value_name = "NAME", requires = "cargo")]
+    pub bin: Option<String>,
+
+    /// header sent when downloading inputs given as urls, as `<name>: <value>`, e.g. for
+    /// authorization. May be repeated
+    #[clap(long, value_name = "HEADER", value_parser = download::parse_header)]
+    pub http_header: Vec<(String, String)>,
+
+    /// path or http(s) url of the elf, defaults to `elf` in wokwi.toml
+    #[clap(default_value = "", hide_default_value = true)]
+    pub elf: PathBuf,
+}
+
+impl ImageArgs {
+    /// An image built from `elf` with the default bootloader and partition table
+    pub fn new(chip: Chip, elf: PathBuf) -> Self {
+        Self {
+            chip,
+            force_chip: false,
+            expected_sha: None,
+            bootloader: None,
+            fallback_bootloader: false,
+            partition_table: None,
+            partition_table_offset: None,
+            image_format: None,
+            direct_boot: false,
+            app_bin: None,
+            secure_boot: false,
+            strip_elf: false,
+            keep_debug: false,
+            project_dir: None,
+            profile: None,
+            release: false,
+            sources: Vec::new(),
+            cargo: false,
+            bin: None,
+            http_header: Vec::new(),
+            elf,
+        }
+    }
+
+    /// The cargo profile asked for, if any
+    fn profile(&self) -> Option<&str> {
+        match self.release {
+            true => Some("release"),
+            false => self.profile.as_deref(),
+        }
+    }
+
+    /// The image format asked for, if any, otherwise the chip's default is used
+    fn image_format(&self) -> Option<ImageFormatId> {
+        match self.direct_boot {
+            true => Some(ImageFormatId::DirectBoot),
+            false => self.image_format,
+        }
+    }
+
+    fn is_direct_boot(&self) -> bool {
+        self.image_format() == Some(ImageFormatId::DirectBoot)
+    }
+
+    /// The directory relative paths are resolved against
+    fn base_dir(&self) -> Result<PathBuf> {
+        let cwd = std::env::current_dir()?;
+        Ok(match &self.project_dir {
+            Some(dir) => project::resolve(&cwd, dir),
+            None => cwd,
+        })
+    }
+
+    /// The Wokwi project named in wokwi.toml, e.g. by `pull`
+    pub fn project_id(&self) -> Result<Option<String>> {
+        Ok(ProjectConfig::find(&self.base_dir()?)?.and_then(|config| config.id))
+    }
+
+    /// The custom chips listed in wokwi.toml followed by those `given`, with absolute paths
+    pub fn custom_chips(&self, given: &[ChipSpec]) -> Result<Vec<ChipSpec>> {
+        let base = self.base_dir()?;
+        let mut chips = ProjectConfig::find(&base)?.map_or_else(Vec::new, |config| config.chips);
+        chips.extend(given.iter().map(|chip| ChipSpec {
+            name: chip.name.clone(),
+            binary: project::resolve(&base, &chip.binary),
+        }));
+        Ok(chips)
+    }
+
+    /// Make the paths absolute, taking the elf from wokwi.toml if none was given
+    pub fn resolve(&mut self) -> Result<()> {
+        let base = self.base_dir()?;
+
+        if download::is_url(&self.elf) {
+            if self.cargo || self.profile().is_some() {
+                anyhow::bail!(
+                    "--cargo, --profile and --release can't be used with an elf downloaded from a url"
+                );
+            }
+            // there is no local build to check
+            self.elf = download::fetch(&self.elf, &self.http_header)?;
+        } else {
+            if self.cargo {
+                if !self.elf.as_os_str().is_empty() {
+                    anyhow::bail!("--cargo finds the elf in the cargo workspace, don't pass one");
+                }
+                let profile = self.profile().unwrap_or("dev");
+                self.elf = cargo_profile::workspace_bin(&base, self.bin.as_deref(), profile)?;
+            } else if self.elf.as_os_str().is_empty() {
+                let config = ProjectConfig::find(&base)?;
+                self.elf = match config {
+                    Some(ProjectConfig { elf: Some(elf), .. }) => elf,
+                    Some(config) => catalog::bail!(
+                        NoElf,
+                        "No elf given, and {} doesn't name one",
+                        config.dir.join(project::CONFIG_FILE).display()
+                    ),
+                    None => catalog::bail!(
+                        NoElf,
+                        "No elf given, and there is no {} in {} or its parents",
+                        project::CONFIG_FILE,
+                        base.display()
+                    ),
+                };
+            } else {
+                self.elf = project::resolve(&base, &self.elf);
+            }
+            match self.profile() {
+                // the workspace's build for the profile was found above
+                Some(_) if self.cargo => {}
+                Some(profile) => self.elf = cargo_profile::select(&self.elf, profile)?,
+                None => {
+                    if let Some(warning) = cargo_profile::newer_build(&self.elf) {
+                        println!("Warning: {}", warning);
+                    }
+                }
+            }
+            if let Some(newer) = stale::newer_source(&self.elf, &base, &self.sources) {
+                println!("Warning: {}", newer);
+            }
+        }
+        for path in [
+            &mut self.bootloader,
+            &mut self.partition_table,
+            &mut self.app_bin,
+        ]
+        .into_iter()
+        .flatten()
+        {
+            *path = match download::is_url(path) {
+                true => download::fetch(path, &self.http_header)?,
+                false => project::resolve(&base, path),
+            };
+        }
+        // after the checks above, which look at the build the compressed elf came from
+        for path in [
+            Some(&mut self.elf),
+            self.bootloader.as_mut(),
+            self.partition_table.as_mut(),
+            self.app_bin.as_mut(),
+        ]
+        .into_iter()
+        .flatten()
+        {
+            *path = compressed::decompressed(path)?;
+        }
+
+        // a direct boot image has no partition table to place
+        if self.partition_table_offset.is_none() && !self.is_direct_boot() {
+            if let Some(sdkconfig) = Sdkconfig::find(&base)? {
+                if let Some(offset) = sdkconfig.get("CONFIG_PARTITION_TABLE_OFFSET") {
+                    let offset = parse_offset(offset).map_err(|e| {
+                        anyhow::anyhow!(
+                            "Invalid CONFIG_PARTITION_TABLE_OFFSET in {}: {}",
+                            sdkconfig.path.display(),
+                            e
+                        )
+                    })?;
+                    // the default bootloader was built to find the table at 0x8000
+                    if self.bootloader.is_some() {
+                        println!(
+                            "Using partition table offset {:#x} from {}",
+                            offset,
+                            sdkconfig.path.display()
+                        );
+                        self.partition_table_offset = Some(offset);
+                    } else if offset != 0x8000 {
+                        println!(
+                            "Warning: {} sets the partition table offset to {:#x}, but the default bootloader looks for it at 0x8000. Pass the project's bootloader with --bootloader to use its offset",
+                            sdkconfig.path.display(),
+                            offset
+                        );
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Check the chip is supported and all the given files exist
+    pub fn validate(&self, has_project_id: bool) -> Result<()> {
+        if chips::lookup(self.chip).is_none() {
+            if !self.force_chip {
+                catalog::bail!(UnsupportedChip, "Chip not supported in Wokwi. See available chips and features at https://docs.wokwi.com/guides/esp32#simulation-features");
+            }
+            if !has_project_id {
+                anyhow::bail!(
+                    "There is no default Wokwi project for {}, pass one with --id",
+                    self.chip
+                );
+            }
+            println!(
+                "Warning: {} is not known to be supported by Wokwi, the simulation may not work",
+                self.chip
+            );
+        }
+
+        if let Some(format) = self.image_format() {
+            let supported = self.chip.supported_image_formats();
+            if !supported.contains(&format) {
+                let names: Vec<_> = supported.iter().map(|f| f.to_string()).collect();
+                anyhow::bail!(
+                    "The {} doesn't support the {} image format, only {}",
+                    self.chip,
+                    format,
+                    names.join(", ")
+                );
+            }
+        }
+        if self.is_direct_boot() {
+            let given = [
+                ("--bootloader", self.bootloader.is_some()),
+                ("--partition-table", self.partition_table.is_some()),
+                (
+                    "--partition-table-offset",
+                    self.partition_table_offset.is_some(),
+                ),
+                ("--app-bin", self.app_bin.is_some()),
+            ];
+            if let Some((flag, _)) = given.iter().find(|(_, given)| *given) {
+                anyhow::bail!(
+                    "{} can't be used with a direct boot image, the firmware is run straight from flash",
+                    flag
+                );
+            }
+        }
+
+        check_exists("elf", &self.elf)?;
+        if let Some(bt) = &self.bootloader {
+            check_exists("bootloader", bt)?;
+        }
+        if let Some(pt) = &self.partition_table {
+            check_exists("partition table", pt)?;
+        }
+        if let Some(app) = &self.app_bin {
+            check_exists("application image", app)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Parse a flash offset in hex (`0x8000`) or decimal, which has to be sector aligned
+pub fn parse_offset(s: &str) -> Result<u32, String> {
+    let offset = match s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+        Some(hex) => u32::from_str_radix(hex, 16),
+        None => s.parse(),
+    }
+    .map_err(|_| format!("`{}` is not a valid offset", s))?;
+    if offset % SECTOR_SIZE != 0 {
+        return Err(format!(
+            "{:#x} is not a multiple of {:#x}",
+            offset, SECTOR_SIZE
+        ));
+    }
+    Ok(offset)
+}
+
+/// Parse the name of an espflash image format
+pub fn parse_image_format(s: &str) -> Result<ImageFormatId, String> {
+    s.parse().map_err(|_| {
+        format!(
+            "unknown image format `{}`, expected bootloader or direct-boot",
+            s
+        )
+    })
+}
+
+/// Build the `start` message for the firmware currently selected in `opts`, log lines are
+/// prefixed with `[label]`
+pub async fn start_packet(opts: &ImageArgs, label: &str) -> Result<SimulationPacket> {
+    let bytes = tokio::fs::read(&opts.elf).await?;
+    let elf = xmas_elf::ElfFile::new(&bytes)
+        .map_err(|e| Failure::new(Code::InvalidElf, format!("Invalid elf file: {}", e)))?;
+    if !built_for(&bytes, opts.chip) {
+        catalog::bail!(
+            ElfArchMismatch,
+            "{} isn't built for the {}, check --chip and the build target",
+            opts.elf.display(),
+            opts.chip
+        );
+    }
+    let firmware = ElfFirmwareImage::new(elf);
+
+    let p = if let Some(p) = &opts.partition_table {
+        Some(PartitionTable::try_from_str(String::from_utf8_lossy(
+            &tokio::fs::read(p).await?,
+        ))?)
+    } else {
+        None
+    };
+
+    let b = if let Some(b) = &opts.bootloader {
+        let data = tokio::fs::read(b).await?;
+        match bootloader::check(opts.chip, &data) {
+            Ok(()) => Some(data),
+            Err(problem) if opts.fallback_bootloader => {
+                println!(
+                    "Warning: {} is not a valid {} bootloader, {}. Using the default bootloader instead",
+                    b.display(),
+                    opts.chip,
+                    problem
+                );
+                None
+            }
+            Err(problem) => {
+                println!(
+                    "Warning: {} is not a valid {} bootloader, {}. The simulation is unlikely to boot, pass --fallback-bootloader to use the default bootloader instead",
+                    b.display(),
+                    opts.chip,
+                    problem
+                );
+                Some(data)
+            }
+        }
+    } else {
+        None
+    };
+
+    // TODO allow setting flash params, or take from bootloader?
+    let image =
+        opts.chip
+            .get_flash_image(&firmware, b, p, opts.image_format(), None, None, None, None)?;
+    let parts: Vec<_> = image.flash_segments().collect();
+
+    let segments = if opts.is_direct_boot() {
+        // one image at the start of flash, which the ROM runs without a bootloader
+        let app = &parts[0];
+        print_firmware_summary(&bytes, &[], app.addr, app.data.len())?;
+        vec![("app", app.addr, app.data.to_vec())]
+    } else {
+        bootloader_segments(opts, &bytes, &parts).await?
+    };
+
+    let elf = if opts.strip_elf {
+        let stripped = strip::strip_elf(&bytes, opts.keep_debug)?;
+        println!(
+            "[{}] Stripped elf from {} to {} bytes",
+            label,
+            bytes.len(),
+            stripped.len()
+        );
+        stripped
+    } else {
+        bytes.clone()
+    };
+
+    let checksums: Vec<_> = segments
+        .iter()
+        .map(|(name, addr, data)| SegmentChecksum::new(name, *addr, data))
+        .collect();
+    println!("[{}] Segment checksums:", label);
+    for c in &checksums {
+        println!(
+            "[{}]   {:<16} {:#08x} {:>8} bytes  sha256 {}",
+            label, c.name, c.addr, c.size, c.sha256
+        );
+    }
+    if let Some(expected) = &opts.expected_sha {
+        let app = checksums.last().expect("there is always an app segment");
+        if !app.sha256.eq_ignore_ascii_case(expected) {
+            catalog::bail!(
+                ChecksumMismatch,
+                "Application image checksum {} does not match the expected {}",
+                app.sha256,
+                expected
+            );
+        }
+    }
+
+    let simdata = SimulationPacket {
+        r#type: "start".to_owned(),
+        elf: base64::encode(&elf),
+        esp_bin: segments
+            .iter()
+            .map(|(_, addr, data)| {
+                vec![
+                    Value::Number((*addr).into()),
+                    Value::String(base64::encode(data)),
+                ]
+            })
+            .collect(),
+        extensions: Some(PacketExtensions {
+            segments: checksums,
+        }),
+    };
+
+    Ok(simdata)
+}
+
+/// The bootloader, partition table and application image, each checked to fit where it goes
+async fn bootloader_segments(
+    opts: &ImageArgs,
+    elf: &[u8],
+    parts: &[RomSegment<'_>],
+) -> Result<Vec<(&'static str, u32, Vec<u8>)>> {
+    let bootloader = &parts[0];
+    let partition_table = &parts[1];
+    let app = &parts[2];
+
+    let partition_table_addr = opts.partition_table_offset.unwrap_or(partition_table.addr);
+    if bootloader.addr as usize + bootloader.data.len() > partition_table_addr as usize {
+        catalog::bail!(
+            ImageLayout,
+            "The bootloader ({} bytes at {:#x}) overlaps the partition table at {:#x}, pass the offset the bootloader was built with using --partition-table-offset",
+            bootloader.data.len(),
+            bootloader.addr,
+            partition_table_addr
+        );
+    }
+    // an offset near the end of the address space doesn't leave room for the table at all
+    let Some(partition_table_end) = partition_table_addr.checked_add(PARTITION_TABLE_SIZE) else {
+        catalog::bail!(
+            ImageLayout,
+            "The partition table at {:#x} doesn't fit in flash, pass the offset the bootloader was built with using --partition-table-offset",
+            partition_table_addr
+        );
+    };
+    if partition_table_end > app.addr {
+        catalog::bail!(
+            ImageLayout,
+            "The partition table at {:#x} overlaps the app partition at {:#x}, move the app partition in the partition table",
+            partition_table_addr,
+            app.addr
+        );
+    }
+
+    // a prebuilt application image replaces the generated one, e.g. to keep its signature intact
+    let app_data = match &opts.app_bin {
+        Some(path) => tokio::fs::read(path).await?,
+        None => app.data.to_vec(),
+    };
+
+    print_firmware_summary(elf, &partition_table.data, app.addr, app_data.len())?;
+
+    if opts.secure_boot {
+        secure_boot::warn_if_unverifiable("Bootloader", &bootloader.data);
+        secure_boot::warn_if_unverifiable("Application image", &app_data);
+    }
+
+    Ok(vec![
+        ("bootloader", bootloader.addr, bootloader.data.to_vec()),
+        (
+            "partition-table",
+            partition_table_addr,
+            partition_table.data.to_vec(),
+        ),
+        ("app", app.addr, app_data),
+    ])
+}
+
+/// Print what is about to be simulated, so users can confirm it's the binary they expect
+fn print_firmware_summary(
+    elf: &[u8],
+    partition_table: &[u8],
+    app_addr: u32,
+    app_size: usize,
+) -> Result<()> {
+    let elf = xmas_elf::ElfFile::new(elf)
+        .map_err(|e| Failure::new(Code::InvalidElf, format!("Invalid elf file: {}", e)))?;
+
+    if let Some(desc) = AppDescriptor::find(&elf) {
+        println!("App name:          {}", desc.project_name);
+        println!("App version:       {}", desc.version);
+        println!("Compiled:          {} {}", desc.date, desc.time);
+        println!("ESP-IDF:           {}", desc.idf_version);
+        if desc.secure_version != 0 {
+            println!("Secure version:    {}", desc.secure_version);
+        }
+    }
+
+    println!("Segments:");
+    for ph in elf.program_iter() {
+        if ph.get_type() == Ok(program::Type::Load) && ph.mem_size() > 0 {
+            println!(
+                "  {:#010x} {:>8} bytes ({} bytes in memory)",
+                ph.virtual_addr(),
+                ph.file_size(),
+                ph.mem_size()
+            );
+        }
+    }
+
+    if let Some(partition) = partitions::parse_partition_table(partition_table)
+        .into_iter()
+        .find(|p| p.offset == app_addr)
+    {
+        println!(
+            "Flash usage:       {}/{} bytes, {:.2}% of partition '{}'",
+            app_size,
+            partition.size,
+            app_size as f32 / partition.size as f32 * 100.0,
+            partition.name
+        );
+    }
+
+    Ok(())
+}
+
+/// Whether the elf's architecture, from its header, is the chip's
+pub fn built_for(elf: &[u8], chip: Chip) -> bool {
+    let expected = match chip {
+        Chip::Esp32c3 | Chip::Esp32c2 => EM_RISCV,
+        _ => EM_XTENSA,
+    };
+    let machine = elf.get(E_MACHINE_OFFSET..E_MACHINE_OFFSET + 2);
+    machine == Some(&expected.to_le_bytes()[..])
+}
+
+fn check_exists(what: &str, path: &Path) -> Result<()> {
+    if !path.exists() {
+        catalog::bail!(
+            FileNotFound,
+            "Path to {} does not exist: {}",
+            what,
+            path.display()
+        );
+    }
+    Ok(())
+}
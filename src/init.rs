@@ -0,0 +1,165 @@
+use crate::project;
+use anyhow::{Context, Result};
+use espflash::Chip;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use wokwi_server::chips;
+
+/// Write a wokwi.toml for the project, so its elf doesn't have to be given on the command line
+#[derive(clap::Args, Debug)]
+pub struct InitArgs {
+    /// directory to write wokwi.toml to, defaults to the current directory
+    #[clap(long)]
+    project_dir: Option<PathBuf>,
+
+    /// overwrite an existing wokwi.toml
+    #[clap(long)]
+    force: bool,
+
+    /// also write a diagram.json with the chip's development board, unless the project has one
+    #[clap(short, long)]
+    chip: Option<Chip>,
+
+    /// path to the elf relative to the project directory, guessed from Cargo.toml or the ESP-IDF
+    /// CMakeLists.txt when not given
+    elf: Option<PathBuf>,
+}
+
+#[derive(Serialize)]
+struct Config {
+    wokwi: WokwiSection,
+}
+
+/// `firmware` is only read by the Wokwi VS Code extension, which accepts an elf there too
+#[derive(Serialize)]
+struct WokwiSection {
+    version: u32,
+    elf: String,
+    firmware: String,
+}
+
+#[derive(Deserialize)]
+struct CargoManifest {
+    package: Option<CargoPackage>,
+}
+
+#[derive(Deserialize)]
+struct CargoPackage {
+    name: String,
+}
+
+#[derive(Deserialize)]
+struct CargoConfig {
+    build: Option<CargoBuild>,
+}
+
+#[derive(Deserialize)]
+struct CargoBuild {
+    target: Option<String>,
+}
+
+pub fn run(args: InitArgs) -> Result<i32> {
+    let cwd = std::env::current_dir()?;
+    let dir = match &args.project_dir {
+        Some(dir) => project::resolve(&cwd, dir),
+        None => cwd,
+    };
+    let path = dir.join(project::CONFIG_FILE);
+    if path.exists() && !args.force {
+        anyhow::bail!(
+            "{} already exists, pass --force to overwrite it",
+            path.display()
+        );
+    }
+
+    let elf = match args.elf {
+        Some(elf) => elf,
+        None => guess_elf(&dir)?.with_context(|| {
+            format!(
+                "Couldn't tell where the elf is built from {}, pass its path",
+                dir.display()
+            )
+        })?,
+    };
+    // wokwi.toml is shared between platforms, so always use forward slashes
+    let elf = elf.to_string_lossy().replace('\\', "/");
+    let config = Config {
+        wokwi: WokwiSection {
+            version: 1,
+            elf: elf.clone(),
+            firmware: elf,
+        },
+    };
+    std::fs::write(&path, toml::to_string(&config)?)
+        .with_context(|| format!("Failed to write {}", path.display()))?;
+    println!("Wrote {} for {}", path.display(), config.wokwi.elf);
+    if !dir.join(&config.wokwi.elf).exists() {
+        println!("The elf doesn't exist yet, build the project before simulating it");
+    }
+    if let Some(chip) = args.chip {
+        write_diagram(&dir, chip)?;
+    }
+    Ok(0)
+}
+
+/// Write the bundled diagram of `chip`'s board, keeping one the project already has
+fn write_diagram(dir: &Path, chip: Chip) -> Result<()> {
+    let info =
+        chips::lookup(chip).with_context(|| format!("Wokwi doesn't simulate the {}", chip))?;
+    let path = dir.join(project::DIAGRAM_FILE);
+    if path.exists() {
+        println!("Kept the existing {}", path.display());
+        return Ok(());
+    }
+    std::fs::write(&path, serde_json::to_string_pretty(&info.diagram())? + "\n")
+        .with_context(|| format!("Failed to write {}", path.display()))?;
+    println!("Wrote {} with a {}", path.display(), info.board);
+    Ok(())
+}
+
+/// Where the project's build puts the elf, relative to `dir`
+fn guess_elf(dir: &Path) -> Result<Option<PathBuf>> {
+    if let Some(name) = cargo_package(dir)? {
+        let target = dir
+            .ancestors()
+            .map(|d| d.join(".cargo").join("config.toml"))
+            .find(|config| config.is_file())
+            .map(|config| read_toml::<CargoConfig>(&config))
+            .transpose()?
+            .and_then(|config| config.build?.target);
+        let profile_dir = match target {
+            Some(target) => Path::new("target").join(target),
+            None => PathBuf::from("target"),
+        };
+        return Ok(Some(profile_dir.join("debug").join(name)));
+    }
+
+    let cmake = dir.join("CMakeLists.txt");
+    if cmake.is_file() {
+        let contents = std::fs::read_to_string(&cmake)
+            .with_context(|| format!("Failed to read {}", cmake.display()))?;
+        let project = Regex::new(r"(?m)^\s*project\(\s*([\w-]+)").unwrap();
+        if let Some(captures) = project.captures(&contents) {
+            return Ok(Some(
+                Path::new("build").join(format!("{}.elf", &captures[1])),
+            ));
+        }
+    }
+    Ok(None)
+}
+
+fn cargo_package(dir: &Path) -> Result<Option<String>> {
+    let manifest = dir.join("Cargo.toml");
+    if !manifest.is_file() {
+        return Ok(None);
+    }
+    let manifest: CargoManifest = read_toml(&manifest)?;
+    Ok(manifest.package.map(|package| package.name))
+}
+
+fn read_toml<T: serde::de::DeserializeOwned>(path: &Path) -> Result<T> {
+    let contents = std::fs::read_to_string(path)
+        .with_context(|| format!("Failed to read {}", path.display()))?;
+    toml::from_str(&contents).with_context(|| format!("Invalid {}", path.display()))
+}
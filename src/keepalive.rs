@@ -0,0 +1,57 @@
+//! Pinging the simulator while it would otherwise hear nothing for a while. Some proxies, like
+//! the port forwarding of Gitpod and Codespaces, drop websockets which look idle, which can
+//! happen while a large firmware is built and sent
+
+use futures_util::{Sink, SinkExt};
+use std::future::Future;
+use std::time::Duration;
+use tungstenite::Message;
+use wokwi_server::router::Progress;
+
+/// the default seconds between pings
+pub const DEFAULT_INTERVAL: u64 = 10;
+
+#[derive(Debug, Clone, Copy)]
+pub struct Keepalive {
+    /// `None` when keep-alives are turned off
+    interval: Option<Duration>,
+}
+
+impl Keepalive {
+    /// Ping every `secs` seconds, or never for 0
+    pub fn new(secs: u64) -> Self {
+        Self {
+            interval: (secs > 0).then(|| Duration::from_secs(secs)),
+        }
+    }
+
+    /// Run `work`, pinging through `sink` each interval until it is done
+    pub async fn during<S, T>(
+        &self,
+        sink: &mut S,
+        work: impl Future<Output = T>,
+    ) -> Result<T, S::Error>
+    where
+        S: Sink<Message> + Unpin,
+    {
+        let Some(interval) = self.interval else {
+            return Ok(work.await);
+        };
+        tokio::pin!(work);
+        loop {
+            tokio::select! {
+                output = &mut work => return Ok(output),
+                _ = tokio::time::sleep(interval) => {
+                    sink.send(Message::Ping(b"building".to_vec())).await?;
+                }
+            }
+        }
+    }
+
+    /// The ping to send after a chunk of a message, telling how much of it has been sent
+    pub fn after(&self, progress: Option<Progress>) -> Option<Message> {
+        let progress = progress.filter(|p| self.interval.is_some() && p.sent < p.count)?;
+        let payload = format!("chunk {}/{}", progress.sent, progress.count);
+        Some(Message::Ping(payload.into_bytes()))
+    }
+}
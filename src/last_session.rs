@@ -0,0 +1,182 @@
+//! A log of the last `run`, written line by line as it happens so that it survives the server
+//! crashing or being killed, for `bug-report` to bundle. The websocket traffic in it is redacted
+//! down to the shape of the messages, without UART data, firmware or anything else long
+
+use regex::Regex;
+use serde_json::Value;
+use std::fs::File;
+use std::io::Write;
+use std::path::PathBuf;
+use std::sync::{Mutex, MutexGuard, OnceLock, PoisonError};
+use std::time::Instant;
+
+/// the log of the current or last session
+pub const FILE: &str = "last-session.log";
+/// the log of the session before it, which is often the one that went wrong
+pub const PREVIOUS: &str = "previous-session.log";
+/// the log stops growing past this, keeping the start of the session where most problems are
+const MAX_LEN: usize = 4 << 20;
+/// strings longer than this are replaced by their length
+const MAX_STRING: usize = 64;
+
+struct Log {
+    file: File,
+    started: Instant,
+    len: usize,
+}
+
+static LOG: Mutex<Option<Log>> = Mutex::new(None);
+static CREDENTIALS: OnceLock<Regex> = OnceLock::new();
+
+/// Where the logs are kept: `$XDG_STATE_HOME/wokwi-server`, `~/.local/state/wokwi-server` or on
+/// Windows `%LOCALAPPDATA%\wokwi-server`
+pub fn dir() -> Option<PathBuf> {
+    let var = |name| std::env::var_os(name).filter(|value| !value.is_empty());
+    if let Some(state) = var("XDG_STATE_HOME") {
+        return Some(PathBuf::from(state).join("wokwi-server"));
+    }
+    if let Some(local) = var("LOCALAPPDATA").filter(|_| cfg!(windows)) {
+        return Some(PathBuf::from(local).join("wokwi-server"));
+    }
+    let home = var("HOME").or_else(|| var("USERPROFILE"))?;
+    Some(PathBuf::from(home).join(".local/state/wokwi-server"))
+}
+
+/// Start a new log, keeping the last one as the previous. Problems with the log are ignored, as
+/// it mustn't get in the way of the simulation
+pub fn start() {
+    let Some(dir) = dir() else {
+        return;
+    };
+    let path = dir.join(FILE);
+    std::fs::create_dir_all(&dir).ok();
+    std::fs::rename(&path, dir.join(PREVIOUS)).ok();
+    let Ok(file) = File::create(&path) else {
+        return;
+    };
+    *lock() = Some(Log {
+        file,
+        started: Instant::now(),
+        len: 0,
+    });
+    for line in environment() {
+        record(&line);
+    }
+
+    let hook = std::panic::take_hook();
+    std::panic::set_hook(Box::new(move |info| {
+        // the log may be what panicked, so don't wait for it
+        if let Ok(mut log) = LOG.try_lock() {
+            write(&mut log, &format!("panic: {}", info));
+        }
+        hook(info);
+    }));
+}
+
+/// Add a line to the log, if there is one
+pub fn record(line: &str) {
+    write(&mut lock(), line);
+}
+
+/// Whether anything is being logged, to skip the work of describing what isn't
+pub fn enabled() -> bool {
+    lock().is_some()
+}
+
+fn lock() -> MutexGuard<'static, Option<Log>> {
+    LOG.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+fn write(log: &mut Option<Log>, line: &str) {
+    let Some(out) = log.as_mut() else {
+        return;
+    };
+    if out.len >= MAX_LEN {
+        return;
+    }
+    let mut line = format!("[{:9.3}] {}\n", out.started.elapsed().as_secs_f64(), line);
+    out.len += line.len();
+    if out.len >= MAX_LEN {
+        line = "The log is full, nothing after this is recorded\n".to_owned();
+    }
+    // unbuffered, so each line is in the file as soon as it happens
+    if out.file.write_all(line.as_bytes()).is_err() {
+        *log = None;
+    }
+}
+
+/// The version, platform, command line and the environment variables which change how the server
+/// behaves, with secrets and the home directory taken out
+pub fn environment() -> Vec<String> {
+    let mut lines = vec![
+        format!(
+            "wokwi-server {} on {} {}",
+            env!("CARGO_PKG_VERSION"),
+            std::env::consts::OS,
+            std::env::consts::ARCH
+        ),
+        format!("in a container: {}", crate::container::in_container()),
+    ];
+
+    let mut args = Vec::new();
+    let mut secret = false;
+    for arg in std::env::args().skip(1) {
+        let is_secret_flag = arg.starts_with('-') && is_secret(&arg);
+        args.push(match arg.split_once('=') {
+            Some((flag, _)) if is_secret_flag => format!("{}=<redacted>", flag),
+            _ if secret => "<redacted>".to_owned(),
+            _ => redact_text(&arg),
+        });
+        secret = is_secret_flag && !arg.contains('=');
+    }
+    lines.push(format!("command line: {}", args.join(" ")));
+
+    let mut vars: Vec<_> = std::env::vars()
+        .filter(|(name, _)| {
+            let upper = name.to_uppercase();
+            upper.starts_with("WOKWI_")
+                || upper.ends_with("_PROXY")
+                || ["BROWSER", "TERM", "VISUAL", "EDITOR"].contains(&upper.as_str())
+        })
+        .collect();
+    vars.sort();
+    for (name, value) in vars {
+        let value = match is_secret(&name) {
+            true => "<redacted>".to_owned(),
+            false => redact_text(&value),
+        };
+        lines.push(format!("{}={}", name, value));
+    }
+    lines
+}
+
+/// Whether a flag or variable holds something which shouldn't be shared
+fn is_secret(name: &str) -> bool {
+    let name = name.to_lowercase();
+    ["token", "secret", "password", "key"]
+        .iter()
+        .any(|word| name.contains(word))
+}
+
+/// Take the home directory and credentials in urls out of `text`
+fn redact_text(text: &str) -> String {
+    let credentials = CREDENTIALS.get_or_init(|| Regex::new(r"://[^/@\s]+@").unwrap());
+    let text = credentials.replace_all(text, "://<redacted>@").into_owned();
+    let home = std::env::var("HOME").or_else(|_| std::env::var("USERPROFILE"));
+    match home {
+        Ok(home) if home.len() > 1 => text.replace(&home, "~"),
+        _ => text,
+    }
+}
+
+/// `value` with the arrays and long strings replaced by their length, leaving the keys, types
+/// and short values that show what a message was
+pub fn redact(value: Value) -> Value {
+    match value {
+        Value::String(s) if s.len() > MAX_STRING => Value::String(format!("<{} chars>", s.len())),
+        Value::String(s) => Value::String(redact_text(&s)),
+        Value::Array(items) => Value::String(format!("<{} items>", items.len())),
+        Value::Object(map) => Value::Object(map.into_iter().map(|(k, v)| (k, redact(v))).collect()),
+        value => value,
+    }
+}
@@ -1,17 +1,191 @@
-
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
 use serde_json::Value;
+use sha2::{Digest, Sha256};
+
+pub mod app_desc;
+pub mod backtrace;
+pub mod bootloader;
+pub mod breakpoints;
+pub mod buses;
+pub mod chips;
+pub mod coredump;
+pub mod error;
+pub mod file_io;
+pub mod freertos;
+pub mod gdb;
+pub mod gdb_script;
+pub mod heap;
+pub mod messages;
+pub mod partitions;
+pub mod plugins;
+pub mod profile;
+pub mod protocol;
+pub mod router;
+pub mod secure_boot;
+pub mod shims;
+pub mod strip;
+pub mod target_description;
+#[cfg(feature = "test-support")]
+pub mod test_support;
+pub mod vcd;
+pub mod watchpoints;
 
-#[derive(Debug, Serialize)]
+pub use error::WokwiServerError;
+use error::{bail, ensure};
+
+#[derive(Debug, Clone, Serialize)]
 pub struct SimulationPacket {
     pub r#type: String,
     pub elf: String, // string because we base64 encode the binary data
     #[serde(rename = "espBin")]
     pub esp_bin: Vec<Vec<Value>>,
+    /// extra information for tooling, ignored by the simulator
+    #[serde(rename = "x-wokwi-server", skip_serializing_if = "Option::is_none")]
+    pub extensions: Option<PacketExtensions>,
+}
+
+impl SimulationPacket {
+    /// Check the packet has the shape the simulator expects, so a mismatch is reported here
+    /// rather than as a simulation that never boots
+    pub fn validate(&self) -> error::Result<()> {
+        ensure!(
+            self.r#type == "start",
+            Image,
+            "type is {:?} rather than \"start\"",
+            self.r#type
+        );
+        let elf = base64::decode(&self.elf)
+            .map_err(|e| WokwiServerError::Image(format!("elf isn't valid base64: {}", e)))?;
+        ensure!(elf.starts_with(b"\x7fELF"), Image, "elf isn't an elf file");
+        ensure!(
+            !self.esp_bin.is_empty(),
+            Image,
+            "espBin has no flash segments"
+        );
+
+        let mut segments = Vec::with_capacity(self.esp_bin.len());
+        for (i, segment) in self.esp_bin.iter().enumerate() {
+            let [addr, data] = segment.as_slice() else {
+                bail!(
+                    Image,
+                    "espBin[{}] should be [address, data] but has {} items",
+                    i,
+                    segment.len()
+                );
+            };
+            let addr = addr
+                .as_u64()
+                .and_then(|addr| u32::try_from(addr).ok())
+                .ok_or_else(|| {
+                    WokwiServerError::Image(format!(
+                        "espBin[{}] address {} isn't a flash offset",
+                        i, addr
+                    ))
+                })?;
+            let data = data.as_str().ok_or_else(|| {
+                WokwiServerError::Image(format!("espBin[{}] data isn't a string", i))
+            })?;
+            let size = base64::decode(data)
+                .map_err(|e| {
+                    WokwiServerError::Image(format!("espBin[{}] data isn't valid base64: {}", i, e))
+                })?
+                .len();
+            ensure!(size > 0, Image, "espBin[{}] at {:#x} is empty", i, addr);
+            segments.push((addr, size));
+        }
+
+        let mut sorted: Vec<_> = segments.iter().enumerate().collect();
+        sorted.sort_by_key(|(_, (addr, _))| *addr);
+        for pair in sorted.windows(2) {
+            let (i, (addr, size)) = pair[0];
+            let (j, (next, _)) = pair[1];
+            ensure!(
+                *addr as u64 + *size as u64 <= *next as u64,
+                Image,
+                "espBin[{}] at {:#x} overlaps espBin[{}] at {:#x}",
+                i,
+                addr,
+                j,
+                next
+            );
+        }
+
+        if let Some(extensions) = &self.extensions {
+            ensure!(
+                extensions.segments.len() == segments.len(),
+                Image,
+                "x-wokwi-server lists {} segments but espBin has {}",
+                extensions.segments.len(),
+                segments.len()
+            );
+            for (checksum, (addr, size)) in extensions.segments.iter().zip(&segments) {
+                ensure!(
+                    checksum.addr == *addr && checksum.size == *size,
+                    Image,
+                    "x-wokwi-server describes the {} segment as {} bytes at {:#x}, \
+                     but espBin has {} bytes at {:#x}",
+                    checksum.name,
+                    checksum.size,
+                    checksum.addr,
+                    size,
+                    addr
+                );
+            }
+        }
+        Ok(())
+    }
+
+    /// The packet as JSON with the elf and segment data replaced by their sizes, for reading
+    /// or attaching to a bug report
+    pub fn summary(&self) -> Value {
+        let elided = |data: &str| {
+            let size = base64::decode(data).map_or(0, |data| data.len());
+            Value::String(format!("<{} bytes>", size))
+        };
+        let mut summary = serde_json::to_value(self).unwrap_or(Value::Null);
+        summary["elf"] = elided(&self.elf);
+        if let Some(segments) = summary["espBin"].as_array_mut() {
+            for segment in segments {
+                if let Some(data) = segment.get(1).and_then(Value::as_str) {
+                    segment[1] = elided(data);
+                }
+            }
+        }
+        summary
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct PacketExtensions {
+    pub segments: Vec<SegmentChecksum>,
+}
+
+/// The SHA-256 digest of a flash segment sent to the simulator
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SegmentChecksum {
+    pub name: String,
+    pub addr: u32,
+    pub size: usize,
+    pub sha256: String,
+}
+
+impl SegmentChecksum {
+    pub fn new(name: &str, addr: u32, data: &[u8]) -> Self {
+        Self {
+            name: name.to_owned(),
+            addr,
+            size: data.len(),
+            sha256: Sha256::digest(data)
+                .iter()
+                .map(|b| format!("{:02x}", b))
+                .collect(),
+        }
+    }
 }
 
 #[derive(Debug)]
 pub enum GdbInstruction {
-    Command(String),
+    /// a packet's contents as they were received, which aren't always UTF-8
+    Command(Vec<u8>),
     Break,
-}
\ No newline at end of file
+}
@@ -0,0 +1,173 @@
+use regex::bytes::Regex;
+
+const RED: &[u8] = b"\x1b[31m";
+const YELLOW: &[u8] = b"\x1b[33m";
+const RESET: &[u8] = b"\x1b[0m";
+
+/// Log levels, in the order ESP-IDF uses them
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum Level {
+    None,
+    Error,
+    Warn,
+    Info,
+    Debug,
+    Verbose,
+}
+
+impl Level {
+    fn parse(s: &str) -> Option<Self> {
+        Some(match s.to_ascii_lowercase().as_str() {
+            "none" | "off" => Level::None,
+            "error" | "e" => Level::Error,
+            "warn" | "w" => Level::Warn,
+            "info" | "i" => Level::Info,
+            "debug" | "d" => Level::Debug,
+            "verbose" | "trace" | "v" => Level::Verbose,
+            _ => return None,
+        })
+    }
+}
+
+/// Suppresses ESP-IDF and esp-println log lines by tag and level, and highlights errors and warnings.
+///
+/// Lines which don't look like log output are always shown.
+#[derive(Debug, Clone)]
+pub struct LogFilter {
+    /// maximum level for each tag, `*` matches any tag
+    rules: Vec<(String, Level)>,
+    /// `I (1234) tag: message`, optionally colored, or `INFO - message`
+    pattern: Regex,
+    /// start of a line which hasn't been finished yet
+    pending: Vec<u8>,
+    /// whether the rest of the current line is shown, once that has been decided
+    current: Option<bool>,
+}
+
+/// Parse `tag=level,...`, a level on its own applies to all tags
+pub fn parse(s: &str) -> Result<LogFilter, String> {
+    let rules = s
+        .split(',')
+        .map(str::trim)
+        .filter(|rule| !rule.is_empty())
+        .map(|rule| {
+            let (tag, level) = rule.split_once('=').unwrap_or(("*", rule));
+            let level = Level::parse(level.trim())
+                .ok_or_else(|| format!("unknown log level `{}`", level.trim()))?;
+            Ok((tag.trim().to_owned(), level))
+        })
+        .collect::<Result<_, String>>()?;
+    Ok(LogFilter {
+        rules,
+        pattern: Regex::new(
+            r"^(?:\x1b\[[0-9;]*m)?(?:([EWIDV]) \([0-9:.]+\) ([^:]*):|(ERROR|WARN|INFO|DEBUG|TRACE) +- )",
+        )
+        .unwrap(),
+        pending: Vec::new(),
+        current: None,
+    })
+}
+
+/// Filters are the same if their rules are, whatever line they are part way through
+impl PartialEq for LogFilter {
+    fn eq(&self, other: &Self) -> bool {
+        self.rules == other.rules
+    }
+}
+
+impl LogFilter {
+    /// Filter newly received bytes, partial lines are held back until complete
+    pub fn push(&mut self, bytes: &[u8]) -> Vec<u8> {
+        let mut out = Vec::with_capacity(bytes.len());
+        for &b in bytes {
+            match self.current {
+                Some(shown) => {
+                    if shown {
+                        out.push(b);
+                    }
+                    if b == b'\n' {
+                        self.current = None;
+                    }
+                }
+                None => {
+                    self.pending.push(b);
+                    if b == b'\n' {
+                        let line = std::mem::take(&mut self.pending);
+                        self.emit(&line, &mut out);
+                    }
+                }
+            }
+        }
+        out
+    }
+
+    /// Whether part of a line is being held back
+    pub fn has_pending(&self) -> bool {
+        !self.pending.is_empty()
+    }
+
+    /// Decide on the line received so far, the rest of it follows once it arrives
+    pub fn flush(&mut self) -> Vec<u8> {
+        let mut out = Vec::new();
+        let line = std::mem::take(&mut self.pending);
+        if !line.is_empty() {
+            let shown = self.emit(&line, &mut out);
+            self.current = Some(shown);
+        }
+        out
+    }
+
+    fn emit(&self, line: &[u8], out: &mut Vec<u8>) -> bool {
+        let Some((tag, level)) = self.classify(line) else {
+            out.extend_from_slice(line);
+            return true;
+        };
+        if level > self.max_level(&tag) {
+            return false;
+        }
+        // leave lines which are already colored alone
+        let color = match level {
+            _ if line.starts_with(b"\x1b") => None,
+            Level::Error => Some(RED),
+            Level::Warn => Some(YELLOW),
+            _ => None,
+        };
+        match color {
+            Some(color) => {
+                let (text, newline) = match line.strip_suffix(b"\n") {
+                    Some(text) => (text, &b"\n"[..]),
+                    None => (line, &b""[..]),
+                };
+                out.extend_from_slice(color);
+                out.extend_from_slice(text);
+                out.extend_from_slice(RESET);
+                out.extend_from_slice(newline);
+            }
+            None => out.extend_from_slice(line),
+        }
+        true
+    }
+
+    /// The tag and level of a log line
+    fn classify(&self, line: &[u8]) -> Option<(String, Level)> {
+        let captures = self.pattern.captures(line)?;
+        let text = |i| {
+            captures
+                .get(i)
+                .map(|m| String::from_utf8_lossy(m.as_bytes()).into_owned())
+        };
+        match (text(1), text(2), text(3)) {
+            (Some(level), Some(tag), _) => Some((tag, Level::parse(&level)?)),
+            (_, _, Some(level)) => Some((String::new(), Level::parse(&level)?)),
+            _ => None,
+        }
+    }
+
+    fn max_level(&self, tag: &str) -> Level {
+        let rule = |t: &str| self.rules.iter().rev().find(|(tag, _)| tag == t);
+        rule(tag)
+            .or_else(|| rule("*"))
+            .map(|(_, level)| *level)
+            .unwrap_or(Level::Verbose)
+    }
+}
@@ -1,103 +1,1138 @@
 use anyhow::Context;
 use anyhow::Result;
 use bytes::{Buf, BytesMut};
-use espflash::elf::ElfFirmwareImage;
-use futures_util::{SinkExt, StreamExt};
+use futures_util::{Sink, SinkExt, StreamExt};
 use serde_json::{json, Value};
-use std::path::PathBuf;
+use std::ffi::OsString;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 use tokio::io::AsyncReadExt;
 use tokio::io::AsyncWriteExt;
 use tokio::net::{TcpListener, TcpStream};
 use tokio::signal;
 use tokio::sync::mpsc::{Receiver, Sender};
+use tokio::sync::watch;
 use tokio::task::JoinSet;
-use tokio_tungstenite::accept_async;
-use wokwi_server::{GdbInstruction, SimulationPacket};
+use tokio_tungstenite::accept_async_with_config;
+use tokio_util::sync::CancellationToken;
+use tungstenite::protocol::frame::coding::CloseCode;
+use tungstenite::protocol::CloseFrame;
+use wokwi_server::breakpoints;
+use wokwi_server::coredump::CrashWatch;
+use wokwi_server::file_io::FileIo;
+use wokwi_server::gdb::{self, GdbPacket};
+use wokwi_server::gdb_script::{self, Runner};
+use wokwi_server::protocol::{self, Hello, ProtocolPin};
+use wokwi_server::router::Outbox;
+use wokwi_server::watchpoints::{Capabilities, Watchpoints};
+use wokwi_server::{chips, GdbInstruction, SimulationPacket};
+use wokwi_server::{freertos, heap, target_description};
 
-use espflash::{Chip, PartitionTable};
+use espflash::Chip;
 
 const PORT: u16 = 9012;
 const GDB_PORT: u16 = 9333;
+/// where the Wokwi embed is served from
+const URL_BASE: &str = "https://wokwi.com/_alpha/wembed/";
 
-use clap::Parser;
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+const RETRY_BACKOFF: Duration = Duration::from_millis(100);
+const MAX_RETRY_BACKOFF: Duration = Duration::from_secs(5);
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(2);
+/// show an incomplete hex dump row after the UART has been quiet for this long
+const UART_IDLE: Duration = Duration::from_millis(100);
 
-/// Wokwi server
-#[derive(Parser, Debug, Clone)]
-#[clap(author, version, about, long_about = None)]
-struct Args {
+use clap::{Parser, Subcommand};
+
+mod activity;
+mod artifacts;
+mod batch;
+mod benchmark;
+mod boot_hints;
+mod broker;
+mod browser;
+mod bug_report;
+mod capture;
+mod cargo_profile;
+mod catalog;
+mod command;
+mod completions;
+mod compressed;
+mod config;
+mod container;
+mod control;
+mod custom_chips;
+mod daemon;
+mod dashboard;
+mod debugger;
+mod doctor;
+mod download;
+mod endpoint;
+mod exit_marker;
+mod expect;
+mod firmware_info;
+mod gdb_trace;
+mod handlers;
+mod http;
+mod image;
+mod init;
+mod keepalive;
+mod last_session;
+mod log_filter;
+mod pack;
+mod panic_trace;
+mod peer;
+mod port_owner;
+mod profiler;
+mod project;
+mod project_settings;
+mod pull;
+mod push;
+mod repeats;
+mod report;
+mod serve;
+mod session;
+mod simulator_port;
+mod sinks;
+mod stale;
+mod stats;
+#[cfg(all(feature = "tui", unix))]
+mod tui;
+mod uart_display;
+mod uart_input;
+mod update;
+mod waveform;
+mod ws_trace;
+
+use activity::Activity;
+use artifacts::RunLog;
+use benchmark::Benchmark;
+use boot_hints::BootHints;
+use broker::{Broker, SimulatorLink};
+use catalog::{Code, Failure};
+use control::ControlRequest;
+use custom_chips::{BridgeSpec, Bridges, ChipSpec, CustomChip};
+use expect::{Expectations, Outcome};
+use firmware_info::FirmwareInfo;
+use gdb_trace::{GdbTrace, Hop};
+use handlers::Run;
+use image::ImageArgs;
+use keepalive::Keepalive;
+use log_filter::LogFilter;
+use panic_trace::PanicTrace;
+use peer::PeerSpec;
+use profiler::Profiler;
+use session::{Kind, Session, Sessions};
+use simulator_port::{SimulatorPort, SimulatorStream};
+use sinks::{SinkOptions, SinkSpec, Sinks};
+use uart_display::UartDisplay;
+use waveform::Waveform;
+use ws_trace::{Direction, TracedSink, WsTrace};
+
+/// Options for serving the simulation to the browser
+#[derive(clap::Args, Debug, Clone)]
+struct ServerArgs {
     #[clap(short, long, env = "WOKWI_HOST")]
     host: Option<String>,
 
-    /// chip name
-    #[clap(short, long)]
-    chip: Chip,
+    /// address to listen on, defaults to 127.0.0.1, or 0.0.0.0 inside a container
+    #[clap(long)]
+    bind: Option<IpAddr>,
 
-    /// path to bootloader
-    #[clap(short, long)]
-    bootloader: Option<PathBuf>,
-
-    /// path to partition table csv
-    #[clap(short, long)]
-    partition_table: Option<PathBuf>,
+    /// port the simulator connects to, 0 picks a free one
+    #[clap(long, default_value_t = PORT)]
+    port: u16,
 
-    /// wokwi project id
+    /// wokwi project id, defaults to `id` in wokwi.toml
     #[clap(short, long)]
     id: Option<String>,
 
-    elf: PathBuf,
+    /// url of the Wokwi embed the project id is appended to, for self-hosted or staging
+    /// instances of Wokwi
+    #[clap(long, value_name = "URL", env = "WOKWI_URL_BASE", default_value = URL_BASE, value_parser = parse_url_base)]
+    wokwi_url_base: url::Url,
+
+    /// another url of the Wokwi embed, used if the others can't be reached when starting. Can
+    /// be repeated
+    #[clap(long, value_name = "URL", value_parser = parse_url_base)]
+    wokwi_url_fallback: Vec<url::Url>,
+
+    /// don't check that the Wokwi embed can be reached when starting
+    #[clap(long)]
+    no_probe: bool,
+
+    /// extra query parameter (key=value) to add to the wokwi embed url, can be repeated
+    #[clap(long, value_parser = parse_embed_param)]
+    embed_param: Vec<(String, String)>,
+
+    /// don't open the simulation in the browser
+    #[clap(long)]
+    no_open: bool,
+
+    /// command used to open the browser, `{url}` is replaced with the simulation link. Defaults
+    /// to the first of the colon separated commands in $BROWSER
+    #[clap(long)]
+    browser: Option<String>,
+
+    /// seconds between pings to the simulator while it would otherwise hear nothing, e.g. during
+    /// a long build, so proxies don't drop the connection. 0 turns them off
+    #[clap(long, value_name = "SECONDS", default_value_t = keepalive::DEFAULT_INTERVAL)]
+    keepalive: u64,
+
+    /// version of the Wokwi embed protocol to speak, `auto` for the one the simulator announces.
+    /// Pinning keeps a newer embed working if it still understands an older version
+    #[clap(long, value_name = "VERSION", env = "WOKWI_EMBED_PROTOCOL", default_value_t = ProtocolPin::Auto)]
+    embed_protocol: ProtocolPin,
+}
+
+impl ServerArgs {
+    /// The address to listen on
+    fn bind_addr(&self, in_container: bool) -> IpAddr {
+        self.bind.unwrap_or(if in_container {
+            IpAddr::V4(Ipv4Addr::UNSPECIFIED)
+        } else {
+            IpAddr::V4(Ipv4Addr::LOCALHOST)
+        })
+    }
+}
+
+/// Wokwi server
+#[derive(Parser, Debug)]
+#[clap(
+    name = "wokwi-server",
+    author,
+    version,
+    about,
+    long_about = None,
+    after_help = "Without a command, the arguments are those of `run`, e.g. `wokwi-server --chip esp32 app.elf`. Use `wokwi-server <command> --help` for details"
+)]
+enum Command {
+    /// simulate a firmware in Wokwi, the default when no command is given
+    Run(Box<Args>),
+    /// run a list of firmwares one after another, checking their UART output
+    Batch(batch::BatchArgs),
+    /// build the flash image for a firmware and write it to a file
+    Pack(pack::PackArgs),
+    /// simulate an image written by `pack`, without building it again
+    #[clap(trailing_var_arg = true, allow_hyphen_values = true)]
+    Serve(serve::ServeArgs),
+    /// check the project and environment for problems which would stop a simulation
+    Doctor(doctor::DoctorArgs),
+    /// write a wokwi.toml for the project
+    Init(init::InitArgs),
+    /// download the diagram of a public Wokwi project and simulate on that project
+    Pull(pull::PullArgs),
+    /// upload the local diagram.json to a Wokwi project
+    Push(push::PushArgs),
+    /// change the project's settings in .wokwi-server.toml, which `run` uses as defaults
+    Config(project_settings::ConfigArgs),
+    /// print a shell completion script
+    Completions(completions::CompletionsArgs),
+    /// check GitHub for a newer wokwi-server and install it
+    SelfUpdate(update::SelfUpdateArgs),
+    /// zip up the log of the last session to attach to an issue
+    #[clap(long_flag = "bug-report")]
+    BugReport(bug_report::BugReportArgs),
+}
+
+impl Command {
+    /// The command line, with `run` inserted when no command is given so that
+    /// `wokwi-server --chip esp32 app.elf` keeps working
+    fn args() -> Vec<OsString> {
+        let mut args: Vec<OsString> = std::env::args_os().collect();
+        let explicit = args.get(1).and_then(|arg| arg.to_str()).is_some_and(|arg| {
+            Self::has_subcommand(arg)
+                || ["help", "--help", "--version", "-V", "--bug-report"].contains(&arg)
+        });
+        if !explicit {
+            args.insert(1.min(args.len()), "run".into());
+        }
+        if args.get(1).is_some_and(|arg| arg == "run") {
+            project_settings::apply(&mut args);
+        }
+        args
+    }
+}
+
+/// Wokwi server
+#[derive(Parser, Debug, Clone)]
+#[clap(
+    author,
+    version,
+    about,
+    long_about = None,
+    after_help = "`run` is the default command, so these options can also be given without it. Use `wokwi-server help` for the other commands"
+)]
+// `-h` is taken by `--host`
+#[clap(disable_help_flag = true)]
+struct Args {
+    /// Print help information
+    #[clap(long, action = clap::ArgAction::Help)]
+    help: Option<bool>,
+
+    #[clap(flatten)]
+    server: ServerArgs,
+
+    /// port to expose the GDB server on, 0 picks a free one
+    #[clap(long, default_value_t = GDB_PORT)]
+    gdb_port: u16,
+
+    /// GDB packets to hold while no simulation is running, the oldest are dropped beyond this
+    #[clap(long, value_name = "PACKETS", default_value_t = 64)]
+    gdb_queue: usize,
+
+    /// copy the simulation link to the clipboard
+    #[clap(long)]
+    copy_url: bool,
+
+    /// launch this GDB executable attached to the simulation once it has started
+    #[clap(long)]
+    gdb: Option<String>,
+
+    /// extra arguments to pass to GDB
+    #[clap(long, requires = "gdb", allow_hyphen_values = true)]
+    gdb_args: Option<String>,
+
+    /// write a GDB init script connecting to this session to the given file
+    #[clap(long)]
+    gdbinit: Option<PathBuf>,
+
+    /// serve this target description to GDB instead of the built in one for the chip
+    #[clap(long, value_name = "PATH")]
+    gdb_target_xml: Option<PathBuf>,
+
+    /// don't serve a target description to GDB, leaving it to work out the registers itself
+    #[clap(long, conflicts_with = "gdb-target-xml")]
+    no_gdb_target_xml: bool,
+
+    /// don't show the firmware's FreeRTOS tasks to GDB as threads
+    #[clap(long)]
+    no_gdb_threads: bool,
+
+    /// stop the firmware as soon as it starts, until a GDB client attaches and continues it, to
+    /// debug early boot code
+    #[clap(long)]
+    halt_on_attach: bool,
+
+    /// stop the firmware at this symbol, e.g. `app_main`, or address as it starts, keeping it
+    /// stopped for GDB to attach. May be repeated
+    #[clap(long, value_name = "SYMBOL", conflicts_with = "halt-on-attach")]
+    break_at: Vec<String>,
+
+    /// GDB commands to run through the simulator's GDB stub as the firmware starts, before the
+    /// firmware is continued: break, hbreak, set, monitor and maintenance packet. Not to be
+    /// confused with --gdbinit, which is written for a GDB client
+    #[clap(long, value_name = "FILE")]
+    gdb_init: Option<PathBuf>,
+
+    /// read the firmware's free heap through the GDB stub every this many seconds and print it,
+    /// to spot leaks over long runs
+    #[clap(long, value_name = "SECONDS")]
+    heap_stats: Option<u64>,
+
+    /// sample the firmware's call stack through the GDB stub, writing the stacks to this file in
+    /// the folded format of flamegraph tools when the simulation ends
+    #[clap(long, value_name = "PATH")]
+    cpu_profile: Option<PathBuf>,
+
+    /// seed the simulator's randomness with this number, or a `random` one which is printed, so a
+    /// run can be reproduced exactly, when the simulator supports it
+    #[clap(long, value_name = "SEED", value_parser = parse_seed)]
+    sim_seed: Option<u64>,
+
+    /// boot the firmware this many times, timing each boot from the start packet being delivered
+    /// to the first UART output and to `--ready-marker`, then print the mean and standard
+    /// deviation of the runs and exit
+    #[clap(long, value_name = "RUNS")]
+    benchmark: Option<u32>,
+
+    /// UART output showing the firmware has finished booting, e.g. `Listening on`, which ends each
+    /// run of `--benchmark` instead of the first output
+    #[clap(long, value_name = "TEXT", requires = "benchmark")]
+    ready_marker: Option<String>,
+
+    /// write the times of the `--benchmark` runs to this file as JSON
+    #[clap(long, value_name = "PATH", requires = "benchmark")]
+    benchmark_json: Option<PathBuf>,
+
+    /// milliseconds between the samples of `--cpu-profile`
+    #[clap(long, value_name = "MS", requires = "cpu-profile", default_value_t = profiler::DEFAULT_INTERVAL)]
+    cpu_profile_interval: u64,
+
+    /// answer the firmware's GDB File-I/O calls with the files in this directory, instead of
+    /// passing them on to GDB
+    #[clap(long, value_name = "DIR")]
+    fio_root: Option<PathBuf>,
+
+    /// print the GDB commands to connect to this session
+    #[clap(long)]
+    print_gdbinit: bool,
+
+    /// print the simulation and GDB addresses, then exit
+    #[clap(long)]
+    print_urls_only: bool,
+
+    /// print a machine readable JSON description of the server on startup
+    #[clap(long)]
+    output_json: bool,
+
+    #[clap(flatten)]
+    image: ImageArgs,
+
+    /// give up after this many consecutive simulation connection errors
+    #[clap(long)]
+    max_errors: Option<u32>,
+
+    /// times to send the firmware again when the connection drops while it is being sent,
+    /// without counting towards --max-errors
+    #[clap(long, value_name = "N", default_value_t = 3)]
+    start_retries: u32,
+
+    /// run the server in the background
+    #[clap(long)]
+    daemon: bool,
+
+    /// write the process id of the server to this file
+    #[clap(long)]
+    pidfile: Option<PathBuf>,
+
+    /// file to write server output to when running in the background
+    #[clap(long, requires = "daemon")]
+    log_file: Option<PathBuf>,
+
+    /// port to serve the control API on
+    #[clap(long)]
+    control_port: Option<u16>,
+
+    /// write the summary printed on exit to this file as JSON
+    #[clap(long, value_name = "PATH")]
+    stats_json: Option<PathBuf>,
+
+    /// a name for this run, e.g. the CI job, added to the event log, the report and the summary
+    /// along with the git commit and build time of the firmware
+    #[clap(long, value_name = "NAME")]
+    session_name: Option<String>,
+
+    /// port to serve a web dashboard on, showing the simulation's output and state
+    #[clap(long)]
+    dashboard_port: Option<u16>,
+
+    /// append connection events as newline delimited JSON to this file
+    #[clap(long)]
+    event_log: Option<PathBuf>,
+
+    /// log every websocket message to and from the simulator as newline delimited JSON, with
+    /// large payloads like the firmware replaced by their length and hash
+    #[clap(long, value_name = "PATH")]
+    ws_trace: Option<PathBuf>,
+
+    /// log every GDB remote protocol packet, between GDB and the server and between the server
+    /// and the simulator's GDB stub, a line each with its timestamp, direction and checksum
+    #[clap(long, value_name = "PATH")]
+    gdb_trace: Option<PathBuf>,
+
+    /// file or named pipe to stream into the simulated UART once the simulation has started
+    #[clap(long)]
+    uart_input: Option<PathBuf>,
+
+    /// rate to send UART input at, in bytes per second
+    #[clap(long, requires = "uart-input")]
+    uart_input_rate: Option<u32>,
+
+    /// milliseconds to pause after each line of UART input
+    #[clap(long, requires = "uart-input")]
+    uart_input_line_delay: Option<u64>,
+
+    /// milliseconds to wait after the simulation has started before sending UART input
+    #[clap(long, requires = "uart-input")]
+    uart_input_delay: Option<u64>,
+
+    /// a device to attach to the simulated UART: the path of a script of rules, script:<path>,
+    /// modbus:<register map> for a Modbus RTU slave, or nmea[:<fix>] for a GPS receiver
+    #[clap(long, value_name = "PEER", value_parser = peer::parse_spec)]
+    uart_peer: Option<PeerSpec>,
+
+    /// a Wokwi custom chip to load into the simulation, `<name>=<path of the .wasm>` with the
+    /// chip's `.json` next to it, can be repeated. Chips listed in wokwi.toml are loaded too
+    #[clap(long, value_name = "NAME=PATH", value_parser = custom_chips::parse_spec)]
+    custom_chip: Vec<ChipSpec>,
+
+    /// a process on the host exchanging a custom chip's serial and pin traffic as lines of JSON,
+    /// `<chip>=<command>`, can be repeated
+    #[clap(long, value_name = "CHIP=COMMAND", value_parser = custom_chips::parse_bridge)]
+    chip_bridge: Vec<BridgeSpec>,
+
+    /// how to show UART output from the simulation
+    #[clap(long, value_enum, default_value_t = UartDisplay::Text)]
+    uart_display: UartDisplay,
+
+    /// hide ESP-IDF and esp-println log lines by tag and level, e.g. `wifi=warn,*=info`
+    #[clap(long, value_name = "FILTER", value_parser = log_filter::parse)]
+    log_filter: Option<LogFilter>,
+
+    /// don't print hints when the UART output shows the firmware failed to boot
+    #[clap(long)]
+    no_boot_hints: bool,
+
+    /// print the I2C transactions of the firmware, with the register and the device usually at
+    /// the address, when the simulator reports them
+    #[clap(long)]
+    i2c_log: bool,
+
+    /// print the SPI transactions of the firmware, when the simulator reports them
+    #[clap(long)]
+    spi_log: bool,
+
+    /// record the firmware's pin changes, when the simulator reports them, and write them to this
+    /// file as a Value Change Dump for GTKWave when the simulation ends
+    #[clap(long, value_name = "PATH")]
+    vcd: Option<PathBuf>,
+
+    /// shared library adding handlers for more types of messages from the simulator, can be
+    /// repeated
+    #[cfg(feature = "dynamic-plugins")]
+    #[clap(long, value_name = "PATH")]
+    plugin: Vec<PathBuf>,
+
+    /// don't keep a log of this session for `bug-report`
+    #[clap(long)]
+    no_session_log: bool,
+
+    /// don't check GitHub for a newer wokwi-server on startup
+    #[clap(long, env = "WOKWI_NO_UPDATE_CHECK")]
+    no_update_check: bool,
+
+    /// don't look up the addresses of panic backtraces on the UART in the elf
+    #[clap(long)]
+    no_backtrace_decode: bool,
+
+    /// the elf of the bootloader, to look up addresses in the bootloader too when decoding
+    /// backtraces, and to load its symbols into GDB next to the app's
+    #[clap(long, value_name = "PATH")]
+    bootloader_elf: Option<PathBuf>,
+
+    /// lines of source shown either side of the line a panic happened on
+    #[clap(long, value_name = "N", default_value_t = panic_trace::DEFAULT_CONTEXT)]
+    source_context: u32,
+
+    /// open the line a panic happened on with this command, e.g. `code -g {file}:{line}`.
+    /// Defaults to $VISUAL or $EDITOR
+    #[clap(
+        long,
+        value_name = "COMMAND",
+        require_equals = true,
+        min_values = 0,
+        multiple_values = false,
+        default_missing_value = "",
+        conflicts_with = "no-backtrace-decode"
+    )]
+    open_editor: Option<String>,
+
+    /// where to send UART output, may be repeated: stdout, file:<path>, tcp:[<addr>:]<port>,
+    /// udp:[<addr>:]<port>, ws:[<addr>:]<port>, pty or defmt. Defaults to stdout
+    #[clap(long, value_name = "SINK", value_parser = sinks::parse_spec)]
+    uart_sink: Vec<SinkSpec>,
+
+    /// mirror the raw UART bytes to an address as UDP datagrams, which may be a multicast group,
+    /// or to clients of a port with `tcp:`, keeping the other sinks. May be repeated
+    #[clap(long, value_name = "[udp:|tcp:][ADDR:]PORT", value_parser = sinks::parse_tap)]
+    uart_tap: Vec<SinkSpec>,
+
+    /// record the UART traffic both ways with timestamps, as pcapng for Wireshark or, with a
+    /// `.sr` extension, a sigrok session for PulseView
+    #[clap(long, value_name = "PATH")]
+    uart_capture: Option<PathBuf>,
+
+    /// the baud rate the UART lines are drawn at in a sigrok session
+    #[clap(
+        long,
+        value_name = "BAUD",
+        default_value_t = 115200,
+        requires = "uart-capture"
+    )]
+    uart_capture_baud: u32,
+
+    /// exit with the code captured by this pattern when a matching line is printed on the UART,
+    /// defaults to `WOKWI_EXIT <code>`
+    #[clap(
+        long,
+        value_name = "REGEX",
+        require_equals = true,
+        min_values = 0,
+        multiple_values = false,
+        default_missing_value = exit_marker::DEFAULT_PATTERN,
+        value_parser = exit_marker::parse_pattern
+    )]
+    exit_marker: Option<regex::Regex>,
+
+    /// text which must appear on the UART, can be repeated to expect several lines in order.
+    /// The server exits once all of them have been seen
+    #[clap(long, value_name = "TEXT")]
+    expect: Vec<String>,
+
+    /// fail as soon as a line containing this text appears on the UART, can be repeated
+    #[clap(long, value_name = "TEXT")]
+    fail_on: Vec<String>,
+
+    /// stop the simulation after this many seconds, failing if expectations are still outstanding
+    #[clap(long, value_name = "SECONDS")]
+    timeout: Option<u64>,
+
+    /// write the outcome of a headless run to this file, as JUnit XML if it ends in `.xml` or JSON otherwise
+    #[clap(long)]
+    report: Option<PathBuf>,
+
+    /// print GitHub Actions annotations for failed headless runs
+    #[clap(long)]
+    gha_annotations: bool,
+
+    /// when a headless run fails, write the UART output, events, recent websocket messages
+    /// and flash segment details to this directory
+    #[clap(long)]
+    artifacts_dir: Option<PathBuf>,
+
+    /// when the firmware crashes, read its registers and RAM through the GDB stub and write them
+    /// to this file as an ESP-IDF core dump (ELF format)
+    #[clap(long, value_name = "PATH")]
+    core_dump: Option<PathBuf>,
+
+    /// write each start packet to this file before sending it, with the elf and flash segments
+    /// replaced by their sizes, to inspect or attach to a bug report
+    #[clap(long, value_name = "PATH")]
+    dump_start_packet: Option<PathBuf>,
+
+    /// write the elf and flash segments to `--dump-start-packet` too
+    #[clap(long, requires = "dump-start-packet")]
+    full: bool,
+
+    /// settings to apply while running, `wokwi-server.toml` in the current directory by default.
+    /// Changes to its log filter, UART sinks and expectations are applied without a restart
+    #[clap(long, value_name = "PATH")]
+    config: Option<PathBuf>,
+
+    /// run the simulation this many times faster than real time, e.g. `0.5` for half speed, when
+    /// the simulator supports it
+    #[clap(long, value_name = "FACTOR", value_parser = parse_speed)]
+    sim_speed: Option<f64>,
+
+    /// show UART output, GDB traffic and connection status in a terminal UI
+    #[cfg(all(feature = "tui", unix))]
+    #[clap(long, conflicts_with_all = &["gdb", "daemon", "output-json"])]
+    tui: bool,
+
+    /// a start packet to send instead of building one from the elf, set by `serve`
+    #[clap(skip)]
+    payload: Option<Arc<SimulationPacket>>,
+
+    /// the custom chips loaded from `--custom-chip` and wokwi.toml
+    #[clap(skip)]
+    custom_chips: Arc<Vec<CustomChip>>,
+
+    /// the commands of `--break-at` and `--gdb-init`
+    #[clap(skip)]
+    gdb_script: Arc<Vec<gdb_script::Command>>,
+}
+
+impl Args {
+    /// The settings which the config file can change
+    fn settings(&self) -> config::Settings {
+        config::Settings {
+            log_filter: self.log_filter.clone(),
+            uart_sinks: self.uart_sink.clone(),
+            expect: self.expect.clone(),
+            fail_on: self.fail_on.clone(),
+        }
+    }
+
+    /// Apply settings from the config file, reopening the UART sinks if they changed. Returns the
+    /// names of the settings which changed, nothing changes if the sinks can't be opened
+    fn reconfigure(
+        &mut self,
+        sinks: &mut Sinks,
+        settings: config::Settings,
+    ) -> Result<Vec<&'static str>> {
+        let changes = settings.changes(&self.settings());
+        if changes.contains(&"uart_sinks") || changes.contains(&"log_filter") {
+            let sink_options = SinkOptions {
+                display: self.uart_display,
+                log_filter: settings.log_filter.clone(),
+            };
+            sinks.configure(&sink_specs(&settings.uart_sinks, self.tui()), &sink_options)?;
+        }
+        self.use_settings(settings);
+        Ok(changes)
+    }
+
+    fn use_settings(&mut self, settings: config::Settings) {
+        self.log_filter = settings.log_filter;
+        self.uart_sink = settings.uart_sinks;
+        self.expect = settings.expect;
+        self.fail_on = settings.fail_on;
+    }
+
+    /// Checks for the expected output, if any was asked for
+    fn expectations(&self) -> Option<Expectations> {
+        (!self.expect.is_empty() || !self.fail_on.is_empty())
+            .then(|| Expectations::new(self.expect.clone(), self.fail_on.clone()))
+    }
+
+    /// The target description served to GDB, if any
+    fn target_xml(&self) -> Result<Option<Vec<u8>>> {
+        if self.no_gdb_target_xml {
+            return Ok(None);
+        }
+        match &self.gdb_target_xml {
+            Some(path) => std::fs::read(path)
+                .map(Some)
+                .with_context(|| format!("Failed to read {}", path.display())),
+            None => Ok(target_description::for_chip(self.image.chip).map(String::into_bytes)),
+        }
+    }
+
+    /// Where the firmware keeps its FreeRTOS tasks, to show them to GDB as threads
+    fn freertos(&self) -> Option<freertos::Symbols> {
+        if self.no_gdb_threads {
+            return None;
+        }
+        let bytes = std::fs::read(&self.image.elf).ok()?;
+        freertos::Symbols::find(&xmas_elf::ElfFile::new(&bytes).ok()?)
+    }
+
+    /// Where the firmware's allocator keeps its books, if `--heap-stats` was given
+    fn heap(&self) -> Option<heap::Symbols> {
+        self.heap_stats?;
+        let bytes = std::fs::read(&self.image.elf).ok()?;
+        heap::Symbols::find(&xmas_elf::ElfFile::new(&bytes).ok()?)
+    }
+
+    /// Whether the terminal UI was asked for
+    fn tui(&self) -> bool {
+        #[cfg(all(feature = "tui", unix))]
+        return self.tui;
+        #[cfg(not(all(feature = "tui", unix)))]
+        false
+    }
+
+    /// The start packet for the firmware, building it unless `serve` gave one
+    async fn start_packet(&self, label: &str) -> Result<SimulationPacket> {
+        let simdata = match &self.payload {
+            Some(payload) => SimulationPacket::clone(payload),
+            None => image::start_packet(&self.image, label).await?,
+        };
+        // dump before validating, an invalid packet is the one worth looking at
+        if let Some(path) = &self.dump_start_packet {
+            let dump = match self.full {
+                true => serde_json::to_value(&simdata)?,
+                false => simdata.summary(),
+            };
+            tokio::fs::write(path, serde_json::to_string_pretty(&dump)?)
+                .await
+                .with_context(|| format!("Failed to write {}", path.display()))?;
+            println!("[{}] Wrote the start packet to {}", label, path.display());
+        }
+        simdata.validate().context(Failure::new(
+            Code::InvalidStartPacket,
+            "Refusing to send an invalid start packet",
+        ))?;
+        Ok(simdata)
+    }
 }
 
 #[tokio::main]
-async fn main() -> Result<(), anyhow::Error> {
+async fn main() {
     #[cfg(feature = "tokio-console")]
     console_subscriber::init();
 
-    let opts = Args::parse();
-    if !matches!(
-        opts.chip,
-        Chip::Esp32 | Chip::Esp32c3 | Chip::Esp32s2 | Chip::Esp32s3
-    ) {
-        anyhow::bail!("Chip not supported in Wokwi. See available chips and features at https://docs.wokwi.com/guides/esp32#simulation-features");
+    let command = Command::parse_from(Command::args());
+    let output_json = matches!(&command, Command::Run(opts) if opts.output_json);
+    let code = match run(command).await {
+        Ok(code) => code,
+        Err(e) => {
+            last_session::record(&format!("Error: {:#}", e));
+            // what reads the startup manifest reads the error instead
+            match output_json {
+                true => println!("{}", catalog::to_json(&e)),
+                false => eprintln!("Error: {}", catalog::describe(&e)),
+            }
+            1
+        }
+    };
+    compressed::clean_up();
+    last_session::record(&format!("Exited with code {}", code));
+    if code != 0 {
+        std::process::exit(code);
     }
+}
+
+async fn run(command: Command) -> Result<i32> {
+    Ok(match command {
+        Command::Run(mut opts) => {
+            opts.image.resolve()?;
+            if opts.server.id.is_none() {
+                opts.server.id = opts.image.project_id()?;
+            }
+            opts.image.validate(opts.server.id.is_some())?;
+            simulate(*opts).await?
+        }
+        Command::Batch(args) => batch::run(args).await?,
+        Command::Pack(args) => pack::run(args).await?,
+        Command::Serve(args) => serve::run(args).await?,
+        Command::Doctor(args) => doctor::run(args)?,
+        Command::Init(args) => init::run(args)?,
+        Command::Pull(args) => pull::run(args)?,
+        Command::Push(args) => push::run(args)?,
+        Command::Config(args) => project_settings::run(args)?,
+        Command::Completions(args) => completions::run(args)?,
+        Command::SelfUpdate(args) => update::run(args)?,
+        Command::BugReport(args) => bug_report::run(args)?,
+    })
+}
 
-    if !opts.elf.exists() {
-        anyhow::bail!("Path to elf does not exist");
+/// Serve the simulation until it is stopped, returning the exit code for the server
+async fn simulate(mut opts: Args) -> Result<i32> {
+    // the background server started by `--daemon` keeps its own log
+    let launching_daemon = opts.daemon && !daemon::is_detached();
+    if !opts.no_session_log && !opts.print_urls_only && !launching_daemon {
+        last_session::start();
+    }
+    if let Some(input) = &opts.uart_input {
+        if !input.exists() {
+            catalog::bail!(FileNotFound, "Path to UART input does not exist");
+        }
+    }
+    if let Some(path) = &opts.bootloader_elf {
+        let bytes = std::fs::read(path).map_err(|e| {
+            Failure::new(
+                Code::FileNotFound,
+                format!(
+                    "Failed to read the bootloader elf {}: {}",
+                    path.display(),
+                    e
+                ),
+            )
+        })?;
+        if xmas_elf::ElfFile::new(&bytes).is_err() {
+            catalog::bail!(InvalidElf, "{} isn't an elf file", path.display());
+        }
+        if !image::built_for(&bytes, opts.image.chip) {
+            println!(
+                "Warning: the bootloader elf {} isn't built for the {}, its addresses won't match the simulation",
+                path.display(),
+                opts.image.chip
+            );
+        }
+    }
+
+    if opts.uart_input_rate == Some(0) {
+        anyhow::bail!("UART input rate must be greater than zero");
+    }
+    if opts.cpu_profile_interval == 0 {
+        anyhow::bail!("The profiling interval must be greater than zero");
+    }
+    if opts.open_editor.as_deref().map(str::trim) == Some("") {
+        let editor = panic_trace::editor();
+        opts.open_editor =
+            Some(editor.context("--open-editor needs a command, or $VISUAL or $EDITOR to be set")?);
+    }
+    if opts.heap_stats == Some(0) {
+        anyhow::bail!("The heap statistics interval must be greater than zero");
+    }
+    if opts.benchmark == Some(0) {
+        anyhow::bail!("The number of benchmark runs must be greater than zero");
+    }
+    if opts.ready_marker.as_deref() == Some("") {
+        anyhow::bail!("The ready marker can't be empty");
+    }
+    if let Some(root) = &opts.fio_root {
+        if !root.is_dir() {
+            anyhow::bail!("File-I/O root {} is not a directory", root.display());
+        }
+    }
+    if let Some(peer) = &opts.uart_peer {
+        peer.open()?;
+    }
+    #[cfg(feature = "dynamic-plugins")]
+    for path in &opts.plugin {
+        let (name, types) = wokwi_server::plugins::load(path)?;
+        if !opts.output_json {
+            println!("Loaded plugin {} for {}", name, types.join(", "));
+        }
+    }
+    opts.custom_chips = Arc::new(
+        opts.image
+            .custom_chips(&opts.custom_chip)?
+            .iter()
+            .map(CustomChip::load)
+            .collect::<Result<_>>()?,
+    );
+    if !opts.break_at.is_empty() || opts.gdb_init.is_some() {
+        let elf = std::fs::read(&opts.image.elf)?;
+        let mut script = Vec::new();
+        for target in &opts.break_at {
+            let breakpoint = breakpoints::resolve(&elf, target)?;
+            script.push(gdb_script::Command::hbreak(
+                breakpoint,
+                breakpoints::kind(&elf),
+            ));
+        }
+        if let Some(path) = &opts.gdb_init {
+            let text = std::fs::read_to_string(path)
+                .with_context(|| format!("Failed to read {}", path.display()))?;
+            let commands = gdb_script::parse(&text, &elf)
+                .with_context(|| format!("Invalid GDB script {}", path.display()))?;
+            script.extend(commands);
+        }
+        opts.gdb_script = Arc::new(script);
+    }
+    let config_path = config::path(opts.config.as_deref());
+    let cli_settings = opts.settings();
+    if opts.config.is_some() || config_path.is_file() {
+        opts.use_settings(config::Settings::load(&config_path, &cli_settings)?);
+        if !opts.output_json {
+            println!("Using settings from {}", config_path.display());
+        }
+    }
+
+    let in_container = container::in_container();
+    let bind = opts.server.bind_addr(in_container);
+
+    endpoint::check(&mut opts.server, opts.output_json || opts.print_urls_only).await;
+    if opts.print_urls_only {
+        // the port isn't known until the server binds it, and it may be different next time
+        if opts.server.port == 0 || opts.gdb_port == 0 {
+            anyhow::bail!("--print-urls-only needs fixed ports, not port 0");
+        }
+        println!(
+            "{}",
+            simulation_url(&opts.server, opts.image.chip, opts.server.port)
+        );
+        println!("ws://{}", connect_addr((bind, opts.server.port).into()));
+        println!("gdb: {}", connect_addr((bind, opts.gdb_port).into()));
+        return Ok(0);
     }
 
-    if let Some(bt) = &opts.bootloader {
-        if !bt.exists() {
-            anyhow::bail!("Path to bootloader does not exist");
-        } 
+    if opts.daemon && !daemon::is_detached() {
+        let pid = daemon::spawn_detached(opts.log_file.as_deref())?;
+        println!("wokwi-server is running in the background (pid {})", pid);
+        return Ok(0);
     }
 
-    if let Some(pt) = &opts.partition_table {
-        if !pt.exists() {
-            anyhow::bail!("Path to partition table does not exist");
-        } 
+    if let Some(pidfile) = &opts.pidfile {
+        daemon::write_pidfile(pidfile)?;
+    }
+    let pidfile = opts.pidfile.clone();
+    let stats_json = opts.stats_json.clone();
+    if !opts.no_update_check && !opts.output_json && !opts.daemon {
+        update::notify_in_background();
+    }
+
+    let activated = daemon::activated_sockets();
+    // nobody is around to look at a browser when running as a service
+    let open_browser = !opts.server.no_open
+        && !opts.daemon
+        && (!in_container || browser::command(opts.server.browser.as_deref()).is_some())
+        && activated.wokwi.is_none();
+    let server = listen(activated.wokwi, (bind, opts.server.port), "--port").await?;
+    let gdb_server = listen(activated.gdb, (bind, opts.gdb_port), "--gdb-port").await?;
+    let control_server = match opts.control_port {
+        Some(port) => Some(listen(None, (bind, port), "--control-port").await?),
+        None => None,
+    };
+    let dashboard_server = match opts.dashboard_port {
+        Some(port) => Some(listen(None, (bind, port), "--dashboard-port").await?),
+        None => None,
+    };
+    let sessions = session::Sessions::new(opts.event_log.as_deref(), opts.session_name.clone())?;
+    let trace = WsTrace::create(opts.ws_trace.as_deref())?;
+    let gdb_trace = GdbTrace::create(opts.gdb_trace.as_deref())?;
+
+    // with `--port 0` the OS picked the ports, so the links have to use what was bound
+    let (port, gdb_port) = (server.local_addr()?.port(), gdb_server.local_addr()?.port());
+    if in_container && !opts.output_json {
+        println!(
+            "Running inside a container, listening on {}. Make sure ports {} and {} are published to the host, e.g. `-p {}:{} -p {}:{}`",
+            bind, port, gdb_port, port, port, gdb_port, gdb_port
+        );
+    }
+    let url = simulation_url(&opts.server, opts.image.chip, port);
+    if opts.output_json {
+        let manifest = startup_manifest(
+            &opts,
+            &url,
+            &server,
+            &gdb_server,
+            control_server.as_ref(),
+            dashboard_server.as_ref(),
+        )?;
+        println!("{}", serde_json::to_string(&manifest)?);
+    } else {
+        println!(
+            "Open the following link in the browser\r\n\r\n{}\r\n\r\n",
+            url
+        );
+    }
+    let gdb_addr = connect_addr(gdb_server.local_addr()?);
+    if opts.gdbinit.is_some() || opts.print_gdbinit {
+        let script =
+            debugger::init_script(&opts.image.elf, opts.bootloader_elf.as_deref(), &gdb_addr)?;
+        if let Some(path) = &opts.gdbinit {
+            std::fs::write(path, &script)
+                .with_context(|| format!("Failed to write {}", path.display()))?;
+        }
+        if opts.print_gdbinit {
+            println!("GDB commands for this session:\r\n\r\n{}", script);
+        }
+    }
+
+    if opts.copy_url {
+        match browser::copy_to_clipboard(&url) {
+            Ok(_) => println!("Copied the link to the clipboard."),
+            Err(e) => println!("Failed to copy the link to the clipboard: {}", e),
+        }
+    }
+    if open_browser {
+        browser::open(&url, opts.server.browser.as_deref());
+    }
+
+    let (broker, routing) = Broker::new(opts.gdb_queue);
+
+    let shutdown = CancellationToken::new();
+    let (started_send, started_recv) = watch::channel(false);
+    let (exit_send, mut exit_recv) = tokio::sync::mpsc::channel(1);
+    let (control_send, control_recv) = tokio::sync::mpsc::channel(1);
+
+    let sink_options = SinkOptions {
+        display: opts.uart_display,
+        log_filter: opts.log_filter.clone(),
+    };
+    let mut sinks = Sinks::default();
+    sinks.configure(&sink_specs(&opts.uart_sink, opts.tui()), &sink_options)?;
+    for tap in &opts.uart_tap {
+        sinks.open_extra(tap, &sink_options)?;
+    }
+    if let Some(path) = &opts.uart_capture {
+        sinks.set_capture(capture::Capture::create(path, opts.uart_capture_baud)?);
+        println!("Capturing UART traffic to {}", path.display());
     }
 
-    let (wsend, wrecv) = tokio::sync::mpsc::channel(1);
-    let (gsend, grecv) = tokio::sync::mpsc::channel(1);
+    if opts.tui() || dashboard_server.is_some() {
+        sinks.add("activity", activity::uart_sink(&sink_options));
+    }
 
     let mut set = JoinSet::new();
-    set.spawn(wokwi_task(opts, gsend, wrecv));
-    set.spawn(gdb_task(wsend, grecv));
+    set.spawn(routing.run(shutdown.clone()));
+    set.spawn(repeats::flush_task(shutdown.clone()));
+    #[cfg(all(feature = "tui", unix))]
+    if opts.tui {
+        set.spawn(tui::run(
+            url.clone(),
+            sessions.clone(),
+            control_send.clone(),
+            exit_send.clone(),
+            shutdown.clone(),
+        ));
+    }
+    // Ctrl-C is meant for GDB to interrupt the target while it runs
+    let (gdb_running_send, gdb_running) = watch::channel(false);
+    if let Some(gdb) = opts.gdb.clone() {
+        set.spawn(debugger::launch(
+            gdb,
+            opts.gdb_args.clone(),
+            opts.image.elf.clone(),
+            opts.bootloader_elf.clone(),
+            gdb_addr,
+            started_recv,
+            exit_send.clone(),
+            gdb_running_send,
+        ));
+    }
+    if let Some(dashboard_server) = dashboard_server {
+        if !opts.output_json {
+            println!(
+                "Dashboard available at http://{}",
+                connect_addr(dashboard_server.local_addr()?)
+            );
+        }
+        set.spawn(dashboard::dashboard_task(
+            dashboard_server,
+            url.clone(),
+            sessions.clone(),
+            control_send.clone(),
+            shutdown.clone(),
+        ));
+    }
+    if let Some(control_server) = control_server {
+        set.spawn(control::control_task(
+            control_server,
+            sessions.clone(),
+            control_send,
+            shutdown.clone(),
+        ));
+    }
+    let (config_send, config_recv) = tokio::sync::mpsc::channel(1);
+    set.spawn(config::watch(
+        config_path,
+        cli_settings,
+        opts.settings(),
+        config_send,
+        shutdown.clone(),
+    ));
+    set.spawn(wokwi_task(
+        opts,
+        SimulatorPort::serve(server)?,
+        Links {
+            broker: broker.clone(),
+            control: control_recv,
+            config: config_recv,
+            started: started_send,
+            exit: exit_send,
+            trace,
+            gdb_trace: gdb_trace.clone(),
+        },
+        sinks,
+        sessions.clone(),
+        shutdown.clone(),
+    ));
+    set.spawn(gdb_task(
+        gdb_server,
+        broker,
+        gdb_trace,
+        sessions.clone(),
+        shutdown.clone(),
+    ));
 
+    let mut exit_code = 0;
     loop {
         tokio::select! {
-            _ = signal::ctrl_c() => {
-                set.shutdown().await;
+            signal = shutdown_signal() => {
+                if signal == Signal::Interrupt && *gdb_running.borrow() {
+                    continue;
+                }
+                graceful_shutdown(&mut set, &shutdown).await;
                 break;
             },
+            Some(code) = exit_recv.recv() => {
+                exit_code = code;
+                graceful_shutdown(&mut set, &shutdown).await;
+                break;
+            }
+            _ = shutdown.cancelled() => { /* asked for over the control API */
+                graceful_shutdown(&mut set, &shutdown).await;
+                break;
+            }
             task = set.join_next() => {
                 match task {
                     Some(Err(join_error)) => {
                         println!("Task failed: {:?}", join_error);
+                        exit_code = 1;
                         set.shutdown().await;
                         break;
                     }
                     Some(Ok(Err(task_error))) => {
                         println!("Task failed: {:?}", task_error);
+                        exit_code = 1;
                         set.shutdown().await;
                         break;
                     }
@@ -107,179 +1142,993 @@ async fn main() -> Result<(), anyhow::Error> {
             }
         }
     }
-    Ok(())
+
+    repeats::flush();
+    let stats = sessions.stats();
+    stats.print();
+    if let Some(path) = stats_json {
+        if let Err(e) = stats.write(&path) {
+            println!("{:#}", e);
+        }
+    }
+
+    if let Some(pidfile) = pidfile {
+        std::fs::remove_file(pidfile).ok();
+    }
+    Ok(exit_code)
 }
 
-async fn wokwi_task(
-    opts: Args,
-    mut send: Sender<String>,
-    mut recv: Receiver<GdbInstruction>,
-) -> Result<()> {
-    let server = TcpListener::bind(("127.0.0.1", PORT))
+/// Ask all tasks to stop, falling back to aborting them if they take too long
+async fn graceful_shutdown(set: &mut JoinSet<Result<()>>, shutdown: &CancellationToken) {
+    println!("Shutting down...");
+    shutdown.cancel();
+    // give the tasks a chance to notify their clients
+    let graceful = async { while set.join_next().await.is_some() {} };
+    if tokio::time::timeout(SHUTDOWN_TIMEOUT, graceful)
         .await
-        .with_context(|| format!("Failed to listen on 127.0.0.1:{}", PORT))?;
+        .is_err()
+    {
+        set.shutdown().await;
+    }
+}
 
-    let project_id = match opts.id.clone() {
-        Some(id) => id,
-        None => match opts.chip {
-            Chip::Esp32 => "338154815612781140".to_string(),
-            Chip::Esp32s2 => "338154940543271506".to_string(),
-            Chip::Esp32c3 => "338322025101656660".to_string(),
-            Chip::Esp32s3 => "345144250522927698".to_string(),
-            _ => unreachable!(),
+/// Use the socket handed to us by the service manager, or bind a new one. `flag` is the option
+/// choosing the port, suggested when it is taken
+async fn listen(
+    activated: Option<std::net::TcpListener>,
+    addr: (IpAddr, u16),
+    flag: &str,
+) -> Result<TcpListener> {
+    match activated {
+        Some(listener) => {
+            listener.set_nonblocking(true)?;
+            Ok(TcpListener::from_std(listener)?)
+        }
+        None => match TcpListener::bind(addr).await {
+            Err(e) if e.kind() == std::io::ErrorKind::AddrInUse => {
+                let owner = port_owner::find(addr.1)
+                    .map(|owner| format!(" by {}", owner))
+                    .unwrap_or_default();
+                catalog::bail!(
+                    PortInUse,
+                    "Port {} is in use{}, choose another with {}",
+                    addr.1,
+                    owner,
+                    flag
+                )
+            }
+            result => result.with_context(|| format!("Failed to listen on {}:{}", addr.0, addr.1)),
         },
-    };
+    }
+}
 
+fn project_id(opts: &ServerArgs, chip: Chip) -> String {
+    match opts.id.clone() {
+        Some(id) => id,
+        None => chips::lookup(chip)
+            .map(|info| info.project_id.to_string())
+            .unwrap_or_default(),
+    }
+}
+
+/// The link to the simulation for `chip`, connecting to the server on `port`
+fn simulation_url(opts: &ServerArgs, chip: Chip, port: u16) -> String {
     let mut url = format!(
-        "https://wokwi.com/_alpha/wembed/{}?partner=espressif&port={}&data=demo",
-        project_id, PORT
+        "{}{}?partner=espressif&port={}&data=demo",
+        opts.wokwi_url_base,
+        project_id(opts, chip),
+        port
     );
 
     if let Some(h) = opts.host.as_ref() {
         url.push_str(&format!("&_host={}", h))
     }
 
-    println!(
-        "Open the following link in the browser\r\n\r\n{}\r\n\r\n",
-        url
-    );
-    opener::open_browser(url).ok(); // we don't care if this fails
+    if !opts.embed_param.is_empty() {
+        url.push('&');
+        url.push_str(
+            &url::form_urlencoded::Serializer::new(String::new())
+                .extend_pairs(&opts.embed_param)
+                .finish(),
+        );
+    }
+
+    url
+}
+
+pub(crate) fn parse_url_base(s: &str) -> Result<url::Url, String> {
+    let mut url = url::Url::parse(s).map_err(|e| e.to_string())?;
+    if !matches!(url.scheme(), "http" | "https") {
+        return Err(format!("expected an http or https url, found '{}'", s));
+    }
+    if url.query().is_some() || url.fragment().is_some() {
+        return Err("query parameters go in --embed-param".to_string());
+    }
+    // the project id is a path segment below the base
+    if !url.path().ends_with('/') {
+        let path = format!("{}/", url.path());
+        url.set_path(&path);
+    }
+    Ok(url)
+}
+
+fn parse_seed(s: &str) -> Result<u64, String> {
+    use std::hash::{BuildHasher, Hasher};
+    match s {
+        // std seeds its hashers randomly, which is all the randomness needed here
+        "random" => Ok(std::collections::hash_map::RandomState::new()
+            .build_hasher()
+            .finish()),
+        _ => s
+            .parse()
+            .map_err(|_| "expected a number, or `random`".to_owned()),
+    }
+}
+
+fn parse_speed(s: &str) -> Result<f64, String> {
+    match s.parse::<f64>() {
+        Ok(speed) if speed.is_finite() && speed > 0.0 => Ok(speed),
+        _ => Err("expected a positive number, e.g. `2` or `0.5`".to_owned()),
+    }
+}
+
+fn parse_embed_param(s: &str) -> Result<(String, String), String> {
+    match s.split_once('=') {
+        Some(("", _)) => Err("parameter name must not be empty".to_string()),
+        Some((key, value)) => Ok((key.to_string(), value.to_string())),
+        None => Err(format!("expected key=value, found '{}'", s)),
+    }
+}
 
+/// The address clients should use to reach a listening socket
+fn connect_addr(addr: SocketAddr) -> String {
+    if addr.ip().is_unspecified() {
+        format!("localhost:{}", addr.port())
+    } else {
+        addr.to_string()
+    }
+}
+
+/// Describe the running server for wrapper scripts and IDE integrations
+fn startup_manifest(
+    opts: &Args,
+    url: &str,
+    server: &TcpListener,
+    gdb_server: &TcpListener,
+    control_server: Option<&TcpListener>,
+    dashboard_server: Option<&TcpListener>,
+) -> Result<Value> {
+    let server_addr = connect_addr(server.local_addr()?);
+    let gdb_addr = connect_addr(gdb_server.local_addr()?);
+    let bytes = std::fs::read(&opts.image.elf)?;
+    let elf = xmas_elf::ElfFile::new(&bytes)
+        .map_err(|e| Failure::new(Code::InvalidElf, format!("Invalid elf file: {}", e)))?;
+
+    let control = control_server
+        .map(|s| s.local_addr().map(connect_addr))
+        .transpose()?;
+    let dashboard = dashboard_server
+        .map(|s| {
+            s.local_addr()
+                .map(|addr| format!("http://{}", connect_addr(addr)))
+        })
+        .transpose()?;
+
+    Ok(json!({
+        "version": env!("CARGO_PKG_VERSION"),
+        "url": url,
+        "websocket": format!("ws://{}", server_addr),
+        "port": server.local_addr()?.port(),
+        "gdb": gdb_addr,
+        "gdb_port": gdb_server.local_addr()?.port(),
+        "control": control,
+        "dashboard": dashboard,
+        "project_id": project_id(&opts.server, opts.image.chip),
+        "chip": opts.image.chip.to_string(),
+        "firmware": {
+            "elf": std::fs::canonicalize(&opts.image.elf)?,
+            "size": bytes.len(),
+            "entry": format!("{:#x}", elf.header.pt2.entry_point()),
+        },
+    }))
+}
+
+/// A signal asking the server to stop
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Signal {
+    /// Ctrl-C
+    Interrupt,
+    /// a service manager asking us to stop
+    Terminate,
+}
+
+/// Resolves on Ctrl-C, or when a service manager asks us to stop
+async fn shutdown_signal() -> Signal {
+    #[cfg(unix)]
+    {
+        let mut term = signal::unix::signal(signal::unix::SignalKind::terminate())
+            .expect("Failed to install SIGTERM handler");
+        tokio::select! {
+            _ = signal::ctrl_c() => Signal::Interrupt,
+            _ = term.recv() => Signal::Terminate,
+        }
+    }
+    #[cfg(not(unix))]
+    {
+        signal::ctrl_c().await.ok();
+        Signal::Interrupt
+    }
+}
+
+/// Channels connecting the simulation to the rest of the server
+struct Links {
+    /// routes packets to and from the GDB client
+    broker: Broker,
+    /// requests from the control API
+    control: Receiver<ControlRequest>,
+    /// settings from the config file, whenever it changes
+    config: Receiver<config::Settings>,
+    /// set once the simulation has been started
+    started: watch::Sender<bool>,
+    /// exit codes requested by the firmware
+    exit: Sender<i32>,
+    /// records the websocket messages of every simulator
+    trace: WsTrace,
+    /// records the GDB packets to and from the simulators' GDB stubs
+    gdb_trace: GdbTrace,
+}
+
+async fn wokwi_task(
+    mut opts: Args,
+    mut server: SimulatorPort,
+    mut links: Links,
+    mut sinks: Sinks,
+    sessions: Sessions,
+    shutdown: CancellationToken,
+) -> Result<()> {
+    let mut errors = 0;
+    // a start packet which couldn't be sent, to send again when the simulator reconnects
+    let mut resend = None;
+    let mut retries = 0;
     loop {
-        let (stream, _) = server.accept().await?;
-        process(opts.clone(), stream, (&mut send, &mut recv)).await?;
+        let accepted = tokio::select! {
+            accepted = server.accept() => accepted,
+            Some(request) = links.control.recv() => {
+                request.reject("No simulator is connected");
+                continue;
+            }
+            Some(settings) = links.config.recv() => {
+                if let Ok(changes) = reload_config(&mut opts, &mut sinks, settings) {
+                    sessions.event("server", "config-reloaded", json!({ "changed": changes }));
+                }
+                continue;
+            }
+            _ = shutdown.cancelled() => return Ok(()),
+        };
+        let result = match accepted {
+            Ok((stream, peer)) => {
+                let session = sessions.open(Kind::Simulator, peer);
+                println!("[{}] Simulation client connected from {}", session.id, peer);
+                let result = process(
+                    &mut opts,
+                    stream,
+                    &mut links,
+                    &mut sinks,
+                    &shutdown,
+                    &session,
+                    resend.take(),
+                )
+                .await;
+                match &result {
+                    Ok(_) => println!("[{}] Simulation client disconnected.", session.id),
+                    Err(e) => {
+                        println!(
+                            "[{}] Simulation connection failed: {}",
+                            session.id,
+                            catalog::describe(e)
+                        )
+                    }
+                }
+                session.close(&result);
+                result
+            }
+            Err(e) => {
+                println!("Simulation connection failed: {}", catalog::describe(&e));
+                Err(e)
+            }
+        };
+
+        match result {
+            Ok(_) => {
+                errors = 0;
+                retries = 0;
+            }
+            Err(e) => {
+                // the firmware won't match on the next connection either
+                if catalog::code(&e) == Some(Code::ChecksumMismatch) {
+                    return Err(e);
+                }
+                if let Some(StartNotSent(simdata)) = e.downcast_ref() {
+                    if retries < opts.start_retries {
+                        retries += 1;
+                        println!(
+                            "The firmware will be sent again when the simulator reconnects (retry {} of {})",
+                            retries, opts.start_retries
+                        );
+                        resend = Some(simdata.clone());
+                        continue;
+                    }
+                }
+                retries = 0;
+                errors += 1;
+                if matches!(opts.max_errors, Some(max) if errors > max) {
+                    catalog::bail!(
+                        TooManyErrors,
+                        "Giving up after {} consecutive errors",
+                        errors
+                    );
+                }
+                // back off exponentially so a persistently failing client doesn't spin
+                let backoff = RETRY_BACKOFF * 2u32.pow(errors.min(6) - 1);
+                let sleep = tokio::time::sleep(backoff.min(MAX_RETRY_BACKOFF));
+                if until_shutdown(&shutdown, sleep).await.is_none() {
+                    return Ok(());
+                }
+            }
+        }
     }
 }
 
+/// The start packet couldn't be sent because the connection failed part way through
+#[derive(Debug)]
+struct StartNotSent(Arc<SimulationPacket>);
+
+impl std::fmt::Display for StartNotSent {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "The connection failed while sending the firmware")
+    }
+}
+
+/// Serve one simulator connection. `resend` is a start packet which a previous connection
+/// failed to send, sent instead of building the image again
 async fn process(
-    opts: Args,
-    stream: TcpStream,
-    (send, recv): (&mut Sender<String>, &mut Receiver<GdbInstruction>),
+    opts: &mut Args,
+    stream: SimulatorStream,
+    links: &mut Links,
+    sinks: &mut Sinks,
+    shutdown: &CancellationToken,
+    session: &Session,
+    resend: Option<Arc<SimulationPacket>>,
 ) -> Result<()> {
-    let websocket = accept_async(stream).await?;
-    let (mut outgoing, mut incoming) = websocket.split();
-    let msg = incoming.next().await; // await for hello message
-    println!("Client connected: {:?}", msg);
-
-    let bytes = tokio::fs::read(&opts.elf).await?;
-    let elf = xmas_elf::ElfFile::new(&bytes).expect("Invalid elf file");
-    let firmware = ElfFirmwareImage::new(elf);
-
-    let p = if let Some(p) = &opts.partition_table {
-        Some(PartitionTable::try_from_str(String::from_utf8_lossy(
-            &tokio::fs::read(p).await?,
-        ))?)
-    } else {
-        None
+    let handshake = tokio::time::timeout(
+        HANDSHAKE_TIMEOUT,
+        accept_async_with_config(stream, Some(protocol::websocket_config())),
+    );
+    let Some(websocket) = until_shutdown(shutdown, handshake).await else {
+        return Ok(());
     };
+    let websocket = websocket.context("Timed out during websocket handshake")??;
+    let (outgoing, mut incoming) = websocket.split();
+    let mut outgoing = TracedSink::new(
+        outgoing,
+        links.trace.clone(),
+        links.gdb_trace.clone(),
+        &session.id,
+    );
+    let hello = tokio::time::timeout(HANDSHAKE_TIMEOUT, incoming.next()); // await for hello message
+    let Some(msg) = until_shutdown(shutdown, hello).await else {
+        return going_away(&mut outgoing).await;
+    };
+    let msg = msg
+        .context("Timed out waiting for hello message")?
+        .ok_or_else(|| anyhow::anyhow!("Simulator disconnected before sending hello message"))??;
+    links.trace.record(&session.id, Direction::In, &msg);
+    let capabilities =
+        accept_hello(&mut outgoing, &msg, session, opts.server.embed_protocol).await?;
+    let controls_time = capabilities.time_control;
 
-    let b = if let Some(b) = &opts.bootloader {
-        Some(tokio::fs::read(b).await?)
-    } else {
-        None
+    let transfer_started = Instant::now();
+    let keepalive = Keepalive::new(opts.server.keepalive);
+    let simdata = match resend {
+        Some(simdata) => {
+            println!("[{}] Sending the firmware again", session.id);
+            simdata
+        }
+        None => {
+            transfer(session, "Building image from", &opts.image.elf);
+            let building = keepalive.during(&mut outgoing, opts.start_packet(&session.id));
+            let Some(simdata) = until_shutdown(shutdown, building).await else {
+                return going_away(&mut outgoing).await;
+            };
+            Arc::new(simdata??)
+        }
     };
 
-    // TODO allow setting flash params, or take from bootloader?
-    let image = opts
-        .chip
-        .get_flash_image(&firmware, b, p, None, None, None, None, None)?;
-    let parts: Vec<_> = image.flash_segments().collect();
-
-    let bootloader = &parts[0];
-    let partition_table = &parts[1];
-    let app = &parts[2];
-
-    let simdata = SimulationPacket {
-        r#type: "start".to_owned(),
-        elf: base64::encode(&bytes),
-        esp_bin: vec![
-            vec![
-                Value::Number(bootloader.addr.into()),
-                Value::String(base64::encode(&bootloader.data)),
-            ],
-            vec![
-                Value::Number(partition_table.addr.into()),
-                Value::String(base64::encode(&partition_table.data)),
-            ],
-            vec![
-                Value::Number(app.addr.into()),
-                Value::String(base64::encode(&app.data)),
-            ],
-        ],
+    let mut router = handlers::router();
+    let loads_chips = capabilities.custom_chips;
+    let reports_events = capabilities.peripheral_events;
+    let takes_seed = capabilities.seed;
+    router.outbox.negotiated(capabilities);
+    let mut run = Run {
+        keep_uart: opts.report.is_some() || opts.artifacts_dir.is_some(),
+        exit_marker: opts.exit_marker.clone().map(exit_marker::ExitMarker::new),
+        boot_hints: (!opts.no_boot_hints).then(|| BootHints::new(opts.image.chip)),
+        expectations: opts.expectations(),
+        deadline: opts
+            .timeout
+            .map(|secs| tokio::time::Instant::now() + Duration::from_secs(secs)),
+        running_for: None,
+        log: RunLog::new(),
+        payload: simdata.clone(),
+        peer: None,
+        bridges: Bridges::default(),
+        target_xml: opts.target_xml()?,
+        qsupported_sent: false,
+        freertos: opts.freertos(),
+        thread_walk: None,
+        tasks: Vec::new(),
+        watchpoints: Capabilities::for_chip(opts.image.chip).map(Watchpoints::new),
+        file_io: opts.fio_root.as_deref().map(FileIo::new).transpose()?,
+        core_dump: opts.core_dump.clone(),
+        crashes: CrashWatch::default(),
+        capture: None,
+        heap: opts.heap(),
+        heap_poll: opts
+            .heap_stats
+            .map(|secs| tokio::time::Instant::now() + Duration::from_secs(secs)),
+        heap_walk: None,
+        heap_baseline: None,
+        halted: false,
+        halting_at_boot: false,
+        gdb_script: None,
+        profiler: opts.cpu_profile.as_deref().map(|path| {
+            let interval = Duration::from_millis(opts.cpu_profile_interval);
+            Profiler::new(path, interval, &opts.image.elf, &session.id)
+        }),
+        profile_poll: opts.cpu_profile.is_some().then(|| {
+            tokio::time::Instant::now() + Duration::from_millis(opts.cpu_profile_interval)
+        }),
+        sample: None,
+        benchmark: opts
+            .benchmark
+            .map(|runs| Benchmark::new(runs, opts.ready_marker.clone(), simdata.clone())),
+        waveform: opts.vcd.as_deref().map(|path| {
+            let module = opts.image.chip.to_string().to_lowercase().replace('-', "_");
+            Waveform::new(path, &module, &session.id)
+        }),
+        panic_trace: (!opts.no_backtrace_decode).then(|| {
+            PanicTrace::new(
+                &opts.image.elf,
+                opts.bootloader_elf.as_deref(),
+                opts.source_context,
+                opts.open_editor.clone(),
+            )
+        }),
+        opts,
+        session,
+        sinks,
+        exit: &links.exit,
     };
 
+    // GDB packets sent before the simulation started are held until now
+    let mut gdb = links.broker.attach_simulator(&session.id);
+
+    // the chips have to be there before the firmware talks to them
+    let chips = &run.opts.custom_chips;
+    if !chips.is_empty() {
+        let names: Vec<_> = chips.iter().map(|chip| chip.name.as_str()).collect();
+        if loads_chips {
+            println!("[{}] Sending custom chips {}", session.id, names.join(", "));
+            router
+                .outbox
+                .send_to_simulator(&custom_chips::message(chips))?;
+        } else {
+            println!(
+                "[{}] Warning: the simulator can't load custom chips, leaving out {}",
+                session.id,
+                names.join(", ")
+            );
+        }
+    }
+
+    if let Some(seed) = run.opts.sim_seed {
+        session.count(|stats| stats.seed = Some(seed));
+        if takes_seed {
+            println!(
+                "[{}] Seeding the simulation with {}, reproduce it with --sim-seed {}",
+                session.id, seed, seed
+            );
+            router
+                .outbox
+                .send_to_simulator(&json!({ "type": "seed", "seed": seed }))?;
+        } else {
+            println!(
+                "[{}] Warning: the simulator can't be seeded, this run may not be reproducible with --sim-seed {}",
+                session.id, seed
+            );
+        }
+    }
+
+    let wanted: Vec<_> = [
+        ("i2c", "--i2c-log", run.opts.i2c_log),
+        ("spi", "--spi-log", run.opts.spi_log),
+        ("gpio", "--vcd", run.opts.vcd.is_some()),
+    ]
+    .into_iter()
+    .filter(|(_, _, wanted)| *wanted)
+    .collect();
+    if !wanted.is_empty() {
+        if reports_events {
+            let events: Vec<_> = wanted.iter().map(|(event, _, _)| *event).collect();
+            router.outbox.send_to_simulator(&json!({
+                "type": "subscribe",
+                "events": events,
+            }))?;
+        } else {
+            let flags: Vec<_> = wanted.iter().map(|(_, flag, _)| *flag).collect();
+            println!(
+                "[{}] Warning: the simulator doesn't report peripheral events, there is nothing to record for {}",
+                session.id,
+                flags.join(" or ")
+            );
+        }
+    }
+
     // send the simulation data
-    outgoing
-        .send(tungstenite::Message::Text(serde_json::to_string(&simdata)?))
-        .await?;
+    run.log.segments(&simdata);
+    if let Err(e) = router.outbox.send_to_simulator(&*simdata) {
+        outgoing
+            .send(tungstenite::Message::Close(Some(CloseFrame {
+                code: CloseCode::Size,
+                reason: "firmware is too large for this client".into(),
+            })))
+            .await
+            .ok();
+        return Err(anyhow::Error::from(e).context(Failure::new(
+            Code::MessageTooLarge,
+            format!(
+                "Failed to send {} to the simulator, --strip-elf makes the message smaller",
+                run.opts.image.elf.display()
+            ),
+        )));
+    }
+    if let Some(speed) = run.opts.sim_speed {
+        if controls_time {
+            println!("[{}] Running the simulation at {}x speed", session.id, speed);
+            router
+                .outbox
+                .send_to_simulator(&json!({ "type": "simSpeed", "speed": speed }))?;
+        } else {
+            println!(
+                "[{}] Warning: the simulator can't change its speed, ignoring --sim-speed {}",
+                session.id, speed
+            );
+        }
+    }
+    if !run.opts.gdb_script.is_empty() {
+        // stopped by the interrupt the script's commands are run after
+        let (runner, packets) =
+            Runner::start(run.opts.gdb_script.to_vec(), !run.opts.halt_on_attach);
+        router
+            .outbox
+            .send_to_simulator(&json!({ "type": "gdbBreak" }))?;
+        for packet in packets {
+            router
+                .outbox
+                .send_to_simulator(&json!({ "type": "gdb", "message": packet }))?;
+        }
+        run.gdb_script = Some(runner);
+    } else if run.opts.halt_on_attach {
+        // sent along with the start packet, so the firmware doesn't get far
+        router
+            .outbox
+            .send_to_simulator(&json!({ "type": "gdbBreak" }))?;
+        run.halting_at_boot = true;
+    }
+    transfer(session, "Sending", &run.opts.image.elf);
+    let sent = deliver(
+        &mut router.outbox,
+        &mut outgoing,
+        &mut run.log,
+        &gdb,
+        keepalive,
+    );
+    match until_shutdown(shutdown, sent).await {
+        Some(sent) => sent.context(StartNotSent(simdata))?,
+        None => return going_away(&mut outgoing).await,
+    }
+    if let Some(benchmark) = &mut run.benchmark {
+        benchmark.delivered();
+    }
+    transfer(session, "Running", &run.opts.image.elf);
+    session.count(|stats| stats.transfer_ms += stats::millis(transfer_started.elapsed()));
+    run.sinks.firmware_started(&run.opts.image.elf);
+    links.started.send(true).ok();
+    let firmware = firmware_info(session, &run.opts.image.elf).await;
+    session.event(
+        "started",
+        json!({
+            "elf": run.opts.image.elf,
+            "chip": run.opts.image.chip.to_string(),
+            "firmware": firmware,
+            "seed": run.opts.sim_seed,
+            "speed": run.opts.sim_speed,
+        }),
+    );
+
+    let mut uart_input = match &run.opts.uart_input {
+        Some(path) => uart_input::spawn(
+            path.clone(),
+            uart_input::Pacing {
+                rate: run.opts.uart_input_rate,
+                line_delay: run.opts.uart_input_line_delay.map(Duration::from_millis),
+                start_delay: run.opts.uart_input_delay.map(Duration::from_millis),
+            },
+        ),
+        None => tokio::sync::mpsc::channel(1).1,
+    };
+    let mut peer_replies = start_peer(&mut run)?;
+    let mut chip_replies = start_bridges(&mut run)?;
 
     loop {
         tokio::select! {
-            Some(msg) = incoming.next() => {
-                let msg = msg?;
+            msg = incoming.next() => {
+                let msg = match msg {
+                    Some(msg) => msg?,
+                    None => return Ok(()), /* client went away */
+                };
+                links.trace.record(&session.id, Direction::In, &msg);
+                links.gdb_trace.record_message(&session.id, &msg);
+                if msg.is_close() {
+                    run.sinks.flush();
+                    return Ok(());
+                }
                 if msg.is_text() {
-                    let v: Value = serde_json::from_str(msg.to_text()?)?;
-                    match &v["type"] {
-                        Value::String(s) if s == "uartData" => {
-                            if let Value::Array(bytes) = &v["bytes"] {
-                                let bytes: Vec<u8> =
-                                    bytes.iter().map(|v| v.as_u64().unwrap() as u8).collect();
-                                tokio::io::stdout().write_all(&bytes).await?;
-                            }
+                    let text = msg.to_text()?;
+                    run.log.received(text);
+                    match serde_json::from_str::<Value>(text) {
+                        Ok(v) => match router.dispatch(&mut run, &v) {
+                            Ok(true) => {}
+                            Ok(false) => repeats::report(&session.id, format_args!("Ignoring unexpected message from simulator: {}", v)),
+                            Err(e) => repeats::report(&session.id, format_args!("Skipping malformed message from simulator: {:#}", e)),
+                        },
+                        Err(e) => repeats::report(&session.id, format_args!("Skipping malformed message from simulator: {}", e)),
+                    }
+                }
+            },
+            Some(bytes) = uart_input.recv() => {
+                run.sinks.input(&bytes);
+                router.outbox.send_to_simulator(&json!({
+                    "type": "uartData",
+                    "bytes": bytes
+                }))?;
+            }
+            Some(message) = chip_replies.recv() => {
+                router.outbox.send_to_simulator(&message)?;
+            }
+            Some(bytes) = peer_replies.recv() => {
+                run.sinks.input(&bytes);
+                router.outbox.send_to_simulator(&json!({
+                    "type": "uartData",
+                    "bytes": bytes
+                }))?;
+            }
+            Some(settings) = links.config.recv() => {
+                if let Ok(changes) = reload_config(run.opts, run.sinks, settings) {
+                    if changes.contains(&"expect") || changes.contains(&"fail_on") {
+                        // output already seen isn't checked again
+                        run.expectations = run.opts.expectations();
+                    }
+                    session.event("config-reloaded", json!({ "changed": changes }));
+                }
+            }
+            Some(request) = links.control.recv() => {
+                let (elf, reply) = match request {
+                    ControlRequest::LoadFirmware { elf, reply } => (elf, reply),
+                    ControlRequest::Restart { reply } => (run.opts.image.elf.clone(), reply),
+                    ControlRequest::RunFor { ms, reply } => {
+                        handlers::run_for(&mut run, controls_time, ms, reply, &mut router.outbox)?;
+                        deliver(&mut router.outbox, &mut outgoing, &mut run.log, &gdb, keepalive).await?;
+                        continue;
+                    }
+                };
+                let mut next = run.opts.clone();
+                if next.image.elf != elf {
+                    next.payload = None;
+                    next.image.elf = compressed::decompressed(&elf).unwrap_or(elf);
+                }
+                let transfer_started = Instant::now();
+                transfer(session, "Building image from", &next.image.elf);
+                let building = keepalive.during(&mut outgoing, next.start_packet(&session.id));
+                let Some(built) = until_shutdown(shutdown, building).await else {
+                    reply.send(Err(anyhow::anyhow!("The server is shutting down"))).ok();
+                    continue; /* the shutdown is handled on the next iteration */
+                };
+                let built = built?.and_then(|simdata| {
+                    router.outbox.send_to_simulator(&simdata)?;
+                    Ok(simdata)
+                });
+                session.count(|stats| stats.transfer_ms += stats::millis(transfer_started.elapsed()));
+                match built {
+                    Ok(simdata) => {
+                        run.log.segments(&simdata);
+                        run.payload = Arc::new(simdata);
+                        run.sinks.firmware_started(&next.image.elf);
+                        if run.boot_hints.is_some() {
+                            run.boot_hints = Some(BootHints::new(next.image.chip));
+                        }
+                        transfer(session, "Running", &next.image.elf);
+                        println!("[{}] Loaded firmware {}", session.id, next.image.elf.display());
+                        let firmware = firmware_info(session, &next.image.elf).await;
+                        session.event("firmware-loaded", json!({ "elf": next.image.elf, "firmware": firmware }));
+                        *run.opts = next;
+                        run.freertos = run.opts.freertos();
+                        run.tasks.clear();
+                        run.heap = run.opts.heap();
+                        run.heap_baseline = None;
+                        run.halted = false;
+                        if let Some(profiler) = &mut run.profiler {
+                            profiler.load(&run.opts.image.elf);
                         }
-                        Value::String(s) if s == "gdbResponse" => {
-                            let s = v["response"].as_str().unwrap();
-                            send.send(s.to_owned()).await?;
+                        if let Some(trace) = &mut run.panic_trace {
+                            trace.load(&run.opts.image.elf);
                         }
-                        _ => unreachable!(),
+                        peer_replies = start_peer(&mut run)?;
+                        chip_replies = start_bridges(&mut run)?;
+                        reply.send(Ok(())).ok();
+                    }
+                    Err(e) => {
+                        transfer(session, "Failed to build image from", &next.image.elf);
+                        reply.send(Err(e)).ok();
                     }
                 }
-            },
-            Some(command) = recv.recv() => {
+            }
+            // the server's own reads stop the firmware and continue it, which the client's
+            // packets mustn't land in the middle of
+            Some(command) = gdb.commands.recv(), if !run.reading_memory() => {
                 match command {
-                    GdbInstruction::Command(s) => {
-                        outgoing
-                            .send(tungstenite::Message::Text(serde_json::to_string(
-                                &json!({
-                                    "type": "gdb",
-                                    "message": s
-                                }))?
-                            )).await?;
-                    },
-                    GdbInstruction::Break => {
-                        outgoing
-                            .send(tungstenite::Message::Text(serde_json::to_string(
-                                &json!({
-                                    "type": "gdbBreak"
-                                }))?
-                            )).await?;
-                    },
+                    GdbInstruction::Command(command) => handlers::gdb_command(&mut run, &command, &mut router.outbox)?,
+                    GdbInstruction::Break => handlers::gdb_break(&mut run, &mut router.outbox)?,
                 }
             }
+            _ = sleep_until(run.deadline), if run.deadline.is_some() => {
+                let outcome = match run.expectations.take() {
+                    Some(expectations) => expectations.timed_out(),
+                    None => Outcome::Passed,
+                };
+                run.finish(outcome);
+            }
+            _ = sleep_until(run.heap_poll), if run.heap_poll.is_some() => {
+                handlers::poll_heap(&mut run, &mut router.outbox)?;
+            }
+            _ = sleep_until(run.profile_poll), if run.profile_poll.is_some() => {
+                handlers::sample_profile(&mut run, &mut router.outbox)?;
+            }
+            _ = tokio::time::sleep(UART_IDLE), if run.sinks.has_pending() => {
+                run.sinks.flush();
+            }
+            _ = shutdown.cancelled() => {
+                run.sinks.flush();
+                return going_away(&mut outgoing).await;
+            }
+        }
+        deliver(
+            &mut router.outbox,
+            &mut outgoing,
+            &mut run.log,
+            &gdb,
+            keepalive,
+        )
+        .await?;
+        if let Some(benchmark) = &mut run.benchmark {
+            benchmark.delivered();
         }
     }
 }
 
-async fn gdb_task(mut send: Sender<GdbInstruction>, mut recv: Receiver<String>) -> Result<()> {
-    let server = TcpListener::bind(("127.0.0.1", GDB_PORT)).await?;
+/// Apply a change to the config file, reporting what changed or why it couldn't be applied
+fn reload_config(
+    opts: &mut Args,
+    sinks: &mut Sinks,
+    settings: config::Settings,
+) -> Result<Vec<&'static str>> {
+    let path = config::path(opts.config.as_deref());
+    match opts.reconfigure(sinks, settings) {
+        Ok(changes) => {
+            println!(
+                "Reloaded {}, changed {}",
+                path.display(),
+                changes.join(", ")
+            );
+            Ok(changes)
+        }
+        Err(e) => {
+            println!("Failed to apply the changes to {}: {:#}", path.display(), e);
+            Err(e)
+        }
+    }
+}
+
+/// The UART sinks to open for `specs`, stdout if there are none unless the terminal UI's UART
+/// pane takes its place
+fn sink_specs(specs: &[SinkSpec], tui: bool) -> Vec<SinkSpec> {
+    match (specs.is_empty(), tui) {
+        (true, false) => vec![sinks::parse_spec("stdout").unwrap()],
+        _ => specs.to_vec(),
+    }
+}
+
+/// Find out where the firmware came from, and record it in the log and the summary
+async fn firmware_info(session: &Session, elf: &Path) -> FirmwareInfo {
+    let elf = elf.to_owned();
+    let info = tokio::task::spawn_blocking(move || FirmwareInfo::collect(&elf))
+        .await
+        .unwrap_or_default();
+    println!("[{}] Firmware {}", session.id, info);
+    session.count(|stats| stats.firmware = Some(info.clone()));
+    info
+}
+
+/// Report progress getting firmware into the simulator
+fn transfer(session: &Session, stage: &str, elf: &Path) {
+    activity::publish(Activity::Transfer(format!(
+        "[{}] {} {}",
+        session.id,
+        stage,
+        elf.display()
+    )));
+}
+
+/// Tell the simulator the server is shutting down, and close the connection
+async fn going_away(outgoing: &mut TracedSink) -> Result<()> {
+    outgoing
+        .send(tungstenite::Message::Close(Some(CloseFrame {
+            code: CloseCode::Away,
+            reason: "wokwi-server is shutting down".into(),
+        })))
+        .await?;
+    outgoing.close().await?;
+    Ok(())
+}
+
+/// Agree on the protocol with the simulator from its hello message and print what was agreed. A
+/// simulator which isn't supported is told why it is being hung up on
+async fn accept_hello<W>(
+    outgoing: &mut W,
+    msg: &tungstenite::Message,
+    session: &Session,
+    pin: ProtocolPin,
+) -> Result<protocol::Capabilities>
+where
+    W: Sink<tungstenite::Message> + Unpin,
+{
+    let hello = match Hello::parse_pinned(msg.to_text()?, pin) {
+        Ok(hello) => hello,
+        Err(e) => {
+            // let the browser know why we are hanging up
+            outgoing
+                .send(tungstenite::Message::Close(Some(CloseFrame {
+                    code: CloseCode::Protocol,
+                    reason: "wokwi-server does not support this client".into(),
+                })))
+                .await
+                .ok();
+            return Err(e.into());
+        }
+    };
+    let capabilities = hello.negotiate();
+    println!(
+        "[{}] Protocol v{}, app version {}",
+        session.id,
+        hello.protocol_version(),
+        hello.app_version.as_deref().unwrap_or("unknown")
+    );
+    if hello.pinned.is_some() && hello.protocol_version() != hello.announced_version() {
+        println!(
+            "[{}] Speaking protocol v{} as pinned with --embed-protocol, the simulator announced v{}",
+            session.id,
+            hello.protocol_version(),
+            hello.announced_version()
+        );
+    }
+    if !hello.capabilities.is_empty() {
+        println!(
+            "[{}] Negotiated capabilities: {:?}",
+            session.id, capabilities
+        );
+    }
+    if let Some(size) = hello.max_message_size {
+        println!(
+            "[{}] Simulator accepts messages up to {} bytes",
+            session.id, size
+        );
+    }
+    Ok(capabilities)
+}
+
+/// Send the messages queued for the simulator, pinging between the chunks of large messages
+async fn send_queued<W>(
+    outbox: &mut Outbox,
+    outgoing: &mut W,
+    log: &mut RunLog,
+    keepalive: Keepalive,
+) -> Result<()>
+where
+    W: Sink<tungstenite::Message, Error = tungstenite::Error> + Unpin,
+{
+    while let Some((text, progress)) = outbox.next_for_simulator_with_progress() {
+        log.sent(&text);
+        outgoing.send(tungstenite::Message::Text(text)).await?;
+        if let Some(ping) = keepalive.after(progress) {
+            outgoing.send(ping).await?;
+        }
+    }
+    Ok(())
+}
+
+/// Send queued messages on to the simulator and the GDB client, pinging between the chunks of
+/// large messages
+async fn deliver(
+    outbox: &mut Outbox,
+    outgoing: &mut TracedSink,
+    log: &mut RunLog,
+    gdb: &SimulatorLink,
+    keepalive: Keepalive,
+) -> Result<()> {
+    send_queued(outbox, outgoing, log, keepalive).await?;
+    while let Some(response) = outbox.next_for_gdb() {
+        activity::publish(Activity::Gdb {
+            to_target: false,
+            packet: gdb::to_binary_string(&response),
+        });
+        gdb.respond(response);
+    }
+    Ok(())
+}
+
+async fn gdb_task(
+    server: TcpListener,
+    broker: Broker,
+    trace: GdbTrace,
+    sessions: Sessions,
+    shutdown: CancellationToken,
+) -> Result<()> {
     loop {
-        let (stream, _) = server.accept().await?;
-        println!("GDB client connected.");
-        match handle_gdb_client(stream, &mut send, &mut recv).await {
-            Ok(_) => println!("GDB Session ended cleanly."),
-            Err(e) => println!("GDB Session ended with error: {:?}", e),
+        let (stream, peer) = tokio::select! {
+            accepted = server.accept() => accepted?,
+            _ = shutdown.cancelled() => return Ok(()),
+        };
+        let session = sessions.open(Kind::Gdb, peer);
+        // a client which keeps reconnecting only differs by its port
+        repeats::report(
+            &session.id,
+            format_args!("GDB client connected from {}", peer.ip()),
+        );
+        let result = handle_gdb_client(stream, &session, &broker, &trace, &shutdown).await;
+        match &result {
+            Ok(_) => repeats::report(&session.id, "GDB Session ended cleanly."),
+            Err(e) => repeats::report(
+                &session.id,
+                format_args!("GDB Session ended with error: {:?}", e),
+            ),
         }
+        session.close(&result);
     }
 }
 
 async fn handle_gdb_client(
     mut stream: TcpStream,
-    send: &mut Sender<GdbInstruction>,
-    recv: &mut Receiver<String>,
+    session: &Session,
+    broker: &Broker,
+    trace: &GdbTrace,
+    shutdown: &CancellationToken,
 ) -> Result<()> {
+    let mut link = broker.attach_gdb(&session.id);
     stream.write_all(b"+").await?;
 
     let mut buffer = BytesMut::with_capacity(1024);
+    // sent again when GDB answers it with a `-`
+    let mut last_response: Option<Vec<u8>> = None;
     loop {
         tokio::select! {
             r = stream.read_buf(&mut buffer) => {
@@ -289,55 +2138,80 @@ async fn handle_gdb_client(
                     anyhow::bail!("GDB End of stream");
                 }
 
-                loop {
-                    let raw_command = String::from_utf8_lossy(buffer.as_ref());
-                    let start = raw_command.find('$').map(|i| i + 1); // we want everything after the $
-                    let end = raw_command.find('#');
-
-                    match (start, end) {
-                        (Some(start), Some(end)) => {
-                            let command = &raw_command[start..end];
-                            let end = end + 1; // move past #
-                            let checksum = &raw_command[end..];
-                            // println!("Command: {}, checksum: {}", command, checksum);
-                            let len = if gdb_checksum(command, checksum).is_err() {
-                                stream.write_all(b"-").await?;
-                                end
-                            } else {
-                                stream.write_all(b"+").await?;
-                                send.send(GdbInstruction::Command(command.to_owned()))
-                                    .await?;
-                                end + 2
-                            };
-                            buffer.advance(len);
+                while let Some((packet, len)) = gdb::parse_packet(&buffer) {
+                    trace.record(&session.id, Hop::FromGdb, &buffer[..len]);
+                    buffer.advance(len);
+                    match packet {
+                        GdbPacket::Command(command) => {
+                            session.count(|stats| stats.gdb_packets += 1);
+                            stream.write_all(b"+").await?;
+                            activity::publish(Activity::Gdb { to_target: true, packet: gdb::to_binary_string(&command) });
+                            link.send(GdbInstruction::Command(command));
                         }
-                        (None, Some(end)) => buffer.advance(end), /* partial command, discard */
-                        (Some(_), None) => break,                 /* incomplete, need more data */
-                        (None, None) => {
-                            if let Some(_index) = buffer.iter().position(|&x| x == 0x03) {
-                                // println!("GDB BREAK detected in packet at index {}", index);
-                                send.send(GdbInstruction::Break).await?;
+                        GdbPacket::BadChecksum { received, calculated } => {
+                            repeats::report(&session.id, format_args!("Invalid checksum, expected {}, calculated {:02x}", received, calculated));
+                            stream.write_all(b"-").await?;
+                        }
+                        GdbPacket::Break => {
+                            session.count(|stats| stats.gdb_packets += 1);
+                            link.send(GdbInstruction::Break);
+                        }
+                        GdbPacket::Nak => {
+                            if let Some(resp) = &last_response {
+                                trace.record(&session.id, Hop::ToGdb, resp);
+                                stream.write_all(resp).await?;
                             }
-                            buffer.advance(buffer.remaining()); /* garbage */
-                            break;
                         }
+                        GdbPacket::Garbage => {}
                     }
                 }
             }
-            resp = recv.recv() => {
-                let resp = resp.ok_or_else(|| anyhow::anyhow!("Channel closed unexpectedly"))?;
-                stream.write_all(resp.as_bytes()).await?;
+            resp = link.responses.recv() => {
+                let resp = resp.ok_or_else(|| anyhow::anyhow!("GDB broker stopped unexpectedly"))?;
+                trace.record(&session.id, Hop::ToGdb, &resp);
+                stream.write_all(&resp).await?;
+                last_response = Some(resp);
+            }
+            _ = shutdown.cancelled() => {
+                stream.shutdown().await?;
+                return Ok(());
             }
         }
     }
 }
 
-fn gdb_checksum(cmd: &str, checksum: &str) -> Result<()> {
-    let cs = cmd.as_bytes().iter().map(|&n| n as u16).sum::<u16>() & 0xff;
-    let cs = format!("{:02x}", cs);
-    if cs != checksum {
-        println!("Invalid checksum, expected {}, calculated {}", checksum, cs);
-        anyhow::bail!("Invalid checksum, expected {}, calculated {}", checksum, cs);
+/// Attach a fresh `--uart-peer` to the firmware, returning what it sends
+fn start_peer(run: &mut Run) -> Result<tokio::sync::mpsc::Receiver<Vec<u8>>> {
+    let Some(spec) = &run.opts.uart_peer else {
+        return Ok(tokio::sync::mpsc::channel(1).1);
+    };
+    let (feed, replies) = peer::spawn(spec.open()?);
+    run.peer = Some(feed);
+    Ok(replies)
+}
+
+/// Start a fresh host process for each `--chip-bridge`, returning what they send their chips
+fn start_bridges(run: &mut Run) -> Result<tokio::sync::mpsc::Receiver<Value>> {
+    let (bridges, replies) = custom_chips::spawn(&run.opts.chip_bridge, &run.session.id)?;
+    run.bridges = bridges;
+    Ok(replies)
+}
+
+/// Run `future` to completion, unless a shutdown is requested first
+async fn until_shutdown<T>(
+    shutdown: &CancellationToken,
+    future: impl std::future::Future<Output = T>,
+) -> Option<T> {
+    tokio::select! {
+        output = future => Some(output),
+        _ = shutdown.cancelled() => None,
+    }
+}
+
+/// Resolves at the deadline, or never if there is none
+async fn sleep_until(deadline: Option<tokio::time::Instant>) {
+    match deadline {
+        Some(deadline) => tokio::time::sleep_until(deadline).await,
+        None => std::future::pending().await,
     }
-    Ok(())
 }
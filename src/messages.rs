@@ -0,0 +1,69 @@
+//! The messages a simulator sends, decoded and passed to what the simulation does with them. The
+//! server dispatches every message through [`router`], so it is also what the fuzzer exercises
+
+use crate::buses::{I2cTransaction, SpiTransaction};
+use crate::error::{Result, WokwiServerError};
+use crate::plugins;
+use crate::protocol;
+use crate::router::{Outbox, Router};
+use crate::vcd::PinChange;
+use serde_json::Value;
+
+/// the types of the messages bridged between a custom chip and its host process
+pub const CHIP_MESSAGE_TYPES: &[&str] = &["chipSerial", "chipPin"];
+
+/// What a simulation does with each message from the simulator, once it has been decoded
+pub trait Simulation {
+    /// UART output of the firmware
+    fn uart_data(&mut self, bytes: Vec<u8>, out: &mut Outbox) -> Result<()>;
+
+    /// The simulator's GDB stub answering a packet, still framed
+    fn gdb_response(&mut self, response: Vec<u8>, out: &mut Outbox) -> Result<()>;
+
+    fn i2c_transaction(&mut self, transaction: I2cTransaction, out: &mut Outbox) -> Result<()>;
+
+    fn spi_transaction(&mut self, transaction: SpiTransaction, out: &mut Outbox) -> Result<()>;
+
+    fn pin_change(&mut self, change: PinChange, out: &mut Outbox) -> Result<()>;
+
+    /// The simulation has paused after the span of simulated time it was asked to run for
+    fn paused(&mut self, out: &mut Outbox) -> Result<()>;
+
+    /// A message from the custom chip called `chip`, for its host process
+    fn chip_message(&mut self, chip: &str, message: &Value, out: &mut Outbox) -> Result<()>;
+}
+
+/// The router the server dispatches messages from the simulator with: a handler decoding each
+/// message type it knows, then the registered plugins for the rest
+pub fn router<S: Simulation>() -> Router<S> {
+    let router = Router::default()
+        .on("uartData", |sim: &mut S, message, out| {
+            sim.uart_data(protocol::uart_data(message)?, out)
+        })
+        .on("gdbResponse", |sim: &mut S, message, out| {
+            sim.gdb_response(protocol::gdb_response(message)?, out)
+        })
+        .on("i2cTransaction", |sim: &mut S, message, out| {
+            sim.i2c_transaction(I2cTransaction::parse(message)?, out)
+        })
+        .on("spiTransaction", |sim: &mut S, message, out| {
+            sim.spi_transaction(SpiTransaction::parse(message)?, out)
+        })
+        .on("pinChange", |sim: &mut S, message, out| {
+            sim.pin_change(PinChange::parse(message)?, out)
+        })
+        .on("paused", |sim: &mut S, _, out| sim.paused(out));
+    let router = CHIP_MESSAGE_TYPES.iter().fold(router, |router, r#type| {
+        router.on(r#type, chip_message::<S>)
+    });
+    plugins::instantiate()
+        .into_iter()
+        .fold(router, Router::plugin)
+}
+
+fn chip_message<S: Simulation>(sim: &mut S, message: &Value, out: &mut Outbox) -> Result<()> {
+    let chip = message["chip"]
+        .as_str()
+        .ok_or_else(|| WokwiServerError::Protocol(format!("{} without a chip", message["type"])))?;
+    sim.chip_message(chip, message, out)
+}
@@ -0,0 +1,72 @@
+use crate::image::{self, ImageArgs};
+use anyhow::{Context, Result};
+use serde_json::json;
+use std::path::PathBuf;
+
+/// flash which hasn't been written reads as all ones
+const ERASED: u8 = 0xFF;
+
+/// Build the flash image for a firmware and write it to a file, without starting a simulation
+#[derive(clap::Args, Debug)]
+pub struct PackArgs {
+    #[clap(flatten)]
+    image: ImageArgs,
+
+    /// file to write, a merged flash image starting at address 0 if it ends in `.bin`, or the
+    /// elf and segments sent to the simulator as JSON otherwise, which `serve` can simulate
+    #[clap(short, long)]
+    output: PathBuf,
+}
+
+pub async fn run(mut args: PackArgs) -> Result<i32> {
+    args.image.resolve()?;
+    args.image.validate(true)?;
+
+    let packet = image::start_packet(&args.image, "pack").await?;
+    packet
+        .validate()
+        .context("Refusing to pack an invalid start packet")?;
+    let segments = packet
+        .esp_bin
+        .iter()
+        .map(|segment| {
+            let addr = segment[0].as_u64().context("Invalid segment address")?;
+            let data = base64::decode(segment[1].as_str().context("Invalid segment data")?)?;
+            Ok((addr as usize, data))
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    let merged = args
+        .output
+        .extension()
+        .is_some_and(|ext| ext.eq_ignore_ascii_case("bin"));
+    let contents = if merged {
+        merge(&segments)
+    } else {
+        let output = json!({
+            "chip": args.image.chip.to_string(),
+            "elf": packet.elf,
+            "espBin": packet.esp_bin,
+            "segments": packet.extensions.map(|e| e.segments),
+        });
+        serde_json::to_vec_pretty(&output)?
+    };
+    std::fs::write(&args.output, &contents)
+        .with_context(|| format!("Failed to write {}", args.output.display()))?;
+    println!("Wrote {} ({} bytes)", args.output.display(), contents.len());
+    Ok(0)
+}
+
+/// Lay the segments out in one image, as they would be in flash
+fn merge(segments: &[(usize, Vec<u8>)]) -> Vec<u8> {
+    let len = segments
+        .iter()
+        .map(|(addr, data)| addr + data.len())
+        .max()
+        .unwrap_or_default();
+    let mut image = vec![ERASED; len];
+    for (addr, data) in segments {
+        image[*addr..addr + data.len()].copy_from_slice(data);
+    }
+    image
+}
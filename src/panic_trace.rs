@@ -0,0 +1,204 @@
+//! Backtraces of panics on the UART, printed with the functions and source lines they point at
+
+use crate::command;
+use anyhow::{Context, Result};
+use serde_json::{json, Value};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use wokwi_server::backtrace::{Backtraces, Frame, Panic, Symbolizer};
+
+/// the default lines of source shown either side of the line a panic happened on
+pub const DEFAULT_CONTEXT: u32 = 2;
+
+pub struct PanicTrace {
+    backtraces: Backtraces,
+    symbolizer: Symbolizer,
+    /// `--bootloader-elf`, searched after the app's elf
+    bootloader: Option<PathBuf>,
+    /// lines of source either side of the failing line
+    context: u32,
+    /// `--open-editor`: the editor command, opened at the first panic with a source line
+    editor: Option<String>,
+    /// whether the editor was opened since the firmware was loaded
+    opened: bool,
+    /// the source line shown last, not shown again for the same panic's other backtrace
+    shown: Option<(String, u32)>,
+}
+
+impl PanicTrace {
+    pub fn new(
+        elf: &Path,
+        bootloader: Option<&Path>,
+        context: u32,
+        editor: Option<String>,
+    ) -> Self {
+        let mut trace = Self {
+            backtraces: Backtraces::default(),
+            symbolizer: Symbolizer::new(&[]),
+            bootloader: bootloader.map(Path::to_owned),
+            context,
+            editor,
+            opened: false,
+            shown: None,
+        };
+        trace.load(elf);
+        trace
+    }
+
+    /// Look up the addresses of backtraces from now on in `elf`, then the bootloader's
+    pub fn load(&mut self, elf: &Path) {
+        let bytes = std::fs::read(elf).unwrap_or_default();
+        self.symbolizer = Symbolizer::new(&bytes);
+        if let Some(bootloader) = &self.bootloader {
+            self.symbolizer
+                .add(&std::fs::read(bootloader).unwrap_or_default());
+        }
+        self.opened = false;
+        self.shown = None;
+    }
+
+    /// Print the backtraces in UART output as functions and source lines, and the source where
+    /// panics happened, returning the events to log
+    pub fn feed(&mut self, bytes: &[u8], label: &str) -> Vec<(&'static str, Value)> {
+        let panics = self.backtraces.feed(bytes);
+        panics
+            .into_iter()
+            .filter_map(|panic| match panic {
+                Panic::Backtrace(addresses) => Some(("backtrace", self.show(&addresses, label)?)),
+                Panic::Location { file, line, column } => {
+                    self.failed_at(&file, line, Some(column), label);
+                    let details = json!({ "file": file, "line": line, "column": column });
+                    Some(("panic", details))
+                }
+            })
+            .collect()
+    }
+
+    fn show(&mut self, addresses: &[u32], label: &str) -> Option<Value> {
+        let frames: Vec<_> = addresses
+            .iter()
+            .map(|&addr| (addr, self.symbolizer.frames(addr)))
+            .collect();
+        if frames.iter().all(|(_, frames)| frames.is_empty()) {
+            return None;
+        }
+
+        println!("[{}] Backtrace:", label);
+        for (addr, frames) in &frames {
+            match frames.split_first() {
+                None => println!("[{}]   {:#010x}: ??", label, addr),
+                Some((first, inlined)) => {
+                    println!("[{}]   {:#010x}: {}", label, addr, describe(first));
+                    for frame in inlined {
+                        println!("[{}]               inlined into {}", label, describe(frame));
+                    }
+                }
+            }
+        }
+
+        // the innermost line there's source for is where things went wrong
+        let failing = frames.iter().flat_map(|(_, frames)| frames).find_map(|f| {
+            let (file, line) = (f.file.as_ref()?, f.line?);
+            Path::new(file).is_file().then(|| (file.clone(), line))
+        });
+        if let Some((file, line)) = failing {
+            self.failed_at(&file, line, None, label);
+        }
+
+        let frames: Vec<_> = frames
+            .iter()
+            .map(|(addr, frames)| {
+                let frames: Vec<_> = frames
+                    .iter()
+                    .map(|f| json!({ "function": f.function, "file": f.file, "line": f.line }))
+                    .collect();
+                json!({ "address": addr, "frames": frames })
+            })
+            .collect();
+        Some(json!({ "addresses": frames }))
+    }
+
+    /// Show the source around where a panic happened, and open it in the editor if it is the
+    /// first. Files which can't be found, e.g. ones only the machine the firmware was built on
+    /// has, are skipped
+    fn failed_at(&mut self, file: &str, line: u32, column: Option<u32>, label: &str) {
+        let failing = (file.to_owned(), line);
+        if !Path::new(file).is_file() || self.shown.as_ref() == Some(&failing) {
+            return;
+        }
+        self.snippet(Path::new(file), line, label);
+        if let Some(editor) = self.editor.as_deref().filter(|_| !self.opened) {
+            if let Err(e) = open_editor(editor, file, line, column) {
+                println!("[{}] Failed to open the editor: {:#}", label, e);
+            }
+            self.opened = true;
+        }
+        self.shown = Some(failing);
+    }
+
+    fn snippet(&self, file: &Path, line: u32, label: &str) {
+        let Ok(source) = std::fs::read_to_string(file) else {
+            return;
+        };
+        let first = line.saturating_sub(self.context).max(1);
+        let last = line.saturating_add(self.context);
+        for (number, text) in (1..).zip(source.lines()) {
+            if (first..=last).contains(&number) {
+                let marker = if number == line { '>' } else { ' ' };
+                println!("[{}] {} {:5} | {}", label, marker, number, text);
+            }
+        }
+    }
+}
+
+/// `function at file:line`, as much of it as is known
+fn describe(frame: &Frame) -> String {
+    let function = frame.function.as_deref().unwrap_or("??");
+    match (&frame.file, frame.line) {
+        (Some(file), Some(line)) => format!("{} at {}:{}", function, file, line),
+        (Some(file), None) => format!("{} at {}", function, file),
+        _ => function.to_owned(),
+    }
+}
+
+/// The user's editor command, from `$VISUAL` or `$EDITOR`
+pub fn editor() -> Option<String> {
+    ["VISUAL", "EDITOR"]
+        .iter()
+        .filter_map(|var| std::env::var(var).ok())
+        .find(|editor| !editor.trim().is_empty())
+}
+
+/// Open `file` at `line` with the editor command, which may hold `{file}`, `{line}` and
+/// `{column}` placeholders. Without them, the `+<line> <file>` most editors understand is
+/// appended, or `-g <file>:<line>` for VS Code
+fn open_editor(editor: &str, file: &str, line: u32, column: Option<u32>) -> Result<()> {
+    let mut words = command::split(editor);
+    let placeholders = words.iter().any(|w| {
+        ["{file}", "{line}", "{column}"]
+            .iter()
+            .any(|p| w.contains(p))
+    });
+    if !placeholders {
+        let program = words.first().map(|p| Path::new(p).file_stem());
+        match program.flatten().and_then(|p| p.to_str()) {
+            Some("code" | "codium" | "code-insiders") => {
+                words.extend(["-g".to_owned(), "{file}:{line}".to_owned()])
+            }
+            _ => words.extend(["+{line}".to_owned(), "{file}".to_owned()]),
+        }
+    }
+    let (line, column) = (line.to_string(), column.unwrap_or(1).to_string());
+    let vars = [("file", file), ("line", &line), ("column", &column)];
+    let words = command::expand(&words, &vars);
+
+    let (program, args) = words.split_first().context("Editor command is empty")?;
+    Command::new(program)
+        .args(args)
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()
+        .with_context(|| format!("failed to launch `{}`", program))?;
+    Ok(())
+}
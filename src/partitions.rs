@@ -0,0 +1,30 @@
+const ENTRY_SIZE: usize = 32;
+const ENTRY_MAGIC: [u8; 2] = [0xAA, 0x50];
+
+/// A single entry of a binary partition table
+#[derive(Debug, Clone)]
+pub struct PartitionEntry {
+    pub name: String,
+    pub ty: u8,
+    pub sub_type: u8,
+    pub offset: u32,
+    pub size: u32,
+}
+
+/// Parse the entries of a binary partition table, stopping at the first non-partition entry
+pub fn parse_partition_table(data: &[u8]) -> Vec<PartitionEntry> {
+    data.chunks_exact(ENTRY_SIZE)
+        .take_while(|entry| entry[0..2] == ENTRY_MAGIC)
+        .map(|entry| {
+            let name = &entry[12..28];
+            let end = name.iter().position(|&b| b == 0).unwrap_or(name.len());
+            PartitionEntry {
+                name: String::from_utf8_lossy(&name[..end]).into_owned(),
+                ty: entry[2],
+                sub_type: entry[3],
+                offset: u32::from_le_bytes(entry[4..8].try_into().unwrap()),
+                size: u32::from_le_bytes(entry[8..12].try_into().unwrap()),
+            }
+        })
+        .collect()
+}
@@ -0,0 +1,176 @@
+//! Devices attached to the simulated UART, which answer what the firmware sends
+
+use anyhow::Result;
+use std::collections::VecDeque;
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tokio::time::{Instant, Interval};
+
+mod modbus;
+mod nmea;
+mod script;
+
+/// A device on the other end of the simulated UART
+pub trait Peer: Send {
+    /// Handle bytes the firmware wrote to the UART
+    fn feed(&mut self, bytes: &[u8]) -> Vec<Reply>;
+
+    /// How often `tick` is called, for devices which send without being asked
+    fn interval(&self) -> Option<Duration> {
+        None
+    }
+
+    fn tick(&mut self) -> Vec<Reply> {
+        Vec::new()
+    }
+}
+
+/// Bytes for the firmware to receive, once `delay` has passed
+#[derive(Debug, PartialEq, Eq)]
+pub struct Reply {
+    pub delay: Duration,
+    pub bytes: Vec<u8>,
+}
+
+/// Opens a peer, given the argument after `name:`
+type OpenFn = fn(Option<&str>) -> Result<Box<dyn Peer>>;
+
+/// A kind of peer which can be chosen with `--uart-peer <name>[:<arg>]`
+#[derive(Debug)]
+pub struct PeerKind {
+    pub name: &'static str,
+    /// what the argument after `name:` means, for peers which take one
+    pub arg: Option<&'static str>,
+    /// whether the peer can be used without its argument
+    pub arg_optional: bool,
+    open: OpenFn,
+}
+
+/// All the available peers, new peers only need to be added here
+pub const KINDS: &[PeerKind] = &[
+    PeerKind {
+        name: "script",
+        arg: Some("path"),
+        arg_optional: false,
+        open: script::open,
+    },
+    PeerKind {
+        name: "modbus",
+        arg: Some("path"),
+        arg_optional: false,
+        open: modbus::open,
+    },
+    PeerKind {
+        name: "nmea",
+        arg: Some("path"),
+        arg_optional: true,
+        open: nmea::open,
+    },
+];
+
+/// A peer given on the command line
+#[derive(Debug, Clone)]
+pub struct PeerSpec {
+    kind: &'static PeerKind,
+    arg: Option<String>,
+}
+
+impl std::fmt::Display for PeerSpec {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.arg {
+            Some(arg) => write!(f, "{}:{}", self.kind.name, arg),
+            None => f.write_str(self.kind.name),
+        }
+    }
+}
+
+impl PeerSpec {
+    pub fn open(&self) -> Result<Box<dyn Peer>> {
+        (self.kind.open)(self.arg.as_deref())
+            .map_err(|e| e.context(format!("Failed to open UART peer `{}`", self)))
+    }
+}
+
+/// Parse `<name>[:<arg>]`, or the path of a script
+pub fn parse_spec(s: &str) -> Result<PeerSpec, String> {
+    let (name, arg) = match s.split_once(':') {
+        Some((name, arg)) => (name, Some(arg.to_owned())),
+        None => (s, None),
+    };
+    let Some(kind) = KINDS.iter().find(|k| k.name == name) else {
+        return Ok(PeerSpec {
+            kind: &KINDS[0],
+            arg: Some(s.to_owned()),
+        });
+    };
+    match (kind.arg, &arg) {
+        (Some(usage), None) if !kind.arg_optional => {
+            Err(format!("expected `{}:<{}>`", name, usage))
+        }
+        (None, Some(_)) => Err(format!("`{}` doesn't take an argument", name)),
+        _ => Ok(PeerSpec { kind, arg }),
+    }
+}
+
+/// Where the firmware's UART output is passed to a running peer
+pub struct PeerFeed(mpsc::UnboundedSender<Vec<u8>>);
+
+impl PeerFeed {
+    pub fn feed(&self, bytes: &[u8]) {
+        self.0.send(bytes.to_vec()).ok();
+    }
+}
+
+/// Run a peer until its feed is dropped, returning the feed and the bytes it sends
+pub fn spawn(peer: Box<dyn Peer>) -> (PeerFeed, mpsc::Receiver<Vec<u8>>) {
+    let (feed, output) = mpsc::unbounded_channel();
+    let (send, replies) = mpsc::channel(16);
+    tokio::spawn(run(peer, output, send));
+    (PeerFeed(feed), replies)
+}
+
+async fn run(
+    mut peer: Box<dyn Peer>,
+    mut output: mpsc::UnboundedReceiver<Vec<u8>>,
+    replies: mpsc::Sender<Vec<u8>>,
+) {
+    let mut pending = VecDeque::new();
+    let mut ticker = peer.interval().map(tokio::time::interval);
+    loop {
+        let next = pending.front().map(|(at, _)| *at);
+        tokio::select! {
+            bytes = output.recv() => match bytes {
+                Some(bytes) => schedule(&mut pending, peer.feed(&bytes)),
+                None => return, /* the simulation went away */
+            },
+            _ = crate::sleep_until(next) => {
+                if let Some((_, bytes)) = pending.pop_front() {
+                    if replies.send(bytes).await.is_err() {
+                        return;
+                    }
+                }
+            }
+            _ = tick(&mut ticker) => schedule(&mut pending, peer.tick()),
+        }
+    }
+}
+
+/// Queue replies by when they are due, keeping the order of those due at the same time
+fn schedule(pending: &mut VecDeque<(Instant, Vec<u8>)>, replies: Vec<Reply>) {
+    let now = Instant::now();
+    for reply in replies {
+        let at = now + reply.delay;
+        let index = pending.partition_point(|(due, _)| *due <= at);
+        pending.insert(index, (at, reply.bytes));
+    }
+}
+
+/// Resolves on the next tick, or never for peers which don't tick
+async fn tick(ticker: &mut Option<Interval>) {
+    match ticker {
+        Some(ticker) => {
+            ticker.tick().await;
+        }
+        None => std::future::pending().await,
+    }
+}
@@ -0,0 +1,310 @@
+use super::{Peer, Reply};
+use anyhow::{Context, Result};
+use serde::Deserialize;
+use std::collections::{BTreeMap, HashMap};
+use std::time::Duration;
+
+/// the longest RTU frame, longer unparsed output can't be a request
+const MAX_FRAME: usize = 256;
+
+/// Exception codes
+const ILLEGAL_FUNCTION: u8 = 1;
+const ILLEGAL_DATA_ADDRESS: u8 = 2;
+const ILLEGAL_DATA_VALUE: u8 = 3;
+
+/// A register map like
+///
+/// ```toml
+/// address = 1
+///
+/// [holding_registers]
+/// 0 = 1234
+/// 0x10 = 42
+///
+/// [coils]
+/// 3 = true
+/// ```
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct MapFile {
+    /// the slave address the device answers to
+    #[serde(default = "default_address")]
+    address: u8,
+    /// milliseconds to wait before answering
+    #[serde(default)]
+    delay: u64,
+    #[serde(default)]
+    coils: HashMap<String, bool>,
+    #[serde(default)]
+    discrete_inputs: HashMap<String, bool>,
+    #[serde(default)]
+    holding_registers: HashMap<String, u16>,
+    #[serde(default)]
+    input_registers: HashMap<String, u16>,
+}
+
+fn default_address() -> u8 {
+    1
+}
+
+/// A Modbus RTU slave answering requests for the registers in its map
+struct Modbus {
+    address: u8,
+    delay: Duration,
+    coils: BTreeMap<u16, bool>,
+    discrete_inputs: BTreeMap<u16, bool>,
+    holding_registers: BTreeMap<u16, u16>,
+    input_registers: BTreeMap<u16, u16>,
+    buffer: Vec<u8>,
+}
+
+enum Frame {
+    Incomplete,
+    Invalid,
+    Complete(usize),
+}
+
+pub fn open(path: Option<&str>) -> Result<Box<dyn Peer>> {
+    let path = path.unwrap_or_default();
+    let contents =
+        std::fs::read_to_string(path).with_context(|| format!("Failed to read {}", path))?;
+    let map: MapFile = toml::from_str(&contents).with_context(|| format!("Invalid {}", path))?;
+    anyhow::ensure!(
+        (1..=247).contains(&map.address),
+        "Invalid {}: slave address {} isn't between 1 and 247",
+        path,
+        map.address
+    );
+    let context = |name: &str| format!("Invalid {} in {}", name, path);
+    Ok(Box::new(Modbus {
+        address: map.address,
+        delay: Duration::from_millis(map.delay),
+        coils: table(map.coils).with_context(|| context("coils"))?,
+        discrete_inputs: table(map.discrete_inputs).with_context(|| context("discrete_inputs"))?,
+        holding_registers: table(map.holding_registers)
+            .with_context(|| context("holding_registers"))?,
+        input_registers: table(map.input_registers).with_context(|| context("input_registers"))?,
+        buffer: Vec::new(),
+    }))
+}
+
+/// A table from the map, keyed by register address
+fn table<T>(table: HashMap<String, T>) -> Result<BTreeMap<u16, T>> {
+    table
+        .into_iter()
+        .map(|(register, value)| Ok((parse_register(&register)?, value)))
+        .collect()
+}
+
+/// Parse a register address, in decimal or hex with a `0x` prefix
+fn parse_register(s: &str) -> Result<u16> {
+    match s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+        Some(hex) => u16::from_str_radix(hex, 16),
+        None => s.parse(),
+    }
+    .map_err(|_| anyhow::anyhow!("`{}` isn't a register address", s))
+}
+
+impl Peer for Modbus {
+    fn feed(&mut self, bytes: &[u8]) -> Vec<Reply> {
+        self.buffer.extend_from_slice(bytes);
+        let mut replies = Vec::new();
+        loop {
+            match frame(&self.buffer) {
+                Frame::Incomplete => break,
+                // out of step with the firmware, try again from the next byte
+                Frame::Invalid => {
+                    self.buffer.remove(0);
+                }
+                Frame::Complete(len) => {
+                    let request: Vec<u8> = self.buffer.drain(..len).collect();
+                    if let Some(response) = self.handle(&request) {
+                        replies.push(Reply {
+                            delay: self.delay,
+                            bytes: response,
+                        });
+                    }
+                }
+            }
+        }
+        replies
+    }
+}
+
+impl Modbus {
+    /// The response to a request frame, if it is for this slave and not a broadcast
+    fn handle(&mut self, request: &[u8]) -> Option<Vec<u8>> {
+        let address = request[0];
+        if address != self.address && address != 0 {
+            return None;
+        }
+        let pdu = &request[1..request.len() - 2];
+        let result = self.execute(pdu);
+        if address == 0 {
+            return None; /* broadcasts are never answered */
+        }
+        let mut response = vec![address];
+        match result {
+            Ok(pdu) => response.extend(pdu),
+            Err(exception) => response.extend([pdu[0] | 0x80, exception]),
+        }
+        response.extend(crc16(&response).to_le_bytes());
+        Some(response)
+    }
+
+    /// Carry out a request, returning the response or an exception code
+    fn execute(&mut self, pdu: &[u8]) -> Result<Vec<u8>, u8> {
+        let function = pdu[0];
+        let word = |i: usize| u16::from_be_bytes([pdu[i], pdu[i + 1]]);
+        match function {
+            1 | 2 => {
+                let (start, count) = (word(1), word(3));
+                if !(1..=2000).contains(&count) {
+                    return Err(ILLEGAL_DATA_VALUE);
+                }
+                let table = match function {
+                    1 => &self.coils,
+                    _ => &self.discrete_inputs,
+                };
+                let bits = read(table, start, count)?;
+                let mut response = vec![function, bits.len().div_ceil(8) as u8];
+                response.extend(pack_bits(&bits));
+                Ok(response)
+            }
+            3 | 4 => {
+                let (start, count) = (word(1), word(3));
+                if !(1..=125).contains(&count) {
+                    return Err(ILLEGAL_DATA_VALUE);
+                }
+                let table = match function {
+                    3 => &self.holding_registers,
+                    _ => &self.input_registers,
+                };
+                let values = read(table, start, count)?;
+                let mut response = vec![function, (values.len() * 2) as u8];
+                response.extend(values.iter().flat_map(|value| value.to_be_bytes()));
+                Ok(response)
+            }
+            5 => {
+                let value = match word(3) {
+                    0xFF00 => true,
+                    0x0000 => false,
+                    _ => return Err(ILLEGAL_DATA_VALUE),
+                };
+                write(&mut self.coils, word(1), &[value])?;
+                Ok(pdu.to_vec())
+            }
+            6 => {
+                write(&mut self.holding_registers, word(1), &[word(3)])?;
+                Ok(pdu.to_vec())
+            }
+            15 => {
+                let (start, count) = (word(1), word(3));
+                let data = &pdu[6..];
+                if !(1..=1968).contains(&count) || data.len() != (count as usize).div_ceil(8) {
+                    return Err(ILLEGAL_DATA_VALUE);
+                }
+                let bits: Vec<bool> = (0..count as usize)
+                    .map(|i| data[i / 8] & (1 << (i % 8)) != 0)
+                    .collect();
+                write(&mut self.coils, start, &bits)?;
+                Ok(pdu[..5].to_vec())
+            }
+            16 => {
+                let (start, count) = (word(1), word(3));
+                let data = &pdu[6..];
+                if !(1..=123).contains(&count) || data.len() != count as usize * 2 {
+                    return Err(ILLEGAL_DATA_VALUE);
+                }
+                let values: Vec<u16> = data
+                    .chunks(2)
+                    .map(|pair| u16::from_be_bytes([pair[0], pair[1]]))
+                    .collect();
+                write(&mut self.holding_registers, start, &values)?;
+                Ok(pdu[..5].to_vec())
+            }
+            _ => Err(ILLEGAL_FUNCTION),
+        }
+    }
+}
+
+/// The addresses `start..start + count`, which must all be in the map
+fn addresses<T>(
+    table: &BTreeMap<u16, T>,
+    start: u16,
+    count: u16,
+) -> Result<impl Iterator<Item = u16>, u8> {
+    let end = start as u32 + count as u32;
+    let mapped = end <= 0x10000 && (start..=(end - 1) as u16).all(|a| table.contains_key(&a));
+    match mapped {
+        true => Ok((start as u32..end).map(|a| a as u16)),
+        false => Err(ILLEGAL_DATA_ADDRESS),
+    }
+}
+
+fn read<T: Copy>(table: &BTreeMap<u16, T>, start: u16, count: u16) -> Result<Vec<T>, u8> {
+    Ok(addresses(table, start, count)?.map(|a| table[&a]).collect())
+}
+
+fn write<T: Copy>(table: &mut BTreeMap<u16, T>, start: u16, values: &[T]) -> Result<(), u8> {
+    for (a, value) in addresses(table, start, values.len() as u16)?.zip(values) {
+        table.insert(a, *value);
+    }
+    Ok(())
+}
+
+/// Pack bits into bytes, the first bit in the lowest bit of the first byte
+fn pack_bits(bits: &[bool]) -> Vec<u8> {
+    bits.chunks(8)
+        .map(|chunk| {
+            chunk
+                .iter()
+                .enumerate()
+                .fold(0, |byte, (i, &bit)| byte | (bit as u8) << i)
+        })
+        .collect()
+}
+
+/// How much of `buffer` is a request, from the length its function implies
+fn frame(buffer: &[u8]) -> Frame {
+    let Some(&function) = buffer.get(1) else {
+        return Frame::Incomplete;
+    };
+    let len = match function {
+        1..=6 => 8,
+        15 | 16 => match buffer.get(6) {
+            Some(&count) => 9 + count as usize,
+            None => return Frame::Incomplete,
+        },
+        // the length of other functions isn't known, so look for a frame that checks out
+        _ => {
+            return match (4..=buffer.len()).find(|&len| crc_matches(&buffer[..len])) {
+                Some(len) => Frame::Complete(len),
+                None if buffer.len() >= MAX_FRAME => Frame::Invalid,
+                None => Frame::Incomplete,
+            }
+        }
+    };
+    if buffer.len() < len {
+        Frame::Incomplete
+    } else if crc_matches(&buffer[..len]) {
+        Frame::Complete(len)
+    } else {
+        Frame::Invalid
+    }
+}
+
+fn crc_matches(frame: &[u8]) -> bool {
+    let (data, crc) = frame.split_at(frame.len() - 2);
+    crc16(data).to_le_bytes() == crc
+}
+
+/// The Modbus CRC-16
+fn crc16(data: &[u8]) -> u16 {
+    data.iter().fold(0xFFFF, |crc, &byte| {
+        (0..8).fold(crc ^ byte as u16, |crc, _| match crc & 1 {
+            1 => (crc >> 1) ^ 0xA001,
+            _ => crc >> 1,
+        })
+    })
+}
@@ -0,0 +1,173 @@
+use super::{Peer, Reply};
+use crate::firmware_info::civil_from_days;
+use anyhow::{Context, Result};
+use serde::Deserialize;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// The fix reported, like
+///
+/// ```toml
+/// latitude = 49.1951
+/// longitude = 16.6068
+/// altitude = 237.0
+/// interval = 1000
+/// ```
+#[derive(Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct Fix {
+    /// degrees, negative to the south
+    latitude: f64,
+    /// degrees, negative to the west
+    longitude: f64,
+    /// metres above mean sea level
+    altitude: f64,
+    /// speed over ground in knots
+    speed: f64,
+    /// course over ground in degrees
+    course: f64,
+    satellites: u8,
+    /// whether there is a fix at all, to test how the firmware copes without one
+    valid: bool,
+    /// milliseconds between reports
+    interval: u64,
+}
+
+impl Default for Fix {
+    fn default() -> Self {
+        Self {
+            latitude: 0.0,
+            longitude: 0.0,
+            altitude: 0.0,
+            speed: 0.0,
+            course: 0.0,
+            satellites: 8,
+            valid: true,
+            interval: 1000,
+        }
+    }
+}
+
+/// A GPS receiver, reporting a fixed position as GGA and RMC sentences
+struct Nmea {
+    fix: Fix,
+}
+
+pub fn open(path: Option<&str>) -> Result<Box<dyn Peer>> {
+    let fix = match path {
+        Some(path) => {
+            let contents = std::fs::read_to_string(path)
+                .with_context(|| format!("Failed to read {}", path))?;
+            toml::from_str(&contents).with_context(|| format!("Invalid {}", path))?
+        }
+        None => Fix::default(),
+    };
+    anyhow::ensure!(fix.interval > 0, "The interval must be greater than zero");
+    anyhow::ensure!(
+        fix.latitude.abs() <= 90.0 && fix.longitude.abs() <= 180.0,
+        "{}, {} isn't a position on Earth",
+        fix.latitude,
+        fix.longitude
+    );
+    Ok(Box::new(Nmea { fix }))
+}
+
+impl Peer for Nmea {
+    /// the receiver doesn't listen to the firmware
+    fn feed(&mut self, _: &[u8]) -> Vec<Reply> {
+        Vec::new()
+    }
+
+    fn interval(&self) -> Option<Duration> {
+        Some(Duration::from_millis(self.fix.interval))
+    }
+
+    fn tick(&mut self) -> Vec<Reply> {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        let bytes = [self.gga(now), self.rmc(now)].concat().into_bytes();
+        vec![Reply {
+            delay: Duration::ZERO,
+            bytes,
+        }]
+    }
+}
+
+impl Nmea {
+    fn gga(&self, now: Duration) -> String {
+        let fix = &self.fix;
+        sentence(&format!(
+            "GPGGA,{},{},{},{},{:02},0.9,{:.1},M,0.0,M,,",
+            time_of_day(now),
+            latitude(fix.latitude),
+            longitude(fix.longitude),
+            fix.valid as u8,
+            if fix.valid { fix.satellites } else { 0 },
+            fix.altitude,
+        ))
+    }
+
+    fn rmc(&self, now: Duration) -> String {
+        let fix = &self.fix;
+        sentence(&format!(
+            "GPRMC,{},{},{},{},{:.1},{:.1},{},,,{}",
+            time_of_day(now),
+            if fix.valid { 'A' } else { 'V' },
+            latitude(fix.latitude),
+            longitude(fix.longitude),
+            fix.speed,
+            fix.course,
+            date(now),
+            if fix.valid { 'A' } else { 'N' },
+        ))
+    }
+}
+
+/// `$<body>*<checksum>` and a line ending
+fn sentence(body: &str) -> String {
+    let checksum = body.bytes().fold(0, |checksum, byte| checksum ^ byte);
+    format!("${}*{:02X}\r\n", body, checksum)
+}
+
+/// `ddmm.mmmm,N`
+fn latitude(degrees: f64) -> String {
+    let hemisphere = if degrees < 0.0 { 'S' } else { 'N' };
+    format!("{},{}", degrees_minutes(degrees, 2), hemisphere)
+}
+
+/// `dddmm.mmmm,E`
+fn longitude(degrees: f64) -> String {
+    let hemisphere = if degrees < 0.0 { 'W' } else { 'E' };
+    format!("{},{}", degrees_minutes(degrees, 3), hemisphere)
+}
+
+fn degrees_minutes(degrees: f64, width: usize) -> String {
+    // in ten thousandths of a minute, so rounding can't produce 60 minutes
+    let total = (degrees.abs() * 60.0 * 10_000.0).round() as u64;
+    let (whole, minutes) = (total / 600_000, total % 600_000);
+    format!(
+        "{:0width$}{:02}.{:04}",
+        whole,
+        minutes / 10_000,
+        minutes % 10_000,
+        width = width
+    )
+}
+
+/// `hhmmss.ss` in UTC
+fn time_of_day(now: Duration) -> String {
+    let seconds = now.as_secs() % 86_400;
+    format!(
+        "{:02}{:02}{:02}.{:02}",
+        seconds / 3600,
+        seconds / 60 % 60,
+        seconds % 60,
+        now.subsec_millis() / 10
+    )
+}
+
+/// `ddmmyy` in UTC
+fn date(now: Duration) -> String {
+    let (year, month, day) = civil_from_days((now.as_secs() / 86_400) as i64);
+    format!("{:02}{:02}{:02}", day, month, year % 100)
+}
@@ -0,0 +1,137 @@
+use super::{Peer, Reply};
+use anyhow::{Context, Result};
+use regex::bytes::Regex;
+use serde::Deserialize;
+use std::time::Duration;
+
+/// how much unmatched output is kept, older output is dropped
+const MAX_BUFFERED: usize = 4096;
+
+/// A script of rules like
+///
+/// ```toml
+/// state = "offline"
+///
+/// [[rule]]
+/// when = 'AT\+CONNECT\r'
+/// reply = "CONNECT\r\n"
+/// delay = 500
+/// state = "offline"
+/// next = "online"
+/// ```
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ScriptFile {
+    /// the state to start in
+    #[serde(default)]
+    state: String,
+    #[serde(default, rename = "rule")]
+    rules: Vec<RuleFile>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RuleFile {
+    /// regex matched against what the firmware sent since the last match
+    when: String,
+    /// sent back, with `$1` and `${name}` replaced by what the regex captured
+    #[serde(default)]
+    reply: String,
+    /// milliseconds to wait before replying
+    #[serde(default)]
+    delay: u64,
+    /// the rule only applies in this state
+    state: Option<String>,
+    /// the state to move to after matching
+    next: Option<String>,
+}
+
+struct Rule {
+    when: Regex,
+    reply: String,
+    delay: Duration,
+    state: Option<String>,
+    next: Option<String>,
+}
+
+/// Answers the firmware's output with the rules for the current state
+struct Script {
+    rules: Vec<Rule>,
+    state: String,
+    buffer: Vec<u8>,
+}
+
+pub fn open(path: Option<&str>) -> Result<Box<dyn super::Peer>> {
+    let path = path.unwrap_or_default();
+    let contents =
+        std::fs::read_to_string(path).with_context(|| format!("Failed to read {}", path))?;
+    Ok(Box::new(
+        Script::parse(&contents).with_context(|| format!("Invalid {}", path))?,
+    ))
+}
+
+impl Script {
+    fn parse(contents: &str) -> Result<Self> {
+        let file: ScriptFile = toml::from_str(contents)?;
+        let rules = file
+            .rules
+            .into_iter()
+            .enumerate()
+            .map(|(i, rule)| {
+                Ok(Rule {
+                    when: Regex::new(&rule.when)
+                        .with_context(|| format!("Invalid `when` in rule {}", i + 1))?,
+                    reply: rule.reply,
+                    delay: Duration::from_millis(rule.delay),
+                    state: rule.state,
+                    next: rule.next,
+                })
+            })
+            .collect::<Result<Vec<_>>>()?;
+        anyhow::ensure!(!rules.is_empty(), "There are no rules");
+        Ok(Self {
+            rules,
+            state: file.state,
+            buffer: Vec::new(),
+        })
+    }
+}
+
+impl Peer for Script {
+    fn feed(&mut self, bytes: &[u8]) -> Vec<Reply> {
+        self.buffer.extend_from_slice(bytes);
+        let mut replies = Vec::new();
+        loop {
+            let matched = self
+                .rules
+                .iter()
+                .filter(|rule| rule.state.as_ref().is_none_or(|s| *s == self.state))
+                .filter_map(|rule| Some((rule, rule.when.captures(&self.buffer)?)))
+                // the earliest output is answered first, then the earliest rule
+                .min_by_key(|(_, captures)| captures.get(0).map_or(0, |m| m.start()));
+            let Some((rule, captures)) = matched else {
+                break;
+            };
+            let mut reply = Vec::new();
+            captures.expand(rule.reply.as_bytes(), &mut reply);
+            let end = captures.get(0).map_or(0, |m| m.end());
+            if !reply.is_empty() {
+                replies.push(Reply {
+                    delay: rule.delay,
+                    bytes: reply,
+                });
+            }
+            if let Some(next) = &rule.next {
+                self.state = next.clone();
+            }
+            self.buffer.drain(..end);
+            if end == 0 {
+                break; /* a rule matching nothing would match forever */
+            }
+        }
+        if self.buffer.len() > MAX_BUFFERED {
+            self.buffer.drain(..self.buffer.len() - MAX_BUFFERED);
+        }
+        replies
+    }
+}
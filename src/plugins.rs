@@ -0,0 +1,237 @@
+//! Handlers for message types wokwi-server doesn't know about, like pin events, network frames or
+//! custom chips, added without changing it. Code built into the server adds them with
+//! [`register`], and with the `dynamic-plugins` feature they are loaded from shared libraries
+//! given with `--plugin`. The handlers built into the server come first, so a plugin can't take
+//! over their message types
+
+use crate::error::Result;
+use crate::router::Outbox;
+use serde_json::Value;
+use std::sync::{Arc, Mutex};
+
+/// Handles the messages from the simulator with the types it names
+pub trait MessageHandlerPlugin: Send {
+    /// shown in the server's output
+    fn name(&self) -> &str;
+
+    /// the `type`s of the messages to pass to [`handle`](Self::handle)
+    fn message_types(&self) -> Vec<String>;
+
+    /// Handle a message, queueing anything to send back in `out`. A failure is reported by the
+    /// router as a [`Protocol`](crate::error::WokwiServerError::Protocol) error naming the plugin
+    fn handle(&mut self, message: &Value, out: &mut Outbox) -> Result<()>;
+}
+
+/// Makes a plugin for each connection, so every simulation starts with fresh state
+pub type Factory = Arc<dyn Fn() -> Box<dyn MessageHandlerPlugin> + Send + Sync>;
+
+static REGISTRY: Mutex<Vec<Factory>> = Mutex::new(Vec::new());
+
+/// Add a plugin to every later connection
+pub fn register(factory: impl Fn() -> Box<dyn MessageHandlerPlugin> + Send + Sync + 'static) {
+    REGISTRY.lock().unwrap().push(Arc::new(factory));
+}
+
+/// A fresh instance of every registered plugin, for a new connection
+pub fn instantiate() -> Vec<Box<dyn MessageHandlerPlugin>> {
+    REGISTRY.lock().unwrap().iter().map(|f| f()).collect()
+}
+
+/// Version of the C interface of plugin libraries, which they return from
+/// `wokwi_plugin_abi_version`
+pub const ABI_VERSION: u32 = 1;
+
+/// Load a plugin from a shared library and register it, returning its name and message types.
+/// The library exports these functions, passing messages as nul terminated JSON:
+///
+/// ```c
+/// uint32_t wokwi_plugin_abi_version(void);      // ABI_VERSION
+/// const char *wokwi_plugin_message_types(void); // a JSON array of strings, never freed
+/// void *wokwi_plugin_new(void);                  // state for a connection, used from any thread
+/// char *wokwi_plugin_handle(void *state, const char *message); // NULL, or a JSON array of
+///                                                // messages for the simulator
+/// void wokwi_plugin_free(char *reply);           // frees what wokwi_plugin_handle returned
+/// void wokwi_plugin_drop(void *state);           // the connection has closed
+/// ```
+#[cfg(feature = "dynamic-plugins")]
+pub fn load(path: &std::path::Path) -> Result<(String, Vec<String>)> {
+    #[cfg(unix)]
+    return dynamic::load(path);
+    #[cfg(not(unix))]
+    crate::error::bail!(
+        Protocol,
+        "Can't load {}, plugins can only be loaded on unix",
+        path.display()
+    );
+}
+
+#[cfg(all(feature = "dynamic-plugins", unix))]
+mod dynamic {
+    use super::{MessageHandlerPlugin, ABI_VERSION};
+    use crate::error::{bail, Result, WokwiServerError};
+    use crate::router::Outbox;
+    use serde_json::Value;
+    use std::ffi::{c_char, c_void, CStr, CString};
+    use std::os::unix::ffi::OsStrExt;
+    use std::path::Path;
+    use std::sync::Arc;
+
+    type AbiVersion = extern "C" fn() -> u32;
+    type MessageTypes = extern "C" fn() -> *const c_char;
+    type New = extern "C" fn() -> *mut c_void;
+    type Handle = extern "C" fn(*mut c_void, *const c_char) -> *mut c_char;
+    type Free = extern "C" fn(*mut c_char);
+    type DropState = extern "C" fn(*mut c_void);
+
+    /// The functions a plugin library exports
+    struct Functions {
+        name: String,
+        types: Vec<String>,
+        new: New,
+        handle: Handle,
+        free: Free,
+        drop: DropState,
+    }
+
+    pub fn load(path: &Path) -> Result<(String, Vec<String>)> {
+        let Ok(file) = CString::new(path.as_os_str().as_bytes()) else {
+            bail!(
+                Protocol,
+                "Can't load plugin {}, its path has a nul byte",
+                path.display()
+            );
+        };
+        // SAFETY: `file` is nul terminated. The library is never closed, as its functions are
+        // used until the server exits
+        let library = unsafe { libc::dlopen(file.as_ptr(), libc::RTLD_NOW | libc::RTLD_LOCAL) };
+        if library.is_null() {
+            bail!(
+                Protocol,
+                "Failed to load plugin {}: {}",
+                path.display(),
+                dlerror()
+            );
+        }
+        let symbol = |name: &str| -> Result<*mut c_void> {
+            let symbol = CString::new(name).expect("symbol names have no nul bytes");
+            // SAFETY: `library` was opened above and `symbol` is nul terminated
+            let address = unsafe { libc::dlsym(library, symbol.as_ptr()) };
+            if address.is_null() {
+                bail!(
+                    Protocol,
+                    "Plugin {} doesn't export {}",
+                    path.display(),
+                    name
+                );
+            }
+            Ok(address)
+        };
+
+        // SAFETY: the interface documented on `load` gives these symbols these signatures
+        let functions = unsafe {
+            let abi_version =
+                std::mem::transmute::<*mut c_void, AbiVersion>(symbol("wokwi_plugin_abi_version")?);
+            let abi_version = abi_version();
+            if abi_version != ABI_VERSION {
+                bail!(
+                    Protocol,
+                    "Plugin {} is built for version {} of the plugin interface, wokwi-server uses version {}",
+                    path.display(),
+                    abi_version,
+                    ABI_VERSION
+                );
+            }
+            let message_types = std::mem::transmute::<*mut c_void, MessageTypes>(symbol(
+                "wokwi_plugin_message_types",
+            )?);
+            let types = CStr::from_ptr(message_types()).to_string_lossy();
+            Functions {
+                name: path
+                    .file_stem()
+                    .map_or_else(String::new, |s| s.to_string_lossy().into_owned()),
+                types: serde_json::from_str(&types).map_err(|e| {
+                    WokwiServerError::Protocol(format!(
+                        "Plugin {} gave message types which aren't a JSON array of strings: {}: {}",
+                        path.display(),
+                        types,
+                        e
+                    ))
+                })?,
+                new: std::mem::transmute::<*mut c_void, New>(symbol("wokwi_plugin_new")?),
+                handle: std::mem::transmute::<*mut c_void, Handle>(symbol("wokwi_plugin_handle")?),
+                free: std::mem::transmute::<*mut c_void, Free>(symbol("wokwi_plugin_free")?),
+                drop: std::mem::transmute::<*mut c_void, DropState>(symbol("wokwi_plugin_drop")?),
+            }
+        };
+        let loaded = (functions.name.clone(), functions.types.clone());
+        let functions = Arc::new(functions);
+        super::register(move || {
+            Box::new(Dynamic {
+                state: (functions.new)(),
+                functions: functions.clone(),
+            })
+        });
+        Ok(loaded)
+    }
+
+    fn dlerror() -> String {
+        // SAFETY: a non-null result is a nul terminated message owned by libc
+        unsafe {
+            let error = libc::dlerror();
+            match error.is_null() {
+                true => "unknown error".to_owned(),
+                false => CStr::from_ptr(error).to_string_lossy().into_owned(),
+            }
+        }
+    }
+
+    /// A plugin's state for one connection
+    struct Dynamic {
+        functions: Arc<Functions>,
+        state: *mut c_void,
+    }
+
+    // SAFETY: the interface requires plugins to allow their state to be used from any thread,
+    // which is only ever done by one at a time
+    unsafe impl Send for Dynamic {}
+
+    impl MessageHandlerPlugin for Dynamic {
+        fn name(&self) -> &str {
+            &self.functions.name
+        }
+
+        fn message_types(&self) -> Vec<String> {
+            self.functions.types.clone()
+        }
+
+        fn handle(&mut self, message: &Value, out: &mut Outbox) -> Result<()> {
+            // escaped by serde_json, so there are no nul bytes in it
+            let message = CString::new(message.to_string()).expect("JSON has no nul bytes");
+            let reply = (self.functions.handle)(self.state, message.as_ptr());
+            if reply.is_null() {
+                return Ok(());
+            }
+            // SAFETY: a non-null reply is a nul terminated string, which the plugin frees
+            let text = unsafe { CStr::from_ptr(reply) }
+                .to_string_lossy()
+                .into_owned();
+            (self.functions.free)(reply);
+            let replies: Vec<Value> = serde_json::from_str(&text).map_err(|e| {
+                WokwiServerError::Protocol(format!(
+                    "Plugin {} replied with something other than a JSON array of messages: {}: {}",
+                    self.functions.name, text, e
+                ))
+            })?;
+            for reply in &replies {
+                out.send_to_simulator(reply)?;
+            }
+            Ok(())
+        }
+    }
+
+    impl Drop for Dynamic {
+        fn drop(&mut self) {
+            (self.functions.drop)(self.state);
+        }
+    }
+}
@@ -0,0 +1,143 @@
+//! Finding which process is listening on a port, to explain why the server couldn't bind it
+
+use std::process::{Command, Stdio};
+
+/// A process listening on a TCP port
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PortOwner {
+    pub name: String,
+    pub pid: u32,
+}
+
+impl std::fmt::Display for PortOwner {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} (pid {})", self.name, self.pid)
+    }
+}
+
+/// Best effort lookup of the process listening on `port`, `None` if it can't be found, e.g.
+/// because it belongs to another user
+pub fn find(port: u16) -> Option<PortOwner> {
+    #[cfg(target_os = "linux")]
+    if let Some(owner) = procfs::find(port) {
+        return Some(owner);
+    }
+    #[cfg(unix)]
+    {
+        lsof(port)
+    }
+    #[cfg(windows)]
+    {
+        netstat(port)
+    }
+    #[cfg(not(any(unix, windows)))]
+    {
+        let _ = port;
+        None
+    }
+}
+
+/// The output of a command, if it could be run and succeeded
+fn output(program: &str, args: &[&str]) -> Option<String> {
+    let output = Command::new(program)
+        .args(args)
+        .stdin(Stdio::null())
+        .stderr(Stdio::null())
+        .output()
+        .ok()?;
+    output
+        .status
+        .success()
+        .then(|| String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+#[cfg(unix)]
+fn lsof(port: u16) -> Option<PortOwner> {
+    let tcp = format!("-iTCP:{}", port);
+    // one field per line, `p<pid>` followed by `c<command>`
+    let fields = output("lsof", &["-nP", &tcp, "-sTCP:LISTEN", "-Fpc"])?;
+    let mut pid = None;
+    for line in fields.lines() {
+        if let Some(p) = line.strip_prefix('p') {
+            pid = p.parse().ok();
+        } else if let (Some(name), Some(pid)) = (line.strip_prefix('c'), pid) {
+            return Some(PortOwner {
+                name: name.to_owned(),
+                pid,
+            });
+        }
+    }
+    None
+}
+
+#[cfg(windows)]
+fn netstat(port: u16) -> Option<PortOwner> {
+    let suffix = format!(":{}", port);
+    let pid = output("netstat", &["-ano", "-p", "TCP"])?
+        .lines()
+        .map(|line| line.split_whitespace().collect::<Vec<_>>())
+        .find(|fields| {
+            matches!(fields.as_slice(), ["TCP", local, _, "LISTENING", _] if local.ends_with(&suffix))
+        })
+        .and_then(|fields| fields[4].parse().ok())?;
+    let filter = format!("PID eq {}", pid);
+    // `"wokwi-server.exe","1234",...`
+    let tasks = output("tasklist", &["/FI", &filter, "/FO", "CSV", "/NH"])?;
+    let name = tasks.split(',').next()?.trim().trim_matches('"');
+    Some(PortOwner {
+        name: name.strip_suffix(".exe").unwrap_or(name).to_owned(),
+        pid,
+    })
+}
+
+#[cfg(target_os = "linux")]
+mod procfs {
+    use super::PortOwner;
+    use std::fs;
+
+    /// `st` of a listening socket in /proc/net/tcp
+    const TCP_LISTEN: &str = "0A";
+
+    /// Find the socket's inode in the kernel's socket tables, then the process with it open
+    pub fn find(port: u16) -> Option<PortOwner> {
+        let inode = ["/proc/net/tcp", "/proc/net/tcp6"]
+            .iter()
+            .filter_map(|table| fs::read_to_string(table).ok())
+            .find_map(|table| listening_inode(&table, port))?;
+        let socket = format!("socket:[{}]", inode);
+        for process in fs::read_dir("/proc").ok()?.flatten() {
+            let Some(pid) = process.file_name().to_str().and_then(|p| p.parse().ok()) else {
+                continue;
+            };
+            // other users' file descriptors can't be read
+            let Ok(fds) = fs::read_dir(process.path().join("fd")) else {
+                continue;
+            };
+            let has_socket = fds.flatten().any(|fd| {
+                fs::read_link(fd.path()).is_ok_and(|link| link.as_os_str() == socket.as_str())
+            });
+            if has_socket {
+                let name = fs::read_to_string(process.path().join("comm")).ok()?;
+                return Some(PortOwner {
+                    name: name.trim().to_owned(),
+                    pid,
+                });
+            }
+        }
+        None
+    }
+
+    /// The inode of the socket listening on `port`, from lines like
+    /// `0: 0100007F:2334 00000000:0000 0A ... 0 12345 ...`
+    fn listening_inode(table: &str, port: u16) -> Option<String> {
+        let suffix = format!(":{:04X}", port);
+        table.lines().skip(1).find_map(|line| {
+            let fields: Vec<_> = line.split_whitespace().collect();
+            let listening = fields.len() > 9
+                && fields[1].ends_with(&suffix)
+                && fields[3] == TCP_LISTEN
+                && fields[9] != "0";
+            listening.then(|| fields[9].to_owned())
+        })
+    }
+}
@@ -0,0 +1,225 @@
+//! A sampling profiler: the firmware is stopped now and then, its call stack is read through the
+//! simulator's GDB stub, and the stacks are counted up in the folded format of flamegraph tools
+
+use crate::{gdb, target_description};
+use espflash::Chip;
+use std::collections::{BTreeMap, HashMap};
+use std::io::{self, Write};
+use xmas_elf::sections::SectionData;
+use xmas_elf::symbol_table::{Entry, Type};
+use xmas_elf::ElfFile;
+
+/// stacks deeper than this are cut short
+const MAX_DEPTH: usize = 32;
+
+/// The functions of the firmware, to name the addresses in a stack
+#[derive(Debug, Default)]
+pub struct Symbols {
+    /// start address, size and demangled name, sorted by address
+    functions: Vec<(u32, u32, String)>,
+}
+
+impl Symbols {
+    pub fn find(elf: &ElfFile) -> Self {
+        let mut functions = Vec::new();
+        for section in elf.section_iter() {
+            let Ok(SectionData::SymbolTable32(entries)) = section.get_data(elf) else {
+                continue;
+            };
+            for entry in entries {
+                if !matches!(entry.get_type(), Ok(Type::Func)) || entry.value() == 0 {
+                    continue;
+                }
+                let Ok(name) = entry.get_name(elf) else {
+                    continue;
+                };
+                let name = format!("{:#}", rustc_demangle::demangle(name));
+                functions.push((entry.value() as u32, entry.size() as u32, name));
+            }
+        }
+        functions.sort();
+        Self { functions }
+    }
+
+    /// The function `addr` is in, its address in hex if it isn't in one
+    pub fn name(&self, addr: u32) -> String {
+        self.function(addr)
+            .map_or_else(|| format!("{:#010x}", addr), str::to_owned)
+    }
+
+    /// The function `addr` is in
+    pub fn function(&self, addr: u32) -> Option<&str> {
+        let index = self
+            .functions
+            .partition_point(|&(start, _, _)| start <= addr);
+        match &self.functions[index.checked_sub(1)?] {
+            (start, size, name) if addr - start < (*size).max(1) => Some(name),
+            _ => None,
+        }
+    }
+}
+
+/// Stacks and how many times each was seen
+#[derive(Debug, Default)]
+pub struct Folded {
+    stacks: BTreeMap<String, u64>,
+    samples: u64,
+}
+
+impl Folded {
+    /// Count a stack of return addresses, the innermost first
+    pub fn add(&mut self, stack: &[u32], symbols: &Symbols) {
+        let names: Vec<_> = stack.iter().rev().map(|&pc| symbols.name(pc)).collect();
+        // `;` separates frames, and the count follows the last space
+        let folded = names.join(";").replace(' ', "_");
+        *self.stacks.entry(folded).or_default() += 1;
+        self.samples += 1;
+    }
+
+    pub fn samples(&self) -> u64 {
+        self.samples
+    }
+
+    /// One line per stack, e.g. `main;app_main;compute 12`
+    pub fn write(&self, mut out: impl Write) -> io::Result<()> {
+        for (stack, count) in &self.stacks {
+            writeln!(out, "{} {}", stack, count)?;
+        }
+        Ok(())
+    }
+}
+
+/// What a sample needs next
+#[derive(Debug, PartialEq, Eq)]
+pub enum Step {
+    /// stop the firmware, passing the stop reply to [`Sample::feed`]
+    Halt,
+    /// read the registers with a `g` packet
+    Registers,
+    /// the contents of memory
+    Read { addr: u32, len: u32 },
+    /// the call stack, the innermost address first
+    Done(Vec<u32>),
+}
+
+enum Stage {
+    Halt,
+    Registers,
+    /// the caller's a0 and a1, spilled below the stack pointer
+    Spilled,
+}
+
+/// Reads one call stack
+pub struct Sample {
+    chip: Chip,
+    stage: Stage,
+    stack: Vec<u32>,
+}
+
+impl Sample {
+    /// Start sampling the firmware, `None` if the chip's registers aren't known
+    pub fn start(chip: Chip) -> Option<(Self, Step)> {
+        target_description::layout(chip)?;
+        let sample = Self {
+            chip,
+            stage: Stage::Halt,
+            stack: Vec::new(),
+        };
+        Some((sample, Step::Halt))
+    }
+
+    /// Carry on with the simulator's (unframed) reply to the last step. `None` if it isn't the
+    /// stop reply being waited for, which belongs to someone else
+    pub fn feed(&mut self, reply: &str) -> Option<Step> {
+        match self.stage {
+            Stage::Halt if !reply.starts_with(['S', 'T']) => None,
+            Stage::Halt => {
+                self.stage = Stage::Registers;
+                Some(Step::Registers)
+            }
+            Stage::Registers => {
+                let packet = gdb::decode_hex(reply).unwrap_or_default();
+                let registers = target_description::parse_registers(self.chip, &packet);
+                let Some(&pc) = registers.get("pc") else {
+                    return Some(Step::Done(Vec::new()));
+                };
+                self.stack.push(pc);
+                Some(match self.chip {
+                    // without frame pointers, only the return address is known, and it is
+                    // only the caller's while the function hasn't called another
+                    Chip::Esp32c2 | Chip::Esp32c3 => {
+                        let ra = registers.get("ra").copied().unwrap_or_default();
+                        if ra != 0 && ra != pc {
+                            self.stack.push(ra);
+                        }
+                        Step::Done(std::mem::take(&mut self.stack))
+                    }
+                    _ => self.unwind_windows(&registers),
+                })
+            }
+            Stage::Spilled => {
+                let memory = gdb::decode_hex(reply).unwrap_or_default();
+                let (Some(a0), Some(sp)) = (word(&memory, 0), word(&memory, 4)) else {
+                    return Some(Step::Done(std::mem::take(&mut self.stack)));
+                };
+                Some(self.unwind_memory(a0, sp))
+            }
+        }
+    }
+
+    /// Follow the Xtensa register windows which are still live in the register file, then
+    /// carry on through memory from the first frame that has been spilled
+    fn unwind_windows(&mut self, registers: &HashMap<String, u32>) -> Step {
+        let register = |name: &str| registers.get(name).copied().unwrap_or_default();
+        let ar = |window: u32, i: u32| register(&format!("ar{}", (window * 4 + i) % 64));
+        let windowstart = register("windowstart");
+        let mut window = register("windowbase") % 16;
+        let (mut a0, mut sp) = (ar(window, 0), ar(window, 1));
+        while let Some(caller) = self.caller(a0) {
+            self.stack.push(caller);
+            let increment = a0 >> 30;
+            let caller_window = (window + 16 - increment) % 16;
+            if windowstart & (1 << caller_window) == 0 {
+                // the caller's a0 and a1 were saved below this frame's stack pointer
+                self.stage = Stage::Spilled;
+                return Step::Read {
+                    addr: sp.wrapping_sub(16),
+                    len: 8,
+                };
+            }
+            window = caller_window;
+            (a0, sp) = (ar(window, 0), ar(window, 1));
+        }
+        Step::Done(std::mem::take(&mut self.stack))
+    }
+
+    /// Carry on from a frame read from memory, whose frames beyond are all in memory too
+    fn unwind_memory(&mut self, a0: u32, sp: u32) -> Step {
+        match self.caller(a0) {
+            Some(caller) if sp != 0 => {
+                self.stack.push(caller);
+                Step::Read {
+                    addr: sp.wrapping_sub(16),
+                    len: 8,
+                }
+            }
+            _ => Step::Done(std::mem::take(&mut self.stack)),
+        }
+    }
+
+    /// The address `a0` returns to, `None` at the end of the stack. The top two bits of a
+    /// windowed return address are the size of the call, and the rest is in the same 1GiB as
+    /// the code that made it
+    fn caller(&self, a0: u32) -> Option<u32> {
+        let pc = *self.stack.last()?;
+        let caller = (a0 & 0x3fff_ffff) | (pc & 0xc000_0000);
+        let is_code = caller >= 0x4000_0000;
+        (a0 >> 30 != 0 && is_code && self.stack.len() < MAX_DEPTH).then_some(caller)
+    }
+}
+
+/// The little endian word at `offset` in `memory`
+fn word(memory: &[u8], offset: usize) -> Option<u32> {
+    let bytes = memory.get(offset..offset + 4)?;
+    Some(u32::from_le_bytes(bytes.try_into().ok()?))
+}
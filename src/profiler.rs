@@ -0,0 +1,78 @@
+//! `--cpu-profile`: call stacks sampled from the firmware, written out in the folded format when
+//! the simulation ends
+
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use wokwi_server::profile::{Folded, Symbols};
+
+/// the default milliseconds between samples
+pub const DEFAULT_INTERVAL: u64 = 20;
+
+pub struct Profiler {
+    path: PathBuf,
+    /// the session the samples are from, to tell which in the output
+    label: String,
+    pub interval: Duration,
+    symbols: Symbols,
+    folded: Folded,
+}
+
+impl Profiler {
+    pub fn new(path: &Path, interval: Duration, elf: &Path, label: &str) -> Self {
+        let mut profiler = Self {
+            path: path.to_owned(),
+            label: label.to_owned(),
+            interval,
+            symbols: Symbols::default(),
+            folded: Folded::default(),
+        };
+        profiler.load(elf);
+        profiler
+    }
+
+    /// Name the addresses of samples from now on with the functions of `elf`
+    pub fn load(&mut self, elf: &Path) {
+        let bytes = std::fs::read(elf).unwrap_or_default();
+        self.symbols = match xmas_elf::ElfFile::new(&bytes) {
+            Ok(elf) => Symbols::find(&elf),
+            Err(_) => Symbols::default(),
+        };
+    }
+
+    /// Count a call stack, the innermost address first
+    pub fn add(&mut self, stack: &[u32]) {
+        if !stack.is_empty() {
+            self.folded.add(stack, &self.symbols);
+        }
+    }
+
+    fn write(&self) -> anyhow::Result<()> {
+        let mut out = std::io::BufWriter::new(std::fs::File::create(&self.path)?);
+        self.folded.write(&mut out)?;
+        out.flush()?;
+        Ok(())
+    }
+}
+
+impl Drop for Profiler {
+    fn drop(&mut self) {
+        if self.folded.samples() == 0 {
+            return;
+        }
+        match self.write() {
+            Ok(()) => println!(
+                "[{}] Profile of {} samples written to {}",
+                self.label,
+                self.folded.samples(),
+                self.path.display()
+            ),
+            Err(e) => println!(
+                "[{}] Failed to write the profile to {}: {:#}",
+                self.label,
+                self.path.display(),
+                e
+            ),
+        }
+    }
+}
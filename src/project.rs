@@ -0,0 +1,180 @@
+use crate::custom_chips::ChipSpec;
+use anyhow::{Context, Result};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+pub const CONFIG_FILE: &str = "wokwi.toml";
+pub const SDKCONFIG_FILE: &str = "sdkconfig";
+pub const DIAGRAM_FILE: &str = "diagram.json";
+
+/// The project configuration shared with the Wokwi VS Code extension, e.g.
+///
+/// ```toml
+/// [wokwi]
+/// version = 1
+/// elf = "target/xtensa-esp32-espidf/debug/app"
+/// id = "345932416223806035"
+///
+/// [[chip]]
+/// name = "inverter"
+/// binary = "chips/inverter.chip.wasm"
+/// ```
+#[derive(Debug, Deserialize)]
+struct Config {
+    wokwi: WokwiSection,
+    /// custom chips, the definition of each is next to its binary
+    #[serde(default)]
+    chip: Vec<ChipSection>,
+}
+
+#[derive(Debug, Deserialize)]
+struct WokwiSection {
+    elf: Option<PathBuf>,
+    /// the Wokwi project to simulate on, only read by wokwi-server
+    id: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ChipSection {
+    name: String,
+    binary: PathBuf,
+}
+
+/// A `wokwi.toml` found for the project
+pub struct ProjectConfig {
+    /// the directory containing `wokwi.toml`, which its paths are relative to
+    pub dir: PathBuf,
+    pub elf: Option<PathBuf>,
+    pub id: Option<String>,
+    pub chips: Vec<ChipSpec>,
+}
+
+impl ProjectConfig {
+    /// Look for `wokwi.toml` in `dir` and its parents
+    pub fn find(dir: &Path) -> Result<Option<Self>> {
+        let Some(dir) = dir.ancestors().find(|d| d.join(CONFIG_FILE).is_file()) else {
+            return Ok(None);
+        };
+        let path = dir.join(CONFIG_FILE);
+        let contents = std::fs::read_to_string(&path)
+            .with_context(|| format!("Failed to read {}", path.display()))?;
+        let config: Config =
+            toml::from_str(&contents).with_context(|| format!("Invalid {}", path.display()))?;
+        Ok(Some(Self {
+            elf: config.wokwi.elf.map(|elf| resolve(dir, &elf)),
+            id: config.wokwi.id,
+            chips: config
+                .chip
+                .into_iter()
+                .map(|chip| ChipSpec {
+                    name: chip.name,
+                    binary: resolve(dir, &chip.binary),
+                })
+                .collect(),
+            dir: dir.to_owned(),
+        }))
+    }
+}
+
+/// Set the project `id` in the `wokwi.toml` in `dir`, creating it if needed, and keeping the rest
+/// of the file as it is
+pub fn set_project_id(dir: &Path, id: &str) -> Result<PathBuf> {
+    let path = dir.join(CONFIG_FILE);
+    let line = format!("id = \"{}\"", id);
+    let contents = match std::fs::read_to_string(&path) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => "[wokwi]\nversion = 1\n".to_owned(),
+        Err(e) => return Err(e).with_context(|| format!("Failed to read {}", path.display())),
+    };
+    toml::from_str::<Config>(&contents).with_context(|| format!("Invalid {}", path.display()))?;
+
+    let mut lines: Vec<String> = contents.lines().map(str::to_owned).collect();
+    let section = lines
+        .iter()
+        .position(|l| l.trim() == "[wokwi]")
+        .unwrap_or(0);
+    let end = lines[section + 1..]
+        .iter()
+        .position(|l| l.trim_start().starts_with('['))
+        .map_or(lines.len(), |i| section + 1 + i);
+    let existing = (section + 1..end).find(|&i| {
+        lines[i]
+            .split_once('=')
+            .is_some_and(|(key, _)| key.trim() == "id")
+    });
+    match existing {
+        Some(i) => lines[i] = line,
+        None => {
+            // after the last key, rather than any blank lines before the next section
+            let last = (section..end)
+                .rev()
+                .find(|&i| !lines[i].trim().is_empty())
+                .unwrap_or(section);
+            lines.insert(last + 1, line);
+        }
+    }
+    std::fs::write(&path, lines.join("\n") + "\n")
+        .with_context(|| format!("Failed to write {}", path.display()))?;
+    Ok(path)
+}
+
+/// The ESP-IDF configuration of the project, made of `CONFIG_NAME=value` lines
+pub struct Sdkconfig {
+    pub path: PathBuf,
+    values: HashMap<String, String>,
+}
+
+impl Sdkconfig {
+    /// Look for `sdkconfig` in `dir`, and in its parents up to the one containing `wokwi.toml` if
+    /// there is one, so the configuration of an unrelated project further up isn't picked up
+    pub fn find(dir: &Path) -> Result<Option<Self>> {
+        let depth = dir
+            .ancestors()
+            .position(|d| d.join(CONFIG_FILE).is_file())
+            .unwrap_or(0);
+        let Some(dir) = dir
+            .ancestors()
+            .take(depth + 1)
+            .find(|d| d.join(SDKCONFIG_FILE).is_file())
+        else {
+            return Ok(None);
+        };
+        let path = dir.join(SDKCONFIG_FILE);
+        let contents = std::fs::read_to_string(&path)
+            .with_context(|| format!("Failed to read {}", path.display()))?;
+        let values = contents
+            .lines()
+            .filter(|line| !line.starts_with('#'))
+            .filter_map(|line| line.split_once('='))
+            .map(|(name, value)| {
+                (
+                    name.trim().to_owned(),
+                    value.trim().trim_matches('"').to_owned(),
+                )
+            })
+            .collect();
+        Ok(Some(Self { path, values }))
+    }
+
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.values.get(name).map(String::as_str)
+    }
+}
+
+/// Expand a leading `~` to the home directory
+pub fn expand_home(path: &Path) -> PathBuf {
+    let home = || std::env::var_os("HOME").or_else(|| std::env::var_os("USERPROFILE"));
+    match path.strip_prefix("~") {
+        Ok(rest) => match home() {
+            Some(home) => PathBuf::from(home).join(rest),
+            None => path.to_owned(),
+        },
+        Err(_) => path.to_owned(),
+    }
+}
+
+/// Make `path` absolute, treating it as relative to `base`
+pub fn resolve(base: &Path, path: &Path) -> PathBuf {
+    base.join(expand_home(path))
+}
@@ -0,0 +1,213 @@
+//! `.wokwi-server.toml`, defaults for the command line kept with the project, written by
+//! `wokwi-server config set` and meant to be committed, e.g.
+//!
+//! ```toml
+//! chip = "esp32c3"
+//! id = "123456789"
+//! port = 9012
+//! gdb_port = 9333
+//! uart_sinks = ["stdout", "file:uart.log"]
+//! ```
+//!
+//! Each setting is used when its option isn't given on the command line of `run`.
+
+use crate::project;
+use crate::sinks;
+use anyhow::{Context, Result};
+use espflash::Chip;
+use serde::{Deserialize, Serialize};
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
+
+pub const SETTINGS_FILE: &str = ".wokwi-server.toml";
+
+/// the settings, with the long and short option each is a default for
+const KEYS: &[(&str, &str, Option<&str>)] = &[
+    ("chip", "--chip", Some("-c")),
+    ("id", "--id", Some("-i")),
+    ("port", "--port", None),
+    ("gdb_port", "--gdb-port", None),
+    ("uart_sinks", "--uart-sink", None),
+];
+
+/// Change the settings of the project in `.wokwi-server.toml`
+#[derive(clap::Args, Debug)]
+pub struct ConfigArgs {
+    #[clap(subcommand)]
+    action: Action,
+}
+
+#[derive(clap::Subcommand, Debug)]
+enum Action {
+    /// set a setting: chip, id, port, gdb_port or uart_sinks, which takes several values
+    Set {
+        key: String,
+        #[clap(required = true)]
+        values: Vec<String>,
+    },
+    /// remove a setting, going back to the default of its option
+    Unset { key: String },
+    /// print the settings and the file they are in
+    List,
+}
+
+#[derive(Debug, Default, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+struct Settings {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    chip: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    port: Option<u16>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    gdb_port: Option<u16>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    uart_sinks: Option<Vec<String>>,
+}
+
+impl Settings {
+    fn load(path: &Path) -> Result<Self> {
+        let contents = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read {}", path.display()))?;
+        toml::from_str(&contents).with_context(|| format!("Invalid {}", path.display()))
+    }
+
+    /// The command line values of the setting `key`, if it is set
+    fn values(&self, key: &str) -> Option<Vec<String>> {
+        match key {
+            "chip" => self.chip.clone().map(|chip| vec![chip]),
+            "id" => self.id.clone().map(|id| vec![id]),
+            "port" => self.port.map(|port| vec![port.to_string()]),
+            "gdb_port" => self.gdb_port.map(|port| vec![port.to_string()]),
+            "uart_sinks" => self.uart_sinks.clone(),
+            _ => None,
+        }
+    }
+
+    fn set(&mut self, key: &str, values: Vec<String>) -> Result<()> {
+        let single = || match values.as_slice() {
+            [value] => Ok(value.clone()),
+            _ => Err(anyhow::anyhow!("{} takes a single value", key)),
+        };
+        let port = |value: String| {
+            value
+                .parse::<u16>()
+                .map_err(|_| anyhow::anyhow!("`{}` is not a valid port", value))
+        };
+        match key {
+            "chip" => {
+                let chip = single()?;
+                chip.parse::<Chip>()
+                    .map_err(|_| anyhow::anyhow!("`{}` is not a chip", chip))?;
+                self.chip = Some(chip);
+            }
+            "id" => self.id = Some(single()?),
+            "port" => self.port = Some(port(single()?)?),
+            "gdb_port" => self.gdb_port = Some(port(single()?)?),
+            "uart_sinks" => {
+                for spec in &values {
+                    sinks::parse_spec(spec).map_err(|e| anyhow::anyhow!(e))?;
+                }
+                self.uart_sinks = Some(values);
+            }
+            _ => unknown(key)?,
+        }
+        Ok(())
+    }
+
+    fn unset(&mut self, key: &str) -> Result<()> {
+        match key {
+            "chip" => self.chip = None,
+            "id" => self.id = None,
+            "port" => self.port = None,
+            "gdb_port" => self.gdb_port = None,
+            "uart_sinks" => self.uart_sinks = None,
+            _ => unknown(key)?,
+        }
+        Ok(())
+    }
+}
+
+fn unknown(key: &str) -> Result<()> {
+    let keys: Vec<_> = KEYS.iter().map(|(key, _, _)| *key).collect();
+    anyhow::bail!(
+        "Unknown setting {}, expected one of {}",
+        key,
+        keys.join(", ")
+    )
+}
+
+/// The settings file of the project in `dir`: the nearest `.wokwi-server.toml` in it or its
+/// parents, or a new one next to `wokwi.toml`, or in `dir` itself
+fn find(dir: &Path) -> PathBuf {
+    let existing = dir
+        .ancestors()
+        .find(|d| d.join(SETTINGS_FILE).is_file())
+        .or_else(|| {
+            dir.ancestors()
+                .find(|d| d.join(project::CONFIG_FILE).is_file())
+        });
+    existing.unwrap_or(dir).join(SETTINGS_FILE)
+}
+
+pub fn run(args: ConfigArgs) -> Result<i32> {
+    let path = find(&std::env::current_dir()?);
+    let mut settings = match path.is_file() {
+        true => Settings::load(&path)?,
+        false => Settings::default(),
+    };
+    match args.action {
+        Action::Set { key, values } => settings.set(&key, values)?,
+        Action::Unset { key } => settings.unset(&key)?,
+        Action::List => {
+            println!("# {}", path.display());
+            print!("{}", toml::to_string(&settings)?);
+            return Ok(0);
+        }
+    }
+    std::fs::write(&path, toml::to_string(&settings)?)
+        .with_context(|| format!("Failed to write {}", path.display()))?;
+    println!("Updated {}", path.display());
+    Ok(0)
+}
+
+/// Whether the command line gives the option `long`, or `short`, which may have its value attached
+fn given(args: &[OsString], long: &str, short: Option<&str>) -> bool {
+    args.iter().filter_map(|arg| arg.to_str()).any(|arg| {
+        arg == long
+            || arg
+                .strip_prefix(long)
+                .is_some_and(|rest| rest.starts_with('='))
+            || short.is_some_and(|short| arg.starts_with(short) && !arg.starts_with("--"))
+    })
+}
+
+/// Add the settings of the project in the current directory to the arguments of `run`, after
+/// `run` at `args[1]`, for the options the command line doesn't give
+pub fn apply(args: &mut Vec<OsString>) {
+    let Ok(cwd) = std::env::current_dir() else {
+        return;
+    };
+    let path = find(&cwd);
+    if !path.is_file() {
+        return;
+    }
+    let settings = match Settings::load(&path) {
+        Ok(settings) => settings,
+        Err(e) => {
+            println!("Warning: ignoring {:#}", e);
+            return;
+        }
+    };
+    let mut defaults = Vec::new();
+    for (key, long, short) in KEYS {
+        if given(args, long, *short) {
+            continue;
+        }
+        for value in settings.values(key).into_iter().flatten() {
+            defaults.push(OsString::from(format!("{}={}", long, value)));
+        }
+    }
+    args.splice(2.min(args.len())..2.min(args.len()), defaults);
+}
@@ -0,0 +1,266 @@
+use crate::error::{bail, Result, WokwiServerError};
+use crate::gdb;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::fmt;
+use std::str::FromStr;
+use tungstenite::protocol::WebSocketConfig;
+
+/// Oldest version of the wokwi embed protocol we can talk
+pub const MIN_PROTOCOL_VERSION: u32 = 1;
+/// Newest version of the wokwi embed protocol we can talk
+pub const MAX_PROTOCOL_VERSION: u32 = 1;
+
+/// Optional protocol features which this server knows how to use
+const SUPPORTED_CAPABILITIES: &[&str] = &[
+    "chunking",
+    "customChips",
+    "peripheralEvents",
+    "seed",
+    "timeControl",
+];
+
+/// Largest message sent to or accepted from a simulator which doesn't give its own limit,
+/// messages are sent as a single frame so this is also the largest frame
+pub const MAX_MESSAGE_SIZE: usize = 16 << 20;
+/// Smallest limit a simulator can ask for, anything less can't fit a chunk
+pub const MIN_MESSAGE_SIZE: usize = 1024;
+/// Room for everything in a `chunk` message except its data
+const CHUNK_OVERHEAD: usize = 128;
+
+/// Size limits for the websocket connection with the simulator
+pub fn websocket_config() -> WebSocketConfig {
+    WebSocketConfig {
+        max_message_size: Some(MAX_MESSAGE_SIZE),
+        max_frame_size: Some(MAX_MESSAGE_SIZE),
+        ..WebSocketConfig::default()
+    }
+}
+
+/// The protocol version to speak, the simulator's unless pinned with `--embed-protocol`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ProtocolPin {
+    /// the version the simulator announces, refusing versions we can't talk
+    #[default]
+    Auto,
+    /// this version whatever the simulator announces, for when a newer embed still understands
+    /// an older protocol, or announces a version it doesn't speak
+    Version(u32),
+}
+
+impl FromStr for ProtocolPin {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        if s == "auto" {
+            return Ok(ProtocolPin::Auto);
+        }
+        match s.trim_start_matches('v').parse() {
+            Ok(version) if (MIN_PROTOCOL_VERSION..=MAX_PROTOCOL_VERSION).contains(&version) => {
+                Ok(ProtocolPin::Version(version))
+            }
+            _ => Err(format!(
+                "expected `auto` or a protocol version from {} to {}",
+                MIN_PROTOCOL_VERSION, MAX_PROTOCOL_VERSION
+            )),
+        }
+    }
+}
+
+impl fmt::Display for ProtocolPin {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ProtocolPin::Auto => write!(f, "auto"),
+            ProtocolPin::Version(version) => write!(f, "{}", version),
+        }
+    }
+}
+
+/// The first message sent by the simulator after connecting
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Hello {
+    pub r#type: String,
+    /// absent in simulators predating protocol versioning, which speak version 1
+    #[serde(default)]
+    pub protocol_version: Option<u32>,
+    #[serde(default)]
+    pub app_version: Option<String>,
+    #[serde(default)]
+    pub capabilities: Vec<String>,
+    /// largest message the simulator can receive, if it is limited
+    #[serde(default)]
+    pub max_message_size: Option<usize>,
+    /// the version pinned with `--embed-protocol`, spoken instead of the announced one
+    #[serde(skip)]
+    pub pinned: Option<u32>,
+}
+
+/// Features both sides of the connection have agreed to use
+#[derive(Debug, Clone)]
+pub struct Capabilities {
+    /// whether the simulator reassembles messages split into `chunk` messages
+    pub chunking: bool,
+    /// whether the simulator loads custom chips sent in a `customChips` message
+    pub custom_chips: bool,
+    /// whether the simulator reports bus transactions to those who `subscribe` to them
+    pub peripheral_events: bool,
+    /// whether the simulator seeds its randomness from a `seed` message
+    pub seed: bool,
+    /// whether the simulator takes a `simSpeed` factor and can `runFor` a span of simulated time
+    pub time_control: bool,
+    /// largest message to send to the simulator
+    pub max_message_size: usize,
+    /// the protocol version spoken, which decides the shim messages go through
+    pub protocol_version: u32,
+}
+
+impl Default for Capabilities {
+    fn default() -> Self {
+        Self {
+            chunking: false,
+            custom_chips: false,
+            peripheral_events: false,
+            seed: false,
+            time_control: false,
+            max_message_size: MAX_MESSAGE_SIZE,
+            protocol_version: MAX_PROTOCOL_VERSION,
+        }
+    }
+}
+
+impl Capabilities {
+    /// The messages to send for `text`, split into `chunk` messages with the given id if it is
+    /// too large to send whole
+    pub fn frame(&self, text: String, id: u32) -> Result<Vec<String>> {
+        if text.len() <= self.max_message_size {
+            return Ok(vec![text]);
+        }
+        if !self.chunking {
+            bail!(
+                Protocol,
+                "A {} byte message is larger than the {} bytes the simulator accepts, and it can't receive messages in chunks",
+                text.len(),
+                self.max_message_size
+            );
+        }
+        // base64 turns every 3 bytes into 4
+        let per_chunk = (self.max_message_size - CHUNK_OVERHEAD) / 4 * 3;
+        let chunks: Vec<&[u8]> = text.as_bytes().chunks(per_chunk).collect();
+        Ok(chunks
+            .iter()
+            .enumerate()
+            .map(|(index, data)| {
+                json!({
+                    "type": "chunk",
+                    "id": id,
+                    "index": index,
+                    "count": chunks.len(),
+                    "data": base64::encode(data),
+                })
+                .to_string()
+            })
+            .collect())
+    }
+}
+
+impl Hello {
+    /// Parse and validate the hello message
+    pub fn parse(msg: &str) -> Result<Self> {
+        Self::parse_pinned(msg, ProtocolPin::Auto)
+    }
+
+    /// Parse and validate the hello message, speaking the pinned protocol version if there is one
+    /// whatever version the simulator announces
+    pub fn parse_pinned(msg: &str, pin: ProtocolPin) -> Result<Self> {
+        let mut hello: Hello = serde_json::from_str(msg).map_err(|e| {
+            WokwiServerError::Protocol(format!("Malformed hello message from simulator: {}", e))
+        })?;
+        if hello.r#type != "hello" {
+            bail!(
+                Protocol,
+                "Expected a hello message from simulator, got '{}'",
+                hello.r#type
+            );
+        }
+
+        let version = hello.announced_version();
+        if let ProtocolPin::Version(pinned) = pin {
+            hello.pinned = Some(pinned);
+        } else if !(MIN_PROTOCOL_VERSION..=MAX_PROTOCOL_VERSION).contains(&version) {
+            bail!(
+                Protocol,
+                "wokwi embed protocol v{} unsupported, this version of wokwi-server supports v{} to v{}. Try updating wokwi-server with `wokwi-server self-update`, or speak a supported version anyway with --embed-protocol.",
+                version,
+                MIN_PROTOCOL_VERSION,
+                MAX_PROTOCOL_VERSION
+            );
+        }
+        if let Some(size) = hello.max_message_size {
+            if size < MIN_MESSAGE_SIZE {
+                bail!(
+                    Protocol,
+                    "The simulator only accepts messages up to {} bytes, wokwi-server needs at least {}",
+                    size,
+                    MIN_MESSAGE_SIZE
+                );
+            }
+        }
+
+        Ok(hello)
+    }
+
+    /// The version spoken on the connection
+    pub fn protocol_version(&self) -> u32 {
+        self.pinned.unwrap_or_else(|| self.announced_version())
+    }
+
+    /// The version the simulator says it speaks
+    pub fn announced_version(&self) -> u32 {
+        self.protocol_version.unwrap_or(MIN_PROTOCOL_VERSION)
+    }
+
+    /// The capabilities offered by the simulator which we also support
+    pub fn negotiate(&self) -> Capabilities {
+        let offered = |name: &str| {
+            SUPPORTED_CAPABILITIES.contains(&name) && self.capabilities.iter().any(|c| c == name)
+        };
+        Capabilities {
+            chunking: offered("chunking"),
+            custom_chips: offered("customChips"),
+            peripheral_events: offered("peripheralEvents"),
+            seed: offered("seed"),
+            time_control: offered("timeControl"),
+            max_message_size: self
+                .max_message_size
+                .map_or(MAX_MESSAGE_SIZE, |size| size.min(MAX_MESSAGE_SIZE)),
+            protocol_version: self.protocol_version(),
+        }
+    }
+}
+
+/// The bytes of a `uartData` message
+pub fn uart_data(message: &Value) -> Result<Vec<u8>> {
+    message["bytes"]
+        .as_array()
+        .ok_or_else(|| WokwiServerError::Protocol("uartData without a bytes array".into()))?
+        .iter()
+        .map(|b| {
+            b.as_u64()
+                .and_then(|b| u8::try_from(b).ok())
+                .ok_or_else(|| {
+                    WokwiServerError::Protocol(format!("Invalid byte {} in uartData", b))
+                })
+        })
+        .collect()
+}
+
+/// The bytes of the response of a `gdbResponse` message, which the simulator sends as a binary
+/// string
+pub fn gdb_response(message: &Value) -> Result<Vec<u8>> {
+    let response = message["response"]
+        .as_str()
+        .ok_or_else(|| WokwiServerError::Gdb("gdbResponse without a response".into()))?;
+    gdb::from_binary_string(response)
+        .ok_or_else(|| WokwiServerError::Gdb("gdbResponse with a character beyond a byte".into()))
+}
@@ -0,0 +1,164 @@
+use crate::project;
+use anyhow::{Context, Result};
+use espflash::Chip;
+use regex::Regex;
+use serde::Deserialize;
+use serde_json::Value;
+use std::path::PathBuf;
+use wokwi_server::chips;
+
+/// where Wokwi's project API lives
+pub const API_URL: &str = "https://wokwi.com/api";
+
+/// Download the diagram of a public Wokwi project, and use the project when simulating
+#[derive(clap::Args, Debug)]
+pub struct PullArgs {
+    /// directory to write diagram.json and wokwi.toml to, defaults to the current directory
+    #[clap(long)]
+    project_dir: Option<PathBuf>,
+
+    /// overwrite an existing diagram.json
+    #[clap(long)]
+    force: bool,
+
+    /// base url of the Wokwi API
+    #[clap(long, env = "WOKWI_API_URL", default_value = API_URL, hide = true)]
+    api_url: String,
+
+    /// ID of the project, or its url, e.g. https://wokwi.com/projects/345932416223806035
+    project: String,
+}
+
+#[derive(Deserialize)]
+struct Response {
+    project: Project,
+}
+
+#[derive(Deserialize)]
+pub struct Project {
+    #[serde(default)]
+    pub name: Option<String>,
+    pub files: Vec<ProjectFile>,
+}
+
+#[derive(Deserialize)]
+pub struct ProjectFile {
+    pub name: String,
+    pub content: String,
+}
+
+impl Project {
+    pub fn file(&self, name: &str) -> Option<&ProjectFile> {
+        self.files.iter().find(|file| file.name == name)
+    }
+}
+
+pub fn run(args: PullArgs) -> Result<i32> {
+    let cwd = std::env::current_dir()?;
+    let dir = match &args.project_dir {
+        Some(dir) => project::resolve(&cwd, dir),
+        None => cwd,
+    };
+    let id = parse_project_id(&args.project)?;
+    let diagram_path = dir.join(project::DIAGRAM_FILE);
+    if diagram_path.exists() && !args.force {
+        anyhow::bail!(
+            "{} already exists, pass --force to overwrite it",
+            diagram_path.display()
+        );
+    }
+
+    let project = fetch(&args.api_url, &id, None)?;
+
+    let diagram = project
+        .file(project::DIAGRAM_FILE)
+        .with_context(|| format!("Project {} has no {}", id, project::DIAGRAM_FILE))?;
+    let parsed: Value = serde_json::from_str(&diagram.content)
+        .with_context(|| format!("Project {} has an invalid {}", id, project::DIAGRAM_FILE))?;
+    std::fs::write(&diagram_path, &diagram.content)
+        .with_context(|| format!("Failed to write {}", diagram_path.display()))?;
+    println!(
+        "Wrote {} from {}",
+        diagram_path.display(),
+        project.name.as_deref().unwrap_or(&id)
+    );
+
+    let config_path = project::set_project_id(&dir, &id)?;
+    println!(
+        "Set the project in {}, simulations will use it unless --id is given",
+        config_path.display()
+    );
+
+    let others: Vec<_> = project
+        .files
+        .iter()
+        .map(|file| file.name.as_str())
+        .filter(|name| *name != project::DIAGRAM_FILE)
+        .collect();
+    if !others.is_empty() {
+        println!("Not downloaded: {}", others.join(", "));
+    }
+    match board_chip(&parsed) {
+        Some((board, Some(chip))) if chips::lookup(chip).is_some() => println!(
+            "The project uses a {}, simulate it with --chip {}",
+            board,
+            format!("{:?}", chip).to_lowercase()
+        ),
+        Some((board, _)) => println!("The project uses a {}, which isn't supported", board),
+        None => println!("The project has no ESP32 board"),
+    }
+    Ok(0)
+}
+
+/// Fetch a project from the API at `api_url`, which must be public unless a token is given
+pub fn fetch(api_url: &str, id: &str, token: Option<&str>) -> Result<Project> {
+    let url = format!("{}/projects/{}", api_url.trim_end_matches('/'), id);
+    let mut request = crate::http::agent(&url)?.get(&url);
+    if let Some(token) = token {
+        request = request.set("Authorization", &format!("Bearer {}", token));
+    }
+    let response: Response = request
+        .call()
+        .map_err(|e| match e {
+            ureq::Error::Status(404, _) => match token {
+                Some(_) => anyhow::anyhow!("There is no Wokwi project with the ID {}", id),
+                None => anyhow::anyhow!("There is no public Wokwi project with the ID {}", id),
+            },
+            e => anyhow::anyhow!("Failed to fetch {}: {}", url, e),
+        })?
+        .into_json()
+        .with_context(|| format!("Unexpected response from {}", url))?;
+    Ok(response.project)
+}
+
+/// The project ID on its own, or taken from the end of a project url
+pub fn parse_project_id(project: &str) -> Result<String> {
+    let id = Regex::new(r"^(?:https?://wokwi\.com/projects/)?(\d+)/?$").unwrap();
+    let captures = id.captures(project.trim()).with_context(|| {
+        format!(
+            "`{}` isn't a Wokwi project ID or a https://wokwi.com/projects/ url",
+            project
+        )
+    })?;
+    Ok(captures[1].to_owned())
+}
+
+/// The ESP32 board part in a diagram and its chip, if it is one Wokwi can be sent firmware for
+fn board_chip(diagram: &Value) -> Option<(&str, Option<Chip>)> {
+    diagram["parts"].as_array()?.iter().find_map(|part| {
+        let board = part["type"].as_str()?;
+        let name = board
+            .strip_prefix("board-")
+            .or_else(|| board.strip_prefix("wokwi-"))?;
+        // e.g. board-esp32-c3-devkitm-1, but board-esp32-devkit-c-v4 for the original
+        let variant = name.strip_prefix("esp32-")?.split('-').next()?;
+        let chip = match variant {
+            "c3" => Some(Chip::Esp32c3),
+            "s2" => Some(Chip::Esp32s2),
+            "s3" => Some(Chip::Esp32s3),
+            v if v.len() == 2 && v.ends_with(|c: char| c.is_ascii_digit()) => None,
+            _ => Some(Chip::Esp32),
+        };
+        Some((board, chip))
+    })
+}
@@ -0,0 +1,89 @@
+use crate::project::{self, ProjectConfig};
+use crate::pull;
+use anyhow::{Context, Result};
+use serde_json::{json, Value};
+use std::path::PathBuf;
+
+/// Upload the local diagram.json to a Wokwi project
+#[derive(clap::Args, Debug)]
+pub struct PushArgs {
+    /// directory containing diagram.json, defaults to the current directory
+    #[clap(long)]
+    project_dir: Option<PathBuf>,
+
+    /// Wokwi API token with access to the project
+    #[clap(long, env = "WOKWI_TOKEN", hide_env_values = true)]
+    token: String,
+
+    /// base url of the Wokwi API
+    #[clap(long, env = "WOKWI_API_URL", default_value = pull::API_URL, hide = true)]
+    api_url: String,
+
+    /// ID of the project, or its url, defaults to `id` in wokwi.toml
+    project: Option<String>,
+}
+
+pub fn run(args: PushArgs) -> Result<i32> {
+    let cwd = std::env::current_dir()?;
+    let dir = match &args.project_dir {
+        Some(dir) => project::resolve(&cwd, dir),
+        None => cwd,
+    };
+    let id = match &args.project {
+        Some(project) => pull::parse_project_id(project)?,
+        None => ProjectConfig::find(&dir)?
+            .and_then(|config| config.id)
+            .with_context(|| {
+                format!(
+                    "No project given, and no {} with an `id` in {} or its parents",
+                    project::CONFIG_FILE,
+                    dir.display()
+                )
+            })?,
+    };
+
+    let diagram_path = dir.join(project::DIAGRAM_FILE);
+    let diagram = std::fs::read_to_string(&diagram_path)
+        .with_context(|| format!("Failed to read {}", diagram_path.display()))?;
+    let parsed: Value = serde_json::from_str(&diagram)
+        .with_context(|| format!("Invalid {}", diagram_path.display()))?;
+
+    let remote = pull::fetch(&args.api_url, &id, Some(&args.token))?;
+    let unchanged = remote
+        .file(project::DIAGRAM_FILE)
+        .and_then(|file| serde_json::from_str::<Value>(&file.content).ok())
+        .is_some_and(|remote| remote == parsed);
+    if unchanged {
+        println!("Project {} already has this {}", id, project::DIAGRAM_FILE);
+        return Ok(0);
+    }
+
+    let url = format!(
+        "{}/projects/{}/files/{}",
+        args.api_url.trim_end_matches('/'),
+        id,
+        project::DIAGRAM_FILE
+    );
+    crate::http::agent(&url)?
+        .put(&url)
+        .set("Authorization", &format!("Bearer {}", args.token))
+        .send_json(json!({ "content": diagram }))
+        .map_err(|e| match e {
+            ureq::Error::Status(401 | 403, _) => {
+                anyhow::anyhow!(
+                    "The token was refused, it may not have access to project {}",
+                    id
+                )
+            }
+            ureq::Error::Status(404 | 405, _) => {
+                anyhow::anyhow!("The Wokwi API at {} doesn't accept uploads", args.api_url)
+            }
+            e => anyhow::anyhow!("Failed to upload to {}: {}", url, e),
+        })?;
+    println!(
+        "Uploaded {} to {}",
+        diagram_path.display(),
+        remote.name.as_deref().unwrap_or(&id)
+    );
+    Ok(0)
+}
@@ -0,0 +1,89 @@
+//! Collapses errors repeated in quick succession, so a misbehaving simulator or a GDB client
+//! which keeps reconnecting doesn't flood the console
+
+use anyhow::Result;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tokio_util::sync::CancellationToken;
+
+/// how long an error is held back for after it was printed, before repeats are summarised
+const WINDOW: Duration = Duration::from_secs(5);
+
+static REPEATS: Mutex<Option<HashMap<String, Repeated>>> = Mutex::new(None);
+
+/// An error which has been printed recently
+struct Repeated {
+    /// session of the latest repeat
+    label: String,
+    printed: Instant,
+    count: usize,
+}
+
+/// Print an error for a session, unless the same error was printed recently
+pub fn report(label: &str, message: impl std::fmt::Display) {
+    let message = message.to_string();
+    let now = Instant::now();
+    let mut repeats = REPEATS.lock().unwrap();
+    let repeats = repeats.get_or_insert_with(HashMap::new);
+    summarise(repeats, |printed| now.duration_since(printed) >= WINDOW);
+
+    match repeats.get_mut(&message) {
+        Some(repeated) => {
+            repeated.label = label.to_owned();
+            repeated.count += 1;
+        }
+        None => {
+            println!("[{}] {}", label, message);
+            repeats.insert(
+                message,
+                Repeated {
+                    label: label.to_owned(),
+                    printed: now,
+                    count: 0,
+                },
+            );
+        }
+    }
+}
+
+/// Print summaries for every error held back, e.g. before exiting
+pub fn flush() {
+    if let Some(repeats) = REPEATS.lock().unwrap().as_mut() {
+        summarise(repeats, |_| true);
+    }
+}
+
+/// Summarise held back errors as they leave the window, even if they are never repeated again
+pub async fn flush_task(shutdown: CancellationToken) -> Result<()> {
+    let mut interval = tokio::time::interval(WINDOW);
+    loop {
+        tokio::select! {
+            _ = interval.tick() => {}
+            _ = shutdown.cancelled() => {
+                flush();
+                return Ok(());
+            }
+        }
+        let now = Instant::now();
+        if let Some(repeats) = REPEATS.lock().unwrap().as_mut() {
+            summarise(repeats, |printed| now.duration_since(printed) >= WINDOW);
+        }
+    }
+}
+
+/// Forget the errors `expired` picks, printing how often they were repeated
+fn summarise(repeats: &mut HashMap<String, Repeated>, expired: impl Fn(Instant) -> bool) {
+    repeats.retain(|message, repeated| {
+        if !expired(repeated.printed) {
+            return true;
+        }
+        if repeated.count > 0 {
+            println!(
+                "[{}] Previous message repeated {} times: {}",
+                repeated.label, repeated.count, message
+            );
+        }
+        false
+    });
+}
@@ -0,0 +1,160 @@
+use crate::expect::Outcome;
+use anyhow::{Context, Result};
+use serde_json::json;
+use std::fmt::Write;
+use std::path::Path;
+use std::time::Duration;
+
+/// The result of one headless run
+#[derive(Debug)]
+pub struct TestResult {
+    pub name: String,
+    pub outcome: Outcome,
+    pub duration: Duration,
+    /// everything the firmware printed on the UART
+    pub output: String,
+}
+
+/// Format of a test summary
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum ReportFormat {
+    Json,
+    Junit,
+}
+
+/// Render a summary of the results of a test suite
+pub fn render(format: ReportFormat, suite: &str, results: &[TestResult]) -> String {
+    match format {
+        ReportFormat::Json => json_report(suite, results),
+        ReportFormat::Junit => junit_report(suite, results),
+    }
+}
+
+/// Write a summary to `path`, as JUnit XML if it ends in `.xml` or JSON otherwise
+pub fn write(path: &Path, suite: &str, results: &[TestResult]) -> Result<()> {
+    let format = match path.extension() {
+        Some(ext) if ext.eq_ignore_ascii_case("xml") => ReportFormat::Junit,
+        _ => ReportFormat::Json,
+    };
+    std::fs::write(path, render(format, suite, results))
+        .with_context(|| format!("Failed to write {}", path.display()))
+}
+
+/// Print a GitHub Actions workflow command annotating a failed result
+pub fn annotate(result: &TestResult) {
+    let message = match &result.outcome {
+        Outcome::Passed => return,
+        Outcome::Failed(m) | Outcome::Error(m) => m,
+    };
+    println!(
+        "::error title={}::{}",
+        escape_property(&result.name),
+        escape_data(message)
+    );
+}
+
+fn escape_data(s: &str) -> String {
+    s.replace('%', "%25")
+        .replace('\r', "%0D")
+        .replace('\n', "%0A")
+}
+
+fn escape_property(s: &str) -> String {
+    escape_data(s).replace(':', "%3A").replace(',', "%2C")
+}
+
+fn json_report(suite: &str, results: &[TestResult]) -> String {
+    let tests: Vec<_> = results
+        .iter()
+        .map(|r| {
+            let (status, message) = match &r.outcome {
+                Outcome::Passed => ("passed", None),
+                Outcome::Failed(m) => ("failed", Some(m)),
+                Outcome::Error(m) => ("error", Some(m)),
+            };
+            json!({
+                "name": r.name,
+                "status": status,
+                "message": message,
+                "duration": r.duration.as_secs_f64(),
+                "output": r.output,
+            })
+        })
+        .collect();
+    let passed = results
+        .iter()
+        .filter(|r| r.outcome == Outcome::Passed)
+        .count();
+
+    let report = json!({
+        "suite": suite,
+        "tests": tests,
+        "passed": passed,
+        "failed": results.len() - passed,
+    });
+    serde_json::to_string_pretty(&report).unwrap_or_default()
+}
+
+fn junit_report(suite: &str, results: &[TestResult]) -> String {
+    let count = |f: fn(&Outcome) -> bool| results.iter().filter(|r| f(&r.outcome)).count();
+    let failures = count(|o| matches!(o, Outcome::Failed(_)));
+    let errors = count(|o| matches!(o, Outcome::Error(_)));
+    let time: f64 = results.iter().map(|r| r.duration.as_secs_f64()).sum();
+
+    let mut out = String::from("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
+    writeln!(
+        out,
+        "<testsuites><testsuite name=\"{}\" tests=\"{}\" failures=\"{}\" errors=\"{}\" time=\"{:.3}\">",
+        escape(suite),
+        results.len(),
+        failures,
+        errors,
+        time
+    )
+    .ok();
+    for r in results {
+        write!(
+            out,
+            "  <testcase name=\"{}\" classname=\"{}\" time=\"{:.3}\">",
+            escape(&r.name),
+            escape(suite),
+            r.duration.as_secs_f64()
+        )
+        .ok();
+        match &r.outcome {
+            Outcome::Passed => {}
+            Outcome::Failed(m) => {
+                write!(out, "<failure message=\"{}\"/>", escape(m)).ok();
+            }
+            Outcome::Error(m) => {
+                write!(out, "<error message=\"{}\"/>", escape(m)).ok();
+            }
+        }
+        writeln!(
+            out,
+            "<system-out>{}</system-out></testcase>",
+            escape(&r.output)
+        )
+        .ok();
+    }
+    out.push_str("</testsuite></testsuites>\n");
+    out
+}
+
+/// Escape text for use in XML, dropping characters XML can't represent
+fn escape(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            '\'' => out.push_str("&apos;"),
+            '\t' | '\n' | '\r' => out.push(c),
+            c if (c as u32) < 0x20 => {}
+            c => out.push(c),
+        }
+    }
+    out
+}
@@ -0,0 +1,158 @@
+use crate::error::{Result, WokwiServerError};
+use crate::plugins::MessageHandlerPlugin;
+use crate::protocol::Capabilities;
+use crate::shims;
+use serde::Serialize;
+use serde_json::Value;
+use std::collections::{HashMap, VecDeque};
+
+/// Handles one type of message from the simulator
+pub type Handler<S> = fn(&mut S, &Value, &mut Outbox) -> Result<()>;
+
+/// Where a chunk falls in the message it was split from
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Progress {
+    /// the number of chunks sent once this one has been
+    pub sent: usize,
+    pub count: usize,
+}
+
+/// Messages waiting to be delivered once the current event has been handled
+#[derive(Default)]
+pub struct Outbox {
+    simulator: VecDeque<(String, Option<Progress>)>,
+    gdb: VecDeque<Vec<u8>>,
+    /// what the simulator can receive, which decides how large messages are sent
+    capabilities: Capabilities,
+    chunked_messages: u32,
+}
+
+impl Outbox {
+    /// Use the capabilities agreed with the simulator for later messages
+    pub fn negotiated(&mut self, capabilities: Capabilities) {
+        self.capabilities = capabilities;
+    }
+
+    /// Queue a message for the simulator, split into chunks if it is too large to send whole
+    pub fn send_to_simulator(&mut self, message: &impl Serialize) -> Result<()> {
+        let version = self.capabilities.protocol_version;
+        let encoded = match shims::for_version(version).outgoing {
+            None => serde_json::to_string(message),
+            Some(adapt) => serde_json::to_value(message).map(|mut value| {
+                adapt(&mut value);
+                value.to_string()
+            }),
+        };
+        let text = encoded
+            .map_err(|e| WokwiServerError::Protocol(format!("Failed to encode message: {}", e)))?;
+        let frames = self.capabilities.frame(text, self.chunked_messages + 1)?;
+        let count = frames.len();
+        if count > 1 {
+            self.chunked_messages += 1;
+        }
+        self.simulator
+            .extend(frames.into_iter().enumerate().map(|(index, frame)| {
+                let progress = (count > 1).then_some(Progress {
+                    sent: index + 1,
+                    count,
+                });
+                (frame, progress)
+            }));
+        Ok(())
+    }
+
+    /// The protocol version spoken with the simulator
+    pub fn protocol_version(&self) -> u32 {
+        self.capabilities.protocol_version
+    }
+
+    /// Queue a response for the GDB client
+    pub fn send_to_gdb(&mut self, response: impl Into<Vec<u8>>) {
+        self.gdb.push_back(response.into());
+    }
+
+    pub fn next_for_simulator(&mut self) -> Option<String> {
+        self.simulator.pop_front().map(|(text, _)| text)
+    }
+
+    /// The next message for the simulator, with how far through its message it is if it's a chunk
+    pub fn next_for_simulator_with_progress(&mut self) -> Option<(String, Option<Progress>)> {
+        self.simulator.pop_front()
+    }
+
+    pub fn next_for_gdb(&mut self) -> Option<Vec<u8>> {
+        self.gdb.pop_front()
+    }
+}
+
+/// Dispatches messages from the simulator to the handler registered for their `type`, or the
+/// plugin handling it
+pub struct Router<S> {
+    handlers: HashMap<&'static str, Handler<S>>,
+    plugins: Vec<Box<dyn MessageHandlerPlugin>>,
+    /// the index in `plugins` of the plugin for each type, the first to name it
+    plugin_types: HashMap<String, usize>,
+    pub outbox: Outbox,
+}
+
+impl<S> Default for Router<S> {
+    fn default() -> Self {
+        Self {
+            handlers: HashMap::new(),
+            plugins: Vec::new(),
+            plugin_types: HashMap::new(),
+            outbox: Outbox::default(),
+        }
+    }
+}
+
+impl<S> Router<S> {
+    /// Register the handler for messages of type `r#type`
+    pub fn on(mut self, r#type: &'static str, handler: Handler<S>) -> Self {
+        self.handlers.insert(r#type, handler);
+        self
+    }
+
+    /// Pass the message types a plugin names to it, unless a handler or earlier plugin has them
+    pub fn plugin(mut self, plugin: Box<dyn MessageHandlerPlugin>) -> Self {
+        let index = self.plugins.len();
+        for r#type in plugin.message_types() {
+            self.plugin_types.entry(r#type).or_insert(index);
+        }
+        self.plugins.push(plugin);
+        self
+    }
+
+    /// Pass a message to its handler, returns false if nothing handles its type. The message is
+    /// first brought into the shape handlers expect
+    pub fn dispatch(&mut self, state: &mut S, message: &Value) -> Result<bool> {
+        let adapted;
+        let version = self.outbox.protocol_version();
+        let message = match shims::for_version(version).incoming {
+            None => message,
+            Some(adapt) => {
+                let mut message = message.clone();
+                adapt(&mut message);
+                adapted = message;
+                &adapted
+            }
+        };
+        let Some(r#type) = message["type"].as_str() else {
+            return Ok(false);
+        };
+        if let Some(handler) = self.handlers.get(r#type) {
+            handler(state, message, &mut self.outbox)?;
+            return Ok(true);
+        }
+        match self.plugin_types.get(r#type) {
+            Some(&index) => {
+                let plugin = &mut self.plugins[index];
+                plugin.handle(message, &mut self.outbox).map_err(|e| {
+                    WokwiServerError::Protocol(format!("Plugin {} failed: {}", plugin.name(), e))
+                })?;
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+}
@@ -0,0 +1,50 @@
+use sha2::{Digest, Sha256};
+
+const SECTOR_SIZE: usize = 0x1000;
+const SIG_BLOCK_MAGIC: u8 = 0xE7;
+const SIG_BLOCK_VERSION_RSA: u8 = 0x02;
+
+/// A Secure Boot V2 signature block found appended to an image
+#[derive(Debug)]
+pub struct SignatureBlock {
+    /// offset of the signature block within the image
+    pub offset: usize,
+    /// whether the digest stored in the block matches the signed image contents
+    pub digest_matches: bool,
+}
+
+/// Search an image for a Secure Boot V2 signature block.
+///
+/// Signature blocks are placed at the first sector boundary after the image contents,
+/// and contain the SHA256 digest of everything preceding them.
+pub fn find_signature_block(image: &[u8]) -> Option<SignatureBlock> {
+    (SECTOR_SIZE..image.len())
+        .step_by(SECTOR_SIZE)
+        .find(|&offset| {
+            image[offset] == SIG_BLOCK_MAGIC
+                && image.get(offset + 1) == Some(&SIG_BLOCK_VERSION_RSA)
+        })
+        .map(|offset| {
+            let stored = image.get(offset + 4..offset + 36);
+            let digest = Sha256::digest(&image[..offset]);
+            SignatureBlock {
+                offset,
+                digest_matches: stored == Some(digest.as_slice()),
+            }
+        })
+}
+
+/// Print a warning if the image would fail Secure Boot V2 signature verification
+pub fn warn_if_unverifiable(name: &str, image: &[u8]) {
+    match find_signature_block(image) {
+        Some(block) if block.digest_matches => {}
+        Some(block) => println!(
+            "Warning: {} signature block at offset {:#x} does not match the image digest, signature verification would fail",
+            name, block.offset
+        ),
+        None => println!(
+            "Warning: {} has no Secure Boot V2 signature block, signature verification would fail",
+            name
+        ),
+    }
+}
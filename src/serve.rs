@@ -0,0 +1,96 @@
+use crate::Args;
+use anyhow::{Context, Result};
+use clap::Parser;
+use serde::Deserialize;
+use serde_json::Value;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use wokwi_server::{PacketExtensions, SegmentChecksum, SimulationPacket};
+
+/// Simulate an image written by `pack`, without building it again
+#[derive(clap::Args, Debug)]
+pub struct ServeArgs {
+    /// JSON file written by `wokwi-server pack`
+    payload: PathBuf,
+
+    /// options for the server, the same as when simulating an elf (e.g. `--port 9012`), except
+    /// those choosing the firmware
+    #[clap(allow_hyphen_values = true, multiple_values = true)]
+    options: Vec<String>,
+}
+
+/// What `pack` writes
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Payload {
+    chip: String,
+    elf: String,
+    esp_bin: Vec<Vec<Value>>,
+    segments: Option<Vec<SegmentChecksum>>,
+}
+
+pub async fn run(args: ServeArgs) -> Result<i32> {
+    let payload = load(&args.payload)?;
+    let elf = base64::decode(&payload.elf).context("Invalid elf in the payload")?;
+
+    // GDB, defmt and the startup manifest want the elf as a file
+    let elf_path = std::env::temp_dir().join(format!("wokwi-server-{}.elf", std::process::id()));
+    std::fs::write(&elf_path, elf)
+        .with_context(|| format!("Failed to write {}", elf_path.display()))?;
+
+    let argv = ["wokwi-server", "--chip", &payload.chip]
+        .into_iter()
+        .map(str::to_owned)
+        .chain(args.options)
+        .chain([elf_path.to_string_lossy().into_owned()]);
+    let mut opts = Args::try_parse_from(argv).unwrap_or_else(|e| e.exit());
+    opts.payload = Some(Arc::new(SimulationPacket {
+        r#type: "start".to_owned(),
+        elf: payload.elf,
+        esp_bin: payload.esp_bin,
+        extensions: payload
+            .segments
+            .map(|segments| PacketExtensions { segments }),
+    }));
+    println!("Serving {} for {}", args.payload.display(), payload.chip);
+
+    let code = crate::simulate(opts).await;
+    std::fs::remove_file(&elf_path).ok();
+    code
+}
+
+/// Read a payload, checking its segments against the checksums `pack` recorded
+fn load(path: &Path) -> Result<Payload> {
+    let contents =
+        std::fs::read(path).with_context(|| format!("Failed to read {}", path.display()))?;
+    let payload: Payload = serde_json::from_slice(&contents)
+        .with_context(|| format!("{} is not a payload written by `pack`", path.display()))?;
+
+    for (i, segment) in payload.esp_bin.iter().enumerate() {
+        let (addr, data) = match segment.as_slice() {
+            [addr, data] => (addr.as_u64(), data.as_str()),
+            _ => (None, None),
+        };
+        let (Some(addr), Some(data)) = (addr, data) else {
+            anyhow::bail!(
+                "Segment {} in {} is not an address and data",
+                i,
+                path.display()
+            );
+        };
+        let data = base64::decode(data)
+            .with_context(|| format!("Invalid data for segment {} in {}", i, path.display()))?;
+        let Some(expected) = payload.segments.as_ref().and_then(|s| s.get(i)) else {
+            continue;
+        };
+        let actual = SegmentChecksum::new(&expected.name, addr as u32, &data);
+        if actual != *expected {
+            anyhow::bail!(
+                "The {} segment in {} doesn't match its checksum, the file may be damaged",
+                expected.name,
+                path.display()
+            );
+        }
+    }
+    Ok(payload)
+}
@@ -0,0 +1,231 @@
+use crate::last_session;
+use crate::stats::{self, Stats};
+use anyhow::{Context, Result};
+use serde::Serialize;
+use serde_json::{json, Value};
+use std::collections::VecDeque;
+use std::io::Write;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+/// What is on the other end of a connection
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Kind {
+    Simulator,
+    Gdb,
+}
+
+impl Kind {
+    fn prefix(self) -> &'static str {
+        match self {
+            Kind::Simulator => "sim",
+            Kind::Gdb => "gdb",
+        }
+    }
+}
+
+/// A currently connected simulator or GDB client
+#[derive(Debug, Clone, Serialize)]
+pub struct SessionInfo {
+    pub id: String,
+    pub kind: Kind,
+    pub peer: SocketAddr,
+    /// milliseconds since the unix epoch
+    pub connected_at: u64,
+}
+
+/// how many events are kept in memory for failure reports
+const RECENT_EVENTS: usize = 256;
+
+#[derive(Default)]
+struct Inner {
+    /// given with `--session-name`, added to every event
+    name: Option<String>,
+    next_id: u32,
+    active: Vec<SessionInfo>,
+    event_log: Option<std::fs::File>,
+    recent: VecDeque<Value>,
+    simulator_connections: u64,
+    stats: Stats,
+}
+
+/// Keeps track of connections, and records their events to the event log
+#[derive(Clone, Default)]
+pub struct Sessions {
+    inner: Arc<Mutex<Inner>>,
+}
+
+impl Sessions {
+    /// Track sessions, appending newline delimited JSON events to `event_log` if given
+    pub fn new(event_log: Option<&Path>, name: Option<String>) -> Result<Self> {
+        let event_log = event_log
+            .map(|path| {
+                std::fs::OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(path)
+                    .with_context(|| format!("Failed to open event log {}", path.display()))
+            })
+            .transpose()?;
+        Ok(Self {
+            inner: Arc::new(Mutex::new(Inner {
+                stats: Stats {
+                    session_name: name.clone(),
+                    ..Default::default()
+                },
+                name,
+                event_log,
+                ..Default::default()
+            })),
+        })
+    }
+
+    /// Register a new connection
+    pub fn open(&self, kind: Kind, peer: SocketAddr) -> Session {
+        let info = {
+            let mut inner = self.inner.lock().unwrap();
+            inner.next_id += 1;
+            let info = SessionInfo {
+                id: format!("{}-{}", kind.prefix(), inner.next_id),
+                kind,
+                peer,
+                connected_at: now(),
+            };
+            inner.active.push(info.clone());
+            if kind == Kind::Simulator {
+                inner.simulator_connections += 1;
+                inner.stats.reconnects = inner.simulator_connections - 1;
+            }
+            info
+        };
+        self.event(&info.id, "connected", json!({ "kind": kind, "peer": peer }));
+        Session {
+            id: info.id,
+            kind,
+            opened: Instant::now(),
+            sessions: self.clone(),
+        }
+    }
+
+    /// Totals for all sessions so far
+    pub fn stats(&self) -> Stats {
+        self.inner.lock().unwrap().stats.clone()
+    }
+
+    /// Add to the totals
+    pub fn count(&self, f: impl FnOnce(&mut Stats)) {
+        f(&mut self.inner.lock().unwrap().stats);
+    }
+
+    /// The currently connected sessions
+    pub fn list(&self) -> Vec<SessionInfo> {
+        self.inner.lock().unwrap().active.clone()
+    }
+
+    /// The most recent events recorded for a session
+    pub fn recent_events(&self, session: &str) -> Vec<Value> {
+        self.inner
+            .lock()
+            .unwrap()
+            .recent
+            .iter()
+            .filter(|e| e["session"] == session)
+            .cloned()
+            .collect()
+    }
+
+    /// Record an event for a session, `details` are merged into the event object
+    pub fn event(&self, session: &str, event: &str, details: Value) {
+        let mut record = json!({
+            "timestamp": now(),
+            "session": session,
+            "event": event,
+        });
+        let mut inner = self.inner.lock().unwrap();
+        if let Value::Object(record) = &mut record {
+            if let Some(name) = &inner.name {
+                record.insert("session_name".into(), json!(name));
+            }
+            if let Value::Object(details) = details {
+                record.extend(details);
+            }
+        }
+
+        if last_session::enabled() {
+            last_session::record(&last_session::redact(record.clone()).to_string());
+        }
+        if let Some(log) = &mut inner.event_log {
+            if writeln!(log, "{}", record).is_err() {
+                println!("Failed to write to the event log, disabling it");
+                inner.event_log = None;
+            }
+        }
+        if inner.recent.len() == RECENT_EVENTS {
+            inner.recent.pop_front();
+        }
+        inner.recent.push_back(record);
+    }
+}
+
+/// A tracked connection, removed from the session list when dropped
+pub struct Session {
+    pub id: String,
+    kind: Kind,
+    opened: Instant,
+    sessions: Sessions,
+}
+
+impl Session {
+    /// Add to the totals
+    pub fn count(&self, f: impl FnOnce(&mut Stats)) {
+        self.sessions.count(f);
+    }
+
+    /// The totals so far
+    pub fn stats(&self) -> Stats {
+        self.sessions.stats()
+    }
+
+    /// The simulators and GDB clients connected now
+    pub fn connected(&self) -> Vec<SessionInfo> {
+        self.sessions.list()
+    }
+
+    /// The most recent events recorded for this session
+    pub fn recent_events(&self) -> Vec<Value> {
+        self.sessions.recent_events(&self.id)
+    }
+
+    /// Record an event for this session
+    pub fn event(&self, event: &str, details: Value) {
+        self.sessions.event(&self.id, event, details);
+    }
+
+    /// Record how the session ended
+    pub fn close(self, result: &Result<()>) {
+        match result {
+            Ok(_) => self.event("disconnected", json!({})),
+            Err(e) => self.event("disconnected", json!({ "error": e.to_string() })),
+        }
+    }
+}
+
+impl Drop for Session {
+    fn drop(&mut self) {
+        let mut inner = self.sessions.inner.lock().unwrap();
+        inner.active.retain(|s| s.id != self.id);
+        if self.kind == Kind::Simulator {
+            inner.stats.simulation_ms += stats::millis(self.opened.elapsed());
+        }
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64
+}
@@ -0,0 +1,50 @@
+//! Adapting messages to the version of the embed protocol spoken on a connection. Handlers only
+//! see and send messages in the shape of [`MAX_PROTOCOL_VERSION`]; the shim of the version in use
+//! rewrites what comes from the simulator into that shape, and what goes to it out of it. A change
+//! to the shape of a message on the Wokwi side then only needs a new shim, rather than every
+//! handler knowing about every version
+
+use crate::protocol::{MAX_PROTOCOL_VERSION, MIN_PROTOCOL_VERSION};
+use serde_json::Value;
+
+/// Rewrites of messages between one protocol version and the newest
+#[derive(Debug)]
+pub struct Shim {
+    pub version: u32,
+    /// turns a message from the simulator into the newest version's shape
+    pub incoming: Option<fn(&mut Value)>,
+    /// turns a message in the newest version's shape into this version's, for the simulator
+    pub outgoing: Option<fn(&mut Value)>,
+}
+
+/// A shim for every version from [`MIN_PROTOCOL_VERSION`] to [`MAX_PROTOCOL_VERSION`]. The
+/// newest version needs no rewriting
+const SHIMS: &[Shim] = &[Shim {
+    version: 1,
+    incoming: None,
+    outgoing: None,
+}];
+
+/// The shim for `version`. Versions without one, which are only spoken when pinned, get the
+/// nearest supported version's, as the closest guess at their shape
+pub fn for_version(version: u32) -> &'static Shim {
+    let version = version.clamp(MIN_PROTOCOL_VERSION, MAX_PROTOCOL_VERSION);
+    SHIMS
+        .iter()
+        .find(|shim| shim.version == version)
+        .expect("every supported version has a shim")
+}
+
+/// Rewrite a message from a simulator speaking `version` into the newest shape
+pub fn incoming(version: u32, message: &mut Value) {
+    if let Some(adapt) = for_version(version).incoming {
+        adapt(message);
+    }
+}
+
+/// Rewrite a message in the newest shape for a simulator speaking `version`
+pub fn outgoing(version: u32, message: &mut Value) {
+    if let Some(adapt) = for_version(version).outgoing {
+        adapt(message);
+    }
+}
@@ -0,0 +1,167 @@
+//! The port simulators connect to, which also answers plain HTTP health checks. Connections are
+//! told apart as they arrive, alongside any running simulation, so a health check never waits
+//! for a simulation to end
+
+use anyhow::{Context, Result};
+use std::io;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::task::{Context as TaskContext, Poll};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+
+/// how long a connection has to send its request line
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+/// the longest request line looked at, anything longer isn't a health check
+const MAX_REQUEST_LINE: usize = 8 << 10;
+
+/// A connection from a simulator, with the start of its request which was read to tell it apart
+/// from a health check put back in front
+pub struct SimulatorStream {
+    read: Vec<u8>,
+    stream: TcpStream,
+}
+
+impl AsyncRead for SimulatorStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        if this.read.is_empty() {
+            return Pin::new(&mut this.stream).poll_read(cx, buf);
+        }
+        let n = this.read.len().min(buf.remaining());
+        buf.put_slice(&this.read[..n]);
+        this.read.drain(..n);
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncWrite for SimulatorStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.get_mut().stream).poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().stream).poll_shutdown(cx)
+    }
+}
+
+/// The simulators connecting to a port, accepted in the background until it is dropped
+pub struct SimulatorPort {
+    addr: SocketAddr,
+    connections: mpsc::Receiver<Result<(SimulatorStream, SocketAddr)>>,
+    accepting: JoinHandle<()>,
+}
+
+impl SimulatorPort {
+    /// Start accepting connections on `server`, answering health checks straight away
+    pub fn serve(server: TcpListener) -> Result<Self> {
+        let addr = server.local_addr()?;
+        let (send, connections) = mpsc::channel(1);
+        let accepting = tokio::spawn(accept_loop(server, send));
+        Ok(Self {
+            addr,
+            connections,
+            accepting,
+        })
+    }
+
+    /// The next simulator to connect, or why a connection failed
+    pub async fn accept(&mut self) -> Result<(SimulatorStream, SocketAddr)> {
+        self.connections
+            .recv()
+            .await
+            .context("Stopped accepting simulator connections")?
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        self.addr
+    }
+}
+
+impl Drop for SimulatorPort {
+    fn drop(&mut self) {
+        self.accepting.abort();
+    }
+}
+
+async fn accept_loop(
+    server: TcpListener,
+    connections: mpsc::Sender<Result<(SimulatorStream, SocketAddr)>>,
+) {
+    loop {
+        let (stream, peer) = match server.accept().await {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                // waiting for the error to be taken keeps a failing listener from spinning
+                if connections.send(Err(e.into())).await.is_err() {
+                    return;
+                }
+                continue;
+            }
+        };
+        let connections = connections.clone();
+        tokio::spawn(async move {
+            let classified = match classify(stream).await {
+                Ok(Some(stream)) => Ok((stream, peer)),
+                Ok(None) => return,
+                Err(e) => Err(e),
+            };
+            connections.send(classified).await.ok();
+        });
+    }
+}
+
+/// Read the request line of a connection, answering it if it is a health check. Returns the
+/// connection if it is for a simulator, or `None` if it has been dealt with
+async fn classify(mut stream: TcpStream) -> Result<Option<SimulatorStream>> {
+    let mut read = Vec::new();
+    tokio::time::timeout(REQUEST_TIMEOUT, async {
+        let mut buf = [0u8; 1024];
+        while !read.contains(&b'\n') && read.len() < MAX_REQUEST_LINE {
+            let n = stream.read(&mut buf).await?;
+            if n == 0 {
+                break;
+            }
+            read.extend_from_slice(&buf[..n]);
+        }
+        Ok::<_, io::Error>(())
+    })
+    .await
+    .context("Timed out waiting for request")??;
+
+    if read.is_empty() {
+        return Ok(None); /* a port check, which connects and hangs up */
+    }
+    if !is_health_check(&read) {
+        return Ok(Some(SimulatorStream { read, stream }));
+    }
+    stream
+        .write_all(b"HTTP/1.1 200 OK\r\nContent-Type: text/plain\r\nContent-Length: 2\r\nConnection: close\r\n\r\nok")
+        .await?;
+    stream.shutdown().await?;
+    Ok(None)
+}
+
+/// Whether a request starts with `GET /healthz`
+fn is_health_check(request: &[u8]) -> bool {
+    let line = request.split(|&b| b == b'\n').next().unwrap_or_default();
+    let mut words = line.split(|&b| b == b' ');
+    let (method, target) = (words.next(), words.next());
+    let path = target.map(|target| target.split(|&b| b == b'?').next().unwrap_or_default());
+    method == Some(b"GET") && path == Some(b"/healthz")
+}
@@ -0,0 +1,86 @@
+use super::{parse_addr, SinkOptions, UartSink};
+use anyhow::{Context, Result};
+use futures_util::SinkExt;
+use std::future::Future;
+use tokio::io::AsyncWriteExt;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::broadcast;
+
+/// how many chunks of output a slow client may fall behind before losing some
+const BACKLOG: usize = 1024;
+
+/// Sends the raw output to every client connected to a listening socket
+struct Broadcast(broadcast::Sender<Vec<u8>>);
+
+impl UartSink for Broadcast {
+    fn write(&mut self, bytes: &[u8]) -> Result<()> {
+        // an error only means nobody is connected
+        self.0.send(bytes.to_vec()).ok();
+        Ok(())
+    }
+}
+
+pub fn open_tcp(addr: Option<&str>, _: &SinkOptions) -> Result<Box<dyn UartSink>> {
+    listen(
+        addr.unwrap_or_default(),
+        "tcp",
+        |mut stream, mut output| async move {
+            while let Some(bytes) = next(&mut output).await {
+                stream.write_all(&bytes).await?;
+            }
+            Ok(())
+        },
+    )
+}
+
+pub fn open_websocket(addr: Option<&str>, _: &SinkOptions) -> Result<Box<dyn UartSink>> {
+    listen(
+        addr.unwrap_or_default(),
+        "ws",
+        |stream, mut output| async move {
+            let mut websocket = tokio_tungstenite::accept_async(stream).await?;
+            while let Some(bytes) = next(&mut output).await {
+                websocket.send(tungstenite::Message::Binary(bytes)).await?;
+            }
+            Ok(())
+        },
+    )
+}
+
+/// Accept clients on `addr`, running `serve` for each of them
+fn listen<F, Fut>(addr: &str, scheme: &str, serve: F) -> Result<Box<dyn UartSink>>
+where
+    F: Fn(TcpStream, broadcast::Receiver<Vec<u8>>) -> Fut + Send + 'static,
+    Fut: Future<Output = Result<()>> + Send + 'static,
+{
+    let addr = parse_addr(addr)?;
+    let listener = std::net::TcpListener::bind(addr)
+        .with_context(|| format!("Failed to listen on {}", addr))?;
+    listener.set_nonblocking(true)?;
+    let listener = TcpListener::from_std(listener)?;
+    println!(
+        "UART output available on {}://{}",
+        scheme,
+        listener.local_addr()?
+    );
+
+    let (sender, _) = broadcast::channel(BACKLOG);
+    let clients = sender.clone();
+    tokio::spawn(async move {
+        while let Ok((stream, _)) = listener.accept().await {
+            tokio::spawn(serve(stream, clients.subscribe()));
+        }
+    });
+    Ok(Box::new(Broadcast(sender)))
+}
+
+/// The next chunk of output, skipping whatever a slow client missed
+async fn next(output: &mut broadcast::Receiver<Vec<u8>>) -> Option<Vec<u8>> {
+    loop {
+        match output.recv().await {
+            Ok(bytes) => return Some(bytes),
+            Err(broadcast::error::RecvError::Lagged(_)) => continue,
+            Err(broadcast::error::RecvError::Closed) => return None,
+        }
+    }
+}
@@ -0,0 +1,102 @@
+use super::{SinkOptions, UartSink};
+use anyhow::{Context, Result};
+use defmt_decoder::{DecodeError, Table};
+use std::io::{IsTerminal, Write};
+use std::path::Path;
+
+/// Decodes defmt frames using the tables in the firmware, and prints the log messages
+struct Defmt {
+    table: Option<Table>,
+    buffer: Vec<u8>,
+    colored: bool,
+}
+
+pub fn open(_: Option<&str>, _: &SinkOptions) -> Result<Box<dyn UartSink>> {
+    Ok(Box::new(Defmt {
+        table: None,
+        buffer: Vec::new(),
+        colored: std::io::stdout().is_terminal(),
+    }))
+}
+
+impl UartSink for Defmt {
+    fn firmware_started(&mut self, elf: &Path) -> Result<()> {
+        let data =
+            std::fs::read(elf).with_context(|| format!("Failed to read {}", elf.display()))?;
+        self.table = Table::parse(&data)?;
+        self.buffer.clear();
+        if self.table.is_none() {
+            println!(
+                "Warning: {} contains no defmt data, UART output won't be shown",
+                elf.display()
+            );
+        }
+        Ok(())
+    }
+
+    fn write(&mut self, bytes: &[u8]) -> Result<()> {
+        let Some(table) = &self.table else {
+            return Ok(());
+        };
+        self.buffer.extend_from_slice(bytes);
+
+        let mut stdout = std::io::stdout().lock();
+        // rzCOBS frames are separated by zeros, so a broken frame can be skipped. Raw frames
+        // can only be decoded one after another
+        if table.encoding().can_recover() {
+            while let Some(end) = self.buffer.iter().position(|&b| b == 0) {
+                let frame: Vec<u8> = self.buffer.drain(..=end).collect();
+                let decoded = rzcobs_decode(&frame[..end]);
+                if let Some((frame, _)) = decoded.and_then(|f| table.decode(&f).ok()) {
+                    writeln!(stdout, "{}", frame.display(self.colored))?;
+                }
+            }
+        } else {
+            loop {
+                match table.decode(&self.buffer) {
+                    Ok((frame, consumed)) => {
+                        writeln!(stdout, "{}", frame.display(self.colored))?;
+                        self.buffer.drain(..consumed);
+                    }
+                    Err(DecodeError::UnexpectedEof) => break,
+                    Err(DecodeError::Malformed) => anyhow::bail!("Malformed defmt frame"),
+                }
+            }
+        }
+        stdout.flush()?;
+        Ok(())
+    }
+}
+
+/// Decode a single rzCOBS frame, without its terminating zero
+fn rzcobs_decode(data: &[u8]) -> Option<Vec<u8>> {
+    let mut out = Vec::new();
+    let mut data = data.iter().rev().copied();
+    while let Some(x) = data.next() {
+        match x {
+            0 => return None,
+            0x01..=0x7f => {
+                for i in 0..7 {
+                    if x & (1 << (6 - i)) == 0 {
+                        out.push(data.next()?);
+                    } else {
+                        out.push(0);
+                    }
+                }
+            }
+            0x80..=0xfe => {
+                out.push(0);
+                for _ in 0..(x & 0x7f) + 7 {
+                    out.push(data.next()?);
+                }
+            }
+            0xff => {
+                for _ in 0..134 {
+                    out.push(data.next()?);
+                }
+            }
+        }
+    }
+    out.reverse();
+    Some(out)
+}
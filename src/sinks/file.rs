@@ -0,0 +1,20 @@
+use super::{SinkOptions, UartSink};
+use anyhow::{Context, Result};
+use std::fs::File;
+use std::io::Write;
+
+/// Writes the raw output to a file
+struct FileSink(File);
+
+pub fn open(path: Option<&str>, _: &SinkOptions) -> Result<Box<dyn UartSink>> {
+    let path = path.unwrap_or_default();
+    let file = File::create(path).with_context(|| format!("Failed to create {}", path))?;
+    Ok(Box::new(FileSink(file)))
+}
+
+impl UartSink for FileSink {
+    fn write(&mut self, bytes: &[u8]) -> Result<()> {
+        self.0.write_all(bytes)?;
+        Ok(())
+    }
+}
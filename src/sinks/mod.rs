@@ -0,0 +1,279 @@
+use crate::capture::{Capture, Direction};
+use crate::log_filter::LogFilter;
+use crate::uart_display::UartDisplay;
+use anyhow::Result;
+use std::net::SocketAddr;
+use std::path::Path;
+
+mod broadcast;
+#[cfg(feature = "defmt")]
+mod defmt;
+mod file;
+#[cfg(unix)]
+mod pty;
+mod stdout;
+mod udp;
+
+pub use stdout::rendered;
+
+/// Somewhere UART output from the simulation is sent
+pub trait UartSink: Send {
+    /// Called whenever firmware is started in the simulator
+    fn firmware_started(&mut self, _elf: &Path) -> Result<()> {
+        Ok(())
+    }
+
+    /// Handle bytes received from the simulated UART
+    fn write(&mut self, bytes: &[u8]) -> Result<()>;
+
+    /// Whether some output is being held back, `flush` is called once the UART goes idle
+    fn has_pending(&self) -> bool {
+        false
+    }
+
+    /// Write out anything held back
+    fn flush(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    /// Use a different log filter from the next line, for sinks which filter
+    fn set_log_filter(&mut self, _filter: Option<&LogFilter>) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// Options shared by all sinks
+pub struct SinkOptions {
+    pub display: UartDisplay,
+    pub log_filter: Option<LogFilter>,
+}
+
+/// Opens a sink, given the argument after `name:`
+type OpenFn = fn(Option<&str>, &SinkOptions) -> Result<Box<dyn UartSink>>;
+
+/// A kind of sink which can be chosen with `--uart-sink <name>[:<arg>]`
+#[derive(Debug)]
+pub struct SinkKind {
+    pub name: &'static str,
+    /// what the argument after `name:` means, for sinks which need one
+    pub arg: Option<&'static str>,
+    open: OpenFn,
+}
+
+/// All the available sinks, new sinks only need to be added here
+pub const KINDS: &[SinkKind] = &[
+    SinkKind {
+        name: "stdout",
+        arg: None,
+        open: stdout::open,
+    },
+    SinkKind {
+        name: "file",
+        arg: Some("path"),
+        open: file::open,
+    },
+    SinkKind {
+        name: "tcp",
+        arg: Some("[addr:]port"),
+        open: broadcast::open_tcp,
+    },
+    SinkKind {
+        name: "udp",
+        arg: Some("[addr:]port"),
+        open: udp::open,
+    },
+    SinkKind {
+        name: "ws",
+        arg: Some("[addr:]port"),
+        open: broadcast::open_websocket,
+    },
+    #[cfg(unix)]
+    SinkKind {
+        name: "pty",
+        arg: None,
+        open: pty::open,
+    },
+    #[cfg(feature = "defmt")]
+    SinkKind {
+        name: "defmt",
+        arg: None,
+        open: defmt::open,
+    },
+];
+
+/// A sink given on the command line
+#[derive(Debug, Clone)]
+pub struct SinkSpec {
+    kind: &'static SinkKind,
+    arg: Option<String>,
+}
+
+/// Specs are the same if they open the same sink
+impl PartialEq for SinkSpec {
+    fn eq(&self, other: &Self) -> bool {
+        self.kind.name == other.kind.name && self.arg == other.arg
+    }
+}
+
+impl std::fmt::Display for SinkSpec {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.arg {
+            Some(arg) => write!(f, "{}:{}", self.kind.name, arg),
+            None => f.write_str(self.kind.name),
+        }
+    }
+}
+
+/// Parse `<name>[:<arg>]`
+pub fn parse_spec(s: &str) -> Result<SinkSpec, String> {
+    let (name, arg) = match s.split_once(':') {
+        Some((name, arg)) => (name, Some(arg.to_owned())),
+        None => (s, None),
+    };
+    let kind = KINDS.iter().find(|k| k.name == name).ok_or_else(|| {
+        let names: Vec<_> = KINDS.iter().map(|k| k.name).collect();
+        format!(
+            "unknown UART sink `{}`, expected one of {}",
+            name,
+            names.join(", ")
+        )
+    })?;
+    match (kind.arg, &arg) {
+        (Some(usage), None) => Err(format!("expected `{}:<{}>`", name, usage)),
+        (None, Some(_)) => Err(format!("`{}` doesn't take an argument", name)),
+        _ => Ok(SinkSpec { kind, arg }),
+    }
+}
+
+/// Parse `[udp:|tcp:][<addr>:]<port>`, sending datagrams unless `tcp:` is given
+pub fn parse_tap(s: &str) -> Result<SinkSpec, String> {
+    match s.split_once(':') {
+        Some(("udp" | "tcp", _)) => parse_spec(s),
+        _ => parse_spec(&format!("udp:{}", s)),
+    }
+}
+
+/// Parse `[addr:]port`, listening on localhost if no address is given
+fn parse_addr(s: &str) -> Result<SocketAddr> {
+    match s.parse::<u16>() {
+        Ok(port) => Ok(SocketAddr::from(([127, 0, 0, 1], port))),
+        Err(_) => s
+            .parse()
+            .map_err(|_| anyhow::anyhow!("Invalid address `{}`", s)),
+    }
+}
+
+/// The active sinks, every one of them sees all the UART output
+#[derive(Default)]
+pub struct Sinks {
+    sinks: Vec<Entry>,
+    /// sees the input sent to the firmware too
+    capture: Option<Capture>,
+}
+
+struct Entry {
+    name: String,
+    /// opened from a `--uart-sink` or the config file, so replaced when the config changes
+    configured: bool,
+    sink: Box<dyn UartSink>,
+}
+
+impl Sinks {
+    /// Make the configured sinks match `specs`, keeping those which are already open so nothing
+    /// is lost from them, and give every sink the log filter. Nothing changes if a new sink can't
+    /// be opened
+    pub fn configure(&mut self, specs: &[SinkSpec], opts: &SinkOptions) -> Result<()> {
+        let mut opened = Vec::new();
+        for spec in specs {
+            let name = spec.to_string();
+            if !self.sinks.iter().any(|e| e.configured && e.name == name) {
+                opened.push(Entry {
+                    name,
+                    configured: true,
+                    sink: open(spec, opts)?,
+                });
+            }
+        }
+        let names: Vec<_> = specs.iter().map(SinkSpec::to_string).collect();
+        self.sinks.retain_mut(|entry| {
+            let keep = !entry.configured || names.contains(&entry.name);
+            if !keep {
+                entry.sink.flush().ok();
+            }
+            keep
+        });
+        self.sinks.extend(opened);
+        let filter = opts.log_filter.as_ref();
+        self.each(|sink| sink.set_log_filter(filter));
+        Ok(())
+    }
+
+    /// Open a sink alongside the others, like a `--uart-tap`
+    pub fn open_extra(&mut self, spec: &SinkSpec, opts: &SinkOptions) -> Result<()> {
+        let sink = open(spec, opts)?;
+        self.add(&spec.to_string(), sink);
+        Ok(())
+    }
+
+    /// Add a sink which can't be chosen on the command line
+    pub fn add(&mut self, name: &str, sink: Box<dyn UartSink>) {
+        self.sinks.push(Entry {
+            name: name.to_owned(),
+            configured: false,
+            sink,
+        });
+    }
+
+    /// Capture the UART traffic in both directions
+    pub fn set_capture(&mut self, capture: Capture) {
+        self.capture = Some(capture);
+    }
+
+    pub fn firmware_started(&mut self, elf: &Path) {
+        self.each(|sink| sink.firmware_started(elf));
+    }
+
+    pub fn write(&mut self, bytes: &[u8]) {
+        self.each(|sink| sink.write(bytes));
+        self.capture(Direction::Tx, bytes);
+    }
+
+    /// Bytes sent to the firmware, which only the capture sees
+    pub fn input(&mut self, bytes: &[u8]) {
+        self.capture(Direction::Rx, bytes);
+    }
+
+    pub fn has_pending(&self) -> bool {
+        self.sinks.iter().any(|entry| entry.sink.has_pending())
+    }
+
+    pub fn flush(&mut self) {
+        self.each(|sink| sink.flush());
+    }
+
+    fn capture(&mut self, direction: Direction, bytes: &[u8]) {
+        let Some(capture) = &mut self.capture else {
+            return;
+        };
+        if let Err(e) = capture.record(direction, bytes) {
+            println!("UART capture failed, stopping it: {:#}", e);
+            self.capture = None;
+        }
+    }
+
+    /// Run `f` on every sink, disabling any which fail
+    fn each(&mut self, mut f: impl FnMut(&mut dyn UartSink) -> Result<()>) {
+        self.sinks.retain_mut(|entry| match f(entry.sink.as_mut()) {
+            Ok(()) => true,
+            Err(e) => {
+                println!("UART sink `{}` failed, disabling it: {:#}", entry.name, e);
+                false
+            }
+        });
+    }
+}
+
+fn open(spec: &SinkSpec, opts: &SinkOptions) -> Result<Box<dyn UartSink>> {
+    (spec.kind.open)(spec.arg.as_deref(), opts)
+        .map_err(|e| e.context(format!("Failed to open UART sink `{}`", spec)))
+}
@@ -0,0 +1,64 @@
+use super::{SinkOptions, UartSink};
+use anyhow::Result;
+use std::fs::File;
+use std::io::{ErrorKind, Write};
+use std::os::unix::io::FromRawFd;
+
+/// Makes the output available on a pseudo terminal, for use with serial terminal programs
+struct Pty {
+    master: File,
+    // keeping the terminal open ourselves means writes don't fail while nobody else has it open
+    _slave: File,
+}
+
+pub fn open(_: Option<&str>, _: &SinkOptions) -> Result<Box<dyn UartSink>> {
+    let mut master = 0;
+    let mut slave = 0;
+    let mut name = [0 as libc::c_char; 128];
+    // SAFETY: the pointers are valid for the duration of the calls, and `name` is nul terminated
+    // by `ttyname_r` on success
+    let path = unsafe {
+        if libc::openpty(
+            &mut master,
+            &mut slave,
+            std::ptr::null_mut(),
+            std::ptr::null(),
+            std::ptr::null(),
+        ) != 0
+        {
+            return Err(std::io::Error::last_os_error().into());
+        }
+        let mut termios = std::mem::zeroed();
+        if libc::tcgetattr(slave, &mut termios) == 0 {
+            libc::cfmakeraw(&mut termios);
+            libc::tcsetattr(slave, libc::TCSANOW, &termios);
+        }
+        // don't hold up the simulation when nobody is reading
+        let flags = libc::fcntl(master, libc::F_GETFL);
+        libc::fcntl(master, libc::F_SETFL, flags | libc::O_NONBLOCK);
+        if libc::ttyname_r(slave, name.as_mut_ptr(), name.len()) != 0 {
+            return Err(std::io::Error::last_os_error().into());
+        }
+        std::ffi::CStr::from_ptr(name.as_ptr())
+            .to_string_lossy()
+            .into_owned()
+    };
+    println!("UART output available on {}", path);
+
+    // SAFETY: both descriptors were just opened and are owned by nothing else
+    Ok(Box::new(unsafe {
+        Pty {
+            master: File::from_raw_fd(master),
+            _slave: File::from_raw_fd(slave),
+        }
+    }))
+}
+
+impl UartSink for Pty {
+    fn write(&mut self, bytes: &[u8]) -> Result<()> {
+        match self.master.write_all(bytes) {
+            Err(e) if e.kind() == ErrorKind::WouldBlock => Ok(()),
+            result => Ok(result?),
+        }
+    }
+}
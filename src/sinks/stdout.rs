@@ -0,0 +1,84 @@
+use super::{SinkOptions, UartSink};
+use crate::log_filter::LogFilter;
+use crate::uart_display::{Renderer, UartDisplay};
+use anyhow::Result;
+use std::io::Write;
+use std::path::Path;
+
+/// Where the rendered output ends up
+type Output = fn(&[u8]) -> Result<()>;
+
+/// Shows the output on the terminal, as chosen with `--uart-display` and `--log-filter`
+struct Stdout {
+    mode: UartDisplay,
+    renderer: Renderer,
+    filter: Option<LogFilter>,
+    output: Output,
+}
+
+pub fn open(_: Option<&str>, opts: &SinkOptions) -> Result<Box<dyn UartSink>> {
+    Ok(rendered(opts, print))
+}
+
+/// A sink which renders the output like stdout does, but hands it to `output`
+pub fn rendered(opts: &SinkOptions, output: Output) -> Box<dyn UartSink> {
+    Box::new(Stdout {
+        mode: opts.display,
+        renderer: Renderer::new(opts.display),
+        filter: opts.log_filter.clone(),
+        output,
+    })
+}
+
+fn print(bytes: &[u8]) -> Result<()> {
+    let mut stdout = std::io::stdout().lock();
+    stdout.write_all(bytes)?;
+    stdout.flush()?;
+    Ok(())
+}
+
+impl Stdout {
+    fn print(&self, bytes: &[u8]) -> Result<()> {
+        (self.output)(bytes)
+    }
+}
+
+impl UartSink for Stdout {
+    fn firmware_started(&mut self, _elf: &Path) -> Result<()> {
+        // hex offsets start again with the new firmware
+        self.flush()?;
+        self.renderer = Renderer::new(self.mode);
+        Ok(())
+    }
+
+    fn write(&mut self, bytes: &[u8]) -> Result<()> {
+        let rendered = match &mut self.filter {
+            Some(filter) => self.renderer.push(&filter.push(bytes)),
+            None => self.renderer.push(bytes),
+        };
+        self.print(&rendered)
+    }
+
+    fn has_pending(&self) -> bool {
+        self.renderer.has_pending() || self.filter.as_ref().is_some_and(|f| f.has_pending())
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        if let Some(filter) = &mut self.filter {
+            let filtered = filter.flush();
+            let rendered = self.renderer.push(&filtered);
+            self.print(&rendered)?;
+        }
+        let rendered = self.renderer.flush();
+        self.print(&rendered)
+    }
+
+    fn set_log_filter(&mut self, filter: Option<&LogFilter>) -> Result<()> {
+        if self.filter.as_ref() != filter {
+            // the line held back by the old filter is decided by it
+            self.flush()?;
+            self.filter = filter.cloned();
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,45 @@
+use super::{parse_addr, SinkOptions, UartSink};
+use anyhow::{Context, Result};
+use std::io::ErrorKind;
+use std::net::{SocketAddr, UdpSocket};
+
+/// the most output sent in one datagram, small enough not to be fragmented
+const MAX_DATAGRAM: usize = 1024;
+
+/// Sends the raw output as datagrams to an address, which may be a multicast group
+struct Udp {
+    socket: UdpSocket,
+    target: SocketAddr,
+}
+
+pub fn open(addr: Option<&str>, _: &SinkOptions) -> Result<Box<dyn UartSink>> {
+    let target = parse_addr(addr.unwrap_or_default())?;
+    let local = match target {
+        SocketAddr::V4(_) => SocketAddr::from(([0, 0, 0, 0], 0)),
+        SocketAddr::V6(_) => SocketAddr::from(([0; 16], 0)),
+    };
+    let socket = UdpSocket::bind(local).context("Failed to open a UDP socket")?;
+    socket.set_nonblocking(true)?;
+    println!("UART output sent to udp://{}", target);
+    Ok(Box::new(Udp { socket, target }))
+}
+
+impl UartSink for Udp {
+    fn write(&mut self, bytes: &[u8]) -> Result<()> {
+        for datagram in bytes.chunks(MAX_DATAGRAM) {
+            match self.socket.send_to(datagram, self.target) {
+                Ok(_) => {}
+                // nobody is listening, or the network can't keep up: the output is just lost
+                Err(e)
+                    if matches!(
+                        e.kind(),
+                        ErrorKind::ConnectionRefused | ErrorKind::WouldBlock
+                    ) => {}
+                Err(e) => {
+                    return Err(e).with_context(|| format!("Failed to send to {}", self.target))
+                }
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,139 @@
+//! Noticing an elf which is older than the sources it is built from, usually because the
+//! firmware wasn't rebuilt after a change
+
+use regex::Regex;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+/// the sources checked when none are given
+pub const DEFAULT_SOURCES: &str = "src/**";
+
+/// directories which never hold sources, and can be large
+const SKIPPED_DIRS: &[&str] = &["target", ".git", "node_modules"];
+
+/// A source file changed after the elf was built
+pub struct NewerSource {
+    pub path: PathBuf,
+    /// how long after the elf was built it changed
+    pub by: std::time::Duration,
+}
+
+impl std::fmt::Display for NewerSource {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "the elf is older than your sources ({} changed {} later), did you forget to build?",
+            self.path.display(),
+            duration(self.by)
+        )
+    }
+}
+
+/// The newest file matching `globs` in the cargo project `elf` was built in, if it is newer
+/// than the elf. Globs are relative to the directory holding `Cargo.toml`, or `base` when the
+/// elf isn't in a cargo project
+pub fn newer_source(elf: &Path, base: &Path, globs: &[String]) -> Option<NewerSource> {
+    let built = modified(elf)?;
+    let root = elf
+        .ancestors()
+        .skip(1)
+        .find(|dir| dir.join("Cargo.toml").is_file())
+        .unwrap_or(base);
+    let default = [DEFAULT_SOURCES.to_owned()];
+    let globs = if globs.is_empty() { &default } else { globs };
+
+    let mut newest: Option<(SystemTime, PathBuf)> = None;
+    for glob in globs {
+        let pattern = to_regex(glob);
+        let mut dirs = vec![root.join(literal_prefix(glob))];
+        while let Some(dir) = dirs.pop() {
+            let Ok(entries) = std::fs::read_dir(&dir) else {
+                continue;
+            };
+            for entry in entries.flatten() {
+                let path = entry.path();
+                let Ok(kind) = entry.file_type() else {
+                    continue;
+                };
+                if kind.is_dir() {
+                    let name = entry.file_name();
+                    if !SKIPPED_DIRS.iter().any(|skipped| name == *skipped) {
+                        dirs.push(path);
+                    }
+                    continue;
+                }
+                let Some(relative) = path.strip_prefix(root).ok().and_then(Path::to_str) else {
+                    continue;
+                };
+                if !pattern.is_match(&relative.replace('\\', "/")) {
+                    continue;
+                }
+                if let Some(changed) = modified(&path) {
+                    if newest.as_ref().is_none_or(|(newest, _)| changed > *newest) {
+                        newest = Some((changed, path));
+                    }
+                }
+            }
+        }
+    }
+
+    let (changed, path) = newest?;
+    let by = changed
+        .duration_since(built)
+        .ok()
+        .filter(|by| !by.is_zero())?;
+    Some(NewerSource { path, by })
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    path.metadata().ok()?.modified().ok()
+}
+
+/// The directories at the start of a glob which have no wildcards, so only they are searched
+fn literal_prefix(glob: &str) -> PathBuf {
+    let mut components: Vec<_> = glob.split('/').collect();
+    // the last component is the file name, even without wildcards
+    components.pop();
+    components
+        .into_iter()
+        .take_while(|c| !c.contains(['*', '?', '[']))
+        .collect()
+}
+
+/// `**` matches any number of directories, `*` and `?` anything but a `/`
+fn to_regex(glob: &str) -> Regex {
+    let mut pattern = String::from("^");
+    let mut rest = glob;
+    while let Some(c) = rest.chars().next() {
+        let (matched, len) = if rest.starts_with("**/") {
+            ("(?:.*/)?".to_owned(), 3)
+        } else if rest.starts_with("**") {
+            (".*".to_owned(), 2)
+        } else if c == '*' {
+            ("[^/]*".to_owned(), 1)
+        } else if c == '?' {
+            ("[^/]".to_owned(), 1)
+        } else {
+            (regex::escape(&c.to_string()), c.len_utf8())
+        };
+        pattern.push_str(&matched);
+        rest = &rest[len..];
+    }
+    pattern.push('$');
+    Regex::new(&pattern).expect("escaped glob is a valid regex")
+}
+
+/// A rough duration, e.g. `3 minutes`
+fn duration(d: std::time::Duration) -> String {
+    let secs = d.as_secs();
+    let (amount, unit) = match secs {
+        0..=119 => (secs, "second"),
+        120..=7199 => (secs / 60, "minute"),
+        7200..=172_799 => (secs / 3600, "hour"),
+        _ => (secs / 86_400, "day"),
+    };
+    match amount {
+        1 => format!("1 {}", unit),
+        n => format!("{} {}s", n, unit),
+    }
+}
@@ -0,0 +1,61 @@
+use crate::firmware_info::FirmwareInfo;
+use anyhow::{Context, Result};
+use serde::Serialize;
+use std::path::Path;
+use std::time::Duration;
+
+/// Totals over the lifetime of the server, printed on exit
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct Stats {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub session_name: Option<String>,
+    /// the firmware simulated last
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub firmware: Option<FirmwareInfo>,
+    /// the `--sim-seed` the simulation ran with
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub seed: Option<u64>,
+    /// how long simulators were connected, in milliseconds
+    pub simulation_ms: u64,
+    pub uart_bytes: u64,
+    /// packets received from GDB clients
+    pub gdb_packets: u64,
+    /// simulator connections after the first one
+    pub reconnects: u64,
+    /// time spent building images and sending them to the simulator, in milliseconds
+    pub transfer_ms: u64,
+}
+
+impl Stats {
+    pub fn print(&self) {
+        println!("Session summary:");
+        if let Some(name) = &self.session_name {
+            println!("  name             {}", name);
+        }
+        if let Some(firmware) = &self.firmware {
+            println!("  firmware         {}", firmware);
+        }
+        if let Some(seed) = self.seed {
+            println!("  seed             {}", seed);
+        }
+        println!("  simulation time  {}", seconds(self.simulation_ms));
+        println!("  UART received    {} bytes", self.uart_bytes);
+        println!("  GDB packets      {}", self.gdb_packets);
+        println!("  reconnects       {}", self.reconnects);
+        println!("  transfer time    {}", seconds(self.transfer_ms));
+    }
+
+    pub fn write(&self, path: &Path) -> Result<()> {
+        std::fs::write(path, serde_json::to_string_pretty(self)?)
+            .with_context(|| format!("Failed to write {}", path.display()))
+    }
+}
+
+/// Milliseconds in a `Duration`
+pub fn millis(duration: Duration) -> u64 {
+    duration.as_millis() as u64
+}
+
+pub fn seconds(ms: u64) -> String {
+    format!("{:.1}s", ms as f64 / 1000.0)
+}
@@ -0,0 +1,237 @@
+use crate::error::{bail, ensure, Result, WokwiServerError};
+
+const SHT_SYMTAB: u32 = 2;
+const SHT_RELA: u32 = 4;
+const SHT_NOBITS: u32 = 8;
+const SHT_REL: u32 = 9;
+const SHF_INFO_LINK: u32 = 0x40;
+const SHN_LORESERVE: u16 = 0xff00;
+const STT_SECTION: u8 = 3;
+
+const PHDR_SIZE: usize = 32;
+const SHDR_SIZE: usize = 40;
+const SYM_SIZE: usize = 16;
+
+#[derive(Debug, Clone)]
+struct SectionHeader {
+    name: u32,
+    r#type: u32,
+    flags: u32,
+    addr: u32,
+    offset: u32,
+    size: u32,
+    link: u32,
+    info: u32,
+    addralign: u32,
+    entsize: u32,
+}
+
+impl SectionHeader {
+    fn parse(b: &[u8]) -> Self {
+        Self {
+            name: read_u32(b, 0),
+            r#type: read_u32(b, 4),
+            flags: read_u32(b, 8),
+            addr: read_u32(b, 12),
+            offset: read_u32(b, 16),
+            size: read_u32(b, 20),
+            link: read_u32(b, 24),
+            info: read_u32(b, 28),
+            addralign: read_u32(b, 32),
+            entsize: read_u32(b, 36),
+        }
+    }
+
+    fn write(&self, out: &mut Vec<u8>) {
+        for v in [
+            self.name,
+            self.r#type,
+            self.flags,
+            self.addr,
+            self.offset,
+            self.size,
+            self.link,
+            self.info,
+            self.addralign,
+            self.entsize,
+        ] {
+            out.extend_from_slice(&v.to_le_bytes());
+        }
+    }
+
+    fn data<'a>(&self, elf: &'a [u8]) -> Result<&'a [u8]> {
+        if self.r#type == SHT_NOBITS {
+            return Ok(&[]);
+        }
+        let end = self.end().ok_or_else(|| out_of_bounds("Section data"))?;
+        elf.get(self.offset as usize..end)
+            .ok_or_else(|| out_of_bounds("Section data"))
+    }
+
+    /// Where the section's data ends in the file, if that can be represented
+    fn end(&self) -> Option<usize> {
+        self.offset.checked_add(self.size).map(|end| end as usize)
+    }
+}
+
+fn out_of_bounds(what: &str) -> WokwiServerError {
+    WokwiServerError::Image(format!("{} out of bounds", what))
+}
+
+fn read_u16(b: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([b[offset], b[offset + 1]])
+}
+
+fn read_u32(b: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes([b[offset], b[offset + 1], b[offset + 2], b[offset + 3]])
+}
+
+fn section_name(strtab: &[u8], offset: u32) -> &str {
+    let name = strtab.get(offset as usize..).unwrap_or_default();
+    let end = name.iter().position(|&c| c == 0).unwrap_or(name.len());
+    std::str::from_utf8(&name[..end]).unwrap_or_default()
+}
+
+/// Strip sections which are not required to run or debug the firmware from a 32-bit little endian elf.
+///
+/// Loadable contents, the symbol table and the string tables are always kept. DWARF debug
+/// info is only kept if `keep_debug` is set.
+pub fn strip_elf(elf: &[u8], keep_debug: bool) -> Result<Vec<u8>> {
+    if elf.len() < 52 || &elf[0..4] != b"\x7fELF" {
+        bail!(Image, "Not an elf file");
+    }
+    if elf[4] != 1 || elf[5] != 1 {
+        bail!(Image, "Only 32-bit little endian elf files can be stripped");
+    }
+
+    let phoff = read_u32(elf, 28) as usize;
+    let shoff = read_u32(elf, 32) as usize;
+    let phentsize = read_u16(elf, 42) as usize;
+    let phnum = read_u16(elf, 44) as usize;
+    let shnum = read_u16(elf, 48) as usize;
+    let shstrndx = read_u16(elf, 50) as usize;
+
+    let headers = shnum
+        .checked_mul(SHDR_SIZE)
+        .and_then(|len| shoff.checked_add(len))
+        .and_then(|end| elf.get(shoff..end))
+        .ok_or_else(|| out_of_bounds("Section headers"))?
+        .chunks(SHDR_SIZE)
+        .map(SectionHeader::parse)
+        .collect::<Vec<_>>();
+    let shstrtab = headers
+        .get(shstrndx)
+        .ok_or_else(|| WokwiServerError::Image("Missing section name table".into()))?
+        .data(elf)?;
+
+    let keep: Vec<bool> = headers
+        .iter()
+        .enumerate()
+        .map(|(i, sh)| {
+            let name = section_name(shstrtab, sh.name);
+            if i == 0 || i == shstrndx {
+                true
+            } else if name.starts_with(".debug") {
+                keep_debug
+            } else {
+                !(sh.r#type == SHT_REL
+                    || sh.r#type == SHT_RELA
+                    || name == ".comment"
+                    || name.starts_with(".xt."))
+            }
+        })
+        .collect();
+
+    let mut index_map = vec![0u32; headers.len()];
+    for (new, (old, _)) in keep.iter().enumerate().filter(|(_, k)| **k).enumerate() {
+        index_map[old] = new as u32;
+    }
+    let remap = |i: u32| index_map.get(i as usize).copied().unwrap_or(0);
+
+    // everything up to the end of the loadable segments is copied verbatim
+    ensure!(
+        phnum == 0 || phentsize >= PHDR_SIZE,
+        Image,
+        "Program headers of {} bytes are too small",
+        phentsize
+    );
+    let phdrs_end = phnum
+        .checked_mul(phentsize)
+        .and_then(|len| phoff.checked_add(len))
+        .ok_or_else(|| out_of_bounds("Program headers"))?;
+    let segments_end = (0..phnum)
+        .map(|i| {
+            let ph = phoff
+                .checked_add(i * phentsize)
+                .and_then(|start| elf.get(start..start.checked_add(PHDR_SIZE)?))
+                .ok_or_else(|| out_of_bounds("Program headers"))?;
+            read_u32(ph, 4)
+                .checked_add(read_u32(ph, 16))
+                .map(|end| end as usize)
+                .ok_or_else(|| out_of_bounds("Segment"))
+        })
+        .try_fold(0, |end, segment| segment.map(|segment| end.max(segment)))?;
+    let prefix_end = phdrs_end.max(segments_end).max(52).min(elf.len());
+    let mut out = elf[..prefix_end].to_vec();
+
+    let mut new_headers = Vec::new();
+    for (i, sh) in headers.iter().enumerate().filter(|(i, _)| keep[*i]) {
+        let mut sh = sh.clone();
+        sh.link = remap(sh.link);
+        if sh.flags & SHF_INFO_LINK != 0 {
+            sh.info = remap(sh.info);
+        }
+
+        let end = sh.end().ok_or_else(|| out_of_bounds("Section data"))?;
+        if i != 0 && sh.r#type != SHT_NOBITS && (end > prefix_end || sh.r#type == SHT_SYMTAB) {
+            let mut data = sh.data(elf)?.to_vec();
+            if sh.r#type == SHT_SYMTAB {
+                let (symbols, first_global) = remap_symbols(&data, sh.info, &remap);
+                data = symbols;
+                sh.info = first_global;
+                sh.size = data.len() as u32;
+            }
+            let align = sh.addralign.max(1) as usize;
+            out.resize(out.len().div_ceil(align) * align, 0);
+            sh.offset = u32::try_from(out.len()).map_err(|_| out_of_bounds("Stripped section"))?;
+            out.extend_from_slice(&data);
+        }
+        new_headers.push(sh);
+    }
+
+    out.resize(out.len().div_ceil(4) * 4, 0);
+    let new_shoff = u32::try_from(out.len()).map_err(|_| out_of_bounds("Section headers"))?;
+    for sh in &new_headers {
+        sh.write(&mut out);
+    }
+
+    out[32..36].copy_from_slice(&new_shoff.to_le_bytes());
+    out[48..50].copy_from_slice(&(new_headers.len() as u16).to_le_bytes());
+    out[50..52].copy_from_slice(&(remap(shstrndx as u32) as u16).to_le_bytes());
+
+    Ok(out)
+}
+
+/// Rewrite symbol section indices, dropping section symbols of removed sections.
+///
+/// Returns the new symbol table and the index of its first global symbol.
+fn remap_symbols(data: &[u8], first_global: u32, remap: &impl Fn(u32) -> u32) -> (Vec<u8>, u32) {
+    let mut out = Vec::with_capacity(data.len());
+    let mut new_first_global = first_global;
+    for (i, sym) in data.chunks_exact(SYM_SIZE).enumerate() {
+        let mut sym = sym.to_vec();
+        let shndx = read_u16(&sym, 14);
+        if shndx != 0 && shndx < SHN_LORESERVE {
+            let new = remap(shndx as u32) as u16;
+            if new == 0 && sym[12] & 0xf == STT_SECTION {
+                if (i as u32) < first_global {
+                    new_first_global -= 1;
+                }
+                continue;
+            }
+            sym[14..16].copy_from_slice(&new.to_le_bytes());
+        }
+        out.extend_from_slice(&sym);
+    }
+    (out, new_first_global)
+}
@@ -0,0 +1,216 @@
+//! GDB target descriptions (`target.xml`), telling GDB which registers a chip has and the order
+//! they are sent in, so `info registers` and unwinding don't rely on GDB's built in guess
+
+use crate::gdb;
+use espflash::Chip;
+use std::collections::HashMap;
+use std::fmt::Write;
+
+/// the feature a GDB stub advertises in its `qSupported` reply to serve target descriptions
+const FEATURE: &str = "qXfer:features:read+";
+
+/// A register, in the order the simulator sends them in a `g` packet
+struct Register {
+    name: String,
+    bits: u32,
+    kind: &'static str,
+}
+
+impl Register {
+    fn new(name: impl Into<String>, bits: u32, kind: &'static str) -> Self {
+        Self {
+            name: name.into(),
+            bits,
+            kind,
+        }
+    }
+}
+
+/// The target description of `chip`, `None` if there isn't one for it
+pub fn for_chip(chip: Chip) -> Option<String> {
+    let registers = registers(chip)?;
+    Some(match chip {
+        Chip::Esp32c2 | Chip::Esp32c3 => {
+            describe("riscv:rv32", "org.gnu.gdb.riscv.cpu", &registers)
+        }
+        _ => describe("xtensa", "org.gnu.gdb.xtensa.core", &registers),
+    })
+}
+
+/// The name and size in bits of each register of `chip`, in the order of a `g` packet
+pub fn layout(chip: Chip) -> Option<Vec<(String, u32)>> {
+    let registers = registers(chip)?;
+    Some(registers.into_iter().map(|r| (r.name, r.bits)).collect())
+}
+
+/// The 32 bit registers of `chip` in the contents of a `g` packet, by name. Registers the packet
+/// is too short for are left out
+pub fn parse_registers(chip: Chip, packet: &[u8]) -> HashMap<String, u32> {
+    let mut values = HashMap::new();
+    let mut offset = 0;
+    for (name, bits) in layout(chip).unwrap_or_default() {
+        if let Some(bytes) = packet.get(offset..offset + 4).filter(|_| bits == 32) {
+            values.insert(name, u32::from_le_bytes(bytes.try_into().unwrap()));
+        }
+        offset += bits as usize / 8;
+    }
+    values
+}
+
+fn registers(chip: Chip) -> Option<Vec<Register>> {
+    let groups = match chip {
+        Chip::Esp32 => vec![
+            lx_core(),
+            ["lbeg", "lend", "lcount"].map(int).into(),
+            special(),
+            ["br", "scompare1", "acclo", "acchi", "m0", "m1", "m2", "m3"]
+                .map(int)
+                .into(),
+            ["expstate", "f64r_lo", "f64r_hi", "f64s"].map(int).into(),
+            fpu(),
+        ],
+        Chip::Esp32s2 => vec![lx_core(), special(), vec![int("gpio_out")]],
+        Chip::Esp32s3 => vec![
+            lx_core(),
+            ["lbeg", "lend", "lcount"].map(int).into(),
+            special(),
+            ["br", "scompare1", "acclo", "acchi", "m0", "m1", "m2", "m3"]
+                .map(int)
+                .into(),
+            fpu(),
+            ["accx_0", "accx_1"].map(int).into(),
+            numbered("qacc_h_", 5, 32, "int"),
+            numbered("qacc_l_", 5, 32, "int"),
+            ["sar_byte", "fft_bit_width"].map(int).into(),
+            numbered("ua_state_", 4, 32, "int"),
+            numbered("q", 8, 128, "uint128"),
+        ],
+        Chip::Esp32c2 | Chip::Esp32c3 => vec![riscv()],
+        _ => return None,
+    };
+    Some(groups.into_iter().flatten().collect())
+}
+
+/// `pc` and the 64 physical address registers of an Xtensa LX core
+fn lx_core() -> Vec<Register> {
+    let mut registers = vec![Register::new("pc", 32, "code_ptr")];
+    registers.extend(numbered("ar", 64, 32, "int"));
+    registers[2].kind = "data_ptr"; // ar1 is the stack pointer
+    registers
+}
+
+/// The special registers every ESP32 core has
+fn special() -> Vec<Register> {
+    [
+        "sar",
+        "windowbase",
+        "windowstart",
+        "configid0",
+        "configid1",
+        "ps",
+        "threadptr",
+    ]
+    .map(int)
+    .into()
+}
+
+fn fpu() -> Vec<Register> {
+    let mut registers = numbered("f", 16, 32, "ieee_single");
+    registers.extend(["fcr", "fsr"].map(int));
+    registers
+}
+
+fn int(name: &str) -> Register {
+    Register::new(name, 32, "int")
+}
+
+/// `count` registers called `<prefix>0`, `<prefix>1`...
+fn numbered(prefix: &str, count: usize, bits: u32, kind: &'static str) -> Vec<Register> {
+    (0..count)
+        .map(|i| Register::new(format!("{}{}", prefix, i), bits, kind))
+        .collect()
+}
+
+fn riscv() -> Vec<Register> {
+    let names = [
+        "zero", "ra", "sp", "gp", "tp", "t0", "t1", "t2", "fp", "s1", "a0", "a1", "a2", "a3", "a4",
+        "a5", "a6", "a7", "s2", "s3", "s4", "s5", "s6", "s7", "s8", "s9", "s10", "s11", "t3", "t4",
+        "t5", "t6", "pc",
+    ];
+    names
+        .iter()
+        .map(|&name| {
+            let kind = match name {
+                "ra" | "pc" => "code_ptr",
+                "sp" | "gp" | "tp" | "fp" => "data_ptr",
+                _ => "int",
+            };
+            Register::new(name, 32, kind)
+        })
+        .collect()
+}
+
+fn describe(architecture: &str, feature: &str, registers: &[Register]) -> String {
+    let mut xml = String::from(
+        "<?xml version=\"1.0\"?>\n<!DOCTYPE target SYSTEM \"gdb-target.dtd\">\n<target version=\"1.0\">\n",
+    );
+    writeln!(xml, "  <architecture>{}</architecture>", architecture).unwrap();
+    writeln!(xml, "  <feature name=\"{}\">", feature).unwrap();
+    for (regnum, register) in registers.iter().enumerate() {
+        writeln!(
+            xml,
+            "    <reg name=\"{}\" bitsize=\"{}\" type=\"{}\" regnum=\"{}\"/>",
+            register.name, register.bits, register.kind, regnum
+        )
+        .unwrap();
+    }
+    xml.push_str("  </feature>\n</target>\n");
+    xml
+}
+
+/// Add the target description feature to a framed `qSupported` reply from the simulator
+pub fn advertise(response: &[u8]) -> Vec<u8> {
+    let features = gdb::unframe(response).map(|f| std::str::from_utf8(f).ok());
+    match features {
+        Some(Some(features)) if !features.split(';').any(|f| f == FEATURE) => {
+            let features = match features {
+                "" => FEATURE.to_owned(),
+                features => format!("{};{}", features, FEATURE),
+            };
+            gdb::frame(features)
+        }
+        _ => response.to_vec(),
+    }
+}
+
+/// The framed reply to `command` if it asks for part of the target description `xml`, e.g.
+/// `qXfer:features:read:target.xml:0,fff`. `None` for any other command
+pub fn read(xml: &[u8], command: &str) -> Option<Vec<u8>> {
+    let request = command.strip_prefix("qXfer:features:read:")?;
+    let reply = match parse_read(request) {
+        Some(("target.xml", offset, length)) => {
+            // offsets are in bytes
+            let rest = &xml[offset.min(xml.len())..];
+            let (kind, part) = match rest.get(..length).filter(|_| length < rest.len()) {
+                Some(part) => (b'm', part),
+                None => (b'l', rest),
+            };
+            let mut reply = vec![kind];
+            reply.extend(gdb::escape_binary(part));
+            reply
+        }
+        _ => b"E00".to_vec(),
+    };
+    Some(gdb::frame(reply))
+}
+
+/// `<annex>:<offset>,<length>`, both in hex
+fn parse_read(request: &str) -> Option<(&str, usize, usize)> {
+    let (annex, range) = request.split_once(':')?;
+    let (offset, length) = range.split_once(',')?;
+    Some((
+        annex,
+        usize::from_str_radix(offset, 16).ok()?,
+        usize::from_str_radix(length, 16).ok()?,
+    ))
+}
@@ -0,0 +1,513 @@
+//! Helpers for end-to-end tests, which stand in for the Wokwi embed running in a browser and for
+//! GDB
+
+use crate::gdb::{self, GdbPacket};
+use anyhow::{Context, Result};
+use futures_util::{SinkExt, StreamExt};
+use serde_json::{json, Value};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio_tungstenite::{MaybeTlsStream, WebSocketStream};
+
+/// how long to wait for the server to start listening, or to answer
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A client speaking the embed protocol, as the simulator in the browser does
+pub struct MockSimulator {
+    websocket: WebSocketStream<MaybeTlsStream<TcpStream>>,
+    /// size in bytes of the largest message received so far
+    pub largest_message: usize,
+    /// payloads of the pings received so far
+    pub pings: Vec<String>,
+}
+
+impl MockSimulator {
+    /// Connect to the server on `port`, waiting for it to start listening
+    pub async fn connect(port: u16) -> Result<Self> {
+        let url = format!("ws://127.0.0.1:{}", port);
+        let connect = async {
+            loop {
+                match tokio_tungstenite::connect_async(&url).await {
+                    Ok((websocket, _)) => return websocket,
+                    Err(_) => tokio::time::sleep(Duration::from_millis(50)).await,
+                }
+            }
+        };
+        let websocket = tokio::time::timeout(TIMEOUT, connect)
+            .await
+            .with_context(|| format!("Timed out connecting to {}", url))?;
+        Ok(Self {
+            websocket,
+            largest_message: 0,
+            pings: Vec::new(),
+        })
+    }
+
+    /// Send the hello message and wait for the start packet
+    pub async fn handshake(&mut self) -> Result<Value> {
+        self.handshake_with(json!({ "type": "hello", "protocolVersion": 1 }))
+            .await
+    }
+
+    /// Send a hello message, e.g. offering capabilities, and wait for the start packet
+    pub async fn handshake_with(&mut self, hello: Value) -> Result<Value> {
+        self.send(hello).await?;
+        let start = self.recv().await?;
+        anyhow::ensure!(
+            start["type"] == "start",
+            "Expected a start packet, got {}",
+            start
+        );
+        Ok(start)
+    }
+
+    /// Send bytes as if the firmware had written them to the UART
+    pub async fn uart(&mut self, bytes: &[u8]) -> Result<()> {
+        self.send(json!({ "type": "uartData", "bytes": bytes }))
+            .await
+    }
+
+    /// Answer a GDB command, sent as a binary string as the simulator does
+    pub async fn gdb_response(&mut self, response: &[u8]) -> Result<()> {
+        let response = gdb::to_binary_string(response);
+        self.send(json!({ "type": "gdbResponse", "response": response }))
+            .await
+    }
+
+    /// Send any message, including ones the real simulator never would
+    pub async fn send(&mut self, message: Value) -> Result<()> {
+        self.send_text(&message.to_string()).await
+    }
+
+    pub async fn send_text(&mut self, text: &str) -> Result<()> {
+        self.websocket
+            .send(tungstenite::Message::Text(text.to_owned()))
+            .await?;
+        Ok(())
+    }
+
+    /// The next message from the server, put back together if it was sent in chunks
+    pub async fn recv(&mut self) -> Result<Value> {
+        let (mut data, mut received) = (Vec::new(), 0);
+        loop {
+            let message = self.recv_frame().await?;
+            if message["type"] != "chunk" {
+                return Ok(message);
+            }
+            anyhow::ensure!(
+                message["index"] == received,
+                "Chunk out of order: {}",
+                message["index"]
+            );
+            data.extend(base64::decode(
+                message["data"].as_str().context("Chunk without data")?,
+            )?);
+            received += 1;
+            if message["count"] == received {
+                return Ok(serde_json::from_slice(&data)?);
+            }
+        }
+    }
+
+    /// The next message from the server as it was sent
+    async fn recv_frame(&mut self) -> Result<Value> {
+        loop {
+            let msg = tokio::time::timeout(TIMEOUT, self.websocket.next())
+                .await
+                .context("Timed out waiting for a message")?
+                .context("Server closed the connection")??;
+            if msg.is_text() {
+                let text = msg.to_text()?;
+                self.largest_message = self.largest_message.max(text.len());
+                return Ok(serde_json::from_str(text)?);
+            }
+            if let tungstenite::Message::Ping(payload) = &msg {
+                self.pings
+                    .push(String::from_utf8_lossy(payload).into_owned());
+            }
+            anyhow::ensure!(!msg.is_close(), "Server closed the connection");
+        }
+    }
+
+    pub async fn close(mut self) -> Result<()> {
+        self.websocket.close(None).await?;
+        Ok(())
+    }
+}
+
+/// A GDB client following a script byte by byte, to check the server speaks the remote serial
+/// protocol: acknowledging packets, rejecting bad checksums and sending packets again when asked
+pub struct FakeGdb {
+    stream: TcpStream,
+    /// bytes received but not taken yet
+    received: Vec<u8>,
+}
+
+impl FakeGdb {
+    /// Connect to the GDB server on `port`, waiting for it to start listening
+    pub async fn connect(port: u16) -> Result<Self> {
+        let connect = async {
+            loop {
+                match TcpStream::connect(("127.0.0.1", port)).await {
+                    Ok(stream) => return stream,
+                    Err(_) => tokio::time::sleep(Duration::from_millis(50)).await,
+                }
+            }
+        };
+        let stream = tokio::time::timeout(TIMEOUT, connect)
+            .await
+            .with_context(|| format!("Timed out connecting to port {}", port))?;
+        Ok(Self {
+            stream,
+            received: Vec::new(),
+        })
+    }
+
+    /// Send a command, framed with its checksum
+    pub async fn send(&mut self, command: &str) -> Result<()> {
+        self.send_bytes(command.as_bytes()).await
+    }
+
+    /// Send a command which isn't text, e.g. the escaped binary data of an `X` packet, framed
+    /// with its checksum
+    pub async fn send_bytes(&mut self, command: &[u8]) -> Result<()> {
+        let mut packet = vec![b'$'];
+        packet.extend_from_slice(command);
+        packet.extend_from_slice(format!("#{:02x}", gdb::checksum(command)).as_bytes());
+        self.write(&packet).await
+    }
+
+    /// Send anything, e.g. a packet with a wrong checksum, an acknowledgement or Ctrl-C
+    pub async fn write(&mut self, bytes: &[u8]) -> Result<()> {
+        self.stream.write_all(bytes).await?;
+        Ok(())
+    }
+
+    /// Expect the next byte to be a `+` acknowledgement
+    pub async fn expect_ack(&mut self) -> Result<()> {
+        let byte = self.byte().await?;
+        anyhow::ensure!(byte == b'+', "Expected +, got {:?}", byte as char);
+        Ok(())
+    }
+
+    /// Expect the next byte to be a `-`, rejecting the last packet
+    pub async fn expect_nak(&mut self) -> Result<()> {
+        let byte = self.byte().await?;
+        anyhow::ensure!(byte == b'-', "Expected -, got {:?}", byte as char);
+        Ok(())
+    }
+
+    /// The next packet, which has to come before anything else and have the right checksum
+    pub async fn packet(&mut self) -> Result<Vec<u8>> {
+        loop {
+            match gdb::parse_packet(&self.received) {
+                Some((GdbPacket::Command(packet), len)) => {
+                    self.received.drain(..len);
+                    return Ok(packet);
+                }
+                Some((
+                    GdbPacket::BadChecksum {
+                        received,
+                        calculated,
+                    },
+                    _,
+                )) => anyhow::bail!(
+                    "Packet with checksum {}, calculated {:02x}",
+                    received,
+                    calculated
+                ),
+                Some(_) => anyhow::bail!(
+                    "Expected a packet, got {:?}",
+                    String::from_utf8_lossy(&self.received)
+                ),
+                None => self.fill().await?,
+            }
+        }
+    }
+
+    /// Expect nothing to be received for `duration`
+    pub async fn expect_silence(&mut self, duration: Duration) -> Result<()> {
+        anyhow::ensure!(
+            self.received.is_empty(),
+            "Expected nothing, got {:?}",
+            String::from_utf8_lossy(&self.received)
+        );
+        if tokio::time::timeout(duration, self.fill()).await.is_ok() {
+            anyhow::bail!(
+                "Expected nothing, got {:?}",
+                String::from_utf8_lossy(&self.received)
+            );
+        }
+        Ok(())
+    }
+
+    async fn byte(&mut self) -> Result<u8> {
+        if self.received.is_empty() {
+            self.fill().await?;
+        }
+        Ok(self.received.remove(0))
+    }
+
+    async fn fill(&mut self) -> Result<()> {
+        let mut buf = [0; 1024];
+        let n = tokio::time::timeout(TIMEOUT, self.stream.read(&mut buf))
+            .await
+            .context("Timed out waiting for the GDB server")??;
+        anyhow::ensure!(n > 0, "GDB server closed the connection");
+        self.received.extend_from_slice(&buf[..n]);
+        Ok(())
+    }
+}
+
+/// A tiny firmware elf, with a single loadable section in the ESP32's IRAM
+pub fn minimal_elf() -> Vec<u8> {
+    const XTENSA: u16 = 94;
+    elf_with_text(
+        XTENSA,
+        0x4008_0000,
+        &[0x06, 0xff, 0xff, 0x00, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0],
+        &[],
+    )
+}
+
+/// A tiny ESP32-C3 firmware elf for direct boot, its code mapped from the start of flash and
+/// beginning with the magic the ROM looks for
+pub fn direct_boot_elf() -> Vec<u8> {
+    const RISCV: u16 = 243;
+    elf_with_text(
+        RISCV,
+        0x4200_0000,
+        &[
+            0x1d, 0x04, 0xdb, 0xae, 0x1d, 0x04, 0xdb, 0xae, 0x6f, 0, 0, 0, 0, 0, 0, 0,
+        ],
+        &[],
+    )
+}
+
+/// [`minimal_elf`] with two functions, `app_main` at 0x40080000 and `panic_handler` at 0x40080008
+pub fn functions_elf() -> Vec<u8> {
+    const XTENSA: u16 = 94;
+    elf_with_text(
+        XTENSA,
+        0x4008_0000,
+        &[0x06, 0xff, 0xff, 0x00, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0],
+        &[
+            ("app_main", 0x4008_0000, 8),
+            ("panic_handler", 0x4008_0008, 8),
+        ],
+    )
+}
+
+/// A bootloader elf for the ESP32, with `bootloader_main` at 0x40078000 where the bootloader runs
+/// from, below the app's code
+pub fn bootloader_elf() -> Vec<u8> {
+    const XTENSA: u16 = 94;
+    elf_with_text(
+        XTENSA,
+        0x4007_8000,
+        &[0x06, 0xff, 0xff, 0x00, 0, 0, 0, 0],
+        &[("bootloader_main", 0x4007_8000, 8)],
+    )
+}
+
+/// where the kernel variables of [`freertos_elf`] are
+const CURRENT_TCB: u32 = 0x3ffb_0000;
+const READY_LISTS: u32 = 0x3ffb_0010;
+const DELAYED_LIST: u32 = 0x3ffb_0040;
+const SUSPENDED_LIST: u32 = 0x3ffb_0060;
+
+/// The tasks of [`freertos_elf`]: their control blocks, names and the list they are on.
+/// `main` is running
+pub const FREERTOS_TASKS: &[(u32, &str, u32)] = &[
+    (0x3ffb_1000, "main", READY_LISTS + 20),
+    (0x3ffb_1100, "IDLE", READY_LISTS),
+    (0x3ffb_1200, "Tmr Svc", DELAYED_LIST),
+];
+
+/// [`minimal_elf`] with the symbols of the FreeRTOS kernel's task lists, two priorities of
+/// ready lists, a delayed and a suspended list
+pub fn freertos_elf() -> Vec<u8> {
+    const XTENSA: u16 = 94;
+    elf_with_text(
+        XTENSA,
+        0x4008_0000,
+        &[0x06, 0xff, 0xff, 0x00, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0],
+        &[
+            ("pxCurrentTCB", CURRENT_TCB, 4),
+            ("pxReadyTasksLists", READY_LISTS, 40),
+            ("xDelayedTaskList1", DELAYED_LIST, 20),
+            ("xSuspendedTaskList", SUSPENDED_LIST, 20),
+        ],
+    )
+}
+
+/// `len` bytes of the memory of [`freertos_elf`] at `addr`, with [`FREERTOS_TASKS`] on their
+/// lists. `None` outside of the kernel's variables and the tasks
+pub fn freertos_memory(addr: u32, len: u32) -> Option<Vec<u8>> {
+    let mut memory = std::collections::BTreeMap::new();
+    let mut write = |addr: u32, words: &[u32]| {
+        for (i, word) in words.iter().enumerate() {
+            for (j, byte) in word.to_le_bytes().into_iter().enumerate() {
+                memory.insert(addr + (i * 4 + j) as u32, byte);
+            }
+        }
+    };
+    write(CURRENT_TCB, &[FREERTOS_TASKS[0].0]);
+    for list in [READY_LISTS, READY_LISTS + 20, DELAYED_LIST, SUSPENDED_LIST] {
+        // an empty list's end marker points at itself
+        let end = list + 8;
+        match FREERTOS_TASKS.iter().find(|(_, _, on)| *on == list) {
+            Some(&(tcb, name, _)) => {
+                // xStateListItem follows pxTopOfStack
+                let item = tcb + 4;
+                write(list, &[1, end, u32::MAX, item, item]);
+                write(item, &[0, end, end, tcb, list]);
+                let mut name = name.as_bytes().to_vec();
+                name.resize(16, 0);
+                let words: Vec<_> = name
+                    .chunks(4)
+                    .map(|b| u32::from_le_bytes(b.try_into().unwrap()))
+                    .collect();
+                write(tcb + 52, &words);
+            }
+            None => write(list, &[0, end, u32::MAX, end, end]),
+        }
+    }
+    (addr..addr + len)
+        .map(|addr| memory.get(&addr).copied())
+        .collect()
+}
+
+/// where the allocator variables of [`heap_elf`] are
+const FREE_BYTES: u32 = 0x3ffb_0100;
+const MINIMUM_FREE_BYTES: u32 = 0x3ffb_0104;
+const FREE_LIST: u32 = 0x3ffb_0108;
+
+/// [`minimal_elf`] with the symbols of FreeRTOS' heap_4
+pub fn heap_elf() -> Vec<u8> {
+    const XTENSA: u16 = 94;
+    elf_with_text(
+        XTENSA,
+        0x4008_0000,
+        &[0x06, 0xff, 0xff, 0x00, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0],
+        &[
+            ("xFreeBytesRemaining", FREE_BYTES, 4),
+            ("xMinimumEverFreeBytesRemaining", MINIMUM_FREE_BYTES, 4),
+            ("xStart", FREE_LIST, 8),
+        ],
+    )
+}
+
+/// `len` bytes of the memory of [`heap_elf`] at `addr`: 0x3000 bytes free in blocks of 0x1000
+/// and 0x2000, and 0x2000 at the lowest. `None` outside of the allocator's variables and blocks
+pub fn heap_memory(addr: u32, len: u32) -> Option<Vec<u8>> {
+    let (first, second, end) = (0x3ffc_0000, 0x3ffc_4000, 0x3ffc_8000);
+    // the free list is made of pxNextFreeBlock and xBlockSize pairs, ending at pxEnd
+    let words: [(u32, &[u32]); 6] = [
+        (FREE_BYTES, &[0x3000]),
+        (MINIMUM_FREE_BYTES, &[0x2000]),
+        (FREE_LIST, &[first, 0]),
+        (first, &[second, 0x1000]),
+        (second, &[end, 0x2000]),
+        (end, &[0, 0]),
+    ];
+    let memory: std::collections::BTreeMap<u32, u8> = words
+        .iter()
+        .flat_map(|(at, words)| {
+            let bytes = words.iter().flat_map(|w| w.to_le_bytes());
+            (*at..).zip(bytes)
+        })
+        .collect();
+    (addr..addr + len)
+        .map(|addr| memory.get(&addr).copied())
+        .collect()
+}
+
+/// A symbol in a test elf: its name, address and size
+type Symbol<'a> = (&'a str, u32, u32);
+
+/// An executable with a single loadable section holding `text` at `addr`, and a symbol table
+/// if there are any `symbols`
+fn elf_with_text(machine: u16, addr: u32, text: &[u8], symbols: &[Symbol]) -> Vec<u8> {
+    const TEXT_OFFSET: u32 = 0x60;
+    let mut shstrtab = b"\0.iram0.text\0.shstrtab\0".to_vec();
+    let mut strtab = vec![0];
+    let mut symtab = vec![0; 16];
+    if !symbols.is_empty() {
+        shstrtab.extend_from_slice(b".symtab\0.strtab\0");
+        for &(name, value, size) in symbols {
+            symtab.extend_from_slice(&(strtab.len() as u32).to_le_bytes());
+            symtab.extend_from_slice(&value.to_le_bytes());
+            symtab.extend_from_slice(&size.to_le_bytes());
+            // a global function in the text, or an object elsewhere, with an absolute address
+            let is_code = (addr..addr + text.len() as u32).contains(&value);
+            let kind = if is_code { 0x12 } else { 0x11 };
+            symtab.extend_from_slice(&[kind, 0, 0xf1, 0xff]);
+            strtab.extend_from_slice(name.as_bytes());
+            strtab.push(0);
+        }
+    }
+    let text_len = text.len() as u32;
+    let shstrtab_offset = TEXT_OFFSET + text_len;
+    let shstrtab_len = shstrtab.len() as u32;
+    let symtab_offset = (shstrtab_offset + shstrtab_len).next_multiple_of(4);
+    let strtab_offset = symtab_offset + symtab.len() as u32;
+    let (sections, end) = match symbols.is_empty() {
+        true => (3, shstrtab_offset + shstrtab_len),
+        false => (5, strtab_offset + strtab.len() as u32),
+    };
+    let shoff = end.next_multiple_of(4);
+
+    let mut elf = Vec::new();
+    let u16 = |elf: &mut Vec<u8>, v: u16| elf.extend_from_slice(&v.to_le_bytes());
+    let u32 = |elf: &mut Vec<u8>, v: u32| elf.extend_from_slice(&v.to_le_bytes());
+
+    // elf header: 32-bit, little endian, executable
+    elf.extend_from_slice(b"\x7fELF\x01\x01\x01\0\0\0\0\0\0\0\0\0");
+    u16(&mut elf, 2);
+    u16(&mut elf, machine);
+    u32(&mut elf, 1);
+    u32(&mut elf, addr);
+    u32(&mut elf, 52);
+    u32(&mut elf, shoff);
+    u32(&mut elf, 0);
+    for v in [52, 32, 1, 40, sections, 2] {
+        u16(&mut elf, v);
+    }
+
+    // a single loadable, executable segment
+    for v in [1, TEXT_OFFSET, addr, addr, text_len, text_len, 5, 4] {
+        u32(&mut elf, v);
+    }
+
+    elf.resize(TEXT_OFFSET as usize, 0);
+    elf.extend_from_slice(text);
+    elf.extend_from_slice(&shstrtab);
+    if !symbols.is_empty() {
+        elf.resize(symtab_offset as usize, 0);
+        elf.extend_from_slice(&symtab);
+        elf.extend_from_slice(&strtab);
+    }
+    elf.resize(shoff as usize, 0);
+
+    // section headers: null, .iram0.text (progbits, alloc + exec) and .shstrtab, then .symtab
+    // and .strtab
+    elf.extend_from_slice(&[0; 40]);
+    for v in [1, 1, 6, addr, TEXT_OFFSET, text_len, 0, 0, 4, 0] {
+        u32(&mut elf, v);
+    }
+    for v in [13, 3, 0, 0, shstrtab_offset, shstrtab_len, 0, 0, 1, 0] {
+        u32(&mut elf, v);
+    }
+    if !symbols.is_empty() {
+        let symtab_len = symtab.len() as u32;
+        for v in [23, 2, 0, 0, symtab_offset, symtab_len, 4, 1, 4, 16] {
+            u32(&mut elf, v);
+        }
+        for v in [31, 3, 0, 0, strtab_offset, strtab.len() as u32, 0, 0, 1, 0] {
+            u32(&mut elf, v);
+        }
+    }
+    elf
+}
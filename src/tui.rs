@@ -0,0 +1,307 @@
+use crate::activity::{self, Activity};
+use crate::control::ControlRequest;
+use crate::session::{SessionInfo, Sessions};
+use anyhow::{Context, Result};
+use ratatui::backend::CrosstermBackend;
+use ratatui::crossterm::event::{self, Event, KeyCode, KeyEventKind, KeyModifiers};
+use ratatui::crossterm::terminal::{self, EnterAlternateScreen, LeaveAlternateScreen};
+use ratatui::crossterm::ExecutableCommand;
+use ratatui::layout::{Constraint, Layout, Rect};
+use ratatui::style::{Modifier, Style};
+use ratatui::text::Line;
+use ratatui::widgets::{Block, Borders, Paragraph};
+use ratatui::{Frame, Terminal};
+use regex::Regex;
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use std::time::Duration;
+use tokio::sync::{mpsc, oneshot};
+use tokio_util::sync::CancellationToken;
+
+/// how often the screen is redrawn
+const TICK: Duration = Duration::from_millis(100);
+/// lines kept in each pane
+const MAX_LINES: usize = 1000;
+/// GDB packets are cut to this many characters
+const MAX_PACKET_LEN: usize = 120;
+
+/// Lines of text, the newest last
+#[derive(Default)]
+struct Pane {
+    lines: VecDeque<String>,
+    /// the current line, until its newline arrives
+    partial: String,
+}
+
+impl Pane {
+    fn push_line(&mut self, line: String) {
+        if self.lines.len() == MAX_LINES {
+            self.lines.pop_front();
+        }
+        self.lines.push_back(line);
+    }
+
+    fn push_text(&mut self, text: &str) {
+        for c in text.chars() {
+            match c {
+                '\n' => {
+                    let line = std::mem::take(&mut self.partial);
+                    self.push_line(line);
+                }
+                '\r' => {}
+                c => self.partial.push(c),
+            }
+        }
+    }
+
+    /// The last lines which fit in `height` rows
+    fn tail(&self, height: u16) -> Vec<Line<'_>> {
+        let lines = self
+            .lines
+            .iter()
+            .map(String::as_str)
+            .chain((!self.partial.is_empty()).then_some(self.partial.as_str()));
+        let count = self.lines.len() + usize::from(!self.partial.is_empty());
+        lines
+            .skip(count.saturating_sub(height as usize))
+            .map(Line::raw)
+            .collect()
+    }
+}
+
+#[derive(Default)]
+struct State {
+    /// the simulation link
+    url: String,
+    uart: Pane,
+    gdb: Pane,
+    log: Pane,
+    transfer: String,
+    /// UART output received while paused
+    held: Vec<u8>,
+    paused: bool,
+}
+
+impl State {
+    fn apply(&mut self, activity: Activity, ansi: &Regex) {
+        match activity {
+            Activity::Uart(bytes) if self.paused => self.held.extend_from_slice(&bytes),
+            Activity::Uart(bytes) => {
+                let text = String::from_utf8_lossy(&bytes);
+                self.uart.push_text(&ansi.replace_all(&text, ""));
+            }
+            Activity::Gdb { to_target, packet } => {
+                let arrow = if to_target { '→' } else { '←' };
+                let packet: String = packet.chars().take(MAX_PACKET_LEN).collect();
+                self.gdb.push_line(format!("{} {}", arrow, packet));
+            }
+            Activity::Transfer(status) => self.transfer = status,
+        }
+    }
+
+    fn toggle_pause(&mut self, ansi: &Regex) {
+        self.paused = !self.paused;
+        if !self.paused {
+            let held = std::mem::take(&mut self.held);
+            self.apply(Activity::Uart(held), ansi);
+        }
+    }
+
+    fn draw(&self, frame: &mut Frame, sessions: &[SessionInfo]) {
+        let [status, main, help] = Layout::vertical([
+            Constraint::Length(4),
+            Constraint::Min(0),
+            Constraint::Length(1),
+        ])
+        .areas(frame.area());
+        let [uart, side] =
+            Layout::horizontal([Constraint::Percentage(60), Constraint::Percentage(40)])
+                .areas(main);
+        let [gdb, log] =
+            Layout::vertical([Constraint::Percentage(50), Constraint::Percentage(50)]).areas(side);
+
+        let connections = if sessions.is_empty() {
+            "waiting for the simulator".to_owned()
+        } else {
+            sessions
+                .iter()
+                .map(|s| format!("{} ({})", s.id, s.peer))
+                .collect::<Vec<_>>()
+                .join(", ")
+        };
+        frame.render_widget(
+            Paragraph::new(vec![
+                Line::raw(self.url.as_str()),
+                Line::raw(format!("{}  {}", connections, self.transfer)),
+            ])
+            .block(Block::default().borders(Borders::ALL).title("Status")),
+            status,
+        );
+
+        let uart_title = if self.paused { "UART (paused)" } else { "UART" };
+        render_pane(frame, &self.uart, uart_title, uart);
+        render_pane(frame, &self.gdb, "GDB", gdb);
+        render_pane(frame, &self.log, "Server", log);
+        frame.render_widget(
+            Paragraph::new("q quit  r restart  p pause output")
+                .style(Style::default().add_modifier(Modifier::DIM)),
+            help,
+        );
+    }
+}
+
+fn render_pane(frame: &mut Frame, pane: &Pane, title: &str, area: Rect) {
+    let lines = pane.tail(area.height.saturating_sub(2));
+    frame.render_widget(
+        Paragraph::new(lines).block(Block::default().borders(Borders::ALL).title(title)),
+        area,
+    );
+}
+
+/// Sends everything written to stdout through a pipe until dropped, so prints don't garble the UI
+struct CapturedStdout {
+    saved: i32,
+}
+
+impl CapturedStdout {
+    fn start() -> Result<(Self, File)> {
+        use std::os::unix::io::FromRawFd;
+
+        let mut fds = [0; 2];
+        // SAFETY: the descriptors are created here, and stdout is restored when dropped
+        unsafe {
+            if libc::pipe(fds.as_mut_ptr()) != 0 {
+                return Err(std::io::Error::last_os_error().into());
+            }
+            let saved = libc::dup(libc::STDOUT_FILENO);
+            libc::dup2(fds[1], libc::STDOUT_FILENO);
+            libc::close(fds[1]);
+            Ok((Self { saved }, File::from_raw_fd(fds[0])))
+        }
+    }
+}
+
+impl Drop for CapturedStdout {
+    fn drop(&mut self) {
+        // SAFETY: `saved` is a copy of the original stdout, owned by us
+        unsafe {
+            libc::dup2(self.saved, libc::STDOUT_FILENO);
+            libc::close(self.saved);
+        }
+    }
+}
+
+/// Puts the terminal back the way it was when dropped
+struct RawTerminal;
+
+impl Drop for RawTerminal {
+    fn drop(&mut self) {
+        terminal::disable_raw_mode().ok();
+        if let Ok(mut tty) = open_tty() {
+            tty.execute(LeaveAlternateScreen).ok();
+        }
+    }
+}
+
+fn open_tty() -> std::io::Result<File> {
+    std::fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open("/dev/tty")
+}
+
+/// Show the UI until shutdown, `q` asks the server to exit
+pub async fn run(
+    url: String,
+    sessions: Sessions,
+    control: mpsc::Sender<ControlRequest>,
+    exit: mpsc::Sender<i32>,
+    shutdown: CancellationToken,
+) -> Result<()> {
+    let mut tty = open_tty().context("--tui needs a terminal")?;
+    let mut activity = activity::subscribe();
+
+    let (captured, output) = CapturedStdout::start()?;
+    let (log_send, mut log) = mpsc::unbounded_channel();
+    std::thread::spawn(move || {
+        let mut output = BufReader::new(output);
+        let mut line = Vec::new();
+        while matches!(output.read_until(b'\n', &mut line), Ok(n) if n > 0) {
+            if log_send
+                .send(String::from_utf8_lossy(&line).into_owned())
+                .is_err()
+            {
+                break;
+            }
+            line.clear();
+        }
+    });
+
+    let (key_send, mut keys) = mpsc::unbounded_channel();
+    std::thread::spawn(move || {
+        while !key_send.is_closed() {
+            if let Ok(true) = event::poll(TICK) {
+                match event::read() {
+                    Ok(Event::Key(key)) if key.kind == KeyEventKind::Press => {
+                        key_send.send(key).ok();
+                    }
+                    _ => {}
+                }
+            }
+        }
+    });
+
+    terminal::enable_raw_mode()?;
+    let raw = RawTerminal;
+    tty.execute(EnterAlternateScreen)?;
+    let mut terminal = Terminal::new(CrosstermBackend::new(tty))?;
+
+    let ansi = Regex::new(r"\x1b\[[0-9;?]*[A-Za-z]").unwrap();
+    let mut state = State {
+        url,
+        ..State::default()
+    };
+    let mut tick = tokio::time::interval(TICK);
+    loop {
+        tokio::select! {
+            Some(line) = log.recv() => state.log.push_text(&ansi.replace_all(&line, "")),
+            Ok(activity) = activity.recv() => state.apply(activity, &ansi),
+            Some(key) = keys.recv() => match key.code {
+                KeyCode::Char('q') => {
+                    exit.try_send(0).ok();
+                }
+                KeyCode::Char('c') if key.modifiers.contains(KeyModifiers::CONTROL) => {
+                    exit.try_send(0).ok();
+                }
+                KeyCode::Char('r') => restart(&control),
+                KeyCode::Char('p') => state.toggle_pause(&ansi),
+                _ => {}
+            },
+            _ = tick.tick() => {
+                let sessions = sessions.list();
+                terminal.draw(|frame| state.draw(frame, &sessions))?;
+            }
+            _ = shutdown.cancelled() => break,
+        }
+    }
+
+    drop(terminal);
+    drop(raw);
+    drop(captured);
+    Ok(())
+}
+
+/// Ask the simulation to start the firmware again
+fn restart(control: &mpsc::Sender<ControlRequest>) {
+    let (reply, result) = oneshot::channel();
+    if control.try_send(ControlRequest::Restart { reply }).is_err() {
+        println!("Can't restart right now");
+        return;
+    }
+    tokio::spawn(async move {
+        if let Ok(Err(e)) = result.await {
+            println!("Restart failed: {:#}", e);
+        }
+    });
+}
@@ -0,0 +1,95 @@
+use std::fmt::Write;
+
+const ROW_SIZE: usize = 16;
+
+/// How UART output from the simulation is shown
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum UartDisplay {
+    /// write the bytes to stdout untouched
+    Text,
+    /// hex dump with offsets and an ASCII column
+    Hex,
+    /// printable text as-is, other bytes escaped as `\xNN`
+    Mixed,
+}
+
+/// Renders UART output according to the chosen display mode
+pub struct Renderer {
+    mode: UartDisplay,
+    offset: usize,
+    pending: Vec<u8>,
+}
+
+impl Renderer {
+    pub fn new(mode: UartDisplay) -> Self {
+        Self {
+            mode,
+            offset: 0,
+            pending: Vec::new(),
+        }
+    }
+
+    /// Render newly received bytes, hex rows are held back until complete
+    pub fn push(&mut self, bytes: &[u8]) -> Vec<u8> {
+        match self.mode {
+            UartDisplay::Text => bytes.to_vec(),
+            UartDisplay::Mixed => escape(bytes).into_bytes(),
+            UartDisplay::Hex => {
+                self.pending.extend_from_slice(bytes);
+                let complete = self.pending.len() - self.pending.len() % ROW_SIZE;
+                let rows: Vec<u8> = self.pending.drain(..complete).collect();
+                self.hex_rows(&rows).into_bytes()
+            }
+        }
+    }
+
+    /// Whether some output is being held back
+    pub fn has_pending(&self) -> bool {
+        !self.pending.is_empty()
+    }
+
+    /// Render any incomplete hex row
+    pub fn flush(&mut self) -> Vec<u8> {
+        let rows = std::mem::take(&mut self.pending);
+        self.hex_rows(&rows).into_bytes()
+    }
+
+    fn hex_rows(&mut self, bytes: &[u8]) -> String {
+        let mut out = String::new();
+        for row in bytes.chunks(ROW_SIZE) {
+            write!(out, "{:08x}  ", self.offset).ok();
+            for i in 0..ROW_SIZE {
+                match row.get(i) {
+                    Some(b) => write!(out, "{:02x} ", b).ok(),
+                    None => write!(out, "   ").ok(),
+                };
+                if i == ROW_SIZE / 2 - 1 {
+                    out.push(' ');
+                }
+            }
+            out.push_str(" |");
+            out.extend(row.iter().map(|&b| {
+                if b.is_ascii_graphic() || b == b' ' {
+                    b as char
+                } else {
+                    '.'
+                }
+            }));
+            out.push_str("|\n");
+            self.offset += row.len();
+        }
+        out
+    }
+}
+
+fn escape(bytes: &[u8]) -> String {
+    let mut out = String::with_capacity(bytes.len());
+    for &b in bytes {
+        if b.is_ascii_graphic() || matches!(b, b' ' | b'\n' | b'\r' | b'\t') {
+            out.push(b as char);
+        } else {
+            write!(out, "\\x{:02x}", b).ok();
+        }
+    }
+    out
+}
@@ -0,0 +1,71 @@
+use std::path::PathBuf;
+use std::time::Duration;
+use tokio::io::AsyncReadExt;
+use tokio::sync::mpsc;
+
+/// How to pace data fed into the simulated UART
+#[derive(Debug, Clone)]
+pub struct Pacing {
+    /// bytes per second, or as fast as possible if unset
+    pub rate: Option<u32>,
+    /// pause after every newline
+    pub line_delay: Option<Duration>,
+    /// wait before sending anything, to give the firmware time to boot
+    pub start_delay: Option<Duration>,
+}
+
+const TICK: Duration = Duration::from_millis(10);
+
+/// Stream the contents of a file or named pipe, paced as requested
+pub fn spawn(path: PathBuf, pacing: Pacing) -> mpsc::Receiver<Vec<u8>> {
+    let (send, recv) = mpsc::channel(16);
+    tokio::spawn(async move {
+        if let Err(e) = feed(&path, &pacing, &send).await {
+            println!("UART input from {} failed: {}", path.display(), e);
+        }
+    });
+    recv
+}
+
+async fn feed(path: &PathBuf, pacing: &Pacing, send: &mpsc::Sender<Vec<u8>>) -> anyhow::Result<()> {
+    if let Some(delay) = pacing.start_delay {
+        tokio::time::sleep(delay).await;
+    }
+
+    let chunk_size = pacing
+        .rate
+        .map(|rate| (rate as usize / (1000 / TICK.as_millis() as usize)).max(1))
+        .unwrap_or(1024);
+    let mut file = tokio::fs::File::open(path).await?;
+    let mut buf = vec![0u8; chunk_size];
+    let mut pending = Vec::new();
+
+    loop {
+        let n = file.read(&mut buf).await?;
+        if n == 0 {
+            return Ok(());
+        }
+        pending.extend_from_slice(&buf[..n]);
+
+        while !pending.is_empty() {
+            let mut len = pending.len().min(chunk_size);
+            let line_end = pacing
+                .line_delay
+                .and_then(|_| pending[..len].iter().position(|&b| b == b'\n'));
+            if let Some(end) = line_end {
+                len = end + 1;
+            }
+
+            let chunk: Vec<u8> = pending.drain(..len).collect();
+            if send.send(chunk).await.is_err() {
+                return Ok(()); /* simulation went away */
+            }
+
+            match (line_end, pacing.line_delay) {
+                (Some(_), Some(delay)) => tokio::time::sleep(delay).await,
+                _ if pacing.rate.is_some() => tokio::time::sleep(TICK).await,
+                _ => {}
+            }
+        }
+    }
+}
@@ -0,0 +1,288 @@
+//! `self-update`: replacing this binary with the latest release on GitHub, and a notice when
+//! there is a newer one, as the Wokwi side of the protocol moves on and old builds stop working
+
+use anyhow::{Context, Result};
+use semver::Version;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// the latest release in GitHub's API
+pub const RELEASES_URL: &str = "https://api.github.com/repos/MabezDev/wokwi-server/releases/latest";
+/// the notice looks for a new release at most this often
+const CHECK_INTERVAL: Duration = Duration::from_secs(24 * 60 * 60);
+/// what the binary is called in release archives
+const BINARY: &str = if cfg!(windows) {
+    "wokwi-server.exe"
+} else {
+    "wokwi-server"
+};
+
+/// Update wokwi-server to the latest release
+#[derive(clap::Args, Debug)]
+pub struct SelfUpdateArgs {
+    /// only say whether there is a newer release
+    #[clap(long)]
+    check: bool,
+
+    /// write the new binary here instead of replacing this one
+    #[clap(long, value_name = "PATH")]
+    output: Option<PathBuf>,
+
+    /// install the download even if it doesn't match the checksum published with the release
+    #[clap(long)]
+    no_verify: bool,
+
+    /// url of the latest release in GitHub's API
+    #[clap(long, env = "WOKWI_SERVER_RELEASES_URL", default_value = RELEASES_URL, hide = true)]
+    releases_url: String,
+}
+
+#[derive(Deserialize)]
+struct Release {
+    tag_name: String,
+    html_url: String,
+    #[serde(default)]
+    assets: Vec<Asset>,
+}
+
+#[derive(Deserialize)]
+struct Asset {
+    name: String,
+    browser_download_url: String,
+}
+
+impl Release {
+    fn version(&self) -> Option<Version> {
+        Version::parse(self.tag_name.trim_start_matches('v')).ok()
+    }
+
+    /// The build for this machine, preferring archives and binaries which can be unpacked here
+    fn asset(&self) -> Option<&Asset> {
+        let os: &[&str] = match std::env::consts::OS {
+            "macos" => &["apple-darwin", "darwin", "macos"],
+            os => &[os],
+        };
+        let mut builds: Vec<_> = self
+            .assets
+            .iter()
+            .filter(|asset| {
+                let name = asset.name.to_lowercase();
+                let is_checksum = [".sha256", ".sig", ".asc"]
+                    .iter()
+                    .any(|s| name.ends_with(s));
+                name.contains(std::env::consts::ARCH)
+                    && os.iter().any(|os| name.contains(os))
+                    && !is_checksum
+            })
+            .collect();
+        builds.sort_by_key(|asset| asset.name.ends_with(".zip"));
+        builds.first().copied()
+    }
+
+    /// The SHA-256 checksum published for `build`, if there is one
+    fn checksum(&self, build: &Asset) -> Option<&Asset> {
+        let name = format!("{}.sha256", build.name).to_lowercase();
+        self.assets
+            .iter()
+            .find(|asset| asset.name.to_lowercase() == name)
+    }
+}
+
+fn current() -> Version {
+    Version::parse(env!("CARGO_PKG_VERSION")).expect("the package version is semver")
+}
+
+fn latest(url: &str) -> Result<Release> {
+    crate::http::agent(url)?
+        .get(url)
+        .set("Accept", "application/vnd.github+json")
+        .call()
+        .with_context(|| format!("Failed to fetch the latest release from {}", url))?
+        .into_json()
+        .with_context(|| format!("Unexpected response from {}", url))
+}
+
+pub fn run(args: SelfUpdateArgs) -> Result<i32> {
+    let release = latest(&args.releases_url)?;
+    let (current, version) = (current(), release.version());
+    let version = match version {
+        Some(version) if version > current => version,
+        _ => {
+            println!("wokwi-server {} is the latest release", current);
+            return Ok(0);
+        }
+    };
+    if args.check {
+        println!(
+            "wokwi-server {} is available, this is {}: {}",
+            version, current, release.html_url
+        );
+        return Ok(0);
+    }
+
+    let asset = release.asset().with_context(|| {
+        format!(
+            "The {} release has no build for {} {}, download one from {}",
+            release.tag_name,
+            std::env::consts::OS,
+            std::env::consts::ARCH,
+            release.html_url
+        )
+    })?;
+    let url = &asset.browser_download_url;
+    println!("Downloading {}", url);
+    let download = fetch(url)?;
+    match release.checksum(asset) {
+        _ if args.no_verify => println!("Not verifying {}, as asked", asset.name),
+        Some(checksum) => verify(&download, asset, checksum)?,
+        None => println!(
+            "Warning: the {} release has no checksum for {}, so it can't be verified",
+            release.tag_name, asset.name
+        ),
+    }
+
+    let name = asset.name.to_lowercase();
+    let binary = if name.ends_with(".tar.gz") || name.ends_with(".tgz") {
+        let mut archive = Vec::new();
+        flate2::read::GzDecoder::new(&download[..])
+            .read_to_end(&mut archive)
+            .with_context(|| format!("{} isn't a gzip archive", asset.name))?;
+        untar(&archive, BINARY)
+            .with_context(|| format!("{} doesn't hold {}", asset.name, BINARY))?
+    } else if name.ends_with(".zip") {
+        anyhow::bail!(
+            "The {} build is a zip archive, which can't be unpacked here. Download it from {}",
+            release.tag_name,
+            url
+        );
+    } else {
+        download
+    };
+
+    match &args.output {
+        Some(output) => {
+            install(&binary, output)?;
+            println!("Wrote wokwi-server {} to {}", version, output.display());
+        }
+        None => {
+            replace(&binary)?;
+            println!("Updated wokwi-server from {} to {}", current, version);
+        }
+    }
+    Ok(0)
+}
+
+fn fetch(url: &str) -> Result<Vec<u8>> {
+    let mut body = Vec::new();
+    crate::http::agent(url)?
+        .get(url)
+        .call()
+        .with_context(|| format!("Failed to download {}", url))?
+        .into_reader()
+        .read_to_end(&mut body)
+        .with_context(|| format!("Failed to download {}", url))?;
+    Ok(body)
+}
+
+/// Check a download against the SHA-256 checksum published with it, in the `sha256sum` format
+fn verify(download: &[u8], build: &Asset, checksum: &Asset) -> Result<()> {
+    let published = fetch(&checksum.browser_download_url)?;
+    let published = String::from_utf8_lossy(&published);
+    let expected = published
+        .split_whitespace()
+        .next()
+        .filter(|sum| sum.len() == 64 && sum.bytes().all(|b| b.is_ascii_hexdigit()))
+        .with_context(|| format!("{} doesn't hold a SHA-256 checksum", checksum.name))?
+        .to_lowercase();
+    let actual: String = Sha256::digest(download)
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect();
+    if actual != expected {
+        anyhow::bail!(
+            "{} doesn't match its checksum, {} was expected but the download is {}. Not installing it, --no-verify installs it anyway",
+            build.name,
+            expected,
+            actual
+        );
+    }
+    println!("Verified {} against {}", build.name, checksum.name);
+    Ok(())
+}
+
+/// The contents of the regular file called `name`, in any directory of a tar archive
+fn untar(archive: &[u8], name: &str) -> Option<Vec<u8>> {
+    let field = |header: &[u8]| {
+        let end = header.iter().position(|&b| b == 0).unwrap_or(header.len());
+        String::from_utf8_lossy(&header[..end]).into_owned()
+    };
+    let mut offset = 0;
+    while let Some(header) = archive.get(offset..offset + 512) {
+        if header.iter().all(|&b| b == 0) {
+            return None;
+        }
+        let size = usize::from_str_radix(field(&header[124..136]).trim(), 8).ok()?;
+        let data = offset + 512;
+        let path = field(&header[..100]);
+        let is_file = matches!(header[156], b'0' | 0);
+        if is_file && Path::new(&path).file_name().is_some_and(|n| n == name) {
+            return archive.get(data..data + size).map(<[u8]>::to_vec);
+        }
+        offset = data + size.next_multiple_of(512);
+    }
+    None
+}
+
+/// Write an executable to `path`, through a file next to it so a failed write leaves the old one
+fn install(binary: &[u8], path: &Path) -> Result<()> {
+    let partial = path.with_file_name(format!(".{}.new", BINARY));
+    std::fs::write(&partial, binary)
+        .with_context(|| format!("Failed to write {}", partial.display()))?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        std::fs::set_permissions(&partial, std::fs::Permissions::from_mode(0o755))?;
+    }
+    std::fs::rename(&partial, path).with_context(|| format!("Failed to replace {}", path.display()))
+}
+
+/// Replace the running binary. Windows won't let it be overwritten, but will let it be renamed
+/// out of the way
+fn replace(binary: &[u8]) -> Result<()> {
+    let exe = std::env::current_exe()?.canonicalize()?;
+    if cfg!(windows) {
+        let old = exe.with_extension("old.exe");
+        std::fs::remove_file(&old).ok();
+        std::fs::rename(&exe, &old)
+            .with_context(|| format!("Failed to move {} aside", exe.display()))?;
+    }
+    install(binary, &exe)
+}
+
+/// Say when there is a newer release, checking at most once a day. Nothing waits for the
+/// check, and failures are quiet, as this is only a courtesy
+pub fn notify_in_background() {
+    let stamp = std::env::temp_dir().join("wokwi-server-update-check");
+    let checked = std::fs::metadata(&stamp).and_then(|m| m.modified()).ok();
+    if checked.is_some_and(|at| at.elapsed().is_ok_and(|age| age < CHECK_INTERVAL)) {
+        return;
+    }
+    if std::fs::write(&stamp, b"").is_err() {
+        return;
+    }
+    std::thread::spawn(|| {
+        let url = std::env::var("WOKWI_SERVER_RELEASES_URL");
+        let Ok(release) = latest(url.as_deref().unwrap_or(RELEASES_URL)) else {
+            return;
+        };
+        if let Some(version) = release.version().filter(|v| *v > current()) {
+            println!(
+                "wokwi-server {} is available, update with `wokwi-server self-update`",
+                version
+            );
+        }
+    });
+}
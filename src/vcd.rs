@@ -0,0 +1,106 @@
+//! Pin changes of the firmware recorded as a Value Change Dump, the format of logic analyzers and
+//! waveform viewers like GTKWave. The simulator reports each change as a `pinChange` message with
+//! the GPIO, its new level and the simulated time in nanoseconds
+
+use crate::error::{bail, Result, WokwiServerError};
+use serde::Deserialize;
+use serde_json::Value;
+use std::collections::BTreeSet;
+use std::io::{self, Write};
+
+/// A GPIO changing level
+#[derive(Debug, Deserialize, PartialEq, Eq)]
+pub struct PinChange {
+    pub pin: u8,
+    /// 0 or 1
+    pub value: u8,
+    /// nanoseconds of simulated time since the simulation started
+    pub time: u64,
+}
+
+impl PinChange {
+    pub fn parse(message: &Value) -> Result<Self> {
+        let change = Self::deserialize(message)
+            .map_err(|e| WokwiServerError::Protocol(format!("Malformed pinChange: {}", e)))?;
+        if change.value > 1 {
+            bail!(Protocol, "Invalid level {} in pinChange", change.value);
+        }
+        Ok(change)
+    }
+}
+
+/// The pin changes of a simulation, in the order they happened
+#[derive(Debug, Default)]
+pub struct Trace {
+    changes: Vec<PinChange>,
+}
+
+impl Trace {
+    /// Record a change. Times never go backwards in a dump, so a change reported out of order is
+    /// moved to the time of the one before it
+    pub fn add(&mut self, mut change: PinChange) {
+        if let Some(last) = self.changes.last() {
+            change.time = change.time.max(last.time);
+        }
+        self.changes.push(change);
+    }
+
+    pub fn len(&self) -> usize {
+        self.changes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.changes.is_empty()
+    }
+
+    /// Write the dump, with a 1 bit wire named `GPIO<n>` for every pin which changed, in the scope
+    /// `module`. Pins are unknown until their first change
+    pub fn write(&self, module: &str, out: &mut impl Write) -> io::Result<()> {
+        let pins: BTreeSet<u8> = self.changes.iter().map(|change| change.pin).collect();
+        let id = |pin: u8| identifier(pins.iter().position(|&p| p == pin).unwrap_or(0));
+
+        writeln!(
+            out,
+            "$version wokwi-server {} $end",
+            env!("CARGO_PKG_VERSION")
+        )?;
+        writeln!(out, "$timescale 1ns $end")?;
+        writeln!(out, "$scope module {} $end", module)?;
+        for &pin in &pins {
+            writeln!(out, "$var wire 1 {} GPIO{} $end", id(pin), pin)?;
+        }
+        writeln!(out, "$upscope $end")?;
+        writeln!(out, "$enddefinitions $end")?;
+        writeln!(out, "#0")?;
+        writeln!(out, "$dumpvars")?;
+        for &pin in &pins {
+            writeln!(out, "x{}", id(pin))?;
+        }
+        writeln!(out, "$end")?;
+
+        let mut time = None;
+        for change in &self.changes {
+            if time != Some(change.time) {
+                writeln!(out, "#{}", change.time)?;
+                time = Some(change.time);
+            }
+            writeln!(out, "{}{}", change.value, id(change.pin))?;
+        }
+        Ok(())
+    }
+}
+
+/// The short name of the `index`th signal, made of the printable characters `!` to `~`
+fn identifier(mut index: usize) -> String {
+    const FIRST: u8 = b'!';
+    const COUNT: usize = (b'~' - b'!' + 1) as usize;
+    let mut id = String::new();
+    loop {
+        id.push((FIRST + (index % COUNT) as u8) as char);
+        index /= COUNT;
+        if index == 0 {
+            return id;
+        }
+        index -= 1;
+    }
+}
@@ -0,0 +1,163 @@
+//! Keeping track of GDB's watchpoints (the `Z2`–`Z4` packets), so ones the chip can't have are
+//! turned down with a reason rather than forwarded to the simulator
+
+use espflash::Chip;
+
+/// What a chip's debug hardware can watch
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Capabilities {
+    /// how many watchpoints can be set at once
+    pub count: usize,
+    /// the largest region a watchpoint covers, regions are a power of two in size and aligned to it
+    pub max_len: u32,
+}
+
+impl Capabilities {
+    /// The watchpoints of `chip`, `None` if they aren't known
+    pub fn for_chip(chip: Chip) -> Option<Self> {
+        let (count, max_len) = match chip {
+            Chip::Esp32 | Chip::Esp32s2 | Chip::Esp32s3 => (2, 64),
+            Chip::Esp32c2 => (2, 0x8000_0000),
+            Chip::Esp32c3 => (8, 0x8000_0000),
+            _ => return None,
+        };
+        Some(Self { count, max_len })
+    }
+}
+
+/// What a watchpoint stops on
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Kind {
+    Write,
+    Read,
+    Access,
+}
+
+impl Kind {
+    fn parse(kind: char) -> Option<Self> {
+        match kind {
+            '2' => Some(Self::Write),
+            '3' => Some(Self::Read),
+            '4' => Some(Self::Access),
+            _ => None,
+        }
+    }
+}
+
+/// A watchpoint on `len` bytes at `addr`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Watchpoint {
+    kind: Kind,
+    addr: u32,
+    len: u32,
+}
+
+/// What to do with a watchpoint packet
+#[derive(Debug, PartialEq, Eq)]
+pub enum Action {
+    /// send it on to the simulator, and pass its response to [`Watchpoints::response`]
+    Forward,
+    /// answer GDB without asking the simulator
+    Reply(&'static str),
+    /// turn it down with an error reply, and tell the user why
+    Reject { reply: &'static str, reason: String },
+}
+
+/// The watchpoints GDB has set
+#[derive(Debug)]
+pub struct Watchpoints {
+    capabilities: Capabilities,
+    set: Vec<Watchpoint>,
+    /// kinds the simulator answered with an empty response, so doesn't support
+    unsupported: Vec<Kind>,
+    /// the forwarded packet awaiting the simulator's response, and whether it sets a watchpoint
+    pending: Option<(Watchpoint, bool)>,
+}
+
+impl Watchpoints {
+    pub fn new(capabilities: Capabilities) -> Self {
+        Self {
+            capabilities,
+            set: Vec::new(),
+            unsupported: Vec::new(),
+            pending: None,
+        }
+    }
+
+    /// How to handle `command` from GDB, `None` if it isn't about a watchpoint
+    pub fn command(&mut self, command: &str) -> Option<Action> {
+        let (insert, rest) = match command.split_at_checked(1)? {
+            ("Z", rest) => (true, rest),
+            ("z", rest) => (false, rest),
+            _ => return None,
+        };
+        let kind = Kind::parse(rest.chars().next()?)?;
+        let Some(watchpoint) = parse(kind, &rest[1..]) else {
+            return Some(Action::Reply("E01"));
+        };
+        if self.unsupported.contains(&kind) {
+            // an empty response tells GDB to fall back to software watchpoints
+            return Some(Action::Reply(""));
+        }
+        if !insert {
+            if !self.set.contains(&watchpoint) {
+                return Some(Action::Reply("OK"));
+            }
+            self.pending = Some((watchpoint, false));
+            return Some(Action::Forward);
+        }
+        if self.set.contains(&watchpoint) {
+            return Some(Action::Reply("OK"));
+        }
+        let Capabilities { count, max_len } = self.capabilities;
+        let Watchpoint { addr, len, .. } = watchpoint;
+        if !len.is_power_of_two() || len > max_len || !addr.is_multiple_of(len) {
+            return Some(Action::Reject {
+                reply: "E01",
+                reason: format!(
+                    "Can't watch {} bytes at {:#x}, watched regions are a power of two up to {} bytes and aligned to their size",
+                    len, addr, max_len
+                ),
+            });
+        }
+        if self.set.len() >= count {
+            return Some(Action::Reject {
+                reply: "E02",
+                reason: format!(
+                    "Can't set another watchpoint, the chip has {} and they are all in use",
+                    count
+                ),
+            });
+        }
+        self.pending = Some((watchpoint, true));
+        Some(Action::Forward)
+    }
+
+    /// Note the simulator's (unframed) response to a forwarded watchpoint packet
+    pub fn response(&mut self, response: &str) {
+        let Some((watchpoint, insert)) = self.pending.take() else {
+            return;
+        };
+        match (response, insert) {
+            ("OK", true) => self.set.push(watchpoint),
+            ("OK", false) => self.set.retain(|w| *w != watchpoint),
+            ("", _) => self.unsupported.push(watchpoint.kind),
+            _ => {}
+        }
+    }
+
+    /// Whether a forwarded packet is waiting for the simulator's response
+    pub fn is_pending(&self) -> bool {
+        self.pending.is_some()
+    }
+}
+
+/// `,<addr>,<len>` in hex
+fn parse(kind: Kind, rest: &str) -> Option<Watchpoint> {
+    let (addr, len) = rest.strip_prefix(',')?.split_once(',')?;
+    Some(Watchpoint {
+        kind,
+        addr: u32::from_str_radix(addr, 16).ok()?,
+        len: u32::from_str_radix(len, 16).ok()?,
+    })
+}
@@ -0,0 +1,59 @@
+//! `--vcd`: the firmware's pin changes, written out as a Value Change Dump when the simulation
+//! ends
+
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use wokwi_server::vcd::{PinChange, Trace};
+
+pub struct Waveform {
+    path: PathBuf,
+    /// the session the changes are from, to tell which in the output
+    label: String,
+    /// the scope the pins are in, named after the chip
+    module: String,
+    trace: Trace,
+}
+
+impl Waveform {
+    pub fn new(path: &Path, module: &str, label: &str) -> Self {
+        Self {
+            path: path.to_owned(),
+            label: label.to_owned(),
+            module: module.to_owned(),
+            trace: Trace::default(),
+        }
+    }
+
+    pub fn add(&mut self, change: PinChange) {
+        self.trace.add(change);
+    }
+
+    fn write(&self) -> anyhow::Result<()> {
+        let mut out = std::io::BufWriter::new(std::fs::File::create(&self.path)?);
+        self.trace.write(&self.module, &mut out)?;
+        out.flush()?;
+        Ok(())
+    }
+}
+
+impl Drop for Waveform {
+    fn drop(&mut self) {
+        if self.trace.is_empty() {
+            return;
+        }
+        match self.write() {
+            Ok(()) => println!(
+                "[{}] {} pin changes written to {}",
+                self.label,
+                self.trace.len(),
+                self.path.display()
+            ),
+            Err(e) => println!(
+                "[{}] Failed to write the pin changes to {}: {:#}",
+                self.label,
+                self.path.display(),
+                e
+            ),
+        }
+    }
+}
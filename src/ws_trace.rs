@@ -0,0 +1,194 @@
+//! A log of every websocket message exchanged with the simulator, for debugging protocol issues
+
+use crate::gdb_trace::GdbTrace;
+use crate::last_session;
+use crate::simulator_port::SimulatorStream;
+use anyhow::{Context, Result};
+use futures_util::stream::SplitSink;
+use futures_util::Sink;
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context as TaskContext, Poll};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio_tungstenite::WebSocketStream;
+use tungstenite::Message;
+
+/// strings and arrays longer than this are replaced by their length and hash
+const MAX_LEN: usize = 256;
+
+/// Which way a message went
+#[derive(Debug, Clone, Copy)]
+pub enum Direction {
+    /// from the simulator
+    In,
+    /// to the simulator
+    Out,
+}
+
+/// Writes newline delimited JSON records of websocket messages, shared by all sessions
+#[derive(Clone, Default)]
+pub struct WsTrace {
+    file: Arc<Mutex<Option<BufWriter<File>>>>,
+}
+
+impl WsTrace {
+    /// Trace to `path` if given, or do nothing
+    pub fn create(path: Option<&Path>) -> Result<Self> {
+        let file = path
+            .map(|path| {
+                File::create(path)
+                    .map(BufWriter::new)
+                    .with_context(|| format!("Failed to create {}", path.display()))
+            })
+            .transpose()?;
+        Ok(Self {
+            file: Arc::new(Mutex::new(file)),
+        })
+    }
+
+    pub fn record(&self, session: &str, direction: Direction, message: &Message) {
+        let direction = match direction {
+            Direction::In => "in",
+            Direction::Out => "out",
+        };
+        if last_session::enabled() {
+            let redacted = last_session::redact(describe(message));
+            last_session::record(&format!("[{}] {} {}", session, direction, redacted));
+        }
+        let mut file = self.file.lock().unwrap();
+        let Some(out) = file.as_mut() else {
+            return;
+        };
+        let mut record = describe(message);
+        let fields = record.as_object_mut().unwrap();
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        fields.insert("timestamp".into(), json!(timestamp.as_millis() as u64));
+        fields.insert("session".into(), json!(session));
+        fields.insert("direction".into(), json!(direction));
+        fields.insert("len".into(), json!(message.len()));
+        // flushed each time, so the trace is complete even if the server is killed
+        if writeln!(out, "{}", record)
+            .and_then(|_| out.flush())
+            .is_err()
+        {
+            println!("Failed to write to the websocket trace, disabling it");
+            *file = None;
+        }
+    }
+}
+
+/// The sending half of a simulator's websocket, tracing everything sent on it
+pub struct TracedSink {
+    sink: SplitSink<WebSocketStream<SimulatorStream>, Message>,
+    trace: WsTrace,
+    /// the GDB packets among the messages are traced as well
+    gdb_trace: GdbTrace,
+    session: String,
+}
+
+impl TracedSink {
+    pub fn new(
+        sink: SplitSink<WebSocketStream<SimulatorStream>, Message>,
+        trace: WsTrace,
+        gdb_trace: GdbTrace,
+        session: &str,
+    ) -> Self {
+        Self {
+            sink,
+            trace,
+            gdb_trace,
+            session: session.to_owned(),
+        }
+    }
+}
+
+impl Sink<Message> for TracedSink {
+    type Error = tungstenite::Error;
+
+    fn poll_ready(
+        mut self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+    ) -> Poll<tungstenite::Result<()>> {
+        Pin::new(&mut self.sink).poll_ready(cx)
+    }
+
+    fn start_send(mut self: Pin<&mut Self>, message: Message) -> tungstenite::Result<()> {
+        self.trace.record(&self.session, Direction::Out, &message);
+        self.gdb_trace.record_message(&self.session, &message);
+        Pin::new(&mut self.sink).start_send(message)
+    }
+
+    fn poll_flush(
+        mut self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+    ) -> Poll<tungstenite::Result<()>> {
+        Pin::new(&mut self.sink).poll_flush(cx)
+    }
+
+    fn poll_close(
+        mut self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+    ) -> Poll<tungstenite::Result<()>> {
+        Pin::new(&mut self.sink).poll_close(cx)
+    }
+}
+
+/// The fields describing a message, with anything large shortened
+fn describe(message: &Message) -> Value {
+    match message {
+        Message::Text(text) => match serde_json::from_str::<Value>(text) {
+            Ok(value) => json!({ "kind": "text", "message": shorten(value) }),
+            Err(_) => json!({ "kind": "text", "text": shorten(Value::String(text.clone())) }),
+        },
+        Message::Binary(data) => json!({ "kind": "binary", "sha256": sha256(data) }),
+        Message::Ping(_) => json!({ "kind": "ping" }),
+        Message::Pong(_) => json!({ "kind": "pong" }),
+        Message::Close(Some(frame)) => json!({
+            "kind": "close",
+            "code": u16::from(frame.code),
+            "reason": frame.reason,
+        }),
+        Message::Close(None) => json!({ "kind": "close" }),
+        Message::Frame(_) => json!({ "kind": "frame" }),
+    }
+}
+
+/// Replace long strings and arrays, like firmware images, with a placeholder giving their length
+/// and a SHA-256, of the string or an array's JSON, so traces stay small but still show whether
+/// payloads differ
+fn shorten(value: Value) -> Value {
+    match value {
+        Value::String(s) if s.len() > MAX_LEN => Value::String(format!(
+            "<{} bytes, sha256 {}>",
+            s.len(),
+            sha256(s.as_bytes())
+        )),
+        Value::Array(items) if items.len() > MAX_LEN => {
+            let encoded = serde_json::to_vec(&items).unwrap_or_default();
+            Value::String(format!(
+                "<{} items, sha256 {}>",
+                items.len(),
+                sha256(&encoded)
+            ))
+        }
+        Value::Array(items) => Value::Array(items.into_iter().map(shorten).collect()),
+        Value::Object(map) => {
+            Value::Object(map.into_iter().map(|(k, v)| (k, shorten(v))).collect())
+        }
+        value => value,
+    }
+}
+
+fn sha256(data: &[u8]) -> String {
+    Sha256::digest(data)
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
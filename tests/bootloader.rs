@@ -0,0 +1,37 @@
+use espflash::Chip;
+use wokwi_server::bootloader::{check, Problem};
+
+/// An image header with the given chip ID in its extended header
+fn header(chip_id: u16) -> Vec<u8> {
+    let mut image = vec![0; 32];
+    image[0] = 0xE9;
+    image[12..14].copy_from_slice(&chip_id.to_le_bytes());
+    image
+}
+
+#[test]
+fn bootloader_for_the_chip_is_accepted() {
+    assert_eq!(check(Chip::Esp32, &header(0)), Ok(()));
+    assert_eq!(check(Chip::Esp32s3, &header(9)), Ok(()));
+}
+
+#[test]
+fn bootloader_for_another_chip_is_rejected() {
+    let problem = check(Chip::Esp32, &header(5)).unwrap_err();
+    assert_eq!(problem, Problem::WrongChip { found: 5 });
+    assert_eq!(problem.to_string(), "it was built for ESP32-C3");
+}
+
+#[test]
+fn data_which_isnt_an_image_is_rejected() {
+    assert_eq!(
+        check(Chip::Esp32, b"not a bootloader, just some text"),
+        Err(Problem::BadMagic(b'n'))
+    );
+    assert_eq!(check(Chip::Esp32, &[0xE9, 3]), Err(Problem::Truncated(2)));
+}
+
+#[test]
+fn esp8266_images_have_no_chip_id() {
+    assert_eq!(check(Chip::Esp8266, &header(9)[..8]), Ok(()));
+}
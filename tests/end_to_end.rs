@@ -0,0 +1,3531 @@
+use serde_json::json;
+use sha2::Digest;
+use std::path::PathBuf;
+use std::process::Stdio;
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+use tokio::process::{Child, ChildStdout, Command};
+use wokwi_server::gdb;
+use wokwi_server::test_support::{
+    bootloader_elf, direct_boot_elf, freertos_elf, freertos_memory, functions_elf, heap_elf,
+    heap_memory, minimal_elf, FakeGdb, MockSimulator, FREERTOS_TASKS,
+};
+
+/// A wokwi-server process, killed when dropped
+struct Server {
+    child: Child,
+    stdout: BufReader<ChildStdout>,
+    /// what the server printed before its startup manifest
+    output: String,
+    /// the startup manifest, with the ports the server bound
+    manifest: serde_json::Value,
+    port: u16,
+    gdb_port: u16,
+    elf: Vec<u8>,
+    _elf_path: TempFile,
+    _state: TempDir,
+}
+
+struct TempFile(PathBuf);
+
+impl Drop for TempFile {
+    fn drop(&mut self) {
+        std::fs::remove_file(&self.0).ok();
+    }
+}
+
+struct TempDir(PathBuf);
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        std::fs::remove_dir_all(&self.0).ok();
+    }
+}
+
+impl Server {
+    async fn start(name: &str, args: &[&str]) -> Self {
+        Self::start_with_elf(name, minimal_elf(), args).await
+    }
+
+    /// Start a server on ports the OS picks, waiting for it to say which
+    async fn start_with_elf(name: &str, elf: Vec<u8>, args: &[&str]) -> Self {
+        let elf_path =
+            std::env::temp_dir().join(format!("wokwi-server-{}-{}.elf", std::process::id(), name));
+        std::fs::write(&elf_path, &elf).unwrap();
+        // the last-session log goes here rather than over the user's
+        let state = TempDir(std::env::temp_dir().join(format!(
+            "wokwi-server-{}-{}-state",
+            std::process::id(),
+            name
+        )));
+
+        let mut child = Command::new(env!("CARGO_BIN_EXE_wokwi-server"))
+            .args([
+                "--no-open",
+                "--no-probe",
+                "--no-update-check",
+                "--output-json",
+                "--chip",
+                "esp32",
+            ])
+            .args(["--port", "0", "--gdb-port", "0"])
+            .args(args)
+            .arg(&elf_path)
+            .env("XDG_STATE_HOME", &state.0)
+            .stdout(Stdio::piped())
+            .kill_on_drop(true)
+            .spawn()
+            .unwrap();
+        let mut stdout = BufReader::new(child.stdout.take().unwrap());
+        let (manifest, output) = read_manifest(&mut stdout).await;
+        Self {
+            child,
+            stdout,
+            output,
+            port: bound_port(&manifest["port"]),
+            gdb_port: bound_port(&manifest["gdb_port"]),
+            manifest,
+            elf,
+            _elf_path: TempFile(elf_path),
+            _state: state,
+        }
+    }
+
+    /// The port of the control API, when started with `--control-port 0`
+    fn control_port(&self) -> u16 {
+        bound_port(&self.manifest["control"])
+    }
+
+    /// The port of the dashboard, when started with `--dashboard-port 0`
+    fn dashboard_port(&self) -> u16 {
+        bound_port(&self.manifest["dashboard"])
+    }
+
+    /// Wait for the server to exit, returning its exit code and output
+    async fn exit(mut self) -> (Option<i32>, String) {
+        let status = tokio::time::timeout(Duration::from_secs(10), async {
+            self.stdout.read_to_string(&mut self.output).await.unwrap();
+            self.child.wait().await.unwrap()
+        })
+        .await
+        .expect("server didn't exit");
+        (status.code(), self.output)
+    }
+}
+
+/// Read up to the startup manifest of a server started with `--output-json`, returning it and
+/// what was printed before it
+async fn read_manifest(stdout: &mut BufReader<ChildStdout>) -> (serde_json::Value, String) {
+    let mut output = String::new();
+    loop {
+        let mut line = String::new();
+        let read = tokio::time::timeout(Duration::from_secs(10), stdout.read_line(&mut line))
+            .await
+            .expect("no startup manifest printed")
+            .unwrap();
+        assert_ne!(read, 0, "server exited before starting: {}", output);
+        match serde_json::from_str::<serde_json::Value>(&line) {
+            Ok(manifest) if manifest.get("port").is_some() => return (manifest, output),
+            _ => output.push_str(&line),
+        }
+    }
+}
+
+/// The port in a manifest entry, either a number or an address
+fn bound_port(entry: &serde_json::Value) -> u16 {
+    match entry.as_u64() {
+        Some(port) => port as u16,
+        None => {
+            let addr = entry.as_str().expect("port not in the manifest");
+            addr.rsplit(':').next().unwrap().parse().unwrap()
+        }
+    }
+}
+
+/// A port nothing listens on, for links that mustn't be reachable. Not for starting servers on,
+/// as something else may take it before they do; give them `--port 0` instead
+fn unused_port() -> u16 {
+    std::net::TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port()
+}
+
+#[tokio::test]
+async fn start_packet_contains_the_firmware() {
+    let server = Server::start("start", &[]).await;
+    let mut sim = MockSimulator::connect(server.port).await.unwrap();
+    let start = sim.handshake().await.unwrap();
+
+    assert_eq!(
+        base64::decode(start["elf"].as_str().unwrap()).unwrap(),
+        server.elf
+    );
+    assert_eq!(segment_addrs(&start), [0x1000, 0x8000, 0x10000]);
+    let segments = start["x-wokwi-server"]["segments"].as_array().unwrap();
+    assert_eq!(segments.len(), 3);
+}
+
+#[tokio::test]
+async fn uart_output_reaches_stdout_and_exit_marker() {
+    let server = Server::start("uart", &["--exit-marker"]).await;
+    let mut sim = MockSimulator::connect(server.port).await.unwrap();
+    sim.handshake().await.unwrap();
+    sim.uart(b"Hello from the firmware\n").await.unwrap();
+    sim.uart(b"WOKWI_EXIT 7\n").await.unwrap();
+
+    let (code, output) = server.exit().await;
+    assert_eq!(code, Some(7));
+    assert!(output.contains("Hello from the firmware\n"), "{}", output);
+}
+
+#[tokio::test]
+async fn log_filter_hides_lines_by_tag_and_level() {
+    let server = Server::start(
+        "log-filter",
+        &["--exit-marker", "--log-filter", "wifi=warn,*=info"],
+    )
+    .await;
+    let mut sim = MockSimulator::connect(server.port).await.unwrap();
+    sim.handshake().await.unwrap();
+    sim.uart(b"I (10) boot: booting\nI (20) wifi: connecting\n")
+        .await
+        .unwrap();
+    sim.uart(b"\x1b[0;33mW (30) wifi: retrying\x1b[0m\nD (40) app: details\n")
+        .await
+        .unwrap();
+    sim.uart(b"E (50) app: failed\nnot a log line\nWOKWI_EXIT 0\n")
+        .await
+        .unwrap();
+
+    let (_, output) = server.exit().await;
+    assert!(output.contains("I (10) boot: booting\n"), "{}", output);
+    assert!(!output.contains("connecting"), "{}", output);
+    assert!(
+        output.contains("\x1b[0;33mW (30) wifi: retrying\x1b[0m\n"),
+        "{}",
+        output
+    );
+    assert!(!output.contains("details"), "{}", output);
+    assert!(
+        output.contains("\x1b[31mE (50) app: failed\x1b[0m\n"),
+        "{}",
+        output
+    );
+    assert!(output.contains("not a log line\n"), "{}", output);
+}
+
+#[tokio::test]
+async fn malformed_messages_are_skipped() {
+    let server = Server::start("malformed", &["--exit-marker"]).await;
+    let mut sim = MockSimulator::connect(server.port).await.unwrap();
+    sim.handshake().await.unwrap();
+    sim.send(json!({ "type": "uartData", "bytes": [72, -1, 300] }))
+        .await
+        .unwrap();
+    sim.send(json!({ "type": "uartData", "bytes": [[{ "nested": "nonsense" }]] }))
+        .await
+        .unwrap();
+    sim.send(json!({ "type": "gdbResponse" })).await.unwrap();
+    sim.send_text("not json").await.unwrap();
+    sim.uart(b"still here\nWOKWI_EXIT 0\n").await.unwrap();
+
+    let (code, output) = server.exit().await;
+    assert_eq!(code, Some(0));
+    assert!(output.contains("still here\n"), "{}", output);
+    assert_eq!(
+        output
+            .matches("Skipping malformed message from simulator")
+            .count(),
+        4,
+        "{}",
+        output
+    );
+}
+
+#[tokio::test]
+async fn repeated_errors_are_summarised() {
+    let server = Server::start("repeats", &["--exit-marker"]).await;
+    let mut sim = MockSimulator::connect(server.port).await.unwrap();
+    sim.handshake().await.unwrap();
+    for _ in 0..10 {
+        sim.send_text("not json").await.unwrap();
+    }
+    sim.uart(b"WOKWI_EXIT 0\n").await.unwrap();
+
+    let (code, output) = server.exit().await;
+    assert_eq!(code, Some(0));
+    assert_eq!(
+        output
+            .matches("Skipping malformed message from simulator")
+            .count(),
+        2,
+        "{}",
+        output
+    );
+    assert!(
+        output.contains("Previous message repeated 9 times: Skipping malformed"),
+        "{}",
+        output
+    );
+}
+
+#[tokio::test]
+async fn gdb_packets_are_bridged() {
+    let server = Server::start("gdb", &[]).await;
+    let mut sim = MockSimulator::connect(server.port).await.unwrap();
+    sim.handshake().await.unwrap();
+
+    let mut gdb = TcpStream::connect(("127.0.0.1", server.gdb_port))
+        .await
+        .unwrap();
+    gdb.write_all(b"$g#67").await.unwrap();
+    let command = sim.recv().await.unwrap();
+    assert_eq!(command["type"], "gdb");
+    assert_eq!(command["message"], "g");
+
+    sim.gdb_response(b"$00#60").await.unwrap();
+    let mut received = Vec::new();
+    while !received.ends_with(b"$00#60") {
+        let mut buf = [0; 64];
+        let n = tokio::time::timeout(Duration::from_secs(10), gdb.read(&mut buf))
+            .await
+            .expect("no response from the GDB server")
+            .unwrap();
+        assert_ne!(n, 0, "GDB server closed the connection");
+        received.extend_from_slice(&buf[..n]);
+    }
+    // acknowledgements for the connection and the packet come first
+    assert_eq!(received, b"++$00#60");
+}
+
+/// A simulator and a GDB client connected to a server, for checking the GDB proxy follows the
+/// remote serial protocol
+async fn gdb_proxy(name: &str) -> (Server, MockSimulator, FakeGdb) {
+    let server = Server::start(name, &[]).await;
+    let mut sim = MockSimulator::connect(server.port).await.unwrap();
+    sim.handshake().await.unwrap();
+    let mut gdb = FakeGdb::connect(server.gdb_port).await.unwrap();
+    // the server acknowledges the connection
+    gdb.expect_ack().await.unwrap();
+    (server, sim, gdb)
+}
+
+#[tokio::test]
+async fn gdb_proxy_acknowledges_packets_and_rejects_bad_checksums() {
+    let (_server, mut sim, mut gdb) = gdb_proxy("gdb-checksums").await;
+    gdb.write(b"$m0,4#00").await.unwrap();
+    gdb.expect_nak().await.unwrap();
+    gdb.send("g").await.unwrap();
+    gdb.expect_ack().await.unwrap();
+    // the rejected packet never reaches the simulator
+    assert_eq!(sim.recv().await.unwrap()["message"], "g");
+
+    // GDB sends a packet again when it misses the acknowledgement, and it is passed on again
+    gdb.send("g").await.unwrap();
+    gdb.expect_ack().await.unwrap();
+    assert_eq!(sim.recv().await.unwrap()["message"], "g");
+}
+
+#[tokio::test]
+async fn gdb_proxy_reassembles_packets_across_reads() {
+    let (_server, mut sim, mut gdb) = gdb_proxy("gdb-reads").await;
+    let packets = [gdb::frame("qC"), gdb::frame("?")].concat();
+    let (first, rest) = packets.split_at(6);
+    gdb.write(first).await.unwrap();
+    gdb.expect_ack().await.unwrap();
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    gdb.write(rest).await.unwrap();
+    gdb.expect_ack().await.unwrap();
+    assert_eq!(sim.recv().await.unwrap()["message"], "qC");
+    assert_eq!(sim.recv().await.unwrap()["message"], "?");
+}
+
+#[tokio::test]
+async fn gdb_proxy_interrupts_on_break() {
+    let (_server, mut sim, mut gdb) = gdb_proxy("gdb-break").await;
+    gdb.write(b"\x03").await.unwrap();
+    assert_eq!(sim.recv().await.unwrap()["type"], "gdbBreak");
+    // Ctrl-C isn't a packet, so it isn't acknowledged
+    gdb.expect_silence(Duration::from_millis(200))
+        .await
+        .unwrap();
+    sim.gdb_response(b"$S02#b5").await.unwrap();
+    assert_eq!(gdb.packet().await.unwrap(), b"S02");
+}
+
+#[tokio::test]
+async fn gdb_proxy_sends_replies_again_when_gdb_rejects_them() {
+    let (_server, mut sim, mut gdb) = gdb_proxy("gdb-retransmit").await;
+    gdb.send("g").await.unwrap();
+    gdb.expect_ack().await.unwrap();
+    sim.recv().await.unwrap();
+    sim.gdb_response(&gdb::frame("00112233")).await.unwrap();
+    assert_eq!(gdb.packet().await.unwrap(), b"00112233");
+    gdb.write(b"-").await.unwrap();
+    assert_eq!(gdb.packet().await.unwrap(), b"00112233");
+    gdb.write(b"+").await.unwrap();
+    gdb.expect_silence(Duration::from_millis(200))
+        .await
+        .unwrap();
+}
+
+#[tokio::test]
+async fn gdb_proxy_passes_binary_packets_through_unchanged() {
+    let (_server, mut sim, mut gdb) = gdb_proxy("gdb-binary").await;
+    // `load` writes memory with X packets, whose data is raw bytes with `}` escaped
+    let mut write = b"X3ffb0000,4:".to_vec();
+    write.extend([0x00, 0x80, 0xff, b'}', b'}' ^ 0x20]);
+    gdb.send_bytes(&write).await.unwrap();
+    gdb.expect_ack().await.unwrap();
+    let command = sim.recv().await.unwrap();
+    assert_eq!(
+        command["message"].as_str().unwrap(),
+        gdb::to_binary_string(&write)
+    );
+
+    // and binary replies, e.g. to the x packet, reach GDB byte for byte
+    sim.gdb_response(&gdb::frame([0xfe, 0x00, 0x7f, 0xc3]))
+        .await
+        .unwrap();
+    assert_eq!(gdb.packet().await.unwrap(), [0xfe, 0x00, 0x7f, 0xc3]);
+}
+
+#[tokio::test]
+async fn firmware_is_halted_at_boot_until_gdb_attaches() {
+    let server = Server::start("halt-on-attach", &["--halt-on-attach", "--exit-marker"]).await;
+    let mut sim = MockSimulator::connect(server.port).await.unwrap();
+    sim.handshake().await.unwrap();
+    assert_eq!(sim.recv().await.unwrap()["type"], "gdbBreak");
+    // the stop reply is for the server, not for a GDB client which attaches later
+    sim.gdb_response(b"$S05#b8").await.unwrap();
+
+    let mut gdb = connect_when_listening(server.gdb_port).await;
+    read_until(&mut gdb, "+").await;
+    gdb.write_all(b"$?#3f").await.unwrap();
+    let command = sim.recv().await.unwrap();
+    assert_eq!(command["message"], "?");
+    sim.gdb_response(b"$S05#b8").await.unwrap();
+    assert_eq!(read_until(&mut gdb, "$S05#b8").await, "+$S05#b8");
+
+    gdb.write_all(b"$c#63").await.unwrap();
+    assert_eq!(sim.recv().await.unwrap()["message"], "c");
+    sim.uart(b"WOKWI_EXIT 0\n").await.unwrap();
+    drop(gdb);
+    let (_, output) = server.exit().await;
+    assert!(output.contains("Halted the firmware at boot"), "{}", output);
+}
+
+#[tokio::test]
+async fn break_at_stops_the_firmware_at_the_symbol() {
+    let server = Server::start_with_elf(
+        "break-at",
+        functions_elf(),
+        &["--break-at", "app_main", "--exit-marker"],
+    )
+    .await;
+    let mut sim = MockSimulator::connect(server.port).await.unwrap();
+    sim.handshake().await.unwrap();
+    assert_eq!(sim.recv().await.unwrap()["type"], "gdbBreak");
+    for command in ["Z1,40080000,3", "c"] {
+        let sent = sim.recv().await.unwrap();
+        assert_eq!(
+            (sent["type"].as_str(), sent["message"].as_str()),
+            (Some("gdb"), Some(command))
+        );
+    }
+    sim.gdb_response(b"$S02#b5").await.unwrap();
+    sim.gdb_response(b"$OK#9a").await.unwrap();
+    sim.gdb_response(b"$T05#b9").await.unwrap();
+
+    // the replies were the server's, GDB only sees the one it asks for
+    let mut gdb = connect_when_listening(server.gdb_port).await;
+    read_until(&mut gdb, "+").await;
+    gdb.write_all(b"$?#3f").await.unwrap();
+    assert_eq!(sim.recv().await.unwrap()["message"], "?");
+    sim.gdb_response(b"$T05#b9").await.unwrap();
+    assert_eq!(read_until(&mut gdb, "$T05#b9").await, "+$T05#b9");
+
+    sim.uart(b"WOKWI_EXIT 0\n").await.unwrap();
+    drop(gdb);
+    let (_, output) = server.exit().await;
+    assert!(
+        output.contains("Stopped at a breakpoint, app_main (0x40080000)"),
+        "{}",
+        output
+    );
+}
+
+#[tokio::test]
+async fn gdb_init_scripts_run_as_the_firmware_starts() {
+    let script =
+        std::env::temp_dir().join(format!("wokwi-server-{}-gdb-init.gdb", std::process::id()));
+    let _script = TempFile(script.clone());
+    std::fs::write(&script, "set {int}0x3ffb0000 = 0x2a\nmonitor info\n").unwrap();
+    let server = Server::start(
+        "gdb-init",
+        &[
+            "--gdb-init",
+            script.to_str().unwrap(),
+            "--halt-on-attach",
+            "--exit-marker",
+        ],
+    )
+    .await;
+    let mut sim = MockSimulator::connect(server.port).await.unwrap();
+    sim.handshake().await.unwrap();
+    assert_eq!(sim.recv().await.unwrap()["type"], "gdbBreak");
+    for command in ["M3ffb0000,4:2a000000", "qRcmd,696e666f"] {
+        assert_eq!(sim.recv().await.unwrap()["message"], command);
+    }
+    sim.gdb_response(b"$S02#b5").await.unwrap();
+    sim.gdb_response(b"$OK#9a").await.unwrap();
+    sim.gdb_response(b"$O776f6b77690a#5f").await.unwrap();
+    sim.gdb_response(b"$OK#9a").await.unwrap();
+    sim.uart(b"WOKWI_EXIT 0\n").await.unwrap();
+
+    let (_, output) = server.exit().await;
+    assert!(output.contains("] wokwi\n"), "{}", output);
+    assert!(output.contains("Halted the firmware at boot"), "{}", output);
+}
+
+#[tokio::test]
+async fn gdb_outlives_simulator_connections() {
+    let server = Server::start("gdb-churn", &[]).await;
+    // packets sent before the simulation starts are held for it
+    let mut gdb = connect_when_listening(server.gdb_port).await;
+    read_until(&mut gdb, "+").await;
+    gdb.write_all(b"$g#67").await.unwrap();
+
+    let mut sim = MockSimulator::connect(server.port).await.unwrap();
+    sim.handshake().await.unwrap();
+    let command = sim.recv().await.unwrap();
+    assert_eq!(command["message"], "g");
+    sim.close().await.unwrap();
+
+    // the GDB client carries on with the next simulation
+    let mut sim = MockSimulator::connect(server.port).await.unwrap();
+    sim.handshake().await.unwrap();
+    gdb.write_all(b"$?#3f").await.unwrap();
+    let command = sim.recv().await.unwrap();
+    assert_eq!(command["message"], "?");
+    sim.gdb_response(b"$S05#b8").await.unwrap();
+    read_until(&mut gdb, "$S05#b8").await;
+}
+
+#[tokio::test]
+async fn gdb_is_served_a_target_description() {
+    let server = Server::start("target-xml", &[]).await;
+    let mut sim = MockSimulator::connect(server.port).await.unwrap();
+    sim.handshake().await.unwrap();
+    let mut gdb = connect_when_listening(server.gdb_port).await;
+    read_until(&mut gdb, "+").await;
+
+    // the simulator's features are kept, with target descriptions added
+    let qsupported = gdb::frame("qSupported:multiprocess+;xmlRegisters=i386");
+    gdb.write_all(&qsupported).await.unwrap();
+    let command = sim.recv().await.unwrap();
+    assert!(command["message"]
+        .as_str()
+        .unwrap()
+        .starts_with("qSupported"));
+    sim.gdb_response(&gdb::frame("PacketSize=1000"))
+        .await
+        .unwrap();
+    read_until(
+        &mut gdb,
+        &gdb::frame("PacketSize=1000;qXfer:features:read+"),
+    )
+    .await;
+
+    // and the description itself, in parts, never reaches the simulator
+    let read = gdb::frame("qXfer:features:read:target.xml:0,fff");
+    gdb.write_all(&read).await.unwrap();
+    let first = read_until(&mut gdb, "#").await;
+    assert!(first.contains("$m<?xml"), "{}", first);
+    assert!(
+        first.contains("<architecture>xtensa</architecture>"),
+        "{}",
+        first
+    );
+    let read = gdb::frame("qXfer:features:read:target.xml:fff,fff");
+    gdb.write_all(&read).await.unwrap();
+    let rest = read_until(&mut gdb, "</target>").await;
+    assert!(rest.contains("$l"), "{}", rest);
+    gdb.write_all(b"$g#67").await.unwrap();
+    let command = sim.recv().await.unwrap();
+    assert_eq!(command["message"], "g");
+}
+
+#[tokio::test]
+async fn monitor_wokwi_info_is_answered_by_the_server() {
+    let server = Server::start("monitor-info", &[]).await;
+    let mut sim = MockSimulator::connect(server.port).await.unwrap();
+    sim.handshake().await.unwrap();
+    let mut gdb = connect_when_listening(server.gdb_port).await;
+    read_until(&mut gdb, "+").await;
+
+    let info = gdb::frame(format!("qRcmd,{}", gdb::encode_hex(b"wokwi info")));
+    gdb.write_all(&info).await.unwrap();
+    let reply = read_until(&mut gdb, "#").await;
+    let hex = reply.split(['$', '#']).nth(1).unwrap();
+    let output = String::from_utf8(gdb::decode_hex(hex).unwrap()).unwrap();
+    assert!(output.starts_with("wokwi-server "), "{}", output);
+    assert!(output.contains("simulator"), "{}", output);
+    assert!(output.contains("GDB"), "{}", output);
+    assert!(output.contains("  elf "), "{}", output);
+    assert!(output.contains("transfer time"), "{}", output);
+
+    // other monitor commands are the simulator's
+    let reset = gdb::frame(format!("qRcmd,{}", gdb::encode_hex(b"reset halt")));
+    gdb.write_all(&reset).await.unwrap();
+    let command = sim.recv().await.unwrap();
+    assert!(command["message"].as_str().unwrap().starts_with("qRcmd,"));
+}
+
+#[tokio::test]
+async fn gdb_sees_freertos_tasks_as_threads() {
+    let server = Server::start_with_elf("threads", freertos_elf(), &[]).await;
+    let mut sim = MockSimulator::connect(server.port).await.unwrap();
+    sim.handshake().await.unwrap();
+    let mut gdb = connect_when_listening(server.gdb_port).await;
+    read_until(&mut gdb, "+").await;
+
+    // the task lists are read through the simulator
+    gdb.write_all(&gdb::frame("qfThreadInfo")).await.unwrap();
+    let ids = loop {
+        tokio::select! {
+            command = sim.recv() => {
+                let command = command.unwrap();
+                let read = command["message"].as_str().unwrap();
+                let (addr, len) = read.strip_prefix('m').unwrap().split_once(',').unwrap();
+                let addr = u32::from_str_radix(addr, 16).unwrap();
+                let len = u32::from_str_radix(len, 16).unwrap();
+                let memory = freertos_memory(addr, len).unwrap();
+                sim.gdb_response(&gdb::frame(gdb::encode_hex(&memory))).await.unwrap();
+            }
+            received = read_until(&mut gdb, "#") => break received,
+        }
+    };
+    let expected: Vec<_> = [1, 0, 2]
+        .iter()
+        .map(|&i| format!("{:x}", FREERTOS_TASKS[i].0))
+        .collect();
+    assert!(
+        ids.contains(&format!("$m{}", expected.join(","))),
+        "{}",
+        ids
+    );
+
+    let extra = format!("qThreadExtraInfo,{:x}", FREERTOS_TASKS[0].0);
+    gdb.write_all(&gdb::frame(&extra)).await.unwrap();
+    let name = gdb::encode_hex(b"main (Running)");
+    read_until(&mut gdb, &gdb::frame(&name)).await;
+}
+
+#[tokio::test]
+async fn watchpoints_beyond_the_chips_are_refused() {
+    let server = Server::start("watchpoints", &["--exit-marker"]).await;
+    let mut sim = MockSimulator::connect(server.port).await.unwrap();
+    sim.handshake().await.unwrap();
+    let mut gdb = connect_when_listening(server.gdb_port).await;
+    read_until(&mut gdb, "+").await;
+
+    for addr in ["3ffb0000", "3ffb0004"] {
+        let watch = format!("Z2,{},4", addr);
+        gdb.write_all(&gdb::frame(&watch)).await.unwrap();
+        let command = sim.recv().await.unwrap();
+        assert_eq!(command["message"], watch);
+        sim.gdb_response(&gdb::frame("OK")).await.unwrap();
+        read_until(&mut gdb, &gdb::frame("OK")).await;
+    }
+    // the ESP32 only has two, so the simulator isn't asked for a third
+    gdb.write_all(&gdb::frame("Z2,3ffb0008,4")).await.unwrap();
+    read_until(&mut gdb, &gdb::frame("E02")).await;
+    gdb.write_all(b"$g#67").await.unwrap();
+    let command = sim.recv().await.unwrap();
+    assert_eq!(command["message"], "g");
+
+    sim.uart(b"WOKWI_EXIT 0\n").await.unwrap();
+    let (_, output) = server.exit().await;
+    assert!(output.contains("the chip has 2"), "{}", output);
+}
+
+#[tokio::test]
+async fn heap_stats_are_polled_through_the_gdb_stub() {
+    let server =
+        Server::start_with_elf("heap", heap_elf(), &["--heap-stats", "1", "--exit-marker"]).await;
+    let mut sim = MockSimulator::connect(server.port).await.unwrap();
+    sim.handshake().await.unwrap();
+
+    // the firmware is stopped, read and carries on
+    assert_eq!(sim.recv().await.unwrap()["type"], "gdbBreak");
+    sim.gdb_response(&gdb::frame("S05")).await.unwrap();
+    loop {
+        let command = sim.recv().await.unwrap();
+        let message = command["message"].as_str().unwrap();
+        let Some(range) = message.strip_prefix('m') else {
+            assert_eq!(message, "c");
+            break;
+        };
+        let (addr, len) = range.split_once(',').unwrap();
+        let addr = u32::from_str_radix(addr, 16).unwrap();
+        let len = u32::from_str_radix(len, 16).unwrap();
+        let memory = heap_memory(addr, len).unwrap();
+        sim.gdb_response(&gdb::frame(gdb::encode_hex(&memory)))
+            .await
+            .unwrap();
+    }
+    sim.uart(b"WOKWI_EXIT 0\n").await.unwrap();
+
+    let (code, output) = server.exit().await;
+    assert_eq!(code, Some(0));
+    assert!(
+        output.contains("Heap: 12288 bytes free (+0 since the first reading), 8192 at the lowest, largest free block 8192"),
+        "{}",
+        output
+    );
+}
+
+#[tokio::test]
+async fn gdb_packets_wait_for_a_heap_reading_to_finish() {
+    let server = Server::start_with_elf("heap-gdb", heap_elf(), &["--heap-stats", "1"]).await;
+    let mut sim = MockSimulator::connect(server.port).await.unwrap();
+    sim.handshake().await.unwrap();
+    assert_eq!(sim.recv().await.unwrap()["type"], "gdbBreak");
+
+    // the client asks for the registers while the heap is being read
+    let mut gdb = FakeGdb::connect(server.gdb_port).await.unwrap();
+    gdb.expect_ack().await.unwrap();
+    gdb.send("g").await.unwrap();
+    gdb.expect_ack().await.unwrap();
+
+    sim.gdb_response(&gdb::frame("S05")).await.unwrap();
+    loop {
+        let command = sim.recv().await.unwrap();
+        let message = command["message"].as_str().unwrap();
+        let Some(range) = message.strip_prefix('m') else {
+            assert_eq!(message, "c");
+            break;
+        };
+        let (addr, len) = range.split_once(',').unwrap();
+        let addr = u32::from_str_radix(addr, 16).unwrap();
+        let len = u32::from_str_radix(len, 16).unwrap();
+        let memory = heap_memory(addr, len).unwrap();
+        sim.gdb_response(&gdb::frame(gdb::encode_hex(&memory)))
+            .await
+            .unwrap();
+    }
+    // which is only passed on once the reading is done
+    assert_eq!(sim.recv().await.unwrap()["message"], "g");
+}
+
+#[tokio::test]
+async fn profiles_are_written_when_the_simulation_ends() {
+    let profile = std::env::temp_dir().join(format!(
+        "wokwi-server-{}-profile.folded",
+        std::process::id()
+    ));
+    let _profile = TempFile(profile.clone());
+    let server = Server::start(
+        "profile",
+        &["--cpu-profile", profile.to_str().unwrap(), "--exit-marker"],
+    )
+    .await;
+    let mut sim = MockSimulator::connect(server.port).await.unwrap();
+    sim.handshake().await.unwrap();
+
+    assert_eq!(sim.recv().await.unwrap()["type"], "gdbBreak");
+    sim.gdb_response(&gdb::frame("S05")).await.unwrap();
+    assert_eq!(sim.recv().await.unwrap()["message"], "g");
+    let mut registers = vec![0; 4 * 105];
+    registers[..4].copy_from_slice(&0x4008_0000u32.to_le_bytes());
+    sim.gdb_response(&gdb::frame(gdb::encode_hex(&registers)))
+        .await
+        .unwrap();
+    assert_eq!(sim.recv().await.unwrap()["message"], "c");
+    sim.uart(b"WOKWI_EXIT 0\n").await.unwrap();
+
+    let (code, output) = server.exit().await;
+    assert_eq!(code, Some(0));
+    assert!(
+        output.contains("Profile of 1 samples written"),
+        "{}",
+        output
+    );
+    assert_eq!(std::fs::read_to_string(&profile).unwrap(), "0x40080000 1\n");
+}
+
+#[tokio::test]
+async fn gdb_packets_wait_for_a_profile_sample_to_finish() {
+    let profile = std::env::temp_dir().join(format!(
+        "wokwi-server-{}-held-profile.folded",
+        std::process::id()
+    ));
+    let _profile = TempFile(profile.clone());
+    let server = Server::start(
+        "profile-gdb",
+        &["--cpu-profile", profile.to_str().unwrap()],
+    )
+    .await;
+    let mut sim = MockSimulator::connect(server.port).await.unwrap();
+    sim.handshake().await.unwrap();
+    assert_eq!(sim.recv().await.unwrap()["type"], "gdbBreak");
+
+    // the client interrupts while the sample is being taken
+    let mut gdb = FakeGdb::connect(server.gdb_port).await.unwrap();
+    gdb.expect_ack().await.unwrap();
+    gdb.write(b"\x03").await.unwrap();
+    tokio::time::sleep(Duration::from_millis(100)).await;
+
+    sim.gdb_response(&gdb::frame("S05")).await.unwrap();
+    assert_eq!(sim.recv().await.unwrap()["message"], "g");
+    sim.gdb_response(&gdb::frame(gdb::encode_hex(&[0; 4 * 105])))
+        .await
+        .unwrap();
+    assert_eq!(sim.recv().await.unwrap()["message"], "c");
+    // and is only passed on once the firmware is running again
+    assert_eq!(sim.recv().await.unwrap()["type"], "gdbBreak");
+    sim.gdb_response(&gdb::frame("S02")).await.unwrap();
+    assert_eq!(gdb.packet().await.unwrap(), b"S02");
+}
+
+#[tokio::test]
+async fn panic_backtraces_are_decoded_with_the_elf() {
+    let server = Server::start_with_elf("backtrace", functions_elf(), &["--exit-marker"]).await;
+    let mut sim = MockSimulator::connect(server.port).await.unwrap();
+    sim.handshake().await.unwrap();
+
+    sim.uart(b"Backtrace: 0x4008000a:0x3ffb0000 0x40080004:0x3ffb0020 0x400c0000:0x3ffb0040\n\n")
+        .await
+        .unwrap();
+    sim.uart(b"WOKWI_EXIT 0\n").await.unwrap();
+
+    let (code, output) = server.exit().await;
+    assert_eq!(code, Some(0));
+    assert!(output.contains("0x4008000a: panic_handler\n"), "{}", output);
+    assert!(output.contains("0x40080004: app_main\n"), "{}", output);
+    assert!(output.contains("0x400c0000: ??\n"), "{}", output);
+}
+
+#[tokio::test]
+async fn compressed_elfs_are_decompressed_before_they_are_sent() {
+    let mut gzipped = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+    std::io::Write::write_all(&mut gzipped, &minimal_elf()).unwrap();
+    let server = Server::start_with_elf("gzipped", gzipped.finish().unwrap(), &[]).await;
+    let mut sim = MockSimulator::connect(server.port).await.unwrap();
+    let start = sim.handshake().await.unwrap();
+
+    assert_eq!(
+        base64::decode(start["elf"].as_str().unwrap()).unwrap(),
+        minimal_elf()
+    );
+    assert_eq!(segment_addrs(&start), [0x1000, 0x8000, 0x10000]);
+}
+
+#[tokio::test]
+async fn backtraces_through_the_bootloader_are_decoded_with_its_elf() {
+    let bootloader = std::env::temp_dir().join(format!(
+        "wokwi-server-{}-bootloader.elf",
+        std::process::id()
+    ));
+    let _bootloader = TempFile(bootloader.clone());
+    std::fs::write(&bootloader, bootloader_elf()).unwrap();
+    let server = Server::start_with_elf(
+        "bootloader-elf",
+        functions_elf(),
+        &[
+            "--exit-marker",
+            "--print-gdbinit",
+            "--bootloader-elf",
+            bootloader.to_str().unwrap(),
+        ],
+    )
+    .await;
+    let mut sim = MockSimulator::connect(server.port).await.unwrap();
+    sim.handshake().await.unwrap();
+
+    sim.uart(b"Backtrace: 0x4008000a:0x3ffb0000 0x40078004:0x3ffb0020\n\n")
+        .await
+        .unwrap();
+    sim.uart(b"WOKWI_EXIT 0\n").await.unwrap();
+
+    let (code, output) = server.exit().await;
+    assert_eq!(code, Some(0), "{}", output);
+    assert!(output.contains("0x4008000a: panic_handler\n"), "{}", output);
+    assert!(
+        output.contains("0x40078004: bootloader_main\n"),
+        "{}",
+        output
+    );
+    let loaded = format!(
+        "add-symbol-file \"{}\"",
+        std::fs::canonicalize(&bootloader).unwrap().display()
+    );
+    assert!(output.contains(&loaded), "{}", output);
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn panics_open_the_editor_at_the_source() {
+    let dir = std::env::temp_dir();
+    let source = TempFile(dir.join(format!("wokwi-server-{}-main.rs", std::process::id())));
+    let opened = TempFile(dir.join(format!("wokwi-server-{}-opened", std::process::id())));
+    std::fs::write(&source.0, "fn main() {\n    let x = 1 / 0;\n}\n").unwrap();
+    let editor = format!(
+        "--open-editor=sh -c 'echo {{file}}:{{line}}:{{column}} > {}'",
+        opened.0.display()
+    );
+    let server = Server::start(
+        "editor",
+        &[&editor, "--source-context", "1", "--exit-marker"],
+    )
+    .await;
+    let mut sim = MockSimulator::connect(server.port).await.unwrap();
+    sim.handshake().await.unwrap();
+
+    let panic = format!(
+        "panicked at {}:2:13:\nattempt to divide by zero\n",
+        source.0.display()
+    );
+    sim.uart(panic.as_bytes()).await.unwrap();
+    sim.uart(b"WOKWI_EXIT 0\n").await.unwrap();
+
+    let (code, output) = server.exit().await;
+    assert_eq!(code, Some(0));
+    assert!(output.contains("      1 | fn main() {\n"), "{}", output);
+    assert!(
+        output.contains(">     2 |     let x = 1 / 0;\n"),
+        "{}",
+        output
+    );
+    assert!(output.contains("      3 | }\n"), "{}", output);
+    let expected = format!("{}:2:13\n", source.0.display());
+    for _ in 0..100 {
+        if std::fs::read_to_string(&opened.0).ok().as_deref() == Some(expected.as_str()) {
+            return;
+        }
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+    panic!("The editor wasn't opened at {}", expected);
+}
+
+#[tokio::test]
+async fn crashes_are_dumped_through_the_gdb_stub() {
+    let core = std::env::temp_dir().join(format!("wokwi-server-{}-core.elf", std::process::id()));
+    let _core = TempFile(core.clone());
+    let server = Server::start("core-dump", &["--core-dump", core.to_str().unwrap()]).await;
+    let mut sim = MockSimulator::connect(server.port).await.unwrap();
+    sim.handshake().await.unwrap();
+
+    sim.uart(
+        b"Guru Meditation Error: Core  0 panic'ed (LoadProhibited). Exception was unhandled.\n",
+    )
+    .await
+    .unwrap();
+    assert_eq!(sim.recv().await.unwrap()["type"], "gdbBreak");
+    sim.gdb_response(&gdb::frame("S05")).await.unwrap();
+    assert_eq!(sim.recv().await.unwrap()["message"], "g");
+    let mut registers = vec![0; 4 * 105];
+    registers[..4].copy_from_slice(&0x400d_1234u32.to_le_bytes());
+    sim.gdb_response(&gdb::frame(gdb::encode_hex(&registers)))
+        .await
+        .unwrap();
+
+    // all of RAM is read, then the firmware carries on
+    let mut read = 0;
+    loop {
+        let command = sim.recv().await.unwrap();
+        let message = command["message"].as_str().unwrap();
+        let Some(range) = message.strip_prefix('m') else {
+            assert_eq!(message, "c");
+            break;
+        };
+        let len = usize::from_str_radix(range.split_once(',').unwrap().1, 16).unwrap();
+        read += len;
+        sim.gdb_response(&gdb::frame(gdb::encode_hex(&vec![0xa5; len])))
+            .await
+            .unwrap();
+    }
+    assert_eq!(read, 0x52000);
+
+    let core = std::fs::read(&core).unwrap();
+    let elf = xmas_elf::ElfFile::new(&core).unwrap();
+    assert_eq!(
+        elf.header.pt2.type_().as_type(),
+        xmas_elf::header::Type::Core
+    );
+    let load = elf.program_iter().nth(1).unwrap();
+    assert_eq!(load.virtual_addr(), 0x3ffa_e000);
+    assert_eq!(load.file_size(), 0x52000);
+}
+
+#[tokio::test]
+async fn restart_resends_the_firmware() {
+    let server = Server::start("restart", &["--control-port", "0"]).await;
+    let mut sim = MockSimulator::connect(server.port).await.unwrap();
+    let first = sim.handshake().await.unwrap();
+
+    let mut control = TcpStream::connect(("127.0.0.1", server.control_port()))
+        .await
+        .unwrap();
+    control.write_all(b"restart\n").await.unwrap();
+    let second = sim.recv().await.unwrap();
+    assert_eq!(second["type"], "start");
+    assert_eq!(second["elf"], first["elf"]);
+
+    let response = read_until(&mut control, "\n").await;
+    assert_eq!(response, "{\"ok\":true}\n");
+}
+
+#[tokio::test]
+async fn shutdown_closes_every_connection() {
+    let server = Server::start("shutdown", &["--control-port", "0"]).await;
+    let mut sim = MockSimulator::connect(server.port).await.unwrap();
+    sim.handshake().await.unwrap();
+    let mut gdb = TcpStream::connect(("127.0.0.1", server.gdb_port))
+        .await
+        .unwrap();
+    read_until(&mut gdb, "+").await;
+
+    let mut control = TcpStream::connect(("127.0.0.1", server.control_port()))
+        .await
+        .unwrap();
+    control.write_all(b"shutdown\n").await.unwrap();
+    assert_eq!(read_until(&mut control, "\n").await, "{\"ok\":true}\n");
+
+    let error = sim.recv().await.unwrap_err();
+    assert!(error.to_string().contains("closed"), "{:#}", error);
+    let mut rest = Vec::new();
+    tokio::time::timeout(Duration::from_secs(10), gdb.read_to_end(&mut rest))
+        .await
+        .expect("GDB connection wasn't closed")
+        .unwrap();
+    let (code, output) = server.exit().await;
+    assert_eq!(code, Some(0));
+    assert!(output.contains("Shutting down"), "{}", output);
+}
+
+/// Send a raw HTTP request, returning the whole response
+async fn http(port: u16, request: &str) -> String {
+    let mut stream = TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+    stream.write_all(request.as_bytes()).await.unwrap();
+    let mut response = String::new();
+    tokio::time::timeout(
+        Duration::from_secs(10),
+        stream.read_to_string(&mut response),
+    )
+    .await
+    .expect("no response from the server")
+    .unwrap();
+    response
+}
+
+/// Connect to `port`, waiting for the server to start listening
+async fn connect_when_listening(port: u16) -> TcpStream {
+    let connect = async {
+        loop {
+            match TcpStream::connect(("127.0.0.1", port)).await {
+                Ok(stream) => return stream,
+                Err(_) => tokio::time::sleep(Duration::from_millis(50)).await,
+            }
+        }
+    };
+    tokio::time::timeout(Duration::from_secs(10), connect)
+        .await
+        .unwrap_or_else(|_| panic!("nothing listening on port {}", port))
+}
+
+/// Read from `stream` until `text` has been received
+async fn read_until(stream: &mut TcpStream, text: impl AsRef<[u8]>) -> String {
+    let text = String::from_utf8_lossy(text.as_ref()).into_owned();
+    let mut received = Vec::new();
+    while !String::from_utf8_lossy(&received).contains(&text) {
+        let mut buf = [0; 1024];
+        let n = tokio::time::timeout(Duration::from_secs(10), stream.read(&mut buf))
+            .await
+            .unwrap_or_else(|_| panic!("didn't receive {:?}", text))
+            .unwrap();
+        assert_ne!(n, 0, "connection closed before {:?} was received", text);
+        received.extend_from_slice(&buf[..n]);
+    }
+    String::from_utf8_lossy(&received).into_owned()
+}
+
+#[tokio::test]
+async fn dashboard_streams_uart_and_restarts() {
+    let server = Server::start("dashboard", &["--dashboard-port", "0"]).await;
+    let dashboard_port = server.dashboard_port();
+    let mut sim = MockSimulator::connect(server.port).await.unwrap();
+    let first = sim.handshake().await.unwrap();
+
+    let page = http(dashboard_port, "GET / HTTP/1.1\r\nHost: localhost\r\n\r\n").await;
+    assert!(page.starts_with("HTTP/1.1 200 OK"), "{}", page);
+    assert!(page.contains("EventSource"), "{}", page);
+
+    let mut events = TcpStream::connect(("127.0.0.1", dashboard_port))
+        .await
+        .unwrap();
+    events
+        .write_all(b"GET /events HTTP/1.1\r\nHost: 127.0.0.1\r\n\r\n")
+        .await
+        .unwrap();
+    read_until(&mut events, r#""type":"hello""#).await;
+    sim.uart(b"Hello dashboard\n").await.unwrap();
+    read_until(&mut events, r#""text":"Hello dashboard\n""#).await;
+
+    // API calls need the dashboard's header and a local host name
+    let forged = http(
+        dashboard_port,
+        "POST /api/restart HTTP/1.1\r\nHost: localhost\r\n\r\n",
+    )
+    .await;
+    assert!(forged.starts_with("HTTP/1.1 404"), "{}", forged);
+    let rebound = http(
+        dashboard_port,
+        "POST /api/restart HTTP/1.1\r\nHost: evil.example:80\r\nX-Wokwi-Dashboard: 1\r\n\r\n",
+    )
+    .await;
+    assert!(rebound.starts_with("HTTP/1.1 403"), "{}", rebound);
+
+    let restart = tokio::spawn(http(
+        dashboard_port,
+        "POST /api/restart HTTP/1.1\r\nHost: localhost\r\nX-Wokwi-Dashboard: 1\r\n\r\n",
+    ));
+    let second = sim.recv().await.unwrap();
+    assert_eq!(second["type"], "start");
+    assert_eq!(second["elf"], first["elf"]);
+    let restart = restart.await.unwrap();
+    assert!(restart.ends_with(r#"{"ok":true}"#), "{}", restart);
+}
+
+#[tokio::test]
+async fn sim_speed_and_run_for_control_simulated_time() {
+    let server = Server::start(
+        "time-control",
+        &["--sim-speed", "0.5", "--control-port", "0"],
+    )
+    .await;
+    let mut sim = MockSimulator::connect(server.port).await.unwrap();
+    sim.send(json!({ "type": "hello", "protocolVersion": 1, "capabilities": ["timeControl"] }))
+        .await
+        .unwrap();
+    assert_eq!(sim.recv().await.unwrap()["type"], "start");
+    assert_eq!(
+        sim.recv().await.unwrap(),
+        json!({ "type": "simSpeed", "speed": 0.5 })
+    );
+
+    // answered once the simulator has paused again
+    let mut control = TcpStream::connect(("127.0.0.1", server.control_port()))
+        .await
+        .unwrap();
+    control.write_all(b"run-for 250\n").await.unwrap();
+    assert_eq!(
+        sim.recv().await.unwrap(),
+        json!({ "type": "runFor", "ms": 250 })
+    );
+    sim.send(json!({ "type": "paused" })).await.unwrap();
+    assert_eq!(read_until(&mut control, "\n").await, "{\"ok\":true}\n");
+}
+
+#[tokio::test]
+async fn time_control_is_refused_without_simulator_support() {
+    let server = Server::start(
+        "no-time-control",
+        &["--exit-marker", "--sim-speed", "2", "--control-port", "0"],
+    )
+    .await;
+    let mut sim = MockSimulator::connect(server.port).await.unwrap();
+    sim.handshake().await.unwrap();
+
+    let mut control = TcpStream::connect(("127.0.0.1", server.control_port()))
+        .await
+        .unwrap();
+    control.write_all(b"run-for 250\n").await.unwrap();
+    let response = read_until(&mut control, "\n").await;
+    assert!(
+        response.contains("can't run for a span of simulated time"),
+        "{}",
+        response
+    );
+    sim.uart(b"WOKWI_EXIT 0\n").await.unwrap();
+    let (_, output) = server.exit().await;
+    assert!(
+        output.contains("the simulator can't change its speed, ignoring --sim-speed 2"),
+        "{}",
+        output
+    );
+}
+
+#[tokio::test]
+async fn stats_are_written_on_exit() {
+    let stats_path = TempFile(
+        std::env::temp_dir().join(format!("wokwi-server-{}-stats.json", std::process::id())),
+    );
+    let server = Server::start(
+        "stats",
+        &[
+            "--exit-marker",
+            "--stats-json",
+            stats_path.0.to_str().unwrap(),
+        ],
+    )
+    .await;
+    let mut sim = MockSimulator::connect(server.port).await.unwrap();
+    sim.handshake().await.unwrap();
+
+    let mut gdb = TcpStream::connect(("127.0.0.1", server.gdb_port))
+        .await
+        .unwrap();
+    gdb.write_all(b"$g#67$?#3f").await.unwrap();
+    sim.recv().await.unwrap();
+    sim.recv().await.unwrap();
+    sim.uart(b"WOKWI_EXIT 0\n").await.unwrap();
+
+    let (code, output) = server.exit().await;
+    assert_eq!(code, Some(0));
+    assert!(output.contains("Session summary:"), "{}", output);
+    let stats: serde_json::Value =
+        serde_json::from_str(&std::fs::read_to_string(&stats_path.0).unwrap()).unwrap();
+    assert_eq!(stats["uart_bytes"], 13);
+    assert_eq!(stats["gdb_packets"], 2);
+    assert_eq!(stats["reconnects"], 0);
+}
+
+#[tokio::test]
+async fn session_name_and_firmware_are_recorded() {
+    let dir = std::env::temp_dir();
+    let events = TempFile(dir.join(format!("wokwi-server-{}-named.ndjson", std::process::id())));
+    let stats_path = TempFile(dir.join(format!("wokwi-server-{}-named.json", std::process::id())));
+    let server = Server::start(
+        "named",
+        &[
+            "--exit-marker",
+            "--session-name",
+            "nightly-42",
+            "--event-log",
+            events.0.to_str().unwrap(),
+            "--stats-json",
+            stats_path.0.to_str().unwrap(),
+        ],
+    )
+    .await;
+    let mut sim = MockSimulator::connect(server.port).await.unwrap();
+    sim.handshake().await.unwrap();
+    sim.uart(b"WOKWI_EXIT 0\n").await.unwrap();
+    let (code, output) = server.exit().await;
+    assert_eq!(code, Some(0));
+    assert!(
+        output.contains("  name             nightly-42\n"),
+        "{}",
+        output
+    );
+
+    let events: Vec<serde_json::Value> = std::fs::read_to_string(&events.0)
+        .unwrap()
+        .lines()
+        .map(|line| serde_json::from_str(line).unwrap())
+        .collect();
+    assert!(events.iter().all(|e| e["session_name"] == "nightly-42"));
+    let started = events.iter().find(|e| e["event"] == "started").unwrap();
+    let built_at = started["firmware"]["built_at"].as_str().unwrap();
+    assert_eq!(built_at.len(), "2022-10-05T14:48:00Z".len(), "{}", built_at);
+    assert!(built_at.ends_with('Z'), "{}", built_at);
+
+    let stats: serde_json::Value =
+        serde_json::from_str(&std::fs::read_to_string(&stats_path.0).unwrap()).unwrap();
+    assert_eq!(stats["session_name"], "nightly-42");
+    assert_eq!(stats["firmware"], started["firmware"]);
+}
+
+#[tokio::test]
+async fn boot_failures_print_hints() {
+    let server = Server::start("boot-hints", &["--exit-marker"]).await;
+    let mut sim = MockSimulator::connect(server.port).await.unwrap();
+    sim.handshake().await.unwrap();
+    for _ in 0..3 {
+        sim.uart(b"rst:0x10 (RTCWDT_RTC_RESET),boot:0x13 (SPI_FAST_FLASH_BOOT)\r\ninvalid header: 0xffffffff\r\n")
+            .await
+            .unwrap();
+    }
+    sim.uart(b"WOKWI_EXIT 0\n").await.unwrap();
+
+    let (_, output) = server.exit().await;
+    assert_eq!(
+        output
+            .matches("Hint: The ROM bootloader found no valid image")
+            .count(),
+        1,
+        "{}",
+        output
+    );
+    assert!(output.contains("built for ESP32 (--chip)"), "{}", output);
+    assert!(
+        output.contains("Hint: The firmware has reset 3 times"),
+        "{}",
+        output
+    );
+}
+
+/// The flash addresses of the segments in a start packet
+fn segment_addrs(start: &serde_json::Value) -> Vec<u64> {
+    start["espBin"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|entry| entry[0].as_u64().unwrap())
+        .collect()
+}
+
+#[tokio::test]
+async fn partition_table_can_be_moved() {
+    let server = Server::start("pt-offset", &["--partition-table-offset", "0x9000"]).await;
+    let mut sim = MockSimulator::connect(server.port).await.unwrap();
+    let start = sim.handshake().await.unwrap();
+    assert_eq!(segment_addrs(&start), [0x1000, 0x9000, 0x10000]);
+}
+
+#[tokio::test]
+async fn partition_table_offset_is_read_from_sdkconfig() {
+    let project =
+        std::env::temp_dir().join(format!("wokwi-server-{}-sdkconfig", std::process::id()));
+    std::fs::create_dir_all(&project).unwrap();
+    std::fs::write(
+        project.join("sdkconfig"),
+        "# comment\nCONFIG_PARTITION_TABLE_OFFSET=0xa000\nCONFIG_IDF_TARGET=\"esp32\"\n",
+    )
+    .unwrap();
+    std::fs::write(project.join("bootloader.bin"), [0xe9; 64]).unwrap();
+    let server = Server::start(
+        "sdkconfig",
+        &[
+            "--project-dir",
+            project.to_str().unwrap(),
+            "--bootloader",
+            "bootloader.bin",
+        ],
+    )
+    .await;
+    let mut sim = MockSimulator::connect(server.port).await.unwrap();
+    let start = sim.handshake().await.unwrap();
+    assert_eq!(segment_addrs(&start), [0x1000, 0xa000, 0x10000]);
+
+    // the default bootloader only finds the table at 0x8000
+    let server = Server::start(
+        "sdkconfig-default-bootloader",
+        &["--project-dir", project.to_str().unwrap()],
+    )
+    .await;
+    assert!(server
+        .output
+        .contains("but the default bootloader looks for it at 0x8000"));
+    let mut sim = MockSimulator::connect(server.port).await.unwrap();
+    let start = sim.handshake().await.unwrap();
+    assert_eq!(segment_addrs(&start), [0x1000, 0x8000, 0x10000]);
+
+    // a project of its own inside doesn't take the sdkconfig of the one around it
+    let nested = project.join("nested");
+    std::fs::create_dir_all(&nested).unwrap();
+    std::fs::write(nested.join("wokwi.toml"), "[wokwi]\nversion = 1\n").unwrap();
+    let server = Server::start(
+        "sdkconfig-nested",
+        &["--project-dir", nested.to_str().unwrap()],
+    )
+    .await;
+    let mut sim = MockSimulator::connect(server.port).await.unwrap();
+    let start = sim.handshake().await.unwrap();
+    std::fs::remove_dir_all(&project).ok();
+    assert_eq!(segment_addrs(&start), [0x1000, 0x8000, 0x10000]);
+}
+
+#[tokio::test]
+async fn partition_table_offsets_past_the_end_of_flash_are_refused() {
+    let server = Server::start(
+        "pt-overflow",
+        &[
+            "--partition-table-offset",
+            "0xfffff000",
+            "--max-errors",
+            "0",
+        ],
+    )
+    .await;
+    let mut sim = MockSimulator::connect(server.port).await.unwrap();
+    assert!(sim.handshake().await.is_err());
+    let (code, output) = server.exit().await;
+    assert_eq!(code, Some(1));
+    assert!(output.contains("doesn't fit in flash"), "{}", output);
+}
+
+#[tokio::test]
+async fn checksum_mismatches_end_the_server_straight_away() {
+    let server = Server::start("expected-sha", &["--expected-sha", &"0".repeat(64)]).await;
+    let mut sim = MockSimulator::connect(server.port).await.unwrap();
+    assert!(sim.handshake().await.is_err());
+    // without waiting for the simulator to reconnect
+    let (code, output) = server.exit().await;
+    assert_eq!(code, Some(1));
+    assert!(output.contains("does not match the expected"), "{}", output);
+}
+
+#[test]
+fn unaligned_partition_table_offset_is_rejected() {
+    let elf_path = TempFile(std::env::temp_dir().join(format!(
+        "wokwi-server-{}-pt-unaligned.elf",
+        std::process::id()
+    )));
+    std::fs::write(&elf_path.0, minimal_elf()).unwrap();
+    // refused before anything is bound
+    let output = std::process::Command::new(env!("CARGO_BIN_EXE_wokwi-server"))
+        .args([
+            "--no-open",
+            "--chip",
+            "esp32",
+            "--port",
+            "0",
+            "--gdb-port",
+            "0",
+        ])
+        .args(["--partition-table-offset", "0x8100"])
+        .arg(&elf_path.0)
+        .output()
+        .unwrap();
+    assert_eq!(output.status.code(), Some(2));
+}
+
+#[test]
+fn a_pidfile_of_a_running_server_is_not_overwritten() {
+    let pidfile =
+        TempFile(std::env::temp_dir().join(format!("wokwi-server-{}.pid", std::process::id())));
+    // this test's own process stands in for the running server
+    std::fs::write(&pidfile.0, format!("{}\n", std::process::id())).unwrap();
+    let elf_path = TempFile(
+        std::env::temp_dir().join(format!("wokwi-server-{}-pidfile.elf", std::process::id())),
+    );
+    std::fs::write(&elf_path.0, minimal_elf()).unwrap();
+    let state = TempDir(
+        std::env::temp_dir().join(format!("wokwi-server-{}-pidfile-state", std::process::id())),
+    );
+    let output = std::process::Command::new(env!("CARGO_BIN_EXE_wokwi-server"))
+        .args(["--no-open", "--chip", "esp32", "--pidfile"])
+        .arg(&pidfile.0)
+        .arg(&elf_path.0)
+        .env("XDG_STATE_HOME", &state.0)
+        .output()
+        .unwrap();
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains(&format!(
+        "wokwi-server is already running (pid {}",
+        std::process::id()
+    )));
+}
+
+#[test]
+fn cargo_finds_the_binary_picked_from_the_workspace() {
+    let dir = std::env::temp_dir().join(format!("wokwi-server-{}-workspace", std::process::id()));
+    let workspace = dir.join("workspace");
+    std::fs::create_dir_all(&workspace).unwrap();
+    std::fs::write(
+        workspace.join("Cargo.toml"),
+        "[workspace]\nmembers = [\"blinky\", \"sensor\"]\n",
+    )
+    .unwrap();
+    for member in ["blinky", "sensor"] {
+        std::fs::create_dir_all(workspace.join(member).join("src")).unwrap();
+        std::fs::write(
+            workspace.join(member).join("Cargo.toml"),
+            format!("[package]\nname = \"{}\"\nversion = \"0.1.0\"\n", member),
+        )
+        .unwrap();
+        std::fs::write(workspace.join(member).join("src/main.rs"), "fn main() {}").unwrap();
+    }
+    // built somewhere else than the workspace's target directory
+    let target = dir.join("target");
+    let profile = target.join("xtensa-esp32-none-elf").join("debug");
+    std::fs::create_dir_all(profile.join(".fingerprint")).unwrap();
+    std::fs::write(profile.join("sensor"), minimal_elf()).unwrap();
+    let output = dir.join("flash.json");
+    let pack = |args: &[&str]| {
+        std::process::Command::new(env!("CARGO_BIN_EXE_wokwi-server"))
+            .args(["pack", "--chip", "esp32", "--cargo", "-o"])
+            .arg(&output)
+            .arg("--project-dir")
+            .arg(&workspace)
+            .args(args)
+            .env("CARGO_TARGET_DIR", &target)
+            .output()
+            .unwrap()
+    };
+
+    let ambiguous = pack(&[]);
+    let stderr = String::from_utf8_lossy(&ambiguous.stderr).into_owned();
+    let unbuilt = pack(&["--bin", "blinky"]);
+    let unbuilt = String::from_utf8_lossy(&unbuilt.stderr).into_owned();
+    let picked = pack(&["--bin", "sensor"]);
+    let stdout = String::from_utf8_lossy(&picked.stdout).into_owned();
+    let packed = std::fs::read(&output);
+    std::fs::remove_dir_all(&dir).ok();
+
+    assert!(!ambiguous.status.success());
+    assert!(
+        stderr.contains("pick one with --bin: blinky, sensor"),
+        "{}",
+        stderr
+    );
+    assert!(
+        unbuilt.contains("There is no dev build of blinky"),
+        "{}",
+        unbuilt
+    );
+    assert!(picked.status.success(), "{}", stdout);
+    assert!(
+        stdout.contains(&profile.join("sensor").display().to_string()),
+        "{}",
+        stdout
+    );
+    let json: serde_json::Value = serde_json::from_slice(&packed.unwrap()).unwrap();
+    assert_eq!(
+        base64::decode(json["elf"].as_str().unwrap()).unwrap(),
+        minimal_elf()
+    );
+}
+
+#[tokio::test]
+async fn pack_writes_segments_and_merged_images() {
+    let dir = std::env::temp_dir().join(format!("wokwi-server-{}-pack", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let elf = dir.join("app.elf");
+    std::fs::write(&elf, minimal_elf()).unwrap();
+    let pack = |output: &str| {
+        std::process::Command::new(env!("CARGO_BIN_EXE_wokwi-server"))
+            .args(["pack", "--chip", "esp32", "-o"])
+            .arg(dir.join(output))
+            .arg(&elf)
+            .output()
+            .unwrap()
+    };
+
+    assert!(pack("flash.json").status.success());
+    let json: serde_json::Value =
+        serde_json::from_slice(&std::fs::read(dir.join("flash.json")).unwrap()).unwrap();
+    assert_eq!(segment_addrs(&json), [0x1000, 0x8000, 0x10000]);
+    assert_eq!(json["segments"].as_array().unwrap().len(), 3);
+
+    assert!(pack("flash.bin").status.success());
+    let merged = std::fs::read(dir.join("flash.bin")).unwrap();
+    std::fs::remove_dir_all(&dir).ok();
+    assert!(merged[..0x1000].iter().all(|&b| b == 0xFF));
+    for entry in json["espBin"].as_array().unwrap() {
+        let addr = entry[0].as_u64().unwrap() as usize;
+        let data = base64::decode(entry[1].as_str().unwrap()).unwrap();
+        assert_eq!(merged[addr..addr + data.len()], data);
+    }
+}
+
+#[test]
+fn direct_boot_images_are_a_single_segment() {
+    let dir = std::env::temp_dir().join(format!("wokwi-server-{}-direct-boot", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let elf = dir.join("app.elf");
+    std::fs::write(&elf, direct_boot_elf()).unwrap();
+    let pack = |args: &[&str]| {
+        std::process::Command::new(env!("CARGO_BIN_EXE_wokwi-server"))
+            .args(["pack", "-o"])
+            .arg(dir.join("flash.json"))
+            .args(args)
+            .arg(&elf)
+            .output()
+            .unwrap()
+    };
+
+    let packed = pack(&["--chip", "esp32c3", "--image-format", "direct-boot"]);
+    assert!(packed.status.success(), "{:?}", packed);
+    let json: serde_json::Value =
+        serde_json::from_slice(&std::fs::read(dir.join("flash.json")).unwrap()).unwrap();
+    assert_eq!(segment_addrs(&json), [0]);
+    assert_eq!(json["segments"][0]["name"], "app");
+
+    // the ESP32's ROM can't boot straight from flash, and there is no bootloader to replace
+    let unsupported = pack(&["--chip", "esp32", "--direct-boot"]);
+    let with_bootloader = pack(&[
+        "--chip",
+        "esp32c3",
+        "--direct-boot",
+        "--bootloader",
+        "app.elf",
+    ]);
+    // a RISC-V elf can't run on the ESP32
+    let mismatched = pack(&["--chip", "esp32"]);
+    std::fs::remove_dir_all(&dir).ok();
+    assert!(String::from_utf8_lossy(&unsupported.stderr)
+        .contains("doesn't support the direct-boot image format"));
+    assert!(String::from_utf8_lossy(&with_bootloader.stderr)
+        .contains("--bootloader can't be used with a direct boot image"));
+    let stderr = String::from_utf8_lossy(&mismatched.stderr);
+    assert!(stderr.contains("isn't built for the ESP32"), "{}", stderr);
+    assert!(stderr.contains("WKS0002"), "{}", stderr);
+}
+
+#[tokio::test]
+async fn serve_sends_a_packed_payload() {
+    let dir = std::env::temp_dir().join(format!("wokwi-server-{}-serve", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let elf = dir.join("app.elf");
+    std::fs::write(&elf, minimal_elf()).unwrap();
+    let payload = dir.join("flash.json");
+    let packed = std::process::Command::new(env!("CARGO_BIN_EXE_wokwi-server"))
+        .args(["pack", "--chip", "esp32", "-o"])
+        .arg(&payload)
+        .arg(&elf)
+        .status()
+        .unwrap();
+    assert!(packed.success());
+    // the elf is only needed to build the payload
+    std::fs::remove_file(&elf).unwrap();
+    let json: serde_json::Value =
+        serde_json::from_slice(&std::fs::read(&payload).unwrap()).unwrap();
+
+    let mut server = Command::new(env!("CARGO_BIN_EXE_wokwi-server"))
+        .arg("serve")
+        .arg(&payload)
+        .args(["--no-open", "--exit-marker", "--output-json"])
+        .args(["--port", "0", "--gdb-port", "0"])
+        .env("XDG_STATE_HOME", &dir)
+        .stdout(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()
+        .unwrap();
+    let mut stdout = BufReader::new(server.stdout.take().unwrap());
+    let (manifest, _) = read_manifest(&mut stdout).await;
+    let mut sim = MockSimulator::connect(bound_port(&manifest["port"]))
+        .await
+        .unwrap();
+    let start = sim.handshake().await.unwrap();
+    assert_eq!(start["elf"], json["elf"]);
+    assert_eq!(start["espBin"], json["espBin"]);
+    assert_eq!(start["x-wokwi-server"]["segments"], json["segments"]);
+    sim.uart(b"WOKWI_EXIT 0\n").await.unwrap();
+    let status = tokio::time::timeout(Duration::from_secs(10), server.wait())
+        .await
+        .expect("server didn't exit")
+        .unwrap();
+    assert!(status.success());
+
+    // a payload whose data doesn't match its checksums is refused
+    let mut damaged = json.clone();
+    damaged["espBin"][2][1] = base64::encode(b"damaged").into();
+    std::fs::write(&payload, serde_json::to_vec(&damaged).unwrap()).unwrap();
+    let refused = std::process::Command::new(env!("CARGO_BIN_EXE_wokwi-server"))
+        .arg("serve")
+        .arg(&payload)
+        .env("XDG_STATE_HOME", &dir)
+        .output()
+        .unwrap();
+    std::fs::remove_dir_all(&dir).ok();
+    assert!(!refused.status.success());
+    let stderr = String::from_utf8_lossy(&refused.stderr);
+    assert!(stderr.contains("doesn't match its checksum"), "{}", stderr);
+}
+
+#[test]
+fn init_and_doctor_set_up_a_cargo_project() {
+    let dir = std::env::temp_dir().join(format!("wokwi-server-{}-init", std::process::id()));
+    let target = dir.join("target/xtensa-esp32-espidf/debug");
+    std::fs::create_dir_all(&target).unwrap();
+    std::fs::create_dir_all(dir.join(".cargo")).unwrap();
+    std::fs::write(dir.join("Cargo.toml"), "[package]\nname = \"blinky\"\n").unwrap();
+    std::fs::write(
+        dir.join(".cargo/config.toml"),
+        "[build]\ntarget = \"xtensa-esp32-espidf\"\n",
+    )
+    .unwrap();
+    std::fs::write(target.join("blinky"), minimal_elf()).unwrap();
+    let wokwi_server = |args: &[&str]| {
+        std::process::Command::new(env!("CARGO_BIN_EXE_wokwi-server"))
+            .args(args)
+            .arg("--project-dir")
+            .arg(&dir)
+            .output()
+            .unwrap()
+    };
+
+    assert!(wokwi_server(&["init"]).status.success());
+    let config = std::fs::read_to_string(dir.join("wokwi.toml")).unwrap();
+    assert!(
+        config.contains("elf = \"target/xtensa-esp32-espidf/debug/blinky\""),
+        "{}",
+        config
+    );
+    // an existing config is kept unless asked to replace it
+    assert!(!wokwi_server(&["init"]).status.success());
+    // the bundled diagram of the board, which an existing one is kept over
+    assert!(wokwi_server(&["init", "--force", "--chip", "esp32"])
+        .status
+        .success());
+    let diagram: serde_json::Value =
+        serde_json::from_slice(&std::fs::read(dir.join("diagram.json")).unwrap()).unwrap();
+    assert_eq!(diagram["parts"][0]["type"], "board-esp32-devkit-c-v4");
+    assert_eq!(diagram["connections"][0][1], "$serialMonitor:RX");
+    let init = wokwi_server(&["init", "--force", "--chip", "esp32s3"]);
+    assert!(String::from_utf8_lossy(&init.stdout).contains("Kept the existing"));
+
+    let doctor = wokwi_server(&[
+        "doctor",
+        "--chip",
+        "esp32",
+        "--port",
+        "0",
+        "--gdb-port",
+        "0",
+    ]);
+    let output = String::from_utf8_lossy(&doctor.stdout);
+    assert!(doctor.status.success(), "{}", output);
+    assert!(output.contains("is built for ESP32"), "{}", output);
+    // the elf is xtensa, so it can't run on a RISC-V chip
+    let doctor = wokwi_server(&[
+        "doctor",
+        "--chip",
+        "esp32c3",
+        "--port",
+        "0",
+        "--gdb-port",
+        "0",
+    ]);
+    assert!(!doctor.status.success());
+
+    // the config is picked up with and without the `run` command
+    for args in [&["run", "--chip", "esp32"][..], &["--chip", "esp32"]] {
+        let run = wokwi_server(&[args, &["--print-urls-only"]].concat());
+        assert!(
+            run.status.success(),
+            "{}",
+            String::from_utf8_lossy(&run.stderr)
+        );
+    }
+    std::fs::remove_dir_all(&dir).ok();
+}
+
+#[test]
+fn completions_include_the_commands() {
+    let output = std::process::Command::new(env!("CARGO_BIN_EXE_wokwi-server"))
+        .args(["completions", "bash"])
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    let script = String::from_utf8_lossy(&output.stdout);
+    for command in ["run", "pack", "serve", "batch", "doctor", "init"] {
+        assert!(script.contains(command), "{}", command);
+    }
+}
+
+#[tokio::test]
+async fn start_packet_is_chunked_for_limited_clients() {
+    let server = Server::start("chunking", &["--exit-marker"]).await;
+    let mut sim = MockSimulator::connect(server.port).await.unwrap();
+    let hello = json!({
+        "type": "hello",
+        "protocolVersion": 1,
+        "capabilities": ["chunking"],
+        "maxMessageSize": 4096,
+    });
+    let start = sim.handshake_with(hello).await.unwrap();
+    assert_eq!(
+        base64::decode(start["elf"].as_str().unwrap()).unwrap(),
+        server.elf
+    );
+    assert_eq!(segment_addrs(&start), [0x1000, 0x8000, 0x10000]);
+    assert!(sim.largest_message <= 4096, "{}", sim.largest_message);
+    sim.close().await.unwrap();
+
+    // a client which can't take chunks is told why it can't have the firmware
+    let mut sim = MockSimulator::connect(server.port).await.unwrap();
+    let hello = json!({ "type": "hello", "protocolVersion": 1, "maxMessageSize": 4096 });
+    assert!(sim.handshake_with(hello).await.is_err());
+
+    let mut sim = MockSimulator::connect(server.port).await.unwrap();
+    sim.handshake().await.unwrap();
+    sim.uart(b"WOKWI_EXIT 0\n").await.unwrap();
+    let (_, output) = server.exit().await;
+    assert!(output.contains("--strip-elf"), "{}", output);
+}
+
+#[tokio::test]
+async fn pinned_protocols_are_spoken_whatever_the_simulator_announces() {
+    let newer = json!({ "type": "hello", "protocolVersion": 99 });
+    let server = Server::start("unpinned", &[]).await;
+    let mut sim = MockSimulator::connect(server.port).await.unwrap();
+    assert!(sim.handshake_with(newer.clone()).await.is_err());
+
+    let server = Server::start("pinned", &["--exit-marker", "--embed-protocol", "1"]).await;
+    let mut sim = MockSimulator::connect(server.port).await.unwrap();
+    sim.handshake_with(newer).await.unwrap();
+    sim.uart(b"WOKWI_EXIT 0\n").await.unwrap();
+    let (code, output) = server.exit().await;
+    assert_eq!(code, Some(0), "{}", output);
+    assert!(
+        output.contains(
+            "Speaking protocol v1 as pinned with --embed-protocol, the simulator announced v99"
+        ),
+        "{}",
+        output
+    );
+}
+
+#[tokio::test]
+async fn chunked_transfers_ping_between_chunks() {
+    let hello = json!({
+        "type": "hello",
+        "protocolVersion": 1,
+        "capabilities": ["chunking"],
+        "maxMessageSize": 4096,
+    });
+    let server = Server::start("keepalive", &[]).await;
+    let mut sim = MockSimulator::connect(server.port).await.unwrap();
+    sim.handshake_with(hello.clone()).await.unwrap();
+    let count = sim.pings.len() + 1;
+    assert!(count > 2, "{:?}", sim.pings);
+    let expected: Vec<_> = (1..count)
+        .map(|i| format!("chunk {}/{}", i, count))
+        .collect();
+    assert_eq!(sim.pings, expected);
+
+    let server = Server::start("no-keepalive", &["--keepalive", "0"]).await;
+    let mut sim = MockSimulator::connect(server.port).await.unwrap();
+    sim.handshake_with(hello).await.unwrap();
+    assert!(sim.pings.is_empty(), "{:?}", sim.pings);
+}
+
+#[tokio::test]
+async fn start_packet_is_resent_after_a_failed_transfer() {
+    let server = Server::start("resend", &["--exit-marker", "--max-errors", "0"]).await;
+    // hang up straight after the hello, so sending the chunks fails
+    let mut sim = MockSimulator::connect(server.port).await.unwrap();
+    sim.send(json!({
+        "type": "hello",
+        "protocolVersion": 1,
+        "capabilities": ["chunking"],
+        "maxMessageSize": 4096,
+    }))
+    .await
+    .unwrap();
+    drop(sim);
+
+    let mut sim = MockSimulator::connect(server.port).await.unwrap();
+    let start = sim.handshake().await.unwrap();
+    assert_eq!(
+        base64::decode(start["elf"].as_str().unwrap()).unwrap(),
+        server.elf
+    );
+    sim.uart(b"WOKWI_EXIT 0\n").await.unwrap();
+    let (code, output) = server.exit().await;
+    assert_eq!(code, Some(0), "{}", output);
+    assert!(output.contains("retry 1 of 3"), "{}", output);
+    assert!(output.contains("Sending the firmware again"), "{}", output);
+}
+
+#[tokio::test]
+async fn start_packet_can_be_dumped() {
+    let dump = std::env::temp_dir().join(format!("wokwi-server-{}-dump.json", std::process::id()));
+    let _dump = TempFile(dump.clone());
+    let server = Server::start("dump", &["--dump-start-packet", dump.to_str().unwrap()]).await;
+    let mut sim = MockSimulator::connect(server.port).await.unwrap();
+    let start = sim.handshake().await.unwrap();
+
+    let dumped: serde_json::Value = serde_json::from_slice(&std::fs::read(&dump).unwrap()).unwrap();
+    assert_eq!(dumped["type"], "start");
+    assert_eq!(dumped["elf"], format!("<{} bytes>", server.elf.len()));
+    assert_eq!(segment_addrs(&dumped), segment_addrs(&start));
+    assert_eq!(dumped["x-wokwi-server"], start["x-wokwi-server"]);
+}
+
+#[tokio::test]
+async fn ws_trace_records_messages_both_ways() {
+    let trace =
+        std::env::temp_dir().join(format!("wokwi-server-{}-trace.ndjson", std::process::id()));
+    let _trace = TempFile(trace.clone());
+    let server = Server::start(
+        "trace",
+        &["--exit-marker", "--ws-trace", trace.to_str().unwrap()],
+    )
+    .await;
+    let mut sim = MockSimulator::connect(server.port).await.unwrap();
+    let start = sim.handshake().await.unwrap();
+    sim.uart(b"WOKWI_EXIT 0\n").await.unwrap();
+    let (code, _) = server.exit().await;
+    assert_eq!(code, Some(0));
+
+    let records: Vec<serde_json::Value> = std::fs::read_to_string(&trace)
+        .unwrap()
+        .lines()
+        .map(|line| serde_json::from_str(line).unwrap())
+        .collect();
+    let summary: Vec<_> = records
+        .iter()
+        .map(|r| {
+            (
+                r["direction"].as_str().unwrap(),
+                r["kind"].as_str().unwrap(),
+            )
+        })
+        .collect();
+    assert_eq!(
+        summary[..3],
+        [("in", "text"), ("out", "text"), ("in", "text")],
+        "{:?}",
+        records
+    );
+    assert_eq!(records[0]["message"]["type"], "hello");
+    assert_eq!(records[2]["message"]["type"], "uartData");
+    assert!(records
+        .iter()
+        .all(|r| r["session"] == records[0]["session"]));
+
+    // the firmware is too large to include, so only its length and hash are
+    let sent = &records[1];
+    assert_eq!(sent["message"]["type"], "start");
+    assert_eq!(sent["len"], start.to_string().len());
+    let elf = start["elf"].as_str().unwrap();
+    let shortened = sent["message"]["elf"].as_str().unwrap();
+    assert!(
+        shortened.starts_with(&format!("<{} bytes, sha256 ", elf.len())),
+        "{}",
+        shortened
+    );
+}
+
+#[tokio::test]
+async fn gdb_trace_records_packets_on_both_sides() {
+    let trace = std::env::temp_dir().join(format!("wokwi-server-{}-gdb.trace", std::process::id()));
+    let _trace = TempFile(trace.clone());
+    let server = Server::start(
+        "gdb-trace",
+        &["--exit-marker", "--gdb-trace", trace.to_str().unwrap()],
+    )
+    .await;
+    let mut sim = MockSimulator::connect(server.port).await.unwrap();
+    sim.handshake().await.unwrap();
+    let mut gdb = connect_when_listening(server.gdb_port).await;
+    read_until(&mut gdb, "+").await;
+    gdb.write_all(b"$g#00").await.unwrap();
+    read_until(&mut gdb, "-").await;
+    gdb.write_all(b"$g#67").await.unwrap();
+    assert_eq!(sim.recv().await.unwrap()["message"], "g");
+    sim.gdb_response(b"$00#60").await.unwrap();
+    read_until(&mut gdb, "$00#60").await;
+    sim.uart(b"WOKWI_EXIT 0\n").await.unwrap();
+    server.exit().await;
+
+    let lines: Vec<Vec<String>> = std::fs::read_to_string(&trace)
+        .unwrap()
+        .lines()
+        .map(|line| line.split(' ').skip(2).map(str::to_owned).collect())
+        .collect();
+    assert_eq!(
+        lines,
+        [
+            ["gdb->server", "bad-checksum(calculated:67)", "$g#00"].as_slice(),
+            &["gdb->server", "ok", "$g#67"],
+            &["server->stub", "ok", "$g#67"],
+            &["stub->server", "ok", "$00#60"],
+            &["server->gdb", "ok", "$00#60"],
+        ]
+    );
+}
+
+#[tokio::test]
+async fn uart_tap_mirrors_output_over_udp() {
+    let tap = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let tap_addr = tap.local_addr().unwrap().to_string();
+    let server = Server::start("tap", &["--exit-marker", "--uart-tap", &tap_addr]).await;
+    let mut sim = MockSimulator::connect(server.port).await.unwrap();
+    sim.handshake().await.unwrap();
+    sim.uart(b"tapped\n").await.unwrap();
+
+    let mut datagram = [0; 64];
+    let len = tokio::time::timeout(Duration::from_secs(10), tap.recv(&mut datagram))
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(&datagram[..len], b"tapped\n");
+
+    sim.uart(b"WOKWI_EXIT 0\n").await.unwrap();
+    let (_, output) = server.exit().await;
+    assert!(output.contains("tapped\n"), "{}", output);
+}
+
+/// Answer an HTTP request with each JSON body in turn, returning the request lines and bodies
+fn serve_json(
+    listener: std::net::TcpListener,
+    bodies: Vec<String>,
+) -> std::thread::JoinHandle<Vec<(String, String)>> {
+    std::thread::spawn(move || {
+        use std::io::{BufRead, BufReader, Read, Write};
+        let mut requests = Vec::new();
+        for body in bodies {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let mut request = String::new();
+            reader.read_line(&mut request).unwrap();
+            let mut content_length = 0;
+            loop {
+                let mut header = String::new();
+                reader.read_line(&mut header).unwrap();
+                if header.trim().is_empty() {
+                    break;
+                }
+                if let Some((name, value)) = header.split_once(':') {
+                    if name.eq_ignore_ascii_case("content-length") {
+                        content_length = value.trim().parse().unwrap();
+                    }
+                }
+            }
+            let mut content = vec![0; content_length];
+            reader.read_exact(&mut content).unwrap();
+            write!(
+                stream,
+                "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                body.len(),
+                body
+            )
+            .unwrap();
+            requests.push((request, String::from_utf8(content).unwrap()));
+        }
+        requests
+    })
+}
+
+#[test]
+fn elfs_are_downloaded_with_the_headers_given() {
+    use std::io::{BufRead, BufReader, Write};
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!(
+        "http://{}/artifacts/app.elf.gz",
+        listener.local_addr().unwrap()
+    );
+    let mut gzipped = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+    gzipped.write_all(&minimal_elf()).unwrap();
+    let body = gzipped.finish().unwrap();
+    let artifacts = std::thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        let mut request = Vec::new();
+        for line in BufReader::new(stream.try_clone().unwrap()).lines() {
+            let line = line.unwrap();
+            if line.is_empty() {
+                break;
+            }
+            request.push(line);
+        }
+        write!(
+            stream,
+            "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+            body.len()
+        )
+        .unwrap();
+        stream.write_all(&body).unwrap();
+        request
+    });
+    let output = std::env::temp_dir().join(format!(
+        "wokwi-server-{}-downloaded.json",
+        std::process::id()
+    ));
+    let _output = TempFile(output.clone());
+
+    let pack = std::process::Command::new(env!("CARGO_BIN_EXE_wokwi-server"))
+        .args(["pack", "--chip", "esp32", "-o"])
+        .arg(&output)
+        .args(["--http-header", "Authorization: Bearer ci-token", &url])
+        .output()
+        .unwrap();
+    let stdout = String::from_utf8_lossy(&pack.stdout);
+    assert!(pack.status.success(), "{}", stdout);
+    assert!(
+        stdout.contains(&format!("Downloaded {}", url)),
+        "{}",
+        stdout
+    );
+
+    let request = artifacts.join().unwrap();
+    assert_eq!(request[0], "GET /artifacts/app.elf.gz HTTP/1.1");
+    assert!(request.contains(&"Authorization: Bearer ci-token".to_owned()));
+    let json: serde_json::Value = serde_json::from_slice(&std::fs::read(&output).unwrap()).unwrap();
+    assert_eq!(
+        base64::decode(json["elf"].as_str().unwrap()).unwrap(),
+        minimal_elf()
+    );
+}
+
+#[test]
+fn pull_writes_the_diagram_and_uses_the_project() {
+    let dir = std::env::temp_dir().join(format!("wokwi-server-{}-pull", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    std::fs::write(dir.join("app.elf"), minimal_elf()).unwrap();
+    std::fs::write(
+        dir.join("wokwi.toml"),
+        "# shared with the VS Code extension\n[wokwi]\nversion = 1\nelf = \"app.elf\"\n",
+    )
+    .unwrap();
+    let diagram = json!({
+        "version": 1,
+        "parts": [{ "type": "board-esp32-c3-devkitm-1", "id": "esp" }],
+        "connections": [],
+    });
+    let body = json!({
+        "project": {
+            "name": "Blinky",
+            "files": [
+                { "name": "diagram.json", "content": diagram.to_string() },
+                { "name": "main.rs", "content": "fn main() {}" },
+            ],
+        },
+    });
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let api_url = format!("http://{}", listener.local_addr().unwrap());
+    let api = serve_json(listener, vec![body.to_string()]);
+    let wokwi_server = |args: &[&str]| {
+        std::process::Command::new(env!("CARGO_BIN_EXE_wokwi-server"))
+            .args(args)
+            .arg("--project-dir")
+            .arg(&dir)
+            .env("WOKWI_API_URL", &api_url)
+            .output()
+            .unwrap()
+    };
+
+    let pull = wokwi_server(&["pull", "https://wokwi.com/projects/123456"]);
+    let output = String::from_utf8_lossy(&pull.stdout);
+    assert!(
+        pull.status.success(),
+        "{}",
+        String::from_utf8_lossy(&pull.stderr)
+    );
+    assert!(output.contains("--chip esp32c3"), "{}", output);
+    assert!(output.contains("Not downloaded: main.rs"), "{}", output);
+    assert!(api.join().unwrap()[0]
+        .0
+        .starts_with("GET /projects/123456 "));
+    let written: serde_json::Value =
+        serde_json::from_slice(&std::fs::read(dir.join("diagram.json")).unwrap()).unwrap();
+    assert_eq!(written, diagram);
+    let config = std::fs::read_to_string(dir.join("wokwi.toml")).unwrap();
+    assert!(
+        config.starts_with("# shared with the VS Code extension\n"),
+        "{}",
+        config
+    );
+    assert!(
+        config.contains("elf = \"app.elf\"\nid = \"123456\"\n"),
+        "{}",
+        config
+    );
+    // a local diagram is kept unless asked to replace it
+    assert!(!wokwi_server(&["pull", "123456"]).status.success());
+
+    let run = wokwi_server(&["--chip", "esp32c3", "--print-urls-only"]);
+    let output = String::from_utf8_lossy(&run.stdout);
+    assert!(output.contains("/wembed/123456?"), "{}", output);
+    std::fs::remove_dir_all(&dir).ok();
+}
+
+#[test]
+fn project_settings_are_defaults_for_the_command_line() {
+    let dir = std::env::temp_dir().join(format!("wokwi-server-{}-settings", std::process::id()));
+    let src = dir.join("src");
+    std::fs::create_dir_all(&src).unwrap();
+    std::fs::write(dir.join("app.elf"), minimal_elf()).unwrap();
+    std::fs::write(
+        dir.join("wokwi.toml"),
+        "[wokwi]\nversion = 1\nelf = \"app.elf\"\n",
+    )
+    .unwrap();
+    // run from a subdirectory, the settings are kept with wokwi.toml
+    let wokwi_server = |args: &[&str]| {
+        std::process::Command::new(env!("CARGO_BIN_EXE_wokwi-server"))
+            .args(args)
+            .current_dir(&src)
+            .env("WOKWI_NO_UPDATE_CHECK", "1")
+            .output()
+            .unwrap()
+    };
+    let stdout =
+        |output: std::process::Output| String::from_utf8_lossy(&output.stdout).into_owned();
+
+    for setting in [
+        &["chip", "esp32c3"][..],
+        &["id", "123456"],
+        &["port", "9876"],
+        &["gdb_port", "9877"],
+        &["uart_sinks", "stdout", "file:uart.log"],
+    ] {
+        let set = wokwi_server(&[&["config", "set"], setting].concat());
+        assert!(set.status.success(), "{:?}", set);
+    }
+    assert!(!wokwi_server(&["config", "set", "port", "lots"])
+        .status
+        .success());
+    assert!(!wokwi_server(&["config", "set", "colour", "blue"])
+        .status
+        .success());
+    let settings = std::fs::read_to_string(dir.join(".wokwi-server.toml")).unwrap();
+    let listed = stdout(wokwi_server(&["config", "list"]));
+
+    let urls = stdout(wokwi_server(&["--no-probe", "--print-urls-only"]));
+    let overridden = stdout(wokwi_server(&[
+        "--no-probe",
+        "--print-urls-only",
+        "--port=9000",
+        "-i",
+        "654321",
+    ]));
+    assert!(wokwi_server(&["config", "unset", "chip"]).status.success());
+    let without_chip = wokwi_server(&["--no-probe", "--print-urls-only"]);
+    std::fs::remove_dir_all(&dir).ok();
+
+    assert_eq!(
+        settings,
+        "chip = \"esp32c3\"\nid = \"123456\"\nport = 9876\ngdb_port = 9877\nuart_sinks = [\"stdout\", \"file:uart.log\"]\n"
+    );
+    assert!(listed.ends_with(&settings), "{}", listed);
+    assert!(urls.contains("/wembed/123456?"), "{}", urls);
+    assert!(urls.contains("port=9876"), "{}", urls);
+    assert!(urls.contains(":9877\n"), "{}", urls);
+    assert!(overridden.contains("/wembed/654321?"), "{}", overridden);
+    assert!(overridden.contains("port=9000"), "{}", overridden);
+    assert!(!without_chip.status.success());
+}
+
+#[test]
+fn pulls_go_through_the_proxy_unless_told_not_to() {
+    let dir = std::env::temp_dir().join(format!("wokwi-server-{}-proxy", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let body = json!({
+        "project": { "files": [{ "name": "diagram.json", "content": "{\"parts\":[]}" }] },
+    });
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let proxy_url = format!("http://{}", listener.local_addr().unwrap());
+    // a proxy which answers the request it tunnels itself
+    let proxy = std::thread::spawn(move || {
+        use std::io::{BufRead, BufReader, Write};
+        let (mut stream, _) = listener.accept().unwrap();
+        let mut reader = BufReader::new(stream.try_clone().unwrap());
+        let mut lines = Vec::new();
+        for response in ["HTTP/1.1 200 Connection established\r\n\r\n".to_owned(), format!(
+            "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            body.to_string().len(),
+            body
+        )] {
+            let mut line = String::new();
+            reader.read_line(&mut line).unwrap();
+            lines.push(line);
+            loop {
+                let mut header = String::new();
+                reader.read_line(&mut header).unwrap();
+                if header.trim().is_empty() {
+                    break;
+                }
+            }
+            stream.write_all(response.as_bytes()).unwrap();
+        }
+        lines
+    });
+    let pull = |no_proxy: &str| {
+        std::process::Command::new(env!("CARGO_BIN_EXE_wokwi-server"))
+            .args(["pull", "--force", "--project-dir"])
+            .arg(&dir)
+            .arg("123456")
+            // a host only the proxy can reach
+            .env("WOKWI_API_URL", "http://api.wokwi.invalid")
+            .env("http_proxy", &proxy_url)
+            .env("NO_PROXY", no_proxy)
+            .env_remove("HTTP_PROXY")
+            .env_remove("ALL_PROXY")
+            .env_remove("all_proxy")
+            .env_remove("no_proxy")
+            .output()
+            .unwrap()
+    };
+
+    let output = pull("");
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    let lines = proxy.join().unwrap();
+    assert!(
+        lines[0].starts_with("CONNECT api.wokwi.invalid:80 "),
+        "{:?}",
+        lines
+    );
+    assert!(lines[1].starts_with("GET /projects/123456 "), "{:?}", lines);
+    // without the proxy the host can't be found
+    assert!(!pull("example.com,.wokwi.invalid").status.success());
+    std::fs::remove_dir_all(&dir).ok();
+}
+
+#[test]
+fn push_uploads_a_changed_diagram() {
+    let dir = std::env::temp_dir().join(format!("wokwi-server-{}-push", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    std::fs::write(
+        dir.join("wokwi.toml"),
+        "[wokwi]\nversion = 1\nid = \"123456\"\n",
+    )
+    .unwrap();
+    let diagram = json!({ "version": 1, "parts": [{ "type": "wokwi-led", "id": "led1" }] });
+    std::fs::write(dir.join("diagram.json"), diagram.to_string()).unwrap();
+    let project = |diagram: &serde_json::Value| {
+        json!({ "project": { "files": [{ "name": "diagram.json", "content": diagram.to_string() }] } })
+            .to_string()
+    };
+    let push = |responses: Vec<String>| {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let api_url = format!("http://{}", listener.local_addr().unwrap());
+        let api = serve_json(listener, responses);
+        let push = std::process::Command::new(env!("CARGO_BIN_EXE_wokwi-server"))
+            .args(["push", "--project-dir"])
+            .arg(&dir)
+            .env("WOKWI_API_URL", &api_url)
+            .env("WOKWI_TOKEN", "secret")
+            .output()
+            .unwrap();
+        assert!(
+            push.status.success(),
+            "{}",
+            String::from_utf8_lossy(&push.stderr)
+        );
+        (
+            String::from_utf8_lossy(&push.stdout).into_owned(),
+            api.join().unwrap(),
+        )
+    };
+
+    let (output, requests) = push(vec![
+        project(&json!({ "version": 1, "parts": [] })),
+        "{}".to_owned(),
+    ]);
+    assert!(output.contains("Uploaded"), "{}", output);
+    assert!(requests[0].0.starts_with("GET /projects/123456 "));
+    assert!(requests[1]
+        .0
+        .starts_with("PUT /projects/123456/files/diagram.json "));
+    let uploaded: serde_json::Value = serde_json::from_str(&requests[1].1).unwrap();
+    assert_eq!(uploaded["content"], diagram.to_string());
+
+    // nothing is uploaded when the project already has the diagram
+    let (output, requests) = push(vec![project(&diagram)]);
+    assert!(
+        output.contains("already has this diagram.json"),
+        "{}",
+        output
+    );
+    assert_eq!(requests.len(), 1);
+    std::fs::remove_dir_all(&dir).ok();
+}
+
+#[test]
+fn self_update_downloads_the_build_for_this_machine() {
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let base = format!("http://{}", listener.local_addr().unwrap());
+    let (os, arch) = (std::env::consts::OS, std::env::consts::ARCH);
+    let release = json!({
+        "tag_name": "v99.0.0",
+        "html_url": "https://github.com/MabezDev/wokwi-server/releases/tag/v99.0.0",
+        "assets": [
+            { "name": "wokwi-server-other-arch", "browser_download_url": format!("{}/other", base) },
+            { "name": format!("wokwi-server-{}-{}.sha256", arch, os), "browser_download_url": format!("{}/sum", base) },
+            { "name": format!("wokwi-server-{}-{}", arch, os), "browser_download_url": format!("{}/build", base) },
+        ],
+    })
+    .to_string();
+    let checksum: String = sha2::Sha256::digest(b"new build")
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect();
+    let api = serve_json(
+        listener,
+        vec![
+            release.clone(),
+            release.clone(),
+            "new build".to_owned(),
+            format!("{}  wokwi-server-{}-{}\n", checksum, arch, os),
+            // a tampered build, refused and then installed with --no-verify
+            release.clone(),
+            "tampered build".to_owned(),
+            format!("{}  wokwi-server-{}-{}\n", checksum, arch, os),
+            release,
+            "tampered build".to_owned(),
+        ],
+    );
+    let output =
+        TempFile(std::env::temp_dir().join(format!("wokwi-server-{}-update", std::process::id())));
+    let wokwi_server = |args: &[&str]| {
+        let result = std::process::Command::new(env!("CARGO_BIN_EXE_wokwi-server"))
+            .arg("self-update")
+            .args(args)
+            .env("WOKWI_SERVER_RELEASES_URL", format!("{}/latest", base))
+            .output()
+            .unwrap();
+        let stdout = String::from_utf8_lossy(&result.stdout).into_owned();
+        let stderr = String::from_utf8_lossy(&result.stderr).into_owned();
+        (result.status.success(), stdout, stderr)
+    };
+
+    let (ok, check, _) = wokwi_server(&["--check"]);
+    assert!(ok);
+    assert!(
+        check.contains("wokwi-server 99.0.0 is available"),
+        "{}",
+        check
+    );
+    let (ok, update, stderr) = wokwi_server(&["--output", output.0.to_str().unwrap()]);
+    assert!(ok, "{}{}", update, stderr);
+    assert!(update.contains("Verified wokwi-server-"), "{}", update);
+    assert!(update.contains("Wrote wokwi-server 99.0.0"), "{}", update);
+    assert_eq!(std::fs::read(&output.0).unwrap(), b"new build");
+
+    let (ok, update, stderr) = wokwi_server(&["--output", output.0.to_str().unwrap()]);
+    assert!(!ok, "{}", update);
+    assert!(stderr.contains("doesn't match its checksum"), "{}", stderr);
+    assert_eq!(std::fs::read(&output.0).unwrap(), b"new build");
+    let (ok, update, stderr) =
+        wokwi_server(&["--no-verify", "--output", output.0.to_str().unwrap()]);
+    assert!(ok, "{}{}", update, stderr);
+    assert_eq!(std::fs::read(&output.0).unwrap(), b"tampered build");
+
+    let requests = api.join().unwrap();
+    assert!(requests[2].0.starts_with("GET /build "), "{:?}", requests);
+    assert!(requests[3].0.starts_with("GET /sum "), "{:?}", requests);
+}
+
+#[tokio::test]
+async fn bug_reports_hold_the_redacted_last_session() {
+    let state = std::env::temp_dir().join(format!("wokwi-server-{}-state", std::process::id()));
+    let elf = TempFile(
+        std::env::temp_dir().join(format!("wokwi-server-{}-report.elf", std::process::id())),
+    );
+    std::fs::write(&elf.0, minimal_elf()).unwrap();
+    let mut server = Command::new(env!("CARGO_BIN_EXE_wokwi-server"))
+        .args([
+            "--no-open",
+            "--no-probe",
+            "--no-update-check",
+            "--exit-marker",
+            "--output-json",
+        ])
+        .args(["--chip", "esp32", "--port", "0", "--gdb-port", "0"])
+        .arg(&elf.0)
+        .env("XDG_STATE_HOME", &state)
+        .env("WOKWI_TOKEN", "hunter2")
+        .stdout(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()
+        .unwrap();
+    let mut stdout = BufReader::new(server.stdout.take().unwrap());
+    let (manifest, _) = read_manifest(&mut stdout).await;
+    let mut sim = MockSimulator::connect(bound_port(&manifest["port"]))
+        .await
+        .unwrap();
+    sim.handshake().await.unwrap();
+    sim.uart(b"wifi password: hunter2\nWOKWI_EXIT 3\n")
+        .await
+        .unwrap();
+    let status = tokio::time::timeout(Duration::from_secs(10), server.wait())
+        .await
+        .expect("server didn't exit")
+        .unwrap();
+    assert_eq!(status.code(), Some(3));
+
+    let zip = TempFile(
+        std::env::temp_dir().join(format!("wokwi-server-{}-report.zip", std::process::id())),
+    );
+    let report = std::process::Command::new(env!("CARGO_BIN_EXE_wokwi-server"))
+        .arg("--bug-report")
+        .arg("--output")
+        .arg(&zip.0)
+        .env("XDG_STATE_HOME", &state)
+        .output()
+        .unwrap();
+    std::fs::remove_dir_all(&state).ok();
+    let stdout = String::from_utf8_lossy(&report.stdout);
+    assert!(report.status.success(), "{}", stdout);
+    assert!(stdout.contains("attach it to an issue"), "{}", stdout);
+
+    // the entries of the zip, from their local headers
+    let archive = std::fs::read(&zip.0).unwrap();
+    let mut entries = std::collections::HashMap::new();
+    let mut rest = &archive[..];
+    while rest.starts_with(b"PK\x03\x04") {
+        let u16_at = |i: usize| u16::from_le_bytes([rest[i], rest[i + 1]]) as usize;
+        let size = u32::from_le_bytes(rest[18..22].try_into().unwrap()) as usize;
+        let (name_len, extra_len) = (u16_at(26), u16_at(28));
+        let name = String::from_utf8(rest[30..30 + name_len].to_vec()).unwrap();
+        let data = &rest[30 + name_len + extra_len..][..size];
+        let mut contents = String::new();
+        std::io::Read::read_to_string(&mut flate2::read::DeflateDecoder::new(data), &mut contents)
+            .unwrap();
+        entries.insert(name, contents);
+        rest = &rest[30 + name_len + extra_len + size..];
+    }
+    assert!(
+        entries.contains_key("environment.txt"),
+        "{:?}",
+        entries.keys()
+    );
+    let log = &entries["last-session.log"];
+    assert!(log.contains("wokwi-server "), "{}", log);
+    assert!(log.contains("WOKWI_TOKEN=<redacted>"), "{}", log);
+    assert!(log.contains("\"type\":\"uartData\""), "{}", log);
+    assert!(log.contains("Exited with code 3"), "{}", log);
+    assert!(!log.contains("hunter2"), "{}", log);
+}
+
+/// Collect UART data sent to the simulator until it ends with `end`
+async fn uart_until(sim: &mut MockSimulator, received: &mut Vec<u8>, end: &[u8]) {
+    while !received.ends_with(end) {
+        let message = sim.recv().await.unwrap();
+        assert_eq!(message["type"], "uartData", "{}", message);
+        for byte in message["bytes"].as_array().unwrap() {
+            received.push(byte.as_u64().unwrap() as u8);
+        }
+    }
+}
+
+#[tokio::test]
+async fn uart_peer_script_answers_the_firmware() {
+    let script =
+        std::env::temp_dir().join(format!("wokwi-server-{}-peer.toml", std::process::id()));
+    let _script = TempFile(script.clone());
+    std::fs::write(
+        &script,
+        r#"
+state = "offline"
+
+[[rule]]
+when = 'AT\+ECHO=(\w+)\r'
+reply = "+ECHO: $1\r\n"
+
+[[rule]]
+when = 'AT\+CONNECT\r'
+reply = "CONNECT\r\n"
+delay = 50
+state = "offline"
+next = "online"
+
+[[rule]]
+when = 'AT\r'
+reply = "OK\r\n"
+state = "online"
+"#,
+    )
+    .unwrap();
+    let server = Server::start("peer", &["--uart-peer", script.to_str().unwrap()]).await;
+    let mut sim = MockSimulator::connect(server.port).await.unwrap();
+    sim.handshake().await.unwrap();
+
+    let mut received = Vec::new();
+    sim.uart(b"AT+ECHO=hi\r").await.unwrap();
+    uart_until(&mut sim, &mut received, b"+ECHO: hi\r\n").await;
+    // nothing answers AT until the modem is online
+    sim.uart(b"AT\rAT+CONNECT\r").await.unwrap();
+    uart_until(&mut sim, &mut received, b"CONNECT\r\n").await;
+    sim.uart(b"AT\r").await.unwrap();
+    uart_until(&mut sim, &mut received, b"OK\r\n").await;
+    assert_eq!(received, b"+ECHO: hi\r\nCONNECT\r\nOK\r\n");
+}
+
+#[tokio::test]
+async fn uart_capture_records_both_directions() {
+    let dir = std::env::temp_dir();
+    let script = dir.join(format!("wokwi-server-{}-capture.toml", std::process::id()));
+    let _script = TempFile(script.clone());
+    std::fs::write(
+        &script,
+        "[[rule]]\nwhen = 'ping\\r'\nreply = \"pong\\r\\n\"\n",
+    )
+    .unwrap();
+    let capture = dir.join(format!(
+        "wokwi-server-{}-capture.pcapng",
+        std::process::id()
+    ));
+    let _capture = TempFile(capture.clone());
+    let server = Server::start(
+        "capture",
+        &[
+            "--exit-marker",
+            "--uart-peer",
+            script.to_str().unwrap(),
+            "--uart-capture",
+            capture.to_str().unwrap(),
+        ],
+    )
+    .await;
+    let mut sim = MockSimulator::connect(server.port).await.unwrap();
+    sim.handshake().await.unwrap();
+    sim.uart(b"ping\r").await.unwrap();
+    uart_until(&mut sim, &mut Vec::new(), b"pong\r\n").await;
+    sim.uart(b"WOKWI_EXIT 0\n").await.unwrap();
+    let (code, _) = server.exit().await;
+    assert_eq!(code, Some(0));
+
+    // the data and direction flags of each enhanced packet block
+    let file = std::fs::read(&capture).unwrap();
+    let word = |at: usize| u32::from_le_bytes(file[at..at + 4].try_into().unwrap());
+    assert_eq!(word(0), 0x0A0D_0D0A);
+    let mut packets = Vec::new();
+    let mut at = 0;
+    while at < file.len() {
+        let (kind, len) = (word(at), word(at + 4) as usize);
+        if kind == 6 {
+            let captured = word(at + 20) as usize;
+            let data = file[at + 28..at + 28 + captured].to_vec();
+            let options = at + 28 + captured.next_multiple_of(4);
+            assert_eq!(word(options) & 0xFFFF, 2, "expected epb_flags");
+            packets.push((word(options + 4), data));
+        }
+        at += len;
+    }
+    assert_eq!(
+        packets,
+        [
+            (2, b"ping\r".to_vec()),
+            (1, b"pong\r\n".to_vec()),
+            (2, b"WOKWI_EXIT 0\n".to_vec()),
+        ]
+    );
+}
+
+#[tokio::test]
+async fn uart_capture_writes_a_sigrok_session() {
+    let capture =
+        std::env::temp_dir().join(format!("wokwi-server-{}-capture.sr", std::process::id()));
+    let _capture = TempFile(capture.clone());
+    let server = Server::start(
+        "capture-sr",
+        &["--exit-marker", "--uart-capture", capture.to_str().unwrap()],
+    )
+    .await;
+    let mut sim = MockSimulator::connect(server.port).await.unwrap();
+    sim.handshake().await.unwrap();
+    sim.uart(b"WOKWI_EXIT 0\n").await.unwrap();
+    let (code, _) = server.exit().await;
+    assert_eq!(code, Some(0));
+
+    let file = std::fs::read(&capture).unwrap();
+    assert!(file.starts_with(b"PK\x03\x04"));
+    // the end of central directory record, counting the version, metadata and a chunk of samples
+    let end = &file[file.len() - 22..];
+    assert_eq!(&end[..4], b"PK\x05\x06");
+    assert_eq!(u16::from_le_bytes([end[10], end[11]]), 3);
+    for name in ["version", "metadata", "logic-1-1"] {
+        assert!(
+            file.windows(name.len()).any(|w| w == name.as_bytes()),
+            "missing {}",
+            name
+        );
+    }
+}
+
+/// A Modbus RTU frame with its CRC
+fn modbus_frame(data: &[u8]) -> Vec<u8> {
+    let crc = data.iter().fold(0xFFFF_u16, |crc, &byte| {
+        (0..8).fold(crc ^ byte as u16, |crc, _| {
+            if crc & 1 == 1 {
+                (crc >> 1) ^ 0xA001
+            } else {
+                crc >> 1
+            }
+        })
+    });
+    [data, &crc.to_le_bytes()].concat()
+}
+
+#[tokio::test]
+async fn uart_peer_modbus_answers_requests() {
+    let map = std::env::temp_dir().join(format!("wokwi-server-{}-modbus.toml", std::process::id()));
+    let _map = TempFile(map.clone());
+    std::fs::write(
+        &map,
+        "address = 7\n[holding_registers]\n0 = 1234\n1 = 0x10\n[coils]\n0 = true\n1 = false\n2 = true\n",
+    )
+    .unwrap();
+    let spec = format!("modbus:{}", map.display());
+    let server = Server::start("modbus", &["--uart-peer", &spec]).await;
+    let mut sim = MockSimulator::connect(server.port).await.unwrap();
+    sim.handshake().await.unwrap();
+    let mut received = Vec::new();
+
+    // a request for another slave is ignored, then two holding registers are read
+    let other = modbus_frame(&[9, 3, 0, 0, 0, 1]);
+    let read = modbus_frame(&[7, 3, 0, 0, 0, 2]);
+    sim.uart(&[other, read].concat()).await.unwrap();
+    let expected = modbus_frame(&[7, 3, 4, 0x04, 0xD2, 0x00, 0x10]);
+    uart_until(&mut sim, &mut received, &expected).await;
+    assert_eq!(received, expected);
+
+    // writes are kept, and unmapped registers are refused
+    received.clear();
+    sim.uart(&modbus_frame(&[7, 6, 0, 1, 0xAB, 0xCD]))
+        .await
+        .unwrap();
+    sim.uart(&modbus_frame(&[7, 3, 0, 1, 0, 1])).await.unwrap();
+    sim.uart(&modbus_frame(&[7, 3, 0, 5, 0, 1])).await.unwrap();
+    sim.uart(&modbus_frame(&[7, 1, 0, 0, 0, 3])).await.unwrap();
+    let expected = [
+        modbus_frame(&[7, 6, 0, 1, 0xAB, 0xCD]),
+        modbus_frame(&[7, 3, 2, 0xAB, 0xCD]),
+        modbus_frame(&[7, 0x83, 2]),
+        modbus_frame(&[7, 1, 1, 0b101]),
+    ]
+    .concat();
+    uart_until(&mut sim, &mut received, &expected).await;
+    assert_eq!(received, expected);
+}
+
+#[tokio::test]
+async fn uart_peer_nmea_reports_the_fix() {
+    let fix = std::env::temp_dir().join(format!("wokwi-server-{}-fix.toml", std::process::id()));
+    let _fix = TempFile(fix.clone());
+    std::fs::write(
+        &fix,
+        "latitude = 49.1951\nlongitude = -16.6068\naltitude = 237.0\ninterval = 100\n",
+    )
+    .unwrap();
+    let spec = format!("nmea:{}", fix.display());
+    let server = Server::start("nmea", &["--uart-peer", &spec]).await;
+    let mut sim = MockSimulator::connect(server.port).await.unwrap();
+    sim.handshake().await.unwrap();
+
+    let mut received = Vec::new();
+    uart_until(&mut sim, &mut received, b"\r\n").await;
+    uart_until(&mut sim, &mut received, b"\r\n").await;
+    let text = String::from_utf8(received).unwrap();
+    let sentences: Vec<&str> = text.lines().collect();
+    assert!(sentences[0].starts_with("$GPGGA,"), "{}", text);
+    assert!(
+        sentences[0].contains(",4911.7060,N,01636.4080,W,1,08,"),
+        "{}",
+        text
+    );
+    assert!(sentences[0].contains(",237.0,M,"), "{}", text);
+    assert!(sentences[1].starts_with("$GPRMC,"), "{}", text);
+    assert!(sentences[1].contains(",A,4911.7060,N,"), "{}", text);
+    for sentence in sentences {
+        let (body, checksum) = sentence[1..].split_once('*').unwrap();
+        let expected = body.bytes().fold(0, |c, b| c ^ b);
+        assert_eq!(
+            u8::from_str_radix(checksum, 16).unwrap(),
+            expected,
+            "{}",
+            sentence
+        );
+    }
+}
+
+/// Play a simulator for a batch, printing `ready` after every start packet until the server
+/// closes the connection, returning how many tests it ran
+async fn batch_simulator(port: u16) -> usize {
+    let mut sim = MockSimulator::connect(port).await.unwrap();
+    sim.handshake().await.unwrap();
+    let mut started = 1;
+    sim.uart(b"ready\n").await.unwrap();
+    while let Ok(message) = sim.recv().await {
+        if message["type"] == "start" {
+            started += 1;
+            sim.uart(b"ready\n").await.unwrap();
+        }
+    }
+    started
+}
+
+#[tokio::test]
+async fn batch_jobs_run_tests_in_parallel() {
+    let dir = std::env::temp_dir().join(format!("wokwi-server-{}-jobs", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    std::fs::write(dir.join("app.elf"), minimal_elf()).unwrap();
+    let mut list = String::from("[defaults]\nchip = \"esp32\"\ntimeout = 10\n");
+    for name in ["one", "two", "three"] {
+        list += &format!(
+            "[[test]]\nname = \"{}\"\nelf = \"app.elf\"\nexpect = [\"ready\"]\n",
+            name
+        );
+    }
+    std::fs::write(dir.join("tests.toml"), list).unwrap();
+
+    let mut batch = Command::new(env!("CARGO_BIN_EXE_wokwi-server"))
+        .args(["batch", "--no-open", "--jobs", "2", "--output"])
+        .arg(dir.join("results.json"))
+        .arg(dir.join("tests.toml"))
+        .stdout(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()
+        .unwrap();
+    // each job prints the link for its own port
+    let mut lines = tokio::io::BufReader::new(batch.stdout.take().unwrap()).lines();
+    let mut ports = Vec::new();
+    while ports.len() < 2 {
+        let line = tokio::time::timeout(Duration::from_secs(10), lines.next_line())
+            .await
+            .expect("no links printed")
+            .unwrap()
+            .expect("batch exited");
+        if let Some((_, rest)) = line.split_once("port=") {
+            ports.push(rest.split('&').next().unwrap().parse::<u16>().unwrap());
+        }
+    }
+    assert_ne!(ports[0], ports[1]);
+    tokio::spawn(async move { while let Ok(Some(_)) = lines.next_line().await {} });
+
+    let ran = futures_util::future::join_all(ports.iter().map(|&port| batch_simulator(port))).await;
+    assert_eq!(ran.iter().sum::<usize>(), 3);
+    let status = tokio::time::timeout(Duration::from_secs(10), batch.wait())
+        .await
+        .expect("batch didn't exit")
+        .unwrap();
+    let results: serde_json::Value =
+        serde_json::from_slice(&std::fs::read(dir.join("results.json")).unwrap()).unwrap();
+    std::fs::remove_dir_all(&dir).ok();
+    assert!(status.success(), "{}", results);
+    let text = results.to_string();
+    let (one, three) = (
+        text.find("\"one\"").unwrap(),
+        text.find("\"three\"").unwrap(),
+    );
+    assert!(one < three, "results aren't in list order: {}", text);
+}
+
+#[tokio::test]
+async fn batch_tests_resolve_their_images_as_the_server_does() {
+    let dir = std::env::temp_dir().join(format!("wokwi-server-{}-resolve", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let mut gzipped = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+    std::io::Write::write_all(&mut gzipped, &minimal_elf()).unwrap();
+    std::fs::write(dir.join("app.elf.gz"), gzipped.finish().unwrap()).unwrap();
+    std::fs::write(
+        dir.join("tests.toml"),
+        "[[test]]\nchip = \"esp32\"\nelf = \"app.elf.gz\"\ntimeout = 10\nexpect = [\"ready\"]\n",
+    )
+    .unwrap();
+
+    let mut batch = Command::new(env!("CARGO_BIN_EXE_wokwi-server"))
+        .args(["batch", "--no-open", "--port", "0", "--output"])
+        .arg(dir.join("results.json"))
+        .arg(dir.join("tests.toml"))
+        .stdout(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()
+        .unwrap();
+    let mut lines = tokio::io::BufReader::new(batch.stdout.take().unwrap()).lines();
+    let port = loop {
+        let line = tokio::time::timeout(Duration::from_secs(10), lines.next_line())
+            .await
+            .expect("no link printed")
+            .unwrap()
+            .expect("batch exited");
+        if let Some((_, rest)) = line.split_once("port=") {
+            break rest.split('&').next().unwrap().parse::<u16>().unwrap();
+        }
+    };
+    tokio::spawn(async move { while let Ok(Some(_)) = lines.next_line().await {} });
+
+    // the elf is decompressed before it is sent, as it would be by the server
+    let mut sim = MockSimulator::connect(port).await.unwrap();
+    let start = sim.handshake().await.unwrap();
+    assert_eq!(
+        base64::decode(start["elf"].as_str().unwrap()).unwrap(),
+        minimal_elf()
+    );
+    sim.uart(b"ready\n").await.unwrap();
+    let status = tokio::time::timeout(Duration::from_secs(10), batch.wait())
+        .await
+        .expect("batch didn't exit")
+        .unwrap();
+    std::fs::remove_dir_all(&dir).ok();
+    assert!(status.success());
+}
+
+#[tokio::test]
+async fn port_zero_links_use_the_bound_ports() {
+    let elf_path = TempFile(
+        std::env::temp_dir().join(format!("wokwi-server-{}-port-zero.elf", std::process::id())),
+    );
+    std::fs::write(&elf_path.0, minimal_elf()).unwrap();
+    let state = TempDir(std::env::temp_dir().join(format!(
+        "wokwi-server-{}-port-zero-state",
+        std::process::id()
+    )));
+    let mut child = Command::new(env!("CARGO_BIN_EXE_wokwi-server"))
+        .args([
+            "--no-open",
+            "--chip",
+            "esp32",
+            "--port",
+            "0",
+            "--gdb-port",
+            "0",
+        ])
+        .args(["--print-gdbinit", "--exit-marker"])
+        .arg(&elf_path.0)
+        .env("XDG_STATE_HOME", &state.0)
+        .stdout(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()
+        .unwrap();
+    let mut lines = tokio::io::BufReader::new(child.stdout.take().unwrap()).lines();
+    let (mut port, mut gdb_port) = (None, None);
+    while port.is_none() || gdb_port.is_none() {
+        let line = tokio::time::timeout(Duration::from_secs(10), lines.next_line())
+            .await
+            .expect("no links printed")
+            .unwrap()
+            .expect("server exited");
+        if let Some((_, rest)) = line.split_once("port=") {
+            port = Some(rest.split('&').next().unwrap().parse::<u16>().unwrap());
+        }
+        if let Some((_, addr)) = line.split_once("target remote localhost:") {
+            gdb_port = Some(addr.trim().parse::<u16>().unwrap());
+        }
+    }
+    let (port, gdb_port) = (port.unwrap(), gdb_port.unwrap());
+    assert_ne!(port, 0);
+    assert_ne!(gdb_port, 0);
+    tokio::spawn(async move { while let Ok(Some(_)) = lines.next_line().await {} });
+
+    TcpStream::connect(("127.0.0.1", gdb_port)).await.unwrap();
+    let mut sim = MockSimulator::connect(port).await.unwrap();
+    sim.handshake().await.unwrap();
+    sim.uart(b"WOKWI_EXIT 0\n").await.unwrap();
+    let status = tokio::time::timeout(Duration::from_secs(10), child.wait())
+        .await
+        .expect("server didn't exit")
+        .unwrap();
+    assert!(status.success());
+
+    // nothing is bound yet, so there is no port to print
+    let refused = std::process::Command::new(env!("CARGO_BIN_EXE_wokwi-server"))
+        .args(["--chip", "esp32", "--port", "0", "--print-urls-only"])
+        .arg(&elf_path.0)
+        .output()
+        .unwrap();
+    assert!(!refused.status.success());
+}
+
+#[test]
+fn simulation_urls_can_point_at_another_wokwi() {
+    let elf_path = TempFile(
+        std::env::temp_dir().join(format!("wokwi-server-{}-url-base.elf", std::process::id())),
+    );
+    std::fs::write(&elf_path.0, minimal_elf()).unwrap();
+    let print_url = |base: &str| {
+        std::process::Command::new(env!("CARGO_BIN_EXE_wokwi-server"))
+            .args(["--chip", "esp32", "--id", "123456", "--print-urls-only"])
+            .args(["--wokwi-url-base", base])
+            .arg(&elf_path.0)
+            .output()
+            .unwrap()
+    };
+
+    let output = print_url("https://wokwi.example.com/embed");
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(output.status.success(), "{}", stdout);
+    assert!(
+        stdout.contains("https://wokwi.example.com/embed/123456?partner=espressif&port=9012"),
+        "{}",
+        stdout
+    );
+    assert!(!print_url("ftp://wokwi.example.com/").status.success());
+    assert!(!print_url("https://wokwi.example.com/?a=b").status.success());
+}
+
+#[test]
+fn unreachable_embeds_fall_back_to_the_next_url() {
+    let elf_path = TempFile(
+        std::env::temp_dir().join(format!("wokwi-server-{}-fallback.elf", std::process::id())),
+    );
+    std::fs::write(&elf_path.0, minimal_elf()).unwrap();
+    let closed = format!("http://127.0.0.1:{}/wembed/", unused_port());
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let open = format!("http://{}/wembed/", listener.local_addr().unwrap());
+    let embed = serve_json(listener, vec![String::new()]);
+    let print_url = |fallback: &str| {
+        let output = std::process::Command::new(env!("CARGO_BIN_EXE_wokwi-server"))
+            .args(["--chip", "esp32", "--id", "123456", "--print-urls-only"])
+            .args([
+                "--wokwi-url-base",
+                &closed,
+                "--wokwi-url-fallback",
+                fallback,
+            ])
+            .arg(&elf_path.0)
+            .output()
+            .unwrap();
+        assert!(output.status.success());
+        String::from_utf8_lossy(&output.stdout).into_owned()
+    };
+
+    let stdout = print_url(&open);
+    assert!(stdout.contains(&format!("{}123456?", open)), "{}", stdout);
+    assert!(embed.join().unwrap()[0].0.starts_with("HEAD /wembed/ "));
+    // with nothing to fall back to, the link is left as it was
+    let stdout = print_url(&format!("http://127.0.0.1:{}/", unused_port()));
+    assert!(stdout.contains(&format!("{}123456?", closed)), "{}", stdout);
+}
+
+#[test]
+fn port_conflicts_name_the_owner() {
+    let taken = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = taken.local_addr().unwrap().port();
+    let elf_path = TempFile(
+        std::env::temp_dir().join(format!("wokwi-server-{}-conflict.elf", std::process::id())),
+    );
+    std::fs::write(&elf_path.0, minimal_elf()).unwrap();
+    let state = TempDir(std::env::temp_dir().join(format!(
+        "wokwi-server-{}-conflict-state",
+        std::process::id()
+    )));
+    let run = |args: &[&str]| {
+        std::process::Command::new(env!("CARGO_BIN_EXE_wokwi-server"))
+            .args(["--no-open", "--chip", "esp32", "--port", &port.to_string()])
+            .args(["--gdb-port", "0"])
+            .args(args)
+            .arg(&elf_path.0)
+            .env("XDG_STATE_HOME", &state.0)
+            .output()
+            .unwrap()
+    };
+    let output = run(&[]);
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        stderr.contains(&format!("Port {} is in use", port)),
+        "{}",
+        stderr
+    );
+    assert!(stderr.contains("choose another with --port"), "{}", stderr);
+    // finding the owner is best effort elsewhere, but /proc always has it for our own process
+    #[cfg(target_os = "linux")]
+    assert!(
+        stderr.contains(&format!("(pid {})", std::process::id())),
+        "{}",
+        stderr
+    );
+    assert!(
+        stderr.contains("WKS0001 (port in use): see https://"),
+        "{}",
+        stderr
+    );
+
+    // the error takes the place of the startup manifest
+    let output = run(&["--output-json"]);
+    assert!(!output.status.success());
+    let json: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+    assert_eq!(json["error"]["code"], "WKS0001");
+    assert!(json["error"]["url"]
+        .as_str()
+        .unwrap()
+        .ends_with("errors.md#wks0001"));
+}
+
+#[tokio::test]
+async fn config_changes_apply_without_a_restart() {
+    let dir = std::env::temp_dir();
+    let config = TempFile(dir.join(format!("wokwi-server-{}-config.toml", std::process::id())));
+    let events = TempFile(dir.join(format!("wokwi-server-{}-config.ndjson", std::process::id())));
+    let log = TempFile(dir.join(format!("wokwi-server-{}-config.log", std::process::id())));
+    std::fs::write(&config.0, "expect = [\"never\"]\n").unwrap();
+    let server = Server::start(
+        "config",
+        &[
+            "--config",
+            config.0.to_str().unwrap(),
+            "--event-log",
+            events.0.to_str().unwrap(),
+        ],
+    )
+    .await;
+    let mut sim = MockSimulator::connect(server.port).await.unwrap();
+    sim.handshake().await.unwrap();
+
+    let sinks = format!("uart_sinks = [\"stdout\", \"file:{}\"]\n", log.0.display());
+    std::fs::write(&config.0, format!("expect = [\"beta\"]\n{}", sinks)).unwrap();
+    let reloaded = async {
+        loop {
+            let events = std::fs::read_to_string(&events.0).unwrap_or_default();
+            if let Some(event) = events.lines().find(|e| e.contains("config-reloaded")) {
+                return serde_json::from_str::<serde_json::Value>(event).unwrap();
+            }
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+    };
+    let event = tokio::time::timeout(Duration::from_secs(10), reloaded)
+        .await
+        .expect("config wasn't reloaded");
+    assert_eq!(event["changed"], json!(["uart_sinks", "expect"]));
+
+    // the new expectation ends the run, and the new sink sees the output
+    sim.uart(b"alpha\nbeta\n").await.unwrap();
+    let (code, output) = server.exit().await;
+    assert_eq!(code, Some(0), "{}", output);
+    assert!(output.contains("Reloaded"), "{}", output);
+    let written = std::fs::read_to_string(&log.0).unwrap();
+    assert!(written.contains("alpha\nbeta\n"), "{}", written);
+}
+
+#[test]
+fn profiles_select_the_build_and_warn_when_stale() {
+    let dir = std::env::temp_dir().join(format!("wokwi-server-{}-profiles", std::process::id()));
+    let target = dir.join("target/xtensa-esp32-espidf");
+    let (debug, release) = (minimal_elf(), [minimal_elf(), vec![0; 4]].concat());
+    for (profile, elf) in [("debug", &debug), ("release", &release)] {
+        std::fs::create_dir_all(target.join(profile).join(".fingerprint")).unwrap();
+        std::fs::write(target.join(profile).join("app"), elf).unwrap();
+    }
+    // the debug build is an hour older than the release build
+    let hour_ago = std::time::SystemTime::now() - Duration::from_secs(3600);
+    std::fs::File::options()
+        .write(true)
+        .open(target.join("debug/app"))
+        .unwrap()
+        .set_modified(hour_ago)
+        .unwrap();
+    let pack = |args: &[&str]| {
+        let output = std::process::Command::new(env!("CARGO_BIN_EXE_wokwi-server"))
+            .args(["pack", "--chip", "esp32", "-o"])
+            .arg(dir.join("out.json"))
+            .args(args)
+            .arg(target.join("debug/app"))
+            .output()
+            .unwrap();
+        let json = std::fs::read(dir.join("out.json"))
+            .ok()
+            .and_then(|json| serde_json::from_slice::<serde_json::Value>(&json).ok());
+        std::fs::remove_file(dir.join("out.json")).ok();
+        (output, json)
+    };
+
+    let (output, json) = pack(&[]);
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(
+        stdout.contains("is newer than the debug build being simulated, pass --profile release"),
+        "{}",
+        stdout
+    );
+    assert_eq!(json.unwrap()["elf"], base64::encode(&debug));
+
+    let (output, json) = pack(&["--release"]);
+    assert!(!String::from_utf8_lossy(&output.stdout).contains("newer"));
+    assert_eq!(json.unwrap()["elf"], base64::encode(&release));
+
+    let (output, json) = pack(&["--profile", "bench"]);
+    assert_eq!(json.unwrap()["elf"], base64::encode(&release));
+    assert!(output.status.success());
+
+    let (output, _) = pack(&["--profile", "size"]);
+    std::fs::remove_dir_all(&dir).ok();
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("There is no size build"), "{}", stderr);
+}
+
+#[test]
+fn elfs_older_than_their_sources_are_reported() {
+    let dir = std::env::temp_dir().join(format!("wokwi-server-{}-stale", std::process::id()));
+    let elf = dir.join("target/xtensa-esp32-espidf/debug/app");
+    std::fs::create_dir_all(elf.parent().unwrap()).unwrap();
+    std::fs::create_dir_all(dir.join("src/bin")).unwrap();
+    std::fs::write(dir.join("Cargo.toml"), "[package]\nname = \"app\"\n").unwrap();
+    std::fs::write(dir.join("src/bin/app.rs"), "fn main() {}\n").unwrap();
+    std::fs::write(&elf, minimal_elf()).unwrap();
+    let source_changed = std::fs::metadata(dir.join("src/bin/app.rs"))
+        .unwrap()
+        .modified()
+        .unwrap();
+    std::fs::File::options()
+        .write(true)
+        .open(&elf)
+        .unwrap()
+        .set_modified(source_changed - Duration::from_secs(2 * 3600))
+        .unwrap();
+    let pack = |args: &[&str]| {
+        let output = std::process::Command::new(env!("CARGO_BIN_EXE_wokwi-server"))
+            .args(["pack", "--chip", "esp32", "-o"])
+            .arg(dir.join("out.json"))
+            .args(args)
+            .arg(&elf)
+            .output()
+            .unwrap();
+        assert!(output.status.success());
+        String::from_utf8_lossy(&output.stdout).into_owned()
+    };
+
+    let stdout = pack(&[]);
+    assert!(
+        stdout.contains("the elf is older than your sources"),
+        "{}",
+        stdout
+    );
+    assert!(
+        stdout.contains("app.rs changed 2 hours later"),
+        "{}",
+        stdout
+    );
+    assert!(!pack(&["--sources", "lib/**/*.rs"]).contains("older than your sources"));
+    std::fs::remove_dir_all(&dir).ok();
+}
+
+#[tokio::test]
+async fn custom_chips_are_sent_and_bridged_to_the_host() {
+    let dir = std::env::temp_dir().join(format!("wokwi-server-{}-chips", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let wasm = dir.join("inverter.chip.wasm");
+    std::fs::write(&wasm, b"\0asm\x01\0\0\0").unwrap();
+    std::fs::write(
+        dir.join("inverter.chip.json"),
+        r#"{"name": "Inverter", "pins": ["IN", "OUT", "GND", "VCC"]}"#,
+    )
+    .unwrap();
+    let chip = format!("inverter={}", wasm.display());
+    // answers every message from the chip by driving its OUT pin low
+    let bridge = r#"inverter=sh -c 'while read line; do echo "{\"type\":\"chipPin\",\"pin\":\"OUT\",\"value\":0}"; done'"#;
+    let server = Server::start(
+        "custom-chips",
+        &[
+            "--exit-marker",
+            "--custom-chip",
+            &chip,
+            "--chip-bridge",
+            bridge,
+        ],
+    )
+    .await;
+
+    let mut sim = MockSimulator::connect(server.port).await.unwrap();
+    sim.send(json!({ "type": "hello", "protocolVersion": 1, "capabilities": ["customChips"] }))
+        .await
+        .unwrap();
+    let chips = sim.recv().await.unwrap();
+    assert_eq!(chips["type"], "customChips");
+    assert_eq!(chips["chips"][0]["name"], "inverter");
+    assert_eq!(chips["chips"][0]["definition"]["pins"][1], "OUT");
+    assert_eq!(
+        base64::decode(chips["chips"][0]["wasm"].as_str().unwrap()).unwrap(),
+        b"\0asm\x01\0\0\0"
+    );
+    assert_eq!(sim.recv().await.unwrap()["type"], "start");
+
+    sim.send(json!({ "type": "chipPin", "chip": "inverter", "pin": "IN", "value": 1 }))
+        .await
+        .unwrap();
+    let reply = sim.recv().await.unwrap();
+    assert_eq!(
+        reply,
+        json!({ "type": "chipPin", "chip": "inverter", "pin": "OUT", "value": 0 })
+    );
+    sim.uart(b"WOKWI_EXIT 0\n").await.unwrap();
+    let (code, output) = server.exit().await;
+    assert_eq!(code, Some(0), "{}", output);
+    assert!(output.contains("Bridging chip inverter"), "{}", output);
+
+    // simulators which can't load chips still get the firmware
+    let server = Server::start(
+        "no-custom-chips",
+        &["--exit-marker", "--custom-chip", &chip],
+    )
+    .await;
+    let mut sim = MockSimulator::connect(server.port).await.unwrap();
+    sim.handshake().await.unwrap();
+    sim.uart(b"WOKWI_EXIT 0\n").await.unwrap();
+    let (_, output) = server.exit().await;
+    assert!(
+        output.contains("the simulator can't load custom chips, leaving out inverter"),
+        "{}",
+        output
+    );
+    std::fs::remove_dir_all(&dir).ok();
+}
+
+#[tokio::test]
+async fn bus_transactions_are_logged_when_the_simulator_reports_them() {
+    let server = Server::start("bus-log", &["--exit-marker", "--i2c-log"]).await;
+    let mut sim = MockSimulator::connect(server.port).await.unwrap();
+    sim.send(
+        json!({ "type": "hello", "protocolVersion": 1, "capabilities": ["peripheralEvents"] }),
+    )
+    .await
+    .unwrap();
+    assert_eq!(
+        sim.recv().await.unwrap(),
+        json!({ "type": "subscribe", "events": ["i2c"] })
+    );
+    assert_eq!(sim.recv().await.unwrap()["type"], "start");
+    sim.send(json!({ "type": "i2cTransaction", "address": 0x68, "write": [0x75], "read": [0x68] }))
+        .await
+        .unwrap();
+    sim.send(json!({ "type": "spiTransaction", "mosi": [0x9f] }))
+        .await
+        .unwrap();
+    sim.uart(b"WOKWI_EXIT 0\n").await.unwrap();
+    let (code, output) = server.exit().await;
+    assert_eq!(code, Some(0), "{}", output);
+    assert!(
+        output.contains("] I2C0 0x68 (MPU-6050 or DS1307/DS3231) read 0x75: 68"),
+        "{}",
+        output
+    );
+    assert!(!output.contains("SPI0"), "{}", output);
+
+    let server = Server::start("no-bus-log", &["--exit-marker", "--i2c-log", "--spi-log"]).await;
+    let mut sim = MockSimulator::connect(server.port).await.unwrap();
+    sim.handshake().await.unwrap();
+    sim.uart(b"WOKWI_EXIT 0\n").await.unwrap();
+    let (_, output) = server.exit().await;
+    assert!(
+        output.contains("there is nothing to record for --i2c-log or --spi-log"),
+        "{}",
+        output
+    );
+}
+
+#[tokio::test]
+async fn pin_changes_are_written_as_a_value_change_dump() {
+    let vcd = std::env::temp_dir().join(format!("wokwi-server-{}.vcd", std::process::id()));
+    let _vcd = TempFile(vcd.clone());
+    let server = Server::start("vcd", &["--exit-marker", "--vcd", vcd.to_str().unwrap()]).await;
+    let mut sim = MockSimulator::connect(server.port).await.unwrap();
+    sim.send(
+        json!({ "type": "hello", "protocolVersion": 1, "capabilities": ["peripheralEvents"] }),
+    )
+    .await
+    .unwrap();
+    assert_eq!(
+        sim.recv().await.unwrap(),
+        json!({ "type": "subscribe", "events": ["gpio"] })
+    );
+    assert_eq!(sim.recv().await.unwrap()["type"], "start");
+    for (value, time) in [(1, 1000), (0, 251000)] {
+        sim.send(json!({ "type": "pinChange", "pin": 2, "value": value, "time": time }))
+            .await
+            .unwrap();
+    }
+    sim.uart(b"WOKWI_EXIT 0\n").await.unwrap();
+    let (code, output) = server.exit().await;
+    assert_eq!(code, Some(0), "{}", output);
+    assert!(output.contains("2 pin changes written to"), "{}", output);
+    let dump = std::fs::read_to_string(&vcd).unwrap();
+    assert!(dump.contains("$var wire 1 ! GPIO2 $end"), "{}", dump);
+    assert!(dump.ends_with("#1000\n1!\n#251000\n0!\n"), "{}", dump);
+}
+
+#[tokio::test]
+async fn benchmarks_time_repeated_boots() {
+    let json = std::env::temp_dir().join(format!("wokwi-server-{}-bench.json", std::process::id()));
+    let _json = TempFile(json.clone());
+    let server = Server::start(
+        "benchmark",
+        &[
+            "--benchmark",
+            "2",
+            "--ready-marker",
+            "Listening",
+            "--benchmark-json",
+            json.to_str().unwrap(),
+        ],
+    )
+    .await;
+    let mut sim = MockSimulator::connect(server.port).await.unwrap();
+    sim.handshake().await.unwrap();
+    for run in 0..2 {
+        sim.uart(b"booting\n").await.unwrap();
+        sim.uart(b"Liste").await.unwrap();
+        sim.uart(b"ning on :80\n").await.unwrap();
+        if run == 0 {
+            // the firmware is booted again for the next run
+            assert_eq!(sim.recv().await.unwrap()["type"], "start");
+        }
+    }
+    let (code, output) = server.exit().await;
+    assert_eq!(code, Some(0), "{}", output);
+    assert!(
+        output.contains("Benchmark run 1/2: first output after"),
+        "{}",
+        output
+    );
+    assert!(output.contains("Benchmark of 2 runs:"), "{}", output);
+    assert!(output.contains("  ready "), "{}", output);
+    let report: serde_json::Value =
+        serde_json::from_str(&std::fs::read_to_string(&json).unwrap()).unwrap();
+    assert_eq!(report["runs"], 2);
+    assert_eq!(report["ready_marker"], "Listening");
+    assert_eq!(report["ready"]["runs_ms"].as_array().unwrap().len(), 2);
+    assert!(
+        report["ready"]["mean_ms"].as_f64().unwrap()
+            >= report["first_output"]["mean_ms"].as_f64().unwrap()
+    );
+}
+
+#[tokio::test]
+async fn sim_seeds_are_sent_and_recorded() {
+    let stats = std::env::temp_dir().join(format!("wokwi-server-{}-seed.json", std::process::id()));
+    let _stats = TempFile(stats.clone());
+    let server = Server::start(
+        "seed",
+        &[
+            "--exit-marker",
+            "--sim-seed",
+            "1234",
+            "--stats-json",
+            stats.to_str().unwrap(),
+        ],
+    )
+    .await;
+    let mut sim = MockSimulator::connect(server.port).await.unwrap();
+    sim.send(json!({ "type": "hello", "protocolVersion": 1, "capabilities": ["seed"] }))
+        .await
+        .unwrap();
+    assert_eq!(
+        sim.recv().await.unwrap(),
+        json!({ "type": "seed", "seed": 1234 })
+    );
+    assert_eq!(sim.recv().await.unwrap()["type"], "start");
+    sim.uart(b"WOKWI_EXIT 0\n").await.unwrap();
+    let (code, output) = server.exit().await;
+    assert_eq!(code, Some(0), "{}", output);
+    assert!(
+        output.contains("reproduce it with --sim-seed 1234"),
+        "{}",
+        output
+    );
+    assert!(output.contains("  seed             1234"), "{}", output);
+    let stats: serde_json::Value =
+        serde_json::from_str(&std::fs::read_to_string(&stats).unwrap()).unwrap();
+    assert_eq!(stats["seed"], 1234);
+
+    // a random seed is chosen once and printed, so the run can be repeated
+    let server = Server::start("random-seed", &["--exit-marker", "--sim-seed", "random"]).await;
+    let mut sim = MockSimulator::connect(server.port).await.unwrap();
+    sim.handshake().await.unwrap();
+    sim.uart(b"WOKWI_EXIT 0\n").await.unwrap();
+    let (_, output) = server.exit().await;
+    assert!(
+        output.contains(
+            "the simulator can't be seeded, this run may not be reproducible with --sim-seed "
+        ),
+        "{}",
+        output
+    );
+}
+
+/// Send a signal to the server, as a terminal or service manager would
+#[cfg(unix)]
+fn signal(server: &Server, signal: &str) {
+    let pid = server.child.id().unwrap().to_string();
+    let status = std::process::Command::new("kill")
+        .args([signal, &pid])
+        .status()
+        .unwrap();
+    assert!(status.success());
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn ctrl_c_stops_the_server_before_gdb_is_launched() {
+    let server = Server::start("ctrl-c-before-gdb", &["--gdb", "sh -c 'exec sleep 30' sh"]).await;
+    // connected, so the server is up, but the simulation hasn't started so GDB isn't running
+    let _sim = MockSimulator::connect(server.port).await.unwrap();
+    signal(&server, "-INT");
+    let (_, output) = server.exit().await;
+    assert!(!output.contains("Launching sh"), "{}", output);
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn sigterm_stops_the_server_while_gdb_runs() {
+    let server = Server::start("sigterm-with-gdb", &["--gdb", "sh -c 'exec sleep 30' sh"]).await;
+    let mut sim = MockSimulator::connect(server.port).await.unwrap();
+    sim.handshake().await.unwrap();
+    tokio::time::sleep(Duration::from_millis(500)).await;
+    // Ctrl-C is left to GDB, but a service manager can always stop the server
+    signal(&server, "-INT");
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    signal(&server, "-TERM");
+    let (_, output) = server.exit().await;
+    assert!(output.contains("Launching sh"), "{}", output);
+}
+
+#[tokio::test]
+async fn health_checks_are_answered_during_a_simulation() {
+    let server = Server::start("healthz", &["--exit-marker"]).await;
+    let mut sim = MockSimulator::connect(server.port).await.unwrap();
+    sim.handshake().await.unwrap();
+
+    let mut probe = TcpStream::connect(("127.0.0.1", server.port))
+        .await
+        .unwrap();
+    probe
+        .write_all(b"GET /healthz HTTP/1.1\r\nHost: localhost\r\n\r\n")
+        .await
+        .unwrap();
+    let mut response = String::new();
+    tokio::time::timeout(Duration::from_secs(5), probe.read_to_string(&mut response))
+        .await
+        .expect("health check wasn't answered while the simulation runs")
+        .unwrap();
+    assert!(response.starts_with("HTTP/1.1 200 OK"), "{}", response);
+    assert!(response.ends_with("ok"), "{}", response);
+
+    sim.uart(b"WOKWI_EXIT 0\n").await.unwrap();
+    let (code, output) = server.exit().await;
+    assert_eq!(code, Some(0), "{}", output);
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn browser_commands_are_only_split_when_they_come_from_the_environment() {
+    let dir = std::env::temp_dir().join(format!("wokwi-server-{}-browser", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let elf = TempFile(dir.join("app.elf"));
+    std::fs::write(&elf.0, minimal_elf()).unwrap();
+    let opened = |args: &[&str], env: Option<&str>, file: &str| {
+        let mut command = Command::new(env!("CARGO_BIN_EXE_wokwi-server"));
+        command
+            .args(["--no-probe", "--no-update-check", "--chip", "esp32"])
+            .args(["--port", "0", "--gdb-port", "0"])
+            .args(args)
+            .arg(&elf.0)
+            .env("XDG_STATE_HOME", &dir)
+            .env_remove("BROWSER")
+            .stdout(Stdio::null())
+            .kill_on_drop(true);
+        if let Some(browser) = env {
+            command.env("BROWSER", browser);
+        }
+        let server = command.spawn().unwrap();
+        let file = dir.join(file);
+        async move {
+            let _server = server;
+            let appeared = async {
+                while !file.exists() {
+                    tokio::time::sleep(Duration::from_millis(50)).await;
+                }
+            };
+            tokio::time::timeout(Duration::from_secs(10), appeared)
+                .await
+                .is_ok()
+        }
+    };
+
+    // a command given with --browser is run as it is, colon and all
+    let touch = format!("touch {}/a:b", dir.display());
+    assert!(opened(&["--browser", &touch], None, "a:b").await);
+    // $BROWSER is a list of commands, of which the first is run
+    let list = format!(
+        "touch {}/first:touch {}/second",
+        dir.display(),
+        dir.display()
+    );
+    assert!(opened(&[], Some(&list), "first").await);
+    assert!(!dir.join("second").exists());
+    std::fs::remove_dir_all(&dir).ok();
+}
@@ -0,0 +1,1076 @@
+use espflash::Chip;
+use serde_json::{json, Value};
+use sha2::Digest;
+use wokwi_server::backtrace::{Backtraces, Panic, Symbolizer};
+use wokwi_server::breakpoints;
+use wokwi_server::buses::{I2cTransaction, SpiTransaction};
+use wokwi_server::coredump::{self, Capture, CrashWatch};
+use wokwi_server::file_io::{self, FileIo};
+use wokwi_server::freertos::{Step, Symbols, Walk};
+use wokwi_server::gdb;
+use wokwi_server::gdb_script::{self, Runner};
+use wokwi_server::heap;
+use wokwi_server::messages::{self, Simulation};
+use wokwi_server::plugins::MessageHandlerPlugin;
+use wokwi_server::profile::{self, Folded, Sample};
+use wokwi_server::protocol::{gdb_response, uart_data, Hello, ProtocolPin, MAX_MESSAGE_SIZE};
+use wokwi_server::router::{Outbox, Router};
+use wokwi_server::strip::strip_elf;
+use wokwi_server::target_description;
+use wokwi_server::test_support::{
+    bootloader_elf, freertos_elf, freertos_memory, functions_elf, heap_elf, heap_memory,
+    minimal_elf,
+};
+use wokwi_server::vcd::{PinChange, Trace};
+use wokwi_server::watchpoints::{Action, Capabilities, Watchpoints};
+use wokwi_server::WokwiServerError;
+use xmas_elf::ElfFile;
+
+#[test]
+fn uart_data_decodes_bytes() {
+    let message = json!({ "type": "uartData", "bytes": [0, 72, 105, 255] });
+    assert_eq!(uart_data(&message).unwrap(), [0, b'H', b'i', 255]);
+}
+
+#[test]
+fn uart_data_rejects_negative_numbers() {
+    let message = json!({ "type": "uartData", "bytes": [72, -1] });
+    assert!(uart_data(&message).is_err());
+}
+
+#[test]
+fn uart_data_rejects_values_above_255() {
+    let message = json!({ "type": "uartData", "bytes": [256] });
+    assert!(uart_data(&message).is_err());
+    let message = json!({ "type": "uartData", "bytes": [u64::MAX] });
+    assert!(uart_data(&message).is_err());
+}
+
+#[test]
+fn uart_data_rejects_non_integers() {
+    for bytes in [json!(["72"]), json!([7.5]), json!([null]), json!([true])] {
+        let message = json!({ "type": "uartData", "bytes": bytes });
+        assert!(uart_data(&message).is_err(), "{}", message);
+    }
+}
+
+#[test]
+fn uart_data_rejects_nested_nonsense() {
+    for bytes in [
+        json!([[72]]),
+        json!([{ "byte": 72 }]),
+        json!({ "0": 72 }),
+        json!("Hi"),
+        json!(72),
+        json!(null),
+    ] {
+        let message = json!({ "type": "uartData", "bytes": bytes });
+        assert!(uart_data(&message).is_err(), "{}", message);
+    }
+    assert!(uart_data(&json!({ "type": "uartData" })).is_err());
+}
+
+#[test]
+fn hello_limits_the_message_size() {
+    let hello = Hello::parse(r#"{"type":"hello","maxMessageSize":65536}"#).unwrap();
+    assert_eq!(hello.negotiate().max_message_size, 65536);
+    let hello = Hello::parse(r#"{"type":"hello","maxMessageSize":1000000000}"#).unwrap();
+    assert_eq!(hello.negotiate().max_message_size, MAX_MESSAGE_SIZE);
+    let hello = Hello::parse(r#"{"type":"hello"}"#).unwrap();
+    assert_eq!(hello.negotiate().max_message_size, MAX_MESSAGE_SIZE);
+    assert!(Hello::parse(r#"{"type":"hello","maxMessageSize":100}"#).is_err());
+}
+
+#[test]
+fn pinned_protocol_versions_override_the_announced_one() {
+    let newer = r#"{"type":"hello","protocolVersion":99}"#;
+    assert!(Hello::parse(newer).is_err());
+    let hello = Hello::parse_pinned(newer, ProtocolPin::Version(1)).unwrap();
+    assert_eq!(hello.protocol_version(), 1);
+    assert_eq!(hello.announced_version(), 99);
+    assert_eq!(hello.negotiate().protocol_version, 1);
+
+    assert_eq!("auto".parse(), Ok(ProtocolPin::Auto));
+    assert_eq!("v1".parse(), Ok(ProtocolPin::Version(1)));
+    assert!("99".parse::<ProtocolPin>().is_err());
+    assert!("latest".parse::<ProtocolPin>().is_err());
+}
+
+#[test]
+fn large_messages_are_chunked_to_fit() {
+    let hello =
+        Hello::parse(r#"{"type":"hello","capabilities":["chunking"],"maxMessageSize":1024}"#)
+            .unwrap();
+    let text = json!({ "type": "start", "elf": "x".repeat(5000) }).to_string();
+    let frames = hello.negotiate().frame(text.clone(), 7).unwrap();
+    assert!(frames.len() > 1);
+
+    let mut data = Vec::new();
+    for (index, frame) in frames.iter().enumerate() {
+        assert!(frame.len() <= 1024, "{}", frame.len());
+        let chunk: Value = serde_json::from_str(frame).unwrap();
+        assert_eq!(chunk["type"], "chunk");
+        assert_eq!(chunk["id"], 7);
+        assert_eq!(chunk["index"], index);
+        assert_eq!(chunk["count"], frames.len());
+        data.extend(base64::decode(chunk["data"].as_str().unwrap()).unwrap());
+    }
+    assert_eq!(data, text.as_bytes());
+
+    // small messages are sent whole
+    let frames = hello.negotiate().frame("{}".to_owned(), 8).unwrap();
+    assert_eq!(frames, ["{}"]);
+}
+
+#[test]
+fn large_messages_fail_without_chunking() {
+    let hello = Hello::parse(r#"{"type":"hello","maxMessageSize":1024}"#).unwrap();
+    let error = hello.negotiate().frame("x".repeat(2000), 1).unwrap_err();
+    assert!(error
+        .to_string()
+        .contains("can't receive messages in chunks"));
+}
+
+#[test]
+fn errors_can_be_told_apart() {
+    assert!(matches!(
+        Hello::parse(r#"{"type":"hello","protocolVersion":99}"#),
+        Err(WokwiServerError::Protocol(_))
+    ));
+    assert!(matches!(
+        uart_data(&json!({ "type": "uartData", "bytes": [256] })),
+        Err(WokwiServerError::Protocol(_))
+    ));
+    assert!(matches!(
+        gdb_response(&json!({ "type": "gdbResponse" })),
+        Err(WokwiServerError::Gdb(_))
+    ));
+    assert!(matches!(
+        strip_elf(b"not an elf", false),
+        Err(WokwiServerError::Image(_))
+    ));
+}
+
+#[test]
+fn malformed_elfs_are_refused_rather_than_stripped() {
+    let elf = minimal_elf();
+    let field = |elf: &[u8], offset: usize| {
+        u32::from_le_bytes(elf[offset..offset + 4].try_into().unwrap()) as usize
+    };
+    let (phoff, shoff) = (field(&elf, 28), field(&elf, 32));
+    let corrupt = |offset: usize, bytes: &[u8]| {
+        let mut elf = elf.clone();
+        elf[offset..offset + bytes.len()].copy_from_slice(bytes);
+        strip_elf(&elf, false)
+    };
+    let refused = [
+        // program headers past the end of the file
+        corrupt(28, &0xffff_fff0u32.to_le_bytes()),
+        // program headers too small to hold a segment's offset and size
+        corrupt(42, &4u16.to_le_bytes()),
+        // a segment whose end doesn't fit in 32 bits
+        corrupt(phoff + 4, &0xffff_ffffu32.to_le_bytes()),
+        // a section whose end doesn't fit in 32 bits
+        corrupt(shoff + 40 + 16, &0xffff_ffffu32.to_le_bytes()),
+        // section headers past the end of the file
+        corrupt(32, &0xffff_fff0u32.to_le_bytes()),
+    ];
+    for (i, result) in refused.into_iter().enumerate() {
+        assert!(
+            matches!(result, Err(WokwiServerError::Image(_))),
+            "{}: {:?}",
+            i,
+            result.map(|elf| elf.len())
+        );
+    }
+}
+
+#[test]
+fn gdb_packets_are_told_apart_from_acknowledgements_and_breaks() {
+    use gdb::{parse_packet, GdbPacket};
+
+    assert_eq!(
+        parse_packet(b"$g#67+"),
+        Some((GdbPacket::Command(b"g".to_vec()), 5))
+    );
+    assert_eq!(
+        parse_packet(b"$g#00"),
+        Some((
+            GdbPacket::BadChecksum {
+                received: "00".into(),
+                calculated: 0x67
+            },
+            5
+        ))
+    );
+    assert_eq!(parse_packet(b"$g#6"), None);
+    assert_eq!(parse_packet(b"+$g#67"), Some((GdbPacket::Garbage, 1)));
+    assert_eq!(parse_packet(b"-"), Some((GdbPacket::Nak, 1)));
+    assert_eq!(parse_packet(b"\x03$g#67"), Some((GdbPacket::Break, 1)));
+}
+
+#[test]
+fn gdb_packets_keep_their_bytes_as_binary_strings() {
+    let bytes: Vec<u8> = (0..=255).collect();
+    let text = gdb::to_binary_string(&bytes);
+    assert_eq!(text.chars().count(), 256);
+    assert_eq!(gdb::from_binary_string(&text), Some(bytes));
+    // a character beyond a byte isn't binary data
+    assert_eq!(gdb::from_binary_string("\u{100}"), None);
+    assert_eq!(gdb::frame([0xff, 0x01]), b"$\xff\x01#00");
+}
+
+#[test]
+fn target_descriptions_list_the_registers_in_order() {
+    let esp32 = target_description::for_chip(Chip::Esp32).unwrap();
+    assert_eq!(esp32.matches("<reg ").count(), 105);
+    assert!(esp32.contains(r#"<reg name="pc" bitsize="32" type="code_ptr" regnum="0"/>"#));
+    assert!(esp32.contains(r#"<reg name="ar1" bitsize="32" type="data_ptr" regnum="2"/>"#));
+
+    let esp32c3 = target_description::for_chip(Chip::Esp32c3).unwrap();
+    assert!(esp32c3.contains("<architecture>riscv:rv32</architecture>"));
+    assert!(esp32c3.contains(r#"<reg name="pc" bitsize="32" type="code_ptr" regnum="32"/>"#));
+
+    assert!(target_description::for_chip(Chip::Esp8266).is_none());
+}
+
+#[test]
+fn target_descriptions_are_read_in_parts() {
+    let xml = b"<target>}</target>";
+    let read = |command| target_description::read(xml, command);
+    assert_eq!(
+        read("qXfer:features:read:target.xml:0,8"),
+        Some(gdb::frame("m<target>"))
+    );
+    // the rest is marked as the last part, with `}` escaped
+    assert_eq!(
+        read("qXfer:features:read:target.xml:8,100"),
+        Some(gdb::frame("l}]</target>"))
+    );
+    assert_eq!(
+        read("qXfer:features:read:target.xml:100,8"),
+        Some(gdb::frame("l"))
+    );
+    assert_eq!(
+        read("qXfer:features:read:other.xml:0,8"),
+        Some(gdb::frame("E00"))
+    );
+    assert_eq!(read("qSupported"), None);
+}
+
+#[test]
+fn qsupported_replies_offer_target_descriptions() {
+    assert_eq!(
+        target_description::advertise(&gdb::frame("PacketSize=1000")),
+        gdb::frame("PacketSize=1000;qXfer:features:read+")
+    );
+    assert_eq!(
+        target_description::advertise(&gdb::frame("")),
+        gdb::frame("qXfer:features:read+")
+    );
+    let offered = gdb::frame("qXfer:features:read+;PacketSize=1000");
+    assert_eq!(target_description::advertise(&offered), offered);
+}
+
+#[test]
+fn freertos_tasks_are_found_by_walking_the_lists() {
+    let elf = freertos_elf();
+    let symbols = Symbols::find(&ElfFile::new(&elf).unwrap()).unwrap();
+    let (mut walk, mut step) = Walk::start(&symbols);
+    let tasks = loop {
+        match step {
+            Step::Read { addr, len } => step = walk.feed(&freertos_memory(addr, len).unwrap()),
+            Step::Done(tasks) => break tasks,
+        }
+    };
+    let found: Vec<_> = tasks.iter().map(|t| (t.tcb, t.describe())).collect();
+    assert_eq!(
+        found,
+        [
+            (0x3ffb_1100, "IDLE (Ready)".to_owned()),
+            (0x3ffb_1000, "main (Running)".to_owned()),
+            (0x3ffb_1200, "Tmr Svc (Blocked)".to_owned()),
+        ]
+    );
+
+    // memory which can't be read ends the walk
+    let (mut walk, _) = Walk::start(&symbols);
+    assert_eq!(
+        walk.feed(&[0; 4]),
+        Step::Read {
+            addr: 0x3ffb_0010,
+            len: 20
+        }
+    );
+    assert_eq!(walk.feed(&[]), Step::Done(Vec::new()));
+
+    let minimal = minimal_elf();
+    assert!(Symbols::find(&ElfFile::new(&minimal).unwrap()).is_none());
+}
+
+#[test]
+fn watchpoints_are_limited_to_what_the_chip_has() {
+    let mut watchpoints = Watchpoints::new(Capabilities::for_chip(Chip::Esp32).unwrap());
+    assert_eq!(watchpoints.command("Z2,3ffb0000,4"), Some(Action::Forward));
+    watchpoints.response("OK");
+    // setting it again doesn't need the simulator
+    assert_eq!(
+        watchpoints.command("Z2,3ffb0000,4"),
+        Some(Action::Reply("OK"))
+    );
+    assert_eq!(watchpoints.command("Z4,3ffb0040,40"), Some(Action::Forward));
+    watchpoints.response("OK");
+    assert!(matches!(
+        watchpoints.command("Z3,3ffb0020,4"),
+        Some(Action::Reject { reply: "E02", .. })
+    ));
+
+    // one removed makes room for another
+    assert_eq!(watchpoints.command("z2,3ffb0000,4"), Some(Action::Forward));
+    watchpoints.response("OK");
+    assert_eq!(
+        watchpoints.command("z2,3ffb0000,4"),
+        Some(Action::Reply("OK"))
+    );
+    assert_eq!(watchpoints.command("Z3,3ffb0020,4"), Some(Action::Forward));
+
+    // regions must be a power of two, aligned and small enough
+    for region in ["Z2,3ffb0001,4", "Z2,3ffb0000,3", "Z2,3ffb0000,80"] {
+        assert!(
+            matches!(
+                watchpoints.command(region),
+                Some(Action::Reject { reply: "E01", .. })
+            ),
+            "{}",
+            region
+        );
+    }
+
+    // breakpoints and everything else are left alone
+    assert_eq!(watchpoints.command("Z0,40080000,2"), None);
+    assert_eq!(watchpoints.command("m3ffb0000,4"), None);
+    assert!(Capabilities::for_chip(Chip::Esp8266).is_none());
+}
+
+#[test]
+fn watchpoint_kinds_the_simulator_lacks_are_reported_unsupported() {
+    let mut watchpoints = Watchpoints::new(Capabilities::for_chip(Chip::Esp32c3).unwrap());
+    assert_eq!(watchpoints.command("Z3,3fc80000,4"), Some(Action::Forward));
+    watchpoints.response("");
+    assert_eq!(
+        watchpoints.command("Z3,3fc80000,4"),
+        Some(Action::Reply(""))
+    );
+    assert_eq!(watchpoints.command("Z2,3fc80000,4"), Some(Action::Forward));
+}
+
+/// A directory of its own for a File-I/O test
+fn fio_root(name: &str) -> std::path::PathBuf {
+    let root =
+        std::env::temp_dir().join(format!("wokwi-server-{}-fio-{}", std::process::id(), name));
+    let _ = std::fs::remove_dir_all(&root);
+    std::fs::create_dir_all(&root).unwrap();
+    root
+}
+
+#[test]
+fn file_io_calls_are_served_from_the_root() {
+    let root = fio_root("calls");
+    std::fs::write(root.join("vectors.bin"), [1, 2, 3, 4, 5]).unwrap();
+    let mut files = FileIo::new(&root).unwrap();
+
+    // open reads the path, which counts its NUL
+    assert_eq!(
+        files.request("Fopen,3ffb0000/c,0,0"),
+        Some(file_io::Step::Read {
+            addr: 0x3ffb0000,
+            len: 12
+        })
+    );
+    assert_eq!(
+        files.feed(Some(b"vectors.bin\0")),
+        file_io::Step::Reply("F3".to_owned())
+    );
+
+    // read writes the data into the buffer, then says how much there was
+    assert_eq!(
+        files.request("Fread,3,3ffb1000,4"),
+        Some(file_io::Step::Write {
+            addr: 0x3ffb1000,
+            data: vec![1, 2, 3, 4]
+        })
+    );
+    assert!(files.is_pending());
+    assert_eq!(files.feed(Some(&[])), file_io::Step::Reply("F4".to_owned()));
+    assert_eq!(
+        files.request("Flseek,3,-1,2"),
+        Some(file_io::Step::Reply("F4".to_owned()))
+    );
+    assert_eq!(
+        files.request("Fclose,3"),
+        Some(file_io::Step::Reply("F0".to_owned()))
+    );
+    assert_eq!(
+        files.request("Fclose,3"),
+        Some(file_io::Step::Reply("F-1,9".to_owned()))
+    );
+
+    // write reads the buffer from the firmware
+    files.request("Fopen,3ffb0000/8,601,1a4");
+    assert_eq!(
+        files.feed(Some(b"out.txt\0")),
+        file_io::Step::Reply("F4".to_owned())
+    );
+    assert_eq!(
+        files.request("Fwrite,4,3ffb2000,5"),
+        Some(file_io::Step::Read {
+            addr: 0x3ffb2000,
+            len: 5
+        })
+    );
+    assert_eq!(
+        files.feed(Some(b"hello")),
+        file_io::Step::Reply("F5".to_owned())
+    );
+    assert_eq!(std::fs::read(root.join("out.txt")).unwrap(), b"hello");
+
+    // a buffer the simulator can't read fails the call
+    files.request("Fwrite,4,0,5");
+    assert_eq!(files.feed(None), file_io::Step::Reply("F-1,e".to_owned()));
+
+    assert_eq!(
+        files.request("Fsystem,3ffb0000/3"),
+        Some(file_io::Step::Reply("F-1,1".to_owned()))
+    );
+    // replies from GDB's own vFile packets aren't calls
+    assert_eq!(files.request("F3;abc"), None);
+    assert_eq!(files.request("F-1,2"), None);
+    assert_eq!(files.request("OK"), None);
+}
+
+#[test]
+fn file_io_paths_stay_inside_the_root() {
+    let root = fio_root("sandbox");
+    std::fs::create_dir_all(root.join("data")).unwrap();
+    std::fs::write(root.join("data/in.txt"), "in").unwrap();
+    let mut files = FileIo::new(&root).unwrap();
+
+    let mut open = |path: &[u8]| {
+        files.request(&format!("Fopen,1000/{:x},0,0", path.len()));
+        files.feed(Some(path))
+    };
+    // absolute paths are taken from the root
+    assert_eq!(
+        open(b"/data/in.txt\0"),
+        file_io::Step::Reply("F3".to_owned())
+    );
+    assert_eq!(
+        open(b"./data/in.txt\0"),
+        file_io::Step::Reply("F4".to_owned())
+    );
+    assert_eq!(open(b"missing\0"), file_io::Step::Reply("F-1,2".to_owned()));
+    assert_eq!(open(b"data\0"), file_io::Step::Reply("F-1,15".to_owned()));
+    assert_eq!(
+        open(b"data/../../etc/passwd\0"),
+        file_io::Step::Reply("F-1,d".to_owned())
+    );
+    #[cfg(unix)]
+    {
+        std::os::unix::fs::symlink("/etc", root.join("etc")).unwrap();
+        assert_eq!(
+            open(b"etc/passwd\0"),
+            file_io::Step::Reply("F-1,d".to_owned())
+        );
+
+        // nor is a file created through a symlink to somewhere that doesn't exist yet
+        let outside = fio_root("sandbox-outside").join("created.txt");
+        std::os::unix::fs::symlink(&outside, root.join("escape")).unwrap();
+        files.request("Fopen,1000/7,201,1a4");
+        assert_eq!(
+            files.feed(Some(b"escape\0")),
+            file_io::Step::Reply("F-1,d".to_owned())
+        );
+        assert!(!outside.exists());
+    }
+}
+
+#[test]
+fn crashes_are_seen_on_the_uart_and_in_stop_replies() {
+    let mut crashes = CrashWatch::default();
+    assert!(!crashes.feed(b"Guru Meditation Error: Core  0 panic'ed"));
+    assert!(crashes.feed(b" (IllegalInstruction)\r\n"));
+    assert!(!crashes.feed(b"I (10) main: still fine\n"));
+
+    assert_eq!(coredump::fatal_signal("T0b;thread:1;"), Some(11));
+    assert_eq!(coredump::fatal_signal("S06"), Some(6));
+    // breakpoints and interrupts aren't crashes
+    assert_eq!(coredump::fatal_signal("S05"), None);
+    assert_eq!(coredump::fatal_signal("T02"), None);
+    assert_eq!(coredump::fatal_signal("OK"), None);
+}
+
+#[test]
+fn core_dumps_hold_the_registers_and_the_memory_read() {
+    let elf = minimal_elf();
+    let (mut capture, step) = Capture::start(Chip::Esp32c3, &elf, Some(11)).unwrap();
+    assert_eq!(step, coredump::Step::Registers);
+
+    // x0 to x31, then pc
+    let registers: Vec<u8> = (0..33u32).flat_map(|i| (0x100 + i).to_le_bytes()).collect();
+    let mut step = capture.feed(&gdb::encode_hex(&registers)).unwrap();
+    let mut reads = 0;
+    let core = loop {
+        match step {
+            coredump::Step::Read { addr, len } => {
+                // one read fails, which splits the memory in two
+                let reply = match reads {
+                    3 => "E01".to_owned(),
+                    _ => gdb::encode_hex(&vec![reads as u8; len as usize]),
+                };
+                assert_eq!(addr, 0x3fc8_0000 + reads * 0x800);
+                reads += 1;
+                step = capture.feed(&reply).unwrap();
+            }
+            coredump::Step::Done(core) => break core,
+            step => panic!("unexpected {:?}", step),
+        }
+    };
+    assert_eq!(reads, 0x60000 / 0x800);
+
+    let elf = ElfFile::new(&core).unwrap();
+    let segments: Vec<_> = elf
+        .program_iter()
+        .map(|p| (p.virtual_addr(), p.file_size()))
+        .collect();
+    assert_eq!(
+        segments[1..],
+        [(0x3fc8_0000, 0x1800), (0x3fc8_2000, 0x60000 - 0x2000)]
+    );
+
+    let notes = elf.program_iter().next().unwrap();
+    let notes = &core[notes.offset() as usize..][..notes.file_size() as usize];
+    // the chip and version go before the elf's SHA-256
+    let info = b"ESP_CORE_DUMP_INFO\0\0";
+    let info = notes.windows(info.len()).position(|w| w == info).unwrap() + info.len();
+    assert_eq!(notes[info..info + 4], [2, 0, 5, 0]);
+    let sha = gdb::encode_hex(&sha2::Sha256::digest(minimal_elf()));
+    assert_eq!(notes[info + 4..info + 68], *sha.as_bytes());
+    // prstatus has the signal, then pc, ra, sp... after its 72 byte header
+    let prstatus = notes.windows(8).position(|w| w == b"CORE\0\0\0\0").unwrap() + 8;
+    assert_eq!(notes[prstatus + 12], 11);
+    let word =
+        |i: usize| u32::from_le_bytes(notes[prstatus + 72 + i * 4..][..4].try_into().unwrap());
+    assert_eq!(
+        [word(0), word(1), word(2), word(31)],
+        [0x120, 0x101, 0x102, 0x11f]
+    );
+    assert!(Capture::start(Chip::Esp8266, &minimal_elf(), None).is_none());
+}
+
+/// Run a heap walk against `memory`, from the stop reply on
+fn walk_heap(symbols: &heap::Symbols, memory: impl Fn(u32, u32) -> Option<Vec<u8>>) -> heap::Step {
+    let (mut walk, step) = heap::Walk::start(symbols);
+    assert_eq!(step, heap::Step::Halt);
+    // output from the firmware isn't the stop reply
+    assert_eq!(walk.feed("O6869"), None);
+    let mut step = walk.feed("T05").unwrap();
+    while let heap::Step::Read { addr, len } = step {
+        let reply = memory(addr, len).map_or("E01".to_owned(), |m| gdb::encode_hex(&m));
+        step = walk.feed(&reply).unwrap();
+    }
+    step
+}
+
+#[test]
+fn heap_stats_are_read_from_freertos_free_list() {
+    let elf = heap_elf();
+    let symbols = heap::Symbols::find(&ElfFile::new(&elf).unwrap()).unwrap();
+    assert_eq!(
+        walk_heap(&symbols, heap_memory),
+        heap::Step::Done(Some(heap::Stats {
+            free: 0x3000,
+            minimum_free: 0x2000,
+            largest_block: Some(0x2000),
+        }))
+    );
+    // a free list that can't be read gives nothing rather than half the picture
+    let broken = |addr: u32, len| heap_memory(addr, len).filter(|_| addr != 0x3ffc_4000);
+    assert_eq!(walk_heap(&symbols, broken), heap::Step::Done(None));
+
+    let elf = freertos_elf();
+    assert!(heap::Symbols::find(&ElfFile::new(&elf).unwrap()).is_none());
+}
+
+#[test]
+fn heap_stats_add_up_esp_idf_heaps() {
+    let symbols = heap::Symbols::EspIdf { heaps: 0x3ffb_0000 };
+    // two heap_t, each pointing at a multi_heap_info with free and minimum free bytes
+    let memory = |addr: u32, len: u32| {
+        let words: &[u32] = match addr {
+            0x3ffb_0000 => &[0x3ffb_1000],
+            0x3ffb_1000 => &[0, 0, 0, 0, 0, 0, 0, 0x3ffc_0000, 0x3ffb_2000],
+            0x3ffb_2000 => &[0, 0, 0, 0, 0, 0, 0, 0x3ffd_0000, 0],
+            0x3ffc_0004 => &[1000, 600],
+            0x3ffd_0004 => &[2000, 1500],
+            _ => return None,
+        };
+        let bytes: Vec<u8> = words.iter().flat_map(|w| w.to_le_bytes()).collect();
+        (bytes.len() == len as usize).then_some(bytes)
+    };
+    assert_eq!(
+        walk_heap(&symbols, memory),
+        heap::Step::Done(Some(heap::Stats {
+            free: 3000,
+            minimum_free: 2100,
+            largest_block: None,
+        }))
+    );
+}
+
+/// A `g` packet of `chip` with the given registers set and the rest 0
+fn registers_packet(chip: Chip, values: &[(&str, u32)]) -> String {
+    let mut packet = Vec::new();
+    for (name, bits) in target_description::layout(chip).unwrap() {
+        let value = values.iter().find(|(n, _)| *n == name).map_or(0, |v| v.1);
+        let mut bytes = value.to_le_bytes().to_vec();
+        bytes.resize(bits as usize / 8, 0);
+        packet.extend(bytes);
+    }
+    gdb::encode_hex(&packet)
+}
+
+#[test]
+fn xtensa_stacks_are_unwound_through_windows_then_memory() {
+    let (mut sample, step) = Sample::start(Chip::Esp32).unwrap();
+    assert_eq!(step, profile::Step::Halt);
+    assert_eq!(sample.feed("T05"), Some(profile::Step::Registers));
+
+    // the current window 2 was called with call8 from window 0, which was called with call4
+    // from a frame that has been spilled to the stack
+    let registers = registers_packet(
+        Chip::Esp32,
+        &[
+            ("pc", 0x400d_1000),
+            ("windowbase", 2),
+            ("windowstart", 0b101),
+            ("ar8", 2 << 30 | 0x000d_2000),
+            ("ar9", 0x3ffb_8000),
+            ("ar0", 1 << 30 | 0x000d_3000),
+            ("ar1", 0x3ffb_8100),
+        ],
+    );
+    assert_eq!(
+        sample.feed(&registers),
+        Some(profile::Step::Read {
+            addr: 0x3ffb_80f0,
+            len: 8
+        })
+    );
+    let spilled: Vec<u8> = [1 << 30 | 0x000d_4000u32, 0x3ffb_8200]
+        .iter()
+        .flat_map(|w| w.to_le_bytes())
+        .collect();
+    assert_eq!(
+        sample.feed(&gdb::encode_hex(&spilled)),
+        Some(profile::Step::Read {
+            addr: 0x3ffb_81f0,
+            len: 8
+        })
+    );
+    // a return address of 0 is the end of the stack
+    let stack = [0x400d_1000, 0x400d_2000, 0x400d_3000, 0x400d_4000];
+    assert_eq!(
+        sample.feed(&gdb::encode_hex(&[0; 8])),
+        Some(profile::Step::Done(stack.to_vec()))
+    );
+
+    let mut folded = Folded::default();
+    folded.add(&stack, &profile::Symbols::default());
+    folded.add(&stack, &profile::Symbols::default());
+    let mut out = Vec::new();
+    folded.write(&mut out).unwrap();
+    assert_eq!(
+        String::from_utf8(out).unwrap(),
+        "0x400d4000;0x400d3000;0x400d2000;0x400d1000 2\n"
+    );
+}
+
+#[test]
+fn riscv_stacks_are_the_pc_and_return_address() {
+    let (mut sample, _) = Sample::start(Chip::Esp32c3).unwrap();
+    // output from the firmware isn't the stop reply
+    assert_eq!(sample.feed("O6869"), None);
+    sample.feed("S05").unwrap();
+    let registers = registers_packet(Chip::Esp32c3, &[("pc", 0x4200_0100), ("ra", 0x4200_0200)]);
+    assert_eq!(
+        sample.feed(&registers),
+        Some(profile::Step::Done(vec![0x4200_0100, 0x4200_0200]))
+    );
+    assert!(Sample::start(Chip::Esp8266).is_none());
+}
+
+#[test]
+fn backtraces_are_found_in_panic_output() {
+    let mut backtraces = Backtraces::default();
+    // ESP-IDF on an ESP32: the register dump, then the backtrace of pc:sp pairs
+    let idf = b"Guru Meditation Error: Core  0 panic'ed (LoadProhibited)\n\
+        PC      : 0x400d1234  PS      : 0x00060630  A0      : 0x800d5678  A1      : 0x3ffb5f00\n\
+        A2      : 0x00000000  A3      : 0x3ffb5f40\n\
+        \n\
+        Backtrace: 0x400d1231:0x3ffb5f00 0x400d5675:0x3ffb5f20 0x40088d85:0x3ffb5f40\n\
+        \n";
+    assert_eq!(
+        backtraces.feed(idf),
+        vec![
+            Panic::Backtrace(vec![0x400d_1234]),
+            Panic::Backtrace(vec![0x400d_1231, 0x400d_5675, 0x4008_8d85])
+        ]
+    );
+
+    // esp-backtrace on an ESP32-C3, where the panic was, then one address per line, which may
+    // come in pieces
+    let rust = b"panicked at src/main.rs:12:5:\nattempt to divide by zero\nBacktrace:\n\n0x42001b6a\n0x420002";
+    let location = Panic::Location {
+        file: "src/main.rs".to_owned(),
+        line: 12,
+        column: 5,
+    };
+    assert_eq!(backtraces.feed(rust), vec![location.clone()]);
+    assert_eq!(
+        backtraces.feed(b"44\n0x42000f10\n\nESP-ROM:esp32c3-api1-20210207\n"),
+        vec![Panic::Backtrace(vec![
+            0x4200_1b6a,
+            0x4200_0244,
+            0x4200_0f10
+        ])]
+    );
+    // before Rust 1.73, and older esp-backtrace
+    assert_eq!(
+        backtraces.feed(b"panicked at 'attempt to divide by zero', src/main.rs:12:5\n"),
+        vec![location.clone()]
+    );
+    assert_eq!(
+        backtraces.feed(b"!! A panic occured in 'src/main.rs', at line 12, column 5\n"),
+        vec![location]
+    );
+
+    // addresses in other output aren't taken for backtraces
+    assert!(backtraces
+        .feed(b"0x40080000\nread 4 bytes at 0x3ffb0000:0x40080000\nok\n")
+        .is_empty());
+}
+
+#[test]
+fn backtrace_addresses_are_named_from_the_symbols() {
+    let symbolizer = Symbolizer::new(&functions_elf());
+    let frames = symbolizer.frames(0x4008_000a);
+    assert_eq!(frames.len(), 1);
+    assert_eq!(frames[0].function.as_deref(), Some("panic_handler"));
+    assert_eq!((frames[0].file.as_ref(), frames[0].line), (None, None));
+    assert!(symbolizer.frames(0x4009_0000).is_empty());
+    assert!(Symbolizer::new(b"not an elf")
+        .frames(0x4008_0000)
+        .is_empty());
+}
+
+/// Counts the pin events it sees, replying with the count
+struct PinCounter(u32);
+
+impl MessageHandlerPlugin for PinCounter {
+    fn name(&self) -> &str {
+        "pin-counter"
+    }
+
+    fn message_types(&self) -> Vec<String> {
+        vec!["pinEvent".to_owned(), "uartData".to_owned()]
+    }
+
+    fn handle(&mut self, message: &Value, out: &mut Outbox) -> wokwi_server::error::Result<()> {
+        if message["pin"] == "broken" {
+            return Err(WokwiServerError::Gdb("no such pin".to_owned()));
+        }
+        self.0 += 1;
+        out.send_to_simulator(&json!({ "type": "pinCount", "count": self.0 }))?;
+        Ok(())
+    }
+}
+
+#[test]
+fn plugins_handle_the_types_no_handler_has() {
+    let mut router: Router<u32> = Router::default()
+        .on("uartData", |seen, _, _| {
+            *seen += 1;
+            Ok(())
+        })
+        .plugin(Box::new(PinCounter(0)));
+    let mut seen = 0;
+
+    for _ in 0..2 {
+        assert!(router
+            .dispatch(&mut seen, &json!({ "type": "pinEvent", "pin": 4 }))
+            .unwrap());
+    }
+    let replies: Vec<Value> = std::iter::from_fn(|| router.outbox.next_for_simulator())
+        .map(|reply| serde_json::from_str(&reply).unwrap())
+        .collect();
+    assert_eq!(
+        replies,
+        [
+            json!({ "type": "pinCount", "count": 1 }),
+            json!({ "type": "pinCount", "count": 2 })
+        ]
+    );
+
+    // the built-in handler keeps its type
+    assert!(router
+        .dispatch(&mut seen, &json!({ "type": "uartData", "bytes": [] }))
+        .unwrap());
+    assert_eq!(seen, 1);
+    assert_eq!(router.outbox.next_for_simulator(), None);
+
+    assert!(!router
+        .dispatch(&mut seen, &json!({ "type": "netFrame" }))
+        .unwrap());
+
+    // a failing plugin is a protocol error naming it
+    match router.dispatch(&mut seen, &json!({ "type": "pinEvent", "pin": "broken" })) {
+        Err(WokwiServerError::Protocol(reason)) => {
+            assert_eq!(reason, "Plugin pin-counter failed: no such pin")
+        }
+        other => panic!("expected a protocol error, got {:?}", other),
+    }
+}
+
+/// Records what the server's router hands a simulation
+#[derive(Default)]
+struct Recorded {
+    uart: Vec<u8>,
+    chips: Vec<String>,
+    paused: bool,
+}
+
+impl Simulation for Recorded {
+    fn uart_data(&mut self, bytes: Vec<u8>, _: &mut Outbox) -> wokwi_server::error::Result<()> {
+        self.uart.extend(bytes);
+        Ok(())
+    }
+
+    fn gdb_response(
+        &mut self,
+        response: Vec<u8>,
+        out: &mut Outbox,
+    ) -> wokwi_server::error::Result<()> {
+        out.send_to_gdb(response);
+        Ok(())
+    }
+
+    fn i2c_transaction(
+        &mut self,
+        _: I2cTransaction,
+        _: &mut Outbox,
+    ) -> wokwi_server::error::Result<()> {
+        Ok(())
+    }
+
+    fn spi_transaction(
+        &mut self,
+        _: SpiTransaction,
+        _: &mut Outbox,
+    ) -> wokwi_server::error::Result<()> {
+        Ok(())
+    }
+
+    fn pin_change(&mut self, _: PinChange, _: &mut Outbox) -> wokwi_server::error::Result<()> {
+        Ok(())
+    }
+
+    fn paused(&mut self, _: &mut Outbox) -> wokwi_server::error::Result<()> {
+        self.paused = true;
+        Ok(())
+    }
+
+    fn chip_message(
+        &mut self,
+        chip: &str,
+        _: &Value,
+        _: &mut Outbox,
+    ) -> wokwi_server::error::Result<()> {
+        self.chips.push(chip.to_owned());
+        Ok(())
+    }
+}
+
+#[test]
+fn the_server_router_decodes_messages_for_the_simulation() {
+    let mut router = messages::router();
+    let mut sim = Recorded::default();
+    for message in [
+        json!({ "type": "uartData", "bytes": [104, 105] }),
+        json!({ "type": "gdbResponse", "response": "$OK#9a" }),
+        json!({ "type": "chipSerial", "chip": "uart-echo", "bytes": [] }),
+        json!({ "type": "paused" }),
+    ] {
+        assert!(router.dispatch(&mut sim, &message).unwrap());
+    }
+    assert_eq!(sim.uart, b"hi");
+    assert_eq!(sim.chips, ["uart-echo"]);
+    assert!(sim.paused);
+    assert_eq!(
+        router.outbox.next_for_gdb().as_deref(),
+        Some(&b"$OK#9a"[..])
+    );
+
+    assert!(!router
+        .dispatch(&mut sim, &json!({ "type": "netFrame" }))
+        .unwrap());
+    for malformed in [
+        json!({ "type": "uartData", "bytes": [256] }),
+        json!({ "type": "pinChange", "pin": 2, "value": 7 }),
+        json!({ "type": "chipPin", "pin": 0 }),
+    ] {
+        assert!(matches!(
+            router.dispatch(&mut sim, &malformed),
+            Err(WokwiServerError::Protocol(_))
+        ));
+    }
+    for malformed in [
+        json!({ "type": "gdbResponse" }),
+        json!({ "type": "gdbResponse", "response": "$\u{100}#00" }),
+    ] {
+        assert!(matches!(
+            router.dispatch(&mut sim, &malformed),
+            Err(WokwiServerError::Gdb(_))
+        ));
+    }
+}
+
+#[test]
+fn bus_transactions_are_decoded_with_their_registers() {
+    let i2c = |message: Value| I2cTransaction::parse(&message).unwrap().describe();
+    assert_eq!(
+        i2c(json!({ "address": 0x68, "write": [0x6b, 0x00] })),
+        "I2C0 0x68 (MPU-6050 or DS1307/DS3231) write 0x6b = 00"
+    );
+    assert_eq!(
+        i2c(json!({ "bus": 1, "address": 0x76, "write": [0xd0], "read": [0x60] })),
+        "I2C1 0x76 (BME280/BMP280) read 0xd0: 60"
+    );
+    assert_eq!(
+        i2c(json!({ "address": 0x12, "write": [0x01] })),
+        "I2C0 0x12 select 0x01"
+    );
+    assert_eq!(
+        i2c(json!({ "address": 0x3c, "nack": true })),
+        "I2C0 0x3c (SSD1306) NACK"
+    );
+    assert!(I2cTransaction::parse(&json!({ "address": 0x68, "write": [256] })).is_err());
+
+    let spi = |message: Value| SpiTransaction::parse(&message).unwrap().describe();
+    assert_eq!(
+        spi(
+            json!({ "bus": 2, "cs": 5, "mosi": [0x9f, 0, 0, 0], "miso": [0xff, 0xef, 0x40, 0x18] })
+        ),
+        "SPI2 cs5 cmd 0x9f 00 00 00 -> ef 40 18"
+    );
+    assert_eq!(
+        spi(json!({ "mosi": [0x06], "miso": [0xff] })),
+        "SPI0 cmd 0x06"
+    );
+}
+
+#[test]
+fn pin_changes_are_dumped_in_time_order() {
+    let change = |pin, value, time| {
+        PinChange::parse(&json!({ "pin": pin, "value": value, "time": time })).unwrap()
+    };
+    let mut trace = Trace::default();
+    trace.add(change(4, 1, 0));
+    trace.add(change(2, 0, 0));
+    trace.add(change(4, 0, 1500));
+    // reported late, so dumped with the change before it
+    trace.add(change(2, 1, 1000));
+    let mut out = Vec::new();
+    trace.write("esp32", &mut out).unwrap();
+    let dump = String::from_utf8(out).unwrap();
+    let body = dump.split_once("$timescale").unwrap().1;
+    assert_eq!(
+        body,
+        " 1ns $end\n$scope module esp32 $end\n$var wire 1 ! GPIO2 $end\n$var wire 1 \" GPIO4 $end\n\
+         $upscope $end\n$enddefinitions $end\n#0\n$dumpvars\nx!\nx\"\n$end\n#0\n1\"\n0!\n#1500\n0\"\n1!\n"
+    );
+    assert!(PinChange::parse(&json!({ "pin": 4, "value": 2, "time": 0 })).is_err());
+}
+
+#[test]
+fn addresses_are_looked_up_in_every_elf_added() {
+    let mut symbolizer = Symbolizer::new(&functions_elf());
+    assert!(symbolizer.frames(0x4007_8000).is_empty());
+    symbolizer.add(&bootloader_elf());
+    let frames = symbolizer.frames(0x4007_8004);
+    assert_eq!(frames[0].function.as_deref(), Some("bootloader_main"));
+    let frames = symbolizer.frames(0x4008_0000);
+    assert_eq!(frames[0].function.as_deref(), Some("app_main"));
+}
+
+#[test]
+fn gdb_scripts_run_before_the_firmware_is_continued() {
+    let elf = functions_elf();
+    let app_main = breakpoints::resolve(&elf, "app_main").unwrap();
+    assert_eq!(app_main.addr, 0x4008_0000);
+    assert_eq!(
+        breakpoints::resolve(&elf, "0x40080008").unwrap().addr,
+        0x4008_0008
+    );
+    assert!(breakpoints::resolve(&elf, "main").is_err());
+    assert!(breakpoints::resolve(&elf, "0xnope").is_err());
+
+    let script = gdb_script::parse(
+        "# stop at the handler\nhbreak panic_handler\nset {short}app_main = -2\nmonitor reset\n",
+        &elf,
+    )
+    .unwrap();
+    let hbreak = gdb_script::Command::hbreak(app_main, breakpoints::kind(&elf));
+    let (mut runner, packets) = Runner::start([vec![hbreak], script].concat(), true);
+    assert_eq!(
+        packets,
+        [
+            "Z1,40080000,3",
+            "Z1,40080008,3",
+            "M40080000,2:feff",
+            "qRcmd,7265736574",
+            "c"
+        ]
+    );
+    assert_eq!(runner.feed("S02"), gdb_script::Step::Swallow);
+    assert_eq!(runner.feed("OK"), gdb_script::Step::Swallow);
+    assert!(
+        matches!(runner.feed(""), gdb_script::Step::Failed(reason) if reason.starts_with("hbreak panic_handler failed"))
+    );
+    assert_eq!(
+        runner.feed("E01"),
+        gdb_script::Step::Failed("set {short}app_main = -2 failed: E01".to_owned())
+    );
+    assert_eq!(
+        runner.feed("O646f6e650a"),
+        gdb_script::Step::Output("done\n".to_owned())
+    );
+    assert!(!runner.is_running());
+    assert_eq!(runner.feed("OK"), gdb_script::Step::Swallow);
+    assert!(runner.is_running());
+    assert_eq!(runner.feed("O6869"), gdb_script::Step::Swallow);
+    assert_eq!(runner.feed("T05"), gdb_script::Step::Stopped);
+    assert!(runner.is_done());
+    assert_eq!(
+        runner.breakpoints(),
+        "app_main (0x40080000), panic_handler (0x40080008)"
+    );
+
+    let error = gdb_script::parse("hbreak app_main\nwatch x\n", &elf).unwrap_err();
+    assert!(
+        error
+            .to_string()
+            .starts_with("line 2: `watch` isn't supported"),
+        "{}",
+        error
+    );
+}
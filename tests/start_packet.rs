@@ -0,0 +1,82 @@
+use serde_json::{json, Value};
+use wokwi_server::test_support::minimal_elf;
+use wokwi_server::{PacketExtensions, SegmentChecksum, SimulationPacket};
+
+fn segment(addr: u32, data: &[u8]) -> Vec<Value> {
+    vec![json!(addr), json!(base64::encode(data))]
+}
+
+/// A packet like the one built for a firmware, with the bootloader, partition table and app
+fn packet() -> SimulationPacket {
+    let segments = [
+        ("bootloader", 0x1000, vec![0xE9; 32]),
+        ("partition-table", 0x8000, vec![0xAA; 32]),
+        ("app", 0x10000, vec![0xE9; 64]),
+    ];
+    SimulationPacket {
+        r#type: "start".to_owned(),
+        elf: base64::encode(minimal_elf()),
+        esp_bin: segments
+            .iter()
+            .map(|(_, addr, data)| segment(*addr, data))
+            .collect(),
+        extensions: Some(PacketExtensions {
+            segments: segments
+                .iter()
+                .map(|(name, addr, data)| SegmentChecksum::new(name, *addr, data))
+                .collect(),
+        }),
+    }
+}
+
+fn problem(packet: &SimulationPacket) -> String {
+    packet.validate().unwrap_err().to_string()
+}
+
+#[test]
+fn built_packet_is_valid() {
+    packet().validate().unwrap();
+}
+
+#[test]
+fn elf_must_be_an_elf() {
+    let mut packet = packet();
+    packet.elf = "not base64!".to_owned();
+    assert!(problem(&packet).contains("base64"));
+    packet.elf = base64::encode(b"MZ\x90\0");
+    assert!(problem(&packet).contains("isn't an elf"));
+}
+
+#[test]
+fn segments_must_be_address_and_data() {
+    let mut packet = packet();
+    packet.esp_bin[0] = vec![json!(0x1000)];
+    assert!(problem(&packet).contains("espBin[0]"));
+
+    let mut packet = self::packet();
+    packet.esp_bin[1][0] = json!(-1);
+    assert!(problem(&packet).contains("isn't a flash offset"));
+}
+
+#[test]
+fn overlapping_segments_are_rejected() {
+    let mut packet = packet();
+    packet.extensions = None;
+    packet.esp_bin[1] = segment(0x1010, &[0xAA; 32]);
+    assert!(problem(&packet).contains("overlaps"));
+}
+
+#[test]
+fn checksums_must_describe_the_segments() {
+    let mut packet = packet();
+    packet.esp_bin[2] = segment(0x20000, &[0xE9; 64]);
+    assert!(problem(&packet).contains("the app segment"));
+}
+
+#[test]
+fn summary_leaves_out_binary_data() {
+    let summary = packet().summary();
+    assert_eq!(summary["elf"], format!("<{} bytes>", minimal_elf().len()));
+    assert_eq!(summary["espBin"][2], json!([0x10000, "<64 bytes>"]));
+    assert_eq!(summary["x-wokwi-server"]["segments"][2]["name"], "app");
+}